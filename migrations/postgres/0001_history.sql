@@ -0,0 +1,34 @@
+-- Orders, trades, ledger journals and the trade event outbox.
+-- Records are kept whole as JSON, next to the columns they are looked up by;
+-- `seq` stands in for SQLite's rowid.
+CREATE TABLE IF NOT EXISTS orders (
+    seq BIGSERIAL,
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS orders_by_user ON orders (user_id, seq);
+
+CREATE TABLE IF NOT EXISTS trades (
+    seq BIGSERIAL,
+    id TEXT PRIMARY KEY,
+    maker_user_id TEXT NOT NULL,
+    taker_user_id TEXT NOT NULL,
+    timestamp_us BIGINT NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS trades_by_maker ON trades (maker_user_id, timestamp_us);
+CREATE INDEX IF NOT EXISTS trades_by_taker ON trades (taker_user_id, timestamp_us);
+
+CREATE TABLE IF NOT EXISTS journals (
+    id BIGINT PRIMARY KEY,
+    body TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS outbox (
+    seq BIGSERIAL,
+    id TEXT PRIMARY KEY,
+    delivered_us BIGINT,
+    body TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS outbox_pending ON outbox (delivered_us, seq);
@@ -0,0 +1,10 @@
+-- Orders are paged by when they were accepted. Databases from before this
+-- have the time only in the JSON body, so it is copied out to the new column
+-- before the index over it is rebuilt.
+ALTER TABLE orders ADD COLUMN IF NOT EXISTS timestamp_us BIGINT;
+UPDATE orders SET timestamp_us =
+    round(extract(epoch FROM (body::json ->> 'timestamp')::timestamptz) * 1000000)::BIGINT
+WHERE timestamp_us IS NULL;
+ALTER TABLE orders ALTER COLUMN timestamp_us SET NOT NULL;
+DROP INDEX IF EXISTS orders_by_user;
+CREATE INDEX orders_by_user ON orders (user_id, timestamp_us);
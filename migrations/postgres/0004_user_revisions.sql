@@ -0,0 +1,3 @@
+-- Each save of an account moves its revision on, and a save made from an
+-- older read is refused, so concurrent changes are retried instead of lost.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS revision BIGINT NOT NULL DEFAULT 0;
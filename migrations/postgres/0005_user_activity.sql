@@ -0,0 +1,9 @@
+-- Each user's audit trail: sign-ins, API key changes and withdrawal address
+-- notices, newest first by time
+CREATE TABLE IF NOT EXISTS user_activity (
+    seq BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    at_us BIGINT NOT NULL,
+    body TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS user_activity_by_user ON user_activity (user_id, at_us, seq);
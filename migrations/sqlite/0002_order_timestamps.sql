@@ -0,0 +1,14 @@
+-- Orders are paged by when they were accepted. Databases from before this
+-- have the time only in the JSON body, so it is copied out to the new column,
+-- to the microsecond, before the index over it is rebuilt.
+ALTER TABLE orders ADD COLUMN timestamp_us INTEGER NOT NULL DEFAULT 0;
+UPDATE orders SET timestamp_us =
+    CAST(strftime('%s', json_extract(body, '$.timestamp')) AS INTEGER) * 1000000
+    + CASE WHEN instr(json_extract(body, '$.timestamp'), '.') > 0 THEN CAST(substr(
+        rtrim(substr(
+            json_extract(body, '$.timestamp'),
+            instr(json_extract(body, '$.timestamp'), '.') + 1
+        ), 'Z') || '000000', 1, 6) AS INTEGER)
+    ELSE 0 END;
+DROP INDEX IF EXISTS orders_by_user;
+CREATE INDEX orders_by_user ON orders (user_id, timestamp_us);
@@ -0,0 +1,149 @@
+//! HTTP client shared by the command-line tools. Tokens and passwords only
+//! leave this machine over TLS: plain `http://` is refused for other hosts.
+
+use reqwest::blocking;
+use reqwest::header::ACCEPT;
+use reqwest::redirect::Policy;
+use reqwest::{Method, Url};
+use serde_json::Value;
+use std::net::IpAddr;
+use std::time::Duration;
+
+pub const API_PREFIX: &str = "/api/v1";
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Blocking HTTP client for `https://` base URLs, or `http://` ones on this machine
+pub struct Client {
+    base: String,
+    http: blocking::Client,
+    token: Option<String>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Client {
+    pub fn new(url: &str, token: Option<String>) -> Result<Self, String> {
+        let parsed = Url::parse(url).map_err(|e| format!("Invalid URL '{}': {}", url, e))?;
+        match parsed.scheme() {
+            "https" => {}
+            "http" if is_local(&parsed) => {}
+            "http" => {
+                return Err(format!(
+                    "Refusing plain HTTP to '{}', use https:// or a local address",
+                    url
+                ))
+            }
+            _ => {
+                return Err(format!(
+                    "Only http:// and https:// URLs are supported, got '{}'",
+                    url
+                ))
+            }
+        }
+        // A redirect could lead the token somewhere the checks above never saw
+        let http = blocking::Client::builder()
+            .timeout(IO_TIMEOUT)
+            .redirect(Policy::none())
+            .build()
+            .map_err(|e| format!("Cannot set up the HTTP client: {}", e))?;
+        Ok(Client {
+            base: url.trim_end_matches('/').to_string(),
+            http,
+            token,
+        })
+    }
+
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<Response, String> {
+        let method = Method::from_bytes(method.as_bytes())
+            .map_err(|_| format!("Invalid HTTP method '{}'", method))?;
+        let mut request = self
+            .http
+            .request(method, format!("{}{}{}", self.base, API_PREFIX, path))
+            .header(ACCEPT, "application/json");
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        let response = request
+            .send()
+            .map_err(|e| format!("Request to {} failed: {}", self.base, e))?;
+        let status = response.status().as_u16();
+        let body = response
+            .bytes()
+            .map_err(|e| format!("Reading response failed: {}", e))?;
+        Ok(Response {
+            status,
+            body: parse_body(&body),
+        })
+    }
+}
+
+/// Whether `url` names this machine, so plain HTTP to it stays off the network
+fn is_local(url: &Url) -> bool {
+    let host = url.host_str().unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// A JSON body, or the text of one that is not JSON
+pub fn parse_body(body: &[u8]) -> Value {
+    if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_body_json_text_and_empty() {
+        assert_eq!(
+            parse_body(b"{\"error\":\"Order not found\"}")["error"],
+            "Order not found"
+        );
+        assert_eq!(
+            parse_body(b"Bad Gateway"),
+            Value::String("Bad Gateway".to_string())
+        );
+        assert_eq!(parse_body(b""), Value::Null);
+    }
+
+    #[test]
+    fn test_plain_http_is_only_allowed_to_this_machine() {
+        for url in [
+            "http://127.0.0.1:8080",
+            "http://localhost:8080/",
+            "http://[::1]:8080",
+            "https://exchange.example.com",
+        ] {
+            assert!(
+                Client::new(url, Some("token".to_string())).is_ok(),
+                "{}",
+                url
+            );
+        }
+        for url in [
+            "http://10.0.0.1:9000",
+            "http://exchange.example.com",
+            "ftp://127.0.0.1",
+        ] {
+            assert!(Client::new(url, None).is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! obctl: operator CLI for the orderbook admin API.
+//!
+//! Talks plain HTTP/1.1 to the server so it needs nothing beyond std and serde_json.
+
+#[path = "common/http.rs"]
+mod http;
+
+use http::{Client, Response};
+use serde_json::Value;
+use std::process::ExitCode;
+
+const DEFAULT_URL: &str = "http://127.0.0.1:8080";
+
+const USAGE: &str = "\
+Usage: obctl [--url URL] [--token TOKEN] [--json] <command> [args]
+
+Commands:
+  markets                                     List markets and their trading status
+  halt <market>                               Stop accepting new orders on a market
+  resume <market>                             Accept new orders again
+  cancel <order_id>                           Cancel any user's order
+  adjust-balance <user_id> <currency> <amount>
+                                              Credit an account (debit if amount < 0)
+  stats                                       Engine queue, metrics and account totals
+
+Options:
+  --url URL      Server address (env OBCTL_URL, default http://127.0.0.1:8080).
+                 Must be https:// unless the server runs on this machine
+  --token TOKEN  Admin token (env ADMIN_TOKEN)
+  --json         Print raw JSON instead of tables";
+
+struct Options {
+    url: String,
+    token: Option<String>,
+    json: bool,
+    command: Vec<String>,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        url: std::env::var("OBCTL_URL").unwrap_or_else(|_| DEFAULT_URL.to_string()),
+        token: std::env::var("ADMIN_TOKEN").ok(),
+        json: false,
+        command: Vec::new(),
+    };
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--url" => options.url = args.next().ok_or("--url needs a value")?,
+            "--token" => options.token = Some(args.next().ok_or("--token needs a value")?),
+            "--json" => options.json = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => options.command.push(arg),
+        }
+    }
+    if options.command.is_empty() {
+        return Err(USAGE.to_string());
+    }
+    Ok(options)
+}
+
+/// Print rows as aligned columns under `headers`
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let line = |cells: &[String]| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(&headers.iter().map(|h| h.to_string()).collect::<Vec<_>>());
+    line(&widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>());
+    for row in rows {
+        line(row);
+    }
+}
+
+/// Render a JSON scalar for a table cell
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let client = Client::new(&options.url, options.token.clone())?;
+    let args: Vec<&str> = options.command.iter().map(String::as_str).collect();
+
+    let (response, render): (Response, fn(&Value)) = match args.as_slice() {
+        ["markets"] => (client.request("GET", "/markets", None)?, render_markets),
+        ["halt", market] => (
+            client.request("POST", &format!("/admin/markets/{}/halt", market), None)?,
+            render_market,
+        ),
+        ["resume", market] => (
+            client.request("POST", &format!("/admin/markets/{}/resume", market), None)?,
+            render_market,
+        ),
+        ["cancel", order_id] => (
+            client.request("DELETE", &format!("/admin/orders/{}", order_id), None)?,
+            render_object,
+        ),
+        ["adjust-balance", user_id, currency, amount] => {
+            let amount: f64 = amount
+                .parse()
+                .map_err(|_| format!("'{}' is not a number", amount))?;
+            let body = serde_json::json!({
+                "user_id": user_id,
+                "currency": currency.to_uppercase(),
+                "amount": amount,
+            });
+            (
+                client.request("POST", "/admin/balances", Some(&body))?,
+                render_object,
+            )
+        }
+        ["stats"] => (
+            client.request(
+                "GET",
+                "/admin/dashboard/summary?depth=5&trades=0&users=0",
+                None,
+            )?,
+            render_stats,
+        ),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    if !(200..300).contains(&response.status) {
+        let message = response
+            .body
+            .get("error")
+            .map(cell)
+            .unwrap_or_else(|| cell(&response.body));
+        return Err(format!("HTTP {}: {}", response.status, message));
+    }
+
+    if options.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&response.body).unwrap_or_default()
+        );
+    } else {
+        render(&response.body);
+    }
+    Ok(())
+}
+
+fn market_row(market: &Value) -> Vec<String> {
+    vec![
+        cell(&market["symbol"]),
+        cell(&market["trading_status"]),
+        cell(&market["feed_mode"]),
+        cell(&market["sweep_limit"]["max_levels"]),
+        cell(&market["sweep_limit"]["max_notional"]),
+    ]
+}
+
+const MARKET_HEADERS: [&str; 5] = ["SYMBOL", "STATUS", "FEED", "MAX LEVELS", "MAX NOTIONAL"];
+
+fn render_markets(body: &Value) {
+    let rows: Vec<Vec<String>> = body["markets"]
+        .as_array()
+        .map(|markets| markets.iter().map(market_row).collect())
+        .unwrap_or_default();
+    print_table(&MARKET_HEADERS, &rows);
+}
+
+fn render_market(body: &Value) {
+    print_table(&MARKET_HEADERS, &[market_row(body)]);
+}
+
+fn render_object(body: &Value) {
+    let rows: Vec<Vec<String>> = body
+        .as_object()
+        .map(|fields| {
+            fields
+                .iter()
+                .map(|(key, value)| vec![key.clone(), cell(value)])
+                .collect()
+        })
+        .unwrap_or_default();
+    print_table(&["FIELD", "VALUE"], &rows);
+}
+
+fn render_stats(body: &Value) {
+    let engine = &body["engine"];
+    let mut rows = vec![
+        vec![
+            "queue".to_string(),
+            format!(
+                "{} / {}",
+                cell(&engine["queue_depth"]),
+                cell(&engine["queue_capacity"])
+            ),
+        ],
+        vec![
+            "resting orders".to_string(),
+            cell(&body["book"]["resting_orders"]),
+        ],
+        vec!["accounts".to_string(), cell(&body["users"]["count"])],
+    ];
+    if let Some(metrics) = engine["metrics"].as_object() {
+        rows.extend(
+            metrics
+                .iter()
+                .map(|(key, value)| vec![key.replace('_', " "), cell(value)]),
+        );
+    }
+    if let Some(totals) = body["users"]["balance_totals"].as_object() {
+        rows.extend(
+            totals
+                .iter()
+                .map(|(currency, total)| vec![format!("total {}", currency), cell(total)]),
+        );
+    }
+    print_table(&["STAT", "VALUE"], &rows);
+}
+
+fn main() -> ExitCode {
+    let result = parse_args(std::env::args().skip(1)).and_then(run);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args_flags_and_command() {
+        let args = ["--json", "halt", "--url", "http://10.0.0.1:9000", "BTC-USD"];
+        let options = parse_args(args.iter().map(|s| s.to_string())).unwrap();
+        assert!(options.json);
+        assert_eq!(options.url, "http://10.0.0.1:9000");
+        assert_eq!(options.command, vec!["halt", "BTC-USD"]);
+
+        assert!(parse_args(std::iter::empty()).is_err());
+        assert!(Client::new("http://example.com", None).is_err());
+    }
+}
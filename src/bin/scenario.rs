@@ -0,0 +1,504 @@
+//! scenario: a scripted two-user story played against a live server, checking
+//! every response along the way.
+//!
+//! Alice makes a market and Bob trades against it: both sign up, link and
+//! fund accounts, Alice quotes, Bob sells into her bid, Alice cancels what is
+//! left and both withdraw. Each step says what it did, so a run reads as a
+//! walkthrough of the API; the exit code is non-zero as soon as a response is
+//! not what the story expects.
+
+#[path = "common/http.rs"]
+mod http;
+
+use http::{Client, Response, API_PREFIX};
+use serde_json::{json, Value};
+use std::process::ExitCode;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_URL: &str = "http://127.0.0.1:8080";
+
+/// Where Alice quotes when the book is empty
+const DEFAULT_PRICE: f64 = 50_000.0;
+
+/// Size of Alice's quotes; Bob sells half of one
+const QUOTE_QUANTITY: f64 = 0.02;
+const TRADE_QUANTITY: f64 = 0.01;
+
+/// What the mock adapters accept: a bank account number, its micro-deposits
+/// and a wallet address
+const BANK_ACCOUNT: &str = "000123456789";
+const MICRO_DEPOSITS: [f64; 2] = [0.32, 0.45];
+const WALLET_ADDRESS: &str = "bc1qscenario0000000000000000";
+
+/// Slack for float rounding when comparing balances
+const TOLERANCE: f64 = 1e-6;
+
+const USAGE: &str = "\
+Usage: scenario [--url URL] [--trace]
+
+Plays one scripted story against the server and checks each response:
+   1. Alice and Bob sign up and sign in
+   2. Alice links a bank account and verifies it with the micro-deposits
+   3. Bob links a crypto wallet
+   4. Alice deposits USD from her bank, Bob deposits BTC from his wallet
+   5. Alice quotes two bids: one inside the spread, one well below it
+   6. Bob sells into Alice's inside bid
+   7. Both balances show the trade
+   8. Alice cancels her bids, by client order ID and by order ID
+   9. Alice withdraws USD to her bank, Bob withdraws BTC to his wallet
+
+Deposits go through mock funding sources, so the server must run a profile
+with the faucet on (APP_ENV=dev or staging, or FAUCET=true). Bob's sell only
+meets Alice's bid if nobody outbids her meanwhile; run the server with
+SIMULATOR_BOTS=0 for a quiet book.
+
+Options:
+  --url URL   Server address (env OBCTL_URL, default http://127.0.0.1:8080).
+              Must be https:// unless the server runs on this machine
+  --trace     Print every request and response";
+
+#[derive(Debug)]
+struct Options {
+    url: String,
+    trace: bool,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        url: std::env::var("OBCTL_URL").unwrap_or_else(|_| DEFAULT_URL.to_string()),
+        trace: false,
+    };
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--url" => options.url = args.next().ok_or("--url needs a value")?,
+            "--trace" => options.trace = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("Unknown argument '{}'\n\n{}", arg, USAGE)),
+        }
+    }
+    Ok(options)
+}
+
+fn round_to(value: f64, step: f64) -> f64 {
+    (value / step).round() * step
+}
+
+/// A price strictly between the best bid and ask, so a bid there is the top
+/// of the book. None if the spread leaves no room.
+fn inside_price(best_bid: Option<f64>, best_ask: Option<f64>) -> Option<f64> {
+    let price = match (best_bid, best_ask) {
+        (None, None) => DEFAULT_PRICE,
+        (Some(bid), None) => bid + 0.01,
+        (None, Some(ask)) => ask * 0.99,
+        (Some(bid), Some(ask)) => (bid + ask) / 2.0,
+    };
+    let price = round_to(price, 0.01);
+    let above_bid = best_bid.is_none_or(|bid| price > bid);
+    let below_ask = best_ask.is_none_or(|ask| price < ask);
+    (price > 0.0 && above_bid && below_ask).then_some(price)
+}
+
+/// One of the story's users, with the session they signed in with
+struct Actor {
+    name: &'static str,
+    client: Client,
+    funding_source_id: String,
+}
+
+struct Story {
+    url: String,
+    trace: bool,
+    run_id: u64,
+    alice: Option<Actor>,
+    bob: Option<Actor>,
+    price: f64,
+    inside_bid: String, // Order IDs of Alice's quotes
+    deep_bid: String,
+}
+
+impl Story {
+    fn alice(&self) -> &Actor {
+        self.alice.as_ref().expect("Alice has signed up")
+    }
+
+    fn bob(&self) -> &Actor {
+        self.bob.as_ref().expect("Bob has signed up")
+    }
+
+    fn client_order_id(&self) -> String {
+        format!("scenario-{:x}-inside", self.run_id)
+    }
+
+    /// Send a request and insist on a 2xx answer
+    fn call(
+        &self,
+        client: &Client,
+        method: &str,
+        path: &str,
+        body: Option<&Value>,
+    ) -> Result<Value, String> {
+        if self.trace {
+            let body = body.map(Value::to_string).unwrap_or_default();
+            println!("      > {} {}{} {}", method, API_PREFIX, path, body);
+        }
+        let Response { status, body } = client.request(method, path, body)?;
+        if self.trace {
+            println!("      < {} {}", status, body);
+        }
+        if !(200..300).contains(&status) {
+            return Err(format!(
+                "{} {} answered HTTP {}: {}",
+                method, path, status, body
+            ));
+        }
+        Ok(body)
+    }
+
+    fn sign_up(&self, name: &'static str) -> Result<Client, String> {
+        let anonymous = Client::new(&self.url, None)?;
+        let username = format!("scenario-{:x}-{}", self.run_id, name.to_lowercase());
+        let password = format!("scenario-{:x}", self.run_id);
+        let signup = json!({
+            "username": username,
+            "email": format!("{}@scenario.invalid", username),
+            "password": password,
+        });
+        self.call(&anonymous, "POST", "/auth/signup", Some(&signup))?;
+        let signin = json!({ "username": username, "password": password });
+        let body = self.call(&anonymous, "POST", "/auth/signin", Some(&signin))?;
+        let token = body["token"]
+            .as_str()
+            .ok_or_else(|| format!("Signin for {} returned no token: {}", name, body))?;
+        Client::new(&self.url, Some(token.to_string()))
+    }
+
+    fn balance(&self, actor: &Actor, currency: &str) -> Result<f64, String> {
+        let body = self.call(&actor.client, "GET", "/user/balance", None)?;
+        Ok(body["balances"][currency].as_f64().unwrap_or(0.0))
+    }
+
+    fn deposit(&self, actor: &Actor, currency: &str, amount: f64) -> Result<(), String> {
+        let body = json!({
+            "currency": currency,
+            "amount": amount,
+            "funding_source_id": actor.funding_source_id,
+        });
+        let body = self.call(&actor.client, "POST", "/user/onramp", Some(&body))?;
+        check_amount(
+            &format!("{}'s {} after depositing", actor.name, currency),
+            body["new_balance"].as_f64(),
+            amount,
+        )
+    }
+
+    fn withdraw(&self, actor: &Actor, currency: &str, amount: f64) -> Result<String, String> {
+        let before = self.balance(actor, currency)?;
+        let body = json!({ "funding_source_id": actor.funding_source_id, "amount": amount });
+        let body = self.call(&actor.client, "POST", "/user/withdraw", Some(&body))?;
+        check_amount(
+            &format!("{}'s {} after withdrawing", actor.name, currency),
+            body["new_balance"].as_f64(),
+            before - amount,
+        )?;
+        Ok(format!("{} withdrew {} {}", actor.name, amount, currency))
+    }
+
+    fn place_bid(&self, price: f64, client_order_id: Option<String>) -> Result<String, String> {
+        let body = json!({
+            "side": "buy",
+            "price": price,
+            "quantity": QUOTE_QUANTITY,
+            "client_order_id": client_order_id,
+        });
+        let body = self.call(&self.alice().client, "POST", "/orders/limit", Some(&body))?;
+        if body["trades_count"].as_u64() != Some(0) {
+            return Err(format!(
+                "Alice's bid at {} traded on arrival: {}",
+                price, body
+            ));
+        }
+        body["order_id"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| format!("Bid placed without an order_id: {}", body))
+    }
+
+    /// Best effort: leave nothing resting if the story stops halfway
+    fn clean_up(&self) {
+        for actor in [&self.alice, &self.bob].into_iter().flatten() {
+            let _ = actor
+                .client
+                .request("DELETE", "/orders/cancel-all", Some(&json!({})));
+        }
+    }
+}
+
+/// Render a JSON scalar for a message
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+fn check_amount(what: &str, actual: Option<f64>, expected: f64) -> Result<(), String> {
+    match actual {
+        Some(actual) if (actual - expected).abs() < TOLERANCE => Ok(()),
+        actual => Err(format!(
+            "{} should be {}, got {}",
+            what,
+            expected,
+            actual.map_or("nothing".to_string(), |a| a.to_string())
+        )),
+    }
+}
+
+type Step = (&'static str, fn(&mut Story) -> Result<String, String>);
+
+const STEPS: [Step; 9] = [
+    ("Alice and Bob sign up and sign in", sign_up),
+    ("Alice links her bank account and verifies it", link_bank),
+    ("Bob links his crypto wallet", link_wallet),
+    ("Both fund their accounts from their sources", fund),
+    ("Alice quotes two bids", quote),
+    ("Bob sells into Alice's inside bid", trade),
+    ("Both balances show the trade", check_balances),
+    ("Alice cancels her bids", cancel),
+    ("Alice and Bob withdraw", withdraw),
+];
+
+fn sign_up(story: &mut Story) -> Result<String, String> {
+    for name in ["Alice", "Bob"] {
+        let actor = Actor {
+            name,
+            client: story.sign_up(name)?,
+            funding_source_id: String::new(),
+        };
+        if name == "Alice" {
+            story.alice = Some(actor);
+        } else {
+            story.bob = Some(actor);
+        }
+    }
+    Ok(format!("run {:x}", story.run_id))
+}
+
+fn link_bank(story: &mut Story) -> Result<String, String> {
+    let alice = story.alice();
+    let body = json!({ "kind": "mock_bank", "label": "Checking", "details": BANK_ACCOUNT });
+    let source = story.call(&alice.client, "POST", "/user/funding-sources", Some(&body))?;
+    if source["status"] != "pending" {
+        return Err(format!("A new bank account should be pending: {}", source));
+    }
+    let source_id = source["id"].as_str().unwrap_or_default().to_string();
+
+    let path = format!("/user/funding-sources/{}/verify", source_id);
+    let body = json!({ "amounts": MICRO_DEPOSITS });
+    let source = story.call(&alice.client, "POST", &path, Some(&body))?;
+    if source["status"] != "verified" {
+        return Err(format!("The bank account should be verified: {}", source));
+    }
+    story.alice.as_mut().unwrap().funding_source_id = source_id;
+    Ok(format!("account {} verified", cell(&source["reference"])))
+}
+
+fn link_wallet(story: &mut Story) -> Result<String, String> {
+    let bob = story.bob();
+    let body = json!({ "kind": "mock_crypto_wallet", "label": "Cold", "details": WALLET_ADDRESS });
+    let source = story.call(&bob.client, "POST", "/user/funding-sources", Some(&body))?;
+    if source["status"] != "verified" {
+        return Err(format!("A wallet should be usable at once: {}", source));
+    }
+    story.bob.as_mut().unwrap().funding_source_id =
+        source["id"].as_str().unwrap_or_default().to_string();
+    Ok(format!("wallet {} verified", cell(&source["reference"])))
+}
+
+fn fund(story: &mut Story) -> Result<String, String> {
+    let book = story.call(&story.alice().client, "GET", "/orderbook", None)?;
+    let best = |side: &str| book[side].as_array()?.first()?["price"].as_f64();
+    story.price = inside_price(best("bids"), best("asks")).ok_or_else(|| {
+        format!(
+            "The spread leaves no room for Alice's bid (bid {:?}, ask {:?})",
+            best("bids"),
+            best("asks")
+        )
+    })?;
+
+    // Enough for both bids and Bob's sell, with room to spare for withdrawals
+    let usd = round_to((story.price * QUOTE_QUANTITY * 3.0).max(1_000.0), 0.01);
+    let btc = QUOTE_QUANTITY * 2.0;
+    story.deposit(story.alice(), "USD", usd)?;
+    story.deposit(story.bob(), "BTC", btc)?;
+    Ok(format!("Alice has {} USD, Bob {} BTC", usd, btc))
+}
+
+fn quote(story: &mut Story) -> Result<String, String> {
+    let deep_price = round_to(story.price / 2.0, 0.01);
+    story.inside_bid = story.place_bid(story.price, Some(story.client_order_id()))?;
+    story.deep_bid = story.place_bid(deep_price, None)?;
+    Ok(format!(
+        "{} BTC at {} and at {}",
+        QUOTE_QUANTITY, story.price, deep_price
+    ))
+}
+
+fn trade(story: &mut Story) -> Result<String, String> {
+    let body = json!({
+        "side": "sell",
+        "price": story.price,
+        "quantity": TRADE_QUANTITY,
+        "time_in_force": "ioc",
+    });
+    let body = story.call(&story.bob().client, "POST", "/orders/limit", Some(&body))?;
+    let trades = body["trades"].as_array().cloned().unwrap_or_default();
+    if trades.is_empty() {
+        return Err(format!("Bob's sell did not trade: {}", body));
+    }
+    if let Some(other) = trades
+        .iter()
+        .find(|trade| trade["maker_order_id"] != story.inside_bid.as_str())
+    {
+        return Err(format!(
+            "Bob traded with someone other than Alice; is the book quiet? {}",
+            other
+        ));
+    }
+    let filled: f64 = trades
+        .iter()
+        .filter_map(|trade| trade["quantity"].as_f64())
+        .sum::<f64>()
+        / 1e8;
+    check_amount("Bob's filled quantity", Some(filled), TRADE_QUANTITY)?;
+    Ok(format!(
+        "{} BTC at {} in {} trade(s)",
+        filled,
+        story.price,
+        trades.len()
+    ))
+}
+
+fn check_balances(story: &mut Story) -> Result<String, String> {
+    let alice_btc = story.balance(story.alice(), "BTC")?;
+    check_amount("Alice's BTC", Some(alice_btc), TRADE_QUANTITY)?;
+
+    // Bob receives the notional, less any taker fee the market charges
+    let notional = story.price * TRADE_QUANTITY;
+    let bob_usd = story.balance(story.bob(), "USD")?;
+    if !(bob_usd > 0.0 && bob_usd <= notional + TOLERANCE) {
+        return Err(format!(
+            "Bob's USD should be the {} notional less fees, got {}",
+            notional, bob_usd
+        ));
+    }
+    Ok(format!(
+        "Alice holds {} BTC, Bob {} USD",
+        alice_btc, bob_usd
+    ))
+}
+
+fn cancel(story: &mut Story) -> Result<String, String> {
+    let alice = story.alice();
+    let client_order_id = story.client_order_id();
+    for body in [
+        json!({ "client_order_id": client_order_id }),
+        json!({ "order_id": story.deep_bid }),
+    ] {
+        let response = story.call(&alice.client, "DELETE", "/orders/cancel", Some(&body))?;
+        if response["cancelled"] != true {
+            return Err(format!("Cancel was not confirmed: {}", response));
+        }
+    }
+
+    let path = format!("/orders/by-client-id/{}", client_order_id);
+    let order = story.call(&alice.client, "GET", &path, None)?;
+    if order["id"] != story.inside_bid.as_str() {
+        return Err(format!(
+            "{} should resolve to {}: {}",
+            client_order_id, story.inside_bid, order
+        ));
+    }
+    Ok(format!("{} and {}", client_order_id, story.deep_bid))
+}
+
+fn withdraw(story: &mut Story) -> Result<String, String> {
+    let alice = story.withdraw(story.alice(), "USD", 100.0)?;
+    let bob = story.withdraw(story.bob(), "BTC", TRADE_QUANTITY / 2.0)?;
+    Ok(format!("{}; {}", alice, bob))
+}
+
+fn run(options: Options) -> Result<bool, String> {
+    let mut story = Story {
+        url: options.url.clone(),
+        trace: options.trace,
+        run_id: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.as_millis() as u64),
+        alice: None,
+        bob: None,
+        price: 0.0,
+        inside_bid: String::new(),
+        deep_bid: String::new(),
+    };
+    // Fail fast with a clear message if nothing is listening
+    Client::new(&options.url, None)?.request("GET", "/health", None)?;
+
+    println!("Scenario against {}", options.url);
+    let started = Instant::now();
+    for (number, (title, step)) in STEPS.iter().enumerate() {
+        println!("{:>2}. {}", number + 1, title);
+        match step(&mut story) {
+            Ok(outcome) => println!("    ok: {}", outcome),
+            Err(message) => {
+                println!("    FAILED: {}", message);
+                story.clean_up();
+                println!("Scenario failed at step {} of {}", number + 1, STEPS.len());
+                return Ok(false);
+            }
+        }
+    }
+    println!(
+        "Scenario passed: {} steps in {:.1}s",
+        STEPS.len(),
+        started.elapsed().as_secs_f64()
+    );
+    Ok(true)
+}
+
+fn main() -> ExitCode {
+    match parse_args(std::env::args().skip(1)).and_then(run) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inside_price_stays_inside_the_spread() {
+        assert_eq!(inside_price(None, None), Some(DEFAULT_PRICE));
+        assert_eq!(inside_price(Some(100.0), Some(101.0)), Some(100.5));
+        assert_eq!(inside_price(Some(100.0), None), Some(100.01));
+        assert_eq!(inside_price(None, Some(100.0)), Some(99.0));
+        assert_eq!(inside_price(Some(100.0), Some(100.01)), None);
+        assert_eq!(inside_price(None, Some(0.01)), None);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = ["--url", "http://example:9000", "--trace"];
+        let options = parse_args(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(options.url, "http://example:9000");
+        assert!(options.trace);
+        assert!(parse_args(["--verbose"].iter().map(|s| s.to_string())).is_err());
+    }
+}
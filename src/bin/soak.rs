@@ -0,0 +1,1029 @@
+//! soak: long-running mixed load against a live server that keeps checking
+//! the books add up.
+//!
+//! A network of bots signs up, funds itself and trades through the public API
+//! for as long as asked, while the main thread polls the admin diagnostics
+//! endpoints and records every invariant that stops holding. At the end the
+//! bots cancel what they left resting and a report is printed; the exit code
+//! is non-zero if any invariant was violated.
+
+#[path = "common/http.rs"]
+mod http;
+
+use http::{Client, Response};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_URL: &str = "http://127.0.0.1:8080";
+
+/// Funds each bot starts with
+const STARTING_USD: f64 = 100_000.0;
+const STARTING_BTC: f64 = 2.0;
+
+/// Price the bots quote around until the book has traded
+const STARTING_MID: f64 = 50_000.0;
+
+/// Resting orders a bot keeps track of before it cancels its oldest
+const MAX_TRACKED_ORDERS: usize = 50;
+
+/// Slack for float rounding when comparing balance totals
+const TOLERANCE: f64 = 1e-6;
+
+/// Latency histogram resolution and range
+const LATENCY_BUCKET_MS: u64 = 1;
+const LATENCY_BUCKETS: usize = 10_000;
+
+const USAGE: &str = "\
+Usage: soak [--url URL] [--token TOKEN] [options]
+
+Runs bots against the server and checks, every interval, that:
+  - the ledger's trial balance is balanced
+  - no account balance is negative
+  - users together hold no more than was ever deposited, plus interest paid
+After the run the bots cancel their resting orders and the gap between the
+ledger and the engine's balances must be back where it started.
+
+Bots fund themselves through /user/onramp, so the server must run a profile
+with the faucet on (APP_ENV=dev or staging, or FAUCET=true).
+
+Options:
+  --url URL            Server address (env OBCTL_URL, default http://127.0.0.1:8080).
+                       Must be https:// unless the server runs on this machine
+  --token TOKEN        Admin token (env ADMIN_TOKEN)
+  --bots N             Concurrent bots (default 8)
+  --duration D         How long to run, e.g. 90s, 30m, 4h (default 10m)
+  --check-every D      Time between invariant checks (default 30s)
+  --think D            Pause between a bot's requests (default 50ms)
+  --seed N             Seed for the bots' choices (default: clock)
+  --report PATH        Also write the report as JSON to PATH";
+
+#[derive(Debug)]
+struct Options {
+    url: String,
+    token: Option<String>,
+    bots: usize,
+    duration: Duration,
+    check_every: Duration,
+    think: Duration,
+    seed: u64,
+    report: Option<String>,
+}
+
+/// Parse `90s`, `30m`, `4h`, `250ms` or a bare number of seconds
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{}'", value);
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "" | "s" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount * 60)),
+        "h" => Ok(Duration::from_secs(amount * 3600)),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        url: std::env::var("OBCTL_URL").unwrap_or_else(|_| DEFAULT_URL.to_string()),
+        token: std::env::var("ADMIN_TOKEN").ok(),
+        bots: 8,
+        duration: Duration::from_secs(600),
+        check_every: Duration::from_secs(30),
+        think: Duration::from_millis(50),
+        seed: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64),
+        report: None,
+    };
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--url" => options.url = value()?,
+            "--token" => options.token = Some(value()?),
+            "--bots" => {
+                options.bots = value()?
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or("--bots must be a positive number")?
+            }
+            "--duration" => options.duration = parse_duration(&value()?)?,
+            "--check-every" => options.check_every = parse_duration(&value()?)?,
+            "--think" => options.think = parse_duration(&value()?)?,
+            "--seed" => options.seed = value()?.parse().map_err(|_| "--seed must be a number")?,
+            "--report" => options.report = Some(value()?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => return Err(format!("Unknown argument '{}'\n\n{}", arg, USAGE)),
+        }
+    }
+    if options.token.is_none() {
+        return Err(format!("An admin token is required\n\n{}", USAGE));
+    }
+    Ok(options)
+}
+
+/// xorshift64*: plenty for picking flows, and reproducible from `--seed`
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn range(&mut self, low: f64, high: f64) -> f64 {
+        low + (high - low) * self.unit()
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.unit() < probability
+    }
+}
+
+/// What a bot does on one turn
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Flow {
+    Quote,
+    Cross,
+    Market,
+    Iceberg,
+    Hidden,
+    Pegged,
+    MinFill,
+    Cancel,
+    Deposit,
+}
+
+/// Relative frequency of each flow, loosely after a retail-heavy venue
+const FLOW_WEIGHTS: [(Flow, u32); 9] = [
+    (Flow::Quote, 35),
+    (Flow::Cross, 10),
+    (Flow::Market, 10),
+    (Flow::Iceberg, 5),
+    (Flow::Hidden, 5),
+    (Flow::Pegged, 5),
+    (Flow::MinFill, 5),
+    (Flow::Cancel, 20),
+    (Flow::Deposit, 5),
+];
+
+impl Flow {
+    fn pick(rng: &mut Rng) -> Flow {
+        let total: u32 = FLOW_WEIGHTS.iter().map(|(_, weight)| weight).sum();
+        let mut roll = (rng.next_u64() % total as u64) as u32;
+        for (flow, weight) in FLOW_WEIGHTS {
+            if roll < weight {
+                return flow;
+            }
+            roll -= weight;
+        }
+        Flow::Quote
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Flow::Quote => "quote",
+            Flow::Cross => "cross",
+            Flow::Market => "market",
+            Flow::Iceberg => "iceberg",
+            Flow::Hidden => "hidden",
+            Flow::Pegged => "pegged",
+            Flow::MinFill => "min-fill",
+            Flow::Cancel => "cancel",
+            Flow::Deposit => "deposit",
+        }
+    }
+}
+
+/// Outcome counts for one flow
+#[derive(Debug, Default, Clone)]
+struct FlowStats {
+    sent: u64,
+    accepted: u64,
+    rejected: u64, // 4xx: expected now and then, e.g. insufficient funds
+    failed: u64,   // 5xx or transport errors
+}
+
+/// Everything the bots report back, shared behind one lock
+#[derive(Debug)]
+struct Stats {
+    flows: BTreeMap<Flow, FlowStats>,
+    trades: u64,
+    deposited: BTreeMap<String, f64>,
+    latency: Vec<u64>,                 // Request count per LATENCY_BUCKET_MS bucket
+    rejections: BTreeMap<String, u64>, // Count per rejection message
+    errors: Vec<String>,               // First few failures, for the report
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            flows: BTreeMap::new(),
+            trades: 0,
+            deposited: BTreeMap::new(),
+            latency: vec![0; LATENCY_BUCKETS],
+            rejections: BTreeMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, flow: Flow, elapsed: Duration, result: &Result<Response, String>) {
+        let bucket = (elapsed.as_millis() as u64 / LATENCY_BUCKET_MS) as usize;
+        self.latency[bucket.min(LATENCY_BUCKETS - 1)] += 1;
+
+        let stats = self.flows.entry(flow).or_default();
+        stats.sent += 1;
+        let failure = match result {
+            Ok(response) if (200..300).contains(&response.status) => {
+                stats.accepted += 1;
+                None
+            }
+            Ok(response) if (400..500).contains(&response.status) => {
+                stats.rejected += 1;
+                let reason = response.body["error"].as_str().unwrap_or("unknown");
+                *self.rejections.entry(reason.to_string()).or_insert(0) += 1;
+                None
+            }
+            Ok(response) => Some(format!("{}: HTTP {}", flow.name(), response.status)),
+            Err(message) => Some(format!("{}: {}", flow.name(), message)),
+        };
+        if let Some(failure) = failure {
+            stats.failed += 1;
+            if self.errors.len() < 20 {
+                self.errors.push(failure);
+            }
+        }
+    }
+
+    /// Latency below which `quantile` of all requests completed, in ms
+    fn latency_ms(&self, quantile: f64) -> u64 {
+        let total: u64 = self.latency.iter().sum();
+        let target = (total as f64 * quantile).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.latency.iter().enumerate() {
+            seen += count;
+            if seen >= target.max(1) {
+                return bucket as u64 * LATENCY_BUCKET_MS;
+            }
+        }
+        0
+    }
+}
+
+/// Mid price the bots quote around, stored as f64 bits so every bot can nudge it
+struct SharedMid(AtomicU64);
+
+impl SharedMid {
+    fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set(&self, price: f64) {
+        self.0.store(price.to_bits(), Ordering::Relaxed);
+    }
+}
+
+fn round_to(value: f64, step: f64) -> f64 {
+    (value / step).round() * step
+}
+
+struct Bot {
+    client: Client,
+    rng: Rng,
+    open_orders: Vec<String>,
+    stats: Arc<Mutex<Stats>>,
+    mid: Arc<SharedMid>,
+}
+
+impl Bot {
+    /// Sign up a fresh user, sign in and fund the account
+    fn join(
+        url: &str,
+        index: usize,
+        run_id: u64,
+        rng: Rng,
+        stats: Arc<Mutex<Stats>>,
+        mid: Arc<SharedMid>,
+    ) -> Result<Bot, String> {
+        let anonymous = Client::new(url, None)?;
+        let username = format!("soak-{:x}-{}", run_id, index);
+        let password = format!("soak-{:x}", run_id);
+        let signup = json!({
+            "username": username,
+            "email": format!("{}@soak.invalid", username),
+            "password": password,
+        });
+        let response = anonymous.request("POST", "/auth/signup", Some(&signup))?;
+        if !(200..300).contains(&response.status) {
+            return Err(format!(
+                "Signup failed: HTTP {} {}",
+                response.status, response.body
+            ));
+        }
+        let signin = json!({ "username": username, "password": password });
+        let response = anonymous.request("POST", "/auth/signin", Some(&signin))?;
+        let token = response.body["token"]
+            .as_str()
+            .ok_or_else(|| format!("Signin failed: HTTP {} {}", response.status, response.body))?
+            .to_string();
+
+        let mut bot = Bot {
+            client: Client::new(url, Some(token))?,
+            rng,
+            open_orders: Vec::new(),
+            stats,
+            mid,
+        };
+        for (currency, amount) in [("USD", STARTING_USD), ("BTC", STARTING_BTC)] {
+            let response = bot.deposit(currency, amount)?;
+            if !(200..300).contains(&response.status) {
+                return Err(format!(
+                    "Funding failed: HTTP {} {}",
+                    response.status, response.body
+                ));
+            }
+        }
+        Ok(bot)
+    }
+
+    fn deposit(&mut self, currency: &str, amount: f64) -> Result<Response, String> {
+        let body = json!({ "currency": currency, "amount": amount });
+        let response = self.client.request("POST", "/user/onramp", Some(&body))?;
+        if (200..300).contains(&response.status) {
+            let mut stats = self.stats.lock().unwrap();
+            *stats.deposited.entry(currency.to_string()).or_insert(0.0) += amount;
+        }
+        Ok(response)
+    }
+
+    fn side(&mut self) -> &'static str {
+        if self.rng.chance(0.5) {
+            "buy"
+        } else {
+            "sell"
+        }
+    }
+
+    /// Price `spread` (a fraction of mid) on the passive side of the mid
+    fn passive_price(&mut self, side: &str, spread: f64) -> f64 {
+        let mid = self.mid.get();
+        let offset = mid * spread;
+        let price = if side == "buy" {
+            mid - offset
+        } else {
+            mid + offset
+        };
+        round_to(price, 0.01)
+    }
+
+    fn quantity(&mut self, low: f64, high: f64) -> f64 {
+        round_to(self.rng.range(low, high), 0.0001)
+    }
+
+    /// Body for a limit order resting up to half a percent away from mid
+    fn limit_body(&mut self) -> Value {
+        let side = self.side();
+        let spread = self.rng.range(0.0, 0.005);
+        json!({
+            "side": side,
+            "price": self.passive_price(side, spread),
+            "quantity": self.quantity(0.001, 0.2),
+        })
+    }
+
+    fn request(&mut self, flow: Flow) -> Result<Response, String> {
+        match flow {
+            Flow::Quote => {
+                let body = self.limit_body();
+                self.client.request("POST", "/orders/limit", Some(&body))
+            }
+            Flow::Cross => {
+                let side = self.side();
+                let body = json!({
+                    "side": side,
+                    "price": self.passive_price(side, -0.002),
+                    "quantity": self.quantity(0.001, 0.1),
+                    "time_in_force": "ioc",
+                });
+                self.client.request("POST", "/orders/limit", Some(&body))
+            }
+            Flow::Market => {
+                let body = json!({ "side": self.side(), "quantity": self.quantity(0.001, 0.05) });
+                self.client.request("POST", "/orders/market", Some(&body))
+            }
+            Flow::Iceberg => {
+                let mut body = self.limit_body();
+                let quantity = body["quantity"].as_f64().unwrap_or(0.1);
+                body["quantity"] = json!(round_to(quantity * 5.0, 0.0001));
+                body["display_quantity"] = json!(quantity);
+                self.client.request("POST", "/orders/limit", Some(&body))
+            }
+            Flow::Hidden => {
+                let mut body = self.limit_body();
+                body["hidden"] = json!(true);
+                self.client.request("POST", "/orders/limit", Some(&body))
+            }
+            Flow::Pegged => {
+                let side = self.side();
+                let offset = round_to(self.rng.range(0.0, 5.0), 0.01);
+                let body = json!({
+                    "side": side,
+                    "quantity": self.quantity(0.001, 0.1),
+                    "peg": if self.rng.chance(0.5) { "mid" } else if side == "buy" { "bid" } else { "ask" },
+                    "offset": if side == "buy" { -offset } else { offset },
+                });
+                self.client.request("POST", "/orders/pegged", Some(&body))
+            }
+            Flow::MinFill => {
+                let mut body = self.limit_body();
+                let quantity = body["quantity"].as_f64().unwrap_or(0.1);
+                body["min_fill_qty"] = json!(round_to(quantity / 2.0, 0.0001).max(0.0001));
+                self.client.request("POST", "/orders/limit", Some(&body))
+            }
+            Flow::Cancel => match self.take_open_order() {
+                Some(order_id) => self.cancel(&order_id),
+                None => {
+                    let body = self.limit_body();
+                    self.client.request("POST", "/orders/limit", Some(&body))
+                }
+            },
+            Flow::Deposit => {
+                if self.rng.chance(0.5) {
+                    let amount = round_to(self.rng.range(100.0, 5_000.0), 0.01);
+                    self.deposit("USD", amount)
+                } else {
+                    let amount = self.quantity(0.01, 0.2);
+                    self.deposit("BTC", amount)
+                }
+            }
+        }
+    }
+
+    fn take_open_order(&mut self) -> Option<String> {
+        if self.open_orders.is_empty() {
+            return None;
+        }
+        let index = (self.rng.next_u64() % self.open_orders.len() as u64) as usize;
+        Some(self.open_orders.swap_remove(index))
+    }
+
+    fn cancel(&mut self, order_id: &str) -> Result<Response, String> {
+        let body = json!({ "order_id": order_id });
+        self.client.request("DELETE", "/orders/cancel", Some(&body))
+    }
+
+    /// Follow the last trade price, with a little noise so quotes keep moving
+    fn observe(&mut self, body: &Value) {
+        let last_price = body["trades"]
+            .as_array()
+            .and_then(|trades| trades.last())
+            .and_then(|trade| trade["price"].as_f64())
+            .map(|raw| raw / 1e6);
+        let drift = self.rng.range(-0.0005, 0.0005);
+        let mid = last_price.unwrap_or_else(|| self.mid.get());
+        self.mid.set(round_to(mid * (1.0 + drift), 0.01));
+    }
+
+    fn turn(&mut self) {
+        let flow = Flow::pick(&mut self.rng);
+        let started = Instant::now();
+        let result = self.request(flow);
+        let elapsed = started.elapsed();
+
+        if let Ok(response) = &result {
+            if let Some(order_id) = response.body["order_id"].as_str() {
+                self.open_orders.push(order_id.to_string());
+            }
+            let trades = response.body["trades"].as_array().map_or(0, Vec::len);
+            self.observe(&response.body);
+            self.stats.lock().unwrap().trades += trades as u64;
+        }
+        self.stats.lock().unwrap().record(flow, elapsed, &result);
+
+        if self.open_orders.len() > MAX_TRACKED_ORDERS {
+            let oldest = self.open_orders.remove(0);
+            let started = Instant::now();
+            let result = self.cancel(&oldest);
+            self.stats
+                .lock()
+                .unwrap()
+                .record(Flow::Cancel, started.elapsed(), &result);
+        }
+    }
+
+    /// Cancel everything this bot may still have resting. Orders that filled
+    /// in the meantime are simply not found.
+    fn drain(&mut self) {
+        for order_id in std::mem::take(&mut self.open_orders) {
+            let _ = self.cancel(&order_id);
+        }
+    }
+}
+
+/// Balances as the admin diagnostics endpoints report them at one moment
+#[derive(Debug, Clone, Default)]
+struct Sample {
+    elapsed_secs: u64,
+    engine_totals: BTreeMap<String, f64>, // Sum of every user's available balance
+    ledger_user_totals: BTreeMap<String, f64>, // Sum of every user account in the ledger
+    treasury_paid: BTreeMap<String, f64>, // Interest paid out of the treasury so far
+    resting_orders: u64,
+    queue_depth: u64,
+}
+
+impl Sample {
+    /// Ledger holdings the engine does not show as available: funds reserved
+    /// for resting orders, trades not yet netted, or a leak
+    fn unaccounted(&self, currency: &str) -> f64 {
+        self.ledger_user_totals
+            .get(currency)
+            .copied()
+            .unwrap_or(0.0)
+            - self.engine_totals.get(currency).copied().unwrap_or(0.0)
+    }
+
+    fn currencies(&self) -> Vec<String> {
+        let mut currencies: Vec<String> = self
+            .engine_totals
+            .keys()
+            .chain(self.ledger_user_totals.keys())
+            .cloned()
+            .collect();
+        currencies.sort();
+        currencies.dedup();
+        currencies
+    }
+}
+
+fn fetch(admin: &Client, path: &str) -> Result<Value, String> {
+    let response = admin.request("GET", path, None)?;
+    if !(200..300).contains(&response.status) {
+        return Err(format!(
+            "GET {}: HTTP {} {}",
+            path, response.status, response.body
+        ));
+    }
+    Ok(response.body)
+}
+
+/// Read the diagnostics endpoints and return the sample along with every
+/// invariant the server itself reports as broken
+fn sample(admin: &Client, started: Instant) -> Result<(Sample, Vec<String>), String> {
+    let mut violations = Vec::new();
+    let mut sample = Sample {
+        elapsed_secs: started.elapsed().as_secs(),
+        ..Sample::default()
+    };
+
+    let trial_balance = fetch(admin, "/admin/ledger/trial-balance")?;
+    if trial_balance["balanced"] != Value::Bool(true) {
+        for total in trial_balance["totals"].as_array().into_iter().flatten() {
+            if total["balanced"] != Value::Bool(true) {
+                violations.push(format!(
+                    "ledger out of balance in {}: debits {} credits {}",
+                    total["currency"], total["debits"], total["credits"]
+                ));
+            }
+        }
+    }
+    for row in trial_balance["rows"].as_array().into_iter().flatten() {
+        let account = row["account"].as_str().unwrap_or_default();
+        let currency = row["currency"].as_str().unwrap_or_default().to_string();
+        let net = row["debit"].as_f64().unwrap_or(0.0) - row["credit"].as_f64().unwrap_or(0.0);
+        if account.starts_with("user:") {
+            *sample.ledger_user_totals.entry(currency).or_insert(0.0) += net;
+        } else if account.starts_with("exchange:treasury:") {
+            sample.treasury_paid.insert(currency, -net);
+        }
+    }
+
+    let summary = fetch(admin, "/admin/dashboard/summary?depth=1&trades=0&users=500")?;
+    if let Some(totals) = summary["users"]["balance_totals"].as_object() {
+        for (currency, total) in totals {
+            sample
+                .engine_totals
+                .insert(currency.clone(), total.as_f64().unwrap_or(0.0));
+        }
+    }
+    for account in summary["users"]["accounts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        for (currency, balance) in account["balances"].as_object().into_iter().flatten() {
+            if balance.as_f64().unwrap_or(0.0) < -TOLERANCE {
+                violations.push(format!(
+                    "negative {} balance {} for user {}",
+                    currency, balance, account["user_id"]
+                ));
+            }
+        }
+    }
+    sample.resting_orders = summary["book"]["resting_orders"].as_u64().unwrap_or(0);
+    sample.queue_depth = summary["engine"]["queue_depth"].as_u64().unwrap_or(0);
+
+    Ok((sample, violations))
+}
+
+/// Users may only hold what was there before, what the bots deposited and
+/// what the treasury paid out as interest
+fn check_conservation(
+    baseline: &Sample,
+    sample: &Sample,
+    deposited: &BTreeMap<String, f64>,
+) -> Vec<String> {
+    let mut violations = Vec::new();
+    for currency in sample.currencies() {
+        let held = sample.engine_totals.get(&currency).copied().unwrap_or(0.0);
+        let before = baseline
+            .engine_totals
+            .get(&currency)
+            .copied()
+            .unwrap_or(0.0);
+        let added = deposited.get(&currency).copied().unwrap_or(0.0);
+        let interest = sample.treasury_paid.get(&currency).copied().unwrap_or(0.0)
+            - baseline
+                .treasury_paid
+                .get(&currency)
+                .copied()
+                .unwrap_or(0.0);
+        let ceiling = before + added + interest;
+        if held > ceiling + TOLERANCE {
+            violations.push(format!(
+                "users hold {:.8} {} but at most {:.8} ever came in",
+                held, currency, ceiling
+            ));
+        }
+    }
+    violations
+}
+
+/// Once nothing rests, what the ledger holds for users and what the engine
+/// shows them should differ exactly as much as before the run
+fn check_leaks(baseline: &Sample, last: &Sample) -> Vec<String> {
+    last.currencies()
+        .into_iter()
+        .filter_map(|currency| {
+            let drift = last.unaccounted(&currency) - baseline.unaccounted(&currency);
+            (drift.abs() > TOLERANCE).then(|| {
+                format!(
+                    "{:.8} {} in the ledger is no longer in any balance after draining",
+                    drift, currency
+                )
+            })
+        })
+        .collect()
+}
+
+struct Report {
+    options_summary: Value,
+    elapsed: Duration,
+    stats: Stats,
+    samples: Vec<Sample>,
+    violations: Vec<(u64, String)>, // Seconds into the run, what broke
+    check_errors: u64,
+}
+
+impl Report {
+    /// Most frequent rejection messages, most common first
+    fn top_rejections(&self, count: usize) -> Vec<(&str, u64)> {
+        let mut reasons: Vec<(&str, u64)> = self
+            .stats
+            .rejections
+            .iter()
+            .map(|(reason, times)| (reason.as_str(), *times))
+            .collect();
+        reasons.sort_by_key(|(_, times)| std::cmp::Reverse(*times));
+        reasons.truncate(count);
+        reasons
+    }
+
+    fn to_json(&self) -> Value {
+        let flows: BTreeMap<&str, Value> = self
+            .stats
+            .flows
+            .iter()
+            .map(|(flow, stats)| {
+                (
+                    flow.name(),
+                    json!({
+                        "sent": stats.sent,
+                        "accepted": stats.accepted,
+                        "rejected": stats.rejected,
+                        "failed": stats.failed,
+                    }),
+                )
+            })
+            .collect();
+        let samples: Vec<Value> = self
+            .samples
+            .iter()
+            .map(|sample| {
+                let unaccounted: BTreeMap<String, f64> = sample
+                    .currencies()
+                    .into_iter()
+                    .map(|currency| {
+                        let gap = sample.unaccounted(&currency);
+                        (currency, gap)
+                    })
+                    .collect();
+                json!({
+                    "elapsed_secs": sample.elapsed_secs,
+                    "engine_totals": sample.engine_totals,
+                    "ledger_user_totals": sample.ledger_user_totals,
+                    "unaccounted": unaccounted,
+                    "resting_orders": sample.resting_orders,
+                    "queue_depth": sample.queue_depth,
+                })
+            })
+            .collect();
+        json!({
+            "options": self.options_summary,
+            "elapsed_secs": self.elapsed.as_secs(),
+            "passed": self.violations.is_empty(),
+            "requests": flows,
+            "trades": self.stats.trades,
+            "deposited": self.stats.deposited,
+            "latency_ms": {
+                "p50": self.stats.latency_ms(0.50),
+                "p99": self.stats.latency_ms(0.99),
+                "p999": self.stats.latency_ms(0.999),
+            },
+            "rejections": self.stats.rejections,
+            "errors": self.stats.errors,
+            "check_errors": self.check_errors,
+            "violations": self
+                .violations
+                .iter()
+                .map(|(at, message)| json!({ "elapsed_secs": at, "message": message }))
+                .collect::<Vec<_>>(),
+            "samples": samples,
+        })
+    }
+
+    fn print(&self) {
+        println!("Soak run: {}s", self.elapsed.as_secs());
+        println!();
+        println!(
+            "{:<10} {:>10} {:>10} {:>10} {:>10}",
+            "FLOW", "SENT", "ACCEPTED", "REJECTED", "FAILED"
+        );
+        for (flow, stats) in &self.stats.flows {
+            println!(
+                "{:<10} {:>10} {:>10} {:>10} {:>10}",
+                flow.name(),
+                stats.sent,
+                stats.accepted,
+                stats.rejected,
+                stats.failed
+            );
+        }
+        println!();
+        println!("trades        {}", self.stats.trades);
+        println!(
+            "latency ms    p50 {}  p99 {}  p99.9 {}",
+            self.stats.latency_ms(0.50),
+            self.stats.latency_ms(0.99),
+            self.stats.latency_ms(0.999)
+        );
+        println!(
+            "checks        {} ({} failed to run)",
+            self.samples.len(),
+            self.check_errors
+        );
+        if let (Some(first), Some(last)) = (self.samples.first(), self.samples.last()) {
+            for currency in last.currencies() {
+                println!(
+                    "unaccounted   {} {:.8} -> {:.8}",
+                    currency,
+                    first.unaccounted(&currency),
+                    last.unaccounted(&currency)
+                );
+            }
+        }
+        for (reason, times) in self.top_rejections(5) {
+            println!("rejected      {:>6}x {}", times, reason);
+        }
+        for error in &self.stats.errors {
+            println!("error         {}", error);
+        }
+        println!();
+        if self.violations.is_empty() {
+            println!("PASS: every invariant held");
+        } else {
+            println!("FAIL: {} violation(s)", self.violations.len());
+            for (at, message) in &self.violations {
+                println!("  [{}s] {}", at, message);
+            }
+        }
+    }
+}
+
+fn run(options: Options) -> Result<bool, String> {
+    let admin = Client::new(&options.url, options.token.clone())?;
+    let started = Instant::now();
+    let (baseline, baseline_violations) = sample(&admin, started)?;
+    if !baseline_violations.is_empty() {
+        return Err(format!(
+            "Invariants already broken before the run:\n  {}",
+            baseline_violations.join("\n  ")
+        ));
+    }
+
+    let stats = Arc::new(Mutex::new(Stats::new()));
+    let mid = Arc::new(SharedMid(AtomicU64::new(STARTING_MID.to_bits())));
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut seeds = Rng::new(options.seed);
+
+    let mut bots = Vec::new();
+    for index in 0..options.bots {
+        let bot = Bot::join(
+            &options.url,
+            index,
+            options.seed,
+            Rng::new(seeds.next_u64()),
+            stats.clone(),
+            mid.clone(),
+        )?;
+        let stop = stop.clone();
+        let think = options.think;
+        bots.push(thread::spawn(move || {
+            let mut bot = bot;
+            while !stop.load(Ordering::Relaxed) {
+                bot.turn();
+                thread::sleep(think);
+            }
+            bot.drain();
+        }));
+    }
+    eprintln!(
+        "{} bots trading for {}s, checking every {}s",
+        options.bots,
+        options.duration.as_secs(),
+        options.check_every.as_secs()
+    );
+
+    let mut samples = vec![baseline.clone()];
+    let mut violations = Vec::new();
+    let mut check_errors = 0;
+    let mut check = |violations: &mut Vec<(u64, String)>, samples: &mut Vec<Sample>| match sample(
+        &admin, started,
+    ) {
+        Ok((sample, found)) => {
+            let deposited = stats.lock().unwrap().deposited.clone();
+            let at = sample.elapsed_secs;
+            for message in found
+                .into_iter()
+                .chain(check_conservation(&baseline, &sample, &deposited))
+            {
+                eprintln!("[{}s] VIOLATION {}", at, message);
+                violations.push((at, message));
+            }
+            samples.push(sample);
+        }
+        Err(message) => {
+            eprintln!("Check failed: {}", message);
+            check_errors += 1;
+        }
+    };
+
+    while started.elapsed() < options.duration {
+        let left = options.duration.saturating_sub(started.elapsed());
+        thread::sleep(options.check_every.min(left));
+        check(&mut violations, &mut samples);
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for bot in bots {
+        bot.join()
+            .map_err(|_| "A bot thread panicked".to_string())?;
+    }
+    // Let the last trades net before comparing ledger and engine
+    thread::sleep(Duration::from_secs(2));
+    check(&mut violations, &mut samples);
+    if let Some(last) = samples.last() {
+        let at = last.elapsed_secs;
+        violations.extend(check_leaks(&baseline, last).into_iter().map(|m| (at, m)));
+    }
+
+    let stats = Arc::try_unwrap(stats)
+        .map_err(|_| "Bot statistics still shared".to_string())?
+        .into_inner()
+        .unwrap();
+    let report = Report {
+        options_summary: json!({
+            "url": options.url,
+            "bots": options.bots,
+            "duration_secs": options.duration.as_secs(),
+            "check_every_secs": options.check_every.as_secs(),
+            "seed": options.seed,
+        }),
+        elapsed: started.elapsed(),
+        stats,
+        samples,
+        violations,
+        check_errors,
+    };
+    report.print();
+    if let Some(path) = &options.report {
+        let json = serde_json::to_string_pretty(&report.to_json()).unwrap_or_default();
+        std::fs::write(path, json).map_err(|e| format!("Cannot write {}: {}", path, e))?;
+    }
+    Ok(report.violations.is_empty())
+}
+
+fn main() -> ExitCode {
+    match parse_args(std::env::args().skip(1)).and_then(run) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_durations_and_args() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("4h").unwrap(), Duration::from_secs(4 * 3600));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("15").unwrap(), Duration::from_secs(15));
+        assert!(parse_duration("soon").is_err());
+
+        let args = [
+            "--token",
+            "t",
+            "--bots",
+            "3",
+            "--duration",
+            "2m",
+            "--seed",
+            "7",
+        ];
+        let options = parse_args(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(options.bots, 3);
+        assert_eq!(options.duration, Duration::from_secs(120));
+        assert_eq!(options.seed, 7);
+        assert!(parse_args(["--bots", "0"].iter().map(|s| s.to_string())).is_err());
+    }
+
+    #[test]
+    fn test_conservation_and_leak_checks() {
+        let totals = |usd: f64| BTreeMap::from([("USD".to_string(), usd)]);
+        let baseline = Sample {
+            engine_totals: totals(1_000.0),
+            ledger_user_totals: totals(1_000.0),
+            ..Sample::default()
+        };
+        let deposited = totals(500.0);
+
+        // Resting orders hold 200 back; nothing was created
+        let trading = Sample {
+            engine_totals: totals(1_300.0),
+            ledger_user_totals: totals(1_500.0),
+            ..Sample::default()
+        };
+        assert!(check_conservation(&baseline, &trading, &deposited).is_empty());
+
+        let minted = Sample {
+            engine_totals: totals(1_600.0),
+            ..trading.clone()
+        };
+        assert_eq!(check_conservation(&baseline, &minted, &deposited).len(), 1);
+
+        // After draining, the 200 should be back in balances
+        assert_eq!(check_leaks(&baseline, &trading).len(), 1);
+        let drained = Sample {
+            engine_totals: totals(1_500.0),
+            ..trading
+        };
+        assert!(check_leaks(&baseline, &drained).is_empty());
+    }
+}
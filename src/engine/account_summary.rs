@@ -0,0 +1,22 @@
+use crate::engine::{Incident, MarginAssessment};
+use crate::types::{FeeSchedule, Order, UserTrade};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Most recent fills a summary may carry
+pub const MAX_SUMMARY_FILLS: usize = 100;
+
+/// Everything a trading UI shows for one account on first load, taken in one
+/// engine step so the parts are consistent with each other
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountSummary {
+    pub user_id: Uuid,
+    pub balances: HashMap<String, f64>,
+    pub open_orders: Vec<Order>,      // Resting orders, oldest first
+    pub recent_fills: Vec<UserTrade>, // Newest first
+    pub position: Option<MarginAssessment>, // None while the book has no mark price
+    pub fees: FeeSchedule,            // Every account pays the market's schedule
+    pub alerts: Vec<Incident>,        // Open incidents against the account, newest first
+    pub quarantined: bool,
+}
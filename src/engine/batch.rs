@@ -0,0 +1,101 @@
+use crate::messages::OrderBookCommand;
+use tokio::sync::mpsc;
+
+/// Most commands pulled off the queue in one go
+pub const MAX_BATCH_SIZE: usize = 256;
+
+/// Collect `first` plus whatever is already queued behind it, without waiting
+pub fn drain_batch(
+    first: OrderBookCommand,
+    rx: &mut mpsc::Receiver<OrderBookCommand>,
+) -> Vec<OrderBookCommand> {
+    let mut batch = Vec::with_capacity(rx.len().min(MAX_BATCH_SIZE) + 1);
+    batch.push(first);
+    while batch.len() < MAX_BATCH_SIZE {
+        match rx.try_recv() {
+            Ok(command) => batch.push(command),
+            Err(_) => break,
+        }
+    }
+    batch
+}
+
+/// Stable-partition `batch` so cancels run before everything else, keeping the
+/// relative order within each group. Returns how many cancels moved ahead of
+/// some other command.
+///
+/// This never reorders a cancel ahead of the placement of its own order: a client
+/// only learns an order ID from the placement response, so that placement has
+/// already been processed by the time the cancel is queued.
+pub fn prioritize_cancels(batch: &mut Vec<OrderBookCommand>) -> usize {
+    let first_other = match batch.iter().position(|c| !c.is_cancel()) {
+        Some(i) => i,
+        None => return 0,
+    };
+
+    let (cancels, others): (Vec<_>, Vec<_>) = batch.drain(..).partition(|c| c.is_cancel());
+    let moved = cancels.len() - first_other;
+    batch.extend(cancels);
+    batch.extend(others);
+    moved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+    use uuid::Uuid;
+
+    fn cancel() -> OrderBookCommand {
+        OrderBookCommand::CancelOrder {
+            user_id: Uuid::new_v4(),
+            order_id: Uuid::new_v4(),
+            response_tx: oneshot::channel().0,
+        }
+    }
+
+    fn add_funds() -> OrderBookCommand {
+        OrderBookCommand::AddFunds {
+            user_id: Uuid::new_v4(),
+            currency: "USD".to_string(),
+            amount: 1.0,
+            response_tx: oneshot::channel().0,
+        }
+    }
+
+    #[test]
+    fn test_cancels_move_to_front_in_order() {
+        let mut batch = vec![cancel(), add_funds(), cancel(), add_funds(), cancel()];
+        let ids: Vec<Uuid> = batch
+            .iter()
+            .filter_map(|c| match c {
+                OrderBookCommand::CancelOrder { order_id, .. } => Some(*order_id),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(prioritize_cancels(&mut batch), 2);
+
+        let reordered: Vec<Uuid> = batch
+            .iter()
+            .take(3)
+            .filter_map(|c| match c {
+                OrderBookCommand::CancelOrder { order_id, .. } => Some(*order_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reordered, ids);
+        assert!(!batch[3].is_cancel() && !batch[4].is_cancel());
+    }
+
+    #[tokio::test]
+    async fn test_drain_batch_takes_queued_commands() {
+        let (tx, mut rx) = mpsc::channel(8);
+        tx.send(add_funds()).await.unwrap();
+        tx.send(cancel()).await.unwrap();
+
+        let batch = drain_batch(add_funds(), &mut rx);
+        assert_eq!(batch.len(), 3);
+        assert!(rx.try_recv().is_err());
+    }
+}
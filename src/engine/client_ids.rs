@@ -0,0 +1,168 @@
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Longest client order ID accepted
+pub const MAX_CLIENT_ORDER_ID_LEN: usize = 64;
+
+/// How often `prune` sweeps the index
+const PRUNE_INTERVAL: chrono::Duration = chrono::Duration::seconds(60);
+
+/// Each user's client order IDs, with the order and time each was last used
+pub type ClientIdRecords = HashMap<Uuid, HashMap<String, (Uuid, DateTime<Utc>)>>;
+
+/// Per-user index from client order IDs to the orders that carry them, so
+/// callers can cancel and look up orders by their own reference. An ID may
+/// not be reused by the same user within `window`; after that it points at
+/// the most recent order placed with it. IDs are forgotten once their window
+/// has passed and their order is no longer live.
+pub struct ClientOrderIds {
+    window: Duration,
+    by_user: ClientIdRecords,
+    next_prune: Option<DateTime<Utc>>,
+}
+
+impl ClientOrderIds {
+    pub fn new(window: Duration) -> Self {
+        ClientOrderIds {
+            window,
+            by_user: HashMap::new(),
+            next_prune: None,
+        }
+    }
+
+    /// Reject `client_order_id` if it is malformed or the user already used
+    /// it within the window. Zero window allows reuse at any time.
+    pub fn check(
+        &self,
+        user_id: Uuid,
+        client_order_id: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        if client_order_id.is_empty() || client_order_id.len() > MAX_CLIENT_ORDER_ID_LEN {
+            return Err(format!(
+                "client_order_id must be 1 to {} characters",
+                MAX_CLIENT_ORDER_ID_LEN
+            ));
+        }
+        if self.window.is_zero() {
+            return Ok(());
+        }
+
+        let window = chrono::Duration::from_std(self.window).unwrap_or(chrono::Duration::MAX);
+        let previous = self
+            .by_user
+            .get(&user_id)
+            .and_then(|ids| ids.get(client_order_id));
+        match previous {
+            Some((order_id, placed_at)) if now - *placed_at < window => Err(format!(
+                "Duplicate client_order_id '{}': already used by order {}",
+                client_order_id, order_id
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remember an accepted order under its client order ID
+    pub fn record(
+        &mut self,
+        user_id: Uuid,
+        client_order_id: &str,
+        order_id: Uuid,
+        placed_at: DateTime<Utc>,
+    ) {
+        self.by_user
+            .entry(user_id)
+            .or_default()
+            .insert(client_order_id.to_string(), (order_id, placed_at));
+    }
+
+    /// Forget every ID whose reuse window has passed and whose order
+    /// `is_live` says is no longer resting or waiting, so the index only keeps
+    /// open orders and recent ones. Sweeps at most once a minute; returns how
+    /// many IDs were forgotten.
+    pub fn prune(&mut self, now: DateTime<Utc>, is_live: impl Fn(Uuid) -> bool) -> usize {
+        if self.next_prune.is_some_and(|at| now < at) {
+            return 0;
+        }
+        self.next_prune = now.checked_add_signed(PRUNE_INTERVAL);
+
+        let window = chrono::Duration::from_std(self.window).unwrap_or(chrono::Duration::MAX);
+        let mut pruned = 0;
+        self.by_user.retain(|_, ids| {
+            let before = ids.len();
+            ids.retain(|_, (order_id, placed_at)| now - *placed_at < window || is_live(*order_id));
+            pruned += before - ids.len();
+            !ids.is_empty()
+        });
+        pruned
+    }
+
+    pub fn records(&self) -> &ClientIdRecords {
+        &self.by_user
+    }
+
+    pub fn restore(&mut self, records: ClientIdRecords) {
+        self.by_user = records;
+    }
+
+    /// The user's most recent order placed with `client_order_id`
+    pub fn resolve(&self, user_id: Uuid, client_order_id: &str) -> Option<Uuid> {
+        self.by_user
+            .get(&user_id)?
+            .get(client_order_id)
+            .map(|(order_id, _)| *order_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse_is_rejected_only_inside_the_window() {
+        let mut ids = ClientOrderIds::new(Duration::from_secs(60));
+        let (user, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+
+        assert!(ids.check(user, "", now).is_err());
+        assert!(ids.check(user, &"x".repeat(65), now).is_err());
+
+        ids.check(user, "grid-1", now).unwrap();
+        ids.record(user, "grid-1", first, now);
+        assert!(ids.check(user, "grid-1", now).is_err());
+        assert!(ids.check(other, "grid-1", now).is_ok());
+        assert_eq!(ids.resolve(user, "grid-1"), Some(first));
+        assert_eq!(ids.resolve(other, "grid-1"), None);
+
+        let later = now + chrono::Duration::seconds(61);
+        ids.check(user, "grid-1", later).unwrap();
+        ids.record(user, "grid-1", second, later);
+        assert_eq!(ids.resolve(user, "grid-1"), Some(second));
+    }
+
+    #[test]
+    fn test_prune_forgets_ids_of_finished_orders_once_their_window_passes() {
+        let mut ids = ClientOrderIds::new(Duration::from_secs(60));
+        let user = Uuid::new_v4();
+        let (open, closed, recent) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        ids.record(user, "open", open, now);
+        ids.record(user, "closed", closed, now);
+        let later = now + chrono::Duration::seconds(90);
+        ids.record(user, "recent", recent, later);
+
+        assert_eq!(ids.prune(later, |id| id == open), 1);
+        assert_eq!(ids.resolve(user, "open"), Some(open));
+        assert_eq!(ids.resolve(user, "closed"), None);
+        assert_eq!(ids.resolve(user, "recent"), Some(recent));
+
+        // Swept again only after the interval
+        let much_later = later + chrono::Duration::seconds(30);
+        assert_eq!(ids.prune(much_later, |_| false), 0);
+        assert_eq!(ids.prune(much_later + PRUNE_INTERVAL, |_| false), 2);
+        assert!(ids.records().is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Longest single step the test clock may be advanced by
+pub const MAX_CLOCK_ADVANCE: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+/// The time scheduled engine work runs on: the wall clock plus an offset
+/// that only ever grows. Integration environments advance it to fire
+/// expiries, dead man's switches, interest and day roll-overs without
+/// waiting for them in real time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EngineClock {
+    offset: Duration,
+    pinned: Option<DateTime<Utc>>, // Set while replaying a logged command
+}
+
+impl EngineClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        if let Some(pinned) = self.pinned {
+            return pinned;
+        }
+        // `advance` keeps the offset in range, so this only saturates once
+        // the wall clock itself runs out
+        chrono::Duration::from_std(self.offset)
+            .ok()
+            .and_then(|offset| Utc::now().checked_add_signed(offset))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    /// Shift a monotonic reading by the same offset
+    pub fn shift(&self, instant: Instant) -> Instant {
+        instant.checked_add(self.offset).unwrap_or(instant)
+    }
+
+    /// How far ahead of the wall clock the engine runs
+    pub fn offset(&self) -> Duration {
+        self.offset
+    }
+
+    /// Move the clock forward by `by`. Refused without effect if either
+    /// reading would then be out of range.
+    pub fn advance(&mut self, by: Duration) -> Result<(), String> {
+        let overflow = || format!("Advancing the clock by {}s would overflow it", by.as_secs());
+        let offset = self.offset.checked_add(by).ok_or_else(overflow)?;
+        let wall_clock = chrono::Duration::from_std(offset)
+            .ok()
+            .and_then(|offset| Utc::now().checked_add_signed(offset));
+        if wall_clock.is_none() || Instant::now().checked_add(offset).is_none() {
+            return Err(overflow());
+        }
+        self.offset = offset;
+        Ok(())
+    }
+
+    /// Read `at` until unpinned, so a replayed command sees the time it was
+    /// first run at
+    pub fn pin(&mut self, at: DateTime<Utc>) {
+        self.pinned = Some(at);
+    }
+
+    pub fn unpin(&mut self) {
+        self.pinned = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advancing_moves_both_readings_forward() {
+        let mut clock = EngineClock::new();
+        let start = Instant::now();
+        assert!(clock.now() - Utc::now() < chrono::Duration::seconds(1));
+
+        clock.advance(Duration::from_secs(3600)).unwrap();
+        clock.advance(Duration::from_secs(60)).unwrap();
+        assert_eq!(clock.offset(), Duration::from_secs(3660));
+        assert!(clock.now() - Utc::now() >= chrono::Duration::seconds(3659));
+        assert_eq!(clock.shift(start), start + Duration::from_secs(3660));
+
+        let logged_at = Utc::now() - chrono::Duration::days(1);
+        clock.pin(logged_at);
+        assert_eq!(clock.now(), logged_at);
+        clock.unpin();
+        assert!(clock.now() > Utc::now());
+    }
+
+    #[test]
+    fn test_advancing_out_of_range_is_refused() {
+        let mut clock = EngineClock::new();
+        clock.advance(Duration::from_secs(60)).unwrap();
+
+        assert!(clock.advance(Duration::MAX).is_err());
+        // Past the last date chrono can represent
+        let millennia = Duration::from_secs(300_000 * 366 * 24 * 60 * 60);
+        assert!(clock.advance(millennia).is_err());
+        assert_eq!(clock.offset(), Duration::from_secs(60));
+        assert!(clock.now() > Utc::now());
+    }
+}
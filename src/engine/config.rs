@@ -0,0 +1,203 @@
+use crate::engine::jetstream::JetStreamConfig;
+use crate::engine::kafka::KafkaConfig;
+use crate::engine::{OutboxTarget, RateCurve, WalConfig};
+use crate::state::{public_depth_limit_from_env, Profile, DEFAULT_PUBLIC_DEPTH_LIMIT};
+use crate::storage::StorageBackend;
+use crate::types::{
+    AllocationPolicy, ClearingMode, FeeSchedule, FeedMode, LeverageTiers, MarketConfig, Price,
+    SweepLimit, TradeThroughBand,
+};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const DEFAULT_TRADE_TAPE_CAPACITY: usize = 10_000;
+pub const DEFAULT_CANCEL_PRIORITY_THRESHOLD: usize = 64;
+pub const DEFAULT_DUPLICATE_ORDER_WINDOW: Duration = Duration::from_millis(250);
+pub const DEFAULT_CLIENT_ORDER_ID_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+pub const DEFAULT_NETTING_WINDOW: Duration = Duration::from_secs(1);
+pub const DEFAULT_ORDER_HISTORY_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Tunables for the engine task, resolved once at startup
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// Where finalized daily market statistics are appended; None keeps them in memory only
+    pub stats_path: Option<PathBuf>,
+    /// Where synthetic index definitions are kept; None keeps them in memory only
+    pub index_path: Option<PathBuf>,
+    /// Where orders, trades and ledger journals are persisted
+    pub storage: StorageBackend,
+    /// How many trades the public tape retains before evicting the oldest
+    pub trade_tape_capacity: usize,
+    /// Identical orders from the same user inside this window are rejected as retries.
+    /// Zero disables the check.
+    pub duplicate_order_window: Duration,
+    /// A user may not reuse a client order ID within this window. Zero allows reuse.
+    pub client_order_id_window: Duration,
+    /// How long closed orders and their fills stay in the engine's memory
+    /// after closing; older ones are read back from the store
+    pub order_history_retention: Duration,
+    /// Once this many commands are waiting, cancels are processed ahead of new orders
+    pub cancel_priority_threshold: usize,
+    /// Settings of the market this engine runs
+    pub market: MarketConfig,
+    /// Most levels per side the market feeds publish, the same public depth
+    /// limit the order book endpoint serves without a bearer token
+    pub public_depth_levels: usize,
+    /// How long trades accumulate before a netted-clearing market settles them
+    pub netting_window: Duration,
+    /// Default leverage brackets for margin calculations; operators can replace them at runtime
+    pub leverage_tiers: LeverageTiers,
+    /// Interest curves for idle balances by currency; empty turns interest off
+    pub interest_rates: BTreeMap<String, RateCurve>,
+    /// Where trades, order updates and balance changes are delivered as outbox events;
+    /// None leaves them queued
+    pub outbox: Option<OutboxTarget>,
+    /// Where accepted commands are logged before they run, to be replayed after a crash;
+    /// None runs without a log
+    pub wal: Option<WalConfig>,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            stats_path: None,
+            index_path: None,
+            storage: StorageBackend::Memory,
+            trade_tape_capacity: DEFAULT_TRADE_TAPE_CAPACITY,
+            duplicate_order_window: Duration::ZERO,
+            client_order_id_window: Duration::ZERO,
+            order_history_retention: DEFAULT_ORDER_HISTORY_RETENTION,
+            cancel_priority_threshold: DEFAULT_CANCEL_PRIORITY_THRESHOLD,
+            market: MarketConfig::default(),
+            public_depth_levels: DEFAULT_PUBLIC_DEPTH_LIMIT,
+            netting_window: DEFAULT_NETTING_WINDOW,
+            leverage_tiers: LeverageTiers::default(),
+            interest_rates: BTreeMap::new(),
+            outbox: None,
+            wal: None,
+        }
+    }
+}
+
+/// Parse an environment variable, ignoring it if unset or malformed
+pub(crate) fn env_parse<T: FromStr>(key: &str) -> Option<T> {
+    std::env::var(key).ok().and_then(|v| v.parse().ok())
+}
+
+impl EngineConfig {
+    /// Read configuration from environment variables, with defaults from
+    /// `profile`. A malformed `STORAGE_URL` is an error, since falling back
+    /// to memory would drop everything written.
+    pub fn from_env(profile: &Profile) -> Result<Self, String> {
+        let stats_path = match std::env::var("STATS_PATH") {
+            Ok(path) if path.is_empty() => None,
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) if profile.persist_stats => Some(PathBuf::from("data/daily_stats.jsonl")),
+            Err(_) => None,
+        };
+        let index_path = match std::env::var("INDEX_DEFINITIONS_PATH") {
+            Ok(path) if path.is_empty() => None,
+            Ok(path) => Some(PathBuf::from(path)),
+            Err(_) if profile.persist_stats => Some(PathBuf::from("data/synthetic_indices.json")),
+            Err(_) => None,
+        };
+
+        // STORAGE_URL=memory, sqlite:<path> or postgres://...
+        let storage = match std::env::var("STORAGE_URL") {
+            Ok(url) => url.parse().map_err(|e| format!("STORAGE_URL: {}", e))?,
+            Err(_) if profile.persist_stats => {
+                StorageBackend::Sqlite(PathBuf::from("data/orderbook.db"))
+            }
+            Err(_) => StorageBackend::Memory,
+        };
+
+        let trade_tape_capacity =
+            env_parse("TRADE_TAPE_CAPACITY").unwrap_or(DEFAULT_TRADE_TAPE_CAPACITY);
+
+        let duplicate_order_window = env_parse("DUPLICATE_ORDER_WINDOW_MS")
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_DUPLICATE_ORDER_WINDOW);
+
+        let client_order_id_window = env_parse("CLIENT_ORDER_ID_WINDOW_SECS")
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_CLIENT_ORDER_ID_WINDOW);
+
+        let order_history_retention = env_parse("ORDER_HISTORY_RETENTION_SECS")
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_ORDER_HISTORY_RETENTION);
+
+        let cancel_priority_threshold = env_parse("CANCEL_PRIORITY_THRESHOLD")
+            .unwrap_or(DEFAULT_CANCEL_PRIORITY_THRESHOLD);
+
+        let market = MarketConfig {
+            feed_mode: env_parse::<FeedMode>("FEED_MODE").unwrap_or_default(),
+            sweep_limit: SweepLimit {
+                max_levels: env_parse("MAX_SWEEP_LEVELS"),
+                max_notional: env_parse("MAX_SWEEP_NOTIONAL"),
+            },
+            clearing_mode: env_parse::<ClearingMode>("CLEARING_MODE").unwrap_or_default(),
+            allocation: env_parse::<AllocationPolicy>("ALLOCATION_POLICY").unwrap_or_default(),
+            fees: FeeSchedule {
+                maker_rate: env_parse("MAKER_FEE_RATE").unwrap_or(0.0),
+                taker_rate: env_parse("TAKER_FEE_RATE").unwrap_or(0.0),
+            },
+            trade_through: TradeThroughBand {
+                reference_price: env_parse::<Price>("TRADE_THROUGH_REFERENCE_PRICE"),
+                band_bps: env_parse("TRADE_THROUGH_BAND_BPS").unwrap_or(0),
+            },
+            expires_at: env_parse("MARKET_EXPIRES_AT"), // RFC 3339
+            ..MarketConfig::default()
+        };
+
+        let netting_window = env_parse("NETTING_WINDOW_MS")
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_NETTING_WINDOW);
+
+        // INTEREST_RATES_USD="1000:0.05,*:0.01" etc.; currencies left unset pay nothing
+        let interest_rates = ["USD", "BTC"]
+            .into_iter()
+            .filter_map(|currency| {
+                let curve = env_parse::<RateCurve>(&format!("INTEREST_RATES_{}", currency))?;
+                Some((currency.to_string(), curve))
+            })
+            .collect();
+
+        let outbox_webhook_url = std::env::var("OUTBOX_WEBHOOK_URL")
+            .ok()
+            .filter(|url| !url.is_empty());
+        // The first one set is used
+        let mut targets = [
+            outbox_webhook_url.map(|url| ("OUTBOX_WEBHOOK_URL", OutboxTarget::Webhook(url))),
+            JetStreamConfig::from_env().map(|config| ("NATS_URL", OutboxTarget::JetStream(config))),
+            KafkaConfig::from_env().map(|config| ("KAFKA_BROKERS", OutboxTarget::Kafka(config))),
+        ]
+        .into_iter()
+        .flatten();
+        let outbox = targets.next().map(|(used, target)| {
+            for (ignored, _) in targets {
+                eprintln!("Both {} and {} are set; using {}", used, ignored, used);
+            }
+            target
+        });
+
+        Ok(EngineConfig {
+            stats_path,
+            index_path,
+            storage,
+            trade_tape_capacity,
+            duplicate_order_window,
+            client_order_id_window,
+            order_history_retention,
+            cancel_priority_threshold,
+            market,
+            public_depth_levels: public_depth_limit_from_env(),
+            netting_window,
+            leverage_tiers: LeverageTiers::default(),
+            interest_rates,
+            outbox,
+            wal: WalConfig::from_env(),
+        })
+    }
+}
@@ -0,0 +1,63 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Control messages are few and small; a handful in flight is plenty
+pub const CONTROL_CHANNEL_CAPACITY: usize = 8;
+
+/// Messages for the engine itself rather than the book. They travel on their
+/// own channel and are answered ahead of queued trading commands, so a health
+/// check never waits behind a backlog of orders.
+#[derive(Debug)]
+pub enum ControlCommand {
+    Ping {
+        response_tx: oneshot::Sender<EngineHealth>,
+    },
+    /// Finish the batch in hand, then stop
+    Shutdown,
+}
+
+/// The engine's answer to a ping
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineHealth {
+    pub queued_commands: usize, // Trading commands waiting, in the queue and the current batch
+    pub answered_at: DateTime<Utc>,
+}
+
+pub fn control_channel() -> (mpsc::Sender<ControlCommand>, mpsc::Receiver<ControlCommand>) {
+    mpsc::channel(CONTROL_CHANNEL_CAPACITY)
+}
+
+/// Ping the engine. None if it is gone or did not answer within `timeout`.
+pub async fn ping_engine(
+    control_tx: &mpsc::Sender<ControlCommand>,
+    timeout: Duration,
+) -> Option<EngineHealth> {
+    let (response_tx, response_rx) = oneshot::channel();
+    tokio::time::timeout(timeout, async {
+        control_tx
+            .send(ControlCommand::Ping { response_tx })
+            .await
+            .ok()?;
+        response_rx.await.ok()
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Answer one control command. False once the engine should stop.
+pub fn handle_control(command: ControlCommand, queued_commands: usize) -> bool {
+    match command {
+        ControlCommand::Ping { response_tx } => {
+            // The pinger may have timed out already; nothing to do then
+            let _ = response_tx.send(EngineHealth {
+                queued_commands,
+                answered_at: Utc::now(),
+            });
+            true
+        }
+        ControlCommand::Shutdown => false,
+    }
+}
@@ -0,0 +1,267 @@
+use crate::types::{Trade, DEFAULT_MARKET};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Aggregated activity for one market over one UTC day
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DailyMarketStats {
+    pub market: String,
+    pub date: NaiveDate,
+    pub volume: f64,       // Base asset traded
+    pub quote_volume: f64, // Notional traded in the quote asset
+    pub trades: u64,
+    pub unique_traders: u64,
+    pub open_orders_eod: u64, // Resting orders when the day closed (or now, if still open)
+    pub complete: bool,       // false for the day currently in progress
+}
+
+/// Append-only JSON-lines store of finalized daily statistics
+pub struct DailyStatsStore {
+    path: Option<PathBuf>,
+    records: Vec<DailyMarketStats>,
+}
+
+impl DailyStatsStore {
+    /// Open the store, loading previously persisted days if `path` exists
+    pub fn open(path: Option<PathBuf>) -> Result<Self, String> {
+        let mut records = Vec::new();
+
+        if let Some(path) = path.as_ref().filter(|p| p.exists()) {
+            let file = fs::File::open(path)
+                .map_err(|e| format!("Failed to open stats store: {}", e))?;
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|e| format!("Failed to read stats store: {}", e))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record = serde_json::from_str(&line)
+                    .map_err(|e| format!("Corrupt stats record: {}", e))?;
+                records.push(record);
+            }
+        }
+
+        Ok(DailyStatsStore { path, records })
+    }
+
+    pub fn in_memory() -> Self {
+        DailyStatsStore {
+            path: None,
+            records: Vec::new(),
+        }
+    }
+
+    pub fn append(&mut self, record: DailyMarketStats) -> Result<(), String> {
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)
+                    .map_err(|e| format!("Failed to create stats directory: {}", e))?;
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Failed to open stats store: {}", e))?;
+            let line = serde_json::to_string(&record)
+                .map_err(|e| format!("Failed to serialize stats: {}", e))?;
+            writeln!(file, "{}", line).map_err(|e| format!("Failed to write stats: {}", e))?;
+        }
+
+        self.records.push(record);
+        Ok(())
+    }
+
+    pub fn query(&self, market: &str, from: NaiveDate, to: NaiveDate) -> Vec<DailyMarketStats> {
+        self.records
+            .iter()
+            .filter(|r| r.market == market && r.date >= from && r.date <= to)
+            .cloned()
+            .collect()
+    }
+}
+
+/// The current day's running totals, as saved in an engine snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DayInProgress {
+    pub date: NaiveDate,
+    pub volume: f64,
+    pub quote_volume: f64,
+    pub trades: u64,
+    pub traders: Vec<Uuid>,
+}
+
+/// Accumulates the current day's activity and rolls it into the store at midnight UTC.
+/// The book only changes when a command is processed, so checking for a rollover
+/// before each command yields exact end-of-day open order counts.
+pub struct DailyStatsRecorder {
+    store: DailyStatsStore,
+    current_date: NaiveDate,
+    volume: f64,
+    quote_volume: f64,
+    trades: u64,
+    traders: HashSet<Uuid>,
+}
+
+impl DailyStatsRecorder {
+    pub fn new(store: DailyStatsStore, now: DateTime<Utc>) -> Self {
+        DailyStatsRecorder {
+            store,
+            current_date: now.date_naive(),
+            volume: 0.0,
+            quote_volume: 0.0,
+            trades: 0,
+            traders: HashSet::new(),
+        }
+    }
+
+    /// Finalize every day that ended before `now`. Idle days in between are recorded
+    /// with zero volume and the (unchanged) open order count.
+    pub fn roll_over(&mut self, now: DateTime<Utc>, open_orders: u64) -> Result<(), String> {
+        let today = now.date_naive();
+
+        while self.current_date < today {
+            let record = self.current_stats(open_orders, true);
+            self.store.append(record)?;
+
+            self.current_date = self.current_date.succ_opt().unwrap_or(today);
+            self.volume = 0.0;
+            self.quote_volume = 0.0;
+            self.trades = 0;
+            self.traders.clear();
+        }
+
+        Ok(())
+    }
+
+    pub fn record_trades(&mut self, trades: &[Trade]) {
+        for trade in trades {
+            let quantity = trade.quantity.to_f64();
+            self.volume += quantity;
+            self.quote_volume += trade.price.to_f64() * quantity;
+            self.trades += 1;
+            self.traders.insert(trade.maker_user_id);
+            self.traders.insert(trade.taker_user_id);
+        }
+    }
+
+    pub fn in_progress(&self) -> DayInProgress {
+        DayInProgress {
+            date: self.current_date,
+            volume: self.volume,
+            quote_volume: self.quote_volume,
+            trades: self.trades,
+            traders: self.traders.iter().copied().collect(),
+        }
+    }
+
+    /// Carry on the running totals of `day` if it is still the current day.
+    /// A day that has finished since is not rolled into the store again.
+    pub fn resume(&mut self, day: DayInProgress) {
+        if day.date != self.current_date {
+            return;
+        }
+        self.volume = day.volume;
+        self.quote_volume = day.quote_volume;
+        self.trades = day.trades;
+        self.traders = day.traders.into_iter().collect();
+    }
+
+    /// Finalized days in `[from, to]`, plus today's running totals if in range
+    pub fn query(
+        &self,
+        market: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+        open_orders: u64,
+    ) -> Vec<DailyMarketStats> {
+        let mut stats = self.store.query(market, from, to);
+        if market == DEFAULT_MARKET && self.current_date >= from && self.current_date <= to {
+            stats.push(self.current_stats(open_orders, false));
+        }
+        stats
+    }
+
+    fn current_stats(&self, open_orders: u64, complete: bool) -> DailyMarketStats {
+        DailyMarketStats {
+            market: DEFAULT_MARKET.to_string(),
+            date: self.current_date,
+            volume: self.volume,
+            quote_volume: self.quote_volume,
+            trades: self.trades,
+            unique_traders: self.traders.len() as u64,
+            open_orders_eod: open_orders,
+            complete,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Price, Quantity};
+    use chrono::TimeZone;
+
+    fn trade(maker: Uuid, taker: Uuid) -> Trade {
+        Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            maker,
+            taker,
+            Price::from_f64(100.0),
+            Quantity::from_f64(2.0),
+        )
+    }
+
+    #[test]
+    fn test_rollover_finalizes_days_and_fills_gaps() {
+        let day1 = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let day3 = Utc.with_ymd_and_hms(2024, 1, 3, 0, 0, 1).unwrap();
+        let mut recorder = DailyStatsRecorder::new(DailyStatsStore::in_memory(), day1);
+
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        recorder.record_trades(&[trade(alice, bob), trade(bob, alice)]);
+        recorder.roll_over(day3, 4).unwrap();
+
+        let from = day1.date_naive();
+        let to = day3.date_naive();
+        let stats = recorder.query(DEFAULT_MARKET, from, to, 4);
+
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats[0].trades, 2);
+        assert_eq!(stats[0].volume, 4.0);
+        assert_eq!(stats[0].quote_volume, 400.0);
+        assert_eq!(stats[0].unique_traders, 2);
+        assert!(stats[0].complete);
+        assert_eq!(stats[1].trades, 0);
+        assert_eq!(stats[1].open_orders_eod, 4);
+        assert!(!stats[2].complete);
+    }
+
+    #[test]
+    fn test_store_round_trip() {
+        let path = std::env::temp_dir().join(format!("daily_stats_{}.jsonl", Uuid::new_v4()));
+        let date = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let record = DailyMarketStats {
+            market: DEFAULT_MARKET.to_string(),
+            date,
+            volume: 1.5,
+            quote_volume: 150.0,
+            trades: 3,
+            unique_traders: 2,
+            open_orders_eod: 1,
+            complete: true,
+        };
+
+        let mut store = DailyStatsStore::open(Some(path.clone())).unwrap();
+        store.append(record.clone()).unwrap();
+
+        let reopened = DailyStatsStore::open(Some(path.clone())).unwrap();
+        assert_eq!(reopened.query(DEFAULT_MARKET, date, date), vec![record]);
+
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::engine::{TapeEntry, TradeTape};
+use crate::orderbook::{DepthLevel, OrderBook};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// One account as seen by operators
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserSummary {
+    pub user_id: Uuid,
+    pub balances: HashMap<String, f64>,
+    pub open_orders: usize,
+}
+
+/// Everything the admin dashboard shows, taken in one engine step so the
+/// parts are consistent with each other
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DashboardSnapshot {
+    pub bids: Vec<DepthLevel>,
+    pub asks: Vec<DepthLevel>,
+    pub recent_trades: Vec<TapeEntry>, // Newest first
+    pub resting_orders: usize,
+    pub user_count: usize,
+    pub users: Vec<UserSummary>, // Most open orders first, capped by the caller's limit
+    pub balance_totals: BTreeMap<String, f64>,
+}
+
+impl DashboardSnapshot {
+    pub fn capture(
+        orderbook: &OrderBook,
+        trade_tape: &TradeTape,
+        depth: usize,
+        trades: usize,
+        users: usize,
+    ) -> Self {
+        let (bids, asks) = orderbook.get_depth(depth);
+
+        let mut balance_totals = BTreeMap::new();
+        let mut summaries: Vec<UserSummary> = orderbook
+            .user_balances
+            .values()
+            .map(|balance| {
+                for (currency, amount) in &balance.balances {
+                    *balance_totals.entry(currency.clone()).or_insert(0.0) += amount;
+                }
+                UserSummary {
+                    user_id: balance.user_id,
+                    balances: balance.balances.clone(),
+                    open_orders: orderbook.open_order_count(balance.user_id),
+                }
+            })
+            .collect();
+        summaries.sort_by(|a, b| {
+            b.open_orders
+                .cmp(&a.open_orders)
+                .then_with(|| a.user_id.cmp(&b.user_id))
+        });
+        let user_count = summaries.len();
+        summaries.truncate(users);
+
+        DashboardSnapshot {
+            bids,
+            asks,
+            recent_trades: trade_tape.recent(trades),
+            resting_orders: orderbook.orders.len(),
+            user_count,
+            users: summaries,
+            balance_totals,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Order, OrderSide, Price, Quantity, Trade};
+
+    #[test]
+    fn test_snapshot_counts_orders_and_totals_balances() {
+        let mut orderbook = OrderBook::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        orderbook.add_funds(alice, "USD", 1000.0);
+        orderbook.add_funds(bob, "USD", 500.0);
+        orderbook.add_funds(bob, "BTC", 1.0);
+
+        let bid = Order::new_limit(
+            alice,
+            OrderSide::Buy,
+            Price::from_f64(100.0),
+            Quantity::from_f64(1.0),
+        );
+        orderbook.add_order(bid);
+
+        let mut tape = TradeTape::new(10);
+        let trades: Vec<Trade> = (0..3)
+            .map(|_| {
+                Trade::new(
+                    Uuid::new_v4(),
+                    Uuid::new_v4(),
+                    Uuid::new_v4(),
+                    Uuid::new_v4(),
+                    Price::from_f64(100.0),
+                    Quantity::from_f64(1.0),
+                )
+            })
+            .collect();
+        tape.append(&trades, OrderSide::Buy);
+
+        let snapshot = DashboardSnapshot::capture(&orderbook, &tape, 5, 2, 1);
+
+        assert_eq!(snapshot.user_count, 2);
+        assert_eq!(snapshot.users.len(), 1);
+        assert_eq!(snapshot.users[0].user_id, alice);
+        assert_eq!(snapshot.users[0].open_orders, 1);
+        assert_eq!(snapshot.balance_totals["USD"], 1500.0);
+        assert_eq!(snapshot.balance_totals["BTC"], 1.0);
+        assert_eq!(
+            snapshot
+                .recent_trades
+                .iter()
+                .map(|e| e.seq)
+                .collect::<Vec<_>>(),
+            vec![3, 2]
+        );
+    }
+}
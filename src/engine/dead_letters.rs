@@ -0,0 +1,179 @@
+use crate::types::Trade;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Automatic settlement attempts before a held trade is compensated
+pub const SETTLEMENT_RETRY_LIMIT: u32 = 5;
+
+/// Wait before the first automatic retry; it doubles after each failure
+pub const SETTLEMENT_RETRY_DELAY_SECS: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterStatus {
+    Pending,
+    Settled,     // A retry moved the balances
+    Compensated, // Given up on; each side got back what it had reserved
+}
+
+/// A matched trade whose balances could not be moved. The fills stand; until
+/// the letter is settled or compensated neither side's balances reflect it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub trade: Trade,
+    pub error: String, // Why the latest attempt was refused
+    pub attempts: u32, // Refused attempts, the first made when the trade matched
+    pub parked_at: DateTime<Utc>,
+    pub next_attempt_at: Option<DateTime<Utc>>, // Only while pending
+    pub status: DeadLetterStatus,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub resolution: Option<String>,
+}
+
+/// Trades whose settlement was refused, in the order they were parked
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DeadLetterQueue {
+    letters: Vec<DeadLetter>, // In id order
+}
+
+impl DeadLetterQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn park(&mut self, trade: Trade, error: String, now: DateTime<Utc>) -> &DeadLetter {
+        self.letters.push(DeadLetter {
+            id: self.letters.len() as u64 + 1,
+            trade,
+            error,
+            attempts: 1,
+            parked_at: now,
+            next_attempt_at: Some(now + retry_delay(1)),
+            status: DeadLetterStatus::Pending,
+            resolved_at: None,
+            resolution: None,
+        });
+        self.letters.last().unwrap()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&DeadLetter> {
+        self.letters.iter().find(|letter| letter.id == id)
+    }
+
+    /// Whether `trade_id` is waiting for its settlement
+    pub fn holds(&self, trade_id: Uuid) -> bool {
+        self.letters
+            .iter()
+            .any(|letter| letter.trade.id == trade_id && letter.status == DeadLetterStatus::Pending)
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.letters
+            .iter()
+            .any(|letter| letter.status == DeadLetterStatus::Pending)
+    }
+
+    /// Pending letters whose next attempt is due, oldest first
+    pub fn due(&self, now: DateTime<Utc>) -> Vec<u64> {
+        self.letters
+            .iter()
+            .filter(|letter| letter.next_attempt_at.is_some_and(|at| at <= now))
+            .map(|letter| letter.id)
+            .collect()
+    }
+
+    /// Count a refused attempt and push the next one back
+    pub fn record_failure(&mut self, id: u64, error: String, now: DateTime<Utc>) {
+        if let Some(letter) = self.letters.iter_mut().find(|letter| letter.id == id) {
+            letter.attempts += 1;
+            letter.error = error;
+            letter.next_attempt_at = Some(now + retry_delay(letter.attempts));
+        }
+    }
+
+    /// Take a pending letter out of the queue as settled or compensated
+    pub fn resolve(
+        &mut self,
+        id: u64,
+        status: DeadLetterStatus,
+        note: String,
+        now: DateTime<Utc>,
+    ) -> Result<DeadLetter, String> {
+        let letter = self
+            .letters
+            .iter_mut()
+            .find(|letter| letter.id == id)
+            .ok_or_else(|| format!("Dead letter {} not found", id))?;
+        if letter.status != DeadLetterStatus::Pending {
+            return Err(format!("Dead letter {} is no longer pending", id));
+        }
+        letter.status = status;
+        letter.next_attempt_at = None;
+        letter.resolved_at = Some(now);
+        letter.resolution = Some(note);
+        Ok(letter.clone())
+    }
+
+    /// Newest first, optionally only those still pending
+    pub fn list(&self, pending_only: bool) -> Vec<DeadLetter> {
+        self.letters
+            .iter()
+            .rev()
+            .filter(|letter| !pending_only || letter.status == DeadLetterStatus::Pending)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Delay after the `attempts`th refused attempt
+fn retry_delay(attempts: u32) -> Duration {
+    Duration::seconds(SETTLEMENT_RETRY_DELAY_SECS << (attempts - 1).min(10))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Price, Quantity};
+
+    #[test]
+    fn test_retries_back_off_until_the_letter_is_resolved() {
+        let mut queue = DeadLetterQueue::new();
+        let trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Price::from_f64(100.0),
+            Quantity::from_f64(1.0),
+        );
+        let now = Utc::now();
+
+        let id = queue.park(trade.clone(), "refused".to_string(), now).id;
+        assert!(queue.holds(trade.id));
+        assert!(queue.due(now).is_empty());
+        let first_retry = now + Duration::seconds(SETTLEMENT_RETRY_DELAY_SECS);
+        assert_eq!(queue.due(first_retry), vec![id]);
+
+        queue.record_failure(id, "still refused".to_string(), first_retry);
+        let letter = queue.get(id).unwrap();
+        assert_eq!(letter.attempts, 2);
+        assert_eq!(
+            letter.next_attempt_at,
+            Some(first_retry + Duration::seconds(2 * SETTLEMENT_RETRY_DELAY_SECS))
+        );
+
+        let settled = queue
+            .resolve(id, DeadLetterStatus::Settled, "retried".to_string(), now)
+            .unwrap();
+        assert_eq!(settled.next_attempt_at, None);
+        assert!(!queue.holds(trade.id) && !queue.has_pending());
+        assert!(queue.due(first_retry + Duration::days(1)).is_empty());
+        assert!(queue
+            .resolve(id, DeadLetterStatus::Compensated, "again".to_string(), now)
+            .is_err());
+        assert!(queue.list(true).is_empty());
+        assert_eq!(queue.list(false).len(), 1);
+    }
+}
@@ -0,0 +1,81 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Per-user cancel-all-after deadlines. A client that stops refreshing its
+/// deadline has every resting order cancelled once it passes, so a bot that
+/// loses its connection does not leave stale quotes on the book.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DeadManSwitches {
+    deadlines: HashMap<Uuid, DateTime<Utc>>,
+}
+
+impl DeadManSwitches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    /// Arm or push back the user's switch; replaces any earlier deadline
+    pub fn arm(&mut self, user_id: Uuid, cancel_at: DateTime<Utc>) {
+        self.deadlines.insert(user_id, cancel_at);
+    }
+
+    pub fn disarm(&mut self, user_id: Uuid) {
+        self.deadlines.remove(&user_id);
+    }
+
+    pub fn deadline(&self, user_id: Uuid) -> Option<DateTime<Utc>> {
+        self.deadlines.get(&user_id).copied()
+    }
+
+    /// The earliest deadline of any armed switch
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.deadlines.values().min().copied()
+    }
+
+    /// Disarm and return every user whose deadline is at or before `now`
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> Vec<Uuid> {
+        let due: Vec<Uuid> = self
+            .deadlines
+            .iter()
+            .filter(|(_, cancel_at)| **cancel_at <= now)
+            .map(|(user_id, _)| *user_id)
+            .collect();
+        for user_id in &due {
+            self.deadlines.remove(user_id);
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_refreshing_pushes_the_deadline_back() {
+        let mut switches = DeadManSwitches::new();
+        let (bot, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+
+        switches.arm(bot, now + Duration::seconds(5));
+        switches.arm(other, now + Duration::seconds(5));
+        switches.arm(bot, now + Duration::seconds(30));
+        switches.disarm(other);
+
+        assert!(switches.take_due(now + Duration::seconds(10)).is_empty());
+        assert_eq!(switches.deadline(bot), Some(now + Duration::seconds(30)));
+        assert_eq!(switches.next_deadline(), Some(now + Duration::seconds(30)));
+
+        // Fires once, then stays disarmed
+        assert_eq!(switches.take_due(now + Duration::seconds(30)), vec![bot]);
+        assert!(switches.is_empty());
+        assert_eq!(switches.next_deadline(), None);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::types::{Order, OrderSide, OrderType, Price, Quantity};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Fields that make two orders "the same order" for retry detection
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct OrderFingerprint {
+    user_id: Uuid,
+    side: OrderSide,
+    order_type: OrderType,
+    price: Option<Price>,
+    quantity: Quantity,
+}
+
+impl OrderFingerprint {
+    fn of(order: &Order) -> Self {
+        OrderFingerprint {
+            user_id: order.user_id,
+            side: order.side,
+            order_type: order.order_type,
+            price: order.price,
+            quantity: order.original_quantity,
+        }
+    }
+}
+
+/// An order the guard still remembers, as saved in an engine snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecentOrder {
+    fingerprint: OrderFingerprint,
+    seen_at: DateTime<Utc>,
+    client_order_id: Option<String>,
+}
+
+/// Rejects an order identical to one the same user placed within `window`,
+/// unless the client tagged the two with different client order IDs.
+/// Guards the book against client retry storms.
+pub struct DuplicateOrderGuard {
+    window: Duration,
+    recent: HashMap<OrderFingerprint, (DateTime<Utc>, Option<String>)>,
+    expiry_queue: VecDeque<(DateTime<Utc>, OrderFingerprint)>,
+}
+
+impl DuplicateOrderGuard {
+    pub fn new(window: Duration) -> Self {
+        DuplicateOrderGuard {
+            window,
+            recent: HashMap::new(),
+            expiry_queue: VecDeque::new(),
+        }
+    }
+
+    /// Reject `order` if it repeats a recently accepted one
+    pub fn check(&mut self, order: &Order) -> Result<(), String> {
+        if self.window.is_zero() {
+            return Ok(());
+        }
+
+        self.evict_before(order.received_at);
+
+        if let Some((_, previous_client_id)) = self.recent.get(&OrderFingerprint::of(order)) {
+            let distinct = match (&order.client_order_id, previous_client_id) {
+                (Some(current), Some(previous)) => current != previous,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+
+            if !distinct {
+                return Err(format!(
+                    "Duplicate order rejected: identical order placed within the last {} ms",
+                    self.window.as_millis()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remember an accepted order so retries of it are caught
+    pub fn record(&mut self, order: &Order) {
+        if self.window.is_zero() {
+            return;
+        }
+
+        let fingerprint = OrderFingerprint::of(order);
+        let seen_at = order.received_at;
+        self.recent
+            .insert(fingerprint.clone(), (seen_at, order.client_order_id.clone()));
+        self.expiry_queue.push_back((seen_at, fingerprint));
+    }
+
+    pub fn check_and_record(&mut self, order: &Order) -> Result<(), String> {
+        self.check(order)?;
+        self.record(order);
+        Ok(())
+    }
+
+    /// Every order remembered, in the order they were recorded
+    pub fn recent_orders(&self) -> Vec<RecentOrder> {
+        self.expiry_queue
+            .iter()
+            .map(|(seen_at, fingerprint)| {
+                // Refreshed since; the later entry carries the client order ID
+                let client_order_id = self
+                    .recent
+                    .get(fingerprint)
+                    .filter(|(t, _)| t == seen_at)
+                    .and_then(|(_, client_order_id)| client_order_id.clone());
+                RecentOrder {
+                    fingerprint: fingerprint.clone(),
+                    seen_at: *seen_at,
+                    client_order_id,
+                }
+            })
+            .collect()
+    }
+
+    /// Remember `orders`, as `recent_orders` listed them, in place of any others
+    pub fn restore(&mut self, orders: Vec<RecentOrder>) {
+        self.recent.clear();
+        self.expiry_queue.clear();
+        for order in orders {
+            self.recent.insert(
+                order.fingerprint.clone(),
+                (order.seen_at, order.client_order_id),
+            );
+            self.expiry_queue
+                .push_back((order.seen_at, order.fingerprint));
+        }
+    }
+
+    fn evict_before(&mut self, now: DateTime<Utc>) {
+        let window = chrono::Duration::from_std(self.window).unwrap_or(chrono::Duration::MAX);
+        while let Some((seen_at, _)) = self.expiry_queue.front() {
+            if now - *seen_at < window {
+                break;
+            }
+            let (seen_at, fingerprint) = self.expiry_queue.pop_front().unwrap();
+            // Only drop the map entry if it wasn't refreshed by a later order
+            if self.recent.get(&fingerprint).map(|(t, _)| *t) == Some(seen_at) {
+                self.recent.remove(&fingerprint);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(user_id: Uuid, client_order_id: Option<&str>) -> Order {
+        let mut order = Order::new_limit(user_id, OrderSide::Buy, Price::new(100), Quantity::new(1));
+        order.client_order_id = client_order_id.map(str::to_string);
+        order
+    }
+
+    #[test]
+    fn test_rejects_identical_order_inside_window() {
+        let mut guard = DuplicateOrderGuard::new(Duration::from_millis(100));
+        let user_id = Uuid::new_v4();
+        let first = order(user_id, None);
+
+        assert!(guard.check_and_record(&first).is_ok());
+        assert!(guard.check_and_record(&order(user_id, None)).is_err());
+        assert!(guard.check_and_record(&order(Uuid::new_v4(), None)).is_ok());
+
+        let later = order(user_id, None)
+            .with_received_at(first.received_at + chrono::Duration::milliseconds(150));
+        assert!(guard.check_and_record(&later).is_ok());
+    }
+
+    #[test]
+    fn test_distinct_client_order_ids_pass() {
+        let mut guard = DuplicateOrderGuard::new(Duration::from_millis(100));
+        let user_id = Uuid::new_v4();
+
+        assert!(guard.check_and_record(&order(user_id, Some("a"))).is_ok());
+        assert!(guard.check_and_record(&order(user_id, Some("b"))).is_ok());
+        assert!(guard.check_and_record(&order(user_id, Some("b"))).is_err());
+        assert!(guard.check_and_record(&order(user_id, None)).is_err());
+    }
+
+    #[test]
+    fn test_zero_window_disables_guard() {
+        let mut guard = DuplicateOrderGuard::new(Duration::ZERO);
+        let user_id = Uuid::new_v4();
+        assert!(guard.check_and_record(&order(user_id, None)).is_ok());
+        assert!(guard.check_and_record(&order(user_id, None)).is_ok());
+    }
+}
@@ -1,205 +1,6604 @@
+use crate::engine::{
+    annotate_price_improvement, assess_position, control_channel, drain_batch, event_channel,
+    handle_control, level_changes, open_event_log, prioritize_cancels, top_of_book,
+    write_with_retries, AccountSummary, ClientOrderIds, ControlCommand, DailyStatsRecorder,
+    DailyStatsStore, DashboardSnapshot, DeadLetter, DeadLetterQueue, DeadLetterStatus,
+    DeadManSwitches, DuplicateOrderGuard, EngineClock, EngineConfig, EngineMetrics, EngineSnapshot,
+    EventChannels, EventLog, ExecutionQualityTracker, ExpirySchedule, FeedActivityTracker,
+    HistoryWorker, IncidentLog, InterestAccrual, InterestSummary, MarginPosition, MarginSettings,
+    MarketEvent, MarketMessage, OrderFill, OrderHistory, OrderIds, OrderTimings, OutboxRelay,
+    PriceAverageTracker, RecoveryReport, SourceVolumeTracker, StopOrder, SyntheticIndices,
+    SyntheticQuote, TapeEntry, TradeTape, TriggerBook, WalRecord, EVENT_DEPTH_LEVELS,
+    SETTLEMENT_RETRY_LIMIT, SNAPSHOT_VERSION,
+};
+use crate::ledger::{
+    from_ledger_units, to_ledger_units, Account, AccountOwner, JournalKind, Ledger, LedgerAmount,
+    NettingWindow, Posting, SettlementRates, DEFAULT_JOURNAL_RETENTION,
+};
 use crate::messages::{OrderBookCommand, OrderBookResponse};
-use crate::orderbook::OrderBook;
-use crate::types::Order;
+use crate::orderbook::{
+    BalanceOperation, DepthLevel, MarketState, OrderBook, OrderFilter, BALANCE_TOLERANCE,
+};
+use crate::storage::{self, HistoryQuery, OutboxEvent};
 use crate::types::OrderSide::*;
-use tokio::sync::mpsc;
+use crate::types::{
+    newest_first, Activity, ActivityEntry, AllocationPolicy, ClearingMode, FeedMode, MarketConfig,
+    MarketSettlement, Order, OrderSide, OrderStatus, OrderType, Price, Quantity, TimeInForce,
+    Trade, TradingStatus,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use uuid::Uuid;
 
-pub async fn run_orderbook_engine(mut rx: mpsc::Receiver<OrderBookCommand>) {
-    let mut orderbook = OrderBook::new();
+/// Send a response back to the caller, counting it if the caller already hung up
+fn respond(
+    metrics: &EngineMetrics,
+    response_tx: oneshot::Sender<OrderBookResponse>,
+    response: OrderBookResponse,
+) {
+    if response_tx.send(response).is_err() {
+        metrics.record_undelivered();
+    }
+}
+
+/// The error for an order change that went through but whose reserved
+/// balance could not be handed back
+fn refund_refused(order_id: Uuid, error: &str) -> String {
+    format!(
+        "Order {} was updated, but its reserved balance was not released and an incident was opened: {}",
+        order_id, error
+    )
+}
+
+/// All state owned by the engine task. Only ever touched from that task,
+/// so commands are applied strictly one at a time.
+pub struct Engine {
+    orderbook: OrderBook,
+    market: MarketConfig,
+    metrics: Arc<EngineMetrics>,
+    daily_stats: DailyStatsRecorder,
+    trade_tape: TradeTape,
+    execution_quality: ExecutionQualityTracker,
+    order_history: OrderHistory,
+    duplicate_guard: DuplicateOrderGuard,
+    client_ids: ClientOrderIds,
+    history_retention: chrono::Duration,
+    incidents: IncidentLog,
+    dead_letters: DeadLetterQueue,
+    margin: MarginSettings,
+    source_volume: SourceVolumeTracker,
+    feed_activity: FeedActivityTracker,
+    price_averages: PriceAverageTracker,
+    triggers: TriggerBook,
+    synthetics: SyntheticIndices,
+    ledger: Ledger,
+    history: HistoryWorker, // Owns the store; every read and write goes through it
+    unsaved_trades: Vec<Trade>,
+    settlement_rates: SettlementRates,
+    netting: NettingWindow,
+    netting_window: Duration,
+    interest: InterestAccrual,
+    expiries: ExpirySchedule,
+    dead_man: DeadManSwitches,
+    clock: EngineClock,
+    pegged: Vec<Uuid>, // Resting pegged orders, oldest first
+    peg_reference: (Option<Price>, Option<Price>), // Best bid and ask they were last priced from
+    events: EventChannels,
+    market_seq: u64, // Sequence of the last public message published
+    published_book: (Vec<DepthLevel>, Vec<DepthLevel>), // Public levels as the last diff left them
+    public_depth_levels: usize, // Levels per side the depth events cover
+    last_diff_seq: u64,
+    settlement_blocked: bool, // Expired with no price to settle at; left to an operator
+    positions: HashMap<Uuid, MarginPosition>, // Opened by trading in this market, settled at expiry
+    order_ids: OrderIds,
+    replaying: bool, // Rebuilding from the command log; nothing is published or saved
+    recovery: Option<RecoveryReport>,
+}
+
+impl Engine {
+    /// An engine over the stores `config` names. Panics if one cannot be
+    /// opened, so it is for configurations that always open, like the
+    /// in-memory default; the engine task uses `open`.
+    pub fn new(metrics: Arc<EngineMetrics>, config: EngineConfig) -> Self {
+        Self::open(metrics, config).expect("engine stores should open")
+    }
+
+    /// An engine over the stores `config` names. A store that cannot be
+    /// opened is an error rather than a silent switch to memory, which would
+    /// lose everything written from then on at the next restart.
+    pub fn open(metrics: Arc<EngineMetrics>, config: EngineConfig) -> Result<Self, String> {
+        let stats_store = DailyStatsStore::open(config.stats_path)
+            .map_err(|e| format!("Daily stats store unavailable: {}", e))?;
+        let store = storage::open(&config.storage)
+            .map_err(|e| format!("Storage backend unavailable: {}", e))?;
+        let synthetics = SyntheticIndices::open(config.index_path)
+            .map_err(|e| format!("Index definitions unavailable: {}", e))?;
+        let mut ledger = Ledger::default();
+        match store.last_journal_id() {
+            Ok(last_id) => {
+                ledger.continue_after(last_id);
+                // Snapshots leave journals to the store, so the recent ones come from it
+                let after = last_id.saturating_sub(DEFAULT_JOURNAL_RETENTION as u64);
+                match store.journals_after(after, DEFAULT_JOURNAL_RETENTION) {
+                    Ok(journals) => ledger.recall(journals),
+                    Err(e) => eprintln!("Failed to read the recent stored journals: {}", e),
+                }
+            }
+            Err(e) => eprintln!("Failed to read the last stored journal: {}", e),
+        }
+
+        // Trade events go out only when there is somewhere to send them
+        let relay = config
+            .outbox
+            .and_then(|target| OutboxRelay::to(target, metrics.clone()));
+        let history = HistoryWorker::spawn(store, relay, metrics.clone());
+
+        let mut orderbook = OrderBook::new();
+        orderbook.sweep_limit = config.market.sweep_limit;
+        orderbook.allocation = config.market.allocation;
+        orderbook.fees = config.market.fees;
+
+        Ok(Engine {
+            orderbook,
+            market: config.market,
+            metrics,
+            daily_stats: DailyStatsRecorder::new(stats_store, Utc::now()),
+            trade_tape: TradeTape::new(config.trade_tape_capacity),
+            execution_quality: ExecutionQualityTracker::new(),
+            order_history: OrderHistory::new(),
+            duplicate_guard: DuplicateOrderGuard::new(config.duplicate_order_window),
+            client_ids: ClientOrderIds::new(config.client_order_id_window),
+            history_retention: chrono::Duration::from_std(config.order_history_retention)
+                .unwrap_or(chrono::Duration::MAX),
+            incidents: IncidentLog::new(),
+            dead_letters: DeadLetterQueue::new(),
+            margin: MarginSettings::new(config.leverage_tiers),
+            source_volume: SourceVolumeTracker::new(),
+            feed_activity: FeedActivityTracker::new(),
+            price_averages: PriceAverageTracker::new(),
+            triggers: TriggerBook::new(),
+            synthetics,
+            ledger,
+            history,
+            unsaved_trades: Vec::new(),
+            settlement_rates: SettlementRates::new(),
+            netting: NettingWindow::new(),
+            netting_window: config.netting_window,
+            interest: InterestAccrual::new(config.interest_rates, Utc::now().date_naive()),
+            expiries: ExpirySchedule::new(),
+            dead_man: DeadManSwitches::new(),
+            clock: EngineClock::new(),
+            pegged: Vec::new(),
+            peg_reference: (None, None),
+            events: event_channel(),
+            market_seq: 0,
+            published_book: (Vec::new(), Vec::new()),
+            public_depth_levels: config.public_depth_levels.max(1),
+            last_diff_seq: 0,
+            settlement_blocked: false,
+            positions: HashMap::new(),
+            order_ids: OrderIds::new(),
+            replaying: false,
+            recovery: None,
+        })
+    }
+
+    /// Publish market events on `events` instead of a private channel
+    pub fn with_events(mut self, events: EventChannels) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Stamp `event` with the feed sequence and send time and send it out
+    fn publish(&mut self, event: MarketEvent) {
+        if event.is_public() {
+            self.market_seq += 1;
+            self.metrics.record_market_seq(self.market_seq);
+        }
+        // Subscribers saw these events before the restart; they are still
+        // counted, so the feed carries on from the numbers it had reached
+        if self.replaying {
+            return;
+        }
+        if let MarketEvent::Trade(_) = event {
+            self.feed_activity.record_trade(self.clock.now());
+            self.metrics.record_trade_published();
+        }
+        self.events.send(MarketMessage {
+            seq: self.market_seq,
+            sent_at: Utc::now(),
+            event,
+        });
+    }
+
+    fn publish_trades(&mut self, entries: Vec<TapeEntry>) {
+        for entry in entries {
+            self.publish(MarketEvent::Trade(entry));
+        }
+    }
+
+    fn publish_fills(&mut self, fills: Vec<OrderFill>) {
+        for fill in fills {
+            self.publish(MarketEvent::Fill(fill));
+        }
+    }
+
+    /// Publish the top of the book, then the levels that changed within the
+    /// public depth; a level pushed out of it is sent emptied. With nobody
+    /// listening the diff is skipped and the next one covers the gap; its
+    /// levels are absolute, so repeating a change is harmless. Top-of-book
+    /// changes are counted either way.
+    fn publish_depth(&mut self) {
+        let (best_bids, best_asks) = self.orderbook.get_depth(1);
+        if self
+            .feed_activity
+            .record_top(top_of_book(&best_bids, &best_asks), self.clock.now())
+        {
+            self.metrics.record_bbo_change();
+        }
+        if self.events.receiver_count() == 0 {
+            return;
+        }
+        let (bids, asks) = self.orderbook.get_depth(self.public_depth_levels);
+        let top = |levels: &[DepthLevel]| levels[..levels.len().min(EVENT_DEPTH_LEVELS)].to_vec();
+        self.publish(MarketEvent::Depth {
+            bids: top(&bids),
+            asks: top(&asks),
+            imbalance: self.feed_activity.rolling_imbalance(),
+        });
+
+        let (published_bids, published_asks) = &self.published_book;
+        let changed_bids = level_changes(Buy, published_bids, &bids);
+        let changed_asks = level_changes(Sell, published_asks, &asks);
+        if !(changed_bids.is_empty() && changed_asks.is_empty()) {
+            self.publish(MarketEvent::DepthDiff {
+                prev_seq: self.last_diff_seq,
+                bids: changed_bids,
+                asks: changed_asks,
+            });
+            self.last_diff_seq = self.market_seq;
+        }
+        self.published_book = (bids, asks);
+    }
+
+    /// The book's reference prices, with the imbalance the feeds publish
+    fn market_state(&self) -> MarketState {
+        MarketState {
+            imbalance: self.feed_activity.rolling_imbalance(),
+            ..self.orderbook.market_state()
+        }
+    }
+
+    /// Rejection sent for new orders while the market is not trading
+    fn halted_response(&self) -> Option<OrderBookResponse> {
+        match self.market.trading_status {
+            TradingStatus::Trading => None,
+            TradingStatus::Halted => Some(OrderBookResponse::Error {
+                message: format!("Market {} is halted", self.market.symbol),
+            }),
+            TradingStatus::Settled => Some(OrderBookResponse::Error {
+                message: format!("Market {} has expired and settled", self.market.symbol),
+            }),
+        }
+    }
+
+    fn quarantined_response(&self, user_id: Uuid) -> Option<OrderBookResponse> {
+        self.incidents
+            .is_quarantined(user_id)
+            .then(|| OrderBookResponse::Error {
+                message: "Account is quarantined pending review".to_string(),
+            })
+    }
+
+    /// Open an incident for every balance change the book refused since the
+    /// last call, quarantining the accounts involved
+    fn raise_balance_incidents(&mut self) {
+        for violation in self.orderbook.take_balance_violations() {
+            let incident = self.incidents.open(violation, self.clock.now());
+            self.metrics.record_incident();
+            eprintln!(
+                "ALERT: incident {}: {:?} would have taken {} {} balance of {} to {}; account quarantined",
+                incident.id,
+                incident.operation,
+                incident.currency,
+                incident.balance,
+                incident.user_id,
+                incident.balance + incident.change
+            );
+        }
+    }
+
+    /// Hold every trade whose settlement the book refused since the last call
+    /// in the dead letter queue, until a retry settles it or it is compensated
+    fn park_unsettled_trades(&mut self) {
+        for (trade, error) in self.orderbook.take_unsettled_trades() {
+            let letter = self.dead_letters.park(trade, error, self.clock.now());
+            eprintln!(
+                "ALERT: dead letter {}: trade {} matched but did not settle: {}",
+                letter.id, letter.trade.id, letter.error
+            );
+        }
+    }
+
+    /// Retry the held settlements that are due, and compensate those that
+    /// have used up their retries
+    fn retry_dead_letters(&mut self, now: DateTime<Utc>) {
+        for id in self.dead_letters.due(now) {
+            let exhausted = self
+                .dead_letters
+                .get(id)
+                .is_some_and(|letter| letter.attempts >= SETTLEMENT_RETRY_LIMIT);
+            let result = if exhausted {
+                self.compensate_settlement(id, "Retries exhausted".to_string(), now)
+            } else {
+                self.retry_settlement(id, now)
+            };
+            match result {
+                Ok(letter) => eprintln!("Dead letter {} {:?}", letter.id, letter.status),
+                Err(e) => eprintln!("Dead letter {} still pending: {}", id, e),
+            }
+        }
+    }
+
+    /// Try once more to move the balances of a held trade. Once they move the
+    /// trade is charged its fees, journalled and published like any other.
+    fn retry_settlement(&mut self, id: u64, now: DateTime<Utc>) -> Result<DeadLetter, String> {
+        let letter = self
+            .dead_letters
+            .get(id)
+            .filter(|letter| letter.status == DeadLetterStatus::Pending)
+            .ok_or_else(|| format!("No pending dead letter {}", id))?;
+        let mut trade = letter.trade.clone();
+        let attempt = letter.attempts + 1;
+
+        // The account is already under review; a refused retry opens no new incident
+        self.raise_balance_incidents();
+        if let Err(e) = self.orderbook.retry_settlement(&mut trade) {
+            self.orderbook.take_balance_violations();
+            self.dead_letters.record_failure(id, e.clone(), now);
+            return Err(format!("Settlement refused: {}", e));
+        }
+        let note = format!("Settled on attempt {}", attempt);
+        self.dead_letters
+            .resolve(id, DeadLetterStatus::Settled, note, now)?;
+        let taker_side = trade.taker_side;
+        self.record_trades(&mut vec![trade], taker_side);
+        self.dead_letters
+            .get(id)
+            .cloned()
+            .ok_or_else(|| format!("Dead letter {} not found", id))
+    }
+
+    /// Give up on settling a held trade. The fills stand, but each side gets
+    /// back what its order set aside to pay for the trade, so neither is out
+    /// of pocket for a trade that never settled. Market orders reserve too,
+    /// so this covers them like limit orders. Both sides are released
+    /// together: if either release is refused, neither moves and the letter
+    /// stays pending until the next attempt.
+    fn compensate_settlement(
+        &mut self,
+        id: u64,
+        note: String,
+        now: DateTime<Utc>,
+    ) -> Result<DeadLetter, String> {
+        let trade = self
+            .dead_letters
+            .get(id)
+            .filter(|letter| letter.status == DeadLetterStatus::Pending)
+            .map(|letter| letter.trade.clone())
+            .ok_or_else(|| format!("No pending dead letter {}", id))?;
+        let (buyer, seller) = match trade.taker_side {
+            Buy => (trade.taker_user_id, trade.maker_user_id),
+            Sell => (trade.maker_user_id, trade.taker_user_id),
+        };
+        let quantity = trade.quantity.to_f64();
+        let cost = trade.price.to_f64() * quantity;
+        let released = self.orderbook.apply_reserved_changes(
+            BalanceOperation::Refund,
+            &[(buyer, "USD", cost), (seller, "BTC", quantity)],
+            &[(buyer, "USD", -cost), (seller, "BTC", -quantity)],
+        );
+        if let Err(e) = released {
+            self.dead_letters.record_failure(id, e.clone(), now);
+            return Err(format!("Compensation refused: {}", e));
+        }
+        self.dead_letters
+            .resolve(id, DeadLetterStatus::Compensated, note, now)
+    }
+
+    /// Whether the best price left for a market order is past its slippage
+    /// guard, i.e. the guard rather than the book stopped its sweep
+    fn beyond_worst_price(&self, order: &Order) -> bool {
+        // Hidden orders count here: the sweep could have traded with them
+        let best = match order.side {
+            Buy => self.orderbook.best_ask(),
+            Sell => self.orderbook.best_bid(),
+        };
+        match (order.side, order.worst_price, best) {
+            (Buy, Some(worst), Some(best)) => best > worst,
+            (Sell, Some(worst), Some(best)) => best < worst,
+            _ => false,
+        }
+    }
+
+    /// Reject an order whose client order ID its owner used too recently
+    fn check_client_order_id(&self, order: &Order) -> Result<(), String> {
+        match &order.client_order_id {
+            Some(client_order_id) => {
+                self.client_ids
+                    .check(order.user_id, client_order_id, order.received_at)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn record_client_order_id(&mut self, order: &Order) {
+        if let Some(client_order_id) = &order.client_order_id {
+            self.client_ids
+                .record(order.user_id, client_order_id, order.id, order.received_at);
+        }
+    }
+
+    /// Return the balance reserved for an order's unfilled quantity
+    fn refund_remainder(&mut self, order: &Order) -> Result<(), String> {
+        self.refund_quantity(order, order.remaining_quantity)
+    }
+
+    /// Release what was reserved for `quantity` of an order. A market buy
+    /// reserves for the whole order at once, so it is released in `execute_order`.
+    fn refund_quantity(&mut self, order: &Order, quantity: Quantity) -> Result<(), String> {
+        let quantity = quantity.to_f64();
+        match (order.side, order.price) {
+            (Buy, Some(price)) => {
+                self.release_reservation(order.user_id, "USD", price.to_f64() * quantity)
+            }
+            (Buy, None) => Ok(()),
+            (Sell, _) => self.release_reservation(order.user_id, "BTC", quantity),
+        }
+    }
+
+    /// Move `amount` of what a user set aside for orders back to what they
+    /// have available. A refused refund is raised as an incident, and the
+    /// error is returned so the caller can report it.
+    fn release_reservation(
+        &mut self,
+        user_id: Uuid,
+        currency: &str,
+        amount: f64,
+    ) -> Result<(), String> {
+        if amount <= 0.0 {
+            return Ok(());
+        }
+        self.orderbook.apply_reserved_changes(
+            BalanceOperation::Refund,
+            &[(user_id, currency, amount)],
+            &[(user_id, currency, -amount)],
+        )
+    }
+
+    /// Take the balance an order's unfilled quantity needs while it rests
+    fn reserve_remainder(&mut self, order: &Order) -> Result<(), String> {
+        let remaining = order.remaining_quantity.to_f64();
+        match order.side {
+            Buy => {
+                let price = order.price.ok_or("Order has no price")?;
+                self.orderbook
+                    .reserve_balance(order.user_id, "USD", price.to_f64() * remaining)
+            }
+            Sell => self
+                .orderbook
+                .reserve_balance(order.user_id, "BTC", remaining),
+        }
+    }
+
+    /// What a buy pays below its limit comes out of a reservation taken at
+    /// the limit; hand the difference back
+    fn release_price_improvement(&mut self, order: &Order, trades: &[Trade]) -> Result<(), String> {
+        let Some(limit) = order.price.filter(|_| order.side == Buy) else {
+            return Ok(());
+        };
+        let improvement: f64 = trades
+            .iter()
+            .map(|trade| (limit.to_f64() - trade.price.to_f64()) * trade.quantity.to_f64())
+            .sum();
+        self.release_reservation(order.user_id, "USD", improvement)
+    }
+
+    /// Set aside what a market order can spend before it sweeps the book: the
+    /// BTC it sells, or what buying through the book as far as its sweep limit
+    /// and slippage guard allow would cost. Returns the USD reserved for a buy.
+    fn reserve_market_order(&mut self, order: &Order) -> Result<f64, String> {
+        match order.side {
+            Buy => {
+                let cost = self.orderbook.market_buy_cost(order);
+                if !self
+                    .orderbook
+                    .has_sufficient_balance(order.user_id, "USD", cost)
+                {
+                    return Err("Insufficient USD balance".to_string());
+                }
+                self.orderbook.reserve_balance(order.user_id, "USD", cost)?;
+                Ok(cost)
+            }
+            Sell => {
+                let quantity = order.remaining_quantity.to_f64();
+                if !self
+                    .orderbook
+                    .has_sufficient_balance(order.user_id, "BTC", quantity)
+                {
+                    return Err("Insufficient BTC balance".to_string());
+                }
+                self.orderbook
+                    .reserve_balance(order.user_id, "BTC", quantity)?;
+                Ok(0.0)
+            }
+        }
+    }
+
+    /// The user's base holding against their quote cash
+    fn margin_position(&self, user_id: Uuid) -> MarginPosition {
+        self.orderbook
+            .get_user_balance(user_id)
+            .map_or(MarginPosition::new(0.0, 0.0), |b| {
+                MarginPosition::new(b.get_balance("BTC"), b.get_balance("USD"))
+            })
+    }
+
+    /// One account as the trading UI first shows it. Recent fills live in the
+    /// store, so they are left for the history worker to add.
+    fn account_summary(&self, user_id: Uuid) -> Result<AccountSummary, String> {
+        let balance = self
+            .orderbook
+            .get_user_balance(user_id)
+            .ok_or("User not found")?;
+        let mut open_orders: Vec<Order> = self.orderbook.user_orders(user_id).cloned().collect();
+        open_orders.sort_by_key(|order| order.timestamp);
+        let position = self.orderbook.mark_price().map(|mark_price| {
+            assess_position(
+                self.margin_position(user_id),
+                self.margin.tiers_for(user_id),
+                mark_price,
+            )
+        });
+        let alerts = self
+            .incidents
+            .list(true)
+            .into_iter()
+            .filter(|incident| incident.user_id == user_id)
+            .collect();
+
+        Ok(AccountSummary {
+            user_id,
+            balances: balance.balances.clone(),
+            open_orders,
+            recent_fills: Vec::new(),
+            position,
+            fees: self.market.fees,
+            alerts,
+            quarantined: self.incidents.is_quarantined(user_id),
+        })
+    }
+
+    /// Notional of the user's base-asset holding at the current mark price
+    fn position_notional(&self, user_id: Uuid) -> f64 {
+        let mark = self.orderbook.mark_price().unwrap_or(0.0);
+        self.margin_position(user_id).notional(mark)
+    }
+
+    /// Match a market order, triggered stop or re-priced peg and record its
+    /// trades everywhere they are tracked.
+    /// Returns the trades and the time spent matching.
+    fn execute_order(&mut self, order: &mut Order) -> Result<(Vec<Trade>, Duration), String> {
+        let side = order.side;
+        self.orderbook.now = self.clock.now();
+        let arrival_bbo = self.orderbook.bbo_snapshot();
+        order.arrival_bbo = Some(arrival_bbo);
+        let reserved = match order.order_type {
+            OrderType::Market => Some(self.reserve_market_order(order)?),
+            _ => None,
+        };
+
+        let match_started = Instant::now();
+        let result = self.orderbook.match_order(order);
+        let matching = match_started.elapsed();
+        self.park_unsettled_trades();
+        let mut released = Ok(());
+        if let Some(reserved) = reserved {
+            // Held trades keep their share until they settle or are compensated
+            let filled = match &result {
+                Ok(trades) => trades.as_slice(),
+                Err(_) => &[],
+            };
+            released = match side {
+                Buy => {
+                    let spent: f64 = filled
+                        .iter()
+                        .map(|trade| trade.price.to_f64() * trade.quantity.to_f64())
+                        .sum();
+                    self.release_reservation(order.user_id, "USD", reserved - spent)
+                }
+                Sell => self.refund_remainder(order),
+            };
+        } else if order.status == OrderStatus::Cancelled {
+            // A limit remainder that would have crossed the book did not rest
+            released = self.refund_remainder(order);
+        }
+        let mut trades = result?;
+
+        annotate_price_improvement(&mut trades, side, None, arrival_bbo.opposite(side));
+        let released = released.and(self.release_price_improvement(order, &trades));
+        self.order_history.upsert(order);
+        self.record_trades(&mut trades, side);
+        self.publish_depth();
+        // The trades stand either way; the refusal is reported after them
+        released.map_err(|e| refund_refused(order.id, &e))?;
+        Ok((trades, matching))
+    }
+
+    /// Charge, record, publish and journal an order's trades. Trades held in
+    /// the dead letter queue have not settled, so they are left out of the
+    /// tape, history and feeds until a retry settles them.
+    fn record_trades(&mut self, trades: &mut Vec<Trade>, side: OrderSide) {
+        trades.retain(|trade| !self.dead_letters.holds(trade.id));
+        self.journal_fees(trades);
+        let fills = self.order_history.record_fills(trades);
+        self.publish_fills(fills);
+        self.execution_quality.record(trades);
+        self.daily_stats.record_trades(trades);
+        self.unsaved_trades.extend_from_slice(trades);
+        let entries = self.trade_tape.append(trades, side);
+        self.publish_trades(entries);
+        self.source_volume.record(trades);
+        self.price_averages.record(trades, self.clock.now());
+        self.post_trades(trades, side);
+    }
+
+    /// Journal the maker and taker fees each trade paid as it settled. A
+    /// trade whose fees could not be paid did not settle, so it is held
+    /// rather than journalled here.
+    fn journal_fees(&mut self, trades: &[Trade]) {
+        for trade in trades {
+            // The buyer receives BTC but pays its fee in USD like the seller
+            let (maker_receives, taker_receives) = match trade.taker_side {
+                Buy => ("USD", "BTC"),
+                Sell => ("BTC", "USD"),
+            };
+            self.journal_fee(trade.maker_user_id, trade.maker_fee, maker_receives);
+            self.journal_fee(trade.taker_user_id, trade.taker_fee, taker_receives);
+        }
+    }
+
+    /// Take a fee in USD from what the user has available and journal it.
+    /// Nothing is charged if they cannot pay it all.
+    fn charge_fee(&mut self, user_id: Uuid, fee: f64) -> Result<f64, String> {
+        if fee <= 0.0 {
+            return Ok(0.0);
+        }
+        self.orderbook
+            .deduct_balance(user_id, "USD", fee)
+            .map_err(|e| format!("Could not charge the {} USD fee: {}", fee, e))?;
+        self.journal_fee(user_id, fee, "USD");
+        Ok(fee)
+    }
+
+    /// Hand back a fee taken by `charge_fee`, journalled as the reverse of
+    /// the charge
+    fn refund_fee(&mut self, user_id: Uuid, fee: f64) -> Result<(), String> {
+        if fee <= 0.0 {
+            return Ok(());
+        }
+        self.orderbook
+            .apply_balance_changes(BalanceOperation::Refund, &[(user_id, "USD", fee)])?;
+        let amount = to_ledger_units(fee);
+        let postings = vec![
+            Posting::new(Account::new(AccountOwner::Fees, "USD"), -amount),
+            Posting::new(Account::user(user_id, "USD"), amount),
+        ];
+        if let Err(e) = self.ledger.post(JournalKind::Fee, postings) {
+            eprintln!("Ledger rejected fee refund for {}: {}", user_id, e);
+        }
+        Ok(())
+    }
+
+    /// Journal a fee in USD already taken from the user into the exchange's
+    /// fee account. If the user received another currency, the journal keeps
+    /// the rate between the two in effect now.
+    fn journal_fee(&mut self, user_id: Uuid, fee: f64, received: &str) {
+        if fee <= 0.0 {
+            return;
+        }
+        let fx_rate = match received {
+            "USD" => None,
+            _ => self.settlement_rates.get(received, "USD").cloned(),
+        };
+        let amount = to_ledger_units(fee);
+        let postings = vec![
+            Posting::new(Account::user(user_id, "USD"), -amount),
+            Posting::new(Account::new(AccountOwner::Fees, "USD"), amount),
+        ];
+        let result = self
+            .ledger
+            .post_with_fx_rate(JournalKind::Fee, postings, fx_rate);
+        if let Err(e) = result {
+            eprintln!("Ledger rejected fee for {}: {}", user_id, e);
+        }
+    }
+
+    /// Whether `order` may pay `fee` for queue position: the market has to rank
+    /// by priority fee, the order has to be fully displayed, and the user has
+    /// to hold the fee in USD on top of a buy order's `notional`.
+    fn check_priority_fee(&self, order: &Order, fee: f64, notional: f64) -> Result<(), String> {
+        if self.market.allocation != AllocationPolicy::PriorityFee {
+            return Err(format!(
+                "Market {} does not take priority fees",
+                self.market.symbol
+            ));
+        }
+        if order.hidden || order.display_quantity.is_some() {
+            return Err("Priority fees are for fully displayed orders only".to_string());
+        }
+        let usd_needed = match order.side {
+            Buy => notional + fee,
+            Sell => fee,
+        };
+        if !self
+            .orderbook
+            .has_sufficient_balance(order.user_id, "USD", usd_needed)
+        {
+            return Err("Insufficient USD balance for the priority fee".to_string());
+        }
+        Ok(())
+    }
+
+    /// Journal each trade's exchange of USD for BTC between buyer and seller,
+    /// or add it to the open netting window if the market clears netted.
+    /// Either way both sides' positions in the market move by it.
+    fn post_trades(&mut self, trades: &[Trade], taker_side: OrderSide) {
+        for trade in trades {
+            // Held trades moved no balances, so there is nothing to journal yet
+            if self.dead_letters.holds(trade.id) {
+                continue;
+            }
+            let (buyer, seller) = match taker_side {
+                Buy => (trade.taker_user_id, trade.maker_user_id),
+                Sell => (trade.maker_user_id, trade.taker_user_id),
+            };
+            let (price, quantity) = (trade.price.to_f64(), trade.quantity.to_f64());
+            for (user_id, delta) in [(buyer, quantity), (seller, -quantity)] {
+                let position = self
+                    .positions
+                    .entry(user_id)
+                    .or_insert(MarginPosition::new(0.0, 0.0));
+                *position = position.with_delta(delta, price);
+            }
+            match self.market.clearing_mode {
+                ClearingMode::PerTrade => {
+                    if let Err(e) = self.ledger.post_trade(trade, taker_side) {
+                        eprintln!("Ledger rejected trade {}: {}", trade.id, e);
+                    }
+                }
+                ClearingMode::Netted => {
+                    self.netting
+                        .add_trade(trade, taker_side, self.clock.shift(Instant::now()))
+                }
+            }
+        }
+    }
+
+    /// Settle the netting window into the ledger
+    fn settle_netting_window(&mut self) {
+        let trades = self.netting.trade_count();
+        if let Err(e) = self.netting.settle(&mut self.ledger) {
+            eprintln!("Ledger rejected net settlement of {} trades: {}", trades, e);
+        }
+    }
+
+    /// Settle the netting window if it has been open for its full length
+    pub fn settle_due_netting(&mut self, now: Instant) {
+        if self.netting.is_due(now, self.netting_window) {
+            self.settle_netting_window();
+        }
+    }
+
+    /// A user's order events and, as far back as the ledger keeps journals,
+    /// their deposits and withdrawals: the page of them `query` selects,
+    /// newest first. Both come from per-user indexes, so the cost follows the
+    /// user's own history rather than everyone's.
+    fn user_activity(&self, user_id: Uuid, query: &HistoryQuery) -> Vec<ActivityEntry> {
+        let mut entries = self.order_history.activity(user_id);
+        entries.reverse();
+        for journal in self.ledger.funding_journals_of(user_id) {
+            let postings = journal
+                .postings
+                .iter()
+                .filter(|posting| posting.account.owner == AccountOwner::User(user_id));
+            for posting in postings {
+                let currency = posting.account.currency.clone();
+                let amount = from_ledger_units(posting.amount.abs());
+                let activity = match (journal.kind, journal.reverses) {
+                    (JournalKind::Deposit, _) => Activity::Deposit { currency, amount },
+                    (_, None) => Activity::Withdrawal { currency, amount },
+                    (_, Some(_)) => Activity::WithdrawalReversed { currency, amount },
+                };
+                entries.push(ActivityEntry::new(journal.timestamp, activity));
+            }
+        }
+        entries.retain(|entry| query.contains(entry.at));
+        newest_first(&mut entries);
+        entries
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit)
+            .collect()
+    }
+
+    /// Journal money entering (positive `amount`) or leaving a user's account
+    fn post_external(&mut self, kind: JournalKind, user_id: Uuid, currency: &str, amount: f64) {
+        let result = self.ledger.transfer(
+            kind,
+            Account::external(currency),
+            Account::user(user_id, currency),
+            to_ledger_units(amount),
+        );
+        if let Err(e) = result {
+            eprintln!("Ledger rejected {:?} for {}: {}", kind, user_id, e);
+        }
+    }
+
+    /// Take `amount` out of a user's balance and journal it out of the
+    /// exchange. Returns the journal, which reverses the withdrawal if its
+    /// payout fails; nothing changes if the ledger refuses it.
+    fn withdraw(&mut self, user_id: Uuid, currency: &str, amount: f64) -> Result<u64, String> {
+        self.orderbook.deduct_balance(user_id, currency, amount)?;
+        let journaled = self.ledger.transfer(
+            JournalKind::Withdrawal,
+            Account::external(currency),
+            Account::user(user_id, currency),
+            -to_ledger_units(amount),
+        );
+        journaled.inspect_err(|_| self.orderbook.credit_balance(user_id, currency, amount))
+    }
+
+    /// Put back the withdrawal journal `journal_id` took out of `user_id`'s
+    /// balance, posting its reversal. Returns the currency it was in.
+    fn reverse_withdrawal(&mut self, user_id: Uuid, journal_id: u64) -> Result<String, String> {
+        let unknown = || format!("No withdrawal {} of this user to reverse", journal_id);
+        let journal = self
+            .ledger
+            .journal(journal_id)
+            .filter(|journal| journal.kind == JournalKind::Withdrawal)
+            .ok_or_else(unknown)?;
+        let posting = journal
+            .postings
+            .iter()
+            .find(|posting| posting.account.owner == AccountOwner::User(user_id))
+            .ok_or_else(unknown)?;
+        let currency = posting.account.currency.clone();
+        let amount = from_ledger_units(-posting.amount);
+        self.ledger.reverse(journal_id)?;
+        self.orderbook.credit_balance(user_id, &currency, amount);
+        Ok(currency)
+    }
+
+    /// Credit a day's interest on idle balances for every day that has finished
+    /// since the last accrual
+    fn accrue_interest(&mut self, today: NaiveDate) {
+        if !self.interest.is_enabled() {
+            return;
+        }
+        let orderbook = &self.orderbook;
+        let accruals = self.interest.accrue(today, |user_id, currency| {
+            orderbook
+                .get_user_balance(user_id)
+                .map_or(0.0, |balance| balance.get_balance(currency))
+        });
+
+        for accrual in accruals {
+            self.orderbook
+                .credit_balance(accrual.user_id, &accrual.currency, accrual.amount);
+            let result = self.ledger.transfer(
+                JournalKind::Interest,
+                Account::new(AccountOwner::Treasury, &accrual.currency),
+                Account::user(accrual.user_id, &accrual.currency),
+                to_ledger_units(accrual.amount),
+            );
+            if let Err(e) = result {
+                eprintln!("Ledger rejected interest for {}: {}", accrual.user_id, e);
+            }
+        }
+    }
+
+    /// Pull every good-till-date order whose expiry has passed off the book and
+    /// refund what it still had reserved
+    fn expire_orders(&mut self, now: DateTime<Utc>) {
+        let mut expired_any = false;
+        for (order_id, expires_at) in self.expiries.take_due(now) {
+            // Filled or cancelled since it was scheduled
+            let Ok(order) = self.orderbook.cancel_order(order_id) else {
+                continue;
+            };
+            if let Err(e) = self.refund_remainder(&order) {
+                eprintln!("Expiry: {}", refund_refused(order_id, &e));
+            }
+            self.order_history.mark_expired(order_id, now);
+            self.publish(MarketEvent::OrderExpired {
+                order_id,
+                expires_at,
+            });
+            expired_any = true;
+        }
+        if expired_any {
+            self.publish_depth();
+        }
+    }
+
+    /// Settle a dated market once its expiry passes. Without a reference
+    /// price to settle at, or if a position cannot be paid, it stays halted
+    /// until an operator settles it.
+    fn settle_expired_market(&mut self, now: DateTime<Utc>) {
+        if let Err(e) = self.settle_market(None, now) {
+            eprintln!(
+                "Market {} expired but was not settled: {}",
+                self.market.symbol, e
+            );
+            self.settlement_blocked = true;
+        }
+    }
+
+    /// Close the market for good: stop trading, cancel every resting and
+    /// waiting order, and close every position opened by trading in it at
+    /// `price`, or by default at the market's external reference price. Each
+    /// long hands its BTC back to the shorts it bought from and is paid for
+    /// it by them, so nothing is created or destroyed and balances that never
+    /// traded here are left alone. All of it goes through, or none.
+    fn settle_market(
+        &mut self,
+        price: Option<Price>,
+        now: DateTime<Utc>,
+    ) -> Result<MarketSettlement, String> {
+        if self.market.settlement.is_some() {
+            return Err(format!("Market {} is already settled", self.market.symbol));
+        }
+        self.market.trading_status = TradingStatus::Halted;
+        self.cancel_all_orders(now)?;
+        if !self.netting.is_empty() {
+            self.settle_netting_window();
+        }
+        let price = price
+            .or(self.market.trade_through.reference_price)
+            .ok_or("No reference price to settle at")?;
+
+        let mut positions: Vec<(Uuid, f64)> = self
+            .positions
+            .iter()
+            .map(|(user_id, position)| (*user_id, position.quantity))
+            .filter(|(_, quantity)| quantity.abs() > BALANCE_TOLERANCE)
+            .collect();
+        positions.sort_by_key(|(user_id, _)| *user_id);
+        let mut changes = Vec::new();
+        let (mut btc_legs, mut usd_legs) = (Vec::new(), Vec::new());
+        for &(user_id, quantity) in &positions {
+            let value = quantity * price.to_f64();
+            changes.extend([(user_id, "BTC", -quantity), (user_id, "USD", value)]);
+            btc_legs.push(Posting::new(
+                Account::user(user_id, "BTC"),
+                -to_ledger_units(quantity),
+            ));
+            usd_legs.push(Posting::new(
+                Account::user(user_id, "USD"),
+                to_ledger_units(value),
+            ));
+        }
+        // Rounding each leg to ledger units can leave them a unit apart
+        for legs in [&mut btc_legs, &mut usd_legs] {
+            let net: LedgerAmount = legs.iter().map(|posting| posting.amount).sum();
+            if let Some(last) = legs.last_mut() {
+                last.amount -= net;
+            }
+        }
+        // A refused payout is raised as an incident
+        self.orderbook
+            .apply_balance_changes(BalanceOperation::Settlement, &changes)
+            .map_err(|e| format!("Positions could not be settled: {}", e))?;
+        if !btc_legs.is_empty() {
+            let postings = btc_legs.into_iter().chain(usd_legs).collect();
+            if let Err(e) = self.ledger.post(JournalKind::ExpirySettlement, postings) {
+                eprintln!(
+                    "Ledger rejected settlement of {}: {}",
+                    self.market.symbol, e
+                );
+            }
+        }
+        self.positions.clear();
+
+        let settlement = MarketSettlement {
+            price,
+            settled_at: now,
+            positions: positions.len(),
+        };
+        // Settling early brings the expiry forward, so trading cannot resume
+        self.market.expires_at = Some(self.market.expires_at.map_or(now, |at| at.min(now)));
+        self.market.trading_status = TradingStatus::Settled;
+        self.market.settlement = Some(settlement.clone());
+        self.settlement_blocked = false;
+        Ok(settlement)
+    }
+
+    /// Cancel every resting order, refunding what each still had reserved,
+    /// and drop every waiting stop. Every order is cancelled even if a refund
+    /// is refused; the first refusal is returned.
+    fn cancel_all_orders(&mut self, now: DateTime<Utc>) -> Result<(), String> {
+        let order_ids: Vec<Uuid> = self.orderbook.orders.keys().copied().collect();
+        let mut refunded = Ok(());
+        for order_id in order_ids {
+            if let Ok(order) = self.orderbook.cancel_order(order_id) {
+                let refund = self.refund_remainder(&order);
+                refunded = refunded.and(refund.map_err(|e| refund_refused(order_id, &e)));
+                self.order_history.mark_cancelled(order_id, now);
+            }
+        }
+        self.triggers.clear();
+        self.synthetics.clear_stops();
+        self.pegged.clear();
+        self.publish_depth();
+        refunded
+    }
+
+    /// Cancel a user's resting orders that `filter` selects, refunding what
+    /// each still had reserved. Returns the IDs of the cancelled orders, or
+    /// the first refund refused; the orders are cancelled either way.
+    fn cancel_user_orders(
+        &mut self,
+        user_id: Uuid,
+        filter: &OrderFilter,
+    ) -> Result<Vec<Uuid>, String> {
+        // Found through the per-user index, so the cost follows this user's
+        // open orders rather than the whole book
+        let order_ids = self.orderbook.matching_user_orders(user_id, filter);
+        let now = self.clock.now();
+        let mut refunded = Ok(());
+        for &order_id in &order_ids {
+            if let Ok(order) = self.orderbook.cancel_order(order_id) {
+                let refund = self.refund_remainder(&order);
+                refunded = refunded.and(refund.map_err(|e| refund_refused(order_id, &e)));
+                self.order_history.mark_cancelled(order_id, now);
+            }
+        }
+        if !order_ids.is_empty() {
+            self.publish_depth();
+        }
+        refunded.map(|()| order_ids)
+    }
+
+    /// Cancel everything resting for each user whose cancel-all-after deadline
+    /// has passed without being refreshed
+    fn fire_dead_man_switches(&mut self, now: DateTime<Utc>) {
+        let mut cancelled_any = false;
+        for user_id in self.dead_man.take_due(now) {
+            match self.cancel_user_orders(user_id, &OrderFilter::default()) {
+                Ok(cancelled) => cancelled_any |= !cancelled.is_empty(),
+                Err(e) => {
+                    eprintln!("Cancel-all-after for {}: {}", user_id, e);
+                    cancelled_any = true;
+                }
+            }
+        }
+        if cancelled_any && !self.pegged.is_empty() {
+            self.reprice_pegged_orders();
+        }
+    }
+
+    /// Move resting pegged orders to follow the BBO. Only does work when the
+    /// reference prices changed since the last pass.
+    fn reprice_pegged_orders(&mut self) {
+        let (best_bid, best_ask) = self.orderbook.reference_bbo();
+        if (best_bid, best_ask) == self.peg_reference {
+            return;
+        }
+        self.peg_reference = (best_bid, best_ask);
+
+        for order_id in std::mem::take(&mut self.pegged) {
+            let Some(order) = self.orderbook.get_order(order_id) else {
+                continue; // Filled, cancelled or expired since
+            };
+            let reprice = order
+                .peg
+                .and_then(|peg| peg.price(order.side, best_bid, best_ask))
+                .filter(|price| order.price != Some(*price));
+            if let Some(price) = reprice {
+                if let Err(e) = self.reprice_order(order_id, price, |_| {}) {
+                    eprintln!("Failed to re-price pegged order {}: {}", order_id, e);
+                }
+                self.orderbook.take_settlement_time();
+            }
+            if self.orderbook.orders.contains_key(&order_id) {
+                self.pegged.push(order_id);
+            }
+        }
+    }
+
+    /// Validate an amendment before anything is changed, so a rejected one
+    /// leaves the order as it was. Returns the new price, if it differs, and
+    /// how much to take off the order's size.
+    fn check_amendment(
+        &self,
+        user_id: Uuid,
+        order_id: Uuid,
+        price: Option<Price>,
+        quantity: Option<Quantity>,
+    ) -> Result<(Option<Price>, Option<Quantity>), String> {
+        let order = self
+            .orderbook
+            .get_order(order_id)
+            .ok_or("Order not found or no longer resting")?;
+        if order.user_id != user_id {
+            return Err("Not authorized to amend this order".to_string());
+        }
+
+        let new_price = price.filter(|price| order.price != Some(*price));
+        if new_price.is_some() && order.peg.is_some() {
+            return Err("Pegged orders follow their reference price".to_string());
+        }
+
+        let filled = order.original_quantity - order.remaining_quantity;
+        let cut = match quantity {
+            Some(quantity) if quantity > order.original_quantity => {
+                return Err("Amendments can only reduce quantity".to_string())
+            }
+            Some(quantity) if quantity <= filled => {
+                return Err("quantity must be larger than what has already filled".to_string())
+            }
+            Some(quantity) => Some(order.original_quantity - quantity).filter(|cut| !cut.is_zero()),
+            None => None,
+        };
+
+        // A buy re-priced higher needs more cash reserved for what is left
+        if let (Buy, Some(new_price), Some(old_price)) = (order.side, new_price, order.price) {
+            let remaining = order.remaining_quantity - cut.unwrap_or_default();
+            let extra = (new_price.to_f64() - old_price.to_f64()) * remaining.to_f64();
+            if extra > 0.0 && !self.orderbook.has_sufficient_balance(user_id, "USD", extra) {
+                return Err("Insufficient USD balance".to_string());
+            }
+        }
+
+        if new_price.is_none() && cut.is_none() {
+            return Err("Nothing to amend".to_string());
+        }
+        Ok((new_price, cut))
+    }
+
+    /// Move a resting order to `price`, with `change` made to it on the way.
+    /// It goes to the back of the queue there and trades first if the new
+    /// price crosses the book. If its reservation cannot be moved, the order
+    /// goes back as it was. Returns the trades and the time spent matching.
+    fn reprice_order(
+        &mut self,
+        order_id: Uuid,
+        price: Price,
+        change: impl FnOnce(&mut Order),
+    ) -> Result<(Vec<Trade>, Duration), String> {
+        let original = self.orderbook.cancel_order(order_id)?;
+        // The reservation follows the price: release the old one, take the new one
+        if let Err(e) = self.refund_remainder(&original) {
+            // Nothing was released, so the order can rest where it was
+            self.orderbook.add_order(original);
+            return Err(refund_refused(order_id, &e));
+        }
+        let mut order = original.clone();
+        change(&mut order);
+        order.price = Some(price);
+        if let Err(e) = self.reserve_remainder(&order) {
+            // Its receipt time puts it back at its place in the queue
+            if self.reserve_remainder(&original).is_ok() {
+                self.orderbook.add_order(original);
+                return Err(format!("cannot reserve at {}: {}", price, e));
+            }
+            let now = self.clock.now();
+            self.order_history.mark_cancelled(order_id, now);
+            self.publish_depth();
+            return Err(format!("cancelled, cannot reserve at {}: {}", price, e));
+        }
+        self.execute_order(&mut order)
+    }
+
+    /// Time-driven work: fire lapsed dead man's switches, expire good-till-date
+    /// orders, settle a due netting window, accrue interest for finished days
+    /// and forget closed orders and client order IDs that are no longer needed. Runs after every
+    /// command and on the engine's idle tick; both readings are moved forward
+    /// by however far the clock was advanced.
+    pub fn run_scheduled(&mut self, now: Instant) {
+        let now = self.clock.shift(now);
+        let wall_clock = self.clock.now();
+        if self.market.has_expired(wall_clock)
+            && self.market.settlement.is_none()
+            && !self.settlement_blocked
+        {
+            self.settle_expired_market(wall_clock);
+        }
+        if !self.dead_man.is_empty() {
+            self.fire_dead_man_switches(wall_clock);
+        }
+        if !self.expiries.is_empty() {
+            self.expire_orders(wall_clock);
+            if !self.pegged.is_empty() {
+                self.reprice_pegged_orders();
+            }
+        }
+        self.settle_due_netting(now);
+        self.accrue_interest(wall_clock.date_naive());
+        self.order_history.prune(wall_clock, self.history_retention);
+        let (book, triggers, synthetics) = (&self.orderbook, &self.triggers, &self.synthetics);
+        self.client_ids.prune(wall_clock, |id| {
+            book.get_order(id).is_some()
+                || triggers.get(id).is_some()
+                || synthetics.stop(id).is_some()
+        });
+        if self.dead_letters.has_pending() {
+            self.retry_dead_letters(wall_clock);
+        }
+    }
+
+    /// When the next dead man's switch or good-till-date expiry falls due, as
+    /// an instant the run loop can sleep until instead of waiting for its
+    /// next tick
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let due = [self.dead_man.next_deadline(), self.expiries.next_due()]
+            .into_iter()
+            .flatten()
+            .min()?;
+        let wait = (due - self.clock.now()).to_std().unwrap_or_default();
+        Some(Instant::now() + wait)
+    }
+
+    fn interest_summary(&self, user_id: Uuid) -> InterestSummary {
+        let balance = self.orderbook.get_user_balance(user_id);
+        self.interest.summary(user_id, |currency| {
+            balance.map_or(0.0, |balance| balance.get_balance(currency))
+        })
+    }
+
+    /// Fire every stop the last trade price has crossed. Triggered orders trade
+    /// in turn and may move the price through further stops, so repeat until
+    /// nothing more fires.
+    fn activate_triggered_stops(&mut self) {
+        while let Some(last_price) = self.orderbook.last_trade_price {
+            let triggered = self.triggers.take_triggered(last_price);
+            if triggered.is_empty() {
+                return;
+            }
+            for stop in triggered {
+                let mut order = stop.into_market_order(self.clock.now());
+                if let Err(e) = self.execute_order(&mut order) {
+                    self.reject_triggered_stop(order, e);
+                }
+                self.orderbook.take_settlement_time();
+            }
+        }
+    }
+
+    /// Work out the synthetic indices again from the last trade, publish the
+    /// values that moved, and fire the stops they crossed. True if any fired,
+    /// since their trades move the price again.
+    fn activate_index_stops(&mut self) -> bool {
+        let market = self.market.symbol.clone();
+        let last_price = self.orderbook.last_trade_price;
+        let changed = self
+            .synthetics
+            .reprice(|component| last_price.filter(|_| component == market));
+        let mut fired = false;
+        for (symbol, value) in changed {
+            let value = match value {
+                Ok(value) => value,
+                Err(e) => {
+                    // Listed with the index until it values again
+                    self.metrics.record_index_value_failure();
+                    eprintln!("{}", e);
+                    continue;
+                }
+            };
+            self.publish(MarketEvent::IndexValue {
+                symbol: symbol.clone(),
+                value,
+            });
+            for stop in self.synthetics.take_triggered(&symbol) {
+                fired = true;
+                let mut order = stop.into_market_order(self.clock.now());
+                if let Err(e) = self.execute_order(&mut order) {
+                    self.reject_triggered_stop(order, e);
+                }
+                self.orderbook.take_settlement_time();
+            }
+        }
+        fired
+    }
+
+    /// Keep a triggered stop that could not execute in its owner's order
+    /// history as rejected, which also sends it to them on the user stream.
+    /// One that traded before failing is already there in its latest state.
+    fn reject_triggered_stop(&mut self, mut order: Order, reason: String) {
+        eprintln!("Triggered stop order {} failed: {}", order.id, reason);
+        if self.order_history.get(order.id).is_some() {
+            return;
+        }
+        order.reject(reason);
+        self.order_history.upsert(&order);
+    }
+
+    /// A stop order still waiting for its trigger, on the last trade price or an index
+    fn waiting_stop(&self, id: Uuid) -> Option<&StopOrder> {
+        self.triggers.get(id).or_else(|| self.synthetics.stop(id))
+    }
+
+    fn remove_waiting_stop(&mut self, id: Uuid) -> Option<StopOrder> {
+        self.triggers
+            .remove(id)
+            .or_else(|| self.synthetics.remove_stop(id))
+    }
+
+    /// Apply a single command and send its response
+    pub fn process(&mut self, command: OrderBookCommand) {
+        // Close out finished days before this command can change the book
+        if let Err(e) = self
+            .daily_stats
+            .roll_over(self.clock.now(), self.orderbook.orders.len() as u64)
+        {
+            eprintln!("Failed to persist daily stats: {}", e);
+        }
+
+        // Skip queries whose caller gave up; mutations are never skipped
+        if let Some(reason) = command.is_abandoned(Instant::now()) {
+            self.metrics.record_abandoned(reason);
+            return;
+        }
+        self.metrics.record_processed();
+
+        self.apply(command);
+        self.follow_trades();
+        self.run_scheduled(Instant::now());
+        self.finish_changes();
+    }
+
+    /// Rebuild the state the last run left, before taking new commands:
+    /// restore `snapshot` if it was taken of this log, then replay the
+    /// commands logged after it. Orders they create get the IDs they had the
+    /// first time. An unusable snapshot is reported and the whole log replayed.
+    pub fn recover(
+        &mut self,
+        log: &dyn EventLog,
+        records: Vec<WalRecord>,
+        snapshot: Result<Option<EngineSnapshot>, String>,
+    ) -> Result<RecoveryReport, String> {
+        let started_at = Utc::now();
+        let started = std::time::Instant::now();
+        self.order_ids = OrderIds::with_log(log.log_id());
+
+        let mut snapshot_error = None;
+        let snapshot = match snapshot {
+            Ok(Some(snapshot)) if snapshot.log_id != log.log_id() => {
+                snapshot_error = Some(format!(
+                    "Snapshot was taken of log {}, not {}",
+                    snapshot.log_id,
+                    log.log_id()
+                ));
+                None
+            }
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                snapshot_error = Some(e);
+                None
+            }
+        };
+        // The log lost commands the snapshot has seen; new ones would reuse their numbers
+        if let Some(snapshot) = snapshot.as_ref().filter(|s| s.wal_seq > log.last_seq()) {
+            return Err(format!(
+                "Snapshot is at command {} but the log ends at {}",
+                snapshot.wal_seq,
+                log.last_seq()
+            ));
+        }
+
+        let (snapshot_seq, snapshot_taken_at) = match snapshot {
+            Some(snapshot) => {
+                let taken = (Some(snapshot.wal_seq), Some(snapshot.taken_at));
+                self.restore(snapshot)?;
+                taken
+            }
+            None => (None, None),
+        };
+        // The log may have dropped the commands a snapshot held; without that
+        // snapshot there is nothing to rebuild them from
+        let after = snapshot_seq.unwrap_or(0);
+        let resumes_at = records
+            .iter()
+            .find(|record| record.seq > after)
+            .map_or(log.last_seq(), |record| record.seq - 1);
+        if resumes_at != after {
+            return Err(format!(
+                "The log has no commands {} to {}, and no usable snapshot holds them",
+                after + 1,
+                resumes_at
+            ));
+        }
+        let mut replayed = 0;
+        for record in records.into_iter().filter(|record| record.seq > after) {
+            self.replay(record);
+            replayed += 1;
+        }
+        self.metrics.record_wal_replayed(replayed);
+
+        let mut available = BTreeMap::new();
+        for balance in self.orderbook.user_balances.values() {
+            for (currency, amount) in &balance.balances {
+                *available.entry(currency.clone()).or_insert(0.0) += amount;
+            }
+        }
+        let report = RecoveryReport {
+            log_id: log.log_id(),
+            started_at,
+            duration_ms: started.elapsed().as_millis() as u64,
+            snapshot_seq,
+            snapshot_taken_at,
+            snapshot_error,
+            commands_replayed: replayed,
+            last_seq: log.last_seq(),
+            resting_orders: self.orderbook.orders.len(),
+            stop_orders: self.triggers.len() + self.synthetics.stop_count(),
+            users: self.orderbook.user_balances.len(),
+            available,
+            reserved: self.reserved_balances(),
+        };
+        self.recovery = Some(report.clone());
+        Ok(report)
+    }
+
+    /// What open orders and held trades set aside out of their owners'
+    /// balances, per currency
+    fn reserved_balances(&self) -> BTreeMap<String, f64> {
+        let mut reserved = BTreeMap::new();
+        for balance in self.orderbook.user_balances.values() {
+            for (currency, amount) in &balance.reserved {
+                *reserved.entry(currency.clone()).or_insert(0.0) += amount;
+            }
+        }
+        reserved
+    }
+
+    /// What it takes to rebuild this engine, as of command `wal_seq` of log
+    /// `log_id`. Only copies state; writing it is left to the caller. None
+    /// while netted trades wait to settle: their window runs on the
+    /// monotonic clock, which a restart does not keep.
+    pub fn snapshot(&self, log_id: Uuid, wal_seq: u64) -> Option<EngineSnapshot> {
+        if !self.netting.is_empty() {
+            return None;
+        }
+        let book = &self.orderbook;
+        let mut balances: Vec<_> = book.user_balances.values().cloned().collect();
+        balances.sort_by_key(|balance| balance.user_id);
+        let mut positions: Vec<_> = self.positions.iter().map(|(k, v)| (*k, *v)).collect();
+        positions.sort_by_key(|(user_id, _)| *user_id);
+        Some(EngineSnapshot {
+            version: SNAPSHOT_VERSION,
+            log_id,
+            wal_seq,
+            taken_at: self.clock.now(),
+            clock_offset: self.clock.offset(),
+            market: self.market.clone(),
+            settlement_blocked: self.settlement_blocked,
+            positions,
+            orders: book.resting_orders(),
+            balances,
+            last_trade_price: book.last_trade_price,
+            rolling_mark_price: book.rolling_mark_price,
+            session_open_price: book.session_open_price,
+            session_date: book.session_date,
+            pegged: self.pegged.clone(),
+            peg_reference: self.peg_reference,
+            stops: self.triggers.clone(),
+            synthetics: self.synthetics.clone(),
+            client_ids: self.client_ids.records().clone(),
+            recent_orders: self.duplicate_guard.recent_orders(),
+            order_history: self.order_history.open_orders(),
+            margin: self.margin.clone(),
+            interest: self.interest.state(),
+            dead_man: self.dead_man.clone(),
+            incidents: self.incidents.clone(),
+            dead_letters: self.dead_letters.clone(),
+            fx_rates: self.settlement_rates.list(),
+            fx_version: self.settlement_rates.last_version(),
+            ledger_balances: self.ledger.balances(),
+            last_journal_id: self.ledger.last_journal_id(),
+            tape_seq: self.trade_tape.last_seq(),
+            market_seq: self.market_seq,
+            execution_quality: self.execution_quality.clone(),
+            source_volume: self.source_volume.clone(),
+            price_averages: self.price_averages.clone(),
+            daily_stats: self.daily_stats.in_progress(),
+        })
+    }
+
+    /// Take up the state `snapshot` holds in place of this engine's own.
+    /// Balances come back as they were, with resting orders' reservations
+    /// already held, so putting the orders back moves no money. A clock
+    /// offset out of range is refused before anything is taken up.
+    fn restore(&mut self, snapshot: EngineSnapshot) -> Result<(), String> {
+        let mut clock = EngineClock::new();
+        clock
+            .advance(snapshot.clock_offset)
+            .map_err(|e| format!("Snapshot clock offset: {}", e))?;
+        self.clock = clock;
+        self.orderbook.sweep_limit = snapshot.market.sweep_limit;
+        self.orderbook.allocation = snapshot.market.allocation;
+        self.orderbook.fees = snapshot.market.fees;
+        self.market = snapshot.market;
+        self.settlement_blocked = snapshot.settlement_blocked;
+        self.positions = snapshot.positions.into_iter().collect();
+
+        for order in snapshot.orders {
+            if let Some(expires_at) = order.expires_at {
+                self.expiries.schedule(order.id, expires_at);
+            }
+            self.orderbook.restore_order(order);
+        }
+        self.orderbook.user_balances = snapshot
+            .balances
+            .into_iter()
+            .map(|balance| (balance.user_id, balance))
+            .collect();
+        self.orderbook.last_trade_price = snapshot.last_trade_price;
+        self.orderbook.rolling_mark_price = snapshot.rolling_mark_price;
+        self.orderbook.session_open_price = snapshot.session_open_price;
+        self.orderbook.session_date = snapshot.session_date;
+        self.pegged = snapshot.pegged;
+        self.peg_reference = snapshot.peg_reference;
+        self.triggers = snapshot.stops;
+        self.synthetics.restore(snapshot.synthetics)?;
+
+        self.client_ids.restore(snapshot.client_ids);
+        self.duplicate_guard.restore(snapshot.recent_orders);
+        self.order_history = snapshot.order_history;
+        self.margin = snapshot.margin;
+        self.interest.restore(snapshot.interest);
+        self.dead_man = snapshot.dead_man;
+        self.incidents = snapshot.incidents;
+        self.dead_letters = snapshot.dead_letters;
+        self.settlement_rates = SettlementRates::restore(snapshot.fx_rates, snapshot.fx_version);
+        self.ledger
+            .restore(snapshot.ledger_balances, snapshot.last_journal_id);
+        self.trade_tape.continue_after(snapshot.tape_seq);
+        self.market_seq = snapshot.market_seq;
+        self.metrics.record_market_seq(self.market_seq);
+        self.execution_quality = snapshot.execution_quality;
+        self.source_volume = snapshot.source_volume;
+        self.price_averages = snapshot.price_averages;
+        self.daily_stats.resume(snapshot.daily_stats);
+        Ok(())
+    }
+
+    /// Write a snapshot as of the last command in `log` and wait for it, so
+    /// a restart replays only what comes after it. False if it was put off
+    /// or could not be written. The engine loop writes on a blocking thread
+    /// instead, and only waits for that on shutdown.
+    pub fn save_snapshot(&self, log: &dyn EventLog) -> bool {
+        let Some(snapshot) = self.snapshot(log.log_id(), log.last_seq()) else {
+            return false;
+        };
+        self.snapshot_written(log.snapshots().write_snapshot(&snapshot))
+    }
+
+    /// Count how writing a snapshot went; true if it is on disk
+    fn snapshot_written(&self, written: Result<(), String>) -> bool {
+        match written {
+            Ok(()) => {
+                self.metrics.record_snapshot();
+                true
+            }
+            Err(e) => {
+                eprintln!("Failed to write the engine snapshot: {}", e);
+                self.metrics.record_snapshot_failure();
+                false
+            }
+        }
+    }
+
+    /// Run a logged command again at the time it was logged. Scheduled work
+    /// that was due by then runs first, as it would have on the idle tick.
+    fn replay(&mut self, record: WalRecord) {
+        self.replaying = true;
+        self.clock.pin(record.at);
+        self.run_scheduled(Instant::now());
+        self.finish_changes();
+
+        let (response_tx, _response_rx) = oneshot::channel();
+        self.order_ids.begin(record.seq);
+        self.process(record.command.into_command(response_tx));
+
+        self.clock.unpin();
+        self.replaying = false;
+    }
+
+    /// Report and save what the last command or scheduled run changed
+    fn finish_changes(&mut self) {
+        self.unschedule_closed_orders();
+        self.raise_balance_incidents();
+        let balances = self.changed_balances();
+        self.publish_account_updates(&balances);
+        self.persist(balances);
+    }
+
+    /// Take good-till-date orders the command filled or cancelled off the
+    /// expiry schedule
+    fn unschedule_closed_orders(&mut self) {
+        if self.expiries.is_empty() {
+            return;
+        }
+        let closed: Vec<Uuid> = self
+            .order_history
+            .unsaved()
+            .filter(|order| order.expires_at.is_some())
+            .filter(|order| {
+                !matches!(
+                    order.status,
+                    OrderStatus::Open | OrderStatus::PartiallyFilled
+                )
+            })
+            .map(|order| order.id)
+            .collect();
+        for order_id in closed {
+            self.expiries.unschedule(order_id);
+        }
+    }
+
+    /// The balances of each user the command changed them for, by user ID
+    fn changed_balances(&mut self) -> Vec<(Uuid, HashMap<String, f64>)> {
+        let mut users: Vec<Uuid> = self.orderbook.take_changed_balances().into_iter().collect();
+        users.sort();
+        users
+            .into_iter()
+            .filter_map(|user_id| {
+                let balance = self.orderbook.get_user_balance(user_id)?;
+                Some((user_id, balance.balances.clone()))
+            })
+            .collect()
+    }
+
+    /// Send each changed order and balance to its owner, orders in the order
+    /// they were accepted. Fills went out while matching, before these.
+    fn publish_account_updates(&mut self, balances: &[(Uuid, HashMap<String, f64>)]) {
+        // Counted for feed activity whether or not anyone is listening
+        let order_events = self.order_history.take_change_count();
+        if order_events > 0 {
+            self.feed_activity
+                .record_order_events(order_events, self.clock.now());
+            self.metrics.record_order_events(order_events);
+        }
+        if !self.events.has_account_subscribers() {
+            return;
+        }
+        let mut orders: Vec<Order> = self.order_history.unsaved().cloned().collect();
+        orders.sort_by_key(|order| (order.timestamp, order.id));
+        for order in orders {
+            self.publish(MarketEvent::Order(Box::new(order)));
+        }
+        for (user_id, balances) in balances {
+            self.publish(MarketEvent::Balance {
+                user_id: *user_id,
+                balances: balances.clone(),
+            });
+        }
+    }
+
+    /// Hand what the command changed to the history worker to write. Each
+    /// write is retried a few times before it is given up on, counted in
+    /// `history_write_failures` and logged; the in-memory state stays
+    /// authoritative. The order updates and balance changes are queued on the
+    /// outbox behind the records, in the order the feed sent them.
+    fn persist(&mut self, balances: Vec<(Uuid, HashMap<String, f64>)>) {
+        let orders = self.order_history.take_unsaved();
+        let trades = std::mem::take(&mut self.unsaved_trades);
+        let journals = self.ledger.take_unsaved();
+        // A replayed command's records were saved when it first ran
+        let unchanged = orders.is_empty() && trades.is_empty() && journals.is_empty();
+        if self.replaying || (unchanged && balances.is_empty()) {
+            return;
+        }
+        let mut updated: Vec<&Order> = orders.iter().collect();
+        updated.sort_by_key(|order| (order.timestamp, order.id));
+        let events: Vec<OutboxEvent> = updated
+            .into_iter()
+            .map(OutboxEvent::order_updated)
+            .chain(
+                balances
+                    .iter()
+                    .map(|(user_id, balances)| OutboxEvent::balance_changed(*user_id, balances)),
+            )
+            .collect();
+        let metrics = self.metrics.clone();
+        self.history.submit(move |store| {
+            if !orders.is_empty() {
+                write_with_retries(&metrics, "orders", || store.save_orders(&orders));
+            }
+            if !trades.is_empty() {
+                write_with_retries(&metrics, "trades", || store.save_trades(&trades));
+            }
+            if !journals.is_empty() {
+                write_with_retries(&metrics, "journals", || store.save_journals(&journals));
+            }
+            if !events.is_empty() {
+                write_with_retries(&metrics, "outbox events", || store.queue_events(&events));
+            }
+        });
+    }
+
+    /// Catch up with what the last command traded
+    fn follow_trades(&mut self) {
+        // Trades may have crossed resting stop triggers, directly or by moving
+        // an index, and the stops that fire trade in turn
+        loop {
+            if !self.triggers.is_empty() {
+                self.activate_triggered_stops();
+            }
+            if self.synthetics.is_empty() || !self.activate_index_stops() {
+                break;
+            }
+        }
+
+        // Pegged orders follow whatever BBO the command and any stops left
+        if !self.pegged.is_empty() {
+            self.reprice_pegged_orders();
+        }
+    }
+
+    fn apply(&mut self, command: OrderBookCommand) {
+        match command {
+            OrderBookCommand::PlaceLimitOrder {
+                user_id,
+                side,
+                price,
+                quantity,
+                time_in_force,
+                display_quantity,
+                hidden,
+                min_fill_qty,
+                expires_at,
+                peg,
+                trade_through_protected,
+                priority_fee,
+                received_at,
+                source,
+                client_order_id,
+                response_tx,
+            } => {
+                if let Some(response) = self
+                    .halted_response()
+                    .or_else(|| self.quarantined_response(user_id))
+                {
+                    respond(&self.metrics, response_tx, response);
+                    return;
+                }
+                if expires_at.is_some_and(|at| at <= self.clock.now()) {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error {
+                            message: "expires_at must be in the future".to_string(),
+                        },
+                    );
+                    return;
+                }
+
+                let started = Instant::now();
+                let queue_wait = (Utc::now() - received_at).to_std().unwrap_or_default();
+
+                let mut order = Order::new_limit(user_id, side, price, quantity)
+                    .with_id(self.order_ids.next_id())
+                    .with_timestamp(self.clock.now())
+                    .with_source(source)
+                    .with_time_in_force(time_in_force)
+                    .with_display_quantity(display_quantity)
+                    .with_hidden(hidden)
+                    .with_min_fill_qty(min_fill_qty)
+                    .with_expires_at(expires_at)
+                    .with_peg(peg)
+                    .with_received_at(received_at)
+                    .with_client_order_id(client_order_id);
+                let order_id = order.id;
+
+                let checked = self
+                    .duplicate_guard
+                    .check(&order)
+                    .and_then(|_| self.check_client_order_id(&order));
+                if let Err(message) = checked {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error { message },
+                    );
+                    return;
+                }
+
+                // A protected order is refused outright rather than partly
+                // filled up to the band
+                let band_limit = self.market.trade_through.limit(side);
+                if let Some(limit) = band_limit.filter(|_| trade_through_protected) {
+                    if self
+                        .orderbook
+                        .would_trade_beyond(side, price, quantity, limit)
+                    {
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error {
+                                message: format!(
+                                    "Order would trade through the reference price band at {}",
+                                    limit
+                                ),
+                            },
+                        );
+                        return;
+                    }
+                }
+
+                if priority_fee > 0.0 {
+                    let notional = price.to_f64() * quantity.to_f64();
+                    if let Err(message) = self.check_priority_fee(&order, priority_fee, notional) {
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error { message },
+                        );
+                        return;
+                    }
+                }
+
+                // Check balance before placing order
+                match side {
+                    Buy => {
+                        // Need USD to buy BTC
+                        let usd_needed = price.to_f64() * quantity.to_f64();
+                        if !self
+                            .orderbook
+                            .has_sufficient_balance(user_id, "USD", usd_needed)
+                        {
+                            respond(
+                                &self.metrics,
+                                response_tx,
+                                OrderBookResponse::Error {
+                                    message: "Insufficient USD balance".to_string(),
+                                },
+                            );
+                            return;
+                        }
+                        // Reserve USD
+                        if let Err(e) = self.orderbook.reserve_balance(user_id, "USD", usd_needed) {
+                            respond(
+                                &self.metrics,
+                                response_tx,
+                                OrderBookResponse::Error {
+                                    message: format!("Failed to reserve USD: {}", e),
+                                },
+                            );
+                            return;
+                        }
+                    }
+                    Sell => {
+                        // Need BTC to sell
+                        let btc_needed = quantity.to_f64();
+                        if !self
+                            .orderbook
+                            .has_sufficient_balance(user_id, "BTC", btc_needed)
+                        {
+                            respond(
+                                &self.metrics,
+                                response_tx,
+                                OrderBookResponse::Error {
+                                    message: "Insufficient BTC balance".to_string(),
+                                },
+                            );
+                            return;
+                        }
+                        // Reserve BTC
+                        if let Err(e) = self.orderbook.reserve_balance(user_id, "BTC", btc_needed) {
+                            respond(
+                                &self.metrics,
+                                response_tx,
+                                OrderBookResponse::Error {
+                                    message: format!("Failed to reserve BTC: {}", e),
+                                },
+                            );
+                            return;
+                        }
+                    }
+                }
+
+                // Paid on acceptance, whether or not the order comes to rest
+                match self.charge_fee(user_id, priority_fee) {
+                    Ok(fee) => order.priority_fee = fee,
+                    Err(e) => {
+                        // Refused before it reached the book, so nothing else stays reserved
+                        let message = match self.refund_remainder(&order) {
+                            Ok(()) => format!("Order rejected: {}", e),
+                            Err(refund) => format!("Order rejected: {}; {}", e, refund),
+                        };
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error { message },
+                        );
+                        return;
+                    }
+                }
+
+                // Snapshot the top of book at acceptance for best-execution records
+                self.orderbook.now = self.clock.now();
+                let arrival_bbo = self.orderbook.bbo_snapshot();
+                order.arrival_bbo = Some(arrival_bbo);
+
+                let match_started = Instant::now();
+                let result = self.orderbook.match_order(&mut order);
+                let matching = match_started.elapsed();
+                let settlement = self.orderbook.take_settlement_time();
+                self.park_unsettled_trades();
+
+                match result {
+                    Ok(mut trades) => {
+                        annotate_price_improvement(
+                            &mut trades,
+                            side,
+                            Some(price),
+                            arrival_bbo.opposite(side),
+                        );
+                        let mut released = self.release_price_improvement(&order, &trades);
+                        self.duplicate_guard.record(&order);
+                        self.record_client_order_id(&order);
+                        self.order_history.upsert(&order);
+                        self.record_trades(&mut trades, side);
+                        self.publish_depth();
+                        if self.orderbook.orders.contains_key(&order_id) {
+                            // A good-till-date remainder rests until its expiry
+                            if let Some(expires_at) = order.expires_at {
+                                self.expiries.schedule(order_id, expires_at);
+                            }
+                            if order.peg.is_some() {
+                                self.pegged.push(order_id);
+                            }
+                        }
+                        let status = if order.status == OrderStatus::Cancelled {
+                            // IOC remainder never rests, and neither does a GTC one
+                            // that would cross the book: release what was reserved for it
+                            released = released.and(self.refund_remainder(&order));
+                            let cancelled = match trades.is_empty() {
+                                true => "Cancelled",
+                                false => "Partially filled, remainder cancelled",
+                            };
+                            match order.time_in_force {
+                                TimeInForce::IOC if trades.is_empty() => {
+                                    "Cancelled, no immediate match".to_string()
+                                }
+                                TimeInForce::IOC => cancelled.to_string(),
+                                TimeInForce::GTC => format!(
+                                    "{}, it would cross orders with a larger minimum fill",
+                                    cancelled
+                                ),
+                            }
+                        } else if trades.is_empty() {
+                            "Added to book".to_string()
+                        } else {
+                            "Matched".to_string()
+                        };
+
+                        let response = match released {
+                            Ok(()) => OrderBookResponse::OrderPlaced {
+                                order_id,
+                                trades,
+                                status,
+                                timings: OrderTimings::new(
+                                    queue_wait,
+                                    matching,
+                                    settlement,
+                                    started.elapsed(),
+                                ),
+                            },
+                            Err(e) => OrderBookResponse::Error {
+                                message: refund_refused(order_id, &e),
+                            },
+                        };
+                        respond(&self.metrics, response_tx, response);
+                    }
+                    Err(e) => {
+                        // Nothing traded or rested: hand back the fee and the reservation
+                        let refunded = self
+                            .refund_fee(user_id, order.priority_fee)
+                            .and_then(|()| self.refund_remainder(&order));
+                        let message = match refunded {
+                            Ok(()) => format!("Failed to place order: {}", e),
+                            Err(refund) => format!(
+                                "Failed to place order: {}; {}",
+                                e,
+                                refund_refused(order_id, &refund)
+                            ),
+                        };
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error { message },
+                        );
+                    }
+                }
+            }
+
+            OrderBookCommand::PlacePeggedOrder {
+                user_id,
+                side,
+                quantity,
+                peg,
+                expires_at,
+                received_at,
+                source,
+                client_order_id,
+                response_tx,
+            } => {
+                let (best_bid, best_ask) = self.orderbook.reference_bbo();
+                let Some(price) = peg.price(side, best_bid, best_ask) else {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error {
+                            message: "No reference price to peg to".to_string(),
+                        },
+                    );
+                    return;
+                };
+
+                // From here on it is a resting limit order that remembers its peg
+                self.apply(OrderBookCommand::PlaceLimitOrder {
+                    user_id,
+                    side,
+                    price,
+                    quantity,
+                    time_in_force: TimeInForce::GTC,
+                    display_quantity: None,
+                    hidden: false,
+                    min_fill_qty: None,
+                    expires_at,
+                    peg: Some(peg),
+                    trade_through_protected: false,
+                    priority_fee: 0.0,
+                    received_at,
+                    source,
+                    client_order_id,
+                    response_tx,
+                });
+            }
+
+            OrderBookCommand::PlaceMarketOrder {
+                user_id,
+                side,
+                quantity,
+                slippage,
+                received_at,
+                source,
+                client_order_id,
+                response_tx,
+            } => {
+                if let Some(response) = self
+                    .halted_response()
+                    .or_else(|| self.quarantined_response(user_id))
+                {
+                    respond(&self.metrics, response_tx, response);
+                    return;
+                }
+
+                let started = Instant::now();
+                let queue_wait = (Utc::now() - received_at).to_std().unwrap_or_default();
+
+                // A basis-point guard is measured from the best price right now
+                let worst_price = slippage
+                    .and_then(|guard| guard.worst_price(side, self.orderbook.bbo_snapshot().opposite(side)));
+
+                let mut order = Order::new_market(user_id, side, quantity)
+                    .with_id(self.order_ids.next_id())
+                    .with_timestamp(self.clock.now())
+                    .with_source(source)
+                    .with_received_at(received_at)
+                    .with_client_order_id(client_order_id)
+                    .with_worst_price(worst_price);
+                let order_id = order.id;
+
+                let checked = self
+                    .duplicate_guard
+                    .check(&order)
+                    .and_then(|_| self.check_client_order_id(&order));
+                if let Err(message) = checked {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error { message },
+                    );
+                    return;
+                }
+
+                // Checks and reserves the balance the sweep can spend before matching
+                let result = self.execute_order(&mut order);
+                let settlement = self.orderbook.take_settlement_time();
+
+                match result {
+                    Ok((trades, matching)) => {
+                        self.duplicate_guard.record(&order);
+                        self.record_client_order_id(&order);
+                        let beyond_worst = self.beyond_worst_price(&order);
+                        let status = if trades.is_empty() && beyond_worst {
+                            "Not filled, best price is beyond the slippage limit".to_string()
+                        } else if trades.is_empty() {
+                            "No liquidity".to_string()
+                        } else if !order.is_fully_filled() && beyond_worst {
+                            "Partially filled, remainder cancelled at slippage limit".to_string()
+                        } else if !order.is_fully_filled() {
+                            "Partially filled, remainder cancelled at sweep limit".to_string()
+                        } else {
+                            "Filled".to_string()
+                        };
+
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::OrderPlaced {
+                                order_id,
+                                trades,
+                                status,
+                                timings: OrderTimings::new(
+                                    queue_wait,
+                                    matching,
+                                    settlement,
+                                    started.elapsed(),
+                                ),
+                            },
+                        );
+                    }
+                    Err(e) => {
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error {
+                                message: format!("Failed to place market order: {}", e),
+                            },
+                        );
+                    }
+                }
+            }
+
+            OrderBookCommand::PlaceStopOrder {
+                user_id,
+                side,
+                quantity,
+                stop_price,
+                trigger_index,
+                received_at,
+                source,
+                client_order_id,
+                response_tx,
+            } => {
+                if let Some(response) = self
+                    .halted_response()
+                    .or_else(|| self.quarantined_response(user_id))
+                {
+                    respond(&self.metrics, response_tx, response);
+                    return;
+                }
+
+                let started = Instant::now();
+                let queue_wait = (Utc::now() - received_at).to_std().unwrap_or_default();
+
+                let mut stop = StopOrder::new(user_id, side, quantity, stop_price);
+                stop.id = self.order_ids.next_id();
+                stop.source = source;
+                stop.client_order_id = client_order_id;
+                stop.received_at = received_at;
+
+                if let Some(client_order_id) = &stop.client_order_id {
+                    if let Err(message) =
+                        self.client_ids.check(user_id, client_order_id, received_at)
+                    {
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error { message },
+                        );
+                        return;
+                    }
+                }
+
+                // What the trigger is measured against: an index, or the last trade
+                let reference = match &trigger_index {
+                    Some(symbol) if !self.synthetics.contains(symbol) => {
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error {
+                                message: format!("Unknown index '{}'", symbol),
+                            },
+                        );
+                        return;
+                    }
+                    Some(symbol) => self
+                        .synthetics
+                        .value(symbol)
+                        .map(|value| ("index value", value)),
+                    None => self
+                        .orderbook
+                        .last_trade_price
+                        .map(|price| ("last trade price", price)),
+                };
+
+                // A stop that would fire immediately is almost always a mistake
+                if let Some((reference, price)) = reference {
+                    if stop.is_triggered_by(price) {
+                        let direction = match side {
+                            Buy => "above",
+                            Sell => "below",
+                        };
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error {
+                                message: format!(
+                                    "Stop price must be {} the {} {}",
+                                    direction, reference, price
+                                ),
+                            },
+                        );
+                        return;
+                    }
+                }
+
+                let order_id = stop.id;
+                if let Some(client_order_id) = &stop.client_order_id {
+                    self.client_ids
+                        .record(user_id, client_order_id, order_id, received_at);
+                }
+                match &trigger_index {
+                    Some(symbol) => self.synthetics.insert_stop(symbol, stop),
+                    None => self.triggers.insert(stop),
+                }
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::OrderPlaced {
+                        order_id,
+                        trades: Vec::new(),
+                        status: "Waiting for trigger".to_string(),
+                        timings: OrderTimings::new(
+                            queue_wait,
+                            Duration::ZERO,
+                            Duration::ZERO,
+                            started.elapsed(),
+                        ),
+                    },
+                );
+            }
+
+            OrderBookCommand::CancelOrder {
+                user_id,
+                order_id,
+                response_tx,
+            } => {
+                // Untriggered stops hold no reservation, so there is nothing to refund
+                if self
+                    .waiting_stop(order_id)
+                    .is_some_and(|stop| stop.user_id == user_id)
+                {
+                    self.remove_waiting_stop(order_id);
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::OrderCancelled {
+                            order_id,
+                            success: true,
+                        },
+                    );
+                    return;
+                }
+
+                // Verify ownership before touching the book: the by-order feed
+                // publishes every resting order's ID
+                if self
+                    .orderbook
+                    .get_order(order_id)
+                    .is_some_and(|order| order.user_id != user_id)
+                {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error {
+                            message: "Not authorized to cancel this order".to_string(),
+                        },
+                    );
+                    return;
+                }
+
+                match self.orderbook.cancel_order(order_id) {
+                    Ok(cancelled_order) => {
+                        // Refund reserved balance
+                        let refunded = self.refund_remainder(&cancelled_order);
+
+                        let now = self.clock.now();
+                        self.order_history.mark_cancelled(order_id, now);
+                        self.publish_depth();
+
+                        let response = match refunded {
+                            Ok(()) => OrderBookResponse::OrderCancelled {
+                                order_id,
+                                success: true,
+                            },
+                            Err(e) => OrderBookResponse::Error {
+                                message: refund_refused(order_id, &e),
+                            },
+                        };
+                        respond(&self.metrics, response_tx, response);
+                    }
+                    Err(e) => {
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error {
+                                message: format!("Failed to cancel order: {}", e),
+                            },
+                        );
+                    }
+                }
+            }
+
+            OrderBookCommand::MassCancel {
+                user_id,
+                filter,
+                response_tx,
+            } => {
+                let response = match self.cancel_user_orders(user_id, &filter) {
+                    Ok(order_ids) => OrderBookResponse::OrdersCancelled { order_ids },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::CancelAllAfter {
+                user_id,
+                timeout,
+                response_tx,
+            } => {
+                let cancel_at = chrono::Duration::from_std(timeout)
+                    .ok()
+                    .and_then(|timeout| self.clock.now().checked_add_signed(timeout));
+                let response = match cancel_at {
+                    _ if timeout.is_zero() => {
+                        self.dead_man.disarm(user_id);
+                        OrderBookResponse::CancelAllAfterSet { cancel_at: None }
+                    }
+                    Some(cancel_at) => {
+                        self.dead_man.arm(user_id, cancel_at);
+                        OrderBookResponse::CancelAllAfterSet {
+                            cancel_at: Some(cancel_at),
+                        }
+                    }
+                    None => OrderBookResponse::Error {
+                        message: format!("Timeout of {}ms is out of range", timeout.as_millis()),
+                    },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::PlaceOrderBatch {
+                user_id,
+                orders,
+                received_at,
+                source,
+                response_tx,
+            } => {
+                // Each order goes through the single-order path in turn, with
+                // its stops and pegs settled before the next one
+                let mut results = Vec::with_capacity(orders.len());
+                for params in orders {
+                    let (order_tx, mut order_rx) = oneshot::channel();
+                    self.apply(OrderBookCommand::PlaceLimitOrder {
+                        user_id,
+                        side: params.side,
+                        price: params.price,
+                        quantity: params.quantity,
+                        time_in_force: params.time_in_force,
+                        display_quantity: params.display_quantity,
+                        hidden: params.hidden,
+                        min_fill_qty: params.min_fill_qty,
+                        expires_at: params.expires_at,
+                        peg: None,
+                        trade_through_protected: params.trade_through_protected,
+                        priority_fee: params.priority_fee,
+                        received_at,
+                        source,
+                        client_order_id: params.client_order_id,
+                        response_tx: order_tx,
+                    });
+                    self.follow_trades();
+                    results.push(order_rx.try_recv().unwrap_or_else(|_| {
+                        OrderBookResponse::Error {
+                            message: "Order was not processed".to_string(),
+                        }
+                    }));
+                }
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::BatchPlaced { results },
+                );
+            }
+
+            OrderBookCommand::AmendOrder {
+                user_id,
+                order_id,
+                price,
+                quantity,
+                received_at,
+                response_tx,
+            } => {
+                // Shrinking an order is always allowed; re-pricing is trading
+                if price.is_some() {
+                    if let Some(response) = self
+                        .halted_response()
+                        .or_else(|| self.quarantined_response(user_id))
+                    {
+                        respond(&self.metrics, response_tx, response);
+                        return;
+                    }
+                }
+                let started = Instant::now();
+                let queue_wait = (Utc::now() - received_at).to_std().unwrap_or_default();
+
+                let amendment = self.check_amendment(user_id, order_id, price, quantity);
+                let (new_price, cut) = match amendment {
+                    Ok(amendment) => amendment,
+                    Err(message) => {
+                        respond(
+                            &self.metrics,
+                            response_tx,
+                            OrderBookResponse::Error { message },
+                        );
+                        return;
+                    }
+                };
+
+                let (trades, matching) = match new_price {
+                    // A new price sends it to the back of the queue there, and
+                    // it trades first if the price crosses the book. The size
+                    // is cut along the way, so a refused re-price changes nothing.
+                    Some(price) => {
+                        let amend = |order: &mut Order| {
+                            if let Some(cut) = cut {
+                                order.reduce(cut);
+                            }
+                            order.received_at = received_at;
+                        };
+                        match self.reprice_order(order_id, price, amend) {
+                            Ok(result) => result,
+                            Err(e) => {
+                                respond(
+                                    &self.metrics,
+                                    response_tx,
+                                    OrderBookResponse::Error {
+                                        message: format!("Failed to amend order: {}", e),
+                                    },
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    // A smaller size keeps the order where it is in the queue
+                    None => {
+                        if let Some(cut) = cut {
+                            match self.orderbook.reduce_order(order_id, cut) {
+                                Ok(order) => {
+                                    let refunded = self.refund_quantity(&order, cut);
+                                    self.order_history.upsert(&order);
+                                    self.publish_depth();
+                                    if let Err(e) = refunded {
+                                        respond(
+                                            &self.metrics,
+                                            response_tx,
+                                            OrderBookResponse::Error {
+                                                message: refund_refused(order_id, &e),
+                                            },
+                                        );
+                                        return;
+                                    }
+                                }
+                                Err(e) => {
+                                    respond(
+                                        &self.metrics,
+                                        response_tx,
+                                        OrderBookResponse::Error {
+                                            message: format!("Failed to amend order: {}", e),
+                                        },
+                                    );
+                                    return;
+                                }
+                            }
+                        }
+                        (Vec::new(), Duration::ZERO)
+                    }
+                };
+                let settlement = self.orderbook.take_settlement_time();
+
+                let status = if trades.is_empty() {
+                    "Amended".to_string()
+                } else if self.orderbook.orders.contains_key(&order_id) {
+                    "Amended, partially filled".to_string()
+                } else {
+                    "Amended, filled".to_string()
+                };
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::OrderPlaced {
+                        order_id,
+                        trades,
+                        status,
+                        timings: OrderTimings::new(
+                            queue_wait,
+                            matching,
+                            settlement,
+                            started.elapsed(),
+                        ),
+                    },
+                );
+            }
+
+            OrderBookCommand::GetOrderBook {
+                depth, response_tx, ..
+            } => {
+                // Shape the snapshot the way this market publishes its feed
+                let response = match self.market.feed_mode {
+                    FeedMode::ByPrice => {
+                        let (bids, asks) = self.orderbook.get_depth(depth);
+                        OrderBookResponse::OrderBookDepth { bids, asks }
+                    }
+                    FeedMode::ByOrder => {
+                        let (bids, asks) = self.orderbook.get_order_depth(depth);
+                        OrderBookResponse::OrderBookByOrder { bids, asks }
+                    }
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetFullBook {
+                depth, response_tx, ..
+            } => {
+                let (bids, asks) = self.orderbook.get_full_book(depth);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::FullBook { bids, asks },
+                );
+            }
+
+            OrderBookCommand::GetImbalance {
+                window,
+                response_tx,
+                ..
+            } => {
+                let (bids, asks) = self.orderbook.depth_totals(window);
+                let response = OrderBookResponse::Imbalance {
+                    mid_price: self.orderbook.displayed_mid(),
+                    bids,
+                    asks,
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetHeatmap {
+                buckets,
+                range_pct,
+                response_tx,
+                ..
+            } => {
+                let heatmap = self.orderbook.get_heatmap(buckets, range_pct);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::Heatmap { heatmap },
+                );
+            }
+
+            OrderBookCommand::GetMarketConfig { response_tx, .. } => {
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::MarketConfig {
+                        config: self.market.clone(),
+                    },
+                );
+            }
+
+            // Depth as `publish_depth` sends it, so a subscriber can pick up
+            // the feed right after `seq`
+            OrderBookCommand::GetMarketSnapshot {
+                levels,
+                response_tx,
+                ..
+            } => {
+                // The feeds carry no deeper than the public depth
+                let (bids, asks) = self
+                    .orderbook
+                    .get_depth(levels.min(self.public_depth_levels));
+                let response = OrderBookResponse::MarketSnapshot {
+                    seq: self.market_seq,
+                    bids,
+                    asks,
+                    state: self.market_state(),
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetMarketState { response_tx, .. } => {
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::MarketState {
+                        state: self.market_state(),
+                    },
+                );
+            }
+
+            OrderBookCommand::GetUserBalance {
+                user_id,
+                response_tx,
+                ..
+            } => {
+                if let Some(balance) = self.orderbook.get_user_balance(user_id) {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::UserBalance {
+                            balance: balance.clone(),
+                        },
+                    );
+                } else {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error {
+                            message: "User not found".to_string(),
+                        },
+                    );
+                }
+            }
+
+            OrderBookCommand::GetDailyStats {
+                market,
+                from,
+                to,
+                response_tx,
+                ..
+            } => {
+                let stats =
+                    self.daily_stats
+                        .query(&market, from, to, self.orderbook.orders.len() as u64);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::DailyStats { stats },
+                );
+            }
+
+            OrderBookCommand::GetTradeTape {
+                after,
+                limit,
+                response_tx,
+                ..
+            } => {
+                let page = self.trade_tape.page(after, limit);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::TradeTape { page },
+                );
+            }
+
+            OrderBookCommand::GetRecentTrades {
+                limit, response_tx, ..
+            } => {
+                let page = self.trade_tape.latest(limit);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::TradeTape { page },
+                );
+            }
+
+            OrderBookCommand::GetExecutionQuality {
+                user_id,
+                response_tx,
+                ..
+            } => {
+                let stats = self.execution_quality.for_user(user_id);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::ExecutionQuality { stats },
+                );
+            }
+
+            OrderBookCommand::GetOrderHistory {
+                user_id,
+                limit,
+                response_tx,
+                ..
+            } => {
+                let orders = self.order_history.for_user(user_id, limit);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::OrderHistory { orders },
+                );
+            }
+
+            OrderBookCommand::GetOrderFills {
+                user_id,
+                order_id,
+                after,
+                response_tx,
+                ..
+            } => {
+                // Other users' orders are reported as unknown, not forbidden
+                let response = match self.order_history.get(order_id) {
+                    Some(order) if order.user_id == user_id => OrderBookResponse::OrderFills {
+                        order_id,
+                        last_fill_seq: order.fill_count,
+                        fills: self.order_history.fills(order_id, after),
+                    },
+                    _ => OrderBookResponse::Error {
+                        message: "Order not found".to_string(),
+                    },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetOrderByClientId {
+                user_id,
+                client_order_id,
+                response_tx,
+                ..
+            } => {
+                let order_id = self.client_ids.resolve(user_id, &client_order_id);
+                let response = match order_id.map(|id| (id, self.order_history.get(id))) {
+                    Some((_, Some(order))) => OrderBookResponse::Order {
+                        order: order.clone(),
+                    },
+                    Some((id, None)) if self.waiting_stop(id).is_some() => {
+                        OrderBookResponse::Error {
+                            message: format!("Stop order {} is waiting for its trigger", id),
+                        }
+                    }
+                    _ => OrderBookResponse::Error {
+                        message: "Order not found".to_string(),
+                    },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetUserTrades {
+                user_id,
+                query,
+                response_tx,
+                ..
+            } => {
+                // Answered by the history worker once earlier writes are in
+                let metrics = self.metrics.clone();
+                self.history.submit(move |store| {
+                    let response = match store.trades_for_user(user_id, &query) {
+                        Ok(trades) => OrderBookResponse::UserTrades {
+                            fills: trades.iter().flat_map(|t| t.fills_of(user_id)).collect(),
+                            trades: trades.len(),
+                        },
+                        Err(message) => OrderBookResponse::Error { message },
+                    };
+                    respond(&metrics, response_tx, response);
+                });
+            }
+
+            OrderBookCommand::GetActivity {
+                user_id,
+                query,
+                response_tx,
+                ..
+            } => {
+                let entries = self.user_activity(user_id, &query);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::Activity { entries },
+                );
+            }
+
+            OrderBookCommand::GetLeverageSettings {
+                user_id,
+                response_tx,
+                ..
+            } => {
+                let settings = self
+                    .margin
+                    .settings(user_id, self.position_notional(user_id));
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::LeverageSettings { settings },
+                );
+            }
+
+            OrderBookCommand::GetLeverageTiers {
+                user_id,
+                response_tx,
+                ..
+            } => {
+                let response = match user_id {
+                    Some(user_id) => OrderBookResponse::LeverageTiers {
+                        user_id: Some(user_id),
+                        tiers: self.margin.tiers_for(user_id).clone(),
+                        custom: self.margin.has_custom_tiers(user_id),
+                    },
+                    None => OrderBookResponse::LeverageTiers {
+                        user_id: None,
+                        tiers: self.margin.default_tiers().clone(),
+                        custom: false,
+                    },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetLiquidationPreview {
+                user_id,
+                delta_qty,
+                delta_price,
+                response_tx,
+                ..
+            } => {
+                let Some(mark_price) = self.orderbook.mark_price() else {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error {
+                            message: "No mark price available: the book has no displayed quotes"
+                                .to_string(),
+                        },
+                    );
+                    return;
+                };
+
+                let tiers = self.margin.tiers_for(user_id);
+                let position = self.margin_position(user_id);
+                let current = assess_position(position, tiers, mark_price);
+                let preview = assess_position(
+                    position.with_delta(delta_qty, delta_price.unwrap_or(mark_price)),
+                    tiers,
+                    mark_price,
+                );
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::LiquidationPreview { current, preview },
+                );
+            }
+
+            OrderBookCommand::SetUserLeverage {
+                user_id,
+                max_leverage,
+                response_tx,
+            } => {
+                let position_notional = self.position_notional(user_id);
+                let response =
+                    match self
+                        .margin
+                        .set_user_leverage(user_id, max_leverage, position_notional)
+                    {
+                        Ok(()) => OrderBookResponse::LeverageSettings {
+                            settings: self.margin.settings(user_id, position_notional),
+                        },
+                        Err(message) => OrderBookResponse::Error { message },
+                    };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::SetLeverageTiers {
+                user_id,
+                tiers,
+                response_tx,
+            } => {
+                let result = match (user_id, tiers) {
+                    (Some(user_id), tiers) => {
+                        let position_notional = self.position_notional(user_id);
+                        self.margin
+                            .set_user_tiers(user_id, tiers, position_notional)
+                    }
+                    (None, Some(tiers)) => {
+                        let positions: Vec<(Uuid, f64)> = self
+                            .orderbook
+                            .user_balances
+                            .keys()
+                            .map(|&user_id| (user_id, self.position_notional(user_id)))
+                            .collect();
+                        self.margin.set_default_tiers(tiers, positions)
+                    }
+                    (None, None) => Err("The default leverage table cannot be removed".to_string()),
+                };
+
+                let response = match result {
+                    Ok(()) => {
+                        let tiers = match user_id {
+                            Some(user_id) => self.margin.tiers_for(user_id).clone(),
+                            None => self.margin.default_tiers().clone(),
+                        };
+                        let custom = user_id.is_some_and(|id| self.margin.has_custom_tiers(id));
+                        OrderBookResponse::LeverageTiers {
+                            user_id,
+                            tiers,
+                            custom,
+                        }
+                    }
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetStoredOrders {
+                user_id,
+                query,
+                response_tx,
+                ..
+            } => {
+                let metrics = self.metrics.clone();
+                self.history.submit(move |store| {
+                    let response = match store.orders_for_user(user_id, &query) {
+                        Ok(orders) => OrderBookResponse::OrderHistory { orders },
+                        Err(message) => OrderBookResponse::Error { message },
+                    };
+                    respond(&metrics, response_tx, response);
+                });
+            }
+
+            OrderBookCommand::GetPriceAverages {
+                window,
+                response_tx,
+                ..
+            } => {
+                let averages = self.price_averages.averages(window, self.clock.now());
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::PriceAverages { averages },
+                );
+            }
+
+            OrderBookCommand::GetSourceVolume { response_tx, .. } => {
+                let stats = self.source_volume.snapshot();
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::SourceVolume { stats },
+                );
+            }
+
+            OrderBookCommand::GetFeedActivity {
+                intervals,
+                response_tx,
+                ..
+            } => {
+                let activity =
+                    self.feed_activity
+                        .snapshot(&self.market.symbol, self.clock.now(), intervals);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::FeedActivity { activity },
+                );
+            }
+
+            OrderBookCommand::GetAccountSummary {
+                user_id,
+                fills,
+                response_tx,
+                ..
+            } => {
+                let mut summary = match self.account_summary(user_id) {
+                    Ok(summary) => summary,
+                    Err(message) => {
+                        let response = OrderBookResponse::Error { message };
+                        respond(&self.metrics, response_tx, response);
+                        return;
+                    }
+                };
+                // Queued behind every earlier write, so the fills agree with the rest
+                let metrics = self.metrics.clone();
+                self.history.submit(move |store| {
+                    let query = HistoryQuery::latest(fills);
+                    let response = match store.trades_for_user(user_id, &query) {
+                        Ok(trades) => {
+                            // A trade against one of the user's own orders yields two fills
+                            summary.recent_fills = trades
+                                .iter()
+                                .flat_map(|trade| trade.fills_of(user_id))
+                                .take(fills)
+                                .collect();
+                            OrderBookResponse::AccountSummary { summary }
+                        }
+                        Err(message) => OrderBookResponse::Error { message },
+                    };
+                    respond(&metrics, response_tx, response);
+                });
+            }
+
+            OrderBookCommand::GetDashboard {
+                depth,
+                trades,
+                users,
+                response_tx,
+                ..
+            } => {
+                let snapshot = DashboardSnapshot::capture(
+                    &self.orderbook,
+                    &self.trade_tape,
+                    depth,
+                    trades,
+                    users,
+                );
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::Dashboard { snapshot },
+                );
+            }
+
+            OrderBookCommand::GetTrialBalance { response_tx, .. } => {
+                // Count trades still waiting in the window without settling
+                // them: this query isn't logged, so it must not post journals
+                let report = self.ledger.trial_balance_including(self.netting.pending());
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::TrialBalance { report },
+                );
+            }
+
+            OrderBookCommand::GetJournals {
+                kind,
+                limit,
+                response_tx,
+                ..
+            } => {
+                let journals = match kind {
+                    Some(kind) => self.ledger.recent_journals_of(kind, limit),
+                    None => self.ledger.recent_journals(limit),
+                };
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::Journals { journals },
+                );
+            }
+
+            OrderBookCommand::GetFxRates { response_tx, .. } => {
+                let rates = self.settlement_rates.list();
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::FxRates { rates },
+                );
+            }
+
+            OrderBookCommand::GetIncidents {
+                open_only,
+                response_tx,
+                ..
+            } => {
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::Incidents {
+                        incidents: self.incidents.list(open_only),
+                    },
+                );
+            }
+
+            OrderBookCommand::ResolveIncident {
+                id,
+                note,
+                response_tx,
+            } => {
+                let now = self.clock.now();
+                let response = match self.incidents.resolve(id, note, now) {
+                    Ok(incident) => OrderBookResponse::Incident { incident },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetDeadLetters {
+                pending_only,
+                response_tx,
+                ..
+            } => {
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::DeadLetters {
+                        letters: self.dead_letters.list(pending_only),
+                    },
+                );
+            }
+
+            OrderBookCommand::GetRecoveryReport { response_tx, .. } => {
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::RecoveryReport {
+                        report: self.recovery.clone(),
+                    },
+                );
+            }
+
+            OrderBookCommand::RetryDeadLetter { id, response_tx } => {
+                let response = match self.retry_settlement(id, self.clock.now()) {
+                    Ok(letter) => OrderBookResponse::DeadLetter { letter },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::CompensateDeadLetter {
+                id,
+                note,
+                response_tx,
+            } => {
+                let response = match self.compensate_settlement(id, note, self.clock.now()) {
+                    Ok(letter) => OrderBookResponse::DeadLetter { letter },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::SetFxRate {
+                base,
+                quote,
+                rate,
+                response_tx,
+            } => {
+                let now = self.clock.now();
+                let response = match self.settlement_rates.set(&base, &quote, rate, now) {
+                    Ok(rate) => OrderBookResponse::FxRate { rate },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::RemoveFxRate {
+                base,
+                quote,
+                response_tx,
+            } => {
+                let response = match self.settlement_rates.remove(&base, &quote) {
+                    Some(rate) => OrderBookResponse::FxRate { rate },
+                    None => OrderBookResponse::Error {
+                        message: format!("No rate set for {}/{}", base, quote),
+                    },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetInterestSummary {
+                user_id,
+                response_tx,
+                ..
+            } => {
+                let summary = self.interest_summary(user_id);
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::InterestSummary { summary },
+                );
+            }
+
+            OrderBookCommand::SetInterestOptIn {
+                user_id,
+                opted_in,
+                response_tx,
+            } => {
+                let response = if opted_in && !self.interest.is_enabled() {
+                    OrderBookResponse::Error {
+                        message: "Interest on balances is not offered".to_string(),
+                    }
+                } else {
+                    self.interest.set_opted_in(user_id, opted_in);
+                    OrderBookResponse::InterestSummary {
+                        summary: self.interest_summary(user_id),
+                    }
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::AddFunds {
+                user_id,
+                currency,
+                amount,
+                response_tx,
+            } => {
+                self.orderbook.add_funds(user_id, &currency, amount);
+                self.post_external(JournalKind::Deposit, user_id, &currency, amount);
+                let new_balance = self
+                    .orderbook
+                    .get_or_create_balance(user_id)
+                    .get_balance(&currency);
+
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::FundsAdded {
+                        user_id,
+                        currency,
+                        new_balance,
+                    },
+                );
+            }
+
+            OrderBookCommand::WithdrawFunds {
+                user_id,
+                currency,
+                amount,
+                response_tx,
+            } => {
+                if let Some(response) = self.quarantined_response(user_id) {
+                    respond(&self.metrics, response_tx, response);
+                    return;
+                }
+                let response = match self.withdraw(user_id, &currency, amount) {
+                    Ok(journal_id) => OrderBookResponse::FundsWithdrawn {
+                        new_balance: self
+                            .orderbook
+                            .get_or_create_balance(user_id)
+                            .get_balance(&currency),
+                        user_id,
+                        currency,
+                        journal_id,
+                    },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::ReverseWithdrawal {
+                user_id,
+                journal_id,
+                response_tx,
+            } => {
+                let response = match self.reverse_withdrawal(user_id, journal_id) {
+                    Ok(currency) => OrderBookResponse::FundsAdded {
+                        new_balance: self
+                            .orderbook
+                            .get_or_create_balance(user_id)
+                            .get_balance(&currency),
+                        user_id,
+                        currency,
+                    },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::SetTradingStatus {
+                market,
+                status,
+                response_tx,
+            } => {
+                if market != self.market.symbol {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error {
+                            message: format!("Unknown market '{}'", market),
+                        },
+                    );
+                    return;
+                }
+                if self.market.has_expired(self.clock.now()) {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error {
+                            message: format!("Market {} has expired", market),
+                        },
+                    );
+                    return;
+                }
+
+                self.market.trading_status = status;
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::MarketConfig {
+                        config: self.market.clone(),
+                    },
+                );
+            }
+
+            OrderBookCommand::SetTradeThroughBand {
+                market,
+                band,
+                response_tx,
+            } => {
+                if market != self.market.symbol {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error {
+                            message: format!("Unknown market '{}'", market),
+                        },
+                    );
+                    return;
+                }
+
+                self.market.trade_through = band;
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::MarketConfig {
+                        config: self.market.clone(),
+                    },
+                );
+            }
+
+            OrderBookCommand::DefineSyntheticIndex { index, response_tx } => {
+                // Only this engine's market has prices to build an index from
+                let unknown = index
+                    .components
+                    .iter()
+                    .find(|component| component.market != self.market.symbol);
+                let refusal = if index.symbol == self.market.symbol {
+                    Some(format!("{} is a market, not an index", index.symbol))
+                } else {
+                    unknown.map(|component| format!("Unknown market '{}'", component.market))
+                };
+                if let Some(message) = refusal {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error { message },
+                    );
+                    return;
+                }
+
+                // Valued before it is taken, so one that is out of range now is refused
+                let market = self.market.symbol.as_str();
+                let last_price = self.orderbook.last_trade_price;
+                let valued = index.value(|component| last_price.filter(|_| component == market));
+                let symbol = index.symbol.clone();
+                if let Err(message) = valued.and_then(|_| self.synthetics.define(index)) {
+                    respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error { message },
+                    );
+                    return;
+                }
+                // Valued and published now, so the response carries the value
+                self.activate_index_stops();
+                let response = match self.synthetics.quote(&symbol) {
+                    Some(quote) => OrderBookResponse::SyntheticIndex { quote },
+                    None => OrderBookResponse::Error {
+                        message: format!("Unknown index '{}'", symbol),
+                    },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::RemoveSyntheticIndex {
+                symbol,
+                response_tx,
+            } => {
+                let response = match self.synthetics.remove(&symbol) {
+                    Ok(index) => OrderBookResponse::SyntheticIndex {
+                        quote: SyntheticQuote {
+                            index,
+                            value: None,
+                            error: None,
+                        },
+                    },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::GetSyntheticIndices { response_tx, .. } => {
+                let quotes = self.synthetics.quotes();
+                respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::SyntheticIndices { quotes },
+                );
+            }
+
+            OrderBookCommand::CancelOrderByClientId {
+                user_id,
+                client_order_id,
+                response_tx,
+            } => match self.client_ids.resolve(user_id, &client_order_id) {
+                Some(order_id) => self.apply(OrderBookCommand::CancelOrder {
+                    user_id,
+                    order_id,
+                    response_tx,
+                }),
+                None => respond(
+                    &self.metrics,
+                    response_tx,
+                    OrderBookResponse::Error {
+                        message: "Order not found".to_string(),
+                    },
+                ),
+            },
+
+            OrderBookCommand::ForceCancelOrder {
+                order_id,
+                response_tx,
+            } => {
+                let owner = self
+                    .orderbook
+                    .orders
+                    .get(&order_id)
+                    .map(|order| order.user_id)
+                    .or_else(|| self.waiting_stop(order_id).map(|stop| stop.user_id));
+
+                match owner {
+                    // Same path as the owner cancelling it, refunds included
+                    Some(user_id) => self.apply(OrderBookCommand::CancelOrder {
+                        user_id,
+                        order_id,
+                        response_tx,
+                    }),
+                    None => respond(
+                        &self.metrics,
+                        response_tx,
+                        OrderBookResponse::Error {
+                            message: "Order not found".to_string(),
+                        },
+                    ),
+                }
+            }
+
+            OrderBookCommand::AdjustBalance {
+                user_id,
+                currency,
+                amount,
+                response_tx,
+            } => {
+                let result = if amount >= 0.0 {
+                    self.orderbook.credit_balance(user_id, &currency, amount);
+                    Ok(())
+                } else {
+                    self.orderbook.deduct_balance(user_id, &currency, -amount)
+                };
+
+                if result.is_ok() {
+                    self.post_external(JournalKind::Adjustment, user_id, &currency, amount);
+                }
+
+                let response = match result {
+                    Ok(()) => OrderBookResponse::FundsAdded {
+                        new_balance: self
+                            .orderbook
+                            .get_or_create_balance(user_id)
+                            .get_balance(&currency),
+                        user_id,
+                        currency,
+                    },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            OrderBookCommand::SettleMarket {
+                market,
+                price,
+                response_tx,
+            } => {
+                let response = if market != self.market.symbol {
+                    Err(format!("Unknown market '{}'", market))
+                } else {
+                    self.settle_market(price, self.clock.now())
+                };
+                let response = match response {
+                    Ok(_) => OrderBookResponse::MarketConfig {
+                        config: self.market.clone(),
+                    },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+
+            // Whatever falls due is fired by the scheduled run after this command
+            OrderBookCommand::AdvanceClock { by, response_tx } => {
+                let response = match self.clock.advance(by) {
+                    Ok(()) => OrderBookResponse::Clock {
+                        now: self.clock.now(),
+                        offset_ms: u64::try_from(self.clock.offset().as_millis())
+                            .unwrap_or(u64::MAX),
+                    },
+                    Err(message) => OrderBookResponse::Error { message },
+                };
+                respond(&self.metrics, response_tx, response);
+            }
+        }
+    }
+}
+
+pub async fn run_orderbook_engine(
+    rx: mpsc::Receiver<OrderBookCommand>,
+    metrics: Arc<EngineMetrics>,
+    events: EventChannels,
+    config: EngineConfig,
+) {
+    // Nobody else holds the sender, so the engine runs until `rx` closes
+    let (_control_tx, control_rx) = control_channel();
+    run_orderbook_engine_with_control(rx, control_rx, metrics, events, config).await
+}
+
+/// Run the engine with a control channel beside the trading queue. Pings on
+/// it are answered before queued commands and between the commands of a
+/// batch; a shutdown stops the engine once its current batch is done.
+pub async fn run_orderbook_engine_with_control(
+    mut rx: mpsc::Receiver<OrderBookCommand>,
+    mut control_rx: mpsc::Receiver<ControlCommand>,
+    metrics: Arc<EngineMetrics>,
+    events: EventChannels,
+    config: EngineConfig,
+) {
+    let cancel_priority_threshold = config.cancel_priority_threshold;
+    let netting_window = config.netting_window.max(Duration::from_millis(1));
+    let wal_config = config.wal.clone();
+    let mut engine = match Engine::open(metrics.clone(), config) {
+        Ok(engine) => engine.with_events(events),
+        Err(e) => {
+            eprintln!("Failed to open the engine's stores: {}", e);
+            return;
+        }
+    };
+
+    // Catch up with every command the last run accepted: from the latest
+    // snapshot, then through the log after it. Running without the log it was
+    // told to keep would lose them, so the engine never starts.
+    let mut wal = None;
+    let mut snapshot_seq = 0;
+    // The snapshot being written on a blocking thread, by the command it is at
+    let mut writing: Option<(u64, tokio::task::JoinHandle<Result<(), String>>)> = None;
+    if let Some(wal_config) = &wal_config {
+        let (log, records) = match open_event_log(wal_config) {
+            Ok(opened) => opened,
+            Err(e) => {
+                eprintln!("Failed to open the command log: {}", e);
+                return;
+            }
+        };
+        let snapshot = log.snapshots().read_snapshot();
+        let report = match engine.recover(log.as_ref(), records, snapshot) {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("Failed to recover engine state: {}", e);
+                return;
+            }
+        };
+        if let Some(e) = &report.snapshot_error {
+            eprintln!("Snapshot not used, replayed the whole log: {}", e);
+        }
+        println!(
+            "Recovery report: {}",
+            serde_json::to_string(&report).unwrap_or_default()
+        );
+        snapshot_seq = report.snapshot_seq.unwrap_or(0);
+        wal = Some(log);
+    }
+
+    // Only now may load balancers send traffic here
+    metrics.mark_ready();
+    println!("OrderBook engine started and listening for commands...");
+
+    // Wakes the engine for scheduled work (expiry, netting, interest) while no commands arrive
+    let mut settlement_tick = tokio::time::interval(netting_window);
+    settlement_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut control_open = true;
+
+    loop {
+        // A switch or expiry fires when it is due, not on the next tick
+        let deadline = engine.next_deadline();
+        let wake = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now));
+        let first = tokio::select! {
+            biased;
+            control = control_rx.recv(), if control_open => {
+                match control {
+                    Some(control) => {
+                        if !handle_control(control, rx.len()) {
+                            break;
+                        }
+                    }
+                    None => control_open = false,
+                }
+                continue;
+            }
+            command = rx.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+            _ = wake, if deadline.is_some() => {
+                engine.run_scheduled(Instant::now());
+                engine.finish_changes();
+                continue;
+            }
+            now = settlement_tick.tick() => {
+                engine.run_scheduled(now);
+                engine.finish_changes();
+                continue;
+            }
+        };
+        let mut batch = drain_batch(first, &mut rx);
+
+        // Under stress, let cancels jump ahead of new orders in this batch
+        if batch.len() + rx.len() >= cancel_priority_threshold {
+            let moved = prioritize_cancels(&mut batch);
+            metrics.record_prioritized_cancels(moved);
+        }
+
+        // Log what the batch will change before any of it runs. A batch that
+        // could not be logged is refused whole: running it would leave state
+        // a restart cannot rebuild.
+        let logged_at = engine.clock.now();
+        let logged = wal.as_mut().map(|log| log.append(&batch, logged_at));
+        let seqs = match logged {
+            Some(Ok(seqs)) => seqs,
+            Some(Err(e)) => {
+                eprintln!("Failed to write the command log: {}", e);
+                metrics.record_wal_failure();
+                for command in batch {
+                    command.reject(format!("Command could not be logged: {}", e));
+                }
+                continue;
+            }
+            None => vec![None; batch.len()],
+        };
+
+        let mut running = true;
+        let batch_len = batch.len();
+        for (done, (command, seq)) in batch.into_iter().zip(seqs).enumerate() {
+            while let Ok(control) = control_rx.try_recv() {
+                running &= handle_control(control, batch_len - done + rx.len());
+            }
+            // A logged command runs at the time it was logged, as its replay will
+            if let Some(seq) = seq {
+                engine.order_ids.begin(seq);
+                engine.clock.pin(logged_at);
+            }
+            engine.process(command);
+            engine.clock.unpin();
+        }
+
+        // Bound how much of the log the next start has to replay. The state
+        // is copied here and written on a blocking thread, one at a time.
+        if let Some((seq, write)) = writing.take_if(|(_, write)| write.is_finished()) {
+            if engine.snapshot_written(finish_write(write).await) {
+                snapshot_seq = seq;
+                compact_log(&mut wal, seq);
+            }
+        }
+        if let (Some(log), Some(wal_config), None) = (&wal, &wal_config, &writing) {
+            let every = wal_config.snapshot_every;
+            if every > 0 && log.last_seq() - snapshot_seq >= every {
+                if let Some(snapshot) = engine.snapshot(log.log_id(), log.last_seq()) {
+                    let snapshots = log.snapshots();
+                    let write =
+                        tokio::task::spawn_blocking(move || snapshots.write_snapshot(&snapshot));
+                    writing = Some((log.last_seq(), write));
+                }
+            }
+        }
+        if !running {
+            break;
+        }
+    }
+
+    println!("OrderBook engine shutting down...");
+    if let Some((seq, write)) = writing {
+        if engine.snapshot_written(finish_write(write).await) {
+            snapshot_seq = seq;
+            compact_log(&mut wal, seq);
+        }
+    }
+    let saved = wal
+        .as_ref()
+        .filter(|log| log.last_seq() > snapshot_seq && engine.save_snapshot(log.as_ref()))
+        .map(|log| log.last_seq());
+    if let Some(seq) = saved {
+        compact_log(&mut wal, seq);
+    }
+}
+
+/// Cut the commands a written snapshot holds from the log. Keeping them
+/// only makes the next start read further, so a failure is just reported.
+fn compact_log(log: &mut Option<Box<dyn EventLog>>, through: u64) {
+    if let Some(Err(e)) = log.as_mut().map(|log| log.compact(through)) {
+        eprintln!("Failed to compact the command log: {}", e);
+    }
+}
+
+/// What came of a snapshot written on a blocking thread
+async fn finish_write(write: tokio::task::JoinHandle<Result<(), String>>) -> Result<(), String> {
+    write
+        .await
+        .unwrap_or_else(|e| Err(format!("Snapshot writer stopped: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{ping_engine, CommandLog, FeedInterval, WalBackend, WalConfig, WalSync};
+    use crate::orderbook::{DepthLevel, OrderFilter};
+    use crate::storage::{HistoryQuery, StorageBackend};
+    use crate::testing::LimitOrder;
+    use crate::types::{
+        FeeSchedule, IndexComponent, LiquidityRole, OrderSource, Peg, PegReference, Quantity,
+        SyntheticIndex, TradeThroughBand,
+    };
+    use std::time::Duration;
+
+    /// Process `order` and return the engine's answer to it
+    fn submit(engine: &mut Engine, order: LimitOrder) -> OrderBookResponse {
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(order.command(response_tx));
+        response_rx.try_recv().unwrap()
+    }
+
+    /// ID of the order `response` placed
+    fn order_id(response: OrderBookResponse) -> Uuid {
+        match response {
+            OrderBookResponse::OrderPlaced { order_id, .. } => order_id,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_expired_and_cancelled_queries() {
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = Arc::new(EngineMetrics::new());
+        let engine = tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            event_channel(),
+            EngineConfig::default(),
+        ));
+
+        // Deadline already passed
+        let (expired_tx, expired_rx) = oneshot::channel();
+        tx.send(OrderBookCommand::GetOrderBook {
+            depth: 10,
+            deadline: Instant::now() - Duration::from_millis(1),
+            response_tx: expired_tx,
+        })
+        .await
+        .unwrap();
+
+        // Caller hung up before the engine got to it
+        let (cancelled_tx, cancelled_rx) = oneshot::channel();
+        drop(cancelled_rx);
+        tx.send(OrderBookCommand::GetOrderBook {
+            depth: 10,
+            deadline: Instant::now() + Duration::from_secs(5),
+            response_tx: cancelled_tx,
+        })
+        .await
+        .unwrap();
+
+        let (live_tx, live_rx) = oneshot::channel();
+        tx.send(OrderBookCommand::GetOrderBook {
+            depth: 10,
+            deadline: Instant::now() + Duration::from_secs(5),
+            response_tx: live_tx,
+        })
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            live_rx.await.unwrap(),
+            OrderBookResponse::OrderBookDepth { .. }
+        ));
+        assert!(expired_rx.await.is_err());
+
+        drop(tx);
+        engine.await.unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.queries_expired, 1);
+        assert_eq!(snapshot.queries_cancelled, 1);
+        assert_eq!(snapshot.commands_processed, 1);
+        assert!(snapshot.ready);
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_written_off_the_engine_loop() {
+        let path = std::env::temp_dir().join(format!("engine-wal-{}.jsonl", Uuid::new_v4()));
+        let wal = WalConfig {
+            path: path.clone(),
+            sync: WalSync::Never,
+            backend: WalBackend::File,
+            snapshot_path: path.with_extension("snapshot"),
+            snapshot_every: 2,
+        };
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = Arc::new(EngineMetrics::new());
+        let engine = tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            event_channel(),
+            EngineConfig {
+                wal: Some(wal.clone()),
+                ..EngineConfig::default()
+            },
+        ));
+        let user_id = Uuid::new_v4();
+        for _ in 0..2 {
+            let (response_tx, response_rx) = oneshot::channel();
+            tx.send(OrderBookCommand::AddFunds {
+                user_id,
+                currency: "USD".to_string(),
+                amount: 100.0,
+                response_tx,
+            })
+            .await
+            .unwrap();
+            response_rx.await.unwrap();
+        }
+
+        // The loop picks up the finished write after a later batch
+        for _ in 0..200 {
+            if metrics.snapshot().snapshots_written == 1 {
+                break;
+            }
+            let (response_tx, response_rx) = oneshot::channel();
+            tx.send(OrderBookCommand::GetUserBalance {
+                user_id,
+                deadline: Instant::now() + Duration::from_secs(5),
+                response_tx,
+            })
+            .await
+            .unwrap();
+            response_rx.await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(tx);
+        engine.await.unwrap();
+
+        // Nothing was logged after it, so shutdown wrote no other
+        assert_eq!(metrics.snapshot().snapshots_written, 1);
+        let snapshot = EngineSnapshot::read(&wal.snapshot_path).unwrap().unwrap();
+        assert_eq!(snapshot.wal_seq, 2);
+        assert_eq!(snapshot.balances[0].balances["USD"], 200.0);
+        // and the commands it holds were cut from the log
+        let (log, records) = CommandLog::open(&wal).unwrap();
+        assert_eq!((log.last_seq(), records.len()), (2, 0));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&wal.snapshot_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_dead_man_switch_fires_at_its_deadline_between_ticks() {
+        let (tx, rx) = mpsc::channel(16);
+        // Far longer than the switch, so only its own deadline can wake the engine
+        let config = EngineConfig {
+            netting_window: Duration::from_secs(60),
+            ..EngineConfig::default()
+        };
+        let engine = tokio::spawn(run_orderbook_engine(
+            rx,
+            Arc::new(EngineMetrics::new()),
+            event_channel(),
+            config,
+        ));
+        let bot = Uuid::new_v4();
+
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(OrderBookCommand::AddFunds {
+            user_id: bot,
+            currency: "USD".to_string(),
+            amount: 1_000.0,
+            response_tx,
+        })
+        .await
+        .unwrap();
+        response_rx.await.unwrap();
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(LimitOrder::new(bot, Buy, 95.0, 1.0).command(response_tx))
+            .await
+            .unwrap();
+        response_rx.await.unwrap();
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(OrderBookCommand::CancelAllAfter {
+            user_id: bot,
+            timeout: Duration::from_millis(100),
+            response_tx,
+        })
+        .await
+        .unwrap();
+        response_rx.await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        // Answered before this command's own scheduled work runs
+        let (response_tx, response_rx) = oneshot::channel();
+        tx.send(OrderBookCommand::GetUserBalance {
+            user_id: bot,
+            deadline: Instant::now() + Duration::from_secs(5),
+            response_tx,
+        })
+        .await
+        .unwrap();
+        let OrderBookResponse::UserBalance { balance } = response_rx.await.unwrap() else {
+            panic!("no balance");
+        };
+        assert_eq!(balance.get_reserved("USD"), 0.0);
+        assert_eq!(balance.get_balance("USD"), 1_000.0);
+
+        drop(tx);
+        engine.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pings_jump_the_queue_and_shutdown_stops_the_engine() {
+        let (tx, rx) = mpsc::channel(64);
+        let (control_tx, control_rx) = control_channel();
+        let engine = tokio::spawn(run_orderbook_engine_with_control(
+            rx,
+            control_rx,
+            Arc::new(EngineMetrics::new()),
+            event_channel(),
+            EngineConfig::default(),
+        ));
+
+        // A backlog the engine has not started on yet
+        let mut queued = Vec::new();
+        for _ in 0..32 {
+            let (response_tx, response_rx) = oneshot::channel();
+            tx.send(OrderBookCommand::GetOrderBook {
+                depth: 10,
+                deadline: Instant::now() + Duration::from_secs(5),
+                response_tx,
+            })
+            .await
+            .unwrap();
+            queued.push(response_rx);
+        }
+
+        let health = ping_engine(&control_tx, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(health.queued_commands, 32);
+        for response_rx in queued {
+            assert!(matches!(
+                response_rx.await.unwrap(),
+                OrderBookResponse::OrderBookDepth { .. }
+            ));
+        }
+
+        // Stops although the trading queue is still open
+        control_tx.send(ControlCommand::Shutdown).await.unwrap();
+        engine.await.unwrap();
+        assert!(tx.is_closed());
+    }
+
+    #[tokio::test]
+    async fn restart_replays_the_command_log_into_the_same_book() {
+        let path = std::env::temp_dir().join(format!("engine-wal-{}.jsonl", Uuid::new_v4()));
+        let snapshot_path = path.with_extension("snapshot");
+        let config = EngineConfig {
+            wal: Some(WalConfig {
+                path: path.clone(),
+                sync: WalSync::Always,
+                backend: WalBackend::File,
+                snapshot_path: snapshot_path.clone(),
+                snapshot_every: 0,
+            }),
+            ..EngineConfig::default()
+        };
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // Run an engine, trade on it, and read its book and balances back.
+        // A crash stops it where it is, with no snapshot on the way out.
+        let run = |commands: Vec<OrderBookCommand>, crash: bool| {
+            let config = config.clone();
+            async move {
+                let (tx, rx) = mpsc::channel(16);
+                let metrics = Arc::new(EngineMetrics::new());
+                let engine = tokio::spawn(run_orderbook_engine(
+                    rx,
+                    metrics.clone(),
+                    event_channel(),
+                    config,
+                ));
+                for command in commands {
+                    tx.send(command).await.unwrap();
+                }
+                let (book_tx, book_rx) = oneshot::channel();
+                tx.send(OrderBookCommand::GetFullBook {
+                    depth: 10,
+                    deadline: Instant::now() + Duration::from_secs(5),
+                    response_tx: book_tx,
+                })
+                .await
+                .unwrap();
+                let (balance_tx, balance_rx) = oneshot::channel();
+                tx.send(OrderBookCommand::GetUserBalance {
+                    user_id: taker,
+                    deadline: Instant::now() + Duration::from_secs(5),
+                    response_tx: balance_tx,
+                })
+                .await
+                .unwrap();
+                let (tape_tx, tape_rx) = oneshot::channel();
+                tx.send(OrderBookCommand::GetRecentTrades {
+                    limit: 10,
+                    deadline: Instant::now() + Duration::from_secs(5),
+                    response_tx: tape_tx,
+                })
+                .await
+                .unwrap();
+                let book = match book_rx.await.unwrap() {
+                    OrderBookResponse::FullBook { bids, asks } => (bids, asks),
+                    other => panic!("unexpected response: {:?}", other),
+                };
+                let balance = match balance_rx.await.unwrap() {
+                    OrderBookResponse::UserBalance { balance } => balance.balances,
+                    other => panic!("unexpected response: {:?}", other),
+                };
+                let trades = match tape_rx.await.unwrap() {
+                    OrderBookResponse::TradeTape { page } => page
+                        .entries
+                        .iter()
+                        .map(|entry| (entry.trade_id, entry.timestamp))
+                        .collect::<Vec<_>>(),
+                    other => panic!("unexpected response: {:?}", other),
+                };
+                if crash {
+                    engine.abort();
+                    let _ = engine.await;
+                } else {
+                    drop(tx);
+                    engine.await.unwrap();
+                }
+                (book, balance, trades, metrics.snapshot())
+            }
+        };
+
+        let place = |user_id, side, price: f64, quantity: f64| {
+            LimitOrder::new(user_id, side, price, quantity).command(oneshot::channel().0)
+        };
+        let fund = |user_id, currency: &str, amount| OrderBookCommand::AddFunds {
+            user_id,
+            currency: currency.to_string(),
+            amount,
+            response_tx: oneshot::channel().0,
+        };
+        let (book, balance, trades, first) = run(
+            vec![
+                fund(maker, "BTC", 10.0),
+                fund(taker, "USD", 1_000.0),
+                place(maker, Sell, 100.0, 2.0),
+                place(maker, Sell, 101.0, 1.0),
+                place(taker, Buy, 100.0, 1.5),
+                place(taker, Buy, 99.0, 1.0),
+            ],
+            true,
+        )
+        .await;
+        assert_eq!((book.0.len(), book.1.len()), (1, 2));
+        assert_eq!(balance["BTC"], 1.5);
+        assert_eq!(trades.len(), 1);
+
+        // Without a snapshot, a restart replays the whole log into the same
+        // orders and trades, under the same IDs and times
+        assert!(!snapshot_path.exists());
+        let (replayed_book, replayed_balance, replayed_trades, metrics) =
+            run(Vec::new(), false).await;
+        assert_eq!(replayed_book, book);
+        assert_eq!(replayed_balance, balance);
+        assert_eq!(replayed_trades, trades);
+        assert_eq!(metrics.wal_commands_replayed, 6);
+        assert_eq!(metrics.market_seq, first.market_seq);
+        assert_eq!(metrics.wal_write_failures, 0);
+        assert_eq!(metrics.snapshots_written, 1);
+
+        // The snapshot written on shutdown leaves nothing to replay
+        let (restored_book, restored_balance, _, metrics) = run(Vec::new(), false).await;
+        assert_eq!(restored_book, book);
+        assert_eq!(restored_balance, balance);
+        assert_eq!(metrics.wal_commands_replayed, 0);
+        // The feed carries on from where it was
+        assert!(first.market_seq > 0);
+        assert_eq!(metrics.market_seq, first.market_seq);
+
+        // It took the commands it holds out of the log, so without it the
+        // engine has nothing to rebuild them from and does not start
+        let wal = config.wal.as_ref().unwrap();
+        let (log, records) = CommandLog::open(wal).unwrap();
+        assert!(records.is_empty());
+        assert_eq!(log.last_seq(), 6);
+        std::fs::remove_file(&snapshot_path).unwrap();
+        let (log, records) = CommandLog::open(wal).unwrap();
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        assert!(engine.recover(&log, records, Ok(None)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stores_that_cannot_open_stop_the_engine_instead_of_falling_back_to_memory() {
+        let config = EngineConfig {
+            storage: StorageBackend::Sqlite(std::path::PathBuf::from("/dev/null/orderbook.db")),
+            ..EngineConfig::default()
+        };
+        let error = Engine::open(Arc::new(EngineMetrics::new()), config)
+            .err()
+            .unwrap();
+        assert!(error.starts_with("Storage backend unavailable"));
+
+        let config = EngineConfig {
+            stats_path: Some(std::env::temp_dir()),
+            ..EngineConfig::default()
+        };
+        let error = Engine::open(Arc::new(EngineMetrics::new()), config)
+            .err()
+            .unwrap();
+        assert!(error.starts_with("Daily stats store unavailable"));
+    }
+
+    #[tokio::test]
+    async fn recovery_restores_the_snapshot_then_replays_the_log_after_it() {
+        let path = std::env::temp_dir().join(format!("engine-wal-{}.jsonl", Uuid::new_v4()));
+        let config = WalConfig {
+            path: path.clone(),
+            sync: WalSync::Never,
+            backend: WalBackend::File,
+            snapshot_path: path.with_extension("snapshot"),
+            snapshot_every: 0,
+        };
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        let place = |user_id, side, price: f64, quantity: f64| {
+            LimitOrder::new(user_id, side, price, quantity).command(oneshot::channel().0)
+        };
+        let fund = |user_id, currency: &str, amount| OrderBookCommand::AddFunds {
+            user_id,
+            currency: currency.to_string(),
+            amount,
+            response_tx: oneshot::channel().0,
+        };
+        // Log and run a command, as the engine loop does
+        let run = |engine: &mut Engine, log: &mut CommandLog, command: OrderBookCommand| {
+            let seqs = log
+                .append(std::slice::from_ref(&command), engine.clock.now())
+                .unwrap();
+            engine.order_ids.begin(seqs[0].unwrap());
+            engine.process(command);
+        };
+        let state = |engine: &Engine| {
+            let balances: Vec<_> = [maker, taker]
+                .iter()
+                .map(|user_id| engine.orderbook.user_balances[user_id].balances.clone())
+                .collect();
+            let orders: Vec<_> = engine
+                .orderbook
+                .resting_orders()
+                .iter()
+                .map(|order| (order.id, order.remaining_quantity))
+                .collect();
+            let seqs = (engine.trade_tape.last_seq(), engine.market_seq);
+            (engine.orderbook.get_depth(10), balances, orders, seqs)
+        };
+
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (mut log, records) = CommandLog::open(&config).unwrap();
+        engine.recover(&log, records, Ok(None)).unwrap();
+        run(&mut engine, &mut log, fund(maker, "BTC", 10.0));
+        run(&mut engine, &mut log, fund(taker, "USD", 1_000.0));
+        run(&mut engine, &mut log, place(maker, Sell, 100.0, 2.0));
+        run(&mut engine, &mut log, place(taker, Buy, 100.0, 1.0));
+        run(&mut engine, &mut log, place(taker, Buy, 99.0, 1.0));
+        assert!(engine.save_snapshot(&log));
+        // Only what it takes to carry on: the filled order and the journals are the store's
+        let saved = EngineSnapshot::read(&config.snapshot_path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.order_history.for_user(taker, 10).len(), 1);
+        assert_eq!(saved.order_history.for_user(maker, 10).len(), 1);
+        assert_eq!(saved.last_journal_id, engine.ledger.last_journal_id());
+        assert_eq!(saved.tape_seq, 1);
+        run(&mut engine, &mut log, place(maker, Sell, 101.0, 1.0));
+        run(&mut engine, &mut log, place(taker, Buy, 100.0, 0.5));
+        let before = state(&engine);
+        let reserved = engine.reserved_balances();
+        assert_eq!(reserved["USD"], 99.0);
+        drop((engine, log));
+
+        // A crash after the snapshot: the last two commands come from the log
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (log, records) = CommandLog::open(&config).unwrap();
+        // Opening skips what the snapshot on disk holds
+        assert_eq!(records.len(), 2);
+        let snapshot = EngineSnapshot::read(&config.snapshot_path);
+        let report = engine.recover(&log, records, snapshot).unwrap();
+        assert_eq!(state(&engine), before);
+        assert_eq!(report.snapshot_seq, Some(5));
+        assert_eq!(report.commands_replayed, 2);
+        assert_eq!(report.last_seq, 7);
+        assert_eq!(report.resting_orders, 3);
+        assert_eq!(report.users, 2);
+        assert_eq!(report.reserved, reserved);
+        assert!(report.snapshot_error.is_none());
+
+        // A snapshot of some other log is not used, and the commands the log
+        // skipped for the one on disk are then missing
+        let mut stale = engine.snapshot(Uuid::new_v4(), 3).unwrap();
+        stale.orders.clear();
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (log, records) = CommandLog::open(&config).unwrap();
+        assert!(engine.recover(&log, records, Ok(Some(stale))).is_err());
+
+        // With no snapshot on disk the whole log is read, and replayed
+        let stale = engine.snapshot(Uuid::new_v4(), 3).unwrap();
+        std::fs::remove_file(&config.snapshot_path).unwrap();
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (log, records) = CommandLog::open(&config).unwrap();
+        let report = engine.recover(&log, records, Ok(Some(stale))).unwrap();
+        assert_eq!(state(&engine), before);
+        assert_eq!(report.commands_replayed, 7);
+        assert!(report.snapshot_error.is_some());
+
+        // Nor is one that has seen commands the log has lost
+        let ahead = engine.snapshot(log.log_id(), 8).unwrap();
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (log, records) = CommandLog::open(&config).unwrap();
+        assert!(engine.recover(&log, records, Ok(Some(ahead))).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn ioc_remainder_is_cancelled_and_refunded() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let maker = Uuid::new_v4();
+        let taker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 2.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+
+        let place = |engine: &mut Engine, user_id, side, quantity, time_in_force| {
+            let order = LimitOrder {
+                time_in_force,
+                ..LimitOrder::new(user_id, side, 100.0, quantity)
+            };
+            submit(engine, order)
+        };
+
+        // Nothing to match against: the whole reservation comes back
+        match place(&mut engine, taker, Buy, 3.0, TimeInForce::IOC) {
+            OrderBookResponse::OrderPlaced { trades, status, .. } => {
+                assert!(trades.is_empty());
+                assert_eq!(status, "Cancelled, no immediate match");
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let balance = engine.orderbook.get_user_balance(taker).unwrap();
+        assert_eq!(balance.get_balance("USD"), 1_000.0);
+
+        place(&mut engine, maker, Sell, 1.0, TimeInForce::GTC);
+        match place(&mut engine, taker, Buy, 3.0, TimeInForce::IOC) {
+            OrderBookResponse::OrderPlaced { trades, status, .. } => {
+                assert_eq!(trades.len(), 1);
+                assert_eq!(status, "Partially filled, remainder cancelled");
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(engine.orderbook.best_bid().is_none());
+        let balance = engine.orderbook.get_user_balance(taker).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 1.0);
+    }
+
+    #[tokio::test]
+    async fn stop_order_fires_when_last_trade_crosses_trigger() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let maker = Uuid::new_v4();
+        let taker = Uuid::new_v4();
+        let stopper = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 10.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+        engine.orderbook.add_funds(stopper, "USD", 1_000.0);
+
+        let limit = |user_id, side, quantity| {
+            LimitOrder::new(user_id, side, 100.0, quantity).command(oneshot::channel().0)
+        };
+        let stop = |stop_price| {
+            let (response_tx, response_rx) = oneshot::channel();
+            let command = OrderBookCommand::PlaceStopOrder {
+                user_id: stopper,
+                side: Buy,
+                quantity: Quantity::from_f64(0.5),
+                stop_price: Price::from_f64(stop_price),
+                trigger_index: None,
+                received_at: Utc::now(),
+                source: OrderSource::Algo,
+                client_order_id: None,
+                response_tx,
+            };
+            (command, response_rx)
+        };
+
+        engine.process(limit(maker, Sell, 1.0));
+        let (command, mut response_rx) = stop(100.0);
+        engine.process(command);
+        let stop_id = match response_rx.try_recv().unwrap() {
+            OrderBookResponse::OrderPlaced {
+                order_id, status, ..
+            } => {
+                assert_eq!(status, "Waiting for trigger");
+                order_id
+            }
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        // The taker's fill prints at 100, which fires the stop into the rest of the ask
+        engine.process(limit(taker, Buy, 0.5));
+        assert!(engine.triggers.is_empty());
+        assert!(engine.orderbook.best_ask().is_none());
+        let balance = engine.orderbook.get_user_balance(stopper).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 0.5);
+        let history = engine.order_history.for_user(stopper, 10);
+        assert_eq!(history[0].id, stop_id);
+        assert_eq!(history[0].source, OrderSource::Algo);
+
+        // Already through the trigger
+        let (command, mut response_rx) = stop(90.0);
+        engine.process(command);
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::Error { .. }
+        ));
+
+        let (command, _) = stop(200.0);
+        engine.process(command);
+        assert_eq!(engine.triggers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_stop_that_cannot_execute_is_rejected_to_its_owner() {
+        let events = event_channel();
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default())
+            .with_events(events.clone());
+        let (maker, taker, stopper) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut feed = events.subscribe_user(stopper);
+        engine.orderbook.add_funds(maker, "BTC", 10.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+
+        // Nothing to pay with once it fires
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::PlaceStopOrder {
+            user_id: stopper,
+            side: Buy,
+            quantity: Quantity::from_f64(0.5),
+            stop_price: Price::from_f64(100.0),
+            trigger_index: None,
+            received_at: Utc::now(),
+            source: OrderSource::Web,
+            client_order_id: None,
+            response_tx,
+        });
+        let OrderBookResponse::OrderPlaced { order_id, .. } = response_rx.try_recv().unwrap()
+        else {
+            panic!("the stop was not placed");
+        };
+        for (user_id, side) in [(maker, Sell), (taker, Buy)] {
+            submit(&mut engine, LimitOrder::new(user_id, side, 100.0, 0.5));
+        }
+
+        assert!(engine.triggers.is_empty());
+        let rejected = engine.order_history.get(order_id).unwrap();
+        assert_eq!(rejected.status, OrderStatus::Rejected);
+        assert!(rejected.reject_reason.is_some());
+        let activity = engine.order_history.activity(stopper);
+        assert!(matches!(
+            activity.last().unwrap().activity,
+            Activity::OrderRejected { .. }
+        ));
+        let mut pushed = Vec::new();
+        while let Ok(message) = feed.try_recv() {
+            if let MarketEvent::Order(order) = message.event {
+                pushed.push(*order);
+            }
+        }
+        assert_eq!(pushed.len(), 1);
+        assert_eq!(pushed[0].status, OrderStatus::Rejected);
+    }
+
+    #[tokio::test]
+    async fn index_stop_fires_when_the_index_crosses_its_trigger() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let maker = Uuid::new_v4();
+        let stopper = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 10.0);
+        engine.orderbook.add_funds(maker, "USD", 1_000.0);
+        engine.orderbook.add_funds(stopper, "USD", 1_000.0);
+        let mut feed = engine.events.subscribe();
+
+        let component = IndexComponent {
+            market: engine.market.symbol.clone(),
+            weight: 1.0,
+        };
+        let index = SyntheticIndex::new("BTC-CENTS".to_string(), vec![component], 0.01).unwrap();
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::DefineSyntheticIndex { index, response_tx });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::SyntheticIndex { quote } => assert_eq!(quote.value, None),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::PlaceStopOrder {
+            user_id: stopper,
+            side: Buy,
+            quantity: Quantity::from_f64(0.5),
+            stop_price: Price::from_f64(10_000.0),
+            trigger_index: Some("BTC-CENTS".to_string()),
+            received_at: Utc::now(),
+            source: OrderSource::Web,
+            client_order_id: None,
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::OrderPlaced { .. }
+        ));
+        assert!(engine.triggers.is_empty());
+
+        // A trade at 100 puts the index at 10,000, which fires the stop into the ask
+        let limit = |user_id, side, quantity| {
+            LimitOrder::new(user_id, side, 100.0, quantity).command(oneshot::channel().0)
+        };
+        engine.process(limit(maker, Sell, 1.0));
+        engine.process(limit(maker, Buy, 0.5));
+        assert_eq!(
+            engine.synthetics.value("BTC-CENTS"),
+            Some(Price::from_f64(10_000.0))
+        );
+        let balance = engine.orderbook.get_user_balance(stopper).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 0.5);
+
+        let mut published = Vec::new();
+        while let Ok(message) = feed.try_recv() {
+            if let MarketEvent::IndexValue { symbol, value } = message.event {
+                published.push((symbol, value));
+            }
+        }
+        assert_eq!(
+            published,
+            vec![("BTC-CENTS".to_string(), Price::from_f64(10_000.0))]
+        );
+
+        // One whose value at today's price would not fit is refused, not saturated
+        let component = IndexComponent {
+            market: engine.market.symbol.clone(),
+            weight: 1e20,
+        };
+        let index = SyntheticIndex::new("TOO-BIG".to_string(), vec![component], 1.0).unwrap();
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::DefineSyntheticIndex { index, response_tx });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::Error { message } => {
+                assert_eq!(message, "Index TOO-BIG value is too large")
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(!engine.synthetics.contains("TOO-BIG"));
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::RemoveSyntheticIndex {
+            symbol: "BTC-CENTS".to_string(),
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::SyntheticIndex { .. }
+        ));
+        assert!(engine.synthetics.is_empty());
+    }
+
+    #[tokio::test]
+    async fn feed_activity_is_counted_without_subscribers() {
+        let metrics = Arc::new(EngineMetrics::new());
+        let mut engine = Engine::new(metrics.clone(), EngineConfig::default());
+        let maker = Uuid::new_v4();
+        let taker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 10.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+        assert_eq!(engine.events.receiver_count(), 0);
+
+        let limit = |user_id, side, quantity| {
+            LimitOrder::new(user_id, side, 100.0, quantity).command(oneshot::channel().0)
+        };
+        // The ask appears, then shrinks when half of it trades
+        engine.process(limit(maker, Sell, 1.0));
+        engine.process(limit(taker, Buy, 0.5));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.bbo_changes, 2);
+        assert_eq!(snapshot.trades_published, 1);
+        assert!(snapshot.order_events >= 2);
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::GetFeedActivity {
+            intervals: 5,
+            deadline: Instant::now() + Duration::from_secs(1),
+            response_tx,
+        });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::FeedActivity { activity } => {
+                assert_eq!(activity.market, engine.market.symbol);
+                assert_eq!(activity.intervals.len(), 5);
+                let total = |count: fn(&FeedInterval) -> u64| {
+                    activity.intervals.iter().map(count).sum::<u64>()
+                };
+                assert_eq!(total(|i| i.bbo_changes), 2);
+                assert_eq!(total(|i| i.trades), 1);
+                assert_eq!(total(|i| i.order_events), snapshot.order_events);
+                assert!(activity.last_trade_at.is_some());
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn halted_market_rejects_orders_but_operators_can_still_cancel() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let maker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 1.0);
+
+        let ask = || LimitOrder::new(maker, Sell, 100.0, 1.0);
+        let order_id = order_id(submit(&mut engine, ask()));
+
+        engine.process(OrderBookCommand::SetTradingStatus {
+            market: "BTC-USD".to_string(),
+            status: TradingStatus::Halted,
+            response_tx: oneshot::channel().0,
+        });
+        match submit(&mut engine, ask()) {
+            OrderBookResponse::Error { message } => assert_eq!(message, "Market BTC-USD is halted"),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::ForceCancelOrder {
+            order_id,
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::OrderCancelled { success: true, .. }
+        ));
+        let balance = engine.orderbook.get_user_balance(maker).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 1.0);
+
+        // Debits may not overdraw
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::AdjustBalance {
+            user_id: maker,
+            currency: "BTC".to_string(),
+            amount: -2.0,
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn deposits_and_trades_keep_the_ledger_balanced() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let maker = Uuid::new_v4();
+        let taker = Uuid::new_v4();
+        for (user_id, currency, amount) in [(maker, "BTC", 4.0), (taker, "USD", 1_000.0)] {
+            let (response_tx, _response_rx) = oneshot::channel();
+            engine.process(OrderBookCommand::AddFunds {
+                user_id,
+                currency: currency.to_string(),
+                amount,
+                response_tx,
+            });
+        }
+
+        for (user_id, side) in [(maker, Sell), (taker, Buy)] {
+            submit(&mut engine, LimitOrder::new(user_id, side, 100.0, 1.5));
+        }
+
+        let usd = |user_id| engine.ledger.balance(&Account::user(user_id, "USD"));
+        assert_eq!(usd(taker), to_ledger_units(850.0));
+        assert_eq!(usd(maker), to_ledger_units(150.0));
+        assert_eq!(
+            engine.ledger.balance(&Account::user(taker, "BTC")),
+            to_ledger_units(1.5)
+        );
+
+        let report = engine.ledger.trial_balance();
+        assert!(report.balanced);
+        assert_eq!(report.journal_count, 3);
+    }
+
+    #[tokio::test]
+    async fn dated_market_settles_positions_at_expiry() {
+        let config = EngineConfig {
+            market: MarketConfig {
+                expires_at: Some(Utc::now() + chrono::Duration::hours(1)),
+                trade_through: TradeThroughBand {
+                    reference_price: Some(Price::from_f64(90.0)),
+                    band_bps: 500,
+                },
+                ..MarketConfig::default()
+            },
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        let maker = Uuid::new_v4();
+        let taker = Uuid::new_v4();
+        let holder = Uuid::new_v4();
+        let funds = [
+            (maker, "BTC", 10.0),
+            (taker, "USD", 1_000.0),
+            (holder, "BTC", 5.0),
+        ];
+        for (user_id, currency, amount) in funds {
+            engine.process(OrderBookCommand::AddFunds {
+                user_id,
+                currency: currency.to_string(),
+                amount,
+                response_tx: oneshot::channel().0,
+            });
+        }
+
+        // 1.5 trades at 100; the rest of the ask and a lower bid keep resting
+        submit(&mut engine, LimitOrder::new(maker, Sell, 100.0, 3.0));
+        submit(&mut engine, LimitOrder::new(taker, Buy, 100.0, 1.5));
+        submit(&mut engine, LimitOrder::new(taker, Buy, 90.0, 1.0));
+        engine.run_scheduled(Instant::now());
+        assert_eq!(engine.market.trading_status, TradingStatus::Trading);
+        assert_eq!(engine.orderbook.orders.len(), 2);
+
+        engine
+            .clock
+            .advance(Duration::from_secs(2 * 60 * 60))
+            .unwrap();
+        engine.run_scheduled(Instant::now());
+        assert_eq!(engine.market.trading_status, TradingStatus::Settled);
+        assert!(engine.orderbook.orders.is_empty());
+        // Settled at the reference price, not the market's own trades
+        let settlement = engine.market.settlement.clone().unwrap();
+        assert_eq!(settlement.price, Price::from_f64(90.0));
+        assert_eq!(settlement.positions, 2);
+
+        // Reservations came back first. The taker's long of 1.5 went back to
+        // the maker at 90, so the maker lost 15 and the taker was paid 135.
+        let balance = |user_id| engine.orderbook.get_user_balance(user_id).unwrap().clone();
+        assert_eq!(balance(taker).get_balance("BTC"), 0.0);
+        assert_eq!(balance(taker).get_balance("USD"), 985.0);
+        assert_eq!(balance(maker).get_balance("BTC"), 10.0);
+        assert_eq!(balance(maker).get_balance("USD"), 15.0);
+        // A holding that never traded here is no position in the market
+        assert_eq!(balance(holder).get_balance("BTC"), 5.0);
+        assert_eq!(balance(holder).get_balance("USD"), 0.0);
+        assert!(engine.positions.is_empty());
+        assert_eq!(
+            engine
+                .ledger
+                .recent_journals_of(JournalKind::ExpirySettlement, 10)
+                .len(),
+            1
+        );
+        assert!(engine.ledger.trial_balance().balanced);
+
+        match submit(&mut engine, LimitOrder::new(taker, Buy, 100.0, 1.0)) {
+            OrderBookResponse::Error { message } => {
+                assert_eq!(message, "Market BTC-USD has expired and settled")
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::SetTradingStatus {
+            market: "BTC-USD".to_string(),
+            status: TradingStatus::Trading,
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::Error { .. }
+        ));
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::SettleMarket {
+            market: "BTC-USD".to_string(),
+            price: None,
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn netted_market_settles_trades_once_per_window() {
+        let config = EngineConfig {
+            market: MarketConfig {
+                clearing_mode: ClearingMode::Netted,
+                ..MarketConfig::default()
+            },
+            netting_window: Duration::from_secs(60),
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        let maker = Uuid::new_v4();
+        let taker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 10.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+
+        for (user_id, side) in [(maker, Sell), (taker, Buy), (maker, Sell), (taker, Buy)] {
+            submit(&mut engine, LimitOrder::new(user_id, side, 100.0, 1.0));
+        }
+
+        // Balances moved per trade, the ledger waits for the window to close
+        let balance = engine.orderbook.get_user_balance(taker).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 2.0);
+        assert_eq!(engine.netting.trade_count(), 2);
+        assert_eq!(engine.ledger.trial_balance().journal_count, 0);
+
+        // The report includes the open window but leaves it open
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::GetTrialBalance {
+            deadline: Instant::now() + Duration::from_secs(5),
+            response_tx,
+        });
+        let report = match response_rx.try_recv().unwrap() {
+            OrderBookResponse::TrialBalance { report } => report,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        assert!(report.balanced);
+        assert_eq!(report.journal_count, 0);
+        let taker_usd = Account::user(taker, "USD").to_string();
+        let row = report
+            .rows
+            .iter()
+            .find(|row| row.account == taker_usd)
+            .unwrap();
+        assert_eq!(row.debit - row.credit, to_ledger_units(-200.0));
+        assert_eq!(engine.netting.trade_count(), 2);
+        assert!(engine.ledger.recent_journals(10).is_empty());
+
+        engine.settle_due_netting(Instant::now());
+        assert_eq!(engine.netting.trade_count(), 2);
+
+        engine.settle_due_netting(Instant::now() + Duration::from_secs(60));
+        assert!(engine.netting.is_empty());
+        let journals = engine.ledger.recent_journals(10);
+        assert_eq!(journals.len(), 1);
+        assert_eq!(journals[0].kind, JournalKind::NetSettlement);
+        assert_eq!(
+            engine.ledger.balance(&Account::user(taker, "USD")),
+            to_ledger_units(-200.0)
+        );
+        assert!(engine.ledger.trial_balance().balanced);
+    }
+
+    #[tokio::test]
+    async fn opted_in_balances_accrue_daily_interest_from_the_treasury() {
+        let config = EngineConfig {
+            interest_rates: [("USD".to_string(), "*:0.0365".parse().unwrap())].into(),
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        let saver = Uuid::new_v4();
+        let (response_tx, _response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::AddFunds {
+            user_id: saver,
+            currency: "USD".to_string(),
+            amount: 1_000.0,
+            response_tx,
+        });
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::SetInterestOptIn {
+            user_id: saver,
+            opted_in: true,
+            response_tx,
+        });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::InterestSummary { summary } => assert!(summary.opted_in),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let tomorrow = Utc::now().date_naive().succ_opt().unwrap();
+        engine.accrue_interest(tomorrow);
+        engine.accrue_interest(tomorrow);
+
+        let balance = engine.orderbook.get_user_balance(saver).unwrap();
+        assert!((balance.get_balance("USD") - 1_000.1).abs() < 1e-9);
+        let treasury = Account::new(AccountOwner::Treasury, "USD");
+        assert_eq!(engine.ledger.balance(&treasury), to_ledger_units(-0.1));
+        assert!(engine.ledger.trial_balance().balanced);
+    }
+
+    #[tokio::test]
+    async fn withdrawal_debits_the_balance_and_journals_it_out() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let user = Uuid::new_v4();
+        let (response_tx, _response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::AddFunds {
+            user_id: user,
+            currency: "USD".to_string(),
+            amount: 500.0,
+            response_tx,
+        });
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::WithdrawFunds {
+            user_id: user,
+            currency: "USD".to_string(),
+            amount: 800.0,
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::Error { .. }
+        ));
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::WithdrawFunds {
+            user_id: user,
+            currency: "USD".to_string(),
+            amount: 200.0,
+            response_tx,
+        });
+        let journal_id = match response_rx.try_recv().unwrap() {
+            OrderBookResponse::FundsWithdrawn {
+                new_balance,
+                journal_id,
+                ..
+            } => {
+                assert_eq!(new_balance, 300.0);
+                journal_id
+            }
+            other => panic!("unexpected response: {:?}", other),
+        };
+        assert_eq!(
+            engine.ledger.balance(&Account::user(user, "USD")),
+            to_ledger_units(300.0)
+        );
+        assert_eq!(
+            engine.ledger.balance(&Account::external("USD")),
+            to_ledger_units(-300.0)
+        );
+        assert!(engine.ledger.trial_balance().balanced);
+
+        // A failed payout is put back by reversing that journal, once, and
+        // only for the user it was taken from
+        let mut reverse = |user_id| {
+            let (response_tx, mut response_rx) = oneshot::channel();
+            engine.process(OrderBookCommand::ReverseWithdrawal {
+                user_id,
+                journal_id,
+                response_tx,
+            });
+            response_rx.try_recv().unwrap()
+        };
+        assert!(matches!(
+            reverse(Uuid::new_v4()),
+            OrderBookResponse::Error { .. }
+        ));
+        match reverse(user) {
+            OrderBookResponse::FundsAdded { new_balance, .. } => assert_eq!(new_balance, 500.0),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(matches!(reverse(user), OrderBookResponse::Error { .. }));
+        let reversal = engine.ledger.recent_journals(1).remove(0);
+        assert_eq!(reversal.kind, JournalKind::Withdrawal);
+        assert_eq!(reversal.reverses, Some(journal_id));
+        assert_eq!(
+            engine.ledger.balance(&Account::external("USD")),
+            to_ledger_units(-500.0)
+        );
+        let activity = engine.user_activity(user, &HistoryQuery::latest(1));
+        assert!(matches!(
+            activity[0].activity,
+            Activity::WithdrawalReversed { amount, .. } if amount == 200.0
+        ));
+    }
+
+    #[tokio::test]
+    async fn good_till_date_order_expires_and_is_refunded() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let mut events = engine.events.subscribe();
+        let maker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 2.0);
+
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+        let order = LimitOrder {
+            expires_at: Some(expires_at),
+            ..LimitOrder::new(maker, Sell, 100.0, 1.5)
+        };
+        let order_id = order_id(submit(&mut engine, order));
+        assert_eq!(engine.expiries.len(), 1);
+
+        // Not due yet
+        engine.expire_orders(Utc::now());
+        assert!(engine.orderbook.get_order(order_id).is_some());
+
+        engine.expire_orders(expires_at);
+        assert!(engine.orderbook.get_order(order_id).is_none());
+        assert!(engine.orderbook.best_ask().is_none());
+        let balance = engine.orderbook.get_user_balance(maker).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 2.0);
+        assert_eq!(
+            engine.order_history.get(order_id).unwrap().status,
+            OrderStatus::Expired
+        );
+
+        let mut saw_expiry = false;
+        while let Ok(message) = events.try_recv() {
+            saw_expiry |= message.event == MarketEvent::OrderExpired { order_id, expires_at };
+        }
+        assert!(saw_expiry);
+
+        // Expiry in the past is rejected up front
+        let expired = LimitOrder {
+            expires_at: Some(Utc::now() - chrono::Duration::seconds(1)),
+            ..LimitOrder::new(maker, Sell, 100.0, 1.0)
+        };
+        assert!(matches!(
+            submit(&mut engine, expired),
+            OrderBookResponse::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn filled_and_cancelled_orders_leave_the_expiry_schedule() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(maker, "BTC", 2.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+        let place = |engine: &mut Engine, user_id, side, price| {
+            let order = LimitOrder {
+                expires_at: Some(expires_at),
+                ..LimitOrder::new(user_id, side, price, 1.0)
+            };
+            order_id(submit(engine, order))
+        };
+        let filled = place(&mut engine, maker, Sell, 100.0);
+        let cancelled = place(&mut engine, maker, Sell, 101.0);
+        assert_eq!(engine.expiries.len(), 2);
+
+        place(&mut engine, taker, Buy, 100.0);
+        assert_eq!(
+            engine.order_history.get(filled).unwrap().status,
+            OrderStatus::Filled
+        );
+        // The taker filled in full and never rested
+        assert_eq!(engine.expiries.len(), 1);
+
+        let (response_tx, _response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::CancelOrder {
+            user_id: maker,
+            order_id: cancelled,
+            response_tx,
+        });
+        assert!(engine.expiries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn arrival_bbo_is_captured_on_the_engine_clock() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let user_id = Uuid::new_v4();
+        engine.orderbook.add_funds(user_id, "USD", 1_000.0);
+
+        let logged_at = Utc::now() - chrono::Duration::hours(1);
+        engine.clock.pin(logged_at);
+        let order_id = order_id(submit(
+            &mut engine,
+            LimitOrder::new(user_id, Buy, 100.0, 1.0),
+        ));
+        let order = engine.orderbook.get_order(order_id).unwrap();
+        assert_eq!(order.arrival_bbo.as_ref().unwrap().captured_at, logged_at);
+    }
+
+    #[tokio::test]
+    async fn test_cancelling_someone_elses_order_leaves_it_untouched() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (owner, other) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(owner, "USD", 1_000.0);
+        let order_id = order_id(submit(&mut engine, LimitOrder::new(owner, Buy, 100.0, 2.0)));
+        let funds = |engine: &Engine| {
+            let balance = engine.orderbook.get_user_balance(owner).unwrap();
+            (
+                balance.get_balance("USD"),
+                balance.reserved.get("USD").copied(),
+            )
+        };
+        let funds_before = funds(&engine);
+
+        let (response_tx, response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::CancelOrder {
+            user_id: other,
+            order_id,
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.await.unwrap(),
+            OrderBookResponse::Error { message } if message.contains("Not authorized")
+        ));
+        assert_eq!(
+            engine
+                .orderbook
+                .get_order(order_id)
+                .map(|o| o.remaining_quantity),
+            Some(Quantity::from_f64(2.0))
+        );
+        assert_eq!(funds(&engine), funds_before);
+        assert_eq!(
+            engine.order_history.get(order_id).unwrap().status,
+            OrderStatus::Open
+        );
+        assert_eq!(engine.orderbook.best_bid(), Some(Price::from_f64(100.0)));
+    }
+
+    #[tokio::test]
+    async fn fills_are_numbered_per_order_and_can_be_fetched_again() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let mut events = engine.events.subscribe();
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut maker_events = engine.events.subscribe_user(maker);
+        engine.orderbook.add_funds(maker, "BTC", 10.0);
+        engine.orderbook.add_funds(taker, "USD", 10_000.0);
+
+        let place = |engine: &mut Engine, user_id, side, quantity| match submit(
+            engine,
+            LimitOrder::new(user_id, side, 100.0, quantity),
+        ) {
+            OrderBookResponse::OrderPlaced {
+                order_id, trades, ..
+            } => (order_id, trades),
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        let (resting, _) = place(&mut engine, maker, Sell, 3.0);
+        let (_, first) = place(&mut engine, taker, Buy, 1.0);
+        let (_, second) = place(&mut engine, taker, Buy, 1.5);
+        assert_eq!((first[0].maker_fill_seq, first[0].taker_fill_seq), (1, 1));
+        assert_eq!((second[0].maker_fill_seq, second[0].taker_fill_seq), (2, 1));
 
-    println!("OrderBook engine started and listening for commands...");
+        // Public messages are numbered without gaps
+        let mut expected_seq = 0;
+        for message in std::iter::from_fn(|| events.try_recv().ok()) {
+            expected_seq += 1;
+            assert_eq!(message.seq, expected_seq);
+        }
+        assert_eq!(engine.metrics.snapshot().market_seq, expected_seq);
 
-    while let Some(command) = rx.recv().await {
-        match command {
-            OrderBookCommand::PlaceLimitOrder {
-                user_id,
-                side,
-                price,
-                quantity,
-                response_tx,
+        // Private ones repeat the number of the last public message before them
+        let messages: Vec<MarketMessage> =
+            std::iter::from_fn(|| maker_events.try_recv().ok()).collect();
+        assert!(messages.windows(2).all(|pair| pair[0].seq <= pair[1].seq));
+        assert!(messages.iter().all(|message| message.seq <= expected_seq));
+        let maker_fills: Vec<OrderFill> = messages
+            .into_iter()
+            .filter_map(|message| match message.event {
+                MarketEvent::Fill(fill) if fill.order_id == resting => Some(fill),
+                _ => None,
+            })
+            .collect();
+        let seqs: Vec<u64> = maker_fills.iter().map(|f| f.fill_seq).collect();
+        assert_eq!(seqs, vec![1, 2]);
+        assert_eq!(maker_fills[1].remaining_quantity, Quantity::from_f64(0.5));
+        assert_eq!(maker_fills[1].role, LiquidityRole::Maker);
+
+        // A client that only saw fill 1 asks for the rest
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::GetOrderFills {
+            user_id: maker,
+            order_id: resting,
+            after: 1,
+            deadline: Instant::now() + Duration::from_secs(1),
+            response_tx,
+        });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::OrderFills {
+                last_fill_seq,
+                fills,
+                ..
             } => {
-                let order = Order::new_limit(user_id, side, price, quantity);
-                let order_id = order.id;
+                assert_eq!(last_fill_seq, 2);
+                assert_eq!(fills, maker_fills[1..].to_vec());
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
 
-                // Check balance before placing order
-                match side {
-                   Buy => {
-                        // Need USD to buy BTC
-                        let usd_needed = price.to_f64() * quantity.to_f64();
-                        if !orderbook.has_sufficient_balance(user_id, "USD", usd_needed) {
-                            let _ = response_tx.send(OrderBookResponse::Error {
-                                message: "Insufficient USD balance".to_string(),
-                            });
-                            continue;
-                        }
-                        // Reserve USD
-                        if let Err(e) = orderbook.deduct_balance(user_id, "USD", usd_needed) {
-                            let _ = response_tx.send(OrderBookResponse::Error {
-                                message: format!("Failed to reserve USD: {}", e),
-                            });
-                            continue;
-                        }
-                    }
-                 Sell => {
-                        // Need BTC to sell
-                        let btc_needed = quantity.to_f64();
-                        if !orderbook.has_sufficient_balance(user_id, "BTC", btc_needed) {
-                            let _ = response_tx.send(OrderBookResponse::Error {
-                                message: "Insufficient BTC balance".to_string(),
-                            });
-                            continue;
-                        }
-                        // Reserve BTC
-                        if let Err(e) = orderbook.deduct_balance(user_id, "BTC", btc_needed) {
-                            let _ = response_tx.send(OrderBookResponse::Error {
-                                message: format!("Failed to reserve BTC: {}", e),
-                            });
-                            continue;
-                        }
-                    }
-                }
+        // Someone else's order is not visible
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::GetOrderFills {
+            user_id: taker,
+            order_id: resting,
+            after: 0,
+            deadline: Instant::now() + Duration::from_secs(1),
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::Error { .. }
+        ));
+    }
 
-                match orderbook.match_order(order) {
-                    Ok(trades) => {
-                        let status = if trades.is_empty() {
-                            "Added to book".to_string()
-                        } else {
-                            "Matched".to_string()
-                        };
+    #[tokio::test]
+    async fn pegged_order_follows_the_best_bid_up_to_its_limit() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (maker, pegger) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(maker, "USD", 1_000.0);
+        engine.orderbook.add_funds(maker, "BTC", 1.0);
+        engine.orderbook.add_funds(pegger, "USD", 1_000.0);
 
-                        let _ = response_tx.send(OrderBookResponse::OrderPlaced {
-                            order_id,
-                            trades,
-                            status,
-                        });
-                    }
-                    Err(e) => {
-                        let _ = response_tx.send(OrderBookResponse::Error {
-                            message: format!("Failed to place order: {}", e),
-                        });
-                    }
-                }
+        // Nothing to peg to on an empty book
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::PlacePeggedOrder {
+            user_id: pegger,
+            side: Buy,
+            quantity: Quantity::from_f64(1.0),
+            peg: Peg {
+                reference: PegReference::Mid,
+                offset: 0,
+                limit: None,
+            },
+            expires_at: None,
+            received_at: Utc::now(),
+            source: OrderSource::Web,
+            client_order_id: None,
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::Error { .. }
+        ));
+
+        let limit = |engine: &mut Engine, side, price| {
+            order_id(submit(engine, LimitOrder::new(maker, side, price, 1.0)))
+        };
+        limit(&mut engine, Sell, 101.0);
+        limit(&mut engine, Buy, 99.0);
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::PlacePeggedOrder {
+            user_id: pegger,
+            side: Buy,
+            quantity: Quantity::from_f64(1.0),
+            peg: Peg {
+                reference: PegReference::BestBid,
+                offset: 500_000, // +0.5
+                limit: Some(Price::from_f64(100.0)),
+            },
+            expires_at: None,
+            received_at: Utc::now(),
+            source: OrderSource::Web,
+            client_order_id: None,
+            response_tx,
+        });
+        let pegged = match response_rx.try_recv().unwrap() {
+            OrderBookResponse::OrderPlaced { order_id, .. } => order_id,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        let price_of = |engine: &Engine| engine.orderbook.get_order(pegged).unwrap().price;
+        let usd_of = |engine: &Engine| {
+            engine
+                .orderbook
+                .get_user_balance(pegger)
+                .unwrap()
+                .get_balance("USD")
+        };
+        assert_eq!(price_of(&engine), Some(Price::from_f64(99.5)));
+        assert_eq!(usd_of(&engine), 900.5);
+
+        // A better bid drags the peg up, but no further than its limit
+        let better_bid = limit(&mut engine, Buy, 99.8);
+        assert_eq!(price_of(&engine), Some(Price::from_f64(100.0)));
+        assert_eq!(usd_of(&engine), 900.0);
+        assert_eq!(engine.orderbook.best_bid(), Some(Price::from_f64(100.0)));
+
+        let (response_tx, _response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::CancelOrder {
+            user_id: maker,
+            order_id: better_bid,
+            response_tx,
+        });
+        assert_eq!(price_of(&engine), Some(Price::from_f64(99.5)));
+        assert_eq!(usd_of(&engine), 900.5);
+    }
+
+    #[tokio::test]
+    async fn trades_carry_roles_and_the_fees_charged_to_each_side() {
+        let config = EngineConfig {
+            market: MarketConfig {
+                fees: FeeSchedule {
+                    maker_rate: 0.001,
+                    taker_rate: 0.002,
+                },
+                ..MarketConfig::default()
+            },
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(maker, "BTC", 4.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+
+        let mut trades = Vec::new();
+        for (user_id, side) in [(maker, Buy), (taker, Sell)] {
+            engine.orderbook.add_funds(maker, "USD", 200.0);
+            engine.orderbook.add_funds(taker, "BTC", 2.0);
+            let order = LimitOrder::new(user_id, side, 100.0, 1.0);
+            if let OrderBookResponse::OrderPlaced { trades: placed, .. } =
+                submit(&mut engine, order)
+            {
+                trades.extend(placed);
             }
+        }
 
-            OrderBookCommand::PlaceMarketOrder {
-                user_id,
-                side,
-                quantity,
+        assert_eq!(trades.len(), 1);
+        let trade = &trades[0];
+        assert_eq!(trade.taker_side, Sell);
+        assert_eq!(trade.buyer_role, LiquidityRole::Maker);
+        assert_eq!(trade.seller_role, LiquidityRole::Taker);
+        assert!((trade.maker_fee - 0.1).abs() < 1e-9);
+        assert!((trade.taker_fee - 0.2).abs() < 1e-9);
+
+        let fees = engine.ledger.balance(&Account::new(AccountOwner::Fees, "USD"));
+        assert_eq!(fees, to_ledger_units(0.3));
+        assert!(engine.ledger.trial_balance().balanced);
+
+        let fills = engine.order_history.fills(trade.taker_order_id, 0);
+        assert_eq!(fills[0].role, LiquidityRole::Taker);
+        assert!((fills[0].fee - 0.2).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn a_remainder_that_would_cross_the_book_is_cancelled_and_refunded() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(maker, "BTC", 5.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+
+        let mut responses = Vec::new();
+        for (user_id, side, quantity, min_fill_qty) in [
+            (maker, Sell, 5.0, Some(Quantity::from_f64(5.0))),
+            (taker, Buy, 1.0, None),
+        ] {
+            let order = LimitOrder {
+                min_fill_qty,
+                ..LimitOrder::new(user_id, side, 100.0, quantity)
+            };
+            responses.push(submit(&mut engine, order));
+        }
+
+        let OrderBookResponse::OrderPlaced { trades, status, .. } = &responses[1] else {
+            panic!("the buy was not placed");
+        };
+        assert!(trades.is_empty());
+        assert!(status.contains("minimum fill"));
+        assert_eq!(engine.orderbook.best_bid(), None);
+        let balance = engine.orderbook.get_user_balance(taker).unwrap();
+        assert_eq!(balance.get_balance("USD"), 1_000.0);
+        assert_eq!(balance.get_reserved("USD"), 0.0);
+    }
+
+    #[tokio::test]
+    async fn a_fill_whose_fee_cannot_be_paid_does_not_settle() {
+        let config = EngineConfig {
+            market: MarketConfig {
+                fees: FeeSchedule {
+                    maker_rate: 0.001,
+                    taker_rate: 0.002,
+                },
+                ..MarketConfig::default()
+            },
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(maker, "BTC", 1.0);
+        // Enough for the trade, none left over for its fee
+        engine.orderbook.add_funds(taker, "USD", 100.0);
+
+        let mut responses = Vec::new();
+        for (user_id, side) in [(maker, Sell), (taker, Buy)] {
+            responses.push(submit(
+                &mut engine,
+                LimitOrder::new(user_id, side, 100.0, 1.0),
+            ));
+        }
+
+        let OrderBookResponse::OrderPlaced { trades, .. } = &responses[1] else {
+            panic!("the buy was not placed");
+        };
+        assert!(trades.is_empty());
+        let held = engine.dead_letters.list(true);
+        assert_eq!(held.len(), 1);
+        assert!(held[0].error.contains(&taker.to_string()));
+        // Neither side paid anything, fee or trade
+        let balance = engine.orderbook.get_user_balance(taker).unwrap();
+        assert_eq!(balance.get_reserved("USD"), 100.0);
+        assert_eq!(balance.get_balance("BTC"), 0.0);
+        let fees = Account::new(AccountOwner::Fees, "USD");
+        assert_eq!(engine.ledger.balance(&fees), 0);
+        let journals = engine.ledger.recent_journals_of(JournalKind::Fee, 10);
+        assert!(journals.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_refunded_priority_fee_is_returned_and_reversed_in_the_ledger() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let user_id = Uuid::new_v4();
+        engine.orderbook.add_funds(user_id, "USD", 10.0);
+
+        assert_eq!(engine.charge_fee(user_id, 2.0), Ok(2.0));
+        engine.refund_fee(user_id, 2.0).unwrap();
+
+        let balance = engine.orderbook.get_user_balance(user_id).unwrap();
+        assert_eq!(balance.get_balance("USD"), 10.0);
+        let fees = Account::new(AccountOwner::Fees, "USD");
+        assert_eq!(engine.ledger.balance(&fees), 0);
+        let journals = engine.ledger.recent_journals_of(JournalKind::Fee, 10);
+        assert_eq!(journals.len(), 2);
+        assert!(engine.ledger.trial_balance().balanced);
+    }
+
+    #[tokio::test]
+    async fn fee_journals_keep_the_fx_rate_they_were_charged_under() {
+        let config = EngineConfig {
+            market: MarketConfig {
+                fees: FeeSchedule {
+                    maker_rate: 0.001,
+                    taker_rate: 0.002,
+                },
+                ..MarketConfig::default()
+            },
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(buyer, "USD", 1_000.0);
+        engine.orderbook.add_funds(seller, "BTC", 2.0);
+
+        let set_rate = |engine: &mut Engine, rate| {
+            let (response_tx, mut response_rx) = oneshot::channel();
+            engine.process(OrderBookCommand::SetFxRate {
+                base: "BTC".to_string(),
+                quote: "USD".to_string(),
+                rate,
                 response_tx,
-            } => {
-                let order = Order::new_market(user_id, side, quantity);
-                let order_id = order.id;
+            });
+            response_rx.try_recv().unwrap()
+        };
+        assert!(matches!(
+            set_rate(&mut engine, -1.0),
+            OrderBookResponse::Error { .. }
+        ));
+        assert!(matches!(
+            set_rate(&mut engine, 100.0),
+            OrderBookResponse::FxRate { .. }
+        ));
 
-                // For market orders, we need to check balance based on estimated execution
-                // For simplicity, we'll skip balance check here and let matching engine handle it
-                // In production, you'd estimate the required balance based on orderbook depth
+        for (user_id, side) in [(buyer, Buy), (seller, Sell)] {
+            submit(&mut engine, LimitOrder::new(user_id, side, 100.0, 1.0));
+        }
+        set_rate(&mut engine, 120.0);
 
-                match orderbook.match_order(order) {
-                    Ok(trades) => {
-                        let status = if trades.is_empty() {
-                            "No liquidity".to_string()
-                        } else {
-                            "Filled".to_string()
-                        };
+        // Newest first: the taker sold and received USD, the maker bought BTC
+        let journals = engine.ledger.recent_journals_of(JournalKind::Fee, 10);
+        assert_eq!(journals.len(), 2);
+        assert!(journals[0].fx_rate.is_none());
+        let fx_rate = journals[1].fx_rate.as_ref().unwrap();
+        assert_eq!((fx_rate.rate, fx_rate.version), (100.0, 1));
+        let fee_in_btc = fx_rate.convert(0.1, "USD").unwrap();
+        assert!((fee_in_btc - 0.001).abs() < 1e-12);
+        assert_eq!(
+            engine.settlement_rates.get("BTC", "USD").unwrap().rate,
+            120.0
+        );
 
-                        let _ = response_tx.send(OrderBookResponse::OrderPlaced {
-                            order_id,
-                            trades,
-                            status,
-                        });
-                    }
-                    Err(e) => {
-                        let _ = response_tx.send(OrderBookResponse::Error {
-                            message: format!("Failed to place market order: {}", e),
-                        });
-                    }
-                }
+        // Stamped from the engine clock, so a replay records the same time
+        let logged_at = Utc::now() - chrono::Duration::hours(1);
+        engine.clock.pin(logged_at);
+        set_rate(&mut engine, 130.0);
+        assert_eq!(
+            engine.settlement_rates.get("BTC", "USD").unwrap().set_at,
+            logged_at
+        );
+    }
+
+    #[tokio::test]
+    async fn each_command_leaves_its_changes_in_the_store() {
+        let config = EngineConfig {
+            storage: StorageBackend::Sqlite(":memory:".into()),
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(buyer, "USD", 1_000.0);
+        engine.orderbook.add_funds(seller, "BTC", 2.0);
+
+        for (user_id, side, quantity) in [(buyer, Buy, 2.0), (seller, Sell, 1.0)] {
+            submit(&mut engine, LimitOrder::new(user_id, side, 100.0, quantity));
+        }
+
+        // The resting bid was saved when placed and again when filled
+        let bids = engine
+            .history
+            .query(move |store| store.orders_for_user(buyer, &HistoryQuery::latest(10)))
+            .unwrap();
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].status, OrderStatus::PartiallyFilled);
+        let trades = engine
+            .history
+            .query(move |store| store.trades_for_user(seller, &HistoryQuery::latest(10)))
+            .unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].maker_user_id, buyer);
+        let journals = engine
+            .history
+            .query(|store| store.journals_after(0, 10))
+            .unwrap();
+        assert!(!journals.is_empty());
+        let mut posted = engine.ledger.recent_journals(10);
+        posted.reverse();
+        assert_eq!(journals, posted);
+
+        // Each order update and balance change is queued for the outbox too
+        let events = engine
+            .history
+            .query(|store| store.pending_events(100))
+            .unwrap();
+        let count = |kind| events.iter().filter(|event| event.kind == kind).count();
+        assert_eq!(count(storage::OutboxEventKind::TradeExecuted), 1);
+        let last_bid = events
+            .iter()
+            .filter_map(|event| event.order.as_ref())
+            .rfind(|order| order.user_id == buyer)
+            .unwrap();
+        assert_eq!(last_bid.status, OrderStatus::PartiallyFilled);
+        assert!(events
+            .iter()
+            .filter_map(|event| event.balance.as_ref())
+            .any(|balance| balance.user_id == seller));
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::GetUserTrades {
+            user_id: buyer,
+            query: HistoryQuery::latest(10),
+            deadline: Instant::now() + Duration::from_secs(1),
+            response_tx,
+        });
+        // Answered by the history worker
+        engine.history.query(|_| ());
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::UserTrades { fills, trades } => {
+                assert_eq!(trades, 1);
+                assert_eq!((fills[0].side, fills[0].role), (Buy, LiquidityRole::Maker));
             }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
 
-            OrderBookCommand::CancelOrder {
+    #[tokio::test]
+    async fn account_summary_gathers_orders_fills_and_position() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(buyer, "USD", 1_000.0);
+        engine.orderbook.add_funds(seller, "BTC", 2.0);
+
+        for (user_id, side, price, quantity) in [
+            (buyer, Buy, 90.0, 1.0),
+            (buyer, Buy, 100.0, 2.0),
+            (seller, Sell, 100.0, 1.0),
+        ] {
+            submit(&mut engine, LimitOrder::new(user_id, side, price, quantity));
+        }
+
+        let summary = |engine: &mut Engine, user_id| {
+            let (response_tx, mut response_rx) = oneshot::channel();
+            engine.process(OrderBookCommand::GetAccountSummary {
                 user_id,
-                order_id,
+                fills: 10,
+                deadline: Instant::now() + Duration::from_secs(1),
                 response_tx,
-            } => {
-                match orderbook.cancel_order(order_id) {
-                    Ok(cancelled_order) => {
-                        // Verify ownership
-                        if cancelled_order.user_id != user_id {
-                            let _ = response_tx.send(OrderBookResponse::Error {
-                                message: "Not authorized to cancel this order".to_string(),
-                            });
-                            continue;
-                        }
+            });
+            engine.history.query(|_| ());
+            response_rx.try_recv().unwrap()
+        };
+        match summary(&mut engine, buyer) {
+            OrderBookResponse::AccountSummary { summary } => {
+                let prices: Vec<_> = summary.open_orders.iter().map(|o| o.price).collect();
+                assert_eq!(
+                    prices,
+                    vec![Some(Price::from_f64(90.0)), Some(Price::from_f64(100.0))]
+                );
+                assert_eq!(summary.recent_fills.len(), 1);
+                assert_eq!(summary.recent_fills[0].role, LiquidityRole::Maker);
+                assert_eq!(summary.position.unwrap().position.quantity, 1.0);
+                assert!(summary.alerts.is_empty() && !summary.quarantined);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(matches!(
+            summary(&mut engine, Uuid::new_v4()),
+            OrderBookResponse::Error { .. }
+        ));
+    }
 
-                        // Refund reserved balance
-                        match cancelled_order.side {
-                            crate::types::OrderSide::Buy => {
-                                // Refund USD
-                                if let Some(price) = cancelled_order.price {
-                                    let usd_refund = price.to_f64()
-                                        * cancelled_order.remaining_quantity.to_f64();
-                                    orderbook.credit_balance(user_id, "USD", usd_refund);
-                                }
-                            }
-                            crate::types::OrderSide::Sell => {
-                                // Refund BTC
-                                let btc_refund = cancelled_order.remaining_quantity.to_f64();
-                                orderbook.credit_balance(user_id, "BTC", btc_refund);
-                            }
-                        }
+    #[tokio::test]
+    async fn activity_lists_order_events_and_transfers_newest_first() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+        let (response_tx, _response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::AddFunds {
+            user_id: buyer,
+            currency: "USD".to_string(),
+            amount: 1_000.0,
+            response_tx,
+        });
+        engine.orderbook.add_funds(seller, "BTC", 2.0);
 
-                        let _ = response_tx.send(OrderBookResponse::OrderCancelled {
-                            order_id,
-                            success: true,
-                        });
-                    }
-                    Err(e) => {
-                        let _ = response_tx.send(OrderBookResponse::Error {
-                            message: format!("Failed to cancel order: {}", e),
-                        });
-                    }
+        let mut order_ids = Vec::new();
+        for (user_id, side, price) in [
+            (buyer, Buy, 90.0),
+            (buyer, Buy, 100.0),
+            (seller, Sell, 100.0),
+        ] {
+            let order = LimitOrder::new(user_id, side, price, 1.0);
+            order_ids.push(order_id(submit(&mut engine, order)));
+        }
+        let (response_tx, _response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::CancelOrder {
+            user_id: buyer,
+            order_id: order_ids[0],
+            response_tx,
+        });
+        let (response_tx, _response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::WithdrawFunds {
+            user_id: buyer,
+            currency: "USD".to_string(),
+            amount: 50.0,
+            response_tx,
+        });
+
+        let activity = |engine: &mut Engine, query| {
+            let (response_tx, mut response_rx) = oneshot::channel();
+            engine.process(OrderBookCommand::GetActivity {
+                user_id: buyer,
+                query,
+                deadline: Instant::now() + Duration::from_secs(1),
+                response_tx,
+            });
+            match response_rx.try_recv().unwrap() {
+                OrderBookResponse::Activity { entries } => entries,
+                other => panic!("unexpected response: {:?}", other),
+            }
+        };
+        let entries = activity(&mut engine, HistoryQuery::latest(10));
+        assert!(entries.windows(2).all(|pair| pair[0].at >= pair[1].at));
+        let nth = |order_id: &Uuid| order_ids.iter().position(|id| id == order_id).unwrap();
+        let kinds: Vec<_> = entries
+            .iter()
+            .map(|entry| match &entry.activity {
+                Activity::Withdrawal { amount, .. } => format!("withdrawal {}", amount),
+                Activity::OrderCancelled { order_id, .. } => format!("cancelled {}", nth(order_id)),
+                Activity::OrderFilled { order_id, role, .. } => {
+                    assert_eq!(*role, LiquidityRole::Maker);
+                    format!("filled {}", nth(order_id))
                 }
+                Activity::OrderPlaced { order_id, .. } => format!("placed {}", nth(order_id)),
+                Activity::Deposit { amount, .. } => format!("deposit {}", amount),
+                other => panic!("unexpected activity: {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "withdrawal 50",
+                "cancelled 0",
+                "filled 1",
+                "placed 1",
+                "placed 0",
+                "deposit 1000"
+            ]
+        );
+
+        let second_page = HistoryQuery {
+            offset: 2,
+            ..HistoryQuery::latest(2)
+        };
+        assert_eq!(activity(&mut engine, second_page), entries[2..4].to_vec());
+        let before_withdrawal = HistoryQuery {
+            to: Some(entries[0].at),
+            ..HistoryQuery::latest(10)
+        };
+        let earlier = activity(&mut engine, before_withdrawal);
+        assert_eq!(earlier, entries[1..].to_vec());
+    }
+
+    #[tokio::test]
+    async fn amending_down_keeps_priority_and_repricing_loses_it() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let seller = Uuid::new_v4();
+        engine.orderbook.add_funds(first, "USD", 1_000.0);
+        engine.orderbook.add_funds(second, "USD", 1_000.0);
+        engine.orderbook.add_funds(seller, "BTC", 5.0);
+
+        let place = |engine: &mut Engine, user_id, side, price: f64, quantity: f64| match submit(
+            engine,
+            LimitOrder::new(user_id, side, price, quantity),
+        ) {
+            OrderBookResponse::OrderPlaced {
+                order_id, trades, ..
+            } => (order_id, trades),
+            other => panic!("unexpected response: {:?}", other),
+        };
+        let amend =
+            |engine: &mut Engine, user_id, order_id, price: Option<f64>, quantity: Option<f64>| {
+                let (response_tx, mut response_rx) = oneshot::channel();
+                engine.process(OrderBookCommand::AmendOrder {
+                    user_id,
+                    order_id,
+                    price: price.map(Price::from_f64),
+                    quantity: quantity.map(Quantity::from_f64),
+                    received_at: Utc::now(),
+                    response_tx,
+                });
+                response_rx.try_recv().unwrap()
+            };
+        let usd = |engine: &Engine, user_id| {
+            engine
+                .orderbook
+                .get_user_balance(user_id)
+                .unwrap()
+                .get_balance("USD")
+        };
+
+        let (a, _) = place(&mut engine, first, Buy, 100.0, 3.0);
+        let (b, _) = place(&mut engine, second, Buy, 100.0, 2.0);
+
+        // Only the owner may amend, and only downwards
+        assert!(matches!(
+            amend(&mut engine, second, a, None, Some(1.0)),
+            OrderBookResponse::Error { .. }
+        ));
+        assert!(matches!(
+            amend(&mut engine, first, a, None, Some(4.0)),
+            OrderBookResponse::Error { .. }
+        ));
+        assert_eq!(usd(&engine, first), 700.0);
+
+        // Shrinking keeps its place at the front and refunds the difference
+        match amend(&mut engine, first, a, None, Some(1.0)) {
+            OrderBookResponse::OrderPlaced { trades, status, .. } => {
+                assert!(trades.is_empty());
+                assert_eq!(status, "Amended");
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert_eq!(usd(&engine, first), 900.0);
+        assert_eq!(
+            engine.orderbook.get_depth(1).0,
+            vec![DepthLevel::new(
+                Price::from_f64(100.0),
+                Quantity::from_f64(3.0),
+                2
+            )]
+        );
+        let (_, trades) = place(&mut engine, seller, Sell, 100.0, 0.5);
+        assert_eq!(trades[0].maker_order_id, a);
+
+        // Re-pricing sends it behind what already rests at the new price
+        amend(&mut engine, second, b, Some(101.0), None);
+        assert_eq!(usd(&engine, second), 1_000.0 - 202.0);
+        amend(&mut engine, first, a, Some(101.0), None);
+        let (_, trades) = place(&mut engine, seller, Sell, 101.0, 0.5);
+        assert_eq!(trades[0].maker_order_id, b);
+
+        // A new price that crosses the book trades straight away
+        let (ask, _) = place(&mut engine, seller, Sell, 102.0, 1.0);
+        match amend(&mut engine, first, a, Some(102.0), None) {
+            OrderBookResponse::OrderPlaced { trades, status, .. } => {
+                assert_eq!(trades.len(), 1);
+                assert_eq!(trades[0].maker_order_id, ask);
+                assert_eq!(status, "Amended, filled");
             }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(engine.orderbook.get_order(a).is_none());
+    }
+
+    #[tokio::test]
+    async fn a_refused_reprice_leaves_the_order_as_it_was() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(first, "USD", 200.0);
+        engine.orderbook.add_funds(second, "USD", 1_000.0);
+        let a = order_id(submit(&mut engine, LimitOrder::new(first, Buy, 100.0, 2.0)));
+        let b = order_id(submit(
+            &mut engine,
+            LimitOrder::new(second, Buy, 100.0, 1.0),
+        ));
+
+        let state = |engine: &Engine| {
+            let balance = engine.orderbook.get_user_balance(first).unwrap();
+            let order = engine.orderbook.get_order(a).unwrap();
+            (
+                order.price,
+                order.remaining_quantity,
+                order.received_at,
+                balance.get_balance("USD"),
+                balance.reserved.get("USD").copied(),
+            )
+        };
+        let queue = |engine: &Engine| -> Vec<Uuid> {
+            engine
+                .orderbook
+                .resting_orders()
+                .iter()
+                .map(|o| o.id)
+                .collect()
+        };
+        let before = state(&engine);
+        assert_eq!(queue(&engine), vec![a, b]);
+
+        // Cutting to 1.5 at 150 needs 225 USD reserved, more than the 200 held.
+        // The amendment is turned down before anything changes.
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::AmendOrder {
+            user_id: first,
+            order_id: a,
+            price: Some(Price::from_f64(150.0)),
+            quantity: Some(Quantity::from_f64(1.5)),
+            received_at: Utc::now(),
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::Error { .. }
+        ));
+        assert_eq!(state(&engine), before);
+        assert_eq!(queue(&engine), vec![a, b]);
+
+        // Should the new reservation still be refused, the cut and the new
+        // receipt time are undone along with the move
+        let later = Utc::now() + chrono::Duration::seconds(1);
+        let result = engine.reprice_order(a, Price::from_f64(150.0), |order| {
+            order.reduce(Quantity::from_f64(0.5));
+            order.received_at = later;
+        });
+        assert!(result.is_err());
+        assert_eq!(state(&engine), before);
+        assert_eq!(queue(&engine), vec![a, b]);
+        assert_eq!(
+            engine.orderbook.get_depth(1).0,
+            vec![DepthLevel::new(
+                Price::from_f64(100.0),
+                Quantity::from_f64(3.0),
+                2
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn batch_places_orders_back_to_back_with_a_result_each() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let maker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "USD", 250.0);
+        engine.orderbook.add_funds(maker, "BTC", 1.0);
 
-            OrderBookCommand::GetOrderBook { depth, response_tx } => {
-                let (bids, asks) = orderbook.get_depth(depth);
-                let _ = response_tx.send(OrderBookResponse::OrderBookDepth { bids, asks });
+        let order = |side, price: f64| crate::messages::LimitOrderParams {
+            side,
+            price: Price::from_f64(price),
+            quantity: Quantity::from_f64(1.0),
+            time_in_force: TimeInForce::GTC,
+            display_quantity: None,
+            hidden: false,
+            min_fill_qty: None,
+            expires_at: None,
+            trade_through_protected: false,
+            priority_fee: 0.0,
+            client_order_id: None,
+        };
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::PlaceOrderBatch {
+            user_id: maker,
+            orders: vec![
+                order(Buy, 99.0),
+                order(Buy, 98.0),
+                order(Buy, 97.0),
+                order(Sell, 101.0),
+            ],
+            received_at: Utc::now(),
+            source: OrderSource::ApiKey,
+            response_tx,
+        });
+
+        let results = match response_rx.try_recv().unwrap() {
+            OrderBookResponse::BatchPlaced { results } => results,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        assert_eq!(results.len(), 4);
+        assert!(matches!(results[0], OrderBookResponse::OrderPlaced { .. }));
+        assert!(matches!(results[1], OrderBookResponse::OrderPlaced { .. }));
+        // Only the cash for two bids was there; the batch carries on past it
+        assert!(matches!(results[2], OrderBookResponse::Error { .. }));
+        assert!(matches!(results[3], OrderBookResponse::OrderPlaced { .. }));
+        assert_eq!(engine.orderbook.open_order_count(maker), 3);
+        assert_eq!(engine.orderbook.best_ask(), Some(Price::from_f64(101.0)));
+    }
+
+    #[tokio::test]
+    async fn protected_orders_are_rejected_rather_than_trading_through_the_band() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let maker = Uuid::new_v4();
+        let taker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 5.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+
+        let place = |engine: &mut Engine, user_id, side, price: f64, quantity: f64, protected| {
+            let order = LimitOrder {
+                trade_through_protected: protected,
+                ..LimitOrder::new(user_id, side, price, quantity)
+            };
+            submit(engine, order)
+        };
+        let filled_at = |response: OrderBookResponse| match response {
+            OrderBookResponse::OrderPlaced { trades, .. } => {
+                trades.iter().map(|t| t.price.to_f64()).collect::<Vec<_>>()
             }
+            other => panic!("unexpected response: {:?}", other),
+        };
 
-            OrderBookCommand::GetUserBalance {
+        place(&mut engine, maker, Sell, 100.2, 1.0, false);
+        place(&mut engine, maker, Sell, 101.0, 1.0, false);
+
+        // Reference 100 with a 50 bps band: buys may trade up to 100.5
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::SetTradeThroughBand {
+            market: "BTC-USD".to_string(),
+            band: TradeThroughBand {
+                reference_price: Some(Price::from_f64(100.0)),
+                band_bps: 50,
+            },
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::MarketConfig { .. }
+        ));
+
+        // Filling 1.5 would reach 101, beyond the 100.5 band: nothing trades
+        match place(&mut engine, taker, Buy, 102.0, 1.5, true) {
+            OrderBookResponse::Error { message } => assert!(message.contains("100.5")),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert_eq!(engine.orderbook.best_ask(), Some(Price::from_f64(100.2)));
+
+        // Filled entirely inside the band, the same price is fine
+        assert_eq!(
+            filled_at(place(&mut engine, taker, Buy, 102.0, 1.0, true)),
+            vec![100.2]
+        );
+
+        // Unprotected orders trade through as before
+        assert_eq!(
+            filled_at(place(&mut engine, taker, Buy, 102.0, 1.0, false)),
+            vec![101.0]
+        );
+    }
+
+    #[tokio::test]
+    async fn mass_cancel_only_touches_the_callers_matching_orders() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let maker = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "USD", 1_000.0);
+        engine.orderbook.add_funds(maker, "BTC", 5.0);
+        engine.orderbook.add_funds(other, "USD", 1_000.0);
+
+        let place = |engine: &mut Engine, user_id, side, price: f64, tag: &str| {
+            let order = LimitOrder {
+                client_order_id: Some(tag.to_string()),
+                ..LimitOrder::new(user_id, side, price, 1.0)
+            };
+            order_id(submit(engine, order))
+        };
+        let mass_cancel = |engine: &mut Engine, user_id, filter| {
+            let (response_tx, mut response_rx) = oneshot::channel();
+            engine.process(OrderBookCommand::MassCancel {
                 user_id,
+                filter,
                 response_tx,
-            } => {
-                if let Some(balance) = orderbook.get_user_balance(user_id) {
-                    let _ = response_tx.send(OrderBookResponse::UserBalance {
-                        balance: balance.clone(),
-                    });
-                } else {
-                    let _ = response_tx.send(OrderBookResponse::Error {
-                        message: "User not found".to_string(),
-                    });
-                }
+            });
+            match response_rx.try_recv().unwrap() {
+                OrderBookResponse::OrdersCancelled { order_ids } => order_ids,
+                other => panic!("unexpected response: {:?}", other),
             }
+        };
 
-            OrderBookCommand::AddFunds {
+        let low_bid = place(&mut engine, maker, Buy, 90.0, "mm-1");
+        let high_bid = place(&mut engine, maker, Buy, 95.0, "mm-2");
+        let other_bid = place(&mut engine, other, Buy, 96.0, "mm-3");
+        let manual_bid = place(&mut engine, maker, Buy, 97.0, "manual");
+        let ask = place(&mut engine, maker, Sell, 110.0, "mm-4");
+
+        // The maker's market-making bids above 92 only
+        let cancelled = mass_cancel(
+            &mut engine,
+            maker,
+            OrderFilter {
+                side: Some(Buy),
+                min_price: Some(Price::from_f64(92.0)),
+                tag: Some("mm-".to_string()),
+                ..OrderFilter::default()
+            },
+        );
+        assert_eq!(cancelled, vec![high_bid]);
+        for kept in [low_bid, other_bid, manual_bid, ask] {
+            assert!(engine.orderbook.get_order(kept).is_some());
+        }
+        let balance = engine.orderbook.get_user_balance(maker).unwrap();
+        assert_eq!(balance.get_balance("USD"), 1_000.0 - 90.0 - 97.0);
+
+        // No filters: everything the maker still has resting
+        let mut cancelled = mass_cancel(&mut engine, maker, OrderFilter::default());
+        cancelled.sort();
+        let mut expected = vec![low_bid, manual_bid, ask];
+        expected.sort();
+        assert_eq!(cancelled, expected);
+        assert_eq!(engine.orderbook.open_order_count(maker), 0);
+        assert_eq!(engine.orderbook.open_order_count(other), 1);
+        let balance = engine.orderbook.get_user_balance(maker).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 5.0);
+    }
+
+    #[tokio::test]
+    async fn lapsed_dead_man_switch_cancels_everything_resting() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let bot = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        engine.orderbook.add_funds(bot, "USD", 1_000.0);
+        engine.orderbook.add_funds(bot, "BTC", 5.0);
+        engine.orderbook.add_funds(other, "USD", 1_000.0);
+
+        let place = |engine: &mut Engine, user_id, side, price: f64| {
+            submit(engine, LimitOrder::new(user_id, side, price, 1.0));
+        };
+        let arm = |engine: &mut Engine, user_id, secs| {
+            let (response_tx, mut response_rx) = oneshot::channel();
+            engine.process(OrderBookCommand::CancelAllAfter {
                 user_id,
-                currency,
-                amount,
+                timeout: Duration::from_secs(secs),
                 response_tx,
+            });
+            match response_rx.try_recv().unwrap() {
+                OrderBookResponse::CancelAllAfterSet { cancel_at } => cancel_at,
+                other => panic!("unexpected response: {:?}", other),
+            }
+        };
+
+        place(&mut engine, bot, Buy, 95.0);
+        place(&mut engine, bot, Sell, 105.0);
+        place(&mut engine, other, Buy, 94.0);
+        arm(&mut engine, other, 30);
+        assert_eq!(arm(&mut engine, other, 0), None);
+
+        // Refreshed before it lapses, so nothing happens
+        let first = arm(&mut engine, bot, 30).unwrap();
+        engine.fire_dead_man_switches(first - chrono::Duration::seconds(1));
+        assert_eq!(engine.orderbook.open_order_count(bot), 2);
+        let refreshed = arm(&mut engine, bot, 60).unwrap();
+        engine.fire_dead_man_switches(first);
+        assert_eq!(engine.orderbook.open_order_count(bot), 2);
+
+        // Lapsed: every order goes and the reservations come back
+        engine.fire_dead_man_switches(refreshed);
+        assert_eq!(engine.orderbook.open_order_count(bot), 0);
+        let balance = engine.orderbook.get_user_balance(bot).unwrap();
+        assert_eq!(balance.get_balance("USD"), 1_000.0);
+        assert_eq!(balance.get_balance("BTC"), 5.0);
+        assert_eq!(engine.orderbook.open_order_count(other), 1);
+        assert!(engine.dead_man.is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_order_ids_are_unique_per_user_and_address_orders() {
+        let config = EngineConfig {
+            client_order_id_window: Duration::from_secs(60),
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        let (trader, other) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(trader, "USD", 1_000.0);
+        engine.orderbook.add_funds(other, "USD", 1_000.0);
+
+        let place = |engine: &mut Engine, user_id, price: f64| {
+            let order = LimitOrder {
+                client_order_id: Some("bid-1".to_string()),
+                ..LimitOrder::new(user_id, Buy, price, 1.0)
+            };
+            submit(engine, order)
+        };
+
+        let order_id = order_id(place(&mut engine, trader, 95.0));
+        // Reused by the same user, even for a different order, is refused
+        match place(&mut engine, trader, 94.0) {
+            OrderBookResponse::Error { message } => assert!(message.contains("bid-1")),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        // Another user's IDs are their own
+        assert!(matches!(
+            place(&mut engine, other, 95.0),
+            OrderBookResponse::OrderPlaced { .. }
+        ));
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::GetOrderByClientId {
+            user_id: trader,
+            client_order_id: "bid-1".to_string(),
+            deadline: Instant::now() + Duration::from_secs(1),
+            response_tx,
+        });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::Order { order } => assert_eq!(order.id, order_id),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::CancelOrderByClientId {
+            user_id: trader,
+            client_order_id: "bid-1".to_string(),
+            response_tx,
+        });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::OrderCancelled {
+                order_id: id,
+                success,
             } => {
-                orderbook.add_funds(user_id, &currency, amount);
-                let new_balance = orderbook
-                    .get_or_create_balance(user_id)
-                    .get_balance(&currency);
+                assert_eq!(id, order_id);
+                assert!(success);
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert_eq!(engine.orderbook.open_order_count(trader), 0);
+        assert_eq!(engine.orderbook.open_order_count(other), 1);
+    }
 
-                let _ = response_tx.send(OrderBookResponse::FundsAdded {
-                    user_id,
-                    currency,
-                    new_balance,
-                });
+    #[tokio::test]
+    async fn refused_settlement_opens_an_incident_and_quarantines_the_account() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(maker, "BTC", 2.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+
+        let place = |engine: &mut Engine, user_id, side| {
+            submit(engine, LimitOrder::new(user_id, side, 100.0, 1.0))
+        };
+
+        place(&mut engine, maker, Sell);
+        // Incident times come from the engine clock, so a replay records them again
+        let logged_at = Utc::now() - chrono::Duration::hours(1);
+        engine.clock.pin(logged_at);
+        // Something outside the engine's bookkeeping emptied what the maker's order reserved
+        engine
+            .orderbook
+            .get_or_create_balance(maker)
+            .reserved
+            .insert("BTC".to_string(), 0.0);
+
+        // The match stands, but its settlement is held rather than half
+        // applied, and the trade is not published until it settles
+        match place(&mut engine, taker, Buy) {
+            OrderBookResponse::OrderPlaced { trades, .. } => assert!(trades.is_empty()),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let letters = engine.dead_letters.list(true);
+        assert_eq!(letters.len(), 1);
+        let trade = letters[0].trade.clone();
+        assert!(engine.orderbook.get_order(trade.maker_order_id).is_none());
+        assert!(engine.trade_tape.page(None, 10).entries.is_empty());
+        // The taker's leg did not go through either
+        let balance = engine.orderbook.get_user_balance(taker).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 0.0);
+        assert!(engine
+            .ledger
+            .recent_journals_of(JournalKind::Trade, 10)
+            .is_empty());
+
+        let incidents = engine.incidents.list(true);
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].user_id, maker);
+        assert_eq!(incidents[0].currency, "BTC");
+        assert!(incidents[0].reserved);
+        assert_eq!(incidents[0].operation, BalanceOperation::Settlement);
+        assert_eq!(incidents[0].opened_at, logged_at);
+        assert_eq!(engine.metrics.snapshot().incidents_opened, 1);
+
+        match place(&mut engine, maker, Buy) {
+            OrderBookResponse::Error { message } => {
+                assert_eq!(message, "Account is quarantined pending review")
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::ResolveIncident {
+            id: incidents[0].id,
+            note: "Restored from the ledger".to_string(),
+            response_tx,
+        });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::Incident { incident } => {
+                assert_eq!(incident.resolved_at, Some(logged_at))
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(engine.quarantined_response(maker).is_none());
+
+        // With the maker's reservation restored, a retry settles the held trade
+        engine
+            .orderbook
+            .get_or_create_balance(maker)
+            .reserved
+            .insert("BTC".to_string(), 1.0);
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::RetryDeadLetter {
+            id: letters[0].id,
+            response_tx,
+        });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::DeadLetter { letter } => {
+                assert_eq!(letter.status, DeadLetterStatus::Settled);
+                assert_eq!(letter.attempts, 1);
             }
+            other => panic!("unexpected response: {:?}", other),
         }
+        let balance = engine.orderbook.get_user_balance(taker).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 1.0);
+        assert_eq!(balance.get_reserved("USD"), 0.0);
+        assert_eq!(engine.trade_tape.page(None, 10).entries.len(), 1);
+        assert!(engine.ledger.trial_balance().balanced);
+        assert!(engine.incidents.list(true).is_empty());
     }
 
-    println!("OrderBook engine shutting down...");
+    #[tokio::test]
+    async fn held_settlement_is_compensated_once_its_retries_run_out() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(maker, "BTC", 2.0);
+        engine.orderbook.add_funds(taker, "USD", 1_000.0);
+
+        for (user_id, side) in [(maker, Sell), (taker, Buy)] {
+            submit(&mut engine, LimitOrder::new(user_id, side, 100.0, 1.0));
+            if user_id == maker {
+                // Something outside the engine's bookkeeping empties the reservation
+                engine
+                    .orderbook
+                    .get_or_create_balance(maker)
+                    .reserved
+                    .insert("BTC".to_string(), 0.0);
+            }
+        }
+        let balance = |engine: &Engine, user_id, currency| {
+            engine
+                .orderbook
+                .get_user_balance(user_id)
+                .map_or(0.0, |b| b.get_balance(currency))
+        };
+        assert_eq!(balance(&engine, taker, "USD"), 900.0);
+        let id = engine.dead_letters.list(true)[0].id;
+
+        for _ in 0..SETTLEMENT_RETRY_LIMIT {
+            engine.process(OrderBookCommand::AdvanceClock {
+                by: Duration::from_secs(60 * 60),
+                response_tx: oneshot::channel().0,
+            });
+        }
+        // Refused retries open no further incidents, but the maker's
+        // reservation is gone, so releasing it is refused and raised. The
+        // taker's side is released with it or not at all.
+        let letter = engine.dead_letters.get(id).unwrap();
+        assert_eq!(letter.status, DeadLetterStatus::Pending);
+        assert_eq!(letter.attempts, SETTLEMENT_RETRY_LIMIT + 1);
+        assert_eq!(balance(&engine, taker, "USD"), 900.0);
+        let incidents = engine.incidents.list(true);
+        assert_eq!(incidents.len(), 2);
+        assert_eq!(incidents[0].user_id, maker);
+        assert_eq!(incidents[0].operation, BalanceOperation::Refund);
+        assert_eq!(balance(&engine, maker, "BTC"), 1.0);
+
+        // Once the reservation is put right, compensation releases both sides
+        engine
+            .orderbook
+            .get_or_create_balance(maker)
+            .reserved
+            .insert("BTC".to_string(), 1.0);
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::CompensateDeadLetter {
+            id,
+            note: "Reservation restored".to_string(),
+            response_tx,
+        });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::DeadLetter { .. }
+        ));
+        let letter = engine.dead_letters.get(id).unwrap();
+        assert_eq!(letter.status, DeadLetterStatus::Compensated);
+        // The taker has back what its order reserved for the trade
+        assert_eq!(balance(&engine, taker, "USD"), 1_000.0);
+        assert_eq!(balance(&engine, taker, "BTC"), 0.0);
+        assert_eq!(balance(&engine, maker, "BTC"), 2.0);
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::RetryDeadLetter { id, response_tx });
+        assert!(matches!(
+            response_rx.try_recv().unwrap(),
+            OrderBookResponse::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn a_refused_refund_is_reported_to_the_canceller() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let user_id = Uuid::new_v4();
+        engine.orderbook.add_funds(user_id, "BTC", 1.0);
+        submit(&mut engine, LimitOrder::new(user_id, Sell, 100.0, 1.0));
+        let order_id = *engine.orderbook.orders.keys().next().unwrap();
+        engine
+            .orderbook
+            .get_or_create_balance(user_id)
+            .reserved
+            .insert("BTC".to_string(), 0.0);
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::CancelOrder {
+            user_id,
+            order_id,
+            response_tx,
+        });
+        let OrderBookResponse::Error { message } = response_rx.try_recv().unwrap() else {
+            panic!("the refused refund was not reported");
+        };
+        assert!(message.contains("not released"));
+        // The cancel itself stands, and the refusal is an incident
+        assert!(engine.orderbook.get_order(order_id).is_none());
+        let incidents = engine.incidents.list(true);
+        assert_eq!(incidents.len(), 1);
+        assert_eq!(incidents[0].operation, BalanceOperation::Refund);
+    }
+
+    #[tokio::test]
+    async fn market_orders_pay_out_of_a_reservation_taken_before_the_sweep() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(maker, "BTC", 2.0);
+        engine.orderbook.add_funds(taker, "USD", 150.0);
+        for price in [100.0, 110.0] {
+            submit(&mut engine, LimitOrder::new(maker, Sell, price, 1.0));
+        }
+        let market_buy = |engine: &mut Engine, quantity| {
+            let (response_tx, mut response_rx) = oneshot::channel();
+            engine.process(OrderBookCommand::PlaceMarketOrder {
+                user_id: taker,
+                side: Buy,
+                quantity: Quantity::from_f64(quantity),
+                slippage: None,
+                received_at: Utc::now(),
+                source: OrderSource::Web,
+                client_order_id: None,
+                response_tx,
+            });
+            response_rx.try_recv().unwrap()
+        };
+
+        // Sweeping both asks costs 210, more than the taker has
+        match market_buy(&mut engine, 2.0) {
+            OrderBookResponse::Error { message } => {
+                assert!(message.contains("Insufficient USD balance"))
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert_eq!(engine.orderbook.orders.len(), 2);
+
+        // Paid once, at the trade price, and nothing stays reserved
+        engine.orderbook.add_funds(taker, "USD", 150.0);
+        match market_buy(&mut engine, 2.0) {
+            OrderBookResponse::OrderPlaced { trades, .. } => assert_eq!(trades.len(), 2),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let balance = engine.orderbook.get_user_balance(taker).unwrap();
+        assert!((balance.get_balance("USD") - 90.0).abs() < 1e-9);
+        assert_eq!(balance.get_balance("BTC"), 2.0);
+        assert_eq!(balance.get_reserved("USD"), 0.0);
+        let balance = engine.orderbook.get_user_balance(maker).unwrap();
+        assert_eq!(balance.get_balance("USD"), 210.0);
+        assert_eq!(balance.get_reserved("BTC"), 0.0);
+
+        // Running out of liquidity partway keeps what filled
+        submit(&mut engine, LimitOrder::new(maker, Buy, 1.0, 1.0));
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::PlaceMarketOrder {
+            user_id: taker,
+            side: Sell,
+            quantity: Quantity::from_f64(2.0),
+            slippage: None,
+            received_at: Utc::now(),
+            source: OrderSource::Web,
+            client_order_id: None,
+            response_tx,
+        });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::OrderPlaced { trades, .. } => assert_eq!(trades.len(), 1),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let balance = engine.orderbook.get_user_balance(taker).unwrap();
+        assert_eq!(balance.get_balance("BTC"), 1.0);
+        assert_eq!(balance.get_reserved("BTC"), 0.0);
+    }
+
+    #[tokio::test]
+    async fn advancing_the_clock_fires_what_falls_due() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let (maker, bot) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.orderbook.add_funds(maker, "BTC", 1.0);
+        engine.orderbook.add_funds(bot, "USD", 1_000.0);
+
+        let place = |engine: &mut Engine, user_id, side, price, expires_at| {
+            let order = LimitOrder {
+                expires_at,
+                ..LimitOrder::new(user_id, side, price, 1.0)
+            };
+            submit(engine, order)
+        };
+        let advance = |engine: &mut Engine, secs| {
+            let (response_tx, mut response_rx) = oneshot::channel();
+            engine.process(OrderBookCommand::AdvanceClock {
+                by: Duration::from_secs(secs),
+                response_tx,
+            });
+            match response_rx.try_recv().unwrap() {
+                OrderBookResponse::Clock { now, offset_ms } => (now, offset_ms),
+                other => panic!("unexpected response: {:?}", other),
+            }
+        };
+
+        let expires_at = Utc::now() + chrono::Duration::hours(1);
+        let order_id = match place(&mut engine, maker, Sell, 100.0, Some(expires_at)) {
+            OrderBookResponse::OrderPlaced { order_id, .. } => order_id,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        place(&mut engine, bot, Buy, 99.0, None);
+        engine.process(OrderBookCommand::CancelAllAfter {
+            user_id: bot,
+            timeout: Duration::from_secs(600),
+            response_tx: oneshot::channel().0,
+        });
+
+        // Half an hour on: the switch has lapsed, the order has not expired
+        let (now, offset_ms) = advance(&mut engine, 30 * 60);
+        assert_eq!(offset_ms, 30 * 60 * 1000);
+        assert!(now > Utc::now() + chrono::Duration::minutes(29));
+        assert_eq!(engine.orderbook.open_order_count(bot), 0);
+        assert!(engine.orderbook.get_order(order_id).is_some());
+
+        // Expiry is judged against the advanced clock too
+        let stale = Utc::now() + chrono::Duration::minutes(10);
+        assert!(matches!(
+            place(&mut engine, maker, Sell, 100.0, Some(stale)),
+            OrderBookResponse::Error { .. }
+        ));
+
+        advance(&mut engine, 30 * 60);
+        assert!(engine.orderbook.get_order(order_id).is_none());
+        assert_eq!(
+            engine.order_history.get(order_id).unwrap().status,
+            OrderStatus::Expired
+        );
+        assert_eq!(advance(&mut engine, 0).1, 60 * 60 * 1000);
+    }
+
+    #[test]
+    fn depth_diffs_carry_changed_levels_below_the_top_of_the_book() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let mut events = engine.events.subscribe();
+        let maker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 100.0);
+        let sell = |engine: &mut Engine, price: f64| {
+            order_id(submit(engine, LimitOrder::new(maker, Sell, price, 1.0)))
+        };
+        let mut diffs = || {
+            let mut diffs = Vec::new();
+            while let Ok(message) = events.try_recv() {
+                if let MarketEvent::DepthDiff { prev_seq, asks, .. } = message.event {
+                    diffs.push((message.seq, prev_seq, asks));
+                }
+            }
+            diffs
+        };
+
+        // Fill past the top levels carried by depth events
+        for i in 0..=EVENT_DEPTH_LEVELS {
+            sell(&mut engine, 100.0 + i as f64);
+        }
+        let deepest = 100.0 + EVENT_DEPTH_LEVELS as f64;
+        let order_id = sell(&mut engine, deepest);
+        let placed = diffs();
+        assert_eq!(placed.len(), EVENT_DEPTH_LEVELS + 2);
+        let level = |price: f64, quantity: f64, orders| {
+            DepthLevel::new(Price::from_f64(price), Quantity::from_f64(quantity), orders)
+        };
+        let (last_seq, _, asks) = placed.last().unwrap();
+        assert_eq!(asks, &vec![level(deepest, 2.0, 2)]);
+        assert!(placed.windows(2).all(|pair| pair[1].1 == pair[0].0));
+
+        // A cancel below the top 20 shows in the diff; a no-op does not
+        engine.process(OrderBookCommand::CancelOrder {
+            user_id: maker,
+            order_id,
+            response_tx: oneshot::channel().0,
+        });
+        engine.process(OrderBookCommand::CancelOrder {
+            user_id: maker,
+            order_id,
+            response_tx: oneshot::channel().0,
+        });
+        let cancelled = diffs();
+        assert_eq!(
+            cancelled,
+            vec![(last_seq + 2, *last_seq, vec![level(deepest, 1.0, 1)])]
+        );
+    }
+
+    #[test]
+    fn depth_events_stop_at_the_public_depth() {
+        let config = EngineConfig {
+            public_depth_levels: 2,
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        let mut events = engine.events.subscribe();
+        let maker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 10.0);
+        let level =
+            |price: f64| DepthLevel::new(Price::from_f64(price), Quantity::from_f64(1.0), 1);
+        let mut sell = |price: f64| {
+            submit(&mut engine, LimitOrder::new(maker, Sell, price, 1.0));
+            let mut published = (None, None);
+            while let Ok(message) = events.try_recv() {
+                match message.event {
+                    MarketEvent::Depth { asks, .. } => published.0 = Some(asks),
+                    MarketEvent::DepthDiff { asks, .. } => published.1 = Some(asks),
+                    _ => {}
+                }
+            }
+            published
+        };
+
+        sell(100.0);
+        sell(101.0);
+        // A third level is deeper than anyone may see without a token
+        let (depth, diff) = sell(102.0);
+        assert_eq!(depth, Some(vec![level(100.0), level(101.0)]));
+        assert_eq!(diff, None);
+
+        // A better price pushes 101 out of the public depth, which empties it
+        let (_, diff) = sell(99.0);
+        let emptied = DepthLevel::new(Price::from_f64(101.0), Quantity::new(0), 0);
+        assert_eq!(diff, Some(vec![level(99.0), emptied]));
+    }
+
+    #[test]
+    fn depth_events_and_snapshots_carry_the_rolling_imbalance() {
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let mut events = engine.events.subscribe();
+        let maker = Uuid::new_v4();
+        engine.orderbook.add_funds(maker, "BTC", 10.0);
+        engine.orderbook.add_funds(maker, "USD", 10_000.0);
+        let mut place = |side, price: f64, quantity: f64| {
+            submit(&mut engine, LimitOrder::new(maker, side, price, quantity));
+            let mut published = None;
+            while let Ok(message) = events.try_recv() {
+                if let MarketEvent::Depth { imbalance, .. } = message.event {
+                    published = Some(imbalance);
+                }
+            }
+            published.expect("a depth event")
+        };
+
+        assert_eq!(place(Buy, 99.0, 3.0), None);
+        assert_eq!(place(Sell, 101.0, 1.0), Some(0.5));
+        // A fifth of the way from 0.5 to the new top's 0.8
+        let rolling = place(Buy, 99.0, 6.0).unwrap();
+        assert!((rolling - 0.56).abs() < 1e-9);
+
+        let (response_tx, mut response_rx) = oneshot::channel();
+        engine.process(OrderBookCommand::GetMarketSnapshot {
+            levels: 1,
+            deadline: Instant::now() + Duration::from_secs(5),
+            response_tx,
+        });
+        match response_rx.try_recv().unwrap() {
+            OrderBookResponse::MarketSnapshot { state, .. } => {
+                assert_eq!(state.imbalance, Some(rolling))
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn priority_fees_jump_the_queue_only_where_the_market_takes_them() {
+        let place = |engine: &mut Engine, user_id, side, priority_fee: f64| {
+            let order = LimitOrder {
+                priority_fee,
+                ..LimitOrder::new(user_id, side, 100.0, 1.0)
+            };
+            submit(engine, order)
+        };
+        let (early, late, taker) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        let mut fifo = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        fifo.orderbook.add_funds(late, "BTC", 2.0);
+        fifo.orderbook.add_funds(late, "USD", 10.0);
+        match place(&mut fifo, late, Sell, 1.0) {
+            OrderBookResponse::Error { message } => {
+                assert!(message.contains("does not take priority fees"))
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let config = EngineConfig {
+            market: MarketConfig {
+                allocation: AllocationPolicy::PriorityFee,
+                ..MarketConfig::default()
+            },
+            ..EngineConfig::default()
+        };
+        let mut engine = Engine::new(Arc::new(EngineMetrics::new()), config);
+        for user_id in [early, late] {
+            engine.orderbook.add_funds(user_id, "BTC", 2.0);
+        }
+        engine.orderbook.add_funds(late, "USD", 10.0);
+        engine.orderbook.add_funds(taker, "USD", 300.0);
+
+        place(&mut engine, early, Sell, 0.0);
+        place(&mut engine, late, Sell, 2.5);
+        let trades = match place(&mut engine, taker, Buy, 0.0) {
+            OrderBookResponse::OrderPlaced { trades, .. } => trades,
+            other => panic!("unexpected response: {:?}", other),
+        };
+
+        // The later sell bid for the front of the level and trades first
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].maker_user_id, late);
+        let fees = engine
+            .ledger
+            .balance(&Account::new(AccountOwner::Fees, "USD"));
+        assert_eq!(fees, to_ledger_units(2.5));
+        assert!(engine.ledger.trial_balance().balanced);
+    }
 }
@@ -0,0 +1,279 @@
+use crate::engine::{OrderFill, TapeEntry};
+use crate::orderbook::DepthLevel;
+use crate::types::{ActivityEntry, Order, OrderSide, Price, Quantity};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// How many events a slow subscriber may fall behind before it starts losing them
+pub const DEFAULT_EVENT_BUFFER: usize = 1024;
+
+/// The same for one user's private events
+pub const DEFAULT_USER_EVENT_BUFFER: usize = 256;
+
+/// Levels per side carried in each depth event
+pub const EVENT_DEPTH_LEVELS: usize = 20;
+
+/// Market data pushed by the engine as it happens. Fills, order updates and
+/// balances are private and only forwarded to the user they belong to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MarketEvent {
+    Trade(TapeEntry),
+    /// The top of each side, and the top of book imbalance averaged over its
+    /// recent changes
+    Depth {
+        bids: Vec<DepthLevel>,
+        asks: Vec<DepthLevel>,
+        imbalance: Option<f64>,
+    },
+    /// The levels anywhere in the book that changed since the previous diff,
+    /// as they now stand; a level that emptied has zero quantity and orders.
+    /// `prev_seq` is the previous diff's `seq`, so a missed diff shows.
+    DepthDiff {
+        prev_seq: u64,
+        bids: Vec<DepthLevel>,
+        asks: Vec<DepthLevel>,
+    },
+    /// A synthetic index moved, because one of its components did
+    IndexValue {
+        symbol: String,
+        value: Price,
+    },
+    /// A good-till-date order reached its expiry and left the book
+    OrderExpired {
+        order_id: Uuid,
+        expires_at: DateTime<Utc>,
+    },
+    /// One execution of one order, published in fill_seq order
+    Fill(OrderFill),
+    /// An order's state after the command that accepted, filled, amended,
+    /// cancelled, expired or rejected it
+    Order(Box<Order>),
+    /// A user's balances after a command changed them
+    Balance {
+        user_id: Uuid,
+        balances: HashMap<String, f64>,
+    },
+    /// An entry on a user's audit trail that they should hear about at once,
+    /// such as a withdrawal address starting its cooling-off period
+    Activity {
+        user_id: Uuid,
+        entry: ActivityEntry,
+    },
+}
+
+/// An event as it goes out on the feed, stamped by the exchange. `seq` counts
+/// public messages without gaps, so a jump means the subscriber lagged; a
+/// private event does not take a number of its own and repeats the `seq` of
+/// the last public message sent before it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketMessage {
+    pub seq: u64,
+    pub sent_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: MarketEvent,
+}
+
+impl MarketEvent {
+    /// Whether every subscriber may see this event
+    pub fn is_public(&self) -> bool {
+        self.owner().is_none()
+    }
+
+    /// The user a private event belongs to
+    pub fn owner(&self) -> Option<Uuid> {
+        match self {
+            MarketEvent::Fill(fill) => Some(fill.user_id),
+            MarketEvent::Order(order) => Some(order.user_id),
+            MarketEvent::Balance { user_id, .. } | MarketEvent::Activity { user_id, .. } => {
+                Some(*user_id)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The part of the book a depth subscriber follows: at most `top` levels per
+/// side, best first, priced within `min_price..=max_price`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DepthFilter {
+    pub top: Option<usize>,
+    pub min_price: Option<Price>,
+    pub max_price: Option<Price>,
+}
+
+impl DepthFilter {
+    /// The levels of one side that pass the filter
+    pub fn apply(&self, levels: Vec<DepthLevel>) -> Vec<DepthLevel> {
+        levels
+            .into_iter()
+            .filter(|level| {
+                self.min_price.is_none_or(|min| level.price >= min)
+                    && self.max_price.is_none_or(|max| level.price <= max)
+            })
+            .take(self.top.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+/// The levels of one side that differ from `before` to `after`, best first.
+/// A level missing from `after` comes back emptied.
+pub fn level_changes(
+    side: OrderSide,
+    before: &[DepthLevel],
+    after: &[DepthLevel],
+) -> Vec<DepthLevel> {
+    let previous: HashMap<Price, &DepthLevel> =
+        before.iter().map(|level| (level.price, level)).collect();
+    let current: HashSet<Price> = after.iter().map(|level| level.price).collect();
+
+    let mut changes: Vec<DepthLevel> = after
+        .iter()
+        .filter(|level| previous.get(&level.price) != Some(level))
+        .copied()
+        .chain(
+            before
+                .iter()
+                .filter(|level| !current.contains(&level.price))
+                .map(|level| DepthLevel::new(level.price, Quantity::new(0), 0)),
+        )
+        .collect();
+    match side {
+        OrderSide::Buy => changes.sort_by_key(|level| Reverse(level.price)),
+        OrderSide::Sell => changes.sort_by_key(|level| level.price),
+    }
+    changes
+}
+
+/// The channels engine events go out on. Public events share one broadcast
+/// every market subscriber reads; a private event goes only to its owner's
+/// channel.
+#[derive(Debug, Clone)]
+pub struct EventChannels {
+    market: broadcast::Sender<MarketMessage>,
+    users: Arc<Mutex<HashMap<Uuid, broadcast::Sender<MarketMessage>>>>,
+    last_seq: Arc<AtomicU64>, // Of the last public message sent
+}
+
+impl EventChannels {
+    /// Public market data
+    pub fn subscribe(&self) -> broadcast::Receiver<MarketMessage> {
+        self.market.subscribe()
+    }
+
+    /// The private events of one user
+    pub fn subscribe_user(&self, user_id: Uuid) -> broadcast::Receiver<MarketMessage> {
+        self.users()
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(DEFAULT_USER_EVENT_BUFFER).0)
+            .subscribe()
+    }
+
+    /// How many subscribers follow the public feed
+    pub fn receiver_count(&self) -> usize {
+        self.market.receiver_count()
+    }
+
+    /// Whether anyone follows private events
+    pub fn has_account_subscribers(&self) -> bool {
+        !self.users().is_empty()
+    }
+
+    /// Send `message` to whoever may see it. A user channel whose last
+    /// subscriber has gone is dropped.
+    pub fn send(&self, message: MarketMessage) {
+        let Some(owner) = message.event.owner() else {
+            self.last_seq.store(message.seq, Ordering::Relaxed);
+            // Sending fails only when nobody is subscribed
+            let _ = self.market.send(message);
+            return;
+        };
+        let mut users = self.users();
+        if let Some(channel) = users.get(&owner) {
+            if channel.send(message).is_err() {
+                users.remove(&owner);
+            }
+        }
+    }
+
+    /// Send a private event from outside the engine, stamped now with the
+    /// `seq` of the last public message
+    pub fn notify(&self, event: MarketEvent) {
+        self.send(MarketMessage {
+            seq: self.last_seq.load(Ordering::Relaxed),
+            sent_at: Utc::now(),
+            event,
+        });
+    }
+
+    fn users(&self) -> MutexGuard<'_, HashMap<Uuid, broadcast::Sender<MarketMessage>>> {
+        // A panic while holding the lock cannot leave the map half-updated
+        self.users
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Create the channels engine events are published on
+pub fn event_channel() -> EventChannels {
+    EventChannels {
+        market: broadcast::channel(DEFAULT_EVENT_BUFFER).0,
+        users: Arc::new(Mutex::new(HashMap::new())),
+        last_seq: Arc::new(AtomicU64::new(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_depth_filter_keeps_window_then_top_levels() {
+        let level =
+            |price: f64| DepthLevel::new(Price::from_f64(price), Quantity::from_f64(1.0), 1);
+        let asks = vec![level(100.0), level(101.0), level(102.0), level(103.0)];
+
+        assert_eq!(DepthFilter::default().apply(asks.clone()), asks);
+        let filter = DepthFilter {
+            top: Some(2),
+            min_price: Some(Price::from_f64(101.0)),
+            max_price: None,
+        };
+        assert_eq!(filter.apply(asks.clone()), vec![level(101.0), level(102.0)]);
+        let filter = DepthFilter {
+            max_price: Some(Price::from_f64(100.5)),
+            ..DepthFilter::default()
+        };
+        assert_eq!(filter.apply(asks), vec![level(100.0)]);
+    }
+
+    #[test]
+    fn test_level_changes_carry_new_and_emptied_levels() {
+        let level = |price: f64, quantity: f64| {
+            DepthLevel::new(Price::from_f64(price), Quantity::from_f64(quantity), 1)
+        };
+        let before = vec![level(99.0, 1.0), level(98.0, 2.0), level(97.0, 1.0)];
+        let after = vec![level(99.5, 1.0), level(99.0, 1.0), level(98.0, 0.5)];
+
+        let emptied = DepthLevel::new(Price::from_f64(97.0), Quantity::new(0), 0);
+        assert_eq!(
+            level_changes(OrderSide::Buy, &before, &after),
+            vec![level(99.5, 1.0), level(98.0, 0.5), emptied]
+        );
+        assert!(level_changes(OrderSide::Buy, &after, &after).is_empty());
+
+        // Asks run the other way, still best first
+        let changes = level_changes(
+            OrderSide::Sell,
+            &[],
+            &[level(101.0, 1.0), level(100.0, 1.0)],
+        );
+        assert_eq!(changes, vec![level(100.0, 1.0), level(101.0, 1.0)]);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::types::{OrderSide, Price, PriceImprovement, Trade};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Signed improvement of `fill` over `reference` for `quantity` units, from the taker's view
+fn improvement(side: OrderSide, reference: Price, fill: Price, quantity: f64) -> f64 {
+    let per_unit = match side {
+        OrderSide::Buy => reference.to_f64() - fill.to_f64(),
+        OrderSide::Sell => fill.to_f64() - reference.to_f64(),
+    };
+    per_unit * quantity
+}
+
+/// Fill in `price_improvement` on each trade of a taker order
+pub fn annotate_price_improvement(
+    trades: &mut [Trade],
+    taker_side: OrderSide,
+    limit_price: Option<Price>,
+    arrival_bbo: Option<Price>,
+) {
+    for trade in trades.iter_mut() {
+        let quantity = trade.quantity.to_f64();
+        trade.price_improvement = PriceImprovement {
+            vs_limit: limit_price.map(|p| improvement(taker_side, p, trade.price, quantity)),
+            vs_arrival_bbo: arrival_bbo.map(|p| improvement(taker_side, p, trade.price, quantity)),
+        };
+    }
+}
+
+/// Per-user totals of price improvement received as a taker
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct UserExecutionQuality {
+    pub taker_trades: u64,
+    pub taker_notional: f64,
+    pub total_improvement_vs_limit: f64,
+    pub total_improvement_vs_arrival_bbo: f64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExecutionQualityTracker {
+    per_user: HashMap<Uuid, UserExecutionQuality>,
+}
+
+impl ExecutionQualityTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, trades: &[Trade]) {
+        for trade in trades {
+            let stats = self.per_user.entry(trade.taker_user_id).or_default();
+            stats.taker_trades += 1;
+            stats.taker_notional += trade.price.to_f64() * trade.quantity.to_f64();
+            stats.total_improvement_vs_limit += trade.price_improvement.vs_limit.unwrap_or(0.0);
+            stats.total_improvement_vs_arrival_bbo +=
+                trade.price_improvement.vs_arrival_bbo.unwrap_or(0.0);
+        }
+    }
+
+    pub fn for_user(&self, user_id: Uuid) -> UserExecutionQuality {
+        self.per_user.get(&user_id).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Quantity;
+
+    fn trade(taker: Uuid, price: f64, qty: f64) -> Trade {
+        Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            taker,
+            Price::from_f64(price),
+            Quantity::from_f64(qty),
+        )
+    }
+
+    #[test]
+    fn test_buy_improvement_against_limit_and_bbo() {
+        let taker = Uuid::new_v4();
+        let mut trades = vec![trade(taker, 100.0, 1.0), trade(taker, 102.0, 2.0)];
+
+        annotate_price_improvement(
+            &mut trades,
+            OrderSide::Buy,
+            Some(Price::from_f64(105.0)),
+            Some(Price::from_f64(100.0)),
+        );
+
+        assert_eq!(trades[0].price_improvement.vs_limit, Some(5.0));
+        assert_eq!(trades[0].price_improvement.vs_arrival_bbo, Some(0.0));
+        assert_eq!(trades[1].price_improvement.vs_limit, Some(6.0));
+        assert_eq!(trades[1].price_improvement.vs_arrival_bbo, Some(-4.0));
+
+        let mut tracker = ExecutionQualityTracker::new();
+        tracker.record(&trades);
+        let stats = tracker.for_user(taker);
+        assert_eq!(stats.taker_trades, 2);
+        assert_eq!(stats.taker_notional, 304.0);
+        assert_eq!(stats.total_improvement_vs_limit, 11.0);
+        assert_eq!(stats.total_improvement_vs_arrival_bbo, -4.0);
+    }
+
+    #[test]
+    fn test_sell_market_order_has_no_limit_reference() {
+        let mut trades = vec![trade(Uuid::new_v4(), 99.0, 1.0)];
+        annotate_price_improvement(&mut trades, OrderSide::Sell, None, Some(Price::from_f64(100.0)));
+
+        assert_eq!(trades[0].price_improvement.vs_limit, None);
+        assert_eq!(trades[0].price_improvement.vs_arrival_bbo, Some(-1.0));
+    }
+}
@@ -0,0 +1,140 @@
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// Expiry times of good-till-date orders, soonest first.
+///
+/// The engine unschedules an order when it leaves the book some other way
+/// (filled or cancelled), so the schedule only holds resting orders.
+#[derive(Debug, Default)]
+pub struct ExpirySchedule {
+    due: BTreeMap<DateTime<Utc>, Vec<Uuid>>,
+    by_order: HashMap<Uuid, DateTime<Utc>>,
+    len: usize,
+}
+
+impl ExpirySchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Expire `order_id` at `expires_at`, in place of any earlier entry for it
+    pub fn schedule(&mut self, order_id: Uuid, expires_at: DateTime<Utc>) {
+        self.unschedule(order_id);
+        self.due.entry(expires_at).or_default().push(order_id);
+        self.by_order.insert(order_id, expires_at);
+        self.len += 1;
+    }
+
+    /// Drop the entry for an order that left the book before expiring.
+    /// Returns whether it had one.
+    pub fn unschedule(&mut self, order_id: Uuid) -> bool {
+        let Some(expires_at) = self.by_order.remove(&order_id) else {
+            return false;
+        };
+        if let Some(ids) = self.due.get_mut(&expires_at) {
+            ids.retain(|id| *id != order_id);
+            if ids.is_empty() {
+                self.due.remove(&expires_at);
+            }
+        }
+        self.len -= 1;
+        true
+    }
+
+    /// When the next entry comes due, if any
+    pub fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.due.keys().next().copied()
+    }
+
+    /// Remove and return every entry due at or before `now`, earliest first,
+    /// in scheduling order within the same instant
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> Vec<(Uuid, DateTime<Utc>)> {
+        let later = match now.checked_add_signed(chrono::Duration::nanoseconds(1)) {
+            Some(after_now) => self.due.split_off(&after_now),
+            None => BTreeMap::new(),
+        };
+        let due = std::mem::replace(&mut self.due, later);
+
+        let expired: Vec<(Uuid, DateTime<Utc>)> = due
+            .into_iter()
+            .flat_map(|(at, ids)| ids.into_iter().map(move |id| (id, at)))
+            .collect();
+        for (order_id, _) in &expired {
+            self.by_order.remove(order_id);
+        }
+        self.len -= expired.len();
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_take_due_in_expiry_order() {
+        let mut schedule = ExpirySchedule::new();
+        let now = Utc::now();
+        let (first, second, tied, later) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        schedule.schedule(second, now);
+        schedule.schedule(later, now + Duration::seconds(10));
+        schedule.schedule(first, now - Duration::seconds(1));
+        schedule.schedule(tied, now);
+        assert_eq!(schedule.next_due(), Some(now - Duration::seconds(1)));
+
+        let due: Vec<Uuid> = schedule
+            .take_due(now)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(due, vec![first, second, tied]);
+        assert_eq!(schedule.len(), 1);
+        assert!(schedule.take_due(now).is_empty());
+
+        assert_eq!(schedule.take_due(now + Duration::seconds(10)).len(), 1);
+        assert!(schedule.is_empty());
+    }
+
+    #[test]
+    fn test_unscheduled_orders_never_come_due() {
+        let mut schedule = ExpirySchedule::new();
+        let now = Utc::now();
+        let (filled, resting) = (Uuid::new_v4(), Uuid::new_v4());
+        schedule.schedule(filled, now);
+        schedule.schedule(resting, now);
+
+        assert!(schedule.unschedule(filled));
+        assert!(!schedule.unschedule(filled));
+        assert_eq!(schedule.len(), 1);
+        let due: Vec<Uuid> = schedule
+            .take_due(now)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(due, vec![resting]);
+
+        // Rescheduling moves the entry rather than adding another
+        schedule.schedule(filled, now);
+        schedule.schedule(filled, now + Duration::seconds(5));
+        assert_eq!(schedule.len(), 1);
+        assert_eq!(schedule.next_due(), Some(now + Duration::seconds(5)));
+        assert!(schedule.unschedule(filled));
+        assert!(schedule.is_empty());
+        assert_eq!(schedule.next_due(), None);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::orderbook::DepthLevel;
+use crate::types::{Price, Quantity};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Length of one feed activity interval
+pub const FEED_INTERVAL_SECS: i64 = 60;
+/// Intervals kept, so an hour of history
+pub const FEED_INTERVALS_KEPT: usize = 60;
+/// Weight the newest top of book gets in the rolling top-of-book imbalance
+pub const IMBALANCE_SMOOTHING: f64 = 0.2;
+
+/// Best bid and ask with their displayed quantity; a change to any of them
+/// is a top-of-book change
+pub type TopOfBook = (Option<(Price, Quantity)>, Option<(Price, Quantity)>);
+
+/// The top of the book from its first bid and ask levels
+pub fn top_of_book(bids: &[DepthLevel], asks: &[DepthLevel]) -> TopOfBook {
+    let top = |levels: &[DepthLevel]| levels.first().map(|level| (level.price, level.quantity));
+    (top(bids), top(asks))
+}
+
+/// Bid size less ask size at the top, over both: 1 when only bids are shown.
+/// None unless both sides are.
+pub fn top_imbalance(top: &TopOfBook) -> Option<f64> {
+    let (Some((_, bid)), Some((_, ask))) = top else {
+        return None;
+    };
+    let (bid, ask) = (bid.to_f64(), ask.to_f64());
+    (bid + ask > 0.0).then(|| (bid - ask) / (bid + ask))
+}
+
+/// What the market published during one interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeedInterval {
+    pub start: DateTime<Utc>,
+    pub bbo_changes: u64,
+    pub trades: u64,
+    pub order_events: u64,
+}
+
+impl FeedInterval {
+    fn empty(start: DateTime<Utc>) -> Self {
+        FeedInterval {
+            start,
+            bbo_changes: 0,
+            trades: 0,
+            order_events: 0,
+        }
+    }
+}
+
+/// Recent per-interval activity of one market, newest interval first. Quiet
+/// intervals are listed with zero counts, so a dead feed shows as a run of
+/// zeros rather than a gap.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeedActivity {
+    pub market: String,
+    pub interval_secs: i64,
+    pub intervals: Vec<FeedInterval>,
+    pub last_bbo_change_at: Option<DateTime<Utc>>,
+    pub last_trade_at: Option<DateTime<Utc>>,
+    pub last_order_event_at: Option<DateTime<Utc>>,
+}
+
+/// Counts top-of-book changes, trades and order events per interval, for
+/// alerting on a feed that has stopped or is spiking. It also keeps the top
+/// of book imbalance averaged over its recent changes, so every feed shows
+/// the same one.
+#[derive(Debug, Default)]
+pub struct FeedActivityTracker {
+    intervals: VecDeque<FeedInterval>, // Oldest first; only intervals that saw activity
+    top: TopOfBook,
+    rolling_imbalance: Option<f64>,
+    last_bbo_change_at: Option<DateTime<Utc>>,
+    last_trade_at: Option<DateTime<Utc>>,
+    last_order_event_at: Option<DateTime<Utc>>,
+}
+
+impl FeedActivityTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the top of the book after a command; returns whether it changed
+    pub fn record_top(&mut self, top: TopOfBook, now: DateTime<Utc>) -> bool {
+        if top == self.top {
+            return false;
+        }
+        self.top = top;
+        // A one-sided book has no imbalance; the average starts over after it
+        self.rolling_imbalance = match (top_imbalance(&top), self.rolling_imbalance) {
+            (Some(latest), Some(rolling)) => {
+                Some(rolling + IMBALANCE_SMOOTHING * (latest - rolling))
+            }
+            (latest, _) => latest,
+        };
+        self.current(now).bbo_changes += 1;
+        self.last_bbo_change_at = Some(now);
+        true
+    }
+
+    /// Top of book imbalance, averaged over the changes recorded so far
+    pub fn rolling_imbalance(&self) -> Option<f64> {
+        self.rolling_imbalance
+    }
+
+    pub fn record_trade(&mut self, now: DateTime<Utc>) {
+        self.current(now).trades += 1;
+        self.last_trade_at = Some(now);
+    }
+
+    pub fn record_order_events(&mut self, count: usize, now: DateTime<Utc>) {
+        self.current(now).order_events += count as u64;
+        self.last_order_event_at = Some(now);
+    }
+
+    fn current(&mut self, now: DateTime<Utc>) -> &mut FeedInterval {
+        let start = interval_start(now);
+        if self.intervals.back().is_none_or(|last| last.start < start) {
+            self.intervals.push_back(FeedInterval::empty(start));
+            if self.intervals.len() > FEED_INTERVALS_KEPT {
+                self.intervals.pop_front();
+            }
+        }
+        self.intervals
+            .back_mut()
+            .expect("an interval was just pushed")
+    }
+
+    /// The last `count` intervals up to the one holding `now`, capped at the
+    /// intervals kept
+    pub fn snapshot(&self, market: &str, now: DateTime<Utc>, count: usize) -> FeedActivity {
+        let current = interval_start(now);
+        let intervals = (0..count.min(FEED_INTERVALS_KEPT) as i64)
+            .map(|back| current - Duration::seconds(back * FEED_INTERVAL_SECS))
+            .map(|start| {
+                self.intervals
+                    .iter()
+                    .find(|interval| interval.start == start)
+                    .copied()
+                    .unwrap_or_else(|| FeedInterval::empty(start))
+            })
+            .collect();
+        FeedActivity {
+            market: market.to_string(),
+            interval_secs: FEED_INTERVAL_SECS,
+            intervals,
+            last_bbo_change_at: self.last_bbo_change_at,
+            last_trade_at: self.last_trade_at,
+            last_order_event_at: self.last_order_event_at,
+        }
+    }
+}
+
+fn interval_start(at: DateTime<Utc>) -> DateTime<Utc> {
+    at.duration_trunc(Duration::seconds(FEED_INTERVAL_SECS))
+        .unwrap_or(at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_counts_land_in_their_interval_and_quiet_ones_show_zero() {
+        let mut tracker = FeedActivityTracker::new();
+        let start = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let bid = Some((Price::from_f64(100.0), Quantity::from_f64(1.0)));
+
+        assert!(tracker.record_top((bid, None), start));
+        assert!(!tracker.record_top((bid, None), start + Duration::seconds(5)));
+        tracker.record_trade(start + Duration::seconds(10));
+        tracker.record_order_events(1, start + Duration::seconds(10));
+        tracker.record_order_events(1, start + Duration::seconds(130));
+
+        let now = start + Duration::seconds(150);
+        let activity = tracker.snapshot("BTC-USD", now, 4);
+        let counts: Vec<(u64, u64, u64)> = activity
+            .intervals
+            .iter()
+            .map(|i| (i.bbo_changes, i.trades, i.order_events))
+            .collect();
+        assert_eq!(counts, vec![(0, 0, 1), (0, 0, 0), (1, 1, 1), (0, 0, 0)]);
+        assert_eq!(activity.intervals[0].start, start + Duration::seconds(120));
+        assert_eq!(activity.last_bbo_change_at, Some(start));
+        assert_eq!(
+            activity.last_order_event_at,
+            Some(start + Duration::seconds(130))
+        );
+        assert_eq!(tracker.snapshot("BTC-USD", now, 1_000).intervals.len(), 60);
+    }
+
+    #[test]
+    fn test_rolling_imbalance_follows_top_of_book_changes() {
+        let mut tracker = FeedActivityTracker::new();
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        let level = |price: f64, quantity: f64| {
+            Some((Price::from_f64(price), Quantity::from_f64(quantity)))
+        };
+
+        tracker.record_top((level(99.0, 3.0), None), now);
+        assert_eq!(tracker.rolling_imbalance(), None);
+        tracker.record_top((level(99.0, 3.0), level(101.0, 1.0)), now);
+        assert_eq!(tracker.rolling_imbalance(), Some(0.5));
+
+        // Halfway to -0.5 would be 0; the average moves a fifth of the way
+        tracker.record_top((level(99.0, 1.0), level(101.0, 3.0)), now);
+        let rolling = tracker.rolling_imbalance().unwrap();
+        assert!((rolling - 0.3).abs() < 1e-9);
+        // An unchanged top of book is not another sample
+        tracker.record_top((level(99.0, 1.0), level(101.0, 3.0)), now);
+        assert_eq!(tracker.rolling_imbalance(), Some(rolling));
+
+        tracker.record_top((None, level(101.0, 3.0)), now);
+        assert_eq!(tracker.rolling_imbalance(), None);
+        tracker.record_top((level(99.0, 1.0), level(101.0, 3.0)), now);
+        assert_eq!(tracker.rolling_imbalance(), Some(-0.5));
+    }
+}
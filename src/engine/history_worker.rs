@@ -0,0 +1,195 @@
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::engine::{EngineMetrics, OutboxRelay};
+use crate::storage::Store;
+
+type Job = Box<dyn FnOnce(&mut dyn Store) + Send>;
+
+/// How often the outbox relay gets a turn between jobs
+const RELAY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tries a history write gets, and the pause before the first retry, doubled
+/// for each one after it
+const WRITE_ATTEMPTS: u32 = 4;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Owns the engine's store on a thread of its own. The engine hands over
+/// history writes and reads without waiting on them, so storage I/O and record
+/// encoding never hold up matching. Jobs run in the order they were handed
+/// over, so a read sees every write queued before it. An outbox relay, when
+/// given one, runs here too so it reads the same store the trades went into.
+pub struct HistoryWorker {
+    jobs: Option<mpsc::Sender<Job>>,
+    thread: Option<JoinHandle<()>>,
+    metrics: Arc<EngineMetrics>,
+}
+
+impl HistoryWorker {
+    pub fn spawn(
+        mut store: Box<dyn Store>,
+        mut relay: Option<OutboxRelay>,
+        metrics: Arc<EngineMetrics>,
+    ) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        let worker_metrics = metrics.clone();
+        let thread = thread::Builder::new()
+            .name("history-worker".to_string())
+            .spawn(move || {
+                let mut last_relay_step = Instant::now();
+                loop {
+                    match rx.recv_timeout(RELAY_INTERVAL) {
+                        Ok(job) => {
+                            let started = Instant::now();
+                            job(store.as_mut());
+                            worker_metrics.record_history_job_done(started.elapsed());
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                    if let Some(relay) = relay.as_mut() {
+                        if last_relay_step.elapsed() >= RELAY_INTERVAL {
+                            relay.step(store.as_mut());
+                            last_relay_step = Instant::now();
+                        }
+                    }
+                }
+            })
+            .expect("failed to spawn the history worker thread");
+
+        HistoryWorker {
+            jobs: Some(jobs),
+            thread: Some(thread),
+            metrics,
+        }
+    }
+
+    /// Queue `job` behind everything handed over before it
+    pub fn submit(&self, job: impl FnOnce(&mut dyn Store) + Send + 'static) {
+        let started = Instant::now();
+        let sent = self
+            .jobs
+            .as_ref()
+            .is_some_and(|jobs| jobs.send(Box::new(job)).is_ok());
+        if !sent {
+            eprintln!("History worker has stopped; dropping a history job");
+        }
+        self.metrics.record_history_handoff(started.elapsed());
+    }
+
+    /// Run `query` once everything queued so far is done and wait for its
+    /// result. The engine never calls this; it is for tests and tools.
+    pub fn query<T: Send + 'static>(
+        &self,
+        query: impl FnOnce(&mut dyn Store) -> T + Send + 'static,
+    ) -> T {
+        let (result_tx, result_rx) = mpsc::sync_channel(1);
+        self.submit(move |store| {
+            let _ = result_tx.send(query(store));
+        });
+        result_rx
+            .recv()
+            .expect("history worker stopped before answering")
+    }
+}
+
+/// Run `write` until it succeeds or has failed `WRITE_ATTEMPTS` times, pausing
+/// the worker between tries so a briefly unavailable store can come back.
+/// The writes are transactional, so a failed try leaves nothing behind. A
+/// write given up on is counted and logged; false then.
+pub fn write_with_retries(
+    metrics: &EngineMetrics,
+    what: &str,
+    mut write: impl FnMut() -> Result<(), String>,
+) -> bool {
+    let mut delay = WRITE_RETRY_DELAY;
+    for attempt in 1..=WRITE_ATTEMPTS {
+        match write() {
+            Ok(()) => return true,
+            Err(e) if attempt == WRITE_ATTEMPTS => {
+                eprintln!(
+                    "Failed to persist {} after {} attempts: {}",
+                    what, attempt, e
+                );
+            }
+            Err(_) => {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+    metrics.record_history_write_failure();
+    false
+}
+
+impl Drop for HistoryWorker {
+    /// Finish the queued writes before the store goes away
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("History worker panicked; queued history may be lost");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{HistoryQuery, MemoryStore};
+    use crate::types::{Price, Quantity, Trade};
+    use std::sync::atomic::Ordering;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_jobs_run_in_order_off_the_calling_thread() {
+        let metrics = Arc::new(EngineMetrics::new());
+        let worker = HistoryWorker::spawn(Box::new(MemoryStore::new()), None, metrics.clone());
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        let trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            maker,
+            taker,
+            Price::from_f64(100.0),
+            Quantity::from_f64(1.0),
+        );
+        worker.submit(move |store| store.save_trades(&[trade]).unwrap());
+
+        let caller = thread::current().id();
+        let (ran_on, trades) = worker.query(move |store| {
+            let trades = store.trades_for_user(maker, &HistoryQuery::latest(10));
+            (thread::current().id(), trades.unwrap().len())
+        });
+        assert_ne!(ran_on, caller);
+        assert_eq!(trades, 1);
+
+        // Dropping the worker waits for it to finish
+        drop(worker);
+        assert_eq!(metrics.history_jobs_queued.load(Ordering::Relaxed), 2);
+        assert_eq!(metrics.history_jobs_done.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_failed_writes_are_retried_then_counted() {
+        let metrics = EngineMetrics::new();
+        let mut tries = 0;
+        let flaky = write_with_retries(&metrics, "trades", || {
+            tries += 1;
+            if tries < 3 {
+                Err("database is locked".to_string())
+            } else {
+                Ok(())
+            }
+        });
+        assert!(flaky);
+        assert_eq!(tries, 3);
+        assert_eq!(metrics.history_write_failures.load(Ordering::Relaxed), 0);
+
+        let down = write_with_retries(&metrics, "trades", || Err("gone".to_string()));
+        assert!(!down);
+        assert_eq!(metrics.history_write_failures.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::orderbook::{BalanceOperation, BalanceViolation};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentStatus {
+    Open,
+    Resolved,
+}
+
+/// A refused balance change awaiting operator review
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Incident {
+    pub id: u64,
+    pub opened_at: DateTime<Utc>,
+    pub user_id: Uuid,
+    pub currency: String,
+    pub balance: f64, // Before the refused change
+    pub change: f64,
+    pub operation: BalanceOperation,
+    #[serde(default)]
+    pub reserved: bool, // Against the part set aside for open orders
+    pub status: IncidentStatus,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub resolution: Option<String>, // The operator's note
+}
+
+/// Incidents raised by the balance guard, and the accounts quarantined by
+/// them. An account stays quarantined until every incident against it is
+/// resolved.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct IncidentLog {
+    incidents: Vec<Incident>, // In id order
+    quarantined: HashSet<Uuid>,
+}
+
+impl IncidentLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `violation` and quarantine the account it happened on
+    pub fn open(&mut self, violation: BalanceViolation, now: DateTime<Utc>) -> &Incident {
+        self.quarantined.insert(violation.user_id);
+        self.incidents.push(Incident {
+            id: self.incidents.len() as u64 + 1,
+            opened_at: now,
+            user_id: violation.user_id,
+            currency: violation.currency,
+            balance: violation.balance,
+            change: violation.change,
+            operation: violation.operation,
+            reserved: violation.reserved,
+            status: IncidentStatus::Open,
+            resolved_at: None,
+            resolution: None,
+        });
+        self.incidents.last().unwrap()
+    }
+
+    pub fn is_quarantined(&self, user_id: Uuid) -> bool {
+        self.quarantined.contains(&user_id)
+    }
+
+    /// Newest first, optionally only those still open
+    pub fn list(&self, open_only: bool) -> Vec<Incident> {
+        self.incidents
+            .iter()
+            .rev()
+            .filter(|incident| !open_only || incident.status == IncidentStatus::Open)
+            .cloned()
+            .collect()
+    }
+
+    /// Close an incident with the operator's note. Lifts the quarantine once
+    /// the account has no open incidents left.
+    pub fn resolve(
+        &mut self,
+        id: u64,
+        note: String,
+        now: DateTime<Utc>,
+    ) -> Result<Incident, String> {
+        let incident = self
+            .incidents
+            .iter_mut()
+            .find(|incident| incident.id == id)
+            .ok_or_else(|| format!("Incident {} not found", id))?;
+        if incident.status == IncidentStatus::Resolved {
+            return Err(format!("Incident {} is already resolved", id));
+        }
+        incident.status = IncidentStatus::Resolved;
+        incident.resolved_at = Some(now);
+        incident.resolution = Some(note);
+        let resolved = incident.clone();
+
+        let still_open = self.incidents.iter().any(|incident| {
+            incident.user_id == resolved.user_id && incident.status == IncidentStatus::Open
+        });
+        if !still_open {
+            self.quarantined.remove(&resolved.user_id);
+        }
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violation(user_id: Uuid) -> BalanceViolation {
+        BalanceViolation {
+            user_id,
+            currency: "USD".to_string(),
+            balance: 10.0,
+            change: -25.0,
+            operation: BalanceOperation::Settlement,
+            reserved: false,
+        }
+    }
+
+    #[test]
+    fn test_quarantine_lasts_until_every_incident_is_resolved() {
+        let mut log = IncidentLog::new();
+        let user = Uuid::new_v4();
+        let now = Utc::now();
+
+        let first = log.open(violation(user), now).id;
+        let second = log.open(violation(user), now).id;
+        assert!(log.is_quarantined(user));
+        assert_eq!(log.list(true).len(), 2);
+
+        log.resolve(first, "reviewed".to_string(), now).unwrap();
+        assert!(log.is_quarantined(user));
+        assert!(log.resolve(first, "again".to_string(), now).is_err());
+
+        let resolved = log.resolve(second, "reviewed".to_string(), now).unwrap();
+        assert_eq!(resolved.status, IncidentStatus::Resolved);
+        assert!(!log.is_quarantined(user));
+        assert!(log.list(true).is_empty());
+        assert_eq!(log.list(false).len(), 2);
+    }
+}
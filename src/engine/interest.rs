@@ -0,0 +1,320 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Days per year used to turn an annual rate into a daily one
+pub const DAYS_PER_YEAR: f64 = 365.0;
+
+/// One bracket of a rate curve: the part of a balance up to `up_to`
+/// (None = no upper bound) earns `apr`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RateTier {
+    pub up_to: Option<f64>,
+    pub apr: f64,
+}
+
+/// Marginal interest brackets for one currency, ordered by `up_to`. Balance
+/// above the last bounded bracket earns nothing unless an unbounded one ends
+/// the curve.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateCurve {
+    pub tiers: Vec<RateTier>,
+}
+
+impl RateCurve {
+    /// Interest one day earns on `balance`
+    pub fn daily_interest(&self, balance: f64) -> f64 {
+        let mut interest = 0.0;
+        let mut floor = 0.0;
+        for tier in &self.tiers {
+            if balance <= floor {
+                break;
+            }
+            let ceiling = tier.up_to.unwrap_or(f64::INFINITY);
+            interest += (balance.min(ceiling) - floor) * tier.apr / DAYS_PER_YEAR;
+            floor = ceiling;
+        }
+        interest
+    }
+
+    /// Blended annual rate `balance` earns across the brackets
+    pub fn effective_apr(&self, balance: f64) -> f64 {
+        if balance <= 0.0 {
+            return self.tiers.first().map_or(0.0, |tier| tier.apr);
+        }
+        self.daily_interest(balance) * DAYS_PER_YEAR / balance
+    }
+}
+
+/// Annual yield of `apr` compounded daily
+pub fn apy(apr: f64) -> f64 {
+    (1.0 + apr / DAYS_PER_YEAR).powf(DAYS_PER_YEAR) - 1.0
+}
+
+/// Parses `"1000:0.05,10000:0.03,*:0.01"`: brackets as `up_to:apr`, `*` for unbounded
+impl FromStr for RateCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tiers = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (up_to, apr) = part
+                .split_once(':')
+                .ok_or_else(|| format!("Expected 'up_to:apr', got '{}'", part))?;
+            let up_to = match up_to.trim() {
+                "*" => None,
+                bound => Some(
+                    bound
+                        .parse::<f64>()
+                        .map_err(|_| format!("Invalid bracket bound '{}'", bound))?,
+                ),
+            };
+            let apr: f64 = apr
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid rate '{}'", apr))?;
+            if !apr.is_finite() || apr < 0.0 {
+                return Err(format!("Rate must be non-negative, got {}", apr));
+            }
+            tiers.push(RateTier { up_to, apr });
+        }
+
+        let bounds: Vec<f64> = tiers
+            .iter()
+            .map(|t| t.up_to.unwrap_or(f64::INFINITY))
+            .collect();
+        if bounds.windows(2).any(|w| w[0] >= w[1]) || bounds.first().is_some_and(|b| *b <= 0.0) {
+            return Err("Bracket bounds must be positive and increasing".to_string());
+        }
+        Ok(RateCurve { tiers })
+    }
+}
+
+/// What one currency earns for a user, as reported by the API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CurrencyInterest {
+    pub currency: String,
+    pub balance: f64,
+    pub apr: f64, // Blended across brackets at the current balance
+    pub apy: f64,
+    pub accrued_total: f64,
+    pub last_accrual: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InterestSummary {
+    pub user_id: Uuid,
+    pub opted_in: bool,
+    pub last_accrual_date: Option<NaiveDate>,
+    pub currencies: Vec<CurrencyInterest>,
+}
+
+/// A day's interest credited to one user's balance
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accrual {
+    pub user_id: Uuid,
+    pub currency: String,
+    pub amount: f64,
+}
+
+#[derive(Debug, Default)]
+struct Earned {
+    total: f64,
+    last: Option<f64>,
+}
+
+/// Who accrues interest and what they have earned so far, as saved in an
+/// engine snapshot. The rate curves are configuration and are not saved.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InterestState {
+    pub opted_in: Vec<Uuid>,
+    pub earned: Vec<EarnedInterest>,
+    pub accrued_through: NaiveDate,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EarnedInterest {
+    pub user_id: Uuid,
+    pub currency: String,
+    pub total: f64,
+    pub last: Option<f64>,
+}
+
+/// Daily interest on idle balances for users who opted in. Accrual runs once
+/// per finished day; the engine applies the returned credits.
+#[derive(Debug)]
+pub struct InterestAccrual {
+    curves: BTreeMap<String, RateCurve>,
+    opted_in: HashSet<Uuid>,
+    earned: HashMap<(Uuid, String), Earned>,
+    accrued_through: NaiveDate,
+}
+
+impl InterestAccrual {
+    /// Start accruing from `today`; nothing is owed for days before it
+    pub fn new(curves: BTreeMap<String, RateCurve>, today: NaiveDate) -> Self {
+        InterestAccrual {
+            curves,
+            opted_in: HashSet::new(),
+            earned: HashMap::new(),
+            accrued_through: today,
+        }
+    }
+
+    /// Whether any currency pays interest at all
+    pub fn is_enabled(&self) -> bool {
+        self.curves.values().any(|curve| !curve.tiers.is_empty())
+    }
+
+    pub fn set_opted_in(&mut self, user_id: Uuid, opted_in: bool) {
+        if opted_in {
+            self.opted_in.insert(user_id);
+        } else {
+            self.opted_in.remove(&user_id);
+        }
+    }
+
+    /// Interest for every day that finished before `today`, computed on each
+    /// opted-in user's balance as `balance_of` reports it now
+    pub fn accrue(
+        &mut self,
+        today: NaiveDate,
+        balance_of: impl Fn(Uuid, &str) -> f64,
+    ) -> Vec<Accrual> {
+        let days = (today - self.accrued_through).num_days().max(0);
+        if days == 0 {
+            return Vec::new();
+        }
+        self.accrued_through = today;
+
+        let mut accruals = Vec::new();
+        for &user_id in &self.opted_in {
+            for (currency, curve) in &self.curves {
+                let balance = balance_of(user_id, currency);
+                let amount = curve.daily_interest(balance) * days as f64;
+                if amount <= 0.0 {
+                    continue;
+                }
+                let earned = self.earned.entry((user_id, currency.clone())).or_default();
+                earned.total += amount;
+                earned.last = Some(amount);
+                accruals.push(Accrual {
+                    user_id,
+                    currency: currency.clone(),
+                    amount,
+                });
+            }
+        }
+        accruals
+    }
+
+    pub fn summary(&self, user_id: Uuid, balance_of: impl Fn(&str) -> f64) -> InterestSummary {
+        let currencies = self
+            .curves
+            .iter()
+            .map(|(currency, curve)| {
+                let balance = balance_of(currency);
+                let apr = curve.effective_apr(balance);
+                let earned = self.earned.get(&(user_id, currency.clone()));
+                CurrencyInterest {
+                    currency: currency.clone(),
+                    balance,
+                    apr,
+                    apy: apy(apr),
+                    accrued_total: earned.map_or(0.0, |e| e.total),
+                    last_accrual: earned.and_then(|e| e.last),
+                }
+            })
+            .collect();
+
+        InterestSummary {
+            user_id,
+            opted_in: self.opted_in.contains(&user_id),
+            last_accrual_date: self.accrued_through.pred_opt(),
+            currencies,
+        }
+    }
+
+    pub fn state(&self) -> InterestState {
+        let earned = self
+            .earned
+            .iter()
+            .map(|((user_id, currency), earned)| EarnedInterest {
+                user_id: *user_id,
+                currency: currency.clone(),
+                total: earned.total,
+                last: earned.last,
+            })
+            .collect();
+        InterestState {
+            opted_in: self.opted_in.iter().copied().collect(),
+            earned,
+            accrued_through: self.accrued_through,
+        }
+    }
+
+    /// Take up `state` in place of whatever accrued here, keeping the curves
+    pub fn restore(&mut self, state: InterestState) {
+        self.opted_in = state.opted_in.into_iter().collect();
+        self.earned = state
+            .earned
+            .into_iter()
+            .map(|e| {
+                let earned = Earned {
+                    total: e.total,
+                    last: e.last,
+                };
+                ((e.user_id, e.currency), earned)
+            })
+            .collect();
+        self.accrued_through = state.accrued_through;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2025, 3, day).unwrap()
+    }
+
+    #[test]
+    fn test_curve_parsing_and_brackets() {
+        let curve: RateCurve = "1000:0.0365,*:0.0073".parse().unwrap();
+        assert_eq!(curve.tiers.len(), 2);
+        // 1000 at 3.65% plus 1000 at 0.73%, per day
+        assert!((curve.daily_interest(2_000.0) - (0.1 + 0.02)).abs() < 1e-9);
+        assert!((curve.effective_apr(2_000.0) - 0.0219).abs() < 1e-9);
+        assert!(apy(0.05) > 0.05);
+
+        assert!("10:0.1,5:0.2".parse::<RateCurve>().is_err());
+        assert!("abc".parse::<RateCurve>().is_err());
+        assert!("*:-0.1".parse::<RateCurve>().is_err());
+    }
+
+    #[test]
+    fn test_accrues_once_per_day_for_opted_in_users_only() {
+        let curves = BTreeMap::from([("USD".to_string(), "*:0.0365".parse().unwrap())]);
+        let mut accrual = InterestAccrual::new(curves, date(1));
+        let (saver, other) = (Uuid::new_v4(), Uuid::new_v4());
+        accrual.set_opted_in(saver, true);
+
+        assert!(accrual.accrue(date(1), |_, _| 1_000.0).is_empty());
+
+        // Two days passed: saver earns 0.1 per day on 1000
+        let credits = accrual.accrue(date(3), |_, _| 1_000.0);
+        assert_eq!(credits.len(), 1);
+        assert_eq!(credits[0].user_id, saver);
+        assert!((credits[0].amount - 0.2).abs() < 1e-9);
+        assert!(accrual.accrue(date(3), |_, _| 1_000.0).is_empty());
+
+        let summary = accrual.summary(saver, |_| 1_000.0);
+        assert!(summary.opted_in);
+        assert_eq!(summary.last_accrual_date, Some(date(2)));
+        assert!((summary.currencies[0].accrued_total - 0.2).abs() < 1e-9);
+        assert!(!accrual.summary(other, |_| 0.0).opted_in);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::types::LeverageTiers;
+use serde::{Deserialize, Serialize};
+
+/// A user's exposure in one market: signed base quantity (negative = short)
+/// against a quote cash balance (negative = borrowed)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MarginPosition {
+    pub quantity: f64,
+    pub cash: f64,
+}
+
+impl MarginPosition {
+    pub fn new(quantity: f64, cash: f64) -> Self {
+        MarginPosition { quantity, cash }
+    }
+
+    /// The position after trading `delta_qty` (signed) at `price`
+    pub fn with_delta(self, delta_qty: f64, price: f64) -> Self {
+        MarginPosition {
+            quantity: self.quantity + delta_qty,
+            cash: self.cash - delta_qty * price,
+        }
+    }
+
+    pub fn notional(&self, price: f64) -> f64 {
+        self.quantity.abs() * price
+    }
+
+    pub fn equity(&self, price: f64) -> f64 {
+        self.cash + self.quantity * price
+    }
+}
+
+/// Margin health of a position at a given mark price
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarginAssessment {
+    pub position: MarginPosition,
+    pub mark_price: f64,
+    pub notional: f64,
+    pub equity: f64,
+    pub maintenance_margin: Option<f64>, // None when the position exceeds every tier
+    pub margin_ratio: Option<f64>,       // Maintenance margin / equity; liquidation at 1
+    pub liquidation_price: Option<f64>,  // None when no positive price triggers liquidation
+    pub liquidatable: bool,
+}
+
+/// Price at which equity falls to the maintenance requirement.
+/// The maintenance rate depends on the notional at that price, so each tier is
+/// tried in turn and the solution that lands inside its own bracket wins.
+fn liquidation_price(position: &MarginPosition, tiers: &LeverageTiers) -> Option<f64> {
+    let qty = position.quantity;
+    if qty == 0.0 {
+        return None;
+    }
+
+    let mut lower = 0.0;
+    for tier in &tiers.tiers {
+        // cash + qty * p = |qty| * p * mmr
+        let denominator = qty.abs() * tier.maintenance_margin_rate - qty;
+        if denominator != 0.0 {
+            let price = position.cash / denominator;
+            let notional = position.notional(price);
+            if price > 0.0 && notional > lower && notional <= tier.max_notional {
+                return Some(price);
+            }
+        }
+        lower = tier.max_notional;
+    }
+    None
+}
+
+/// Evaluate a position against the margin tiers at `mark_price`, for the
+/// account summary and the liquidation preview. Nothing liquidates positions
+/// yet; `liquidatable` only reports what this check concludes.
+pub fn assess_position(
+    position: MarginPosition,
+    tiers: &LeverageTiers,
+    mark_price: f64,
+) -> MarginAssessment {
+    let notional = position.notional(mark_price);
+    let equity = position.equity(mark_price);
+    let maintenance_margin = tiers.maintenance_margin(notional).ok();
+    let margin_ratio = match maintenance_margin {
+        Some(_) if notional == 0.0 => Some(0.0),
+        Some(mm) if equity > 0.0 => Some(mm / equity),
+        _ => None,
+    };
+    let liquidatable = match margin_ratio {
+        Some(ratio) => ratio >= 1.0,
+        None => notional > 0.0,
+    };
+
+    MarginAssessment {
+        position,
+        mark_price,
+        notional,
+        equity,
+        maintenance_margin,
+        margin_ratio,
+        liquidation_price: liquidation_price(&position, tiers),
+        liquidatable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fully_funded_long_is_never_liquidated() {
+        let tiers = LeverageTiers::default();
+        let assessment = assess_position(MarginPosition::new(1.0, 0.0), &tiers, 30_000.0);
+
+        assert_eq!(assessment.equity, 30_000.0);
+        assert_eq!(assessment.maintenance_margin, Some(750.0));
+        assert_eq!(assessment.liquidation_price, None);
+        assert!(!assessment.liquidatable);
+    }
+
+    #[test]
+    fn test_leveraged_long_liquidation_price() {
+        let tiers = LeverageTiers::default();
+        // 1 BTC bought at 30k with 27k borrowed
+        let position = MarginPosition::new(0.0, 3_000.0).with_delta(1.0, 30_000.0);
+        let assessment = assess_position(position, &tiers, 30_000.0);
+
+        // 27000 / (1 - 0.025)
+        let expected = 27_000.0 / 0.975;
+        assert!((assessment.liquidation_price.unwrap() - expected).abs() < 1e-6);
+        assert_eq!(assessment.margin_ratio, Some(0.25));
+        assert!(!assessment.liquidatable);
+
+        let crashed = assess_position(position, &tiers, 27_500.0);
+        assert!(crashed.liquidatable);
+    }
+
+    #[test]
+    fn test_short_liquidation_price_uses_bracket_at_that_price() {
+        let tiers = LeverageTiers::default();
+        // Short 2 BTC at 30k with 10k of own cash
+        let position = MarginPosition::new(0.0, 10_000.0).with_delta(-2.0, 30_000.0);
+        let price = liquidation_price(&position, &tiers).unwrap();
+
+        // 70000 / (2 * 1.05): notional at that price is in the second bracket
+        assert!((price - 70_000.0 / 2.1).abs() < 1e-6);
+        assert!(position.notional(price) > 50_000.0);
+    }
+}
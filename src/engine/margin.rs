@@ -0,0 +1,211 @@
+use crate::types::LeverageTiers;
+use crate::utils::{format_amount, BASE_QUOTE_CURRENCY};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// A user's effective leverage configuration, as reported by the API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeverageSettings {
+    pub tiers: LeverageTiers,
+    pub custom_tiers: bool, // True when an operator override replaces the default table
+    pub max_leverage: Option<f64>, // User-chosen cap, None = whatever the tier allows
+    pub position_notional: f64,
+    pub tier_max_leverage: Option<f64>, // Cap of the bracket the current position falls in
+    pub initial_margin: Option<f64>,
+    pub maintenance_margin: Option<f64>,
+}
+
+/// Check that a position of `position_notional` is allowed under `tiers`
+/// with the user's chosen leverage cap
+fn check_position(
+    tiers: &LeverageTiers,
+    position_notional: f64,
+    max_leverage: Option<f64>,
+) -> Result<(), String> {
+    let tier = tiers.tier_for(position_notional).ok_or_else(|| {
+        format!(
+            "Current position notional {} exceeds the largest tier ({})",
+            format_amount(position_notional, BASE_QUOTE_CURRENCY, false),
+            format_amount(tiers.max_notional(), BASE_QUOTE_CURRENCY, false)
+        )
+    })?;
+    if let Some(leverage) = max_leverage {
+        if leverage > tier.max_leverage {
+            return Err(format!(
+                "Leverage {} exceeds the {}x allowed for the current position",
+                leverage, tier.max_leverage
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Leverage tiers used for margin calculations, with per-user overrides
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MarginSettings {
+    default_tiers: LeverageTiers,
+    user_tiers: HashMap<Uuid, LeverageTiers>,
+    user_leverage: HashMap<Uuid, f64>,
+}
+
+impl MarginSettings {
+    pub fn new(default_tiers: LeverageTiers) -> Self {
+        MarginSettings {
+            default_tiers,
+            ..Default::default()
+        }
+    }
+
+    pub fn default_tiers(&self) -> &LeverageTiers {
+        &self.default_tiers
+    }
+
+    pub fn tiers_for(&self, user_id: Uuid) -> &LeverageTiers {
+        self.user_tiers.get(&user_id).unwrap_or(&self.default_tiers)
+    }
+
+    pub fn has_custom_tiers(&self, user_id: Uuid) -> bool {
+        self.user_tiers.contains_key(&user_id)
+    }
+
+    pub fn leverage_for(&self, user_id: Uuid) -> Option<f64> {
+        self.user_leverage.get(&user_id).copied()
+    }
+
+    pub fn settings(&self, user_id: Uuid, position_notional: f64) -> LeverageSettings {
+        let tiers = self.tiers_for(user_id);
+        let max_leverage = self.leverage_for(user_id);
+        let tier_max_leverage = tiers.tier_for(position_notional).map(|t| t.max_leverage);
+        let effective = match (max_leverage, tier_max_leverage) {
+            (Some(chosen), Some(cap)) => Some(chosen.min(cap)),
+            (_, cap) => cap,
+        };
+
+        LeverageSettings {
+            tiers: tiers.clone(),
+            custom_tiers: self.has_custom_tiers(user_id),
+            max_leverage,
+            position_notional,
+            tier_max_leverage,
+            initial_margin: effective.and_then(|l| tiers.initial_margin(position_notional, l).ok()),
+            maintenance_margin: tiers.maintenance_margin(position_notional).ok(),
+        }
+    }
+
+    /// Set (or with None, clear) the user's own leverage cap
+    pub fn set_user_leverage(
+        &mut self,
+        user_id: Uuid,
+        max_leverage: Option<f64>,
+        position_notional: f64,
+    ) -> Result<(), String> {
+        if let Some(leverage) = max_leverage {
+            if !(leverage.is_finite() && leverage >= 1.0) {
+                return Err("Leverage must be at least 1".to_string());
+            }
+        }
+        check_position(self.tiers_for(user_id), position_notional, max_leverage)?;
+
+        match max_leverage {
+            Some(leverage) => self.user_leverage.insert(user_id, leverage),
+            None => self.user_leverage.remove(&user_id),
+        };
+        Ok(())
+    }
+
+    /// Install (or with None, remove) a tier override for one user
+    pub fn set_user_tiers(
+        &mut self,
+        user_id: Uuid,
+        tiers: Option<LeverageTiers>,
+        position_notional: f64,
+    ) -> Result<(), String> {
+        let effective = tiers.as_ref().unwrap_or(&self.default_tiers);
+        check_position(effective, position_notional, self.leverage_for(user_id))?;
+
+        match tiers {
+            Some(tiers) => self.user_tiers.insert(user_id, tiers),
+            None => self.user_tiers.remove(&user_id),
+        };
+        Ok(())
+    }
+
+    /// Replace the default table. Every user without an override must still
+    /// fit; `positions` yields each user's current position notional.
+    pub fn set_default_tiers(
+        &mut self,
+        tiers: LeverageTiers,
+        positions: impl IntoIterator<Item = (Uuid, f64)>,
+    ) -> Result<(), String> {
+        for (user_id, notional) in positions {
+            if self.user_tiers.contains_key(&user_id) {
+                continue;
+            }
+            check_position(&tiers, notional, self.leverage_for(user_id))
+                .map_err(|e| format!("User {}: {}", user_id, e))?;
+        }
+
+        self.default_tiers = tiers;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::LeverageTier;
+
+    fn single_tier(max_notional: f64, max_leverage: f64) -> LeverageTiers {
+        LeverageTiers::new(vec![LeverageTier {
+            max_notional,
+            max_leverage,
+            maintenance_margin_rate: 0.01,
+        }])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_user_leverage_limited_by_position_bracket() {
+        let mut margin = MarginSettings::default();
+        let user = Uuid::new_v4();
+
+        assert!(margin.set_user_leverage(user, Some(20.0), 10_000.0).is_ok());
+        assert!(margin
+            .set_user_leverage(user, Some(20.0), 100_000.0)
+            .is_err());
+        assert!(margin.set_user_leverage(user, Some(0.5), 0.0).is_err());
+
+        let settings = margin.settings(user, 10_000.0);
+        assert_eq!(settings.max_leverage, Some(20.0));
+        assert_eq!(settings.initial_margin, Some(500.0));
+        assert_eq!(settings.maintenance_margin, Some(250.0));
+    }
+
+    #[test]
+    fn test_tier_changes_validated_against_positions() {
+        let mut margin = MarginSettings::default();
+        let user = Uuid::new_v4();
+        margin
+            .set_user_leverage(user, Some(20.0), 10_000.0)
+            .unwrap();
+
+        // New default would cap the user's chosen leverage below what they use
+        assert!(margin
+            .set_default_tiers(single_tier(1_000_000.0, 10.0), [(user, 10_000.0)])
+            .is_err());
+        // Position too large for the override
+        assert!(margin
+            .set_user_tiers(user, Some(single_tier(5_000.0, 50.0)), 10_000.0)
+            .is_err());
+
+        margin
+            .set_user_tiers(user, Some(single_tier(50_000.0, 50.0)), 10_000.0)
+            .unwrap();
+        assert!(margin.settings(user, 10_000.0).custom_tiers);
+        // Users with overrides are unaffected by the default table
+        assert!(margin
+            .set_default_tiers(single_tier(1_000_000.0, 10.0), [(user, 10_000.0)])
+            .is_ok());
+    }
+}
@@ -0,0 +1,198 @@
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::messages::AbandonReason;
+
+/// Counters maintained by the engine task and read by HTTP handlers.
+/// Atomics keep reads lock-free so scraping metrics never stalls matching.
+#[derive(Debug, Default)]
+pub struct EngineMetrics {
+    pub commands_processed: AtomicU64,
+    pub queries_expired: AtomicU64,
+    pub queries_cancelled: AtomicU64,
+    pub responses_undelivered: AtomicU64,
+    pub cancels_prioritized: AtomicU64,
+    pub incidents_opened: AtomicU64, // Balance guard incidents; any increase needs an operator
+    pub market_seq: AtomicU64,       // Last sequence number published on the market data feed
+    pub ready: AtomicBool,           // Set once the engine has built its book and takes commands
+    pub history_jobs_queued: AtomicU64,
+    pub history_jobs_done: AtomicU64,
+    pub history_handoff_us: AtomicU64, // Engine time spent handing history jobs over
+    pub history_work_us: AtomicU64,    // History worker time spent running them
+    pub history_write_failures: AtomicU64, // Writes given up on after their retries
+    pub outbox_events_delivered: AtomicU64,
+    pub outbox_delivery_failures: AtomicU64, // Attempts that will be retried
+    pub bbo_changes: AtomicU64,
+    pub trades_published: AtomicU64,
+    pub order_events: AtomicU64,
+    pub wal_commands_replayed: AtomicU64,
+    pub wal_write_failures: AtomicU64,
+    pub snapshots_written: AtomicU64,
+    pub snapshot_failures: AtomicU64,
+    pub index_value_failures: AtomicU64, // Synthetic index valuations that came out of range
+}
+
+/// Point-in-time copy of `EngineMetrics` suitable for serialization
+#[derive(Debug, Clone, Serialize)]
+pub struct EngineMetricsSnapshot {
+    pub commands_processed: u64,
+    pub queries_expired: u64,
+    pub queries_cancelled: u64,
+    pub responses_undelivered: u64,
+    pub cancels_prioritized: u64,
+    pub incidents_opened: u64,
+    pub market_seq: u64,
+    pub ready: bool,
+    pub history_jobs_queued: u64,
+    pub history_backlog: u64, // Handed over but not yet run
+    pub history_handoff_us: u64,
+    pub history_work_us: u64,
+    pub history_write_failures: u64,
+    pub outbox_events_delivered: u64,
+    pub outbox_delivery_failures: u64,
+    pub bbo_changes: u64,
+    pub trades_published: u64,
+    pub order_events: u64,
+    pub wal_commands_replayed: u64,
+    pub wal_write_failures: u64,
+    pub snapshots_written: u64,
+    pub snapshot_failures: u64,
+    pub index_value_failures: u64,
+}
+
+impl EngineMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_processed(&self) {
+        self.commands_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_abandoned(&self, reason: AbandonReason) {
+        let counter = match reason {
+            AbandonReason::Expired => &self.queries_expired,
+            AbandonReason::Cancelled => &self.queries_cancelled,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_undelivered(&self) {
+        self.responses_undelivered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_prioritized_cancels(&self, count: usize) {
+        self.cancels_prioritized
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_incident(&self) {
+        self.incidents_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_market_seq(&self, seq: u64) {
+        self.market_seq.store(seq, Ordering::Relaxed);
+    }
+
+    pub fn record_history_handoff(&self, elapsed: Duration) {
+        self.history_jobs_queued.fetch_add(1, Ordering::Relaxed);
+        self.history_handoff_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_history_job_done(&self, elapsed: Duration) {
+        self.history_work_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.history_jobs_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A history write failed every attempt, so the store is missing it
+    pub fn record_history_write_failure(&self) {
+        self.history_write_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_outbox_delivered(&self, count: usize) {
+        self.outbox_events_delivered
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_outbox_failure(&self) {
+        self.outbox_delivery_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bbo_change(&self) {
+        self.bbo_changes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_trade_published(&self) {
+        self.trades_published.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_order_events(&self, count: usize) {
+        self.order_events.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_wal_replayed(&self, count: usize) {
+        self.wal_commands_replayed
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// A batch could not be logged, so it was refused
+    pub fn record_wal_failure(&self) {
+        self.wal_write_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_snapshot(&self) {
+        self.snapshots_written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A snapshot could not be written; a restart replays more of the log
+    pub fn record_snapshot_failure(&self) {
+        self.snapshot_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A synthetic index could not be valued, so it has no value until it can
+    pub fn record_index_value_failure(&self) {
+        self.index_value_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn mark_ready(&self) {
+        self.ready.store(true, Ordering::Release);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn snapshot(&self) -> EngineMetricsSnapshot {
+        let history_jobs_done = self.history_jobs_done.load(Ordering::Relaxed);
+        let history_jobs_queued = self.history_jobs_queued.load(Ordering::Relaxed);
+        EngineMetricsSnapshot {
+            commands_processed: self.commands_processed.load(Ordering::Relaxed),
+            queries_expired: self.queries_expired.load(Ordering::Relaxed),
+            queries_cancelled: self.queries_cancelled.load(Ordering::Relaxed),
+            responses_undelivered: self.responses_undelivered.load(Ordering::Relaxed),
+            cancels_prioritized: self.cancels_prioritized.load(Ordering::Relaxed),
+            incidents_opened: self.incidents_opened.load(Ordering::Relaxed),
+            market_seq: self.market_seq.load(Ordering::Relaxed),
+            ready: self.is_ready(),
+            history_jobs_queued,
+            history_backlog: history_jobs_queued.saturating_sub(history_jobs_done),
+            history_handoff_us: self.history_handoff_us.load(Ordering::Relaxed),
+            history_work_us: self.history_work_us.load(Ordering::Relaxed),
+            history_write_failures: self.history_write_failures.load(Ordering::Relaxed),
+            outbox_events_delivered: self.outbox_events_delivered.load(Ordering::Relaxed),
+            outbox_delivery_failures: self.outbox_delivery_failures.load(Ordering::Relaxed),
+            bbo_changes: self.bbo_changes.load(Ordering::Relaxed),
+            trades_published: self.trades_published.load(Ordering::Relaxed),
+            order_events: self.order_events.load(Ordering::Relaxed),
+            wal_commands_replayed: self.wal_commands_replayed.load(Ordering::Relaxed),
+            wal_write_failures: self.wal_write_failures.load(Ordering::Relaxed),
+            snapshots_written: self.snapshots_written.load(Ordering::Relaxed),
+            snapshot_failures: self.snapshot_failures.load(Ordering::Relaxed),
+            index_value_failures: self.index_value_failures.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -1,3 +1,68 @@
+pub mod account_summary;
+pub mod batch;
+pub mod client_ids;
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod daily_stats;
+pub mod dashboard;
+pub mod dead_letters;
+pub mod dead_man;
+pub mod dedupe;
+#[allow(clippy::module_inception)]
 pub mod engine;
+pub mod events;
+pub mod execution_quality;
+pub mod expiry;
+pub mod feed_activity;
+pub mod history_worker;
+pub mod incidents;
+pub mod interest;
+pub mod liquidation;
+pub mod margin;
+pub mod metrics;
+pub mod order_history;
+pub mod outbox;
+pub mod price_averages;
+pub mod simulator;
+pub mod snapshot;
+pub mod source_volume;
+pub mod synthetics;
+pub mod timings;
+pub mod trade_tape;
+pub mod triggers;
+pub mod wal;
 
+pub use account_summary::*;
+pub use batch::*;
+pub use client_ids::*;
+pub use clock::*;
+pub use config::*;
+pub use control::*;
+pub use daily_stats::*;
+pub use dashboard::*;
+pub use dead_letters::*;
+pub use dead_man::*;
+pub use dedupe::*;
 pub use engine::*;
+pub use events::*;
+pub use execution_quality::*;
+pub use expiry::*;
+pub use feed_activity::*;
+pub use history_worker::*;
+pub use incidents::*;
+pub use interest::*;
+pub use liquidation::*;
+pub use margin::*;
+pub use metrics::*;
+pub use order_history::*;
+pub use outbox::*;
+pub use price_averages::*;
+pub use simulator::*;
+pub use snapshot::*;
+pub use source_volume::*;
+pub use synthetics::*;
+pub use timings::*;
+pub use trade_tape::*;
+pub use triggers::*;
+pub use wal::*;
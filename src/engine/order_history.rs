@@ -0,0 +1,459 @@
+use crate::types::{
+    Activity, ActivityEntry, LiquidityRole, Order, OrderStatus, Price, Quantity, Trade,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// One execution of one order. `fill_seq` counts the order's fills from 1 with
+/// no gaps, so a client that sees 3 after 1 knows it missed one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderFill {
+    pub order_id: Uuid,
+    pub user_id: Uuid,
+    pub fill_seq: u64,
+    pub trade_id: Uuid,
+    pub role: LiquidityRole,
+    pub price: Price,
+    pub quantity: Quantity,
+    pub fee: f64,                     // Quote currency, charged to this order's owner
+    pub remaining_quantity: Quantity, // Left on the order after this fill
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Every order the engine accepted, in its latest known state, indexed by user,
+/// plus every trade each order took part in, indexed by order.
+/// Unlike `OrderBook::orders` this keeps filled, cancelled and market orders,
+/// until `prune` drops those closed longer than the retention period.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct OrderHistory {
+    orders: HashMap<Uuid, Order>,
+    by_user: HashMap<Uuid, Vec<Uuid>>, // Insertion (acceptance) order
+    fills: HashMap<Uuid, Vec<OrderFill>>, // By order id, in fill_seq order
+    closed_at: HashMap<Uuid, DateTime<Utc>>, // When cancelled or expired orders were
+    // Orders changed since the last `take_unsaved`
+    #[serde(skip)]
+    unsaved: HashSet<Uuid>,
+    // Changes made since the last `take_change_count`, one per update
+    #[serde(skip)]
+    changes: usize,
+    #[serde(skip)]
+    next_prune: Option<DateTime<Utc>>,
+}
+
+/// How often `prune` sweeps the history
+const PRUNE_INTERVAL: chrono::Duration = chrono::Duration::seconds(60);
+
+impl OrderHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert or replace the record for `order`
+    pub fn upsert(&mut self, order: &Order) {
+        if self.orders.insert(order.id, order.clone()).is_none() {
+            self.by_user.entry(order.user_id).or_default().push(order.id);
+        }
+        // The unfilled rest of an IOC or market order is cancelled as it is
+        // accepted, and a rejected stop is closed as it triggers
+        if matches!(order.status, OrderStatus::Cancelled | OrderStatus::Rejected) {
+            self.closed_at.entry(order.id).or_insert(order.timestamp);
+        }
+        self.changed(order.id);
+    }
+
+    fn changed(&mut self, order_id: Uuid) {
+        self.unsaved.insert(order_id);
+        self.changes += 1;
+    }
+
+    /// How many times orders were accepted, filled, cancelled or expired since
+    /// the last call. An order that fills three times counts three times.
+    pub fn take_change_count(&mut self) -> usize {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Latest state of every order changed since `take_unsaved` was last called
+    pub fn unsaved(&self) -> impl Iterator<Item = &Order> {
+        self.unsaved.iter().filter_map(|id| self.orders.get(id))
+    }
+
+    /// Latest state of every order changed since the last call, for the store
+    pub fn take_unsaved(&mut self) -> Vec<Order> {
+        self.unsaved
+            .drain()
+            .filter_map(|id| self.orders.get(&id).cloned())
+            .collect()
+    }
+
+    /// Apply the maker side of `trades` to the stored maker orders
+    pub fn record_maker_fills(&mut self, trades: &[Trade]) {
+        for trade in trades {
+            self.apply_fill(trade.maker_order_id, trade.quantity);
+        }
+    }
+
+    /// Apply the maker side of `trades` and index the fills of both sides by
+    /// order. Call after upserting the taker. Returns the new fills in order.
+    pub fn record_fills(&mut self, trades: &[Trade]) -> Vec<OrderFill> {
+        self.record_maker_fills(trades);
+
+        let mut recorded = Vec::with_capacity(trades.len() * 2);
+        for trade in trades {
+            recorded.push(self.index_fill(trade, LiquidityRole::Maker));
+            recorded.push(self.index_fill(trade, LiquidityRole::Taker));
+        }
+        recorded
+    }
+
+    fn index_fill(&mut self, trade: &Trade, role: LiquidityRole) -> OrderFill {
+        let (order_id, user_id, fill_seq, fee) = match role {
+            LiquidityRole::Maker => (
+                trade.maker_order_id,
+                trade.maker_user_id,
+                trade.maker_fill_seq,
+                trade.maker_fee,
+            ),
+            LiquidityRole::Taker => (
+                trade.taker_order_id,
+                trade.taker_user_id,
+                trade.taker_fill_seq,
+                trade.taker_fee,
+            ),
+        };
+        let original = self
+            .orders
+            .get(&order_id)
+            .map_or(trade.quantity, |o| o.original_quantity);
+        let fills = self.fills.entry(order_id).or_default();
+        let before = fills.last().map_or(original, |f| f.remaining_quantity);
+        let fill = OrderFill {
+            order_id,
+            user_id,
+            fill_seq,
+            trade_id: trade.id,
+            role,
+            price: trade.price,
+            quantity: trade.quantity,
+            fee,
+            remaining_quantity: before - trade.quantity,
+            timestamp: trade.timestamp,
+        };
+        fills.push(fill.clone());
+        fill
+    }
+
+    fn apply_fill(&mut self, order_id: Uuid, quantity: Quantity) {
+        if let Some(order) = self.orders.get_mut(&order_id) {
+            order.fill(quantity);
+            self.changed(order_id);
+        }
+    }
+
+    pub fn mark_cancelled(&mut self, order_id: Uuid, at: DateTime<Utc>) {
+        if let Some(order) = self.orders.get_mut(&order_id) {
+            order.cancel();
+            self.closed_at.insert(order_id, at);
+            self.changed(order_id);
+        }
+    }
+
+    pub fn mark_expired(&mut self, order_id: Uuid, at: DateTime<Utc>) {
+        if let Some(order) = self.orders.get_mut(&order_id) {
+            order.expire();
+            self.closed_at.insert(order_id, at);
+            self.changed(order_id);
+        }
+    }
+
+    /// When a closed order closed: its cancel, expiry or rejection, or else
+    /// its last fill
+    fn closed_time(&self, order: &Order) -> DateTime<Utc> {
+        self.closed_at
+            .get(&order.id)
+            .or_else(|| {
+                self.fills
+                    .get(&order.id)?
+                    .last()
+                    .map(|fill| &fill.timestamp)
+            })
+            .copied()
+            .unwrap_or(order.timestamp)
+    }
+
+    /// Drop orders that closed more than `retention` before `now`, with their
+    /// fills. The store keeps them; orders not yet saved stay. Sweeps at most
+    /// once a minute; returns how many orders were dropped.
+    pub fn prune(&mut self, now: DateTime<Utc>, retention: chrono::Duration) -> usize {
+        if self.next_prune.is_some_and(|at| now < at) {
+            return 0;
+        }
+        self.next_prune = now.checked_add_signed(PRUNE_INTERVAL);
+
+        let Some(cutoff) = now.checked_sub_signed(retention) else {
+            return 0;
+        };
+        let expired: HashSet<Uuid> = self
+            .orders
+            .values()
+            .filter(|order| {
+                !matches!(
+                    order.status,
+                    OrderStatus::Open | OrderStatus::PartiallyFilled
+                )
+            })
+            .filter(|order| !self.unsaved.contains(&order.id))
+            .filter(|order| self.closed_time(order) < cutoff)
+            .map(|order| order.id)
+            .collect();
+        if expired.is_empty() {
+            return 0;
+        }
+        for id in &expired {
+            self.orders.remove(id);
+            self.fills.remove(id);
+            self.closed_at.remove(id);
+        }
+        self.by_user.retain(|_, ids| {
+            ids.retain(|id| !expired.contains(id));
+            !ids.is_empty()
+        });
+        expired.len()
+    }
+
+    /// The part of the history still in play: orders that may yet fill or be
+    /// cancelled, with their fills. Closed orders are already in the store.
+    pub fn open_orders(&self) -> OrderHistory {
+        let mut open = OrderHistory::new();
+        for (user_id, ids) in &self.by_user {
+            for id in ids {
+                let Some(order) = self.orders.get(id) else {
+                    continue;
+                };
+                if !matches!(
+                    order.status,
+                    OrderStatus::Open | OrderStatus::PartiallyFilled
+                ) {
+                    continue;
+                }
+                open.orders.insert(*id, order.clone());
+                open.by_user.entry(*user_id).or_default().push(*id);
+                if let Some(fills) = self.fills.get(id) {
+                    open.fills.insert(*id, fills.clone());
+                }
+            }
+        }
+        open
+    }
+
+    pub fn get(&self, order_id: Uuid) -> Option<&Order> {
+        self.orders.get(&order_id)
+    }
+
+    /// Fills of `order_id` with `fill_seq > after`, oldest first
+    pub fn fills(&self, order_id: Uuid, after: u64) -> Vec<OrderFill> {
+        self.fills
+            .get(&order_id)
+            .map(|fills| {
+                fills
+                    .iter()
+                    .filter(|f| f.fill_seq > after)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Every placement, fill, cancellation, expiry and rejection of a user's
+    /// orders, oldest first
+    pub fn activity(&self, user_id: Uuid) -> Vec<ActivityEntry> {
+        let mut entries = Vec::new();
+        for order in self
+            .by_user
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.orders.get(id))
+        {
+            entries.push(ActivityEntry::new(
+                order.timestamp,
+                Activity::OrderPlaced {
+                    order_id: order.id,
+                    side: order.side,
+                    order_type: order.order_type,
+                    price: order.price,
+                    quantity: order.original_quantity,
+                },
+            ));
+            for fill in self.fills.get(&order.id).into_iter().flatten() {
+                entries.push(ActivityEntry::new(
+                    fill.timestamp,
+                    Activity::OrderFilled {
+                        order_id: order.id,
+                        trade_id: fill.trade_id,
+                        role: fill.role,
+                        price: fill.price,
+                        quantity: fill.quantity,
+                        fee: fill.fee,
+                    },
+                ));
+            }
+            let Some(&closed_at) = self.closed_at.get(&order.id) else {
+                continue;
+            };
+            let (order_id, remaining_quantity) = (order.id, order.remaining_quantity);
+            let closed = match order.status {
+                OrderStatus::Expired => Activity::OrderExpired {
+                    order_id,
+                    remaining_quantity,
+                },
+                OrderStatus::Rejected => Activity::OrderRejected {
+                    order_id,
+                    reason: order.reject_reason.clone().unwrap_or_default(),
+                },
+                _ => Activity::OrderCancelled {
+                    order_id,
+                    remaining_quantity,
+                },
+            };
+            entries.push(ActivityEntry::new(closed_at, closed));
+        }
+        entries.sort_by_key(|entry| entry.at);
+        entries
+    }
+
+    /// A user's orders, newest first
+    pub fn for_user(&self, user_id: Uuid, limit: usize) -> Vec<Order> {
+        self.by_user
+            .get(&user_id)
+            .map(|ids| {
+                ids.iter()
+                    .rev()
+                    .filter_map(|id| self.orders.get(id))
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{OrderSide, OrderStatus, Price};
+
+    #[test]
+    fn test_tracks_maker_fills_and_cancels() {
+        let user_id = Uuid::new_v4();
+        let maker = Order::new_limit(user_id, OrderSide::Sell, Price::new(100), Quantity::new(10));
+        let other = Order::new_limit(user_id, OrderSide::Sell, Price::new(101), Quantity::new(5));
+
+        let mut history = OrderHistory::new();
+        history.upsert(&maker);
+        history.upsert(&other);
+
+        let trade = Trade::new(
+            maker.id,
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            Price::new(100),
+            Quantity::new(4),
+        );
+        history.record_maker_fills(&[trade]);
+        history.mark_cancelled(other.id, Utc::now());
+
+        let stored = history.get(maker.id).unwrap();
+        assert_eq!(stored.remaining_quantity, Quantity::new(6));
+        assert_eq!(stored.status, OrderStatus::PartiallyFilled);
+        assert_eq!(history.get(other.id).unwrap().status, OrderStatus::Cancelled);
+
+        // Two acceptances, a fill and a cancel, of two orders
+        assert_eq!(history.take_change_count(), 4);
+        assert_eq!(history.unsaved().count(), 2);
+        assert_eq!(history.take_change_count(), 0);
+
+        let orders = history.for_user(user_id, 10);
+        assert_eq!(orders.len(), 2);
+        assert_eq!(orders[0].id, other.id);
+        assert_eq!(history.for_user(user_id, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_indexes_fills_by_order_with_role() {
+        let (maker_user, taker_user) = (Uuid::new_v4(), Uuid::new_v4());
+        let maker = Order::new_limit(maker_user, OrderSide::Sell, Price::new(100), Quantity::new(10));
+        let taker = Order::new_limit(taker_user, OrderSide::Buy, Price::new(100), Quantity::new(7));
+
+        let mut history = OrderHistory::new();
+        history.upsert(&maker);
+        history.upsert(&taker);
+
+        let fill = |quantity, seq| {
+            Trade::new(
+                maker.id,
+                taker.id,
+                maker_user,
+                taker_user,
+                Price::new(100),
+                Quantity::new(quantity),
+            )
+            .with_fill_seqs(seq, seq)
+        };
+        let trades = vec![fill(3, 1), fill(4, 2)];
+        assert_eq!(history.record_fills(&trades).len(), 4);
+
+        let maker_fills = history.fills(maker.id, 0);
+        assert_eq!(maker_fills.len(), 2);
+        assert!(maker_fills.iter().all(|f| f.role == LiquidityRole::Maker));
+        assert_eq!(maker_fills[1].trade_id, trades[1].id);
+        assert_eq!(maker_fills[1].remaining_quantity, Quantity::new(3));
+
+        let taker_fills = history.fills(taker.id, 1);
+        assert_eq!(taker_fills.len(), 1);
+        assert_eq!(taker_fills[0].role, LiquidityRole::Taker);
+        assert_eq!(taker_fills[0].remaining_quantity, Quantity::new(0));
+        assert!(history.fills(Uuid::new_v4(), 0).is_empty());
+    }
+
+    #[test]
+    fn test_prune_drops_orders_closed_before_the_retention_period() {
+        let user_id = Uuid::new_v4();
+        let order = |price| {
+            Order::new_limit(
+                user_id,
+                OrderSide::Sell,
+                Price::new(price),
+                Quantity::new(5),
+            )
+        };
+        let (old, recent, open, unsaved) = (order(100), order(101), order(102), order(103));
+        let now = Utc::now();
+        let week = chrono::Duration::days(7);
+
+        let mut history = OrderHistory::new();
+        for order in [&old, &recent, &open] {
+            history.upsert(order);
+        }
+        history.mark_cancelled(old.id, now - week - chrono::Duration::seconds(1));
+        history.mark_cancelled(recent.id, now - chrono::Duration::hours(1));
+        history.take_unsaved();
+        history.upsert(&unsaved);
+        history.mark_cancelled(unsaved.id, now - week * 2);
+
+        assert_eq!(history.prune(now, week), 1);
+        assert!(history.get(old.id).is_none());
+        for kept in [&recent, &open, &unsaved] {
+            assert!(history.get(kept.id).is_some());
+        }
+        assert_eq!(history.for_user(user_id, 10).len(), 3);
+
+        // Saved now, but not swept again until the interval has passed
+        history.take_unsaved();
+        assert_eq!(history.prune(now, week), 0);
+        assert_eq!(history.prune(now + PRUNE_INTERVAL, week), 1);
+        assert!(history.get(unsaved.id).is_none());
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::Arc;
+
+use crate::engine::{EngineMetrics, OutboxRelay};
+use crate::storage::OutboxEvent;
+
+/// Stream name used when `NATS_STREAM` is not set
+pub const DEFAULT_NATS_STREAM: &str = "ORDERBOOK";
+/// Subject prefix used when `NATS_SUBJECT_PREFIX` is not set
+pub const DEFAULT_NATS_SUBJECT_PREFIX: &str = "orderbook";
+
+/// The JetStream stream outbox events are published to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JetStreamConfig {
+    pub url: String,            // Comma-separated NATS servers
+    pub stream: String,         // Created on first use, over every subject under the prefix
+    pub subject_prefix: String, // Events go to `<prefix>.<kind>`
+}
+
+impl JetStreamConfig {
+    /// From `NATS_URL`, `NATS_STREAM` and `NATS_SUBJECT_PREFIX`; None unless
+    /// a URL is set
+    pub fn from_env() -> Option<Self> {
+        let var = |key: &str| {
+            std::env::var(key)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+        };
+        Some(JetStreamConfig {
+            url: var("NATS_URL")?,
+            stream: var("NATS_STREAM").unwrap_or_else(|| DEFAULT_NATS_STREAM.to_string()),
+            subject_prefix: var("NATS_SUBJECT_PREFIX")
+                .unwrap_or_else(|| DEFAULT_NATS_SUBJECT_PREFIX.to_string()),
+        })
+    }
+
+    /// Where `event` is published, e.g. `orderbook.trade.executed`
+    pub fn subject(&self, event: &OutboxEvent) -> String {
+        format!("{}.{}", self.subject_prefix, event.kind.as_str())
+    }
+
+    /// Subjects the stream captures
+    pub fn stream_subjects(&self) -> String {
+        format!("{}.>", self.subject_prefix)
+    }
+}
+
+/// A relay that publishes each event to the configured stream. Must be called
+/// inside the Tokio runtime; the sender stops when the relay is dropped.
+#[cfg(feature = "nats")]
+pub fn jetstream_relay(
+    config: JetStreamConfig,
+    metrics: Arc<EngineMetrics>,
+) -> Option<OutboxRelay> {
+    let (relay, batches, acks) = OutboxRelay::new();
+    tokio::spawn(send_to_jetstream(config, batches, acks, metrics));
+    Some(relay)
+}
+
+/// Without the `nats` feature there is nothing to publish with, and events
+/// stay queued in the store
+#[cfg(not(feature = "nats"))]
+pub fn jetstream_relay(
+    config: JetStreamConfig,
+    _metrics: Arc<EngineMetrics>,
+) -> Option<OutboxRelay> {
+    eprintln!(
+        "NATS_URL is set to {}, but this build has no NATS support; rebuild with --features nats",
+        config.url
+    );
+    None
+}
+
+/// Publish each batch in order and wait for the stream to store each event,
+/// stopping at the first failure like the webhook sender. The event ID goes
+/// in the `Nats-Msg-Id` header, so the stream drops an event sent again after
+/// a crash if it arrives within its duplicate window.
+#[cfg(feature = "nats")]
+async fn send_to_jetstream(
+    config: JetStreamConfig,
+    mut batches: tokio::sync::mpsc::UnboundedReceiver<Vec<OutboxEvent>>,
+    acks: std::sync::mpsc::Sender<Vec<uuid::Uuid>>,
+    metrics: Arc<EngineMetrics>,
+) {
+    use async_nats::header::NATS_MESSAGE_ID;
+    use async_nats::jetstream::{self, stream};
+    use async_nats::{ConnectOptions, HeaderMap};
+
+    // Keeps reconnecting in the background; until then publishing fails and
+    // the events stay queued
+    let client = match ConnectOptions::new()
+        .retry_on_initial_connect()
+        .connect(config.url.as_str())
+        .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Outbox cannot reach NATS at {}: {}", config.url, e);
+            return;
+        }
+    };
+    let context = jetstream::new(client);
+    let mut stream_ready = false;
+
+    while let Some(batch) = batches.recv().await {
+        let mut delivered = Vec::new();
+        if !stream_ready {
+            let stream_config = stream::Config {
+                name: config.stream.clone(),
+                subjects: vec![config.stream_subjects()],
+                ..Default::default()
+            };
+            match context.get_or_create_stream(stream_config).await {
+                Ok(_) => stream_ready = true,
+                Err(e) => {
+                    eprintln!("Outbox cannot open stream {}: {}", config.stream, e);
+                    metrics.record_outbox_failure();
+                }
+            }
+        }
+        for event in batch.iter().filter(|_| stream_ready) {
+            let mut headers = HeaderMap::new();
+            headers.insert(NATS_MESSAGE_ID, event.id.to_string().as_str());
+            let payload = serde_json::to_vec(event).expect("outbox event serializes to JSON");
+            let stored = match context
+                .publish_with_headers(config.subject(event), headers, payload.into())
+                .await
+            {
+                Ok(ack) => ack.await.map(|_| ()).map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match stored {
+                Ok(()) => delivered.push(event.id),
+                Err(e) => {
+                    eprintln!("Outbox delivery of {} failed: {}", event.id, e);
+                    metrics.record_outbox_failure();
+                    break;
+                }
+            }
+        }
+        metrics.record_outbox_delivered(delivered.len());
+        if acks.send(delivered).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Price, Quantity, Trade};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_events_are_published_under_the_prefix_by_kind() {
+        let config = JetStreamConfig {
+            url: "nats://localhost:4222".to_string(),
+            stream: DEFAULT_NATS_STREAM.to_string(),
+            subject_prefix: "exchange.events".to_string(),
+        };
+        let trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Price::from_f64(100.0),
+            Quantity::from_f64(1.0),
+        );
+        let event = OutboxEvent::trade_executed(&trade);
+        assert_eq!(config.subject(&event), "exchange.events.trade.executed");
+        assert_eq!(config.stream_subjects(), "exchange.events.>");
+    }
+}
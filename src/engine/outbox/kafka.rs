@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use crate::engine::{EngineMetrics, OutboxRelay};
+use crate::storage::{OutboxEvent, OutboxEventKind};
+
+/// Topic prefix used when `KAFKA_TOPIC_PREFIX` is not set
+pub const DEFAULT_KAFKA_TOPIC_PREFIX: &str = "orderbook";
+/// How long an event may wait in the producer's queue before it counts as failed
+#[cfg(feature = "kafka")]
+const KAFKA_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Where the Kafka relay publishes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaConfig {
+    pub brokers: String,      // Comma-separated bootstrap servers
+    pub topic_prefix: String, // Topics are `<prefix>.trades`, `<prefix>.orders` and `<prefix>.balances`
+}
+
+/// One message bound for a topic. Records with the same key land on the same
+/// partition, so consumers see them in the order they were published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaRecord {
+    pub topic: String,
+    pub key: String,
+    pub payload: String,
+}
+
+impl KafkaConfig {
+    /// From `KAFKA_BROKERS` and `KAFKA_TOPIC_PREFIX`; None unless brokers are set
+    pub fn from_env() -> Option<Self> {
+        let brokers = std::env::var("KAFKA_BROKERS")
+            .ok()
+            .filter(|brokers| !brokers.trim().is_empty())?;
+        let topic_prefix = std::env::var("KAFKA_TOPIC_PREFIX")
+            .ok()
+            .filter(|prefix| !prefix.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_KAFKA_TOPIC_PREFIX.to_string());
+        Some(KafkaConfig {
+            brokers,
+            topic_prefix,
+        })
+    }
+
+    /// The record `event` is published as: trades keyed by trade, orders by
+    /// order and balances by user. The payload is the event as the webhook
+    /// receives it.
+    pub fn record(&self, event: &OutboxEvent) -> KafkaRecord {
+        let topic = match event.kind {
+            OutboxEventKind::TradeExecuted => "trades",
+            OutboxEventKind::OrderUpdated => "orders",
+            OutboxEventKind::BalanceChanged => "balances",
+        };
+        KafkaRecord {
+            topic: format!("{}.{}", self.topic_prefix, topic),
+            key: event.subject_id().to_string(),
+            payload: serde_json::to_string(event).expect("outbox event serializes to JSON"),
+        }
+    }
+}
+
+/// A relay that publishes each event to its topic. Must be called inside the
+/// Tokio runtime; the sender stops when the relay is dropped.
+#[cfg(feature = "kafka")]
+pub fn kafka_relay(config: KafkaConfig, metrics: Arc<EngineMetrics>) -> Option<OutboxRelay> {
+    use rdkafka::config::ClientConfig;
+    use rdkafka::producer::FutureProducer;
+
+    let producer: FutureProducer = match ClientConfig::new()
+        .set("bootstrap.servers", &config.brokers)
+        .set("enable.idempotence", "true")
+        .create()
+    {
+        Ok(producer) => producer,
+        Err(e) => {
+            eprintln!("Outbox cannot create a Kafka producer: {}", e);
+            return None;
+        }
+    };
+    let (relay, batches, acks) = OutboxRelay::new();
+    tokio::spawn(send_to_kafka(config, producer, batches, acks, metrics));
+    Some(relay)
+}
+
+/// Without the `kafka` feature there is nothing to publish with, and events
+/// stay queued in the store
+#[cfg(not(feature = "kafka"))]
+pub fn kafka_relay(config: KafkaConfig, _metrics: Arc<EngineMetrics>) -> Option<OutboxRelay> {
+    eprintln!(
+        "KAFKA_BROKERS is set to {}, but this build has no Kafka support; rebuild with --features kafka",
+        config.brokers
+    );
+    None
+}
+
+/// Publish each batch in order and wait for the brokers to acknowledge each
+/// event, stopping at the first failure like the webhook sender. The event ID
+/// goes in the `event_id` header, so consumers can drop an event sent again
+/// after a crash.
+#[cfg(feature = "kafka")]
+async fn send_to_kafka(
+    config: KafkaConfig,
+    producer: rdkafka::producer::FutureProducer,
+    mut batches: tokio::sync::mpsc::UnboundedReceiver<Vec<OutboxEvent>>,
+    acks: std::sync::mpsc::Sender<Vec<uuid::Uuid>>,
+    metrics: Arc<EngineMetrics>,
+) {
+    use rdkafka::message::{Header, OwnedHeaders};
+    use rdkafka::producer::FutureRecord;
+
+    while let Some(batch) = batches.recv().await {
+        let mut delivered = Vec::new();
+        for event in batch {
+            let record = config.record(&event);
+            let id = event.id.to_string();
+            let headers = OwnedHeaders::new().insert(Header {
+                key: "event_id",
+                value: Some(&id),
+            });
+            let stored = producer
+                .send(
+                    FutureRecord::to(&record.topic)
+                        .key(&record.key)
+                        .payload(&record.payload)
+                        .headers(headers),
+                    KAFKA_TIMEOUT,
+                )
+                .await;
+            match stored {
+                Ok(_) => delivered.push(event.id),
+                Err((e, _)) => {
+                    eprintln!("Outbox delivery of {} failed: {}", event.id, e);
+                    metrics.record_outbox_failure();
+                    break;
+                }
+            }
+        }
+        metrics.record_outbox_delivered(delivered.len());
+        if acks.send(delivered).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Order, OrderSide, Price, Quantity, Trade};
+    use chrono::DateTime;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    fn config() -> KafkaConfig {
+        KafkaConfig {
+            brokers: "localhost:9092".to_string(),
+            topic_prefix: "ob".to_string(),
+        }
+    }
+
+    fn payload(record: &KafkaRecord) -> serde_json::Value {
+        serde_json::from_str(&record.payload).unwrap()
+    }
+
+    #[test]
+    fn test_outbox_events_go_to_a_topic_per_kind() {
+        let user_id = Uuid::from_u128(1);
+        let mut order = Order::new_limit(
+            user_id,
+            OrderSide::Buy,
+            Price::from_f64(100.0),
+            Quantity::from_f64(2.0),
+        );
+        order.id = Uuid::from_u128(2);
+        order.timestamp = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let event = OutboxEvent::order_updated(&order);
+        let record = config().record(&event);
+        assert_eq!(
+            (record.topic.as_str(), record.key.as_str()),
+            ("ob.orders", "00000000-0000-0000-0000-000000000002")
+        );
+        // Fails when a published field changes; that calls for a version 2
+        assert_eq!(
+            payload(&record),
+            serde_json::json!({
+                "id": event.id,
+                "kind": "order.updated",
+                "version": 1,
+                "order": {
+                    "id": "00000000-0000-0000-0000-000000000002",
+                    "user_id": "00000000-0000-0000-0000-000000000001",
+                    "client_order_id": null,
+                    "side": "Buy",
+                    "order_type": "Limit",
+                    "time_in_force": "GTC",
+                    "price": 100000000,
+                    "original_quantity": 200000000,
+                    "remaining_quantity": 200000000,
+                    "status": "Open",
+                    "source": "web",
+                    "timestamp": "2023-11-14T22:13:20Z"
+                }
+            })
+        );
+
+        let trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            user_id,
+            Uuid::new_v4(),
+            Price::from_f64(100.0),
+            Quantity::from_f64(0.5),
+        );
+        let record = config().record(&OutboxEvent::trade_executed(&trade));
+        assert_eq!(record.topic, "ob.trades");
+        assert_eq!(record.key, trade.id.to_string());
+        assert_eq!(payload(&record)["kind"], "trade.executed");
+
+        let balances = HashMap::from([("USD".to_string(), 50.0), ("BTC".to_string(), 1.5)]);
+        let record = config().record(&OutboxEvent::balance_changed(user_id, &balances));
+        assert_eq!(record.topic, "ob.balances");
+        assert_eq!(record.key, user_id.to_string());
+        assert_eq!(
+            payload(&record)["balance"],
+            serde_json::json!({ "user_id": user_id, "balances": { "BTC": 1.5, "USD": 50.0 } })
+        );
+    }
+}
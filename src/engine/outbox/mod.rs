@@ -0,0 +1,266 @@
+/// Delivery to a NATS JetStream stream, for deployments that run NATS
+pub mod jetstream;
+/// Delivery to Kafka topics, for analytics and surveillance
+pub mod kafka;
+
+use std::collections::HashSet;
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::engine::EngineMetrics;
+use crate::storage::{OutboxEvent, Store};
+use jetstream::{jetstream_relay, JetStreamConfig};
+use kafka::{kafka_relay, KafkaConfig};
+
+/// How many pending events are handed to the sender at once
+const OUTBOX_BATCH: usize = 100;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pause after a failed delivery before events are offered again, doubled for
+/// each failure in a row up to the cap
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Where queued outbox events are delivered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutboxTarget {
+    Webhook(String),            // POSTed to this URL
+    JetStream(JetStreamConfig), // Published to a NATS JetStream stream
+    Kafka(KafkaConfig),         // Published to Kafka topics by kind
+}
+
+/// Moves queued outbox events to their receiver. It runs on the history worker
+/// beside the store, while the network calls happen on a task of their own.
+///
+/// An event is only marked delivered after the receiver accepted it, so one
+/// that was sent just before a crash is sent again on restart. It keeps its ID
+/// across retries and receivers drop repeats by that ID.
+pub struct OutboxRelay {
+    batches: mpsc::UnboundedSender<Vec<OutboxEvent>>,
+    acks: std_mpsc::Receiver<Vec<Uuid>>, // What the sender delivered, one reply per batch
+    unmarked: HashSet<Uuid>,             // Delivered but not yet recorded as such
+    in_flight: Option<usize>,            // Size of the batch being sent
+    retry_delay: Duration,               // Zero until a delivery fails
+    retry_at: Option<Instant>,
+}
+
+impl OutboxRelay {
+    /// A relay and the ends its sender works from: the batches to send and
+    /// where to report what was delivered
+    pub fn new() -> (
+        Self,
+        mpsc::UnboundedReceiver<Vec<OutboxEvent>>,
+        std_mpsc::Sender<Vec<Uuid>>,
+    ) {
+        let (batches, batches_rx) = mpsc::unbounded_channel();
+        let (acks_tx, acks) = std_mpsc::channel();
+        let relay = OutboxRelay {
+            batches,
+            acks,
+            unmarked: HashSet::new(),
+            in_flight: None,
+            retry_delay: Duration::ZERO,
+            retry_at: None,
+        };
+        (relay, batches_rx, acks_tx)
+    }
+
+    /// A relay that POSTs each event to `url`. Must be called inside the Tokio
+    /// runtime; the sender stops when the relay is dropped.
+    pub fn webhook(url: String, metrics: Arc<EngineMetrics>) -> Self {
+        let (relay, batches, acks) = Self::new();
+        tokio::spawn(send_webhooks(url, batches, acks, metrics));
+        relay
+    }
+
+    /// A relay delivering to `target`, or None when this build cannot
+    pub fn to(target: OutboxTarget, metrics: Arc<EngineMetrics>) -> Option<Self> {
+        match target {
+            OutboxTarget::Webhook(url) => Some(Self::webhook(url, metrics)),
+            OutboxTarget::JetStream(config) => jetstream_relay(config, metrics),
+            OutboxTarget::Kafka(config) => kafka_relay(config, metrics),
+        }
+    }
+
+    /// Record what was delivered since the last step, then hand over the next
+    /// batch once the previous one is done. After a failure nothing is handed
+    /// over until the retry delay has passed.
+    pub fn step(&mut self, store: &mut dyn Store) {
+        self.step_at(store, Instant::now());
+    }
+
+    fn step_at(&mut self, store: &mut dyn Store, now: Instant) {
+        while let Ok(delivered) = self.acks.try_recv() {
+            // The sender stops at the first event it could not deliver
+            if delivered.len() < self.in_flight.take().unwrap_or(0) {
+                self.back_off(now);
+            } else {
+                self.retry_delay = Duration::ZERO;
+                self.retry_at = None;
+            }
+            self.unmarked.extend(delivered);
+        }
+
+        if !self.unmarked.is_empty() {
+            let ids: Vec<Uuid> = self.unmarked.iter().copied().collect();
+            match store.mark_delivered(&ids, Utc::now()) {
+                Ok(()) => self.unmarked.clear(),
+                Err(e) => eprintln!("Failed to mark outbox events delivered: {}", e),
+            }
+        }
+
+        if self.in_flight.is_some() || self.retry_at.is_some_and(|at| now < at) {
+            return;
+        }
+        let batch: Vec<OutboxEvent> = match store.pending_events(OUTBOX_BATCH) {
+            // Events still waiting to be marked must not go out twice
+            Ok(events) => events
+                .into_iter()
+                .filter(|event| !self.unmarked.contains(&event.id))
+                .collect(),
+            Err(e) => {
+                eprintln!("Failed to read the outbox: {}", e);
+                self.back_off(now);
+                return;
+            }
+        };
+        if batch.is_empty() {
+            return;
+        }
+        let size = batch.len();
+        if self.batches.send(batch).is_err() {
+            eprintln!("Outbox sender has stopped; events stay queued");
+            return;
+        }
+        self.in_flight = Some(size);
+    }
+
+    fn back_off(&mut self, now: Instant) {
+        self.retry_delay = (self.retry_delay * 2).clamp(RETRY_DELAY, MAX_RETRY_DELAY);
+        self.retry_at = Some(now + self.retry_delay);
+    }
+}
+
+/// Send each batch in order, stopping at the first failure so later events
+/// never overtake an earlier one. The rest are offered again on a later step.
+async fn send_webhooks(
+    url: String,
+    mut batches: mpsc::UnboundedReceiver<Vec<OutboxEvent>>,
+    acks: std_mpsc::Sender<Vec<Uuid>>,
+    metrics: Arc<EngineMetrics>,
+) {
+    let client = reqwest::Client::new();
+    while let Some(batch) = batches.recv().await {
+        let mut delivered = Vec::new();
+        for event in batch {
+            let sent = client
+                .post(&url)
+                .header("Idempotency-Key", event.id.to_string())
+                .timeout(WEBHOOK_TIMEOUT)
+                .json(&event)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match sent {
+                Ok(_) => delivered.push(event.id),
+                Err(e) => {
+                    eprintln!("Outbox delivery of {} failed: {}", event.id, e);
+                    metrics.record_outbox_failure();
+                    break;
+                }
+            }
+        }
+        metrics.record_outbox_delivered(delivered.len());
+        if acks.send(delivered).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStore, OutboxStore, TradeStore};
+    use crate::types::{Price, Quantity, Trade};
+
+    fn trade() -> Trade {
+        Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Price::from_f64(100.0),
+            Quantity::from_f64(1.0),
+        )
+    }
+
+    #[test]
+    fn test_each_trade_is_sent_until_acknowledged_and_never_again() {
+        let mut store = MemoryStore::new();
+        let (first, second) = (trade(), trade());
+        store.save_trades(&[first.clone(), second.clone()]).unwrap();
+        let (mut relay, mut batches, acks) = OutboxRelay::new();
+        let now = Instant::now();
+
+        relay.step_at(&mut store, now);
+        let batch = batches.try_recv().unwrap();
+        let ids: Vec<Uuid> = batch.iter().map(|event| event.subject_id()).collect();
+        assert_eq!(ids, vec![first.id, second.id]);
+
+        // Nothing more goes out while that batch is being sent
+        relay.step_at(&mut store, now);
+        assert!(batches.try_recv().is_err());
+
+        // The receiver took the first event and failed on the second, which
+        // waits out the retry delay
+        acks.send(vec![batch[0].id]).unwrap();
+        relay.step_at(&mut store, now);
+        assert!(batches.try_recv().is_err());
+        relay.step_at(&mut store, now + RETRY_DELAY);
+        let retry = batches.try_recv().unwrap();
+        assert_eq!(retry.len(), 1);
+        assert_eq!(retry[0].id, batch[1].id);
+        assert_eq!(store.pending_events(10).unwrap().len(), 1);
+
+        acks.send(vec![retry[0].id]).unwrap();
+        relay.step_at(&mut store, now + RETRY_DELAY);
+        assert!(batches.try_recv().is_err());
+        assert!(store.pending_events(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_retries_back_off_until_a_delivery_succeeds() {
+        let mut store = MemoryStore::new();
+        store.save_trades(&[trade()]).unwrap();
+        let (mut relay, mut batches, acks) = OutboxRelay::new();
+        let mut now = Instant::now();
+
+        for delay in [1, 2, 4, 8].map(|n| RETRY_DELAY * n) {
+            relay.step_at(&mut store, now);
+            assert_eq!(batches.try_recv().unwrap().len(), 1);
+            acks.send(vec![]).unwrap();
+            relay.step_at(&mut store, now);
+            relay.step_at(&mut store, now + delay - Duration::from_millis(1));
+            assert!(batches.try_recv().is_err());
+            now += delay;
+        }
+        assert_eq!(relay.retry_delay, RETRY_DELAY * 8);
+
+        // Success resets the delay for the next failure
+        relay.step_at(&mut store, now);
+        let batch = batches.try_recv().unwrap();
+        acks.send(vec![batch[0].id]).unwrap();
+        relay.step_at(&mut store, now);
+        assert_eq!(relay.retry_delay, Duration::ZERO);
+        assert!(store.pending_events(10).unwrap().is_empty());
+
+        for _ in 0..20 {
+            relay.back_off(now);
+        }
+        assert_eq!(relay.retry_delay, MAX_RETRY_DELAY);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::types::Trade;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Longest window averages can be asked for
+pub const MAX_AVERAGE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Trades of one second, folded together
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct SecondBucket {
+    second: i64, // Unix seconds
+    notional: f64,
+    volume: f64,
+    trades: u64,
+    last_price: f64,
+}
+
+/// Volume- and time-weighted average trade price over a trailing window
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceAverages {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub trades: u64,
+    pub volume: f64,
+    pub vwap: Option<f64>, // None without trades in the window
+    pub twap: Option<f64>, // None until a trade is known at some point in the window
+}
+
+/// Keeps a day of trades, one bucket per second, so any window up to
+/// `MAX_AVERAGE_WINDOW` can be averaged in time bounded by its length
+/// rather than by how much traded
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct PriceAverageTracker {
+    buckets: VecDeque<SecondBucket>, // Oldest first
+}
+
+impl PriceAverageTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, trades: &[Trade], now: DateTime<Utc>) {
+        let second = now.timestamp();
+        for trade in trades {
+            let price = trade.price.to_f64();
+            let volume = trade.quantity.to_f64();
+            match self.buckets.back_mut() {
+                Some(bucket) if bucket.second == second => {
+                    bucket.notional += price * volume;
+                    bucket.volume += volume;
+                    bucket.trades += 1;
+                    bucket.last_price = price;
+                }
+                _ => self.buckets.push_back(SecondBucket {
+                    second,
+                    notional: price * volume,
+                    volume,
+                    trades: 1,
+                    last_price: price,
+                }),
+            }
+        }
+
+        // Keep one bucket from before the longest window; its price is where
+        // the time-weighted average of that window starts
+        let horizon = second - MAX_AVERAGE_WINDOW.as_secs() as i64;
+        while self.buckets.len() > 1 && self.buckets[1].second <= horizon {
+            self.buckets.pop_front();
+        }
+    }
+
+    /// Averages over the `window` ending at `now`, to the second. The TWAP
+    /// holds each second's last trade price until the next trade, starting
+    /// from the last price before the window if there is one.
+    pub fn averages(&self, window: Duration, now: DateTime<Utc>) -> PriceAverages {
+        let end = now.timestamp();
+        let start = end - window.as_secs() as i64;
+        let first_inside = self.buckets.partition_point(|b| b.second <= start);
+
+        let mut averages = PriceAverages {
+            from: DateTime::from_timestamp(start, 0).unwrap_or(now),
+            to: now,
+            trades: 0,
+            volume: 0.0,
+            vwap: None,
+            twap: None,
+        };
+        let mut notional = 0.0;
+        let mut price = first_inside
+            .checked_sub(1)
+            .map(|before| self.buckets[before].last_price);
+        let (mut since, mut weighted, mut weight) = (start, 0.0, 0);
+        for bucket in self.buckets.iter().skip(first_inside) {
+            if bucket.second > end {
+                break;
+            }
+            averages.trades += bucket.trades;
+            averages.volume += bucket.volume;
+            notional += bucket.notional;
+            if let Some(price) = price {
+                weighted += price * (bucket.second - since) as f64;
+                weight += bucket.second - since;
+            }
+            price = Some(bucket.last_price);
+            since = bucket.second;
+        }
+        if let Some(price) = price {
+            weighted += price * (end - since) as f64;
+            weight += end - since;
+            averages.twap = Some(if weight > 0 {
+                weighted / weight as f64
+            } else {
+                price
+            });
+        }
+        if averages.volume > 0.0 {
+            averages.vwap = Some(notional / averages.volume);
+        }
+        averages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Price, Quantity};
+    use uuid::Uuid;
+
+    fn trade(price: f64, quantity: f64) -> Trade {
+        Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Price::from_f64(price),
+            Quantity::from_f64(quantity),
+        )
+    }
+
+    #[test]
+    fn test_vwap_weighs_by_volume_and_twap_by_time_held() {
+        let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let at = |secs| t0 + chrono::Duration::seconds(secs);
+        let mut tracker = PriceAverageTracker::new();
+        tracker.record(&[trade(90.0, 5.0)], at(-100)); // Before the window
+        tracker.record(&[trade(100.0, 1.0), trade(110.0, 1.0)], at(0));
+        tracker.record(&[trade(130.0, 2.0)], at(30));
+
+        let window = Duration::from_secs(60);
+        let averages = tracker.averages(window, at(40));
+        assert_eq!(averages.trades, 3);
+        assert_eq!(averages.volume, 4.0);
+        assert_eq!(averages.vwap, Some(117.5));
+        // 90 for 20s, 110 for 30s, then 130 for 10s
+        let twap = (90.0 * 20.0 + 110.0 * 30.0 + 130.0 * 10.0) / 60.0;
+        assert_eq!(averages.twap, Some(twap));
+
+        // A quiet window still knows the price it held at
+        let quiet = tracker.averages(Duration::from_secs(5), at(40));
+        assert_eq!((quiet.trades, quiet.vwap), (0, None));
+        assert_eq!(quiet.twap, Some(130.0));
+
+        // Without an earlier price, time before the first trade is left out
+        let fresh = PriceAverageTracker::new().averages(window, at(40));
+        assert_eq!((fresh.vwap, fresh.twap), (None, None));
+        let mut tracker = PriceAverageTracker::new();
+        tracker.record(&[trade(100.0, 1.0)], at(20));
+        tracker.record(&[trade(200.0, 1.0)], at(30));
+        assert_eq!(tracker.averages(window, at(40)).twap, Some(150.0));
+    }
+
+    #[test]
+    fn test_only_a_day_is_kept() {
+        let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut tracker = PriceAverageTracker::new();
+        for hour in 0..30 {
+            tracker.record(&[trade(100.0, 1.0)], t0 + chrono::Duration::hours(hour));
+        }
+        assert_eq!(tracker.buckets.len(), 25);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::types::{OrderSide, OrderSource, Price, Quantity, TimeInForce};
+use chrono::Utc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+/// Price simulator bots quote around while the book is empty
+pub const SIMULATOR_START_PRICE: f64 = 50_000.0;
+
+/// Time between a simulator bot's turns in the server
+pub const SIMULATOR_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a bot waits on a query before skipping its turn
+const SIMULATOR_QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Send one command and wait for its reply. None once the engine is gone.
+async fn request(
+    tx: &mpsc::Sender<OrderBookCommand>,
+    command: impl FnOnce(oneshot::Sender<OrderBookResponse>) -> OrderBookCommand,
+) -> Option<OrderBookResponse> {
+    let (response_tx, response_rx) = oneshot::channel();
+    tx.send(command(response_tx)).await.ok()?;
+    response_rx.await.ok()
+}
+
+/// xorshift64: only picks prices and sizes, nothing depends on its quality
+struct Noise(u64);
+
+impl Noise {
+    /// Uniform in [low, high)
+    fn range(&mut self, low: f64, high: f64) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        low + (high - low) * ((self.0 >> 11) as f64 / (1u64 << 53) as f64)
+    }
+}
+
+/// A market-making bot run inside the server so a fresh dev book has something
+/// to trade against. It funds itself, then every turn replaces its bid and ask
+/// around the mid and now and then crosses the spread, one turn per `interval`.
+/// Returns when the engine shuts down.
+pub async fn run_simulator_bot(tx: mpsc::Sender<OrderBookCommand>, seed: u64, interval: Duration) {
+    let user_id = Uuid::new_v4();
+    let mut noise = Noise(seed.max(1));
+
+    for (currency, amount) in [("USD", 1_000_000.0), ("BTC", 20.0)] {
+        let funded = request(&tx, |response_tx| OrderBookCommand::AddFunds {
+            user_id,
+            currency: currency.to_string(),
+            amount,
+            response_tx,
+        })
+        .await;
+        if funded.is_none() {
+            return;
+        }
+    }
+
+    let mut quotes: Vec<Uuid> = Vec::new();
+    let mut turns = tokio::time::interval(interval);
+    loop {
+        turns.tick().await;
+
+        for order_id in quotes.drain(..) {
+            let cancelled = request(&tx, |response_tx| OrderBookCommand::CancelOrder {
+                user_id,
+                order_id,
+                response_tx,
+            })
+            .await;
+            if cancelled.is_none() {
+                return;
+            }
+        }
+
+        let depth = request(&tx, |response_tx| OrderBookCommand::GetOrderBook {
+            depth: 1,
+            deadline: Instant::now() + SIMULATOR_QUERY_TIMEOUT,
+            response_tx,
+        })
+        .await;
+        let mid = match depth {
+            Some(OrderBookResponse::OrderBookDepth { bids, asks }) => {
+                match (bids.first(), asks.first()) {
+                    (Some(bid), Some(ask)) => (bid.price.to_f64() + ask.price.to_f64()) / 2.0,
+                    (Some(level), None) | (None, Some(level)) => level.price.to_f64(),
+                    (None, None) => SIMULATOR_START_PRICE,
+                }
+            }
+            Some(_) => continue,
+            None => return,
+        };
+
+        // Wander a little so the other bots' quotes get hit
+        let mid = mid * (1.0 + noise.range(-0.001, 0.001));
+        let half_spread = mid * noise.range(0.0005, 0.002);
+        for (side, price) in [
+            (OrderSide::Buy, mid - half_spread),
+            (OrderSide::Sell, mid + half_spread),
+        ] {
+            let quantity = Quantity::from_f64(noise.range(0.01, 0.2));
+            let placed = request(&tx, |response_tx| OrderBookCommand::PlaceLimitOrder {
+                user_id,
+                side,
+                price: Price::from_f64(price),
+                quantity,
+                time_in_force: TimeInForce::GTC,
+                display_quantity: None,
+                hidden: false,
+                min_fill_qty: None,
+                expires_at: None,
+                peg: None,
+                trade_through_protected: false,
+                priority_fee: 0.0,
+                received_at: Utc::now(),
+                source: OrderSource::Algo,
+                client_order_id: None,
+                response_tx,
+            })
+            .await;
+            match placed {
+                Some(OrderBookResponse::OrderPlaced { order_id, .. }) => quotes.push(order_id),
+                Some(_) => {}
+                None => return,
+            }
+        }
+
+        if noise.range(0.0, 1.0) < 0.2 {
+            let side = if noise.range(0.0, 1.0) < 0.5 {
+                OrderSide::Buy
+            } else {
+                OrderSide::Sell
+            };
+            let taken = request(&tx, |response_tx| OrderBookCommand::PlaceMarketOrder {
+                user_id,
+                side,
+                quantity: Quantity::from_f64(noise.range(0.005, 0.05)),
+                slippage: None,
+                received_at: Utc::now(),
+                source: OrderSource::Algo,
+                client_order_id: None,
+                response_tx,
+            })
+            .await;
+            if taken.is_none() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{event_channel, run_orderbook_engine, EngineConfig, EngineMetrics};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_bots_keep_both_sides_of_the_book_quoted() {
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(run_orderbook_engine(
+            rx,
+            Arc::new(EngineMetrics::new()),
+            event_channel(),
+            EngineConfig::default(),
+        ));
+        let interval = Duration::from_millis(10);
+        for seed in 1..=2 {
+            tokio::spawn(run_simulator_bot(tx.clone(), seed, interval));
+        }
+        tokio::time::sleep(interval * 10).await;
+
+        let depth = request(&tx, |response_tx| OrderBookCommand::GetOrderBook {
+            depth: 5,
+            deadline: Instant::now() + SIMULATOR_QUERY_TIMEOUT,
+            response_tx,
+        })
+        .await;
+        match depth {
+            Some(OrderBookResponse::OrderBookDepth { bids, asks }) => {
+                assert!(!bids.is_empty() && !asks.is_empty());
+                assert!(bids[0].price < asks[0].price);
+            }
+            _ => panic!("unexpected response"),
+        }
+    }
+}
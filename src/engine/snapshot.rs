@@ -0,0 +1,196 @@
+use crate::engine::{
+    ClientIdRecords, DayInProgress, DeadLetterQueue, DeadManSwitches, ExecutionQualityTracker,
+    IncidentLog, InterestState, MarginPosition, MarginSettings, OrderHistory, PriceAverageTracker,
+    RecentOrder, SourceVolumeTracker, SyntheticIndices, TriggerBook,
+};
+use crate::ledger::{Account, FxRate, LedgerAmount};
+use crate::types::{MarketConfig, Order, Price, UserBalance};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Format of snapshot files; one written in another is not used
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// What the engine needs to carry on, as it stood once command `wal_seq` of
+/// the log `log_id` had run. Restoring it and replaying the log's later
+/// commands gives back the state the engine had when it stopped. History the
+/// store already keeps, closed orders and past journals and trades, is left
+/// out, so a snapshot grows with open positions rather than with time.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    pub version: u32,
+    pub log_id: Uuid,
+    pub wal_seq: u64,
+    pub taken_at: DateTime<Utc>, // Engine clock
+    pub clock_offset: Duration,
+    pub market: MarketConfig,
+    pub settlement_blocked: bool,
+    #[serde(default)]
+    pub positions: Vec<(Uuid, MarginPosition)>, // Open in the market, by user
+    pub orders: Vec<Order>, // Resting: bids best first, then asks, each level in queue order
+    pub balances: Vec<UserBalance>, // Available and reserved; resting orders hold the reserved part
+    pub last_trade_price: Option<Price>,
+    pub rolling_mark_price: Option<Price>,
+    pub session_open_price: Option<Price>,
+    pub session_date: Option<NaiveDate>,
+    pub pegged: Vec<Uuid>,
+    pub peg_reference: (Option<Price>, Option<Price>),
+    pub stops: TriggerBook,
+    pub synthetics: SyntheticIndices,
+    pub client_ids: ClientIdRecords,
+    pub recent_orders: Vec<RecentOrder>, // Seen by the duplicate order guard
+    pub order_history: OrderHistory,     // Open orders only, with their fills
+    pub margin: MarginSettings,
+    pub interest: InterestState,
+    pub dead_man: DeadManSwitches,
+    pub incidents: IncidentLog,
+    pub dead_letters: DeadLetterQueue,
+    pub fx_rates: Vec<FxRate>,
+    pub fx_version: u64,
+    pub ledger_balances: Vec<(Account, LedgerAmount)>,
+    pub last_journal_id: u64, // The ledger numbers on from it
+    pub tape_seq: u64,        // Last print on the trade tape; the next is numbered after it
+    pub market_seq: u64,      // Last public message on the market feed
+    pub execution_quality: ExecutionQualityTracker,
+    pub source_volume: SourceVolumeTracker,
+    pub price_averages: PriceAverageTracker,
+    pub daily_stats: DayInProgress,
+}
+
+impl EngineSnapshot {
+    /// Write the snapshot to `path` by way of a temporary file beside it, so
+    /// a crash part way through leaves the previous snapshot in place
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let describe = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(describe)?;
+        }
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+
+        let file = File::create(&temp_path).map_err(describe)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self).map_err(|e| e.to_string())?;
+        writer.flush().map_err(describe)?;
+        let file = writer.into_inner().map_err(|e| describe(e.into_error()))?;
+        file.sync_all().map_err(describe)?;
+        fs::rename(&temp_path, path).map_err(describe)
+    }
+
+    /// The snapshot at `path`, None if none was written yet
+    pub fn read(path: &Path) -> Result<Option<Self>, String> {
+        let describe = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(describe(e)),
+        };
+        let snapshot: EngineSnapshot = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        snapshot
+            .check_version()
+            .map(Some)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The log and command the snapshot at `path` was taken at, without
+    /// keeping its state; None if there is none this build could restore
+    pub fn position(path: &Path) -> Option<(Uuid, u64)> {
+        let file = File::open(path).ok()?;
+        serde_json::from_reader::<_, SnapshotPosition>(BufReader::new(file))
+            .ok()?
+            .usable()
+    }
+
+    /// `position`, of a snapshot in its JSON
+    pub fn position_in(bytes: &[u8]) -> Option<(Uuid, u64)> {
+        serde_json::from_slice::<SnapshotPosition>(bytes)
+            .ok()?
+            .usable()
+    }
+
+    /// A snapshot from its JSON, as `write` puts it in a file
+    pub fn from_json(bytes: &[u8]) -> Result<Self, String> {
+        let snapshot: EngineSnapshot = serde_json::from_slice(bytes).map_err(|e| e.to_string())?;
+        snapshot.check_version()
+    }
+
+    fn check_version(self) -> Result<Self, String> {
+        if self.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot version {} is not the {} this build writes",
+                self.version, SNAPSHOT_VERSION
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// The fields of a snapshot that say where in which log it was taken
+#[derive(Deserialize)]
+struct SnapshotPosition {
+    version: u32,
+    log_id: Uuid,
+    wal_seq: u64,
+}
+
+impl SnapshotPosition {
+    fn usable(self) -> Option<(Uuid, u64)> {
+        (self.version == SNAPSHOT_VERSION).then_some((self.log_id, self.wal_seq))
+    }
+}
+
+/// What the engine rebuilt on startup, and from where
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecoveryReport {
+    pub log_id: Uuid,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub snapshot_seq: Option<u64>, // None when the whole log was replayed
+    pub snapshot_taken_at: Option<DateTime<Utc>>,
+    pub snapshot_error: Option<String>, // Why a snapshot on disk was not used
+    pub commands_replayed: usize,
+    pub last_seq: u64,
+    pub resting_orders: usize,
+    pub stop_orders: usize,
+    pub users: usize,
+    pub available: BTreeMap<String, f64>, // Summed over every user, per currency
+    pub reserved: BTreeMap<String, f64>,  // Held by resting orders, per currency
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{Engine, EngineConfig, EngineMetrics};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_snapshot_survives_a_round_trip_through_its_file() {
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", Uuid::new_v4()));
+        assert!(EngineSnapshot::read(&path).unwrap().is_none());
+
+        let engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let log_id = Uuid::new_v4();
+        let snapshot = engine.snapshot(log_id, 42).unwrap();
+        snapshot.write(&path).unwrap();
+        snapshot.write(&path).unwrap();
+
+        let read = EngineSnapshot::read(&path).unwrap().unwrap();
+        assert_eq!((read.log_id, read.wal_seq), (log_id, 42));
+        assert_eq!(read.market, snapshot.market);
+        assert_eq!(read.taken_at, snapshot.taken_at);
+
+        // A snapshot in another format is not taken for this one
+        let mut other = serde_json::to_value(&snapshot).unwrap();
+        other["version"] = serde_json::json!(SNAPSHOT_VERSION + 1);
+        fs::write(&path, other.to_string()).unwrap();
+        assert!(EngineSnapshot::read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use crate::types::{OrderSource, Trade};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Traded volume attributed to one order channel, split by liquidity role
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourceVolume {
+    pub source: OrderSource,
+    pub maker_trades: u64,
+    pub maker_volume: f64,
+    pub maker_quote_volume: f64,
+    pub taker_trades: u64,
+    pub taker_volume: f64,
+    pub taker_quote_volume: f64,
+}
+
+/// Per-channel volume since startup, for analytics and channel-specific pricing
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SourceVolumeTracker {
+    by_source: HashMap<OrderSource, SourceVolume>,
+}
+
+impl SourceVolumeTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, trades: &[Trade]) {
+        for trade in trades {
+            let volume = trade.quantity.to_f64();
+            let quote_volume = trade.price.to_f64() * volume;
+
+            let maker = self.entry(trade.maker_source);
+            maker.maker_trades += 1;
+            maker.maker_volume += volume;
+            maker.maker_quote_volume += quote_volume;
+
+            let taker = self.entry(trade.taker_source);
+            taker.taker_trades += 1;
+            taker.taker_volume += volume;
+            taker.taker_quote_volume += quote_volume;
+        }
+    }
+
+    fn entry(&mut self, source: OrderSource) -> &mut SourceVolume {
+        self.by_source.entry(source).or_insert_with(|| SourceVolume {
+            source,
+            ..SourceVolume::default()
+        })
+    }
+
+    /// All channels that have traded, largest quote volume first
+    pub fn snapshot(&self) -> Vec<SourceVolume> {
+        let mut stats: Vec<SourceVolume> = self.by_source.values().cloned().collect();
+        stats.sort_by(|a, b| {
+            let total = |s: &SourceVolume| s.maker_quote_volume + s.taker_quote_volume;
+            total(b).total_cmp(&total(a))
+        });
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Price, Quantity};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_volume_split_by_source_and_role() {
+        let trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Price::from_f64(100.0),
+            Quantity::from_f64(2.0),
+        )
+        .with_sources(OrderSource::Algo, OrderSource::Web);
+
+        let mut tracker = SourceVolumeTracker::new();
+        tracker.record(&[trade.clone(), trade]);
+        let stats = tracker.snapshot();
+
+        assert_eq!(stats.len(), 2);
+        let algo = stats.iter().find(|s| s.source == OrderSource::Algo).unwrap();
+        assert_eq!(algo.maker_trades, 2);
+        assert_eq!(algo.maker_quote_volume, 400.0);
+        assert_eq!(algo.taker_trades, 0);
+        let web = stats.iter().find(|s| s.source == OrderSource::Web).unwrap();
+        assert_eq!(web.taker_volume, 4.0);
+    }
+}
@@ -0,0 +1,310 @@
+use crate::engine::{StopOrder, TriggerBook};
+use crate::types::{Price, SyntheticIndex};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// A synthetic index with its latest value, as listed to clients
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyntheticQuote {
+    pub index: SyntheticIndex,
+    pub value: Option<Price>, // None until every component has traded, or while it can't be valued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>, // Why the last valuation failed
+}
+
+/// The synthetic indices defined on this engine, their latest values, and the
+/// stop orders waiting for an index to reach their trigger
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SyntheticIndices {
+    definitions: BTreeMap<String, SyntheticIndex>,
+    values: HashMap<String, Price>,
+    #[serde(default)]
+    failures: HashMap<String, String>, // By index symbol, until it values again
+    stops: HashMap<String, TriggerBook>, // By index symbol
+    #[serde(skip)]
+    path: Option<PathBuf>, // Where the definitions are kept; None keeps them in memory only
+}
+
+impl SyntheticIndices {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the definitions kept at `path`, if it exists. Each is checked
+    /// again as it is loaded, so a hand-edited file can't bring in an index
+    /// the admin endpoint would refuse.
+    pub fn open(path: Option<PathBuf>) -> Result<Self, String> {
+        let mut indices = SyntheticIndices {
+            path,
+            ..Self::default()
+        };
+        let Some(path) = indices.path.as_ref().filter(|p| p.exists()) else {
+            return Ok(indices);
+        };
+        let body = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read index definitions: {}", e))?;
+        let stored: Vec<SyntheticIndex> =
+            serde_json::from_str(&body).map_err(|e| format!("Corrupt index definitions: {}", e))?;
+        for index in stored {
+            let index = SyntheticIndex::new(index.symbol, index.components, index.divisor)
+                .map_err(|e| format!("Invalid stored index: {}", e))?;
+            indices.definitions.insert(index.symbol.clone(), index);
+        }
+        Ok(indices)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.definitions.is_empty()
+    }
+
+    /// Add an index or replace its definition. Its value is worked out again
+    /// on the next reprice. Nothing changes if the definitions can't be saved.
+    pub fn define(&mut self, index: SyntheticIndex) -> Result<(), String> {
+        let mut definitions = self.definitions.clone();
+        definitions.insert(index.symbol.clone(), index.clone());
+        self.save(&definitions)?;
+        self.definitions = definitions;
+        self.values.remove(&index.symbol);
+        self.failures.remove(&index.symbol);
+        Ok(())
+    }
+
+    /// Remove an index no stop order is waiting on
+    pub fn remove(&mut self, symbol: &str) -> Result<SyntheticIndex, String> {
+        let waiting = self.stops.get(symbol).map_or(0, TriggerBook::len);
+        if waiting > 0 {
+            return Err(format!(
+                "{} stop orders are waiting on index {}",
+                waiting, symbol
+            ));
+        }
+        let mut definitions = self.definitions.clone();
+        let index = definitions
+            .remove(symbol)
+            .ok_or_else(|| format!("Unknown index '{}'", symbol))?;
+        self.save(&definitions)?;
+        self.definitions = definitions;
+        self.values.remove(symbol);
+        self.failures.remove(symbol);
+        self.stops.remove(symbol);
+        Ok(index)
+    }
+
+    /// Take up the indices a snapshot holds, keeping them where this set
+    /// keeps its definitions
+    pub fn restore(&mut self, snapshot: SyntheticIndices) -> Result<(), String> {
+        self.save(&snapshot.definitions)?;
+        *self = SyntheticIndices {
+            path: self.path.take(),
+            ..snapshot
+        };
+        Ok(())
+    }
+
+    /// Write `definitions` in place of the stored ones, through a temporary
+    /// file so a crash mid-write leaves the old set whole
+    fn save(&self, definitions: &BTreeMap<String, SyntheticIndex>) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        write_definitions(path, definitions)
+            .map_err(|e| format!("Failed to save index definitions: {}", e))
+    }
+
+    pub fn contains(&self, symbol: &str) -> bool {
+        self.definitions.contains_key(symbol)
+    }
+
+    pub fn value(&self, symbol: &str) -> Option<Price> {
+        self.values.get(symbol).copied()
+    }
+
+    pub fn quote(&self, symbol: &str) -> Option<SyntheticQuote> {
+        let index = self.definitions.get(symbol)?;
+        Some(SyntheticQuote {
+            index: index.clone(),
+            value: self.value(symbol),
+            error: self.failures.get(symbol).cloned(),
+        })
+    }
+
+    /// Every index, by symbol
+    pub fn quotes(&self) -> Vec<SyntheticQuote> {
+        self.definitions
+            .keys()
+            .filter_map(|symbol| self.quote(symbol))
+            .collect()
+    }
+
+    /// Work out each index from the component prices `price_of` gives, and
+    /// return the ones whose value changed, by symbol. An index that can't be
+    /// valued loses its last value, so no stop fires on a stale one, and is
+    /// returned with the reason the first time it fails.
+    pub fn reprice(
+        &mut self,
+        price_of: impl Fn(&str) -> Option<Price>,
+    ) -> Vec<(String, Result<Price, String>)> {
+        let mut changed = Vec::new();
+        for (symbol, index) in &self.definitions {
+            match index.value(&price_of) {
+                Ok(None) => {}
+                Ok(Some(value)) => {
+                    self.failures.remove(symbol);
+                    if self.values.insert(symbol.clone(), value) != Some(value) {
+                        changed.push((symbol.clone(), Ok(value)));
+                    }
+                }
+                Err(e) => {
+                    self.values.remove(symbol);
+                    if self.failures.insert(symbol.clone(), e.clone()).as_ref() != Some(&e) {
+                        changed.push((symbol.clone(), Err(e)));
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    /// Park `stop` until index `symbol` reaches its trigger
+    pub fn insert_stop(&mut self, symbol: &str, stop: StopOrder) {
+        self.stops
+            .entry(symbol.to_string())
+            .or_default()
+            .insert(stop);
+    }
+
+    /// Stop orders waiting on any index
+    pub fn stop_count(&self) -> usize {
+        self.stops.values().map(TriggerBook::len).sum()
+    }
+
+    pub fn stop(&self, id: Uuid) -> Option<&StopOrder> {
+        self.stops.values().find_map(|stops| stops.get(id))
+    }
+
+    pub fn remove_stop(&mut self, id: Uuid) -> Option<StopOrder> {
+        self.stops.values_mut().find_map(|stops| stops.remove(id))
+    }
+
+    /// Drop the stops waiting on every index
+    pub fn clear_stops(&mut self) {
+        self.stops.clear();
+    }
+
+    /// Remove and return the stops on `symbol` its current value activates
+    pub fn take_triggered(&mut self, symbol: &str) -> Vec<StopOrder> {
+        match (self.value(symbol), self.stops.get_mut(symbol)) {
+            (Some(value), Some(stops)) => stops.take_triggered(value),
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn write_definitions(
+    path: &Path,
+    definitions: &BTreeMap<String, SyntheticIndex>,
+) -> Result<(), String> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let list: Vec<&SyntheticIndex> = definitions.values().collect();
+    let body = serde_json::to_string_pretty(&list).map_err(|e| e.to_string())?;
+    let staged = path.with_extension("tmp");
+    fs::write(&staged, body).map_err(|e| e.to_string())?;
+    fs::rename(&staged, path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{IndexComponent, OrderSide, Quantity};
+
+    #[test]
+    fn test_values_change_with_their_components_and_fire_stops() {
+        let mut indices = SyntheticIndices::new();
+        let component = IndexComponent {
+            market: "BTC-USD".to_string(),
+            weight: 1.0,
+        };
+        let index = SyntheticIndex::new("BTC-CENTS".to_string(), vec![component], 0.01).unwrap();
+        indices.define(index).unwrap();
+        assert!(indices.reprice(|_| None).is_empty());
+        assert_eq!(indices.quotes()[0].value, None);
+
+        let price = |value: f64| move |_: &str| Some(Price::from_f64(value));
+        let changed = indices.reprice(price(100.0));
+        assert_eq!(
+            changed,
+            vec![("BTC-CENTS".to_string(), Ok(Price::from_f64(10_000.0)))]
+        );
+        assert!(indices.reprice(price(100.0)).is_empty());
+
+        let stop = StopOrder::new(
+            Uuid::new_v4(),
+            OrderSide::Buy,
+            Quantity::from_f64(1.0),
+            Price::from_f64(11_000.0),
+        );
+        indices.insert_stop("BTC-CENTS", stop.clone());
+        assert!(indices.remove("BTC-CENTS").is_err());
+        assert!(indices.take_triggered("BTC-CENTS").is_empty());
+
+        indices.reprice(price(110.0));
+        assert_eq!(indices.take_triggered("BTC-CENTS"), vec![stop]);
+        assert!(indices.remove("BTC-CENTS").is_ok());
+        assert!(indices.is_empty());
+    }
+
+    #[test]
+    fn test_an_index_out_of_range_loses_its_value_until_it_fits_again() {
+        let mut indices = SyntheticIndices::new();
+        let component = IndexComponent {
+            market: "BTC-USD".to_string(),
+            weight: 1e9,
+        };
+        let index = SyntheticIndex::new("BIG".to_string(), vec![component], 1.0).unwrap();
+        indices.define(index).unwrap();
+        let price = |value: f64| move |_: &str| Some(Price::from_f64(value));
+
+        assert!(indices.reprice(price(1.0))[0].1.is_ok());
+        let changed = indices.reprice(price(1e12));
+        let too_large = Err("Index BIG value is too large".to_string());
+        assert_eq!(changed, vec![("BIG".to_string(), too_large)]);
+        assert_eq!(indices.value("BIG"), None);
+        assert!(indices.quote("BIG").unwrap().error.is_some());
+        // Reported once, not on every trade
+        assert!(indices.reprice(price(1e12)).is_empty());
+
+        assert!(indices.reprice(price(2.0))[0].1.is_ok());
+        assert_eq!(indices.quote("BIG").unwrap().error, None);
+    }
+
+    #[test]
+    fn test_definitions_survive_reopening() {
+        let dir = std::env::temp_dir().join(format!("indices-{}", Uuid::new_v4()));
+        let path = dir.join("synthetic_indices.json");
+        let component = IndexComponent {
+            market: "BTC-USD".to_string(),
+            weight: 2.0,
+        };
+        let index = SyntheticIndex::new("BTC2".to_string(), vec![component], 1.0).unwrap();
+
+        let mut indices = SyntheticIndices::open(Some(path.clone())).unwrap();
+        indices.define(index.clone()).unwrap();
+        let other = SyntheticIndex::new("GONE".to_string(), index.components.clone(), 1.0);
+        indices.define(other.unwrap()).unwrap();
+        indices.remove("GONE").unwrap();
+
+        let reopened = SyntheticIndices::open(Some(path.clone())).unwrap();
+        let listed: Vec<_> = reopened.quotes().into_iter().map(|q| q.index).collect();
+        assert_eq!(listed, vec![index]);
+
+        // A stored definition the endpoint would refuse is not loaded
+        fs::write(&path, r#"[{"symbol":"X","components":[],"divisor":1.0}]"#).unwrap();
+        assert!(SyntheticIndices::open(Some(path)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
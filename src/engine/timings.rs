@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Where an order spent its time inside the server, in microseconds.
+/// Lets integrators separate network latency from engine latency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderTimings {
+    pub queue_wait_us: u64, // Gateway receipt until the engine picked the command up
+    pub match_us: u64,      // Walking the book, excluding settlement
+    pub settlement_us: u64, // Balance transfers for the resulting trades
+    pub engine_us: u64,     // Total time inside the engine for this command
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().min(u64::MAX as u128) as u64
+}
+
+impl OrderTimings {
+    pub fn new(
+        queue_wait: Duration,
+        matching: Duration,
+        settlement: Duration,
+        engine: Duration,
+    ) -> Self {
+        OrderTimings {
+            queue_wait_us: micros(queue_wait),
+            match_us: micros(matching.saturating_sub(settlement)),
+            settlement_us: micros(settlement),
+            engine_us: micros(engine),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_time_excludes_settlement() {
+        let timings = OrderTimings::new(
+            Duration::from_micros(40),
+            Duration::from_micros(100),
+            Duration::from_micros(30),
+            Duration::from_micros(120),
+        );
+        assert_eq!(timings.queue_wait_us, 40);
+        assert_eq!(timings.match_us, 70);
+        assert_eq!(timings.settlement_us, 30);
+        assert_eq!(timings.engine_us, 120);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::types::{OrderSide, Price, Quantity, Trade};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+/// One public print on the tape. `seq` is strictly increasing with no reuse.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TapeEntry {
+    pub seq: u64,
+    pub trade_id: Uuid,
+    pub price: Price,
+    pub quantity: Quantity,
+    pub taker_side: OrderSide,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A contiguous slice of the tape
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TapePage {
+    pub entries: Vec<TapeEntry>,
+    /// True when entries the caller asked for were already evicted by retention
+    pub gap: bool,
+    /// Oldest sequence number still retained (next_seq if the tape is empty)
+    pub first_available_seq: u64,
+    /// Highest sequence number assigned so far (0 if no trades yet)
+    pub last_seq: u64,
+}
+
+/// Bounded, sequence-numbered history of public trades
+pub struct TradeTape {
+    entries: VecDeque<TapeEntry>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl TradeTape {
+    pub fn new(capacity: usize) -> Self {
+        TradeTape {
+            entries: VecDeque::with_capacity(capacity.min(1024)),
+            capacity: capacity.max(1),
+            next_seq: 1,
+        }
+    }
+
+    /// Add `trades` to the tape, returning the entries as recorded
+    pub fn append(&mut self, trades: &[Trade], taker_side: OrderSide) -> Vec<TapeEntry> {
+        let mut appended = Vec::with_capacity(trades.len());
+        for trade in trades {
+            if self.entries.len() == self.capacity {
+                self.entries.pop_front();
+            }
+            let entry = TapeEntry {
+                seq: self.next_seq,
+                trade_id: trade.id,
+                price: trade.price,
+                quantity: trade.quantity,
+                taker_side,
+                timestamp: trade.timestamp,
+            };
+            self.entries.push_back(entry.clone());
+            appended.push(entry);
+            self.next_seq += 1;
+        }
+        appended
+    }
+
+    /// Up to `limit` entries with `seq > after` (or from the oldest retained entry)
+    pub fn page(&self, after: Option<u64>, limit: usize) -> TapePage {
+        let first_available_seq = self.entries.front().map_or(self.next_seq, |e| e.seq);
+        let start = after.map_or(first_available_seq, |a| a.saturating_add(1));
+        let gap = start < first_available_seq;
+
+        let skip = start.saturating_sub(first_available_seq) as usize;
+        let entries = self.entries.iter().skip(skip).take(limit).cloned().collect();
+
+        TapePage {
+            entries,
+            gap,
+            first_available_seq,
+            last_seq: self.next_seq - 1,
+        }
+    }
+
+    /// The last `limit` entries as a page, oldest first. Entries printed after
+    /// it start at `last_seq + 1`.
+    pub fn latest(&self, limit: usize) -> TapePage {
+        let after = (self.next_seq - 1).saturating_sub(limit as u64);
+        self.page(Some(after), limit)
+    }
+
+    /// The last `limit` entries, newest first
+    pub fn recent(&self, limit: usize) -> Vec<TapeEntry> {
+        self.entries.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// Highest sequence number assigned so far (0 if no trades yet)
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Pick up numbering after `last_seq`, as when restoring a snapshot. The
+    /// tape starts empty, so a reader asking for earlier prints sees a gap.
+    pub fn continue_after(&mut self, last_seq: u64) {
+        self.entries.clear();
+        self.next_seq = last_seq + 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trades(n: usize) -> Vec<Trade> {
+        (0..n)
+            .map(|_| {
+                Trade::new(
+                    Uuid::new_v4(),
+                    Uuid::new_v4(),
+                    Uuid::new_v4(),
+                    Uuid::new_v4(),
+                    Price::new(100),
+                    Quantity::new(1),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sequence_and_pagination() {
+        let mut tape = TradeTape::new(10);
+        tape.append(&trades(5), OrderSide::Buy);
+
+        let page = tape.page(None, 2);
+        assert_eq!(page.entries.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(!page.gap);
+        assert_eq!(page.last_seq, 5);
+
+        let page = tape.page(Some(2), 10);
+        assert_eq!(page.entries.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert!(!page.gap);
+
+        assert!(tape.page(Some(5), 10).entries.is_empty());
+    }
+
+    #[test]
+    fn test_gap_after_retention_eviction() {
+        let mut tape = TradeTape::new(3);
+        tape.append(&trades(5), OrderSide::Sell);
+
+        assert_eq!(tape.len(), 3);
+        let page = tape.page(Some(1), 10);
+        assert!(page.gap);
+        assert_eq!(page.first_available_seq, 3);
+        assert_eq!(page.entries.first().unwrap().seq, 3);
+
+        assert!(!tape.page(Some(2), 10).gap);
+    }
+
+    #[test]
+    fn test_latest_ends_at_last_seq() {
+        let mut tape = TradeTape::new(3);
+        assert!(tape.latest(2).entries.is_empty());
+        tape.append(&trades(5), OrderSide::Buy);
+
+        let page = tape.latest(2);
+        assert_eq!(
+            page.entries.iter().map(|e| e.seq).collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+        assert!(!page.gap);
+        assert_eq!(page.last_seq, 5);
+        // Asking past retention returns what is left and flags the gap
+        let page = tape.latest(10);
+        assert_eq!(page.entries.len(), 3);
+        assert!(page.gap);
+    }
+}
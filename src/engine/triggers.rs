@@ -0,0 +1,219 @@
+use crate::types::{Order, OrderSide, OrderSource, Price, Quantity};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use uuid::Uuid;
+
+/// A market order parked until the last trade price reaches `stop_price`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StopOrder {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub side: OrderSide,
+    pub quantity: Quantity,
+    pub stop_price: Price,
+    pub source: OrderSource,
+    pub client_order_id: Option<String>,
+    pub received_at: DateTime<Utc>,
+}
+
+impl StopOrder {
+    pub fn new(user_id: Uuid, side: OrderSide, quantity: Quantity, stop_price: Price) -> Self {
+        StopOrder {
+            id: Uuid::new_v4(),
+            user_id,
+            side,
+            quantity,
+            stop_price,
+            source: OrderSource::default(),
+            client_order_id: None,
+            received_at: Utc::now(),
+        }
+    }
+
+    /// Whether a trade at `last_price` activates this stop.
+    /// Buy stops fire on a rise through the trigger, sell stops on a fall.
+    pub fn is_triggered_by(&self, last_price: Price) -> bool {
+        match self.side {
+            OrderSide::Buy => last_price >= self.stop_price,
+            OrderSide::Sell => last_price <= self.stop_price,
+        }
+    }
+
+    /// The market order this stop becomes once triggered at `at`; it keeps the
+    /// stop's id
+    pub fn into_market_order(self, at: DateTime<Utc>) -> Order {
+        let mut order = Order::new_market(self.user_id, self.side, self.quantity)
+            .with_timestamp(at)
+            .with_source(self.source)
+            .with_received_at(self.received_at)
+            .with_client_order_id(self.client_order_id);
+        order.id = self.id;
+        order
+    }
+}
+
+/// Resting stop orders, keyed by trigger price with FIFO order within a price.
+/// Saved as a plain list of stops, in the order they are kept.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(into = "Vec<StopOrder>", from = "Vec<StopOrder>")]
+pub struct TriggerBook {
+    buy_stops: BTreeMap<Price, VecDeque<StopOrder>>,
+    sell_stops: BTreeMap<Price, VecDeque<StopOrder>>,
+    index: HashMap<Uuid, (OrderSide, Price)>,
+}
+
+impl TriggerBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<&StopOrder> {
+        let (side, price) = self.index.get(&id)?;
+        self.side(*side)
+            .get(price)?
+            .iter()
+            .find(|stop| stop.id == id)
+    }
+
+    fn side(&self, side: OrderSide) -> &BTreeMap<Price, VecDeque<StopOrder>> {
+        match side {
+            OrderSide::Buy => &self.buy_stops,
+            OrderSide::Sell => &self.sell_stops,
+        }
+    }
+
+    fn side_mut(&mut self, side: OrderSide) -> &mut BTreeMap<Price, VecDeque<StopOrder>> {
+        match side {
+            OrderSide::Buy => &mut self.buy_stops,
+            OrderSide::Sell => &mut self.sell_stops,
+        }
+    }
+
+    pub fn insert(&mut self, stop: StopOrder) {
+        self.index.insert(stop.id, (stop.side, stop.stop_price));
+        self.side_mut(stop.side)
+            .entry(stop.stop_price)
+            .or_default()
+            .push_back(stop);
+    }
+
+    /// Drop every waiting stop, as when the market closes for good
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn remove(&mut self, id: Uuid) -> Option<StopOrder> {
+        let (side, price) = self.index.remove(&id)?;
+        let stops = self.side_mut(side);
+        let queue = stops.get_mut(&price)?;
+        let position = queue.iter().position(|stop| stop.id == id)?;
+        let stop = queue.remove(position);
+        if queue.is_empty() {
+            stops.remove(&price);
+        }
+        stop
+    }
+
+    /// Remove and return every stop that a trade at `last_price` activates, in the
+    /// order a move to that price would have crossed their triggers, then by arrival
+    pub fn take_triggered(&mut self, last_price: Price) -> Vec<StopOrder> {
+        let mut triggered = Vec::new();
+
+        // Buy stops at or below the last price, lowest trigger first
+        let remaining = self.buy_stops.split_off(&Price::new(last_price.raw() + 1));
+        let fired = std::mem::replace(&mut self.buy_stops, remaining);
+        triggered.extend(fired.into_values().flatten());
+
+        // Sell stops at or above the last price, highest trigger first
+        let fired = self.sell_stops.split_off(&last_price);
+        triggered.extend(fired.into_values().rev().flatten());
+
+        for stop in &triggered {
+            self.index.remove(&stop.id);
+        }
+        triggered
+    }
+}
+
+impl From<TriggerBook> for Vec<StopOrder> {
+    fn from(book: TriggerBook) -> Self {
+        let (buys, sells) = (book.buy_stops, book.sell_stops);
+        buys.into_values()
+            .chain(sells.into_values())
+            .flatten()
+            .collect()
+    }
+}
+
+impl From<Vec<StopOrder>> for TriggerBook {
+    fn from(stops: Vec<StopOrder>) -> Self {
+        let mut book = TriggerBook::new();
+        for stop in stops {
+            book.insert(stop);
+        }
+        book
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(side: OrderSide, price: f64) -> StopOrder {
+        StopOrder::new(
+            Uuid::new_v4(),
+            side,
+            Quantity::from_f64(1.0),
+            Price::from_f64(price),
+        )
+    }
+
+    #[test]
+    fn test_take_triggered_by_side_and_price() {
+        let mut book = TriggerBook::new();
+        let buy_low = stop(OrderSide::Buy, 101.0);
+        let buy_high = stop(OrderSide::Buy, 105.0);
+        let sell_high = stop(OrderSide::Sell, 99.0);
+        let sell_low = stop(OrderSide::Sell, 95.0);
+        for s in [&buy_low, &buy_high, &sell_high, &sell_low] {
+            book.insert(s.clone());
+        }
+
+        assert!(book.take_triggered(Price::from_f64(100.0)).is_empty());
+
+        let fired = book.take_triggered(Price::from_f64(102.0));
+        assert_eq!(fired, vec![buy_low]);
+
+        let fired = book.take_triggered(Price::from_f64(95.0));
+        assert_eq!(fired, vec![sell_high, sell_low]);
+        assert_eq!(book.len(), 1);
+        assert!(book.get(buy_high.id).is_some());
+    }
+
+    #[test]
+    fn test_remove_and_fifo_within_price() {
+        let mut book = TriggerBook::new();
+        let first = stop(OrderSide::Sell, 90.0);
+        let second = stop(OrderSide::Sell, 90.0);
+        let third = stop(OrderSide::Sell, 90.0);
+        for s in [&first, &second, &third] {
+            book.insert(s.clone());
+        }
+
+        assert_eq!(book.remove(second.id), Some(second.clone()));
+        assert_eq!(book.remove(second.id), None);
+
+        let fired = book.take_triggered(Price::from_f64(89.0));
+        assert_eq!(fired, vec![first, third]);
+        assert!(book.is_empty());
+    }
+}
@@ -0,0 +1,1283 @@
+/// The command log and snapshots in an embedded RocksDB database, for
+/// durability without a database server
+pub mod rocksdb;
+
+use crate::engine::config::env_parse;
+use crate::engine::EngineSnapshot;
+use crate::messages::{LimitOrderParams, OrderBookCommand, OrderBookResponse};
+use crate::orderbook::OrderFilter;
+use crate::types::{
+    LeverageTiers, OrderSide, OrderSource, Peg, Price, Quantity, SlippageGuard, SyntheticIndex,
+    TimeInForce, TradeThroughBand, TradingStatus,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+/// Format of the log's lines; a log written in another is refused
+pub const WAL_VERSION: u32 = 1;
+
+/// Logged commands between snapshots, unless `WAL_SNAPSHOT_EVERY` says otherwise
+pub const DEFAULT_SNAPSHOT_EVERY: u64 = 10_000;
+
+/// When appended commands are forced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalSync {
+    /// After each command, before it runs
+    Always,
+    /// Once per engine batch, before any of it runs
+    #[default]
+    Batch,
+    /// Never; the OS writes the log out when it chooses
+    Never,
+}
+
+impl std::str::FromStr for WalSync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(WalSync::Always),
+            "batch" => Ok(WalSync::Batch),
+            "never" | "off" => Ok(WalSync::Never),
+            _ => Err(format!("Unknown WAL sync policy '{}'", s)),
+        }
+    }
+}
+
+/// What keeps the command log and its snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalBackend {
+    /// A file of JSON lines, with the snapshot in a file beside it
+    #[default]
+    File,
+    /// A RocksDB database in the directory at the log's path
+    RocksDb,
+}
+
+impl std::str::FromStr for WalBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "file" => Ok(WalBackend::File),
+            "rocksdb" => Ok(WalBackend::RocksDb),
+            _ => Err(format!("Unknown WAL backend '{}'", s)),
+        }
+    }
+}
+
+/// Where the engine logs its commands, how durably, and where and how often
+/// it snapshots its state so a restart replays only the commands after that
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalConfig {
+    pub path: PathBuf,
+    pub sync: WalSync,
+    pub backend: WalBackend,
+    pub snapshot_path: PathBuf, // File backend only; RocksDB keeps snapshots in the database
+    pub snapshot_every: u64,    // Logged commands between snapshots; 0 = only on shutdown
+}
+
+impl WalConfig {
+    /// From `WAL_PATH`, `WAL_FSYNC`, `WAL_BACKEND`, `WAL_SNAPSHOT_PATH`
+    /// (default: the log's path with `.snapshot` added) and
+    /// `WAL_SNAPSHOT_EVERY`; None unless a log path is set
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var("WAL_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())?;
+        let snapshot_path = std::env::var("WAL_SNAPSHOT_PATH")
+            .ok()
+            .filter(|path| !path.is_empty())
+            .unwrap_or_else(|| format!("{}.snapshot", path));
+        Some(WalConfig {
+            path: PathBuf::from(path),
+            sync: env_parse("WAL_FSYNC").unwrap_or_default(),
+            backend: env_parse("WAL_BACKEND").unwrap_or_default(),
+            snapshot_path: PathBuf::from(snapshot_path),
+            snapshot_every: env_parse("WAL_SNAPSHOT_EVERY").unwrap_or(DEFAULT_SNAPSHOT_EVERY),
+        })
+    }
+}
+
+/// A command that changes engine state, without its response channel.
+/// Queries are never logged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LoggedCommand {
+    PlaceLimitOrder {
+        user_id: Uuid,
+        side: OrderSide,
+        price: Price,
+        quantity: Quantity,
+        time_in_force: TimeInForce,
+        display_quantity: Option<Quantity>,
+        hidden: bool,
+        min_fill_qty: Option<Quantity>,
+        expires_at: Option<DateTime<Utc>>,
+        peg: Option<Peg>,
+        trade_through_protected: bool,
+        priority_fee: f64,
+        received_at: DateTime<Utc>,
+        source: OrderSource,
+        client_order_id: Option<String>,
+    },
+    PlacePeggedOrder {
+        user_id: Uuid,
+        side: OrderSide,
+        quantity: Quantity,
+        peg: Peg,
+        expires_at: Option<DateTime<Utc>>,
+        received_at: DateTime<Utc>,
+        source: OrderSource,
+        client_order_id: Option<String>,
+    },
+    PlaceMarketOrder {
+        user_id: Uuid,
+        side: OrderSide,
+        quantity: Quantity,
+        slippage: Option<SlippageGuard>,
+        received_at: DateTime<Utc>,
+        source: OrderSource,
+        client_order_id: Option<String>,
+    },
+    PlaceStopOrder {
+        user_id: Uuid,
+        side: OrderSide,
+        quantity: Quantity,
+        stop_price: Price,
+        trigger_index: Option<String>,
+        received_at: DateTime<Utc>,
+        source: OrderSource,
+        client_order_id: Option<String>,
+    },
+    CancelOrder {
+        user_id: Uuid,
+        order_id: Uuid,
+    },
+    CancelOrderByClientId {
+        user_id: Uuid,
+        client_order_id: String,
+    },
+    MassCancel {
+        user_id: Uuid,
+        filter: OrderFilter,
+    },
+    CancelAllAfter {
+        user_id: Uuid,
+        timeout: Duration,
+    },
+    PlaceOrderBatch {
+        user_id: Uuid,
+        orders: Vec<LimitOrderParams>,
+        received_at: DateTime<Utc>,
+        source: OrderSource,
+    },
+    AmendOrder {
+        user_id: Uuid,
+        order_id: Uuid,
+        price: Option<Price>,
+        quantity: Option<Quantity>,
+        received_at: DateTime<Utc>,
+    },
+    SetUserLeverage {
+        user_id: Uuid,
+        max_leverage: Option<f64>,
+    },
+    SetLeverageTiers {
+        user_id: Option<Uuid>,
+        tiers: Option<LeverageTiers>,
+    },
+    AddFunds {
+        user_id: Uuid,
+        currency: String,
+        amount: f64,
+    },
+    WithdrawFunds {
+        user_id: Uuid,
+        currency: String,
+        amount: f64,
+    },
+    ReverseWithdrawal {
+        user_id: Uuid,
+        journal_id: u64,
+    },
+    SetInterestOptIn {
+        user_id: Uuid,
+        opted_in: bool,
+    },
+    SetTradingStatus {
+        market: String,
+        status: TradingStatus,
+    },
+    SettleMarket {
+        market: String,
+        price: Option<Price>,
+    },
+    SetTradeThroughBand {
+        market: String,
+        band: TradeThroughBand,
+    },
+    DefineSyntheticIndex {
+        index: SyntheticIndex,
+    },
+    RemoveSyntheticIndex {
+        symbol: String,
+    },
+    ForceCancelOrder {
+        order_id: Uuid,
+    },
+    ResolveIncident {
+        id: u64,
+        note: String,
+    },
+    RetryDeadLetter {
+        id: u64,
+    },
+    CompensateDeadLetter {
+        id: u64,
+        note: String,
+    },
+    SetFxRate {
+        base: String,
+        quote: String,
+        rate: f64,
+    },
+    RemoveFxRate {
+        base: String,
+        quote: String,
+    },
+    AdjustBalance {
+        user_id: Uuid,
+        currency: String,
+        amount: f64,
+    },
+    AdvanceClock {
+        by: Duration,
+    },
+}
+
+impl LoggedCommand {
+    /// The loggable part of `command`; None for queries
+    pub fn from_command(command: &OrderBookCommand) -> Option<Self> {
+        let logged = match command {
+            OrderBookCommand::PlaceLimitOrder {
+                user_id,
+                side,
+                price,
+                quantity,
+                time_in_force,
+                display_quantity,
+                hidden,
+                min_fill_qty,
+                expires_at,
+                peg,
+                trade_through_protected,
+                priority_fee,
+                received_at,
+                source,
+                client_order_id,
+                ..
+            } => LoggedCommand::PlaceLimitOrder {
+                user_id: *user_id,
+                side: *side,
+                price: *price,
+                quantity: *quantity,
+                time_in_force: *time_in_force,
+                display_quantity: *display_quantity,
+                hidden: *hidden,
+                min_fill_qty: *min_fill_qty,
+                expires_at: *expires_at,
+                peg: *peg,
+                trade_through_protected: *trade_through_protected,
+                priority_fee: *priority_fee,
+                received_at: *received_at,
+                source: *source,
+                client_order_id: client_order_id.clone(),
+            },
+            OrderBookCommand::PlacePeggedOrder {
+                user_id,
+                side,
+                quantity,
+                peg,
+                expires_at,
+                received_at,
+                source,
+                client_order_id,
+                ..
+            } => LoggedCommand::PlacePeggedOrder {
+                user_id: *user_id,
+                side: *side,
+                quantity: *quantity,
+                peg: *peg,
+                expires_at: *expires_at,
+                received_at: *received_at,
+                source: *source,
+                client_order_id: client_order_id.clone(),
+            },
+            OrderBookCommand::PlaceMarketOrder {
+                user_id,
+                side,
+                quantity,
+                slippage,
+                received_at,
+                source,
+                client_order_id,
+                ..
+            } => LoggedCommand::PlaceMarketOrder {
+                user_id: *user_id,
+                side: *side,
+                quantity: *quantity,
+                slippage: *slippage,
+                received_at: *received_at,
+                source: *source,
+                client_order_id: client_order_id.clone(),
+            },
+            OrderBookCommand::PlaceStopOrder {
+                user_id,
+                side,
+                quantity,
+                stop_price,
+                trigger_index,
+                received_at,
+                source,
+                client_order_id,
+                ..
+            } => LoggedCommand::PlaceStopOrder {
+                user_id: *user_id,
+                side: *side,
+                quantity: *quantity,
+                stop_price: *stop_price,
+                trigger_index: trigger_index.clone(),
+                received_at: *received_at,
+                source: *source,
+                client_order_id: client_order_id.clone(),
+            },
+            OrderBookCommand::CancelOrder {
+                user_id, order_id, ..
+            } => LoggedCommand::CancelOrder {
+                user_id: *user_id,
+                order_id: *order_id,
+            },
+            OrderBookCommand::CancelOrderByClientId {
+                user_id,
+                client_order_id,
+                ..
+            } => LoggedCommand::CancelOrderByClientId {
+                user_id: *user_id,
+                client_order_id: client_order_id.clone(),
+            },
+            OrderBookCommand::MassCancel {
+                user_id, filter, ..
+            } => LoggedCommand::MassCancel {
+                user_id: *user_id,
+                filter: filter.clone(),
+            },
+            OrderBookCommand::CancelAllAfter {
+                user_id, timeout, ..
+            } => LoggedCommand::CancelAllAfter {
+                user_id: *user_id,
+                timeout: *timeout,
+            },
+            OrderBookCommand::PlaceOrderBatch {
+                user_id,
+                orders,
+                received_at,
+                source,
+                ..
+            } => LoggedCommand::PlaceOrderBatch {
+                user_id: *user_id,
+                orders: orders.clone(),
+                received_at: *received_at,
+                source: *source,
+            },
+            OrderBookCommand::AmendOrder {
+                user_id,
+                order_id,
+                price,
+                quantity,
+                received_at,
+                ..
+            } => LoggedCommand::AmendOrder {
+                user_id: *user_id,
+                order_id: *order_id,
+                price: *price,
+                quantity: *quantity,
+                received_at: *received_at,
+            },
+            OrderBookCommand::SetUserLeverage {
+                user_id,
+                max_leverage,
+                ..
+            } => LoggedCommand::SetUserLeverage {
+                user_id: *user_id,
+                max_leverage: *max_leverage,
+            },
+            OrderBookCommand::SetLeverageTiers { user_id, tiers, .. } => {
+                LoggedCommand::SetLeverageTiers {
+                    user_id: *user_id,
+                    tiers: tiers.clone(),
+                }
+            }
+            OrderBookCommand::AddFunds {
+                user_id,
+                currency,
+                amount,
+                ..
+            } => LoggedCommand::AddFunds {
+                user_id: *user_id,
+                currency: currency.clone(),
+                amount: *amount,
+            },
+            OrderBookCommand::WithdrawFunds {
+                user_id,
+                currency,
+                amount,
+                ..
+            } => LoggedCommand::WithdrawFunds {
+                user_id: *user_id,
+                currency: currency.clone(),
+                amount: *amount,
+            },
+            OrderBookCommand::ReverseWithdrawal {
+                user_id,
+                journal_id,
+                ..
+            } => LoggedCommand::ReverseWithdrawal {
+                user_id: *user_id,
+                journal_id: *journal_id,
+            },
+            OrderBookCommand::SetInterestOptIn {
+                user_id, opted_in, ..
+            } => LoggedCommand::SetInterestOptIn {
+                user_id: *user_id,
+                opted_in: *opted_in,
+            },
+            OrderBookCommand::SetTradingStatus { market, status, .. } => {
+                LoggedCommand::SetTradingStatus {
+                    market: market.clone(),
+                    status: *status,
+                }
+            }
+            OrderBookCommand::SettleMarket { market, price, .. } => LoggedCommand::SettleMarket {
+                market: market.clone(),
+                price: *price,
+            },
+            OrderBookCommand::SetTradeThroughBand { market, band, .. } => {
+                LoggedCommand::SetTradeThroughBand {
+                    market: market.clone(),
+                    band: *band,
+                }
+            }
+            OrderBookCommand::DefineSyntheticIndex { index, .. } => {
+                LoggedCommand::DefineSyntheticIndex {
+                    index: index.clone(),
+                }
+            }
+            OrderBookCommand::RemoveSyntheticIndex { symbol, .. } => {
+                LoggedCommand::RemoveSyntheticIndex {
+                    symbol: symbol.clone(),
+                }
+            }
+            OrderBookCommand::ForceCancelOrder { order_id, .. } => {
+                LoggedCommand::ForceCancelOrder {
+                    order_id: *order_id,
+                }
+            }
+            OrderBookCommand::ResolveIncident { id, note, .. } => LoggedCommand::ResolveIncident {
+                id: *id,
+                note: note.clone(),
+            },
+            OrderBookCommand::RetryDeadLetter { id, .. } => {
+                LoggedCommand::RetryDeadLetter { id: *id }
+            }
+            OrderBookCommand::CompensateDeadLetter { id, note, .. } => {
+                LoggedCommand::CompensateDeadLetter {
+                    id: *id,
+                    note: note.clone(),
+                }
+            }
+            OrderBookCommand::SetFxRate {
+                base, quote, rate, ..
+            } => LoggedCommand::SetFxRate {
+                base: base.clone(),
+                quote: quote.clone(),
+                rate: *rate,
+            },
+            OrderBookCommand::RemoveFxRate { base, quote, .. } => LoggedCommand::RemoveFxRate {
+                base: base.clone(),
+                quote: quote.clone(),
+            },
+            OrderBookCommand::AdjustBalance {
+                user_id,
+                currency,
+                amount,
+                ..
+            } => LoggedCommand::AdjustBalance {
+                user_id: *user_id,
+                currency: currency.clone(),
+                amount: *amount,
+            },
+            OrderBookCommand::AdvanceClock { by, .. } => LoggedCommand::AdvanceClock { by: *by },
+            // Queries change nothing, so there is nothing to replay. Listed
+            // one by one so a new command has to be placed on one side or the other.
+            OrderBookCommand::GetOrderBook { .. }
+            | OrderBookCommand::GetFullBook { .. }
+            | OrderBookCommand::GetImbalance { .. }
+            | OrderBookCommand::GetHeatmap { .. }
+            | OrderBookCommand::GetMarketConfig { .. }
+            | OrderBookCommand::GetMarketState { .. }
+            | OrderBookCommand::GetSyntheticIndices { .. }
+            | OrderBookCommand::GetMarketSnapshot { .. }
+            | OrderBookCommand::GetUserBalance { .. }
+            | OrderBookCommand::GetDailyStats { .. }
+            | OrderBookCommand::GetTradeTape { .. }
+            | OrderBookCommand::GetRecentTrades { .. }
+            | OrderBookCommand::GetExecutionQuality { .. }
+            | OrderBookCommand::GetOrderHistory { .. }
+            | OrderBookCommand::GetOrderByClientId { .. }
+            | OrderBookCommand::GetOrderFills { .. }
+            | OrderBookCommand::GetUserTrades { .. }
+            | OrderBookCommand::GetActivity { .. }
+            | OrderBookCommand::GetStoredOrders { .. }
+            | OrderBookCommand::GetSourceVolume { .. }
+            | OrderBookCommand::GetFeedActivity { .. }
+            | OrderBookCommand::GetPriceAverages { .. }
+            | OrderBookCommand::GetAccountSummary { .. }
+            | OrderBookCommand::GetDashboard { .. }
+            | OrderBookCommand::GetTrialBalance { .. }
+            | OrderBookCommand::GetJournals { .. }
+            | OrderBookCommand::GetFxRates { .. }
+            | OrderBookCommand::GetIncidents { .. }
+            | OrderBookCommand::GetDeadLetters { .. }
+            | OrderBookCommand::GetRecoveryReport { .. }
+            | OrderBookCommand::GetInterestSummary { .. }
+            | OrderBookCommand::GetLeverageSettings { .. }
+            | OrderBookCommand::GetLeverageTiers { .. }
+            | OrderBookCommand::GetLiquidationPreview { .. } => return None,
+        };
+        Some(logged)
+    }
+
+    /// The command to run again, answering on `response_tx`
+    pub fn into_command(self, response_tx: oneshot::Sender<OrderBookResponse>) -> OrderBookCommand {
+        match self {
+            LoggedCommand::PlaceLimitOrder {
+                user_id,
+                side,
+                price,
+                quantity,
+                time_in_force,
+                display_quantity,
+                hidden,
+                min_fill_qty,
+                expires_at,
+                peg,
+                trade_through_protected,
+                priority_fee,
+                received_at,
+                source,
+                client_order_id,
+            } => OrderBookCommand::PlaceLimitOrder {
+                user_id,
+                side,
+                price,
+                quantity,
+                time_in_force,
+                display_quantity,
+                hidden,
+                min_fill_qty,
+                expires_at,
+                peg,
+                trade_through_protected,
+                priority_fee,
+                received_at,
+                source,
+                client_order_id,
+                response_tx,
+            },
+            LoggedCommand::PlacePeggedOrder {
+                user_id,
+                side,
+                quantity,
+                peg,
+                expires_at,
+                received_at,
+                source,
+                client_order_id,
+            } => OrderBookCommand::PlacePeggedOrder {
+                user_id,
+                side,
+                quantity,
+                peg,
+                expires_at,
+                received_at,
+                source,
+                client_order_id,
+                response_tx,
+            },
+            LoggedCommand::PlaceMarketOrder {
+                user_id,
+                side,
+                quantity,
+                slippage,
+                received_at,
+                source,
+                client_order_id,
+            } => OrderBookCommand::PlaceMarketOrder {
+                user_id,
+                side,
+                quantity,
+                slippage,
+                received_at,
+                source,
+                client_order_id,
+                response_tx,
+            },
+            LoggedCommand::PlaceStopOrder {
+                user_id,
+                side,
+                quantity,
+                stop_price,
+                trigger_index,
+                received_at,
+                source,
+                client_order_id,
+            } => OrderBookCommand::PlaceStopOrder {
+                user_id,
+                side,
+                quantity,
+                stop_price,
+                trigger_index,
+                received_at,
+                source,
+                client_order_id,
+                response_tx,
+            },
+            LoggedCommand::CancelOrder { user_id, order_id } => OrderBookCommand::CancelOrder {
+                user_id,
+                order_id,
+                response_tx,
+            },
+            LoggedCommand::CancelOrderByClientId {
+                user_id,
+                client_order_id,
+            } => OrderBookCommand::CancelOrderByClientId {
+                user_id,
+                client_order_id,
+                response_tx,
+            },
+            LoggedCommand::MassCancel { user_id, filter } => OrderBookCommand::MassCancel {
+                user_id,
+                filter,
+                response_tx,
+            },
+            LoggedCommand::CancelAllAfter { user_id, timeout } => {
+                OrderBookCommand::CancelAllAfter {
+                    user_id,
+                    timeout,
+                    response_tx,
+                }
+            }
+            LoggedCommand::PlaceOrderBatch {
+                user_id,
+                orders,
+                received_at,
+                source,
+            } => OrderBookCommand::PlaceOrderBatch {
+                user_id,
+                orders,
+                received_at,
+                source,
+                response_tx,
+            },
+            LoggedCommand::AmendOrder {
+                user_id,
+                order_id,
+                price,
+                quantity,
+                received_at,
+            } => OrderBookCommand::AmendOrder {
+                user_id,
+                order_id,
+                price,
+                quantity,
+                received_at,
+                response_tx,
+            },
+            LoggedCommand::SetUserLeverage {
+                user_id,
+                max_leverage,
+            } => OrderBookCommand::SetUserLeverage {
+                user_id,
+                max_leverage,
+                response_tx,
+            },
+            LoggedCommand::SetLeverageTiers { user_id, tiers } => {
+                OrderBookCommand::SetLeverageTiers {
+                    user_id,
+                    tiers,
+                    response_tx,
+                }
+            }
+            LoggedCommand::AddFunds {
+                user_id,
+                currency,
+                amount,
+            } => OrderBookCommand::AddFunds {
+                user_id,
+                currency,
+                amount,
+                response_tx,
+            },
+            LoggedCommand::WithdrawFunds {
+                user_id,
+                currency,
+                amount,
+            } => OrderBookCommand::WithdrawFunds {
+                user_id,
+                currency,
+                amount,
+                response_tx,
+            },
+            LoggedCommand::ReverseWithdrawal {
+                user_id,
+                journal_id,
+            } => OrderBookCommand::ReverseWithdrawal {
+                user_id,
+                journal_id,
+                response_tx,
+            },
+            LoggedCommand::SetInterestOptIn { user_id, opted_in } => {
+                OrderBookCommand::SetInterestOptIn {
+                    user_id,
+                    opted_in,
+                    response_tx,
+                }
+            }
+            LoggedCommand::SetTradingStatus { market, status } => {
+                OrderBookCommand::SetTradingStatus {
+                    market,
+                    status,
+                    response_tx,
+                }
+            }
+            LoggedCommand::SettleMarket { market, price } => OrderBookCommand::SettleMarket {
+                market,
+                price,
+                response_tx,
+            },
+            LoggedCommand::SetTradeThroughBand { market, band } => {
+                OrderBookCommand::SetTradeThroughBand {
+                    market,
+                    band,
+                    response_tx,
+                }
+            }
+            LoggedCommand::DefineSyntheticIndex { index } => {
+                OrderBookCommand::DefineSyntheticIndex { index, response_tx }
+            }
+            LoggedCommand::RemoveSyntheticIndex { symbol } => {
+                OrderBookCommand::RemoveSyntheticIndex {
+                    symbol,
+                    response_tx,
+                }
+            }
+            LoggedCommand::ForceCancelOrder { order_id } => OrderBookCommand::ForceCancelOrder {
+                order_id,
+                response_tx,
+            },
+            LoggedCommand::ResolveIncident { id, note } => OrderBookCommand::ResolveIncident {
+                id,
+                note,
+                response_tx,
+            },
+            LoggedCommand::RetryDeadLetter { id } => {
+                OrderBookCommand::RetryDeadLetter { id, response_tx }
+            }
+            LoggedCommand::CompensateDeadLetter { id, note } => {
+                OrderBookCommand::CompensateDeadLetter {
+                    id,
+                    note,
+                    response_tx,
+                }
+            }
+            LoggedCommand::SetFxRate { base, quote, rate } => OrderBookCommand::SetFxRate {
+                base,
+                quote,
+                rate,
+                response_tx,
+            },
+            LoggedCommand::RemoveFxRate { base, quote } => OrderBookCommand::RemoveFxRate {
+                base,
+                quote,
+                response_tx,
+            },
+            LoggedCommand::AdjustBalance {
+                user_id,
+                currency,
+                amount,
+            } => OrderBookCommand::AdjustBalance {
+                user_id,
+                currency,
+                amount,
+                response_tx,
+            },
+            LoggedCommand::AdvanceClock { by } => {
+                OrderBookCommand::AdvanceClock { by, response_tx }
+            }
+        }
+    }
+}
+
+/// One logged command, numbered from 1 in the order it was accepted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalRecord {
+    pub seq: u64,
+    pub at: DateTime<Utc>, // Engine clock when it was logged; a replay runs it at this time
+    pub command: LoggedCommand,
+}
+
+/// Where the engine keeps the commands it accepted and snapshots of its
+/// state. Recovery and snapshotting go through this alone, so any store that
+/// keeps records in order and survives a crash can stand behind it.
+pub trait EventLog: Send {
+    /// Seeds the IDs of the orders logged commands create
+    fn log_id(&self) -> Uuid;
+
+    /// Sequence number of the last command written, 0 before the first
+    fn last_seq(&self) -> u64;
+
+    /// Write the state-changing commands of `batch` and make them as durable
+    /// as the sync policy says. Returns each command's sequence number, None
+    /// for queries.
+    fn append(
+        &mut self,
+        batch: &[OrderBookCommand],
+        at: DateTime<Utc>,
+    ) -> Result<Vec<Option<u64>>, String>;
+
+    /// Where the log keeps its snapshots
+    fn snapshots(&self) -> Arc<dyn SnapshotStore>;
+
+    /// Drop the commands up to `through`, which a written snapshot now
+    /// holds, so the log and the next replay stay short. A log that keeps
+    /// them does no harm: recovery skips what the snapshot has seen.
+    fn compact(&mut self, through: u64) -> Result<(), String> {
+        let _ = through;
+        Ok(())
+    }
+}
+
+/// The snapshots of one log. Shared with the thread that writes them, so
+/// the engine only pays for copying its state, never for the disk.
+pub trait SnapshotStore: Send + Sync {
+    /// The latest snapshot, None if none was written yet
+    fn read_snapshot(&self) -> Result<Option<EngineSnapshot>, String>;
+
+    /// Replace the latest snapshot. A crash part way through must leave the
+    /// previous one readable.
+    fn write_snapshot(&self, snapshot: &EngineSnapshot) -> Result<(), String>;
+}
+
+/// Open the log `config` asks for, returning it with the records already in it
+pub fn open_event_log(config: &WalConfig) -> Result<(Box<dyn EventLog>, Vec<WalRecord>), String> {
+    match config.backend {
+        WalBackend::File => {
+            let (log, records) = CommandLog::open(config)?;
+            Ok((Box::new(log), records))
+        }
+        WalBackend::RocksDb => rocksdb::open_rocksdb_log(config),
+    }
+}
+
+/// First line of every log. `log_id` seeds the IDs of the orders its
+/// commands create, so a replay gives them the same IDs again.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct WalHeader {
+    version: u32,
+    log_id: Uuid,
+    #[serde(default)]
+    after_seq: u64, // Commands up to this one were dropped once a snapshot held them
+}
+
+/// Just the number of a logged command, to skip it without reading it
+#[derive(Deserialize)]
+struct RecordSeq {
+    seq: u64,
+}
+
+/// Append-only file of the commands the engine has accepted, one JSON line
+/// each, written before they run. Snapshots go in a file of their own; once
+/// one is written the commands it holds are cut from the front of the file.
+#[derive(Debug)]
+pub struct CommandLog {
+    path: PathBuf,
+    file: File,
+    sync: WalSync,
+    log_id: Uuid,
+    last_seq: u64,
+    snapshots: Arc<SnapshotFile>,
+}
+
+impl CommandLog {
+    /// Open or create the log, returning it with the records already in it
+    /// after the latest snapshot of it; those before are skipped unread.
+    /// A partly written last line, left by a crash mid-append, is cut off;
+    /// its command never ran. Any other unreadable line is an error.
+    pub fn open(config: &WalConfig) -> Result<(Self, Vec<WalRecord>), String> {
+        let describe = |e: std::io::Error| format!("{}: {}", config.path.display(), e);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&config.path)
+            .map_err(describe)?;
+
+        let snapshot = EngineSnapshot::position(&config.snapshot_path);
+        let mut header: Option<WalHeader> = None;
+        let mut skip_through = 0;
+        let mut last_seq = 0;
+        let mut records = Vec::new();
+        let mut good_len = 0;
+        let mut torn = false;
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(describe)?;
+            if read == 0 {
+                break;
+            }
+            if torn {
+                return Err(format!(
+                    "{}: unreadable record before the end of the log",
+                    config.path.display()
+                ));
+            }
+            let parsed = match header {
+                None => serde_json::from_str::<WalHeader>(&line).map(|parsed| {
+                    skip_through = match snapshot {
+                        Some((log_id, seq)) if log_id == parsed.log_id => seq,
+                        _ => 0,
+                    };
+                    last_seq = parsed.after_seq;
+                    header = Some(parsed);
+                }),
+                Some(_) => serde_json::from_str::<RecordSeq>(&line).and_then(|record| {
+                    last_seq = record.seq;
+                    if record.seq > skip_through {
+                        records.push(serde_json::from_str::<WalRecord>(&line)?);
+                    }
+                    Ok(())
+                }),
+            };
+            match parsed {
+                Ok(()) if line.ends_with('\n') => good_len += read as u64,
+                _ => torn = true,
+            }
+        }
+
+        if torn {
+            file.set_len(good_len).map_err(describe)?;
+        }
+        file.seek(SeekFrom::End(0)).map_err(describe)?;
+        let header = match header {
+            Some(header) if header.version != WAL_VERSION => {
+                return Err(format!(
+                    "{}: log version {} is not the {} this build writes",
+                    config.path.display(),
+                    header.version,
+                    WAL_VERSION
+                ))
+            }
+            Some(header) => header,
+            None => {
+                let header = WalHeader {
+                    version: WAL_VERSION,
+                    log_id: Uuid::new_v4(),
+                    after_seq: 0,
+                };
+                let mut line = serde_json::to_string(&header).map_err(|e| e.to_string())?;
+                line.push('\n');
+                file.write_all(line.as_bytes()).map_err(describe)?;
+                file.sync_data().map_err(describe)?;
+                header
+            }
+        };
+
+        let log = CommandLog {
+            path: config.path.clone(),
+            file,
+            sync: config.sync,
+            log_id: header.log_id,
+            last_seq,
+            snapshots: Arc::new(SnapshotFile {
+                path: config.snapshot_path.clone(),
+            }),
+        };
+        Ok((log, records))
+    }
+}
+
+impl EventLog for CommandLog {
+    fn log_id(&self) -> Uuid {
+        self.log_id
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    fn append(
+        &mut self,
+        batch: &[OrderBookCommand],
+        at: DateTime<Utc>,
+    ) -> Result<Vec<Option<u64>>, String> {
+        let mut seqs = Vec::with_capacity(batch.len());
+        let mut unsynced = false;
+        for command in batch {
+            let Some(command) = LoggedCommand::from_command(command) else {
+                seqs.push(None);
+                continue;
+            };
+            let record = WalRecord {
+                seq: self.last_seq + 1,
+                at,
+                command,
+            };
+            let mut line = serde_json::to_string(&record).map_err(|e| e.to_string())?;
+            line.push('\n');
+            self.file
+                .write_all(line.as_bytes())
+                .map_err(|e| e.to_string())?;
+            self.last_seq = record.seq;
+            seqs.push(Some(record.seq));
+            match self.sync {
+                WalSync::Always => self.file.sync_data().map_err(|e| e.to_string())?,
+                WalSync::Batch => unsynced = true,
+                WalSync::Never => {}
+            }
+        }
+        if unsynced {
+            self.file.sync_data().map_err(|e| e.to_string())?;
+        }
+        Ok(seqs)
+    }
+
+    fn snapshots(&self) -> Arc<dyn SnapshotStore> {
+        self.snapshots.clone()
+    }
+
+    /// Rewrite the file beside it with the header and the commands after
+    /// `through`, then rename it over the log. A crash before the rename
+    /// leaves the whole log, which recovery reads past the snapshot.
+    fn compact(&mut self, through: u64) -> Result<(), String> {
+        let describe = |e: std::io::Error| format!("{}: {}", self.path.display(), e);
+        let header = WalHeader {
+            version: WAL_VERSION,
+            log_id: self.log_id,
+            after_seq: through,
+        };
+        let mut kept = serde_json::to_string(&header).map_err(|e| e.to_string())?;
+        kept.push('\n');
+        let reader = BufReader::new(File::open(&self.path).map_err(describe)?);
+        for line in reader.lines().skip(1) {
+            let line = line.map_err(describe)?;
+            let record: RecordSeq = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+            if record.seq > through {
+                kept.push_str(&line);
+                kept.push('\n');
+            }
+        }
+
+        let mut temp_path = self.path.as_os_str().to_owned();
+        temp_path.push(".compact");
+        let mut temp = File::create(&temp_path).map_err(describe)?;
+        temp.write_all(kept.as_bytes()).map_err(describe)?;
+        temp.sync_all().map_err(describe)?;
+        std::fs::rename(&temp_path, &self.path).map_err(describe)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(describe)?;
+        Ok(())
+    }
+}
+
+/// The file log's snapshot, in a file of its own
+#[derive(Debug)]
+pub struct SnapshotFile {
+    path: PathBuf,
+}
+
+impl SnapshotStore for SnapshotFile {
+    fn read_snapshot(&self) -> Result<Option<EngineSnapshot>, String> {
+        EngineSnapshot::read(&self.path)
+    }
+
+    fn write_snapshot(&self, snapshot: &EngineSnapshot) -> Result<(), String> {
+        snapshot.write(&self.path)
+    }
+}
+
+/// Hands out order IDs. With a command log they are derived from the log
+/// and the command's sequence number, so replaying the command creates its
+/// orders under the same IDs; without one they are random.
+#[derive(Debug, Default)]
+pub struct OrderIds {
+    log_id: Option<Uuid>,
+    seq: u64,
+    drawn: u64, // IDs handed out for the current command
+}
+
+impl OrderIds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Derive IDs from `log_id` from now on
+    pub fn with_log(log_id: Uuid) -> Self {
+        OrderIds {
+            log_id: Some(log_id),
+            ..Self::default()
+        }
+    }
+
+    /// Start on the IDs of logged command `seq`
+    pub fn begin(&mut self, seq: u64) {
+        self.seq = seq;
+        self.drawn = 0;
+    }
+
+    pub fn next_id(&mut self) -> Uuid {
+        let Some(log_id) = self.log_id else {
+            return Uuid::new_v4();
+        };
+        let mut hasher = Sha256::new();
+        hasher.update(log_id.as_bytes());
+        hasher.update(self.seq.to_be_bytes());
+        hasher.update(self.drawn.to_be_bytes());
+        self.drawn += 1;
+        let digest = hasher.finalize();
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        uuid::Builder::from_random_bytes(bytes).into_uuid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_reopens_with_its_records_and_drops_a_torn_tail() {
+        let path = std::env::temp_dir().join(format!("wal-{}.jsonl", Uuid::new_v4()));
+        let config = WalConfig {
+            path: path.clone(),
+            sync: WalSync::Batch,
+            backend: WalBackend::File,
+            snapshot_path: path.with_extension("snapshot"),
+            snapshot_every: 0,
+        };
+        let (mut log, records) = CommandLog::open(&config).unwrap();
+        assert!(records.is_empty());
+        let user_id = Uuid::new_v4();
+        let batch = vec![
+            OrderBookCommand::AddFunds {
+                user_id,
+                currency: "USD".to_string(),
+                amount: 100.0,
+                response_tx: oneshot::channel().0,
+            },
+            OrderBookCommand::GetUserBalance {
+                user_id,
+                deadline: tokio::time::Instant::now(),
+                response_tx: oneshot::channel().0,
+            },
+            OrderBookCommand::CancelOrder {
+                user_id,
+                order_id: Uuid::new_v4(),
+                response_tx: oneshot::channel().0,
+            },
+        ];
+        assert_eq!(
+            log.append(&batch, Utc::now()).unwrap(),
+            vec![Some(1), None, Some(2)]
+        );
+        let log_id = log.log_id();
+        drop(log);
+
+        // A crash in the middle of the next append
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"seq":3,"at":"2024-"#).unwrap();
+        drop(file);
+
+        let (mut log, records) = CommandLog::open(&config).unwrap();
+        assert_eq!(log.log_id(), log_id);
+        assert_eq!(records.len(), 2);
+        assert!(matches!(
+            records[0].command,
+            LoggedCommand::AddFunds { amount, .. } if amount == 100.0
+        ));
+        assert_eq!(log.append(&batch[2..], Utc::now()).unwrap(), vec![Some(3)]);
+        drop(log);
+        assert_eq!(CommandLog::open(&config).unwrap().1.len(), 3);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut ids = OrderIds::with_log(log_id);
+        ids.begin(7);
+        let first = (ids.next_id(), ids.next_id());
+        ids.begin(7);
+        assert_eq!((ids.next_id(), ids.next_id()), first);
+        assert_ne!(first.0, first.1);
+        assert_eq!(first.0.get_version_num(), 4);
+    }
+
+    #[test]
+    fn test_compacting_cuts_the_commands_a_snapshot_holds() {
+        let path = std::env::temp_dir().join(format!("wal-{}.jsonl", Uuid::new_v4()));
+        let config = WalConfig {
+            path: path.clone(),
+            sync: WalSync::Batch,
+            backend: WalBackend::File,
+            snapshot_path: path.with_extension("snapshot"),
+            snapshot_every: 0,
+        };
+        let add_funds = || OrderBookCommand::AddFunds {
+            user_id: Uuid::nil(),
+            currency: "USD".to_string(),
+            amount: 100.0,
+            response_tx: oneshot::channel().0,
+        };
+        let (mut log, _) = CommandLog::open(&config).unwrap();
+        let batch = [add_funds(), add_funds(), add_funds()];
+        assert_eq!(
+            log.append(&batch, Utc::now()).unwrap(),
+            vec![Some(1), Some(2), Some(3)]
+        );
+        log.compact(2).unwrap();
+        assert_eq!(
+            log.append(&[add_funds()], Utc::now()).unwrap(),
+            vec![Some(4)]
+        );
+        let log_id = log.log_id();
+        drop(log);
+
+        let (log, records) = CommandLog::open(&config).unwrap();
+        assert_eq!((log.log_id(), log.last_seq()), (log_id, 4));
+        assert_eq!(
+            records.iter().map(|record| record.seq).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+use crate::engine::{EventLog, WalConfig, WalRecord};
+
+#[cfg(feature = "rocksdb")]
+use super::WalHeader;
+#[cfg(feature = "rocksdb")]
+use crate::engine::{EngineSnapshot, LoggedCommand, SnapshotStore, WalSync, WAL_VERSION};
+#[cfg(feature = "rocksdb")]
+use crate::messages::OrderBookCommand;
+#[cfg(feature = "rocksdb")]
+use chrono::{DateTime, Utc};
+#[cfg(feature = "rocksdb")]
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch,
+    WriteOptions, DB,
+};
+#[cfg(feature = "rocksdb")]
+use std::sync::Arc;
+#[cfg(feature = "rocksdb")]
+use uuid::Uuid;
+
+/// Column family of the logged commands, keyed by sequence number
+pub const EVENTS_CF: &str = "events";
+/// Column family of the latest snapshot, keyed by the last command it includes
+pub const SNAPSHOTS_CF: &str = "snapshots";
+/// Column family of the log's header
+pub const INDEXES_CF: &str = "indexes";
+
+#[cfg(feature = "rocksdb")]
+const HEADER_KEY: &[u8] = b"header";
+
+/// Open or create the RocksDB log in the directory at `config.path`
+#[cfg(feature = "rocksdb")]
+pub fn open_rocksdb_log(config: &WalConfig) -> Result<(Box<dyn EventLog>, Vec<WalRecord>), String> {
+    let (log, records) = RocksEventLog::open(config)?;
+    Ok((Box::new(log), records))
+}
+
+/// Without the `rocksdb` feature there is no database to open, and running
+/// without the log the engine was told to keep would lose commands
+#[cfg(not(feature = "rocksdb"))]
+pub fn open_rocksdb_log(config: &WalConfig) -> Result<(Box<dyn EventLog>, Vec<WalRecord>), String> {
+    Err(format!(
+        "WAL_BACKEND is rocksdb for {}, but this build has no RocksDB support; rebuild with --features rocksdb",
+        config.path.display()
+    ))
+}
+
+/// The command log and its snapshots in one RocksDB database. A batch of
+/// commands is written in a single atomic write, as is a snapshot with the
+/// removal of the one before it, and the pruning of the commands it holds
+/// with the header that records it.
+#[cfg(feature = "rocksdb")]
+pub struct RocksEventLog {
+    db: Arc<DB>,
+    sync: WalSync,
+    log_id: Uuid,
+    last_seq: u64,
+}
+
+#[cfg(feature = "rocksdb")]
+impl RocksEventLog {
+    /// Open or create the database, returning it with the records in it
+    /// after the latest snapshot, which are read from there on. RocksDB
+    /// recovers its own write-ahead log, so a command is either there whole
+    /// or not at all; an unreadable one is an error.
+    pub fn open(config: &WalConfig) -> Result<(Self, Vec<WalRecord>), String> {
+        let path = config.path.display();
+        let describe = |e: rocksdb::Error| format!("{}: {}", path, e);
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let families = [EVENTS_CF, SNAPSHOTS_CF, INDEXES_CF]
+            .map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+        let db = DB::open_cf_descriptors(&options, &config.path, families).map_err(describe)?;
+
+        let indexes = column_family(&db, INDEXES_CF)?;
+        let header = match db.get_cf(indexes, HEADER_KEY).map_err(describe)? {
+            Some(bytes) => serde_json::from_slice::<WalHeader>(&bytes)
+                .map_err(|e| format!("{}: unreadable header: {}", path, e))?,
+            None => {
+                let header = WalHeader {
+                    version: WAL_VERSION,
+                    log_id: Uuid::new_v4(),
+                    after_seq: 0,
+                };
+                let value = serde_json::to_vec(&header).map_err(|e| e.to_string())?;
+                let mut writes = WriteBatch::default();
+                writes.put_cf(indexes, HEADER_KEY, value);
+                db.write_opt(writes, &synced(true)).map_err(describe)?;
+                header
+            }
+        };
+        if header.version != WAL_VERSION {
+            return Err(format!(
+                "{}: log version {} is not the {} this build writes",
+                path, header.version, WAL_VERSION
+            ));
+        }
+
+        // A snapshot this build cannot restore holds nothing to skip
+        let snapshots = column_family(&db, SNAPSHOTS_CF)?;
+        let skip_through = match db.iterator_cf(snapshots, IteratorMode::End).next() {
+            Some(entry) => {
+                let (_, value) = entry.map_err(describe)?;
+                EngineSnapshot::position_in(&value)
+                    .filter(|(log_id, _)| *log_id == header.log_id)
+                    .map_or(0, |(_, seq)| seq)
+            }
+            None => 0,
+        };
+        let events = column_family(&db, EVENTS_CF)?;
+        let last_seq = match db.iterator_cf(events, IteratorMode::End).next() {
+            Some(entry) => {
+                let (key, _) = entry.map_err(describe)?;
+                let seq = <[u8; 8]>::try_from(&key[..])
+                    .map_err(|_| format!("{}: unreadable record key", path))?;
+                u64::from_be_bytes(seq)
+            }
+            None => header.after_seq,
+        };
+        let mut records = Vec::new();
+        let start = (skip_through + 1).to_be_bytes();
+        for entry in db.iterator_cf(events, IteratorMode::From(&start, Direction::Forward)) {
+            let (_, value) = entry.map_err(describe)?;
+            let record = serde_json::from_slice::<WalRecord>(&value)
+                .map_err(|e| format!("{}: unreadable record: {}", path, e))?;
+            records.push(record);
+        }
+
+        let log = RocksEventLog {
+            db: Arc::new(db),
+            sync: config.sync,
+            log_id: header.log_id,
+            last_seq,
+        };
+        Ok((log, records))
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl EventLog for RocksEventLog {
+    fn log_id(&self) -> Uuid {
+        self.log_id
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// The whole batch is one write, synced unless the policy is `never`;
+    /// `always` and `batch` therefore behave the same here
+    fn append(
+        &mut self,
+        batch: &[OrderBookCommand],
+        at: DateTime<Utc>,
+    ) -> Result<Vec<Option<u64>>, String> {
+        let events = column_family(&self.db, EVENTS_CF)?;
+        let mut seqs = Vec::with_capacity(batch.len());
+        let mut writes = WriteBatch::default();
+        let mut last_seq = self.last_seq;
+        for command in batch {
+            let Some(command) = LoggedCommand::from_command(command) else {
+                seqs.push(None);
+                continue;
+            };
+            let record = WalRecord {
+                seq: last_seq + 1,
+                at,
+                command,
+            };
+            let value = serde_json::to_vec(&record).map_err(|e| e.to_string())?;
+            writes.put_cf(events, record.seq.to_be_bytes(), value);
+            last_seq = record.seq;
+            seqs.push(Some(record.seq));
+        }
+        if last_seq > self.last_seq {
+            self.db
+                .write_opt(writes, &synced(self.sync != WalSync::Never))
+                .map_err(|e| e.to_string())?;
+            self.last_seq = last_seq;
+        }
+        Ok(seqs)
+    }
+
+    fn snapshots(&self) -> Arc<dyn SnapshotStore> {
+        Arc::new(RocksSnapshots {
+            db: self.db.clone(),
+        })
+    }
+
+    /// Delete the commands through `through` and record in the header that
+    /// the log now starts after them, in one write
+    fn compact(&mut self, through: u64) -> Result<(), String> {
+        let events = column_family(&self.db, EVENTS_CF)?;
+        let indexes = column_family(&self.db, INDEXES_CF)?;
+        let header = WalHeader {
+            version: WAL_VERSION,
+            log_id: self.log_id,
+            after_seq: through,
+        };
+        let value = serde_json::to_vec(&header).map_err(|e| e.to_string())?;
+        let mut writes = WriteBatch::default();
+        writes.delete_range_cf(events, 0u64.to_be_bytes(), (through + 1).to_be_bytes());
+        writes.put_cf(indexes, HEADER_KEY, value);
+        self.db
+            .write_opt(writes, &synced(true))
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The snapshots column family of a log's database
+#[cfg(feature = "rocksdb")]
+pub struct RocksSnapshots {
+    db: Arc<DB>,
+}
+
+#[cfg(feature = "rocksdb")]
+impl SnapshotStore for RocksSnapshots {
+    fn read_snapshot(&self) -> Result<Option<EngineSnapshot>, String> {
+        let snapshots = column_family(&self.db, SNAPSHOTS_CF)?;
+        match self.db.iterator_cf(snapshots, IteratorMode::End).next() {
+            Some(Ok((_, value))) => EngineSnapshot::from_json(&value).map(Some),
+            Some(Err(e)) => Err(e.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn write_snapshot(&self, snapshot: &EngineSnapshot) -> Result<(), String> {
+        let snapshots = column_family(&self.db, SNAPSHOTS_CF)?;
+        let value = serde_json::to_vec(snapshot).map_err(|e| e.to_string())?;
+        let key = snapshot.wal_seq.to_be_bytes();
+        let mut writes = WriteBatch::default();
+        writes.delete_range_cf(snapshots, 0u64.to_be_bytes(), key);
+        writes.put_cf(snapshots, key, value);
+        self.db
+            .write_opt(writes, &synced(true))
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+fn column_family<'a>(db: &'a DB, name: &str) -> Result<&'a ColumnFamily, String> {
+    db.cf_handle(name)
+        .ok_or_else(|| format!("Column family {} is missing", name))
+}
+
+#[cfg(feature = "rocksdb")]
+fn synced(sync: bool) -> WriteOptions {
+    let mut options = WriteOptions::default();
+    options.set_sync(sync);
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{WalBackend, WalSync};
+    use uuid::Uuid;
+
+    fn config() -> WalConfig {
+        let path = std::env::temp_dir().join(format!("wal-{}.rocksdb", Uuid::new_v4()));
+        WalConfig {
+            path: path.clone(),
+            sync: WalSync::Batch,
+            backend: WalBackend::RocksDb,
+            snapshot_path: path.with_extension("snapshot"),
+            snapshot_every: 0,
+        }
+    }
+
+    #[cfg(not(feature = "rocksdb"))]
+    #[test]
+    fn test_rocksdb_log_needs_the_feature() {
+        let error = open_rocksdb_log(&config()).err().unwrap();
+        assert!(error.contains("--features rocksdb"));
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_rocksdb_log_reopens_with_its_records_and_latest_snapshot() {
+        use crate::engine::{Engine, EngineConfig, EngineMetrics};
+        use std::sync::Arc;
+        use tokio::sync::oneshot;
+
+        let config = config();
+        let (mut log, records) = RocksEventLog::open(&config).unwrap();
+        assert!(records.is_empty());
+        assert!(log.snapshots().read_snapshot().unwrap().is_none());
+        let user_id = Uuid::new_v4();
+        let add_funds = || OrderBookCommand::AddFunds {
+            user_id,
+            currency: "USD".to_string(),
+            amount: 100.0,
+            response_tx: oneshot::channel().0,
+        };
+        let query = OrderBookCommand::GetUserBalance {
+            user_id,
+            deadline: tokio::time::Instant::now(),
+            response_tx: oneshot::channel().0,
+        };
+        assert_eq!(
+            log.append(&[add_funds(), query, add_funds()], Utc::now())
+                .unwrap(),
+            vec![Some(1), None, Some(2)]
+        );
+        assert_eq!(
+            log.append(&[add_funds()], Utc::now()).unwrap(),
+            vec![Some(3)]
+        );
+
+        let engine = Engine::new(Arc::new(EngineMetrics::new()), EngineConfig::default());
+        let snapshots = log.snapshots();
+        snapshots
+            .write_snapshot(&engine.snapshot(log.log_id(), 1).unwrap())
+            .unwrap();
+        snapshots
+            .write_snapshot(&engine.snapshot(log.log_id(), 2).unwrap())
+            .unwrap();
+        drop(snapshots);
+        let log_id = log.log_id();
+        drop(log);
+
+        // Reading starts after the latest snapshot
+        let (mut log, records) = RocksEventLog::open(&config).unwrap();
+        assert_eq!((log.log_id(), log.last_seq()), (log_id, 3));
+        assert_eq!(
+            records.iter().map(|record| record.seq).collect::<Vec<_>>(),
+            vec![3]
+        );
+        assert_eq!(log.snapshots().read_snapshot().unwrap().unwrap().wal_seq, 2);
+        let snapshots = column_family(&log.db, SNAPSHOTS_CF).unwrap();
+        assert_eq!(
+            log.db.iterator_cf(snapshots, IteratorMode::Start).count(),
+            1
+        );
+
+        // Compacting deletes what the snapshot holds
+        log.compact(2).unwrap();
+        let events = column_family(&log.db, EVENTS_CF).unwrap();
+        assert_eq!(log.db.iterator_cf(events, IteratorMode::Start).count(), 1);
+        log.compact(3).unwrap();
+        drop(log);
+
+        // and the header keeps the numbering when no command is left
+        let (mut log, records) = RocksEventLog::open(&config).unwrap();
+        assert_eq!((log.last_seq(), records.len()), (3, 0));
+        assert_eq!(
+            log.append(&[add_funds()], Utc::now()).unwrap(),
+            vec![Some(4)]
+        );
+        drop(log);
+        std::fs::remove_dir_all(&config.path).unwrap();
+    }
+}
@@ -0,0 +1,756 @@
+use async_graphql::futures_util::{stream, Stream, StreamExt};
+use async_graphql::{
+    Context, EmptyMutation, Error, Object, Result, Schema, SimpleObject, Subscription,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio::sync::{broadcast, oneshot};
+use uuid::Uuid;
+
+use crate::engine::{DepthFilter, MarketEvent, MarketMessage, OrderFill, TapeEntry};
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::orderbook::{DepthLevel, DepthTotals};
+use crate::state::{AppState, MaintenanceNotice};
+use crate::types::{MarketConfig, Order, Price};
+
+/// Most levels per side a depth query may ask for
+pub const MAX_DEPTH_LEVELS: usize = 100;
+
+/// Most entries a trades or orders query may return
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+pub type OrderbookSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+/// Build the schema; the app state is the only context resolvers need
+pub fn build_schema(state: AppState) -> OrderbookSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(state)
+        .finish()
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Market")]
+pub struct GqlMarket {
+    pub symbol: String,
+    pub feed_mode: String,
+    pub trading_status: String,
+    pub max_sweep_levels: Option<u64>,
+    pub max_sweep_notional: Option<f64>,
+}
+
+impl From<MarketConfig> for GqlMarket {
+    fn from(config: MarketConfig) -> Self {
+        GqlMarket {
+            symbol: config.symbol,
+            feed_mode: enum_name(&config.feed_mode),
+            trading_status: enum_name(&config.trading_status),
+            max_sweep_levels: config.sweep_limit.max_levels.map(|levels| levels as u64),
+            max_sweep_notional: config.sweep_limit.max_notional,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Level")]
+pub struct GqlLevel {
+    pub price: f64,
+    pub quantity: f64,
+    pub orders: u64,
+}
+
+impl From<DepthLevel> for GqlLevel {
+    fn from(level: DepthLevel) -> Self {
+        GqlLevel {
+            price: level.price.to_f64(),
+            quantity: level.quantity.to_f64(),
+            orders: level.orders as u64,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "DepthTotals")]
+pub struct GqlDepthTotals {
+    pub orders: u64,
+    pub quantity: f64,
+    pub notional: f64,
+}
+
+impl From<DepthTotals> for GqlDepthTotals {
+    fn from(totals: DepthTotals) -> Self {
+        GqlDepthTotals {
+            orders: totals.orders as u64,
+            quantity: totals.quantity.to_f64(),
+            notional: totals.notional,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Depth")]
+pub struct GqlDepth {
+    pub bids: Vec<GqlLevel>,
+    pub asks: Vec<GqlLevel>,
+    pub bid_totals: GqlDepthTotals, // Over the levels sent, not the whole side
+    pub ask_totals: GqlDepthTotals,
+    pub imbalance: Option<f64>, // From -1 (only asks) to 1 (only bids)
+    pub feed_seq: Option<u64>,  // Feed stamp; only set on subscription messages
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+impl GqlDepth {
+    fn new(bids: Vec<DepthLevel>, asks: Vec<DepthLevel>) -> Self {
+        let (bid_totals, ask_totals) = (DepthTotals::of(&bids), DepthTotals::of(&asks));
+        GqlDepth {
+            bids: bids.into_iter().map(GqlLevel::from).collect(),
+            asks: asks.into_iter().map(GqlLevel::from).collect(),
+            imbalance: DepthTotals::imbalance(&bid_totals, &ask_totals),
+            bid_totals: bid_totals.into(),
+            ask_totals: ask_totals.into(),
+            feed_seq: None,
+            sent_at: None,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Trade")]
+pub struct GqlTrade {
+    pub seq: u64,
+    pub trade_id: String,
+    pub price: f64,
+    pub quantity: f64,
+    pub taker_side: String,
+    pub timestamp: DateTime<Utc>,
+    pub feed_seq: Option<u64>, // Feed stamp; only set on subscription messages
+    pub sent_at: Option<DateTime<Utc>>,
+}
+
+impl From<TapeEntry> for GqlTrade {
+    fn from(entry: TapeEntry) -> Self {
+        GqlTrade {
+            seq: entry.seq,
+            trade_id: entry.trade_id.to_string(),
+            price: entry.price.to_f64(),
+            quantity: entry.quantity.to_f64(),
+            taker_side: enum_name(&entry.taker_side),
+            timestamp: entry.timestamp,
+            feed_seq: None,
+            sent_at: None,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Fill")]
+pub struct GqlFill {
+    pub order_id: String,
+    pub fill_seq: u64,
+    pub trade_id: String,
+    pub role: String,
+    pub price: f64,
+    pub quantity: f64,
+    pub fee: f64,
+    pub remaining_quantity: f64,
+    pub timestamp: DateTime<Utc>,
+    pub feed_seq: u64,
+    pub sent_at: DateTime<Utc>,
+}
+
+impl GqlFill {
+    fn new(fill: OrderFill, feed_seq: u64, sent_at: DateTime<Utc>) -> Self {
+        GqlFill {
+            order_id: fill.order_id.to_string(),
+            fill_seq: fill.fill_seq,
+            trade_id: fill.trade_id.to_string(),
+            role: enum_name(&fill.role),
+            price: fill.price.to_f64(),
+            quantity: fill.quantity.to_f64(),
+            fee: fill.fee,
+            remaining_quantity: fill.remaining_quantity.to_f64(),
+            timestamp: fill.timestamp,
+            feed_seq,
+            sent_at,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Order")]
+pub struct GqlOrder {
+    pub id: String,
+    pub side: String,
+    pub order_type: String,
+    pub time_in_force: String,
+    pub price: Option<f64>,
+    pub original_quantity: f64,
+    pub remaining_quantity: f64,
+    pub status: String,
+    pub source: String,
+    pub client_order_id: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl From<Order> for GqlOrder {
+    fn from(order: Order) -> Self {
+        GqlOrder {
+            id: order.id.to_string(),
+            side: enum_name(&order.side),
+            order_type: enum_name(&order.order_type),
+            time_in_force: enum_name(&order.time_in_force),
+            price: order.price.map(|p| p.to_f64()),
+            original_quantity: order.original_quantity.to_f64(),
+            remaining_quantity: order.remaining_quantity.to_f64(),
+            status: enum_name(&order.status),
+            source: enum_name(&order.source),
+            client_order_id: order.client_order_id,
+            timestamp: order.timestamp,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "Balance")]
+pub struct GqlBalance {
+    pub currency: String,
+    pub amount: f64,
+}
+
+#[derive(SimpleObject)]
+#[graphql(name = "MaintenanceNotice")]
+pub struct GqlMaintenanceNotice {
+    pub message: String,
+    pub scheduled_at: DateTime<Utc>,
+    pub announced_at: DateTime<Utc>,
+}
+
+impl From<MaintenanceNotice> for GqlMaintenanceNotice {
+    fn from(notice: MaintenanceNotice) -> Self {
+        GqlMaintenanceNotice {
+            message: notice.message,
+            scheduled_at: notice.scheduled_at,
+            announced_at: notice.announced_at,
+        }
+    }
+}
+
+/// Wire name of a unit enum, so GraphQL values match the REST payloads
+fn enum_name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+fn unexpected_response() -> Error {
+    Error::new("Unexpected response from orderbook")
+}
+
+/// `levels` if the caller may see that deep: anyone up to the public depth
+/// limit, and a caller with a bearer token up to `MAX_DEPTH_LEVELS`
+fn allowed_depth(ctx: &Context<'_>, levels: usize) -> Result<usize> {
+    let limit = ctx.data::<AppState>()?.public_depth_limit;
+    if levels > limit && ctx.data_opt::<Uuid>().is_none() {
+        return Err(Error::new(format!(
+            "A depth beyond {} levels needs a bearer token",
+            limit
+        )));
+    }
+    Ok(levels.clamp(1, MAX_DEPTH_LEVELS))
+}
+
+/// User id of the bearer token the request came with, if any
+fn require_user(ctx: &Context<'_>) -> Result<Uuid> {
+    ctx.data_opt::<Uuid>()
+        .copied()
+        .ok_or_else(|| Error::new("Not authenticated"))
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Markets served by this engine
+    async fn markets(&self, ctx: &Context<'_>) -> Result<Vec<GqlMarket>> {
+        let state = ctx.data::<AppState>()?;
+        let (response_tx, response_rx) = oneshot::channel();
+        let deadline = state.deadline();
+        let command = OrderBookCommand::GetMarketConfig {
+            deadline,
+            response_tx,
+        };
+        match state.dispatch(command, response_rx, deadline).await? {
+            OrderBookResponse::MarketConfig { config } => Ok(vec![config.into()]),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Aggregated book depth, best price first. Beyond the public depth limit
+    /// it requires a bearer token.
+    async fn depth(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 10)] levels: usize,
+    ) -> Result<GqlDepth> {
+        let state = ctx.data::<AppState>()?;
+        let depth = allowed_depth(ctx, levels)?;
+        let (response_tx, response_rx) = oneshot::channel();
+        let deadline = state.deadline();
+        let command = OrderBookCommand::GetOrderBook {
+            depth,
+            deadline,
+            response_tx,
+        };
+        match state.dispatch(command, response_rx, deadline).await? {
+            OrderBookResponse::OrderBookDepth { bids, asks } => Ok(GqlDepth::new(bids, asks)),
+            OrderBookResponse::OrderBookByOrder { bids, asks } => {
+                // L3 markets report individual orders; fold them back into levels
+                let aggregate = |entries: Vec<crate::orderbook::OrderEntry>| {
+                    let mut levels: Vec<DepthLevel> = Vec::new();
+                    for entry in entries {
+                        match levels.last_mut() {
+                            Some(level) if level.price == entry.price => {
+                                level.quantity += entry.quantity;
+                                level.orders += 1;
+                            }
+                            _ => levels.push(DepthLevel::new(entry.price, entry.quantity, 1)),
+                        }
+                    }
+                    levels
+                };
+                Ok(GqlDepth::new(aggregate(bids), aggregate(asks)))
+            }
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Public trades after the `after` sequence number, oldest first
+    async fn trades(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<u64>,
+        #[graphql(default = 100)] limit: usize,
+    ) -> Result<Vec<GqlTrade>> {
+        let state = ctx.data::<AppState>()?;
+        let (response_tx, response_rx) = oneshot::channel();
+        let deadline = state.deadline();
+        let command = OrderBookCommand::GetTradeTape {
+            after,
+            limit: limit.clamp(1, MAX_QUERY_LIMIT),
+            deadline,
+            response_tx,
+        };
+        match state.dispatch(command, response_rx, deadline).await? {
+            OrderBookResponse::TradeTape { page } => {
+                Ok(page.entries.into_iter().map(GqlTrade::from).collect())
+            }
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// The caller's orders, newest first. Requires a bearer token.
+    async fn my_orders(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 100)] limit: usize,
+    ) -> Result<Vec<GqlOrder>> {
+        let user_id = require_user(ctx)?;
+        let state = ctx.data::<AppState>()?;
+        let (response_tx, response_rx) = oneshot::channel();
+        let deadline = state.deadline();
+        let command = OrderBookCommand::GetOrderHistory {
+            user_id,
+            limit: limit.clamp(1, MAX_QUERY_LIMIT),
+            deadline,
+            response_tx,
+        };
+        match state.dispatch(command, response_rx, deadline).await? {
+            OrderBookResponse::OrderHistory { orders } => {
+                Ok(orders.into_iter().map(GqlOrder::from).collect())
+            }
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// The caller's balances by currency. Requires a bearer token.
+    async fn my_balances(&self, ctx: &Context<'_>) -> Result<Vec<GqlBalance>> {
+        let user_id = require_user(ctx)?;
+        let state = ctx.data::<AppState>()?;
+        let (response_tx, response_rx) = oneshot::channel();
+        let deadline = state.deadline();
+        let command = OrderBookCommand::GetUserBalance {
+            user_id,
+            deadline,
+            response_tx,
+        };
+        match state.dispatch(command, response_rx, deadline).await? {
+            OrderBookResponse::UserBalance { balance } => {
+                let sorted: BTreeMap<String, f64> = balance.balances.into_iter().collect();
+                Ok(sorted
+                    .into_iter()
+                    .map(|(currency, amount)| GqlBalance { currency, amount })
+                    .collect())
+            }
+            // Users who never funded an account have no balances yet
+            OrderBookResponse::Error { .. } => Ok(Vec::new()),
+            _ => Err(unexpected_response()),
+        }
+    }
+}
+
+/// Engine events as a stream; a subscriber that falls behind skips what it missed
+fn market_events(rx: broadcast::Receiver<MarketMessage>) -> impl Stream<Item = MarketMessage> {
+    stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((event, rx)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// The last `snapshot` trades, oldest first and without a feed stamp,
+    /// then every public trade as it prints, stamped with the feed sequence
+    /// and send time. `seq` runs on from the snapshot with no gap or repeat.
+    async fn trades(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 50)] snapshot: usize,
+    ) -> Result<impl Stream<Item = GqlTrade>> {
+        let state = ctx.data::<AppState>()?;
+        // Subscribe first, so a trade printed while the snapshot is taken is
+        // in one or the other
+        let rx = state.events.subscribe();
+
+        let (recent, last_seq) = if snapshot == 0 {
+            (Vec::new(), 0)
+        } else {
+            let (response_tx, response_rx) = oneshot::channel();
+            let deadline = state.deadline();
+            let command = OrderBookCommand::GetRecentTrades {
+                limit: snapshot.min(MAX_QUERY_LIMIT),
+                deadline,
+                response_tx,
+            };
+            match state.dispatch(command, response_rx, deadline).await? {
+                OrderBookResponse::TradeTape { page } => (page.entries, page.last_seq),
+                _ => return Err(unexpected_response()),
+            }
+        };
+
+        let live = market_events(rx).filter_map(move |message| async move {
+            match message.event {
+                MarketEvent::Trade(entry) if entry.seq > last_seq => Some(GqlTrade {
+                    feed_seq: Some(message.seq),
+                    sent_at: Some(message.sent_at),
+                    ..GqlTrade::from(entry)
+                }),
+                _ => None,
+            }
+        });
+        Ok(stream::iter(recent.into_iter().map(GqlTrade::from)).chain(live))
+    }
+
+    /// Top of book after every change. `levels` keeps the best N levels per
+    /// side and `minPrice`/`maxPrice` a price window; a change that leaves the
+    /// kept levels as they were is not sent.
+    async fn depth(
+        &self,
+        ctx: &Context<'_>,
+        levels: Option<usize>,
+        min_price: Option<f64>,
+        max_price: Option<f64>,
+    ) -> Result<impl Stream<Item = GqlDepth>> {
+        if levels == Some(0) {
+            return Err(Error::new("levels must be at least 1"));
+        }
+        if min_price.zip(max_price).is_some_and(|(min, max)| min > max) {
+            return Err(Error::new("minPrice cannot exceed maxPrice"));
+        }
+        let price_bound = |field: &str, value: Option<f64>| {
+            value
+                .map(Price::try_from_f64)
+                .transpose()
+                .map_err(|e| Error::new(format!("{} is {}", field, e)))
+        };
+        let filter = DepthFilter {
+            top: levels
+                .map(|levels| allowed_depth(ctx, levels))
+                .transpose()?,
+            min_price: price_bound("minPrice", min_price)?,
+            max_price: price_bound("maxPrice", max_price)?,
+        };
+
+        let rx = ctx.data::<AppState>()?.events.subscribe();
+        let mut last_sent = None;
+        Ok(market_events(rx).filter_map(move |message| {
+            let update = match message.event {
+                MarketEvent::Depth { bids, asks, .. } => {
+                    let view = (filter.apply(bids), filter.apply(asks));
+                    if last_sent.as_ref() == Some(&view) {
+                        None
+                    } else {
+                        last_sent = Some(view.clone());
+                        Some(GqlDepth {
+                            feed_seq: Some(message.seq),
+                            sent_at: Some(message.sent_at),
+                            ..GqlDepth::new(view.0, view.1)
+                        })
+                    }
+                }
+                _ => None,
+            };
+            async move { update }
+        }))
+    }
+
+    /// Executions of the caller's orders, each order's in fill_seq order. A gap
+    /// in fill_seq means fills were missed; fetch them from the order's fills
+    /// endpoint. Requires a bearer token.
+    async fn my_fills(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = GqlFill>> {
+        let user_id = require_user(ctx)?;
+        let rx = ctx.data::<AppState>()?.events.subscribe();
+        Ok(market_events(rx).filter_map(move |message| async move {
+            match message.event {
+                MarketEvent::Fill(fill) if fill.user_id == user_id => {
+                    Some(GqlFill::new(fill, message.seq, message.sent_at))
+                }
+                _ => None,
+            }
+        }))
+    }
+
+    /// The announced maintenance notice, or null if there is none, then the
+    /// notice again each time an operator replaces or withdraws it
+    async fn maintenance(
+        &self,
+        ctx: &Context<'_>,
+    ) -> Result<impl Stream<Item = Option<GqlMaintenanceNotice>>> {
+        let rx = ctx.data::<AppState>()?.maintenance.subscribe();
+        Ok(stream::unfold((rx, true), |(mut rx, first)| async move {
+            if !first && rx.changed().await.is_err() {
+                return None;
+            }
+            let notice = rx
+                .borrow_and_update()
+                .clone()
+                .map(GqlMaintenanceNotice::from);
+            Some((notice, (rx, false)))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{event_channel, run_orderbook_engine, EngineConfig, EngineMetrics};
+    use crate::testing::LimitOrder;
+    use crate::types::{OrderSide, Price, Quantity};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_queries_and_trade_subscription() {
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        let events = event_channel();
+        tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            events.clone(),
+            EngineConfig::default(),
+        ));
+        let schema = build_schema(AppState::new(tx, metrics).with_events(events.clone()));
+
+        let response = schema
+            .execute("{ markets { symbol } depth(levels: 5) { bids { price } } }")
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let data = response.data.into_json().unwrap();
+        assert_eq!(data["markets"][0]["symbol"], "BTC-USD");
+        assert_eq!(data["depth"]["bids"], serde_json::json!([]));
+
+        let response = schema.execute("{ myBalances { currency } }").await;
+        assert_eq!(response.errors[0].message, "Not authenticated");
+
+        // Deeper than the public limit takes a bearer token
+        let response = schema
+            .execute("{ depth(levels: 51) { bids { price } } }")
+            .await;
+        assert_eq!(
+            response.errors[0].message,
+            "A depth beyond 50 levels needs a bearer token"
+        );
+        let request = async_graphql::Request::new("{ depth(levels: 51) { bids { price } } }")
+            .data(Uuid::new_v4());
+        assert!(schema.execute(request).await.errors.is_empty());
+
+        let mut trades = schema
+            .execute_stream("subscription { trades(snapshot: 0) { seq price feedSeq sentAt } }");
+        let entry = TapeEntry {
+            seq: 7,
+            trade_id: Uuid::new_v4(),
+            price: Price::from_f64(100.0),
+            quantity: Quantity::from_f64(1.0),
+            taker_side: OrderSide::Buy,
+            timestamp: Utc::now(),
+        };
+        // The subscription registers its receiver on first poll
+        let next = tokio::spawn(async move { trades.next().await });
+        while events.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        events.send(MarketMessage {
+            seq: 3,
+            sent_at: Utc::now(),
+            event: MarketEvent::Trade(entry),
+        });
+        let data = next.await.unwrap().unwrap().data.into_json().unwrap();
+        assert_eq!(data["trades"]["seq"], 7);
+        assert_eq!(data["trades"]["price"], 100.0);
+        assert_eq!(data["trades"]["feedSeq"], 3);
+        assert!(data["trades"]["sentAt"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_trade_subscription_starts_with_a_snapshot() {
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        let events = event_channel();
+        tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            events.clone(),
+            EngineConfig::default(),
+        ));
+        let state = AppState::new(tx, metrics).with_events(events.clone());
+        let schema = build_schema(state.clone());
+
+        // Two trades on the tape before anyone subscribes
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+        for (user_id, currency, amount) in [(buyer, "USD", 1_000.0), (seller, "BTC", 4.0)] {
+            let (response_tx, response_rx) = oneshot::channel();
+            let command = OrderBookCommand::AddFunds {
+                user_id,
+                currency: currency.to_string(),
+                amount,
+                response_tx,
+            };
+            state.orderbook_tx.send(command).await.unwrap();
+            response_rx.await.unwrap();
+        }
+        for (user_id, side) in [(buyer, OrderSide::Buy), (seller, OrderSide::Sell)] {
+            for _ in 0..2 {
+                let (response_tx, response_rx) = oneshot::channel();
+                let command = LimitOrder::new(user_id, side, 100.0, 1.0).command(response_tx);
+                state.orderbook_tx.send(command).await.unwrap();
+                response_rx.await.unwrap();
+            }
+        }
+
+        let mut trades =
+            schema.execute_stream("subscription { trades(snapshot: 1) { seq feedSeq } }");
+        let first = trades.next().await.unwrap().data.into_json().unwrap();
+        assert_eq!(first["trades"]["seq"], 2);
+        assert!(first["trades"]["feedSeq"].is_null());
+
+        // A print already in the snapshot is not repeated
+        for seq in [2, 3] {
+            let entry = TapeEntry {
+                seq,
+                trade_id: Uuid::new_v4(),
+                price: Price::from_f64(100.0),
+                quantity: Quantity::from_f64(1.0),
+                taker_side: OrderSide::Sell,
+                timestamp: Utc::now(),
+            };
+            events.send(MarketMessage {
+                seq: 10 + seq,
+                sent_at: Utc::now(),
+                event: MarketEvent::Trade(entry),
+            });
+        }
+        let live = trades.next().await.unwrap().data.into_json().unwrap();
+        assert_eq!(live["trades"]["seq"], 3);
+        assert_eq!(live["trades"]["feedSeq"], 13);
+    }
+
+    #[tokio::test]
+    async fn test_depth_subscription_skips_changes_outside_its_window() {
+        let events = event_channel();
+        let (tx, _rx) = mpsc::channel(1);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        let schema = build_schema(AppState::new(tx, metrics).with_events(events.clone()));
+
+        let mut depth = schema.execute_stream(
+            "subscription { depth(levels: 1, minPrice: 100.0) { bids { price } feedSeq } }",
+        );
+        let next = tokio::spawn(async move {
+            let first = depth.next().await;
+            let second = depth.next().await;
+            (first, second)
+        });
+        while events.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let level = |price: f64, quantity: f64| {
+            DepthLevel::new(Price::from_f64(price), Quantity::from_f64(quantity), 1)
+        };
+        let publish = |seq, bids: Vec<DepthLevel>| {
+            events.send(MarketMessage {
+                seq,
+                sent_at: Utc::now(),
+                event: MarketEvent::Depth {
+                    bids,
+                    asks: Vec::new(),
+                    imbalance: None,
+                },
+            });
+        };
+        publish(1, vec![level(101.0, 1.0), level(99.0, 1.0)]);
+        // Below the window, and behind the one level followed
+        publish(
+            2,
+            vec![level(101.0, 1.0), level(100.5, 2.0), level(99.0, 5.0)],
+        );
+        publish(3, vec![level(101.0, 3.0)]);
+
+        let (first, second) = next.await.unwrap();
+        let first = first.unwrap().data.into_json().unwrap();
+        assert_eq!(first["depth"]["feedSeq"], 1);
+        assert_eq!(
+            first["depth"]["bids"],
+            serde_json::json!([{ "price": 101.0 }])
+        );
+        let second = second.unwrap().data.into_json().unwrap();
+        assert_eq!(second["depth"]["feedSeq"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_subscription_follows_announcements() {
+        let (tx, _rx) = mpsc::channel(1);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        let state = AppState::new(tx, metrics);
+        let schema = build_schema(state.clone());
+
+        let now = Utc::now();
+        let notice =
+            MaintenanceNotice::new("Upgrade", now + chrono::Duration::hours(1), now).unwrap();
+        state.maintenance.announce(notice);
+        let mut maintenance =
+            schema.execute_stream("subscription { maintenance { message scheduledAt } }");
+
+        let first = maintenance.next().await.unwrap().data.into_json().unwrap();
+        assert_eq!(first["maintenance"]["message"], "Upgrade");
+        state.maintenance.clear();
+        let second = maintenance.next().await.unwrap().data.into_json().unwrap();
+        assert!(second["maintenance"].is_null());
+    }
+}
@@ -0,0 +1,894 @@
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::engine::MAX_CLOCK_ADVANCE;
+use crate::handlers::market::synthetic_json;
+use crate::handlers::orders::positive_price;
+use crate::ledger::JournalKind;
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::orderbook::DepthLevel;
+use crate::state::{AppState, MaintenanceNotice};
+use crate::types::{
+    IndexComponent, SyntheticIndex, TradeThroughBand, TradingStatus, DEFAULT_MARKET,
+};
+use crate::utils::error::ApiError;
+use crate::utils::response::ApiResponse;
+
+/// The dashboard page. It holds no data itself; every number on it comes from
+/// `/admin/dashboard/summary`, which requires the admin token.
+const DASHBOARD_HTML: &str = include_str!("../../static/admin/dashboard.html");
+
+/// Upper bound for each of the summary's list sizes
+const MAX_DASHBOARD_ROWS: usize = 500;
+
+/// Most journals one request may list
+const MAX_JOURNALS: usize = 1_000;
+
+#[derive(Debug, Deserialize)]
+pub struct DashboardQuery {
+    pub depth: Option<usize>,  // Levels per side, defaults to 10
+    pub trades: Option<usize>, // Most recent trades, defaults to 20
+    pub users: Option<usize>,  // Accounts listed, defaults to 50
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BalanceAdjustmentRequest {
+    pub user_id: String,
+    pub currency: String,
+    pub amount: f64, // Signed: negative debits the account
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JournalQuery {
+    pub kind: Option<JournalKind>, // Every kind when omitted
+    pub limit: Option<usize>,      // Defaults to 100
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FxRateRequest {
+    pub rate: f64, // Units of quote per unit of base
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IncidentQuery {
+    pub status: Option<String>, // "open" (default) or "all"
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveIncidentRequest {
+    pub note: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeadLetterQuery {
+    pub status: Option<String>, // "pending" (default) or "all"
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompensateDeadLetterRequest {
+    pub note: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AdvanceClockRequest {
+    pub seconds: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MaintenanceNoticeRequest {
+    pub message: String,
+    pub scheduled_at: DateTime<Utc>, // RFC 3339
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TradeThroughBandRequest {
+    pub reference_price: Option<f64>, // Omit to switch protection off
+    pub band_bps: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SettleMarketRequest {
+    pub settlement_price: Option<f64>, // Omit to use the market's reference price
+}
+
+fn level_json(level: &DepthLevel) -> serde_json::Value {
+    serde_json::json!({
+        "price": level.price.to_f64(),
+        "quantity": level.quantity.to_f64(),
+        "orders": level.orders,
+    })
+}
+
+#[get("/admin/dashboard")]
+pub async fn admin_dashboard() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(DASHBOARD_HTML)
+}
+
+#[get("/dashboard/summary")]
+pub async fn get_dashboard_summary(
+    state: web::Data<AppState>,
+    query: web::Query<DashboardQuery>,
+) -> Result<impl Responder, ApiError> {
+    let depth = query.depth.unwrap_or(10).min(MAX_DASHBOARD_ROWS);
+    let trades = query.trades.unwrap_or(20).min(MAX_DASHBOARD_ROWS);
+    let users = query.users.unwrap_or(50).min(MAX_DASHBOARD_ROWS);
+
+    // Commands waiting for the engine, read before ours joins the queue
+    let queue_capacity = state.orderbook_tx.max_capacity();
+    let queue_depth = queue_capacity - state.orderbook_tx.capacity();
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetDashboard {
+        depth,
+        trades,
+        users,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::Dashboard { snapshot } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "engine": {
+                    "queue_depth": queue_depth,
+                    "queue_capacity": queue_capacity,
+                    "metrics": state.metrics.snapshot(),
+                },
+                "book": {
+                    "bids": snapshot.bids.iter().map(level_json).collect::<Vec<_>>(),
+                    "asks": snapshot.asks.iter().map(level_json).collect::<Vec<_>>(),
+                    "resting_orders": snapshot.resting_orders,
+                },
+                "recent_trades": snapshot.recent_trades.iter().map(|entry| serde_json::json!({
+                    "seq": entry.seq,
+                    "price": entry.price.to_f64(),
+                    "quantity": entry.quantity.to_f64(),
+                    "taker_side": entry.taker_side,
+                    "timestamp": entry.timestamp,
+                })).collect::<Vec<_>>(),
+                "users": {
+                    "count": snapshot.user_count,
+                    "balance_totals": snapshot.balance_totals,
+                    "accounts": snapshot.users,
+                },
+            })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Shared by halt and resume: both just set the market's trading status
+async fn set_trading_status(
+    state: &AppState,
+    market: String,
+    status: TradingStatus,
+) -> Result<ApiResponse, ApiError> {
+    if market != DEFAULT_MARKET {
+        return Err(ApiError::NotFound(format!("Unknown market '{}'", market)));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::SetTradingStatus {
+        market,
+        status,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::MarketConfig { config } => Ok(ApiResponse::ok(config)),
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+#[post("/markets/{market}/halt")]
+pub async fn halt_market(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    set_trading_status(&state, path.into_inner(), TradingStatus::Halted).await
+}
+
+#[post("/markets/{market}/resume")]
+pub async fn resume_market(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    set_trading_status(&state, path.into_inner(), TradingStatus::Trading).await
+}
+
+/// Close a dated market now instead of at its expiry, or one whose expiry
+/// passed with nothing traded to take a settlement price from
+#[post("/markets/{market}/settle")]
+pub async fn settle_market(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<SettleMarketRequest>,
+) -> Result<impl Responder, ApiError> {
+    let market = path.into_inner();
+    if market != DEFAULT_MARKET {
+        return Err(ApiError::NotFound(format!("Unknown market '{}'", market)));
+    }
+    let price = body
+        .settlement_price
+        .map(|price| positive_price("settlement_price", price))
+        .transpose()
+        .map_err(ApiError::BadRequest)?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::SettleMarket {
+        market,
+        price,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::MarketConfig { config } => Ok(ApiResponse::ok(config)),
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+#[put("/markets/{market}/trade-through")]
+pub async fn set_trade_through_band(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<TradeThroughBandRequest>,
+) -> Result<impl Responder, ApiError> {
+    let reference_price = body
+        .reference_price
+        .map(|price| positive_price("reference_price", price))
+        .transpose()
+        .map_err(ApiError::BadRequest)?;
+    let band = TradeThroughBand {
+        reference_price,
+        band_bps: body.band_bps,
+    };
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::SetTradeThroughBand {
+        market: path.into_inner(),
+        band,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::MarketConfig { config } => Ok(ApiResponse::ok(config)),
+        OrderBookResponse::Error { message } => Err(ApiError::NotFound(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyntheticIndexRequest {
+    pub components: Vec<IndexComponent>,
+    pub divisor: Option<f64>, // 1 when omitted
+}
+
+/// Define a synthetic index over the market, or replace its definition
+#[put("/synthetics/{symbol}")]
+pub async fn define_synthetic_index(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<SyntheticIndexRequest>,
+) -> Result<impl Responder, ApiError> {
+    let body = body.into_inner();
+    let index = SyntheticIndex::new(
+        path.into_inner(),
+        body.components,
+        body.divisor.unwrap_or(1.0),
+    )
+    .map_err(ApiError::BadRequest)?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::DefineSyntheticIndex { index, response_tx };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::SyntheticIndex { quote } => {
+            Ok(ApiResponse::ok(synthetic_json(&quote)))
+        }
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Remove a synthetic index. Refused while stop orders wait on it.
+#[delete("/synthetics/{symbol}")]
+pub async fn remove_synthetic_index(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::RemoveSyntheticIndex {
+        symbol: path.into_inner(),
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::SyntheticIndex { quote } => {
+            Ok(ApiResponse::ok(synthetic_json(&quote)))
+        }
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+#[delete("/orders/{order_id}")]
+pub async fn force_cancel_order(
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    let order_id = Uuid::parse_str(&path)
+        .map_err(|_| ApiError::BadRequest("Invalid order_id format".to_string()))?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::ForceCancelOrder {
+        order_id,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::OrderCancelled { order_id, success } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "order_id": order_id.to_string(),
+                "cancelled": success,
+            })))
+        }
+        OrderBookResponse::Error { message } => Err(ApiError::NotFound(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+#[post("/balances")]
+pub async fn adjust_balance(
+    state: web::Data<AppState>,
+    body: web::Json<BalanceAdjustmentRequest>,
+) -> Result<impl Responder, ApiError> {
+    let user_id = Uuid::parse_str(&body.user_id)
+        .map_err(|_| ApiError::BadRequest("Invalid user_id format".to_string()))?;
+    if body.currency != "USD" && body.currency != "BTC" {
+        return Err(ApiError::BadRequest(
+            "Currency must be 'USD' or 'BTC'".to_string(),
+        ));
+    }
+    if !body.amount.is_finite() || body.amount == 0.0 {
+        return Err(ApiError::BadRequest(
+            "amount must be a non-zero number".to_string(),
+        ));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::AdjustBalance {
+        user_id,
+        currency: body.currency.clone(),
+        amount: body.amount,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::FundsAdded {
+            user_id,
+            currency,
+            new_balance,
+        } => Ok(ApiResponse::ok(serde_json::json!({
+            "user_id": user_id.to_string(),
+            "currency": currency,
+            "adjustment": body.amount,
+            "new_balance": new_balance,
+        }))),
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Every ledger account with a non-zero balance, debits and credits totalled
+/// per currency. `balanced` is false only if the books no longer sum to zero.
+#[get("/ledger/trial-balance")]
+pub async fn get_trial_balance(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetTrialBalance {
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::TrialBalance { report } => Ok(ApiResponse::ok(report)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// The most recent ledger journals, newest first. Fee journals charged in a
+/// currency the user did not receive carry the FX rate they were valued at.
+#[get("/ledger/journals")]
+pub async fn get_journals(
+    state: web::Data<AppState>,
+    query: web::Query<JournalQuery>,
+) -> Result<impl Responder, ApiError> {
+    let limit = query.limit.unwrap_or(100).min(MAX_JOURNALS);
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetJournals {
+        kind: query.kind,
+        limit,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::Journals { journals } => {
+            Ok(ApiResponse::ok(serde_json::json!({ "journals": journals })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+#[get("/fx-rates")]
+pub async fn get_fx_rates(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetFxRates {
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::FxRates { rates } => {
+            Ok(ApiResponse::ok(serde_json::json!({ "rates": rates })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Set the rate fees are valued at from now on. Journals already posted keep
+/// the rate they were charged under.
+#[put("/fx-rates/{base}/{quote}")]
+pub async fn set_fx_rate(
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    body: web::Json<FxRateRequest>,
+) -> Result<impl Responder, ApiError> {
+    let (base, quote) = currency_pair(path.into_inner())?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::SetFxRate {
+        base,
+        quote,
+        rate: body.rate,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::FxRate { rate } => Ok(ApiResponse::ok(rate)),
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+#[delete("/fx-rates/{base}/{quote}")]
+pub async fn remove_fx_rate(
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> Result<impl Responder, ApiError> {
+    let (base, quote) = currency_pair(path.into_inner())?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::RemoveFxRate {
+        base,
+        quote,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::FxRate { rate } => Ok(ApiResponse::ok(rate)),
+        OrderBookResponse::Error { message } => Err(ApiError::NotFound(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Upper-cased currency codes of a pair, which must be 3 to 10 letters
+fn currency_pair((base, quote): (String, String)) -> Result<(String, String), ApiError> {
+    let valid = |code: &str| {
+        (3..=10).contains(&code.len()) && code.chars().all(|c| c.is_ascii_alphabetic())
+    };
+    if !valid(&base) || !valid(&quote) {
+        return Err(ApiError::BadRequest(
+            "Currency codes must be 3 to 10 letters".to_string(),
+        ));
+    }
+    Ok((base.to_ascii_uppercase(), quote.to_ascii_uppercase()))
+}
+
+/// Incidents opened when a settlement or refund would have driven a balance
+/// negative. Each one quarantined the account involved.
+#[get("/incidents")]
+pub async fn get_incidents(
+    state: web::Data<AppState>,
+    query: web::Query<IncidentQuery>,
+) -> Result<impl Responder, ApiError> {
+    let open_only = match query.status.as_deref() {
+        None | Some("open") => true,
+        Some("all") => false,
+        Some(_) => {
+            return Err(ApiError::BadRequest(
+                "status must be 'open' or 'all'".to_string(),
+            ))
+        }
+    };
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetIncidents {
+        open_only,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::Incidents { incidents } => {
+            Ok(ApiResponse::ok(serde_json::json!({ "incidents": incidents })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Close an incident after review. The account is released from quarantine
+/// once none of its incidents are left open.
+#[post("/incidents/{id}/resolve")]
+pub async fn resolve_incident(
+    state: web::Data<AppState>,
+    path: web::Path<u64>,
+    body: web::Json<ResolveIncidentRequest>,
+) -> Result<impl Responder, ApiError> {
+    let note = body.note.trim();
+    if note.is_empty() {
+        return Err(ApiError::BadRequest(
+            "note must describe the resolution".to_string(),
+        ));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::ResolveIncident {
+        id: path.into_inner(),
+        note: note.to_string(),
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::Incident { incident } => Ok(ApiResponse::ok(incident)),
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Trades that matched but could not settle. Pending ones are retried with
+/// backoff and compensated once their retries run out.
+#[get("/settlements/dead-letters")]
+pub async fn get_dead_letters(
+    state: web::Data<AppState>,
+    query: web::Query<DeadLetterQuery>,
+) -> Result<impl Responder, ApiError> {
+    let pending_only = match query.status.as_deref() {
+        None | Some("pending") => true,
+        Some("all") => false,
+        Some(_) => {
+            return Err(ApiError::BadRequest(
+                "status must be 'pending' or 'all'".to_string(),
+            ))
+        }
+    };
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetDeadLetters {
+        pending_only,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::DeadLetters { letters } => {
+            Ok(ApiResponse::ok(serde_json::json!({ "dead_letters": letters })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Retry a held settlement now, typically after fixing the balance that
+/// refused it
+#[post("/settlements/dead-letters/{id}/retry")]
+pub async fn retry_dead_letter(
+    state: web::Data<AppState>,
+    path: web::Path<u64>,
+) -> Result<impl Responder, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::RetryDeadLetter {
+        id: path.into_inner(),
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::DeadLetter { letter } => Ok(ApiResponse::ok(letter)),
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Stop trying to settle a held trade and give each side back what it had
+/// reserved for it
+#[post("/settlements/dead-letters/{id}/compensate")]
+pub async fn compensate_dead_letter(
+    state: web::Data<AppState>,
+    path: web::Path<u64>,
+    body: web::Json<CompensateDeadLetterRequest>,
+) -> Result<impl Responder, ApiError> {
+    let note = body.note.trim();
+    if note.is_empty() {
+        return Err(ApiError::BadRequest(
+            "note must say why the trade is compensated".to_string(),
+        ));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::CompensateDeadLetter {
+        id: path.into_inner(),
+        note: note.to_string(),
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::DeadLetter { letter } => Ok(ApiResponse::ok(letter)),
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// What the engine rebuilt from its snapshot and command log on startup
+#[get("/recovery")]
+pub async fn get_recovery_report(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetRecoveryReport {
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::RecoveryReport {
+            report: Some(report),
+        } => Ok(ApiResponse::ok(report)),
+        OrderBookResponse::RecoveryReport { report: None } => Err(ApiError::NotFound(
+            "The engine keeps no command log, so nothing was recovered".to_string(),
+        )),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Where the engine clock stands relative to the wall clock
+#[get("/clock")]
+pub async fn get_clock(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    advance_clock(&state, Duration::ZERO).await
+}
+
+/// Move the engine clock forward so expiries, dead man's switches, interest
+/// and day roll-overs fall due without waiting in real time. Anything due is
+/// fired before the engine takes its next command. The clock never goes back.
+#[post("/clock/advance")]
+pub async fn advance_clock_by(
+    state: web::Data<AppState>,
+    body: web::Json<AdvanceClockRequest>,
+) -> Result<impl Responder, ApiError> {
+    let by = Duration::from_secs(body.seconds);
+    if by.is_zero() || by > MAX_CLOCK_ADVANCE {
+        return Err(ApiError::BadRequest(format!(
+            "seconds must be between 1 and {}",
+            MAX_CLOCK_ADVANCE.as_secs()
+        )));
+    }
+    advance_clock(&state, by).await
+}
+
+async fn advance_clock(state: &AppState, by: Duration) -> Result<ApiResponse, ApiError> {
+    // Time travel is for test environments only
+    if !state.profile.test_clock {
+        return Err(ApiError::NotFound(
+            "Test clock is not available in this environment".to_string(),
+        ));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::AdvanceClock { by, response_tx };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::Clock { now, offset_ms } => {
+            Ok(ApiResponse::ok(serde_json::json!({ "now": now, "offset_ms": offset_ms })))
+        }
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Warn clients of upcoming maintenance. The notice is pushed to GraphQL
+/// `maintenance` subscribers and named in the headers of every API response
+/// until it is cleared; a new notice replaces the current one.
+#[post("/maintenance")]
+pub async fn announce_maintenance(
+    state: web::Data<AppState>,
+    body: web::Json<MaintenanceNoticeRequest>,
+) -> Result<impl Responder, ApiError> {
+    let notice = MaintenanceNotice::new(&body.message, body.scheduled_at, Utc::now())
+        .map_err(ApiError::BadRequest)?;
+    state.maintenance.announce(notice.clone());
+    Ok(ApiResponse::ok(notice))
+}
+
+#[delete("/maintenance")]
+pub async fn clear_maintenance(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    match state.maintenance.clear() {
+        Some(notice) => Ok(ApiResponse::ok(notice)),
+        None => Err(ApiError::NotFound(
+            "No maintenance notice is announced".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,133 @@
+use actix_web::middleware::from_fn;
+use actix_web::{delete, get, post, web, HttpMessage, HttpRequest, Responder};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::state::AppState;
+use crate::storage::ApiKeyStore;
+use crate::types::{default_scopes, Activity, ApiKey, ApiKeyScope, GrantedScopes};
+use crate::utils::error::ApiError;
+use crate::utils::middleware::admin_scope;
+use crate::utils::response::ApiResponse;
+use crate::utils::secret_box;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub label: Option<String>,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+fn api_key_json(key: &ApiKey, last_used_at: Option<DateTime<Utc>>) -> serde_json::Value {
+    serde_json::json!({
+        "key_id": key.key_id,
+        "label": key.label,
+        "scopes": key.scopes,
+        "created_at": key.created_at,
+        "last_used_at": last_used_at,
+    })
+}
+
+#[post("/api-keys", wrap = "from_fn(admin_scope)")]
+pub async fn create_api_key(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    api_keys: web::Data<ApiKeyStore>,
+    body: web::Json<CreateApiKeyRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    if body.scopes.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Give the key at least one scope".to_string(),
+        ));
+    }
+    // A key may only hand out what it holds itself
+    if let Some(GrantedScopes(granted)) = req.extensions().get::<GrantedScopes>() {
+        if let Some(scope) = body.scopes.iter().find(|s| !granted.contains(s)) {
+            return Err(ApiError::Forbidden(format!(
+                "This API key cannot grant the {} scope",
+                scope.as_str()
+            )));
+        }
+    }
+
+    let (key, secret) = api_keys
+        .create(
+            secret_box(),
+            user_id,
+            body.label.clone(),
+            body.scopes.clone(),
+        )
+        .await
+        .map_err(ApiError::InternalError)?;
+    let created = Activity::ApiKeyCreated {
+        key_id: key.key_id.clone(),
+        label: key.label.clone(),
+        scopes: key.scopes.iter().map(|s| s.as_str().to_string()).collect(),
+    };
+    state
+        .audit
+        .record(user_id, created, key.created_at)
+        .await
+        .map_err(ApiError::InternalError)?;
+
+    // The only time the secret leaves the server
+    let mut response = api_key_json(&key, None);
+    response["secret"] = serde_json::Value::String(secret);
+    Ok(ApiResponse::ok(response))
+}
+
+#[get("/api-keys", wrap = "from_fn(admin_scope)")]
+pub async fn list_api_keys(
+    req: HttpRequest,
+    api_keys: web::Data<ApiKeyStore>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let keys: Vec<_> = api_keys
+        .list(user_id)
+        .await
+        .map_err(ApiError::InternalError)?
+        .iter()
+        .map(|key| api_key_json(key, api_keys.last_used(&key.key_id)))
+        .collect();
+    Ok(ApiResponse::ok(keys))
+}
+
+#[delete("/api-keys/{key_id}", wrap = "from_fn(admin_scope)")]
+pub async fn revoke_api_key(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    api_keys: web::Data<ApiKeyStore>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let revoked = api_keys
+        .revoke(user_id, &path)
+        .await
+        .map_err(ApiError::InternalError)?;
+    if !revoked {
+        return Err(ApiError::NotFound("API key not found".to_string()));
+    }
+    let revoked = Activity::ApiKeyRevoked {
+        key_id: path.to_string(),
+    };
+    state
+        .audit
+        .record(user_id, revoked, Utc::now())
+        .await
+        .map_err(ApiError::InternalError)?;
+    Ok(ApiResponse::ok(serde_json::json!({
+        "key_id": path.into_inner(),
+        "revoked": true,
+    })))
+}
@@ -1,22 +1,238 @@
-use actix_web::{post, web, HttpResponse, Responder};
+use actix_web::cookie::time::Duration as CookieDuration;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header::{LOCATION, SET_COOKIE};
+use actix_web::middleware::from_fn;
+use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
 
-use crate::types::User;
-use crate::utils::auth::{generate_token, hash_password, verify_password};
+use crate::state::AppState;
+use crate::storage::{MemoryUserRepository, UserRepository};
+use crate::types::{Activity, ExternalIdentity, FundingSource, User};
+use crate::utils::auth::{generate_token, hash_password, jwt_keys, verify_password};
 use crate::utils::error::ApiError;
+use crate::utils::lru::LruCache;
+use crate::utils::middleware::admin_scope;
+use crate::utils::oidc::{OidcConfig, LOGIN_STATE_COOKIE, LOGIN_STATE_TTL};
+use crate::utils::response::ApiResponse;
 
-// Simple in-memory user store (in production, use a database)
+/// How many user records `find_by_id` keeps at hand
+const USER_CACHE_CAPACITY: usize = 10_000;
+
+/// Times a change is tried against a fresh copy of the account before giving up
+const USER_UPDATE_ATTEMPTS: u32 = 8;
+
+/// User accounts, kept in a `UserRepository`
 pub struct UserStore {
-    pub users: Mutex<HashMap<String, User>>, // username -> User
+    repository: Arc<dyn UserRepository>,
+    /// Recently looked up users by ID, so per-request lookups skip the
+    /// backing store. Every change made here replaces the entry. Nothing is
+    /// cached when other instances share the repository, since their
+    /// changes would never reach it.
+    cache: Mutex<LruCache<Uuid, User>>,
 }
 
+#[allow(clippy::new_without_default)]
 impl UserStore {
+    /// Accounts kept in process memory, lost on restart
     pub fn new() -> Self {
+        Self::with_repository(Arc::new(MemoryUserRepository::new()))
+    }
+
+    pub fn with_repository(repository: Arc<dyn UserRepository>) -> Self {
+        let capacity = if repository.is_shared() {
+            0
+        } else {
+            USER_CACHE_CAPACITY
+        };
         UserStore {
-            users: Mutex::new(HashMap::new()),
+            repository,
+            cache: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Add a new account; false if the username is taken
+    pub async fn create_user(&self, user: &User) -> Result<bool, String> {
+        self.repository.insert_user(user).await
+    }
+
+    pub async fn find_by_username(&self, username: &str) -> Result<Option<User>, String> {
+        self.repository.user_by_username(username).await
+    }
+
+    pub async fn find_by_id(&self, user_id: Uuid) -> Result<Option<User>, String> {
+        if let Some(user) = self.cache.lock().unwrap().get(&user_id) {
+            return Ok(Some(user));
+        }
+        let user = self.repository.user_by_id(user_id).await?;
+        if let Some(user) = &user {
+            self.cache_newer(user);
+        }
+        Ok(user)
+    }
+
+    /// Cache `user` unless a change saved while it was being read has
+    /// already cached a later revision
+    fn cache_newer(&self, user: &User) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache
+            .get(&user.id)
+            .is_none_or(|cached| cached.revision < user.revision)
+        {
+            cache.insert(user.id, user.clone());
+        }
+    }
+
+    /// Read the user, let `change` edit them and save the result. A save
+    /// refused because someone else saved the account in between is tried
+    /// again from a fresh read, so `change` may run more than once and always
+    /// sees the latest copy. None, with nothing saved, when the user does not
+    /// exist or `change` returns None.
+    async fn modify<T>(
+        &self,
+        user_id: Uuid,
+        mut change: impl FnMut(&mut User) -> Option<T>,
+    ) -> Result<Option<(User, T)>, String> {
+        for _ in 0..USER_UPDATE_ATTEMPTS {
+            let Some(mut user) = self.repository.user_by_id(user_id).await? else {
+                return Ok(None);
+            };
+            let Some(result) = change(&mut user) else {
+                return Ok(None);
+            };
+            if self.repository.update_user(&user).await? {
+                user.revision += 1;
+                self.cache_newer(&user);
+                return Ok(Some((user, result)));
+            }
+        }
+        Err(format!(
+            "User {} is being changed by other requests; try again",
+            user_id
+        ))
+    }
+
+    /// The user an outside identity signs in as: the account it was linked
+    /// to, or else a new one without a password. None if the identity is new
+    /// but its verified email belongs to an account, whose owner must sign in
+    /// and link it; matching emails alone never hand over an account.
+    pub async fn find_or_create_external(
+        &self,
+        identity: ExternalIdentity,
+    ) -> Result<Option<User>, String> {
+        if let Some(user) = self
+            .repository
+            .user_by_identity(&identity.provider, &identity.subject)
+            .await?
+        {
+            return Ok(Some(user));
+        }
+
+        let verified_email = identity.email.clone().filter(|_| identity.email_verified);
+        if let Some(email) = &verified_email {
+            if self.repository.user_by_email(email).await?.is_some() {
+                return Ok(None);
+            }
         }
+
+        // Name the account after the email, or the provider account if there is none
+        let base = verified_email
+            .as_deref()
+            .and_then(|email| email.split('@').next())
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("{}-{}", identity.provider, identity.subject));
+        for n in 1.. {
+            let username = if n == 1 {
+                base.clone()
+            } else {
+                format!("{}-{}", base, n)
+            };
+            let mut user = User::new(
+                username,
+                verified_email.clone().unwrap_or_default(),
+                String::new(),
+            );
+            user.external_identities.push(identity.clone());
+            if self.repository.insert_user(&user).await? {
+                return Ok(Some(user));
+            }
+        }
+        unreachable!("some numbered username is free")
+    }
+
+    /// Link an outside identity to the account of the signed-in user who
+    /// asked for it, so it signs in as them from then on. None if the user
+    /// does not exist; an error if the identity already signs in as someone
+    /// else.
+    pub async fn link_external(
+        &self,
+        user_id: Uuid,
+        identity: ExternalIdentity,
+    ) -> Result<Option<User>, String> {
+        if let Some(owner) = self
+            .repository
+            .user_by_identity(&identity.provider, &identity.subject)
+            .await?
+        {
+            if owner.id != user_id {
+                return Err(format!(
+                    "This {} account is linked to another user",
+                    identity.provider
+                ));
+            }
+            return Ok(Some(owner));
+        }
+        let linked = self
+            .modify(user_id, |user| {
+                user.external_identities.push(identity.clone());
+                Some(())
+            })
+            .await?;
+        Ok(linked.map(|(user, ())| user))
+    }
+
+    /// Run `f` on one of a user's funding sources and save the change,
+    /// returning its result. None when the user or the source does not exist.
+    /// `f` may run again on a fresh copy if the account changed meanwhile, so
+    /// a limit it checks is never checked against a stale one.
+    pub async fn with_funding_source<T, F>(
+        &self,
+        user_id: Uuid,
+        source_id: Uuid,
+        mut f: F,
+    ) -> Result<Option<T>, String>
+    where
+        F: FnMut(&mut FundingSource) -> T,
+    {
+        let changed = self
+            .modify(user_id, |user| {
+                let source = user
+                    .funding_sources
+                    .iter_mut()
+                    .find(|s| s.id == source_id)?;
+                Some(f(source))
+            })
+            .await?;
+        Ok(changed.map(|(_, result)| result))
+    }
+
+    /// Apply `update` to the user with `user_id` and save it, returning the
+    /// updated record. `update` may run again on a fresh copy if the account
+    /// changed meanwhile.
+    pub async fn update_user<F>(&self, user_id: Uuid, mut update: F) -> Result<Option<User>, String>
+    where
+        F: FnMut(&mut User),
+    {
+        let changed = self
+            .modify(user_id, |user| {
+                update(user);
+                Some(())
+            })
+            .await?;
+        Ok(changed.map(|(user, ())| user))
     }
 }
 
@@ -40,7 +256,28 @@ pub struct AuthResponse {
     pub username: String,
 }
 
+/// Note a successful sign-in on the user's activity feed
+async fn record_login(
+    state: &AppState,
+    req: &HttpRequest,
+    user_id: Uuid,
+    method: &str,
+) -> Result<(), ApiError> {
+    let ip = state.client_ip(req).map(|ip| ip.to_string());
+    let login = Activity::Login {
+        method: method.to_string(),
+        ip,
+    };
+    state
+        .audit
+        .record(user_id, login, Utc::now())
+        .await
+        .map(|_| ())
+        .map_err(ApiError::InternalError)
+}
+
 #[post("/signup")]
+#[allow(clippy::redundant_closure)]
 pub async fn signup(
     user_store: web::Data<UserStore>,
     req: web::Json<SignupRequest>,
@@ -67,22 +304,20 @@ pub async fn signup(
     let user_id = user.id;
     let username = user.username.clone();
 
-    // Store user
-    let mut users = user_store.users.lock().unwrap();
-
-    // Check if username already exists
-    if users.contains_key(&req.username) {
+    // Store user, unless the username is taken
+    let created = user_store
+        .create_user(&user)
+        .await
+        .map_err(ApiError::InternalError)?;
+    if !created {
         return Err(ApiError::BadRequest("Username already exists".to_string()));
     }
 
-    users.insert(req.username.clone(), user);
-    drop(users);
-
     // Generate token
     let token = generate_token(user_id, username.clone())
         .map_err(|e| ApiError::InternalError(e))?;
 
-    Ok(HttpResponse::Ok().json(AuthResponse {
+    Ok(ApiResponse::ok(AuthResponse {
         token,
         user_id: user_id.to_string(),
         username,
@@ -90,7 +325,10 @@ pub async fn signup(
 }
 
 #[post("/signin")]
+#[allow(clippy::redundant_closure)]
 pub async fn signin(
+    http_req: HttpRequest,
+    state: web::Data<AppState>,
     user_store: web::Data<UserStore>,
     req: web::Json<SigninRequest>,
 ) -> Result<impl Responder, ApiError> {
@@ -102,12 +340,16 @@ pub async fn signin(
     }
 
     // Get user
-    let users = user_store.users.lock().unwrap();
-    let user = users
-        .get(&req.username)
-        .ok_or_else(|| ApiError::Unauthorized("Invalid credentials".to_string()))?
-        .clone();
-    drop(users);
+    let user = user_store
+        .find_by_username(&req.username)
+        .await
+        .map_err(ApiError::InternalError)?
+        .ok_or_else(|| ApiError::Unauthorized("Invalid credentials".to_string()))?;
+
+    // Accounts created through an identity provider sign in there
+    if !user.has_password() {
+        return Err(ApiError::Unauthorized("Invalid credentials".to_string()));
+    }
 
     // Verify password
     let valid = verify_password(&req.password, &user.password_hash)
@@ -120,10 +362,307 @@ pub async fn signin(
     // Generate token
     let token = generate_token(user.id, user.username.clone())
         .map_err(|e| ApiError::InternalError(e))?;
+    record_login(&state, &http_req, user.id, "password").await?;
 
-    Ok(HttpResponse::Ok().json(AuthResponse {
+    Ok(ApiResponse::ok(AuthResponse {
         token,
         user_id: user.id.to_string(),
         username: user.username,
     }))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub state: String,
+    pub code: Option<String>,
+    pub error: Option<String>, // Set by the provider when the user declined
+}
+
+#[get("/oidc/providers")]
+pub async fn oidc_providers(oidc: web::Data<OidcConfig>) -> impl Responder {
+    ApiResponse::ok(serde_json::json!({
+        "providers": oidc.provider_names(),
+    }))
+}
+
+/// Remember a login's state in the browser starting it, for the callback to
+/// check. Sent on every API path, since callbacks may arrive on any version.
+fn login_state_cookie(state: &str, secure: bool) -> Cookie<'static> {
+    Cookie::build(LOGIN_STATE_COOKIE, state.to_string())
+        .path("/api")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Lax)
+        .max_age(CookieDuration::seconds(LOGIN_STATE_TTL.as_secs() as i64))
+        .finish()
+}
+
+#[get("/oidc/{provider}/login")]
+pub async fn oidc_login(
+    oidc: web::Data<OidcConfig>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    let provider = oidc
+        .provider(&path)
+        .ok_or_else(|| ApiError::NotFound("Unknown identity provider".to_string()))?;
+
+    // Send the user to the provider; it redirects back to the callback
+    let login = oidc.begin_login(provider, None);
+    let secure = provider.redirect_uri.starts_with("https://");
+    Ok(HttpResponse::Found()
+        .append_header((LOCATION, login.authorize_url))
+        .cookie(login_state_cookie(&login.state, secure))
+        .finish())
+}
+
+/// Start linking an identity to the signed-in account. The browser is sent to
+/// the returned URL, and the callback links whoever signs in there.
+#[post("/identities/{provider}/link", wrap = "from_fn(admin_scope)")]
+pub async fn link_identity(
+    req: HttpRequest,
+    oidc: web::Data<OidcConfig>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+    let provider = oidc
+        .provider(&path)
+        .ok_or_else(|| ApiError::NotFound("Unknown identity provider".to_string()))?;
+
+    let login = oidc.begin_login(provider, Some(user_id));
+    let secure = provider.redirect_uri.starts_with("https://");
+    let cookie = login_state_cookie(&login.state, secure);
+    Ok(ApiResponse::ok(serde_json::json!({
+        "authorize_url": login.authorize_url,
+    }))
+    .customize()
+    .append_header((SET_COOKIE, cookie.to_string())))
+}
+
+#[get("/oidc/{provider}/callback")]
+pub async fn oidc_callback(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    user_store: web::Data<UserStore>,
+    oidc: web::Data<OidcConfig>,
+    path: web::Path<String>,
+    query: web::Query<OidcCallbackQuery>,
+) -> Result<impl Responder, ApiError> {
+    let provider = oidc
+        .provider(&path)
+        .ok_or_else(|| ApiError::NotFound("Unknown identity provider".to_string()))?;
+
+    // The state proves this callback answers a login we started, and the
+    // cookie that it reached the browser which started it
+    let same_browser = req
+        .cookie(LOGIN_STATE_COOKIE)
+        .is_some_and(|cookie| cookie.value() == query.state);
+    let login = match oidc.finish_login(provider, &query.state) {
+        Some(login) if same_browser => login,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "Invalid or expired login state".to_string(),
+            ))
+        }
+    };
+    if let Some(error) = &query.error {
+        return Err(ApiError::Unauthorized(format!(
+            "Sign-in was not completed: {}",
+            error
+        )));
+    }
+    let code = query
+        .code
+        .as_deref()
+        .ok_or_else(|| ApiError::BadRequest("Missing authorization code".to_string()))?;
+
+    // Look up who signed in, then find, create or link their account
+    let identity = provider
+        .fetch_identity(&oidc.http, code, &login.code_verifier)
+        .await
+        .map_err(ApiError::Unauthorized)?;
+    let user = match login.link_to {
+        Some(user_id) => user_store
+            .link_external(user_id, identity)
+            .await
+            .map_err(ApiError::BadRequest)?
+            .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?,
+        None => user_store
+            .find_or_create_external(identity)
+            .await
+            .map_err(ApiError::InternalError)?
+            .ok_or_else(|| {
+                ApiError::Forbidden(
+                    "An account already uses this email; sign in to it and link this identity from there"
+                        .to_string(),
+                )
+            })?,
+    };
+
+    // Generate token
+    let token = generate_token(user.id, user.username.clone())
+        .map_err(ApiError::InternalError)?;
+    record_login(&state, &req, user.id, provider.kind.as_str()).await?;
+
+    let mut removal = login_state_cookie("", false);
+    removal.make_removal();
+    Ok(ApiResponse::ok(AuthResponse {
+        token,
+        user_id: user.id.to_string(),
+        username: user.username,
+    })
+    .customize()
+    .append_header((SET_COOKIE, removal.to_string())))
+}
+
+/// Public keys user tokens are signed with, so other services can verify
+/// tokens without holding the signing key
+#[get("/.well-known/jwks.json")]
+pub async fn jwks() -> impl Responder {
+    ApiResponse::ok(jwt_keys().jwks())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(provider: &str, subject: &str, email: &str, verified: bool) -> ExternalIdentity {
+        ExternalIdentity {
+            provider: provider.to_string(),
+            subject: subject.to_string(),
+            email: Some(email.to_string()),
+            email_verified: verified,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_external_logins_create_accounts_and_link_only_when_asked() {
+        let store = UserStore::new();
+        let local = User::new(
+            "alice".to_string(),
+            "Alice@example.com".to_string(),
+            "hash".to_string(),
+        );
+        assert!(store.create_user(&local).await.unwrap());
+
+        // A verified email of an existing account neither links to it nor
+        // makes a second account
+        assert!(store
+            .find_or_create_external(identity("google", "g-1", "alice@example.com", true))
+            .await
+            .unwrap()
+            .is_none());
+
+        // Linked by the signed-in owner, the identity signs in as them, and sticks
+        let linked = store
+            .link_external(
+                local.id,
+                identity("google", "g-1", "alice@example.com", true),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(linked.id, local.id);
+        let again = store
+            .find_or_create_external(identity("google", "g-1", "changed@example.com", true))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(again.id, local.id);
+
+        // An unverified email gets an account of its own
+        let other = store
+            .find_or_create_external(identity("github", "7", "alice@example.com", false))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(other.id, local.id);
+        assert_eq!(other.username, "github-7");
+        assert!(!other.has_password());
+        // which nobody else can then link
+        assert!(store
+            .link_external(
+                local.id,
+                identity("github", "7", "alice@example.com", false)
+            )
+            .await
+            .is_err());
+
+        // New accounts are named after the email, without clashing
+        let bob = store
+            .find_or_create_external(identity("google", "g-2", "alice@elsewhere.com", true))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bob.username, "alice-2");
+    }
+
+    #[tokio::test]
+    async fn test_cached_users_are_refreshed_after_updates() {
+        let store = UserStore::new();
+        let user = User::new(
+            "carol".to_string(),
+            "carol@example.com".to_string(),
+            "hash".to_string(),
+        );
+        assert!(store.create_user(&user).await.unwrap());
+
+        let cached = || async { store.find_by_id(user.id).await.unwrap().unwrap() };
+        assert_eq!(cached().await.display_currency, None);
+        store
+            .update_user(user.id, |u| u.display_currency = Some("EUR".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(cached().await.display_currency.as_deref(), Some("EUR"));
+
+        store
+            .link_external(
+                user.id,
+                identity("google", "g-3", "carol@example.com", true),
+            )
+            .await
+            .unwrap();
+        assert_eq!(cached().await.external_identities.len(), 1);
+        assert!(store.find_by_id(Uuid::new_v4()).await.unwrap().is_none());
+        // Taken usernames are refused
+        assert!(!store.create_user(&cached().await).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_changes_saved_meanwhile_are_kept_by_trying_again() {
+        use futures_util::FutureExt;
+
+        let repository: Arc<dyn UserRepository> = Arc::new(MemoryUserRepository::new());
+        let here = UserStore::with_repository(repository.clone());
+        let elsewhere = UserStore::with_repository(repository);
+        let user = User::new(
+            "dave".to_string(),
+            "dave@example.com".to_string(),
+            "hash".to_string(),
+        );
+        assert!(here.create_user(&user).await.unwrap());
+
+        let mut runs = 0;
+        let updated = here
+            .update_user(user.id, |u| {
+                runs += 1;
+                if runs == 1 {
+                    // Another instance saves the account after this copy was read
+                    elsewhere
+                        .link_external(u.id, identity("google", "g-4", "dave@example.com", true))
+                        .now_or_never()
+                        .unwrap()
+                        .unwrap();
+                }
+                u.display_currency = Some("EUR".to_string());
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(runs, 2);
+        assert_eq!(updated.external_identities.len(), 1);
+        assert_eq!(updated.display_currency.as_deref(), Some("EUR"));
+        assert_eq!(here.find_by_id(user.id).await.unwrap().unwrap().revision, 2);
+    }
+}
@@ -0,0 +1,307 @@
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
+use actix_web::{get, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::Deserialize;
+use std::io;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::state::AppState;
+use crate::storage::HistoryQuery;
+use crate::types::{
+    LiquidityRole, Order, OrderSide, OrderSource, OrderStatus, OrderType, TimeInForce, UserTrade,
+};
+use crate::utils::error::ApiError;
+use crate::utils::middleware::read_scope;
+use crate::utils::DecimalSeparator;
+
+/// Records fetched from the engine for each chunk of an export
+const EXPORT_PAGE_SIZE: usize = 500;
+
+const TRADES_CSV_HEADER: [&str; 8] = [
+    "timestamp",
+    "trade_id",
+    "order_id",
+    "side",
+    "role",
+    "price",
+    "quantity",
+    "fee",
+];
+
+const ORDERS_CSV_HEADER: [&str; 11] = [
+    "timestamp",
+    "order_id",
+    "client_order_id",
+    "side",
+    "type",
+    "time_in_force",
+    "price",
+    "quantity",
+    "remaining_quantity",
+    "status",
+    "source",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportType {
+    Trades,
+    Orders,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    #[serde(rename = "type")]
+    pub export_type: ExportType,
+    pub format: Option<String>,            // Only "csv" so far
+    pub decimal_separator: Option<String>, // "." (default) or ","
+}
+
+fn side_name(side: OrderSide) -> &'static str {
+    match side {
+        OrderSide::Buy => "buy",
+        OrderSide::Sell => "sell",
+    }
+}
+
+fn trade_csv_fields(fill: &UserTrade, separator: DecimalSeparator) -> [String; 8] {
+    let role = match fill.role {
+        LiquidityRole::Maker => "maker",
+        LiquidityRole::Taker => "taker",
+    };
+    [
+        fill.timestamp.to_rfc3339(),
+        fill.trade_id.to_string(),
+        fill.order_id.to_string(),
+        side_name(fill.side).to_string(),
+        role.to_string(),
+        separator.localize(&fill.price.to_string()),
+        separator.localize(&fill.quantity.to_string()),
+        separator.localize(&fill.fee.to_string()),
+    ]
+}
+
+fn order_csv_fields(order: &Order, separator: DecimalSeparator) -> [String; 11] {
+    let order_type = match order.order_type {
+        OrderType::Limit => "limit",
+        OrderType::Market => "market",
+    };
+    let time_in_force = match order.time_in_force {
+        TimeInForce::GTC => "gtc",
+        TimeInForce::IOC => "ioc",
+    };
+    let status = match order.status {
+        OrderStatus::Open => "open",
+        OrderStatus::PartiallyFilled => "partially_filled",
+        OrderStatus::Filled => "filled",
+        OrderStatus::Cancelled => "cancelled",
+        OrderStatus::Expired => "expired",
+        OrderStatus::Rejected => "rejected",
+    };
+    let source = match order.source {
+        OrderSource::Web => "web",
+        OrderSource::ApiKey => "api-key",
+        OrderSource::Fix => "fix",
+        OrderSource::Algo => "algo",
+        OrderSource::Liquidation => "liquidation",
+    };
+    [
+        order.timestamp.to_rfc3339(),
+        order.id.to_string(),
+        order.client_order_id.clone().unwrap_or_default(),
+        side_name(order.side).to_string(),
+        order_type.to_string(),
+        time_in_force.to_string(),
+        order
+            .price
+            .map(|price| separator.localize(&price.to_string()))
+            .unwrap_or_default(), // Market orders have no price
+        separator.localize(&order.original_quantity.to_string()),
+        separator.localize(&order.remaining_quantity.to_string()),
+        status.to_string(),
+        source.to_string(),
+    ]
+}
+
+/// One page of the user's history as CSV rows, and how many records it held
+async fn export_page(
+    state: &AppState,
+    user_id: Uuid,
+    export_type: ExportType,
+    query: HistoryQuery,
+    separator: DecimalSeparator,
+) -> Result<(String, usize), ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = match export_type {
+        ExportType::Trades => OrderBookCommand::GetUserTrades {
+            user_id,
+            query,
+            deadline,
+            response_tx,
+        },
+        ExportType::Orders => OrderBookCommand::GetStoredOrders {
+            user_id,
+            query,
+            deadline,
+            response_tx,
+        },
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::UserTrades { fills, trades } => {
+            let rows = fills
+                .iter()
+                .map(|fill| separator.csv_record(&trade_csv_fields(fill, separator)))
+                .collect();
+            Ok((rows, trades))
+        }
+        OrderBookResponse::OrderHistory { orders } => {
+            let rows = orders
+                .iter()
+                .map(|order| separator.csv_record(&order_csv_fields(order, separator)))
+                .collect();
+            Ok((rows, orders.len()))
+        }
+        OrderBookResponse::Error { message } => Err(ApiError::InternalError(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// The header row, then one chunk per page until a page comes back short.
+/// Records made after `until` are left out, so new activity during a long
+/// export cannot shift the pages.
+fn export_chunks(
+    state: web::Data<AppState>,
+    user_id: Uuid,
+    export_type: ExportType,
+    until: DateTime<Utc>,
+    separator: DecimalSeparator,
+) -> impl Stream<Item = Result<web::Bytes, io::Error>> {
+    let header = match export_type {
+        ExportType::Trades => separator.csv_record(&TRADES_CSV_HEADER),
+        ExportType::Orders => separator.csv_record(&ORDERS_CSV_HEADER),
+    };
+    let pages = stream::unfold(Some(0), move |offset| {
+        let state = state.clone();
+        async move {
+            let query = HistoryQuery {
+                from: None,
+                to: Some(until),
+                offset: offset?,
+                limit: EXPORT_PAGE_SIZE,
+            };
+            match export_page(&state, user_id, export_type, query, separator).await {
+                Ok((rows, records)) => {
+                    let next = (records == EXPORT_PAGE_SIZE).then_some(query.offset + records);
+                    Some((Ok(web::Bytes::from(rows)), next))
+                }
+                // Abort the transfer so the client sees an incomplete download
+                Err(e) => Some((Err(io::Error::other(e.to_string())), None)),
+            }
+        }
+    });
+    stream::once(async move { Ok(web::Bytes::from(header)) }).chain(pages)
+}
+
+/// The user's whole trade or order history, newest first, streamed as CSV
+/// one page at a time for tax and accounting tools
+#[get("/export", wrap = "from_fn(read_scope)")]
+pub async fn export_history(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<ExportQuery>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req
+        .extensions()
+        .get::<Uuid>()
+        .copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    match query.format.as_deref() {
+        None | Some("csv") => {}
+        Some(format) => {
+            return Err(ApiError::BadRequest(format!(
+                "Unsupported export format '{}', use 'csv'",
+                format
+            )))
+        }
+    }
+    let separator: DecimalSeparator = match &query.decimal_separator {
+        Some(separator) => separator.parse().map_err(ApiError::BadRequest)?,
+        None => DecimalSeparator::default(),
+    };
+
+    let until = Utc::now();
+    let name = match query.export_type {
+        ExportType::Trades => "trades",
+        ExportType::Orders => "orders",
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}-{}.csv\"",
+                name,
+                until.format("%Y%m%dT%H%M%SZ")
+            ),
+        ))
+        .streaming(export_chunks(
+            state,
+            user_id,
+            query.export_type,
+            until,
+            separator,
+        )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Price, Quantity};
+
+    #[test]
+    fn test_csv_fields() {
+        let mut order = Order::new_limit(
+            Uuid::nil(),
+            OrderSide::Sell,
+            Price::from_f64(101.5),
+            Quantity::from_f64(2.0),
+        );
+        order.status = OrderStatus::PartiallyFilled;
+        order.client_order_id = Some("my, order".to_string());
+        let fields = order_csv_fields(&order, DecimalSeparator::Comma);
+        assert_eq!(fields[2], "my, order");
+        assert_eq!(&fields[3..7], ["sell", "limit", "gtc", "101,50"]);
+        assert_eq!(fields[9], "partially_filled");
+
+        let fill = UserTrade {
+            trade_id: Uuid::nil(),
+            order_id: order.id,
+            side: OrderSide::Buy,
+            role: LiquidityRole::Maker,
+            price: Price::from_f64(100.0),
+            quantity: Quantity::from_f64(0.5),
+            fee: 0.25,
+            timestamp: order.timestamp,
+        };
+        let fields = trade_csv_fields(&fill, DecimalSeparator::Point);
+        assert_eq!(
+            &fields[3..],
+            ["buy", "maker", "100.00", "0.50000000", "0.25"]
+        );
+    }
+}
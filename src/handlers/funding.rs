@@ -0,0 +1,420 @@
+use actix_web::middleware::from_fn;
+use actix_web::{get, post, web, HttpMessage, HttpRequest, Responder};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::engine::MarketEvent;
+use crate::handlers::auth::UserStore;
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::state::AppState;
+use crate::types::{Activity, FundingSource, FundingSourceKind, VerificationStatus};
+use crate::utils::error::ApiError;
+use crate::utils::funding::funding_adapter;
+use crate::utils::middleware::withdraw_scope;
+use crate::utils::response::ApiResponse;
+
+/// Most funding sources one user may have linked
+const MAX_FUNDING_SOURCES: usize = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct LinkFundingSourceRequest {
+    pub kind: String,    // "mock_bank" or "mock_crypto_wallet"
+    pub label: String,   // The user's own name for it
+    pub details: String, // Account number or wallet address
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyFundingSourceRequest {
+    pub amounts: Vec<f64>, // Micro-deposit amounts, for bank accounts
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WithdrawRequest {
+    pub funding_source_id: String,
+    pub amount: f64,
+}
+
+fn parse_source_id(source_id: &str) -> Result<Uuid, ApiError> {
+    Uuid::parse_str(source_id)
+        .map_err(|_| ApiError::BadRequest("Invalid funding_source_id format".to_string()))
+}
+
+async fn find_source(
+    user_store: &UserStore,
+    user_id: Uuid,
+    source_id: Uuid,
+) -> Result<FundingSource, ApiError> {
+    user_store
+        .find_by_id(user_id)
+        .await
+        .map_err(ApiError::InternalError)?
+        .and_then(|user| user.funding_sources.into_iter().find(|s| s.id == source_id))
+        .ok_or_else(|| ApiError::NotFound("Funding source not found".to_string()))
+}
+
+/// Count `amount` against the source's limits before any money moves
+async fn reserve_transfer(
+    user_store: &UserStore,
+    user_id: Uuid,
+    source_id: Uuid,
+    amount: f64,
+) -> Result<(), ApiError> {
+    let today = Utc::now().date_naive();
+    user_store
+        .with_funding_source(user_id, source_id, |source| source.reserve(amount, today))
+        .await
+        .map_err(ApiError::InternalError)?
+        .ok_or_else(|| ApiError::NotFound("Funding source not found".to_string()))?
+        .map_err(ApiError::BadRequest)
+}
+
+async fn release_transfer(user_store: &UserStore, user_id: Uuid, source_id: Uuid, amount: f64) {
+    let today = Utc::now().date_naive();
+    let released = user_store
+        .with_funding_source(user_id, source_id, |source| source.release(amount, today))
+        .await;
+    if let Err(e) = released {
+        eprintln!("Funding source {} limits not released: {}", source_id, e);
+    }
+}
+
+/// Return a withdrawal to the balance it left, reversing journal `journal_id`
+async fn reverse_withdrawal(
+    state: &AppState,
+    user_id: Uuid,
+    journal_id: u64,
+) -> Result<(), ApiError> {
+    let (response_tx, response_rx) = oneshot::channel();
+    let command = OrderBookCommand::ReverseWithdrawal {
+        user_id,
+        journal_id,
+        response_tx,
+    };
+    let deadline = state.deadline();
+    match state.dispatch(command, response_rx, deadline).await? {
+        OrderBookResponse::FundsAdded { .. } => Ok(()),
+        OrderBookResponse::Error { message } => Err(ApiError::InternalError(message)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Store a withdrawal-address notice on the user's activity feed and push
+/// it to their open user streams
+async fn notify_activity(
+    state: &AppState,
+    user_id: Uuid,
+    activity: Activity,
+    at: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    let entry = state
+        .audit
+        .record(user_id, activity, at)
+        .await
+        .map_err(ApiError::InternalError)?;
+    let notice = MarketEvent::Activity { user_id, entry };
+    state.events.notify(notice);
+    Ok(())
+}
+
+/// Onramp from a linked source: pull the money through the source's adapter,
+/// then credit it
+pub async fn deposit_from_source(
+    state: &AppState,
+    user_store: &UserStore,
+    user_id: Uuid,
+    source_id: &str,
+    currency: &str,
+    amount: f64,
+) -> Result<ApiResponse, ApiError> {
+    let source_id = parse_source_id(source_id)?;
+    let source = find_source(user_store, user_id, source_id).await?;
+    if source.currency() != currency {
+        return Err(ApiError::BadRequest(format!(
+            "This funding source moves {} only",
+            source.currency()
+        )));
+    }
+    let adapter = funding_adapter(source.kind);
+    if adapter.simulated() && !state.profile.faucet {
+        return Err(ApiError::NotFound(
+            "Simulated funding sources are not available in this environment".to_string(),
+        ));
+    }
+
+    reserve_transfer(user_store, user_id, source_id, amount).await?;
+    let transfer_reference = match adapter.deposit(&source, amount) {
+        Ok(reference) => reference,
+        Err(e) => {
+            release_transfer(user_store, user_id, source_id, amount).await;
+            return Err(ApiError::BadRequest(format!("Deposit failed: {}", e)));
+        }
+    };
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::AddFunds {
+        user_id,
+        currency: currency.to_string(),
+        amount,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::FundsAdded {
+            user_id,
+            currency,
+            new_balance,
+        } => Ok(ApiResponse::ok(serde_json::json!({
+            "user_id": user_id.to_string(),
+            "currency": currency,
+            "new_balance": new_balance,
+            "funding_source_id": source_id.to_string(),
+            "transfer_reference": transfer_reference,
+        }))),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+#[get("/funding-sources", wrap = "from_fn(withdraw_scope)")]
+pub async fn list_funding_sources(
+    req: HttpRequest,
+    user_store: web::Data<UserStore>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req
+        .extensions()
+        .get::<Uuid>()
+        .copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let user = user_store
+        .find_by_id(user_id)
+        .await
+        .map_err(ApiError::InternalError)?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    Ok(ApiResponse::ok(serde_json::json!({
+        "funding_sources": user.funding_sources,
+    })))
+}
+
+/// Link a bank account or wallet. Bank accounts start out pending until the
+/// micro-deposits are confirmed; wallets are usable at once.
+#[post("/funding-sources", wrap = "from_fn(withdraw_scope)")]
+pub async fn link_funding_source(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    user_store: web::Data<UserStore>,
+    body: web::Json<LinkFundingSourceRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req
+        .extensions()
+        .get::<Uuid>()
+        .copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let kind: FundingSourceKind = body.kind.parse().map_err(ApiError::BadRequest)?;
+    let label = body.label.trim();
+    if label.is_empty() || label.len() > 64 {
+        return Err(ApiError::BadRequest(
+            "label must be 1 to 64 characters".to_string(),
+        ));
+    }
+    let (reference, status) = funding_adapter(kind)
+        .link(&body.details)
+        .map_err(ApiError::BadRequest)?;
+    let mut source = FundingSource::new(kind, label.to_string(), reference, status);
+    // A stolen session must not be able to add an address and empty the
+    // account into it at once
+    if kind.is_address() && !state.withdrawal_address_delay.is_zero() {
+        let delay = chrono::Duration::from_std(state.withdrawal_address_delay)
+            .map_err(|e| ApiError::InternalError(e.to_string()))?;
+        source.withdrawable_from = Some(source.created_at + delay);
+    }
+
+    let mut linked = Ok(());
+    user_store
+        .update_user(user_id, |user| {
+            if user.funding_sources.len() >= MAX_FUNDING_SOURCES {
+                linked = Err(format!(
+                    "At most {} funding sources may be linked",
+                    MAX_FUNDING_SOURCES
+                ));
+            } else {
+                linked = Ok(());
+                user.funding_sources.push(source.clone());
+            }
+        })
+        .await
+        .map_err(ApiError::InternalError)?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+    linked.map_err(ApiError::BadRequest)?;
+
+    if let Some(usable_from) = source.withdrawable_from {
+        let added = Activity::WithdrawalAddressAdded {
+            funding_source_id: source.id,
+            address: source.reference.clone(),
+            usable_from,
+        };
+        notify_activity(&state, user_id, added, source.created_at).await?;
+    }
+
+    Ok(ApiResponse::created(source))
+}
+
+#[post(
+    "/funding-sources/{source_id}/verify",
+    wrap = "from_fn(withdraw_scope)"
+)]
+pub async fn verify_funding_source(
+    req: HttpRequest,
+    user_store: web::Data<UserStore>,
+    path: web::Path<String>,
+    body: web::Json<VerifyFundingSourceRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req
+        .extensions()
+        .get::<Uuid>()
+        .copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let source_id = parse_source_id(&path)?;
+    let source = user_store
+        .with_funding_source(user_id, source_id, |source| {
+            if source.status == VerificationStatus::Pending {
+                let passed = funding_adapter(source.kind).confirm(source, &body.amounts);
+                source.record_verification(passed);
+            }
+            source.clone()
+        })
+        .await
+        .map_err(ApiError::InternalError)?
+        .ok_or_else(|| ApiError::NotFound("Funding source not found".to_string()))?;
+
+    match source.status {
+        VerificationStatus::Verified => Ok(ApiResponse::ok(source)),
+        VerificationStatus::Pending => Err(ApiError::BadRequest(
+            "Amounts do not match the micro-deposits".to_string(),
+        )),
+        VerificationStatus::Rejected => Err(ApiError::BadRequest(
+            "Funding source failed verification; link it again".to_string(),
+        )),
+    }
+}
+
+/// Send funds out to a linked source, within its limits
+#[post("/withdraw", wrap = "from_fn(withdraw_scope)")]
+pub async fn withdraw(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    user_store: web::Data<UserStore>,
+    body: web::Json<WithdrawRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req
+        .extensions()
+        .get::<Uuid>()
+        .copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Validate amount
+    if !(body.amount.is_finite() && body.amount > 0.0) {
+        return Err(ApiError::BadRequest("Amount must be positive".to_string()));
+    }
+    let source_id = parse_source_id(&body.funding_source_id)?;
+    let source = find_source(&user_store, user_id, source_id).await?;
+    let now = Utc::now();
+    if let Some(usable_from) = source.withdrawal_hold(now) {
+        let blocked = Activity::WithdrawalBlocked {
+            funding_source_id: source_id,
+            address: source.reference.clone(),
+            amount: body.amount,
+            usable_from,
+        };
+        notify_activity(&state, user_id, blocked, now).await?;
+        return Err(ApiError::Forbidden(format!(
+            "This address was added recently; withdrawals to it are allowed from {}",
+            usable_from.to_rfc3339()
+        )));
+    }
+    let currency = source.currency().to_string();
+    reserve_transfer(&user_store, user_id, source_id, body.amount).await?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response. One still running at the deadline
+    // goes through, so once it has, put the money back and free the limits.
+    let deadline = state.deadline();
+    let command = OrderBookCommand::WithdrawFunds {
+        user_id,
+        currency: currency.clone(),
+        amount: body.amount,
+        response_tx,
+    };
+    let settle = {
+        let (state, user_store, amount) = (state.clone(), user_store.clone(), body.amount);
+        move |response| async move {
+            if let Some(OrderBookResponse::FundsWithdrawn { journal_id, .. }) = response {
+                if let Err(e) = reverse_withdrawal(&state, user_id, journal_id).await {
+                    eprintln!("Timed-out withdrawal {} not reversed: {:?}", journal_id, e);
+                }
+            }
+            release_transfer(&user_store, user_id, source_id, amount).await;
+        }
+    };
+    let response = state
+        .dispatch_or_settle(command, response_rx, deadline, settle)
+        .await?;
+
+    // Handle response
+    let (new_balance, journal_id) = match response {
+        OrderBookResponse::FundsWithdrawn {
+            new_balance,
+            journal_id,
+            ..
+        } => (new_balance, journal_id),
+        OrderBookResponse::Error { message } => {
+            release_transfer(&user_store, user_id, source_id, body.amount).await;
+            return Err(ApiError::BadRequest(message));
+        }
+        _ => {
+            return Err(ApiError::InternalError(
+                "Unexpected response from orderbook".to_string(),
+            ))
+        }
+    };
+
+    // The balance has already left the account; if the rail refuses the
+    // payout, reverse the withdrawal's journal to put it back
+    let transfer_reference = match funding_adapter(source.kind).withdraw(&source, body.amount) {
+        Ok(reference) => reference,
+        Err(e) => {
+            release_transfer(&user_store, user_id, source_id, body.amount).await;
+            reverse_withdrawal(&state, user_id, journal_id).await?;
+            return Err(ApiError::BadRequest(format!("Withdrawal failed: {}", e)));
+        }
+    };
+
+    Ok(ApiResponse::ok(serde_json::json!({
+        "user_id": user_id.to_string(),
+        "currency": currency,
+        "amount": body.amount,
+        "new_balance": new_balance,
+        "funding_source_id": source_id.to_string(),
+        "transfer_reference": transfer_reference,
+    })))
+}
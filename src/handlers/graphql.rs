@@ -0,0 +1,249 @@
+use actix_web::http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use actix_ws::{CloseCode, CloseReason, Message};
+use async_graphql::http::{
+    playground_source, ClientMessage, GraphQLPlaygroundConfig, WebSocket, WebSocketProtocols,
+    WsMessage,
+};
+use async_graphql::Data;
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use crate::graphql::OrderbookSchema;
+use crate::state::AppState;
+use crate::utils::auth::user_id_from_request;
+use crate::utils::error::ApiError;
+
+#[post("/graphql")]
+pub async fn graphql_query(
+    req: HttpRequest,
+    schema: web::Data<OrderbookSchema>,
+    body: web::Json<async_graphql::Request>,
+) -> impl Responder {
+    // Bearer token is optional: public fields work without it, `my*` fields need it
+    let mut request = body.into_inner();
+    if let Some(user_id) = user_id_from_request(&req) {
+        request = request.data(user_id);
+    }
+
+    let response = schema.execute(request).await;
+    HttpResponse::Ok().json(response)
+}
+
+#[get("/graphql")]
+pub async fn graphql_playground(req: HttpRequest) -> impl Responder {
+    let endpoint = req.path();
+    let subscription_endpoint = format!("{}/ws", endpoint);
+    let config =
+        GraphQLPlaygroundConfig::new(endpoint).subscription_endpoint(&subscription_endpoint);
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(playground_source(config))
+}
+
+/// The operations one GraphQL socket has running, by ID. Both protocols start
+/// one with a `start` or `subscribe` frame; it ends when the client stops it
+/// or the server sends `complete`.
+#[derive(Clone)]
+struct Operations {
+    running: Arc<Mutex<HashSet<String>>>,
+    limit: usize,
+}
+
+/// Just enough of a server frame to see which operation it completes
+#[derive(Deserialize)]
+struct ServerFrame {
+    #[serde(rename = "type")]
+    kind: String,
+    id: Option<String>,
+}
+
+impl Operations {
+    fn new(limit: usize) -> Self {
+        Operations {
+            running: Arc::new(Mutex::new(HashSet::new())),
+            limit,
+        }
+    }
+
+    /// Note a client frame. Err with the operation's ID if it would start one
+    /// past the limit, in which case it must not reach the schema.
+    fn admit(&self, frame: &[u8]) -> Result<(), String> {
+        let mut running = self.running.lock().unwrap();
+        match ClientMessage::from_bytes(frame) {
+            Ok(ClientMessage::Start { id, .. }) if !running.contains(&id) => {
+                if running.len() >= self.limit {
+                    return Err(id);
+                }
+                running.insert(id);
+            }
+            Ok(ClientMessage::Stop { id }) => {
+                running.remove(&id);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Note a server frame, forgetting the operation it completes
+    fn sent(&self, frame: &str) {
+        if let Ok(ServerFrame { kind, id: Some(id) }) = serde_json::from_str(frame) {
+            if kind == "complete" {
+                self.running.lock().unwrap().remove(&id);
+            }
+        }
+    }
+}
+
+/// The error frame refusing operation `id`, in the shape `protocol` uses
+fn refusal(protocol: WebSocketProtocols, id: &str, limit: usize) -> String {
+    let error = json!({ "message": format!("At most {} operations per connection", limit) });
+    let payload = match protocol {
+        WebSocketProtocols::SubscriptionsTransportWS => error,
+        WebSocketProtocols::GraphQLWS => json!([error]),
+    };
+    json!({ "type": "error", "id": id, "payload": payload }).to_string()
+}
+
+/// Counts towards the same connection limits as the other sockets, and runs
+/// at most `WS_MAX_SUBSCRIPTIONS` operations at once. A `start` or `subscribe`
+/// beyond that gets an `error` frame for its ID and is not run.
+#[get("/graphql/ws")]
+pub async fn graphql_ws(
+    req: HttpRequest,
+    schema: web::Data<OrderbookSchema>,
+    state: web::Data<AppState>,
+    body: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    // Pick the first subprotocol we speak out of those the client offered
+    let protocol = req
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| {
+            value
+                .split(',')
+                .find_map(|p| p.trim().parse::<WebSocketProtocols>().ok())
+        })
+        .ok_or_else(|| ApiError::BadRequest("Unsupported Sec-WebSocket-Protocol".to_string()))?;
+
+    let user_id = user_id_from_request(&req);
+    let slot = state
+        .websockets
+        .open(user_id, req.peer_addr().map(|addr| addr.ip()))
+        .map_err(ApiError::TooManyRequests)?;
+    let mut connection_data = Data::default();
+    if let Some(user_id) = user_id {
+        connection_data.insert(user_id);
+    }
+    let operations = Operations::new(state.websockets.limits.max_subscriptions);
+
+    let (mut response, session, messages) =
+        actix_ws::handle(&req, body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    response.headers_mut().insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static(protocol.sec_websocket_protocol()),
+    );
+
+    // Client frames as raw payloads; pings are answered here, close ends the
+    // stream, and operations past the limit are refused here
+    let incoming = stream::unfold(
+        (messages, session.clone(), operations.clone()),
+        move |(mut messages, mut session, operations)| async move {
+            while let Some(Ok(message)) = messages.next().await {
+                let frame = match message {
+                    Message::Text(text) => text.into_bytes(),
+                    Message::Binary(bytes) => bytes,
+                    Message::Ping(bytes) if session.pong(&bytes).await.is_err() => return None,
+                    Message::Close(_) => return None,
+                    _ => continue,
+                };
+                match operations.admit(&frame) {
+                    Ok(()) => return Some((frame, (messages, session, operations))),
+                    Err(id) => {
+                        let refused = refusal(protocol, &id, operations.limit);
+                        if session.text(refused).await.is_err() {
+                            return None;
+                        }
+                    }
+                }
+            }
+            None
+        },
+    );
+
+    let schema = schema.get_ref().clone();
+    actix_web::rt::spawn(async move {
+        let _slot = slot;
+        let mut session = session;
+        let outgoing = WebSocket::new(schema, incoming, protocol).connection_data(connection_data);
+        futures_util::pin_mut!(outgoing);
+
+        while let Some(message) = outgoing.next().await {
+            match message {
+                WsMessage::Text(text) => {
+                    operations.sent(&text);
+                    if session.text(text).await.is_err() {
+                        return;
+                    }
+                }
+                WsMessage::Close(code, reason) => {
+                    let reason = CloseReason {
+                        code: CloseCode::from(code),
+                        description: Some(reason),
+                    };
+                    let _ = session.close(Some(reason)).await;
+                    return;
+                }
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(id: &str) -> Vec<u8> {
+        let payload = json!({ "query": "subscription { trades { price } }" });
+        json!({ "type": "subscribe", "id": id, "payload": payload })
+            .to_string()
+            .into_bytes()
+    }
+
+    #[test]
+    fn test_operations_past_the_limit_are_refused_until_one_ends() {
+        let operations = Operations::new(2);
+        assert_eq!(operations.admit(&start("1")), Ok(()));
+        assert_eq!(operations.admit(&start("2")), Ok(()));
+        assert_eq!(operations.admit(&start("3")), Err("3".to_string()));
+        // Not operations, or one already running
+        assert_eq!(operations.admit(br#"{"type":"ping"}"#), Ok(()));
+        assert_eq!(operations.admit(&start("2")), Ok(()));
+
+        operations.sent(r#"{"type":"next","id":"1","payload":{"data":null}}"#);
+        assert_eq!(operations.admit(&start("3")), Err("3".to_string()));
+        operations.sent(r#"{"type":"complete","id":"1"}"#);
+        assert_eq!(operations.admit(&start("3")), Ok(()));
+        assert_eq!(operations.admit(br#"{"type":"complete","id":"2"}"#), Ok(()));
+        assert_eq!(operations.admit(&start("4")), Ok(()));
+
+        let refused: serde_json::Value =
+            serde_json::from_str(&refusal(WebSocketProtocols::GraphQLWS, "5", 2)).unwrap();
+        assert_eq!(
+            refused,
+            json!({
+                "type": "error",
+                "id": "5",
+                "payload": [{ "message": "At most 2 operations per connection" }]
+            })
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use actix_web::middleware::from_fn;
+use actix_web::{get, put, web, HttpMessage, HttpRequest, Responder};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::state::AppState;
+use crate::types::{LeverageTier, LeverageTiers, DEFAULT_MARKET};
+use crate::utils::error::ApiError;
+use crate::utils::middleware::{read_scope, trade_scope};
+use crate::utils::response::ApiResponse;
+
+#[derive(Debug, Deserialize)]
+pub struct LeverageRequest {
+    pub max_leverage: Option<f64>, // null clears the cap
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LeverageTiersQuery {
+    pub user_id: Option<String>, // Omit for the default table
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LeverageTiersRequest {
+    pub user_id: Option<String>,          // Omit to replace the default table
+    pub tiers: Option<Vec<LeverageTier>>, // null removes a user override
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LiquidationPreviewQuery {
+    pub delta_qty: Option<f64>,   // Signed, negative = sell; defaults to 0
+    pub delta_price: Option<f64>, // Defaults to the mark price
+}
+
+fn parse_user_id(user_id: Option<&String>) -> Result<Option<Uuid>, ApiError> {
+    user_id
+        .map(|id| Uuid::parse_str(id))
+        .transpose()
+        .map_err(|_| ApiError::BadRequest("Invalid user_id format".to_string()))
+}
+
+#[get("/leverage", wrap = "from_fn(read_scope)")]
+pub async fn get_leverage(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetLeverageSettings {
+        user_id,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::LeverageSettings { settings } => {
+            Ok(ApiResponse::ok(settings))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[put("/leverage", wrap = "from_fn(trade_scope)")]
+pub async fn update_leverage(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<LeverageRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::SetUserLeverage {
+        user_id,
+        max_leverage: body.max_leverage,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::LeverageSettings { settings } => {
+            Ok(ApiResponse::ok(settings))
+        }
+        OrderBookResponse::Error { message } => {
+            Err(ApiError::BadRequest(message))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[get(
+    "/positions/{market}/liquidation-preview",
+    wrap = "from_fn(read_scope)"
+)]
+pub async fn get_liquidation_preview(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<LiquidationPreviewQuery>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let market = path.into_inner();
+    if market != DEFAULT_MARKET {
+        return Err(ApiError::NotFound(format!("Unknown market '{}'", market)));
+    }
+
+    let delta_qty = query.delta_qty.unwrap_or(0.0);
+    if !delta_qty.is_finite() {
+        return Err(ApiError::BadRequest("delta_qty must be a finite number".to_string()));
+    }
+    if let Some(price) = query.delta_price {
+        if !(price.is_finite() && price > 0.0) {
+            return Err(ApiError::BadRequest("delta_price must be positive".to_string()));
+        }
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetLiquidationPreview {
+        user_id,
+        delta_qty,
+        delta_price: query.delta_price,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::LiquidationPreview { current, preview } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "market": market,
+                "current": current,
+                "preview": preview,
+            })))
+        }
+        OrderBookResponse::Error { message } => {
+            Err(ApiError::BadRequest(message))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[get("/leverage-tiers")]
+pub async fn get_leverage_tiers(
+    state: web::Data<AppState>,
+    query: web::Query<LeverageTiersQuery>,
+) -> Result<impl Responder, ApiError> {
+    let user_id = parse_user_id(query.user_id.as_ref())?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetLeverageTiers {
+        user_id,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::LeverageTiers { user_id, tiers, custom } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "user_id": user_id.map(|id| id.to_string()),
+                "tiers": tiers.tiers,
+                "custom": custom,
+            })))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[put("/leverage-tiers")]
+pub async fn update_leverage_tiers(
+    state: web::Data<AppState>,
+    body: web::Json<LeverageTiersRequest>,
+) -> Result<impl Responder, ApiError> {
+    let user_id = parse_user_id(body.user_id.as_ref())?;
+
+    // Validate table shape before it reaches the engine
+    let tiers = body
+        .tiers
+        .clone()
+        .map(LeverageTiers::new)
+        .transpose()
+        .map_err(ApiError::BadRequest)?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::SetLeverageTiers {
+        user_id,
+        tiers,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::LeverageTiers { user_id, tiers, custom } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "user_id": user_id.map(|id| id.to_string()),
+                "tiers": tiers.tiers,
+                "custom": custom,
+            })))
+        }
+        OrderBookResponse::Error { message } => {
+            Err(ApiError::BadRequest(message))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
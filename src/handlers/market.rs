@@ -1,64 +1,555 @@
-use actix_web::{get, web, HttpResponse, Responder};
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use chrono::Utc;
 use serde::Deserialize;
+use std::time::Duration;
 use tokio::sync::oneshot;
 
+use crate::engine::{ping_engine, SyntheticQuote};
+use crate::handlers::auth::UserStore;
 use crate::messages::{OrderBookCommand, OrderBookResponse};
 use crate::state::AppState;
+use crate::orderbook::{BookOrder, DepthLevel, DepthTotals, OrderEntry};
+use crate::types::{FeedMode, Price, EVENT_SCHEMAS};
+use crate::utils::auth::user_id_from_request;
 use crate::utils::error::ApiError;
+use crate::utils::fx::{FxRates, BASE_QUOTE_CURRENCY};
+use crate::utils::response::{ApiResponse, Pagination};
 
 #[derive(Debug, Deserialize)]
 pub struct OrderBookQuery {
     pub depth: Option<usize>,
+    pub currency: Option<String>, // Optional display currency, e.g. "EUR"
 }
 
-#[get("/orderbook")]
+/// Resolve the display currency for a market data request: an explicit `currency`
+/// query parameter wins, otherwise the signed-in user's saved preference is used
+async fn resolve_display_currency(
+    req: &HttpRequest,
+    user_store: &UserStore,
+    requested: Option<&str>,
+) -> Option<String> {
+    if let Some(requested) = requested {
+        return Some(requested.to_uppercase());
+    }
+    let user_id = user_id_from_request(req)?;
+    // Falls back to USD if the account cannot be read
+    user_store.find_by_id(user_id).await.ok()??.display_currency
+}
+
+fn depth_level_json(
+    level: &DepthLevel,
+    fx_rates: &FxRates,
+    currency: Option<&str>,
+) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "price": level.price.to_f64(),
+        "quantity": level.quantity.to_f64(),
+        "orders": level.orders,
+    });
+    add_converted_price(&mut json, level.price, fx_rates, currency);
+    json
+}
+
+fn depth_totals_json(bids: DepthTotals, asks: DepthTotals) -> serde_json::Value {
+    let side = |totals: DepthTotals| {
+        serde_json::json!({
+            "orders": totals.orders,
+            "quantity": totals.quantity.to_f64(),
+            "notional": totals.notional,
+        })
+    };
+    serde_json::json!({
+        "bids": side(bids),
+        "asks": side(asks),
+        "imbalance": DepthTotals::imbalance(&bids, &asks),
+    })
+}
+
+fn order_entry_json(
+    entry: &OrderEntry,
+    fx_rates: &FxRates,
+    currency: Option<&str>,
+) -> serde_json::Value {
+    let mut level = serde_json::json!({
+        "order_id": entry.order_id.to_string(),
+        "price": entry.price.to_f64(),
+        "quantity": entry.quantity.to_f64(),
+    });
+    add_converted_price(&mut level, entry.price, fx_rates, currency);
+    level
+}
+
+fn add_converted_price(
+    level: &mut serde_json::Value,
+    price: Price,
+    fx_rates: &FxRates,
+    currency: Option<&str>,
+) {
+    if let Some(converted) = currency.and_then(|c| fx_rates.convert(price.to_f64(), c)) {
+        level["converted_price"] = serde_json::json!(converted);
+    }
+}
+
+/// Aggregated depth, or each resting order on by-order markets. Past the
+/// public depth limit the request needs a bearer token (see `depth_limit`).
+#[get("")]
 pub async fn get_orderbook(
+    req: HttpRequest,
     state: web::Data<AppState>,
+    user_store: web::Data<UserStore>,
     query: web::Query<OrderBookQuery>,
 ) -> Result<impl Responder, ApiError> {
     let depth = query.depth.unwrap_or(10); // Default to 10 levels
 
+    let currency = resolve_display_currency(&req, &user_store, query.currency.as_deref())
+        .await
+        .filter(|c| c != BASE_QUOTE_CURRENCY);
+    if let Some(currency) = &currency {
+        if !state.fx_rates.supports(currency) {
+            return Err(ApiError::BadRequest(format!(
+                "Unsupported display currency '{}'",
+                currency
+            )));
+        }
+    }
+
     // Create oneshot channel
     let (response_tx, response_rx) = oneshot::channel();
 
-    // Send command
-    state.orderbook_tx.send(OrderBookCommand::GetOrderBook {
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetOrderBook {
         depth,
+        deadline,
         response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    let currency = currency.as_deref();
+    let mut body = match response {
+        OrderBookResponse::OrderBookDepth { bids, asks } => {
+            serde_json::json!({
+                "feed": FeedMode::ByPrice,
+                "bids": bids.iter().map(|level| {
+                    depth_level_json(level, &state.fx_rates, currency)
+                }).collect::<Vec<_>>(),
+                "asks": asks.iter().map(|level| {
+                    depth_level_json(level, &state.fx_rates, currency)
+                }).collect::<Vec<_>>(),
+                "totals": depth_totals_json(DepthTotals::of(&bids), DepthTotals::of(&asks)),
+            })
+        }
+        OrderBookResponse::OrderBookByOrder { bids, asks } => {
+            serde_json::json!({
+                "feed": FeedMode::ByOrder,
+                "bids": bids.iter().map(|entry| {
+                    order_entry_json(entry, &state.fx_rates, currency)
+                }).collect::<Vec<_>>(),
+                "asks": asks.iter().map(|entry| {
+                    order_entry_json(entry, &state.fx_rates, currency)
+                }).collect::<Vec<_>>(),
+                "totals": depth_totals_json(
+                    DepthTotals::of_orders(&bids),
+                    DepthTotals::of_orders(&asks),
+                ),
+            })
+        }
+        _ => return Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    };
+    if let Some(currency) = currency {
+        body["display_currency"] = serde_json::json!(currency);
+    }
+    Ok(ApiResponse::ok(body))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FullBookQuery {
+    pub depth: Option<usize>, // Price levels per side
+}
+
+fn book_order_json(order: &BookOrder) -> serde_json::Value {
+    serde_json::json!({
+        "order_id": order.order_id.to_string(),
+        "user_id": order.user_id.to_string(),
+        "price": order.price.to_f64(),
+        "queue_position": order.queue_position,
+        "remaining_quantity": order.remaining_quantity.to_f64(),
+        "displayed_quantity": order.displayed_quantity.to_f64(),
+        "reserve_quantity": order.reserve_quantity.to_f64(),
+        "hidden": order.hidden,
+        "received_at": order.received_at,
     })
-    .await
-    .map_err(|_| ApiError::InternalError("Failed to send command to orderbook".to_string()))?;
+}
+
+/// Level 3 view: every resting order in queue order, with its owner, iceberg
+/// reserve and hidden orders included. For operators debugging fill priority,
+/// so it sits behind the admin token.
+#[get("")]
+pub async fn get_l3_orderbook(
+    state: web::Data<AppState>,
+    query: web::Query<FullBookQuery>,
+) -> Result<impl Responder, ApiError> {
+    let depth = query.depth.unwrap_or(10);
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetFullBook {
+        depth,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::FullBook { bids, asks } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "bids": bids.iter().map(book_order_json).collect::<Vec<_>>(),
+                "asks": asks.iter().map(book_order_json).collect::<Vec<_>>(),
+            })))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
 
-    // Wait for response
-    let response = response_rx.await
-        .map_err(|_| ApiError::InternalError("Failed to receive response from orderbook".to_string()))?;
+/// Most buckets one heatmap request may ask for
+const MAX_HEATMAP_BUCKETS: usize = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct HeatmapQuery {
+    pub buckets: Option<usize>,
+    pub range: Option<f64>, // Percent either side of the mid price
+}
+
+/// Displayed volume binned into equal price buckets around the mid price, so
+/// depth charts and heatmaps can redraw from one small response
+#[get("/orderbook/heatmap")]
+pub async fn get_orderbook_heatmap(
+    state: web::Data<AppState>,
+    query: web::Query<HeatmapQuery>,
+) -> Result<impl Responder, ApiError> {
+    let buckets = query.buckets.unwrap_or(20);
+    if buckets == 0 || buckets > MAX_HEATMAP_BUCKETS {
+        return Err(ApiError::BadRequest(format!(
+            "buckets must be between 1 and {}",
+            MAX_HEATMAP_BUCKETS
+        )));
+    }
+    let range_pct = query.range.unwrap_or(5.0);
+    if !(range_pct > 0.0 && range_pct < 100.0) {
+        return Err(ApiError::BadRequest(
+            "range must be a percentage above 0 and below 100".to_string(),
+        ));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetHeatmap {
+        buckets,
+        range_pct,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
 
     // Handle response
     match response {
-        OrderBookResponse::OrderBookDepth { bids, asks } => {
-            Ok(HttpResponse::Ok().json(serde_json::json!({
-                "bids": bids.iter().map(|(price, qty)| {
+        OrderBookResponse::Heatmap { heatmap } => {
+            let Some(heatmap) = heatmap else {
+                return Ok(ApiResponse::ok(serde_json::json!({
+                    "mid_price": null,
+                    "bucket_width": null,
+                    "buckets": [],
+                })));
+            };
+            let buckets: Vec<_> = heatmap
+                .buckets
+                .iter()
+                .map(|bucket| {
                     serde_json::json!({
-                        "price": price.to_f64(),
-                        "quantity": qty.to_f64(),
+                        "price_low": bucket.price_low,
+                        "price_high": bucket.price_high,
+                        "bid_quantity": bucket.bid_quantity.to_f64(),
+                        "ask_quantity": bucket.ask_quantity.to_f64(),
                     })
-                }).collect::<Vec<_>>(),
-                "asks": asks.iter().map(|(price, qty)| {
+                })
+                .collect();
+            Ok(ApiResponse::ok(serde_json::json!({
+                "mid_price": heatmap.mid_price,
+                "bucket_width": heatmap.bucket_width,
+                "buckets": buckets,
+            })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Largest page the trade tape endpoint will return
+const MAX_TAPE_PAGE: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct TradeTapeQuery {
+    pub after: Option<u64>, // Return entries with seq > after
+    pub limit: Option<usize>,
+}
+
+/// Public trade tape. Each page is signed over its JSON encoding (minus the
+/// `signature` and `key_id` fields) so mirrors can prove they received it unaltered, and
+/// `gap` tells them when retention has already dropped entries they asked for.
+#[get("/trades/tape")]
+pub async fn get_trade_tape(
+    state: web::Data<AppState>,
+    query: web::Query<TradeTapeQuery>,
+) -> Result<impl Responder, ApiError> {
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_TAPE_PAGE);
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetTradeTape {
+        after: query.after,
+        limit,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::TradeTape { page } => {
+            let payload = serde_json::to_vec(&page)
+                .map_err(|e| ApiError::InternalError(format!("Failed to encode page: {}", e)))?;
+            let mut body = serde_json::to_value(&page)
+                .map_err(|e| ApiError::InternalError(format!("Failed to encode page: {}", e)))?;
+            body["signature"] = serde_json::json!(state.tape_signer.sign(&payload));
+            body["key_id"] = serde_json::json!(state.tape_signer.key_id);
+            // Entries past this page are already on the tape
+            let next_after = page
+                .entries
+                .last()
+                .map(|entry| entry.seq)
+                .filter(|&seq| seq < page.last_seq);
+            let pagination = Pagination::by_seq(query.after, limit, page.entries.len(), next_after);
+            Ok(ApiResponse::ok(body).paginated(pagination))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RecentTradesQuery {
+    pub limit: Option<usize>,
+}
+
+/// The most recent public trades, oldest first, for charting clients to seed
+/// a tape with. `side` is the taker's direction.
+#[get("/trades")]
+pub async fn get_recent_trades(
+    state: web::Data<AppState>,
+    query: web::Query<RecentTradesQuery>,
+) -> Result<impl Responder, ApiError> {
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_TAPE_PAGE);
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetRecentTrades {
+        limit,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::TradeTape { page } => {
+            let trades: Vec<_> = page
+                .entries
+                .iter()
+                .map(|entry| {
                     serde_json::json!({
-                        "price": price.to_f64(),
-                        "quantity": qty.to_f64(),
+                        "seq": entry.seq,
+                        "trade_id": entry.trade_id.to_string(),
+                        "price": entry.price.to_f64(),
+                        "quantity": entry.quantity.to_f64(),
+                        "side": entry.taker_side,
+                        "timestamp": entry.timestamp,
                     })
-                }).collect::<Vec<_>>(),
+                })
+                .collect();
+            Ok(ApiResponse::ok(serde_json::json!({
+                "trades": trades,
+                "last_seq": page.last_seq,
             })))
         }
         _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
     }
 }
 
+/// Configuration of the markets this server runs, including order protection limits
+#[get("/markets")]
+pub async fn get_markets(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetMarketConfig {
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::MarketConfig { config } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "markets": [config],
+            })))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+/// Last trade, rolling mark and session open prices of the market
+#[get("/markets/state")]
+pub async fn get_market_state(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetMarketState {
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::MarketState { state } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "last_trade_price": state.last_trade_price.map(|price| price.to_f64()),
+                "mark_price": state.mark_price.map(|price| price.to_f64()),
+                "session_open_price": state.session_open_price.map(|price| price.to_f64()),
+                "session_date": state.session_date,
+            })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+pub(crate) fn synthetic_json(quote: &SyntheticQuote) -> serde_json::Value {
+    serde_json::json!({
+        "symbol": quote.index.symbol,
+        "components": quote.index.components,
+        "divisor": quote.index.divisor,
+        "value": quote.value.map(|value| value.to_f64()),
+        "error": quote.error,
+        "tradable": false,
+    })
+}
+
+/// Synthetic indices and their latest values. They cannot be traded, but
+/// stop orders can trigger on them.
+#[get("/synthetics")]
+pub async fn get_synthetic_indices(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetSyntheticIndices {
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::SyntheticIndices { quotes } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "indices": quotes.iter().map(synthetic_json).collect::<Vec<_>>(),
+            })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Event schemas published to outside receivers, with the version now sent
+#[get("/events/schemas")]
+pub async fn get_event_schemas() -> impl Responder {
+    ApiResponse::ok(serde_json::json!({ "schemas": EVENT_SCHEMAS }))
+}
+
+#[get("/metrics")]
+pub async fn metrics(state: web::Data<AppState>) -> impl Responder {
+    ApiResponse::ok(state.metrics.snapshot())
+}
+
 #[get("/health")]
 pub async fn health() -> impl Responder {
-    HttpResponse::Ok().json(serde_json::json!({
+    ApiResponse::ok(serde_json::json!({
         "status": "healthy",
         "service": "orderbook"
     }))
 }
+
+/// How long `/readyz` waits for the engine to answer a ping
+const READYZ_PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Readiness probe: unlike `/health`, fails with 503 until the engine has
+/// built its order book, so load balancers hold traffic back meanwhile. With
+/// a control channel it also pings the engine, which answers ahead of its
+/// trading queue, and fails if the engine does not answer in time.
+#[get("/readyz")]
+pub async fn readyz(state: web::Data<AppState>) -> impl Responder {
+    if !state.metrics.is_ready() {
+        return ApiResponse::unavailable(serde_json::json!({ "status": "starting" }));
+    }
+    let Some(control_tx) = &state.control_tx else {
+        return ApiResponse::ok(serde_json::json!({ "status": "ready" }));
+    };
+    match ping_engine(control_tx, READYZ_PING_TIMEOUT).await {
+        Some(pong) => ApiResponse::ok(serde_json::json!({
+            "status": "ready",
+            "queued_commands": pong.queued_commands,
+        })),
+        None => ApiResponse::unavailable(serde_json::json!({ "status": "unresponsive" })),
+    }
+}
+
+/// Answers paths no route matches, so version 2 reports them in its envelope
+pub async fn not_found(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    Err(ApiError::NotFound(format!("No route for {}", req.path())))
+}
+
+/// Exchange clock for clients syncing theirs or measuring feed latency. The
+/// sequence is that of the last public market data message, so it can be
+/// compared against the `seq` on the feed.
+#[get("/time")]
+pub async fn server_time(state: web::Data<AppState>) -> impl Responder {
+    let now = Utc::now();
+    ApiResponse::ok(serde_json::json!({
+        "server_time": now,
+        "epoch_ms": now.timestamp_millis(),
+        "market_seq": state.metrics.snapshot().market_seq,
+    }))
+}
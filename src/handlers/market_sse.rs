@@ -0,0 +1,236 @@
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde_json::Value;
+use tokio::sync::broadcast;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::engine::{MarketEvent, MarketMessage, EVENT_DEPTH_LEVELS};
+use crate::handlers::market_ws::{levels_json, request_snapshot, trade_json};
+use crate::state::{AppState, WsSlot};
+use crate::utils::error::ApiError;
+
+/// One Server-Sent Event. `id` is the feed sequence, so a client can tell
+/// where it left off.
+fn sse_event(event: &str, id: Option<u64>, data: &Value) -> web::Bytes {
+    let id = id.map(|id| format!("id: {}\n", id)).unwrap_or_default();
+    web::Bytes::from(format!("event: {}\n{}data: {}\n\n", event, id, data))
+}
+
+/// A comment line, which clients ignore but proxies see as traffic
+const KEEP_ALIVE: &str = ": keep-alive\n\n";
+
+/// `first` and then every feed message `frame` turns into an event. Keep-alive
+/// comments go out on the WebSocket heartbeat interval; a client that falls
+/// behind gets a `lagged` event saying how many messages it missed. The
+/// connection slot is held for as long as the stream is.
+fn event_stream(
+    state: &AppState,
+    events: broadcast::Receiver<MarketMessage>,
+    slot: WsSlot,
+    first: Vec<web::Bytes>,
+    frame: impl Fn(&MarketMessage) -> Option<web::Bytes> + 'static,
+) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    let period = state.websockets.limits.heartbeat_interval;
+    let mut heartbeat = tokio::time::interval_at(Instant::now() + period, period);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let live = stream::unfold(
+        (events, heartbeat, slot, frame),
+        |(mut events, mut heartbeat, slot, frame)| async move {
+            loop {
+                let bytes = tokio::select! {
+                    _ = heartbeat.tick() => web::Bytes::from_static(KEEP_ALIVE.as_bytes()),
+                    event = events.recv() => match event {
+                        Ok(message) => match frame(&message) {
+                            Some(bytes) => bytes,
+                            None => continue,
+                        },
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            sse_event("lagged", None, &serde_json::json!({ "missed": missed }))
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                };
+                return Some((bytes, (events, heartbeat, slot, frame)));
+            }
+        },
+    );
+    stream::iter(first).chain(live).map(Ok)
+}
+
+fn event_response(
+    body: impl Stream<Item = Result<web::Bytes, actix_web::Error>> + 'static,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body)
+}
+
+fn trade_stream(
+    state: &AppState,
+    slot: WsSlot,
+) -> impl Stream<Item = Result<web::Bytes, actix_web::Error>> {
+    let events = state.events.subscribe();
+    event_stream(state, events, slot, Vec::new(), |message| {
+        match &message.event {
+            MarketEvent::Trade(entry) => {
+                let data = serde_json::json!({
+                    "seq": message.seq,
+                    "sent_at": message.sent_at,
+                    "trade": trade_json(entry),
+                });
+                Some(sse_event("trade", Some(message.seq), &data))
+            }
+            _ => None,
+        }
+    })
+}
+
+async fn depth_stream(
+    state: &AppState,
+    slot: WsSlot,
+) -> Result<impl Stream<Item = Result<web::Bytes, actix_web::Error>>, ApiError> {
+    // Subscribe before the snapshot, so no change falls between the two
+    let events = state.events.subscribe();
+    let (seq, bids, asks, _) = request_snapshot(state, EVENT_DEPTH_LEVELS).await?;
+    let data = serde_json::json!({
+        "seq": seq,
+        "snapshot": true,
+        "bids": levels_json(&bids),
+        "asks": levels_json(&asks),
+    });
+    let snapshot = sse_event("depth", Some(seq), &data);
+
+    Ok(event_stream(
+        state,
+        events,
+        slot,
+        vec![snapshot],
+        move |message| {
+            match &message.event {
+                // Books older than the snapshot are already in it
+                MarketEvent::Depth { bids, asks, .. } if message.seq > seq => {
+                    let data = serde_json::json!({
+                        "seq": message.seq,
+                        "sent_at": message.sent_at,
+                        "bids": levels_json(bids),
+                        "asks": levels_json(asks),
+                    });
+                    Some(sse_event("depth", Some(message.seq), &data))
+                }
+                _ => None,
+            }
+        },
+    ))
+}
+
+/// Public trades as Server-Sent Events, for clients that cannot use the
+/// market data WebSocket. Each `trade` event carries the same trade as the
+/// WebSocket's trades channel.
+#[get("/stream/trades")]
+pub async fn stream_trades(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slot = state
+        .websockets
+        .open(None, req.peer_addr().map(|addr| addr.ip()))
+        .map_err(ApiError::TooManyRequests)?;
+    Ok(event_response(trade_stream(&state, slot)))
+}
+
+/// The top 20 levels of each side as Server-Sent Events: a snapshot marked
+/// `"snapshot": true`, then a `depth` event each time the book changes
+#[get("/stream/depth")]
+pub async fn stream_depth(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let slot = state
+        .websockets
+        .open(None, req.peer_addr().map(|addr| addr.ip()))
+        .map_err(ApiError::TooManyRequests)?;
+    Ok(event_response(depth_stream(&state, slot).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        event_channel, run_orderbook_engine, EngineConfig, EngineMetrics, TapeEntry,
+    };
+    use crate::orderbook::DepthLevel;
+    use crate::types::{OrderSide, Price, Quantity};
+    use chrono::Utc;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    fn message(seq: u64, event: MarketEvent) -> MarketMessage {
+        MarketMessage {
+            seq,
+            sent_at: Utc::now(),
+            event,
+        }
+    }
+
+    async fn next_text(
+        stream: &mut (impl Stream<Item = Result<web::Bytes, actix_web::Error>> + Unpin),
+    ) -> String {
+        let bytes = stream.next().await.unwrap().unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_streams_replay_the_feed_as_server_sent_events() {
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        let events = event_channel();
+        tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            events.clone(),
+            EngineConfig::default(),
+        ));
+        let state = AppState::new(tx, metrics).with_events(events.clone());
+
+        let slot = state.websockets.open(None, None).unwrap();
+        let mut depth = Box::pin(depth_stream(&state, slot).await.unwrap());
+        let slot = state.websockets.open(None, None).unwrap();
+        let mut trades = Box::pin(trade_stream(&state, slot));
+        assert_eq!(state.websockets.open_count(), 2);
+
+        let snapshot = next_text(&mut depth).await;
+        assert!(snapshot.starts_with("event: depth\nid: 0\ndata: {"));
+        assert!(snapshot.contains(r#""snapshot":true"#));
+
+        let level = DepthLevel::new(Price::from_f64(99.0), Quantity::from_f64(1.0), 1);
+        let book = MarketEvent::Depth {
+            bids: vec![level],
+            asks: Vec::new(),
+            imbalance: None,
+        };
+        let trade = TapeEntry {
+            seq: 1,
+            trade_id: Uuid::new_v4(),
+            price: Price::from_f64(100.0),
+            quantity: Quantity::from_f64(1.0),
+            taker_side: OrderSide::Buy,
+            timestamp: Utc::now(),
+        };
+        // Already in the snapshot, then newer than it
+        events.send(message(0, book.clone()));
+        events.send(message(1, MarketEvent::Trade(trade)));
+        events.send(message(2, book));
+
+        let live = next_text(&mut depth).await;
+        assert!(live.starts_with("event: depth\nid: 2\n"));
+        assert!(live.contains(r#""price":99.0"#));
+        let printed = next_text(&mut trades).await;
+        assert!(printed.starts_with("event: trade\nid: 1\n"));
+        assert!(printed.ends_with("\n\n"));
+
+        drop(depth);
+        assert_eq!(state.websockets.open_count(), 1);
+    }
+}
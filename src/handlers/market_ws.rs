@@ -0,0 +1,590 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use tokio::sync::{broadcast, oneshot};
+
+use crate::engine::{
+    top_imbalance, top_of_book, MarketEvent, MarketMessage, TapeEntry, EVENT_DEPTH_LEVELS,
+};
+use crate::handlers::ws_session::open_ws;
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::orderbook::{DepthLevel, MarketState};
+use crate::state::{AppState, MaintenanceNotice};
+use crate::types::{Price, Quantity};
+use crate::utils::error::ApiError;
+
+/// What a market data connection can follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarketChannel {
+    Depth,
+    Trades,
+    Ticker,
+    DepthDiff, // The public depth once, then only the levels that change
+    Indices,   // Synthetic index values as they change
+}
+
+/// A request frame on the market data WebSocket
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum FeedRequest {
+    Subscribe { channels: Vec<MarketChannel> },
+    Unsubscribe { channels: Vec<MarketChannel> },
+}
+
+/// Best bid and ask with their displayed size, and the last trade price
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Ticker {
+    best_bid: Option<(Price, Quantity)>,
+    best_ask: Option<(Price, Quantity)>,
+    last_price: Option<Price>,
+    imbalance: Option<f64>, // Top of book imbalance, as the engine averages it
+}
+
+impl Ticker {
+    fn set_book(&mut self, bids: &[DepthLevel], asks: &[DepthLevel], imbalance: Option<f64>) {
+        (self.best_bid, self.best_ask) = top_of_book(bids, asks);
+        self.imbalance = imbalance;
+    }
+
+    /// Mid price and half the spread, when both sides are shown
+    fn mid_and_half_spread(&self) -> Option<(f64, f64)> {
+        let (bid, _) = self.best_bid?;
+        let (ask, _) = self.best_ask?;
+        let (bid, ask) = (bid.to_f64(), ask.to_f64());
+        Some(((bid + ask) / 2.0, (ask - bid) / 2.0))
+    }
+
+    /// The mid moved toward the side with less size behind it, which is where
+    /// the next trade is likelier to go. Equal to each price weighted by the
+    /// size on the other side.
+    fn microprice(&self) -> Option<f64> {
+        let (mid, half_spread) = self.mid_and_half_spread()?;
+        Some(mid + half_spread * top_imbalance(&(self.best_bid, self.best_ask))?)
+    }
+
+    /// Like the microprice, but leaning by the engine's rolling imbalance,
+    /// so one order flickering at the top moves it less
+    fn fair_value(&self) -> Option<f64> {
+        let (mid, half_spread) = self.mid_and_half_spread()?;
+        Some(mid + half_spread * self.imbalance?)
+    }
+
+    fn to_json(self) -> Value {
+        serde_json::json!({
+            "best_bid": self.best_bid.map(|(price, _)| price.to_f64()),
+            "bid_quantity": self.best_bid.map(|(_, quantity)| quantity.to_f64()),
+            "best_ask": self.best_ask.map(|(price, _)| price.to_f64()),
+            "ask_quantity": self.best_ask.map(|(_, quantity)| quantity.to_f64()),
+            "last_price": self.last_price.map(|price| price.to_f64()),
+            "mid_price": self.mid_and_half_spread().map(|(mid, _)| mid),
+            "microprice": self.microprice(),
+            "fair_value": self.fair_value(),
+        })
+    }
+}
+
+pub(crate) fn levels_json(levels: &[DepthLevel]) -> Vec<Value> {
+    levels
+        .iter()
+        .map(|level| {
+            serde_json::json!({
+                "price": level.price.to_f64(),
+                "quantity": level.quantity.to_f64(),
+                "orders": level.orders,
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn trade_json(entry: &TapeEntry) -> Value {
+    serde_json::json!({
+        "seq": entry.seq,
+        "trade_id": entry.trade_id.to_string(),
+        "price": entry.price.to_f64(),
+        "quantity": entry.quantity.to_f64(),
+        "side": entry.taker_side,
+        "timestamp": entry.timestamp,
+    })
+}
+
+fn error_frame(message: &str) -> Value {
+    serde_json::json!({ "event": "error", "error": message })
+}
+
+/// Sent to every connection, whatever it follows: the announced maintenance
+/// notice, or null once it is withdrawn
+fn maintenance_frame(notice: Option<&MaintenanceNotice>) -> Value {
+    serde_json::json!({ "event": "maintenance", "notice": notice })
+}
+
+/// One connection's subscriptions, and the ticker it keeps from the feed
+#[derive(Debug, Default)]
+struct MarketFeed {
+    channels: BTreeSet<MarketChannel>,
+    ticker: Ticker,
+    snapshot_seq: u64, // Depth and ticker messages up to here are older than the snapshot sent
+    diff_seq: u64,     // Diffs up to here are already in the book snapshot sent
+}
+
+impl MarketFeed {
+    /// Frames a feed message produces on the subscribed channels
+    fn frames(&mut self, message: &MarketMessage) -> Vec<Value> {
+        let stamp = |channel: MarketChannel, mut frame: Value| {
+            frame["channel"] = serde_json::json!(channel);
+            frame["seq"] = serde_json::json!(message.seq);
+            frame["sent_at"] = serde_json::json!(message.sent_at);
+            frame
+        };
+        let mut frames = Vec::new();
+        let before = self.ticker;
+        match &message.event {
+            MarketEvent::Trade(entry) => {
+                if self.channels.contains(&MarketChannel::Trades) {
+                    let frame = serde_json::json!({ "trade": trade_json(entry) });
+                    frames.push(stamp(MarketChannel::Trades, frame));
+                }
+                if message.seq > self.snapshot_seq {
+                    self.ticker.last_price = Some(entry.price);
+                }
+            }
+            MarketEvent::Depth {
+                bids,
+                asks,
+                imbalance,
+            } if message.seq > self.snapshot_seq => {
+                if self.channels.contains(&MarketChannel::Depth) {
+                    let frame = serde_json::json!({
+                        "bids": levels_json(bids),
+                        "asks": levels_json(asks),
+                    });
+                    frames.push(stamp(MarketChannel::Depth, frame));
+                }
+                self.ticker.set_book(bids, asks, *imbalance);
+            }
+            MarketEvent::DepthDiff {
+                prev_seq,
+                bids,
+                asks,
+            } if message.seq > self.diff_seq
+                && self.channels.contains(&MarketChannel::DepthDiff) =>
+            {
+                let frame = serde_json::json!({
+                    "prev_seq": prev_seq,
+                    "bids": levels_json(bids),
+                    "asks": levels_json(asks),
+                });
+                frames.push(stamp(MarketChannel::DepthDiff, frame));
+            }
+            MarketEvent::IndexValue { symbol, value }
+                if self.channels.contains(&MarketChannel::Indices) =>
+            {
+                let frame = serde_json::json!({
+                    "symbol": symbol,
+                    "value": value.to_f64(),
+                });
+                frames.push(stamp(MarketChannel::Indices, frame));
+            }
+            // Private, or a book older than the snapshot
+            _ => {}
+        }
+        if self.channels.contains(&MarketChannel::Ticker) && self.ticker != before {
+            frames.push(stamp(MarketChannel::Ticker, self.ticker.to_json()));
+        }
+        frames
+    }
+
+    /// Apply a request frame and answer it. Newly followed depth, ticker and
+    /// depth diff channels start with a snapshot, which live messages then
+    /// continue.
+    async fn handle_request(&mut self, state: &AppState, text: &str) -> Vec<Value> {
+        let request = match serde_json::from_str::<FeedRequest>(text) {
+            Ok(request) => request,
+            Err(e) => return vec![error_frame(&format!("Invalid request: {}", e))],
+        };
+        let added: Vec<MarketChannel> = match request {
+            FeedRequest::Subscribe { channels } => channels
+                .into_iter()
+                .filter(|channel| self.channels.insert(*channel))
+                .collect(),
+            FeedRequest::Unsubscribe { channels } => {
+                for channel in &channels {
+                    self.channels.remove(channel);
+                }
+                Vec::new()
+            }
+        };
+        let mut frames =
+            vec![serde_json::json!({ "event": "subscriptions", "channels": self.channels })];
+        frames.extend(self.snapshot(state, &added).await);
+        frames
+    }
+
+    /// After falling behind the feed, the state channels start over from a
+    /// fresh snapshot rather than replaying what was missed. Trades that were
+    /// missed are gone; the lagged frame says how many messages that was.
+    async fn resync(&mut self, state: &AppState, missed: u64) -> Vec<Value> {
+        let mut frames = vec![serde_json::json!({ "event": "lagged", "missed": missed })];
+        let channels: Vec<MarketChannel> = self.channels.iter().copied().collect();
+        frames.extend(self.snapshot(state, &channels).await);
+        frames
+    }
+
+    /// Snapshots of the depth, ticker and depth diff channels among `channels`
+    async fn snapshot(&mut self, state: &AppState, channels: &[MarketChannel]) -> Vec<Value> {
+        let mut frames = Vec::new();
+        let wants_depth = channels.contains(&MarketChannel::Depth);
+        let wants_ticker = channels.contains(&MarketChannel::Ticker);
+        let wants_diff = channels.contains(&MarketChannel::DepthDiff);
+        if !wants_depth && !wants_ticker && !wants_diff {
+            return frames;
+        }
+        let levels = if wants_diff {
+            state.public_depth_limit
+        } else {
+            EVENT_DEPTH_LEVELS
+        };
+        let (seq, bids, asks, market) = match request_snapshot(state, levels).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                frames.push(error_frame(e.status_and_message().1));
+                return frames;
+            }
+        };
+        self.snapshot_seq = seq;
+        if wants_diff {
+            self.diff_seq = seq;
+        }
+        self.ticker.set_book(&bids, &asks, market.imbalance);
+        self.ticker.last_price = market.last_trade_price;
+
+        let stamp = |channel: MarketChannel, mut frame: Value| {
+            frame["channel"] = serde_json::json!(channel);
+            frame["seq"] = serde_json::json!(seq);
+            frame["snapshot"] = serde_json::json!(true);
+            frame
+        };
+        let top =
+            |levels: &[DepthLevel]| levels_json(&levels[..levels.len().min(EVENT_DEPTH_LEVELS)]);
+        if wants_depth {
+            let frame = serde_json::json!({
+                "bids": top(&bids),
+                "asks": top(&asks),
+            });
+            frames.push(stamp(MarketChannel::Depth, frame));
+        }
+        if wants_ticker {
+            frames.push(stamp(MarketChannel::Ticker, self.ticker.to_json()));
+        }
+        if wants_diff {
+            let frame = serde_json::json!({
+                "bids": levels_json(&bids),
+                "asks": levels_json(&asks),
+            });
+            frames.push(stamp(MarketChannel::DepthDiff, frame));
+        }
+        frames
+    }
+}
+
+pub(crate) async fn request_snapshot(
+    state: &AppState,
+    levels: usize,
+) -> Result<(u64, Vec<DepthLevel>, Vec<DepthLevel>, MarketState), ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetMarketSnapshot {
+        levels,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::MarketSnapshot {
+            seq,
+            bids,
+            asks,
+            state,
+        } => Ok((seq, bids, asks, state)),
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Live depth, trades and ticker for clients that cannot poll. Send
+/// `{"op": "subscribe", "channels": ["depth", "trades", "ticker"]}` to follow
+/// channels and `"op": "unsubscribe"` to stop. `depth_diff` keeps a local copy
+/// of the book to the public depth limit: a snapshot, then the changed levels. `indices` carries
+/// synthetic index values as they change. A client that falls too far behind
+/// the feed is told how many messages it missed, then sent fresh snapshots;
+/// one that stops reading altogether is disconnected.
+#[get("/ws/market")]
+pub async fn market_ws(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    let (response, mut connection, mut messages) = open_ws(&req, body, &state, None)?;
+    // Subscribe before any snapshot is taken, so nothing falls between the two
+    let mut events = state.events.subscribe();
+    let mut maintenance = state.maintenance.subscribe();
+
+    actix_web::rt::spawn(async move {
+        let mut feed = MarketFeed::default();
+        let mut heartbeat = connection.heartbeat();
+        let announced = maintenance.borrow_and_update().clone();
+        if let Some(notice) = announced {
+            if !connection.send(&maintenance_frame(Some(&notice))).await {
+                return;
+            }
+        }
+        loop {
+            let frames = tokio::select! {
+                _ = heartbeat.tick() => {
+                    if !connection.beat().await {
+                        return;
+                    }
+                    continue;
+                }
+                message = messages.next() => {
+                    connection.heard();
+                    match message {
+                        Some(Ok(Message::Text(text))) => feed.handle_request(&state, &text).await,
+                        Some(Ok(Message::Binary(_))) => {
+                            vec![error_frame("Send requests as text frames")]
+                        }
+                        Some(Ok(Message::Ping(bytes))) => {
+                            if !connection.pong(&bytes).await {
+                                return;
+                            }
+                            continue;
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(message) => feed.frames(&message),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        feed.resync(&state, missed).await
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                changed = maintenance.changed() => match changed {
+                    Ok(()) => vec![maintenance_frame(maintenance.borrow_and_update().as_ref())],
+                    Err(_) => break,
+                },
+            };
+            for frame in frames {
+                if !connection.send(&frame).await {
+                    return;
+                }
+            }
+        }
+        connection.close().await;
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{event_channel, run_orderbook_engine, EngineConfig, EngineMetrics};
+    use chrono::Utc;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    fn message(seq: u64, event: MarketEvent) -> MarketMessage {
+        MarketMessage {
+            seq,
+            sent_at: Utc::now(),
+            event,
+        }
+    }
+
+    fn depth(bid: f64, ask: f64) -> MarketEvent {
+        let level = |price| DepthLevel::new(Price::from_f64(price), Quantity::from_f64(1.0), 1);
+        MarketEvent::Depth {
+            bids: vec![level(bid)],
+            asks: vec![level(ask)],
+            imbalance: Some(0.0),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_then_live_frames_per_channel() {
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            event_channel(),
+            EngineConfig::default(),
+        ));
+        let state = AppState::new(tx, metrics);
+        let mut feed = MarketFeed::default();
+
+        let frames = feed.handle_request(&state, r#"{"op": "listen"}"#).await;
+        assert_eq!(frames[0]["event"], "error");
+
+        let request = r#"{"op": "subscribe", "channels": ["depth", "ticker"]}"#;
+        let frames = feed.handle_request(&state, request).await;
+        assert_eq!(frames[0]["channels"][1], "ticker");
+        assert_eq!(frames[1]["channel"], "depth");
+        assert_eq!(frames[1]["snapshot"], true);
+        assert_eq!(frames[2]["channel"], "ticker");
+        assert_eq!(frames[2]["best_bid"], Value::Null);
+
+        // The book changed; trades are not followed, but move the ticker
+        let frames = feed.frames(&message(1, depth(99.0, 101.0)));
+        let channels: Vec<&Value> = frames.iter().map(|frame| &frame["channel"]).collect();
+        assert_eq!(channels, vec!["depth", "ticker"]);
+        assert_eq!(frames[1]["best_ask"], 101.0);
+        let trade = TapeEntry {
+            seq: 1,
+            trade_id: Uuid::new_v4(),
+            price: Price::from_f64(100.0),
+            quantity: Quantity::from_f64(1.0),
+            taker_side: crate::types::OrderSide::Buy,
+            timestamp: Utc::now(),
+        };
+        let frames = feed.frames(&message(2, MarketEvent::Trade(trade)));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0]["last_price"], 100.0);
+
+        // An unchanged top of book sends depth but no ticker
+        let frames = feed.frames(&message(3, depth(99.0, 101.0)));
+        assert_eq!(frames.len(), 1);
+
+        let request = r#"{"op": "unsubscribe", "channels": ["depth", "ticker"]}"#;
+        feed.handle_request(&state, request).await;
+        assert!(feed.frames(&message(4, depth(98.0, 102.0))).is_empty());
+
+        let index = MarketEvent::IndexValue {
+            symbol: "BTC-CENTS".to_string(),
+            value: Price::from_f64(10_000.0),
+        };
+        assert!(feed.frames(&message(5, index.clone())).is_empty());
+        let request = r#"{"op": "subscribe", "channels": ["indices"]}"#;
+        feed.handle_request(&state, request).await;
+        let frames = feed.frames(&message(6, index));
+        assert_eq!(frames[0]["channel"], "indices");
+        assert_eq!(frames[0]["value"], 10_000.0);
+    }
+
+    #[test]
+    fn test_ticker_leans_toward_the_thinner_side() {
+        let level = |price, quantity| {
+            DepthLevel::new(Price::from_f64(price), Quantity::from_f64(quantity), 1)
+        };
+        let mut ticker = Ticker::default();
+        ticker.set_book(&[level(99.0, 3.0)], &[], None);
+        assert_eq!(ticker.to_json()["mid_price"], Value::Null);
+        assert_eq!(ticker.to_json()["fair_value"], Value::Null);
+
+        // Three times the size bid as offered: a quarter of the way from the ask
+        ticker.set_book(&[level(99.0, 3.0)], &[level(101.0, 1.0)], Some(0.5));
+        let json = ticker.to_json();
+        assert_eq!(json["mid_price"], 100.0);
+        assert_eq!(json["microprice"], 100.5);
+        assert_eq!(json["fair_value"], 100.5);
+
+        // The sizes flip; the microprice follows at once, the fair value by
+        // the engine's average
+        ticker.set_book(&[level(99.0, 1.0)], &[level(101.0, 3.0)], Some(0.3));
+        assert_eq!(ticker.microprice(), Some(99.5));
+        let fair_value = ticker.fair_value().unwrap();
+        assert!((fair_value - 100.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_maintenance_frames_carry_the_notice_or_null() {
+        let now = Utc::now();
+        let scheduled_at = now + chrono::Duration::hours(1);
+        let notice = MaintenanceNotice::new("Engine upgrade", scheduled_at, now).unwrap();
+        let frame = maintenance_frame(Some(&notice));
+        assert_eq!(frame["event"], "maintenance");
+        assert_eq!(frame["notice"]["message"], "Engine upgrade");
+        assert_eq!(
+            frame["notice"]["scheduled_at"],
+            serde_json::json!(scheduled_at)
+        );
+        assert_eq!(maintenance_frame(None)["notice"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_depth_diff_continues_from_the_whole_book_snapshot() {
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            event_channel(),
+            EngineConfig::default(),
+        ));
+        let state = AppState::new(tx, metrics);
+        let mut feed = MarketFeed::default();
+
+        let request = r#"{"op": "subscribe", "channels": ["depth_diff"]}"#;
+        let frames = feed.handle_request(&state, request).await;
+        assert_eq!(frames[1]["channel"], "depth_diff");
+        assert_eq!(frames[1]["snapshot"], true);
+        let snapshot_seq = frames[1]["seq"].as_u64().unwrap();
+
+        let diff = |seq: u64, prev_seq| {
+            let MarketEvent::Depth { bids, asks, .. } = depth(99.0, 101.0) else {
+                unreachable!()
+            };
+            message(
+                seq,
+                MarketEvent::DepthDiff {
+                    prev_seq,
+                    bids,
+                    asks,
+                },
+            )
+        };
+        // Already in the snapshot
+        assert!(feed.frames(&diff(snapshot_seq, 0)).is_empty());
+        let frames = feed.frames(&diff(snapshot_seq + 2, snapshot_seq));
+        assert_eq!(frames[0]["prev_seq"], snapshot_seq);
+        assert_eq!(frames[0]["asks"][0]["price"], 101.0);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_feed_starts_over_from_fresh_snapshots() {
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            event_channel(),
+            EngineConfig::default(),
+        ));
+        let state = AppState::new(tx, metrics);
+        let mut feed = MarketFeed::default();
+
+        let request = r#"{"op": "subscribe", "channels": ["trades", "ticker"]}"#;
+        feed.handle_request(&state, request).await;
+        feed.frames(&message(7, depth(99.0, 101.0)));
+
+        let frames = feed.resync(&state, 12).await;
+        assert_eq!(frames[0]["event"], "lagged");
+        assert_eq!(frames[0]["missed"], 12);
+        // The ticker is current again; trades have no snapshot to send
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1]["channel"], "ticker");
+        assert_eq!(frames[1]["snapshot"], true);
+        assert_eq!(frames[1]["best_bid"], Value::Null);
+    }
+}
@@ -1,9 +1,33 @@
+pub mod admin;
+pub mod api_keys;
 pub mod auth;
+pub mod export;
+pub mod funding;
+pub mod graphql;
+pub mod margin;
 pub mod market;
+pub mod market_sse;
+pub mod market_ws;
 pub mod orders;
+pub mod stats;
+pub mod trading_ws;
 pub mod user;
+pub mod user_ws;
+pub mod ws_session;
 
+pub use admin::*;
+pub use api_keys::*;
 pub use auth::*;
+pub use export::*;
+pub use funding::*;
+pub use graphql::*;
+pub use margin::*;
 pub use market::*;
+pub use market_sse::*;
+pub use market_ws::*;
 pub use orders::*;
+pub use stats::*;
+pub use trading_ws::*;
 pub use user::*;
+pub use user_ws::*;
+pub use ws_session::*;
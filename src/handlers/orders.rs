@@ -1,40 +1,436 @@
-use actix_web::{delete, post, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web::middleware::from_fn;
+use actix_web::{delete, get, patch, post, web, HttpMessage, HttpRequest, Responder};
+use chrono::{DateTime, Utc};
 use serde::Deserialize;
+use std::time::Duration;
 use tokio::sync::oneshot;
 use uuid::Uuid;
 
-use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::engine::OrderTimings;
+use crate::messages::{LimitOrderParams, OrderBookCommand, OrderBookResponse};
+use crate::orderbook::OrderFilter;
 use crate::state::AppState;
-use crate::types::{OrderSide, Price, Quantity};
+use crate::types::{
+    OrderSide, OrderSource, Peg, PegReference, Price, Quantity, SlippageGuard, TimeInForce, Trade,
+};
 use crate::utils::error::ApiError;
+use crate::utils::format::FixedPointError;
+use crate::utils::middleware::{read_scope, trade_scope, ReceivedAt};
+use crate::utils::response::ApiResponse;
 
 #[derive(Debug, Deserialize)]
 pub struct LimitOrderRequest {
     pub side: String,     // "buy" or "sell"
     pub price: f64,
     pub quantity: f64,
+    pub time_in_force: Option<String>, // "gtc" (default) or "ioc"
+    pub display_quantity: Option<f64>, // Iceberg: show only this much at a time
+    pub hidden: Option<bool>,          // Rest without appearing in the book at all
+    pub min_fill_qty: Option<f64>,     // Resting: skip takers that cannot fill this much
+    pub expires_at: Option<DateTime<Utc>>, // Good-till-date: cancelled if still resting then
+    pub trade_through_protection: Option<bool>, // Reject rather than trade beyond the market's band
+    pub priority_fee: Option<f64>, // Quote currency bid for queue position, where the market takes it
+    pub client_order_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchOrderRequest {
+    pub orders: Vec<LimitOrderRequest>, // At most MAX_BATCH_ORDERS
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PeggedOrderRequest {
+    pub side: String,     // "buy" or "sell"
+    pub quantity: f64,
+    pub peg: String,      // "bid", "ask" or "mid"
+    pub offset: Option<f64>,      // Added to the reference price; may be negative
+    pub limit_price: Option<f64>, // Buys never re-price above it, sells never below
+    pub expires_at: Option<DateTime<Utc>>,
+    pub client_order_id: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MarketOrderRequest {
     pub side: String,     // "buy" or "sell"
     pub quantity: f64,
+    pub max_slippage_bps: Option<u32>, // Stop this far from the best price on arrival
+    pub limit_price: Option<f64>,      // Or at an explicit worst price
+    pub client_order_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StopOrderRequest {
+    pub side: String,     // "buy" or "sell"
+    pub quantity: f64,
+    pub stop_price: f64,  // Buy stops trigger at or above, sell stops at or below
+    pub trigger_index: Option<String>, // Watch this synthetic index instead of the last trade price
+    pub client_order_id: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CancelOrderRequest {
-    pub order_id: String,
+    pub order_id: Option<String>,
+    pub client_order_id: Option<String>, // Instead of order_id
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MassCancelRequest {
+    pub side: Option<String>, // "buy" or "sell"; both when omitted
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    pub tag: Option<String>, // Only orders whose client_order_id starts with this
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CancelAllAfterRequest {
+    pub timeout_ms: u64, // 0 disarms
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AmendOrderRequest {
+    pub price: Option<f64>,    // Loses queue priority
+    pub quantity: Option<f64>, // New total size, only smaller; keeps queue priority
 }
 
-#[post("/limit")]
+#[derive(Debug, Deserialize)]
+pub struct OrderHistoryQuery {
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrderFillsQuery {
+    pub after: Option<u64>, // Last fill_seq the client has seen; omit for all fills
+}
+
+/// Largest number of orders accepted in one batch
+pub const MAX_BATCH_ORDERS: usize = 50;
+
+/// Largest number of orders returned by the history endpoint
+const MAX_HISTORY_LIMIT: usize = 500;
+
+/// Bounds on a dead man's switch timeout: long enough to survive a slow
+/// round trip, short enough to still be a safety net
+const MIN_CANCEL_AFTER_MS: u64 = 1_000;
+const MAX_CANCEL_AFTER_MS: u64 = 3_600_000;
+
+/// Request header that opts an order response into latency breakdowns
+pub const DEBUG_TIMINGS_HEADER: &str = "X-Debug-Timings";
+
+/// Whether the request asked for a latency breakdown
+fn debug_timings(req: &HttpRequest) -> bool {
+    req.headers().contains_key(DEBUG_TIMINGS_HEADER)
+}
+
+/// Build the order placement response, attaching the latency breakdown when asked for
+fn order_placed_json(
+    debug_timings: bool,
+    order_id: Uuid,
+    trades: Vec<Trade>,
+    status: String,
+    timings: OrderTimings,
+    received_at: DateTime<Utc>,
+    client_order_id: &Option<String>,
+) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "order_id": order_id.to_string(),
+        "status": status,
+        "received_at": received_at,
+        "client_order_id": client_order_id,
+        "trades_count": trades.len(),
+        "trades": trades,
+    });
+
+    if debug_timings {
+        let gateway_us = (Utc::now() - received_at)
+            .num_microseconds()
+            .unwrap_or(i64::MAX);
+        json["timings"] = serde_json::json!({
+            "queue_wait_us": timings.queue_wait_us,
+            "match_us": timings.match_us,
+            "settlement_us": timings.settlement_us,
+            "engine_us": timings.engine_us,
+            "gateway_us": gateway_us,
+        });
+    }
+
+    json
+}
+
+/// A price from request input, which has to be above zero. Anything that
+/// would not survive conversion to fixed point is refused here, before it
+/// can reach the book.
+pub(crate) fn positive_price(field: &str, value: f64) -> Result<Price, String> {
+    match Price::try_from_f64(value) {
+        Ok(price) if price.raw() > 0 => Ok(price),
+        Ok(_) | Err(FixedPointError::Negative) => Err(format!("{} must be positive", field)),
+        Err(e) => Err(format!("{} is {}", field, e)),
+    }
+}
+
+/// A quantity from request input, which has to be above zero
+pub(crate) fn positive_quantity(field: &str, value: f64) -> Result<Quantity, String> {
+    match Quantity::try_from_f64(value) {
+        Ok(quantity) if !quantity.is_zero() => Ok(quantity),
+        Ok(_) | Err(FixedPointError::Negative) => Err(format!("{} must be positive", field)),
+        Err(e) => Err(format!("{} is {}", field, e)),
+    }
+}
+
+/// Check a limit order request and convert it for the engine
+fn parse_limit_order(body: &LimitOrderRequest) -> Result<LimitOrderParams, String> {
+    // Parse side
+    let side = match body.side.to_lowercase().as_str() {
+        "buy" => OrderSide::Buy,
+        "sell" => OrderSide::Sell,
+        _ => return Err("Invalid side, use 'buy' or 'sell'".to_string()),
+    };
+
+    let price = positive_price("price", body.price)?;
+    let quantity = positive_quantity("quantity", body.quantity)?;
+
+    // Parse time in force
+    let time_in_force = match &body.time_in_force {
+        Some(tif) => tif.parse::<TimeInForce>()?,
+        None => TimeInForce::default(),
+    };
+
+    // A good-till-date order has to be able to rest
+    if body.expires_at.is_some() && time_in_force == TimeInForce::IOC {
+        return Err("expires_at cannot be combined with IOC".to_string());
+    }
+
+    // Parse iceberg display size
+    let display_quantity = match body.display_quantity {
+        Some(display) if !(display > 0.0 && display <= body.quantity) => {
+            return Err("display_quantity must be positive and no larger than quantity".to_string())
+        }
+        display => display
+            .map(|display| positive_quantity("display_quantity", display))
+            .transpose()?,
+    };
+    let hidden = body.hidden.unwrap_or(false);
+    if hidden && display_quantity.is_some() {
+        return Err("display_quantity cannot be combined with hidden".to_string());
+    }
+
+    // Parse minimum fill; an iceberg slice has to be able to satisfy it
+    let min_fill_qty = match body.min_fill_qty {
+        Some(min) if !(min > 0.0 && min <= body.quantity) => {
+            return Err("min_fill_qty must be positive and no larger than quantity".to_string())
+        }
+        min => min
+            .map(|min| positive_quantity("min_fill_qty", min))
+            .transpose()?,
+    };
+    if min_fill_qty.zip(display_quantity).is_some_and(|(min, display)| min > display) {
+        return Err("min_fill_qty cannot exceed display_quantity".to_string());
+    }
+
+    let priority_fee = body.priority_fee.unwrap_or(0.0);
+    if !(priority_fee.is_finite() && priority_fee >= 0.0) {
+        return Err("priority_fee cannot be negative".to_string());
+    }
+
+    Ok(LimitOrderParams {
+        side,
+        price,
+        quantity,
+        time_in_force,
+        display_quantity,
+        hidden,
+        min_fill_qty,
+        expires_at: body.expires_at,
+        trade_through_protected: body.trade_through_protection.unwrap_or(false),
+        priority_fee,
+        client_order_id: body.client_order_id.clone(),
+    })
+}
+
+#[post("/limit", wrap = "from_fn(trade_scope)")]
 pub async fn create_limit_order(
     req: HttpRequest,
     state: web::Data<AppState>,
     body: web::Json<LimitOrderRequest>,
 ) -> Result<impl Responder, ApiError> {
+    // Stamped before auth and body parsing so in-process queueing can't skew time priority
+    let received_at = ReceivedAt::of(&req);
+
     // Extract user_id from request extensions (added by JWT middleware)
     let user_id = req.extensions().get::<Uuid>().copied()
         .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+    let source = req.extensions().get::<OrderSource>().copied().unwrap_or_default();
+
+    let placed = submit_limit_order(
+        &state,
+        user_id,
+        source,
+        &body,
+        received_at,
+        debug_timings(&req),
+    )
+    .await?;
+    Ok(ApiResponse::ok(placed))
+}
+
+/// Validate a limit order and place it, answering with the placement response.
+/// Shared by the REST endpoint and the trading WebSocket.
+pub(crate) async fn submit_limit_order(
+    state: &AppState,
+    user_id: Uuid,
+    source: OrderSource,
+    body: &LimitOrderRequest,
+    received_at: DateTime<Utc>,
+    debug_timings: bool,
+) -> Result<serde_json::Value, ApiError> {
+    let params = parse_limit_order(body).map_err(ApiError::BadRequest)?;
+
+    // Create oneshot channel for response
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command to orderbook engine and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::PlaceLimitOrder {
+        user_id,
+        side: params.side,
+        price: params.price,
+        quantity: params.quantity,
+        time_in_force: params.time_in_force,
+        display_quantity: params.display_quantity,
+        hidden: params.hidden,
+        min_fill_qty: params.min_fill_qty,
+        expires_at: params.expires_at,
+        peg: None,
+        trade_through_protected: params.trade_through_protected,
+        priority_fee: params.priority_fee,
+        received_at,
+        source,
+        client_order_id: params.client_order_id,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::OrderPlaced { order_id, trades, status, timings } => {
+            Ok(order_placed_json(
+                debug_timings,
+                order_id,
+                trades,
+                status,
+                timings,
+                received_at,
+                &body.client_order_id,
+            ))
+        }
+        OrderBookResponse::Error { message } => {
+            Err(ApiError::BadRequest(message))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[post("/batch", wrap = "from_fn(trade_scope)")]
+pub async fn create_order_batch(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<BatchOrderRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Stamped before auth and body parsing so in-process queueing can't skew time priority
+    let received_at = ReceivedAt::of(&req);
+
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+    let source = req.extensions().get::<OrderSource>().copied().unwrap_or_default();
+
+    if body.orders.is_empty() || body.orders.len() > MAX_BATCH_ORDERS {
+        return Err(ApiError::BadRequest(format!(
+            "A batch holds between 1 and {} orders",
+            MAX_BATCH_ORDERS
+        )));
+    }
+
+    // Invalid orders are answered here; the rest go to the engine together
+    let parsed: Vec<Result<LimitOrderParams, String>> =
+        body.orders.iter().map(parse_limit_order).collect();
+    let orders: Vec<LimitOrderParams> =
+        parsed.iter().filter_map(|p| p.as_ref().ok().cloned()).collect();
+
+    let mut placed = if orders.is_empty() {
+        Vec::new()
+    } else {
+        // Create oneshot channel
+        let (response_tx, response_rx) = oneshot::channel();
+
+        // Send command and wait for response
+        let deadline = state.deadline();
+        let command = OrderBookCommand::PlaceOrderBatch {
+            user_id,
+            orders,
+            received_at,
+            source,
+            response_tx,
+        };
+        match state.dispatch(command, response_rx, deadline).await? {
+            OrderBookResponse::BatchPlaced { results } => results,
+            _ => {
+                return Err(ApiError::InternalError(
+                    "Unexpected response from orderbook".to_string(),
+                ))
+            }
+        }
+    }
+    .into_iter();
+
+    // Handle response, one result per requested order in request order
+    let results: Vec<serde_json::Value> = parsed
+        .into_iter()
+        .zip(&body.orders)
+        .map(|(parsed, order)| {
+            let response = match parsed {
+                Ok(_) => placed.next(),
+                Err(message) => Some(OrderBookResponse::Error { message }),
+            };
+            match response {
+                Some(OrderBookResponse::OrderPlaced { order_id, trades, status, timings }) => {
+                    order_placed_json(
+                        debug_timings(&req),
+                        order_id,
+                        trades,
+                        status,
+                        timings,
+                        received_at,
+                        &order.client_order_id,
+                    )
+                }
+                Some(OrderBookResponse::Error { message }) => serde_json::json!({
+                    "error": message,
+                    "client_order_id": order.client_order_id,
+                }),
+                _ => serde_json::json!({
+                    "error": "Unexpected response from orderbook",
+                    "client_order_id": order.client_order_id,
+                }),
+            }
+        })
+        .collect();
+
+    Ok(ApiResponse::ok(serde_json::json!({ "results": results })))
+}
+
+#[post("/pegged", wrap = "from_fn(trade_scope)")]
+pub async fn create_pegged_order(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<PeggedOrderRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Stamped before auth and body parsing so in-process queueing can't skew time priority
+    let received_at = ReceivedAt::of(&req);
+
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+    let source = req.extensions().get::<OrderSource>().copied().unwrap_or_default();
 
     // Parse side
     let side = match body.side.to_lowercase().as_str() {
@@ -43,33 +439,54 @@ pub async fn create_limit_order(
         _ => return Err(ApiError::BadRequest("Invalid side, use 'buy' or 'sell'".to_string())),
     };
 
-    // Create oneshot channel for response
+    // Parse peg
+    let reference = body.peg.parse::<PegReference>().map_err(ApiError::BadRequest)?;
+    let offset = body.offset.unwrap_or(0.0);
+    if !offset.is_finite() {
+        return Err(ApiError::BadRequest("offset must be a number".to_string()));
+    }
+    let limit = body
+        .limit_price
+        .map(|limit| positive_price("limit_price", limit))
+        .transpose()
+        .map_err(ApiError::BadRequest)?;
+    let quantity = positive_quantity("quantity", body.quantity).map_err(ApiError::BadRequest)?;
+    let peg = Peg {
+        reference,
+        offset: (offset * 10f64.powi(Price::DECIMALS as i32)).round() as i64,
+        limit,
+    };
+
+    // Create oneshot channel
     let (response_tx, response_rx) = oneshot::channel();
 
-    // Send command to orderbook engine
-    state.orderbook_tx.send(OrderBookCommand::PlaceLimitOrder {
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::PlacePeggedOrder {
         user_id,
         side,
-        price: Price::from_f64(body.price),
-        quantity: Quantity::from_f64(body.quantity),
+        quantity,
+        peg,
+        expires_at: body.expires_at,
+        received_at,
+        source,
+        client_order_id: body.client_order_id.clone(),
         response_tx,
-    })
-    .await
-    .map_err(|_| ApiError::InternalError("Failed to send command to orderbook".to_string()))?;
-
-    // Wait for response
-    let response = response_rx.await
-        .map_err(|_| ApiError::InternalError("Failed to receive response from orderbook".to_string()))?;
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
 
     // Handle response
     match response {
-        OrderBookResponse::OrderPlaced { order_id, trades, status } => {
-            Ok(HttpResponse::Ok().json(serde_json::json!({
-                "order_id": order_id.to_string(),
-                "status": status,
-                "trades_count": trades.len(),
-                "trades": trades,
-            })))
+        OrderBookResponse::OrderPlaced { order_id, trades, status, timings } => {
+            Ok(ApiResponse::ok(order_placed_json(
+                debug_timings(&req),
+                order_id,
+                trades,
+                status,
+                timings,
+                received_at,
+                &body.client_order_id,
+            )))
         }
         OrderBookResponse::Error { message } => {
             Err(ApiError::BadRequest(message))
@@ -78,16 +495,42 @@ pub async fn create_limit_order(
     }
 }
 
-#[post("/market")]
+#[post("/market", wrap = "from_fn(trade_scope)")]
 pub async fn create_market_order(
     req: HttpRequest,
     state: web::Data<AppState>,
     body: web::Json<MarketOrderRequest>,
 ) -> Result<impl Responder, ApiError> {
+    // Stamped before auth and body parsing so in-process queueing can't skew time priority
+    let received_at = ReceivedAt::of(&req);
+
     // Extract user_id from JWT
     let user_id = req.extensions().get::<Uuid>().copied()
         .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+    let source = req.extensions().get::<OrderSource>().copied().unwrap_or_default();
 
+    let placed = submit_market_order(
+        &state,
+        user_id,
+        source,
+        &body,
+        received_at,
+        debug_timings(&req),
+    )
+    .await?;
+    Ok(ApiResponse::ok(placed))
+}
+
+/// Validate a market order and place it, answering with the placement response.
+/// Shared by the REST endpoint and the trading WebSocket.
+pub(crate) async fn submit_market_order(
+    state: &AppState,
+    user_id: Uuid,
+    source: OrderSource,
+    body: &MarketOrderRequest,
+    received_at: DateTime<Utc>,
+    debug_timings: bool,
+) -> Result<serde_json::Value, ApiError> {
     // Parse side
     let side = match body.side.to_lowercase().as_str() {
         "buy" => OrderSide::Buy,
@@ -95,32 +538,112 @@ pub async fn create_market_order(
         _ => return Err(ApiError::BadRequest("Invalid side, use 'buy' or 'sell'".to_string())),
     };
 
+    // Parse slippage guard; at most one way of bounding the sweep
+    let slippage = match (body.max_slippage_bps, body.limit_price) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::BadRequest(
+                "Use either max_slippage_bps or limit_price, not both".to_string(),
+            ))
+        }
+        (Some(bps), None) => Some(SlippageGuard::MaxBps(bps)),
+        (None, Some(limit)) => Some(SlippageGuard::LimitPrice(
+            positive_price("limit_price", limit).map_err(ApiError::BadRequest)?,
+        )),
+        (None, None) => None,
+    };
+    let quantity = positive_quantity("quantity", body.quantity).map_err(ApiError::BadRequest)?;
+
     // Create oneshot channel
     let (response_tx, response_rx) = oneshot::channel();
 
-    // Send command
-    state.orderbook_tx.send(OrderBookCommand::PlaceMarketOrder {
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::PlaceMarketOrder {
         user_id,
         side,
-        quantity: Quantity::from_f64(body.quantity),
+        quantity,
+        slippage,
+        received_at,
+        source,
+        client_order_id: body.client_order_id.clone(),
         response_tx,
-    })
-    .await
-    .map_err(|_| ApiError::InternalError("Failed to send command to orderbook".to_string()))?;
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::OrderPlaced { order_id, trades, status, timings } => {
+            Ok(order_placed_json(
+                debug_timings,
+                order_id,
+                trades,
+                status,
+                timings,
+                received_at,
+                &body.client_order_id,
+            ))
+        }
+        OrderBookResponse::Error { message } => {
+            Err(ApiError::BadRequest(message))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
 
-    // Wait for response
-    let response = response_rx.await
-        .map_err(|_| ApiError::InternalError("Failed to receive response from orderbook".to_string()))?;
+#[post("/stop", wrap = "from_fn(trade_scope)")]
+pub async fn create_stop_order(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<StopOrderRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Stamped before auth and body parsing so in-process queueing can't skew time priority
+    let received_at = ReceivedAt::of(&req);
+
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+    let source = req.extensions().get::<OrderSource>().copied().unwrap_or_default();
+
+    // Parse side
+    let side = match body.side.to_lowercase().as_str() {
+        "buy" => OrderSide::Buy,
+        "sell" => OrderSide::Sell,
+        _ => return Err(ApiError::BadRequest("Invalid side, use 'buy' or 'sell'".to_string())),
+    };
+
+    let stop_price = positive_price("stop_price", body.stop_price).map_err(ApiError::BadRequest)?;
+    let quantity = positive_quantity("quantity", body.quantity).map_err(ApiError::BadRequest)?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::PlaceStopOrder {
+        user_id,
+        side,
+        quantity,
+        stop_price,
+        trigger_index: body.trigger_index.clone(),
+        received_at,
+        source,
+        client_order_id: body.client_order_id.clone(),
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
 
     // Handle response
     match response {
-        OrderBookResponse::OrderPlaced { order_id, trades, status } => {
-            Ok(HttpResponse::Ok().json(serde_json::json!({
-                "order_id": order_id.to_string(),
-                "status": status,
-                "trades_count": trades.len(),
-                "trades": trades,
-            })))
+        OrderBookResponse::OrderPlaced { order_id, trades, status, timings } => {
+            Ok(ApiResponse::ok(order_placed_json(
+                debug_timings(&req),
+                order_id,
+                trades,
+                status,
+                timings,
+                received_at,
+                &body.client_order_id,
+            )))
         }
         OrderBookResponse::Error { message } => {
             Err(ApiError::BadRequest(message))
@@ -129,47 +652,443 @@ pub async fn create_market_order(
     }
 }
 
-#[delete("/cancel")]
+#[delete("/cancel", wrap = "from_fn(trade_scope)")]
 pub async fn cancel_order(
     req: HttpRequest,
     state: web::Data<AppState>,
     body: web::Json<CancelOrderRequest>,
 ) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let cancelled = submit_cancel(&state, user_id, &body).await?;
+    Ok(ApiResponse::ok(cancelled))
+}
+
+/// Cancel the order named by id or client ID. Shared by the REST endpoint and
+/// the trading WebSocket.
+pub(crate) async fn submit_cancel(
+    state: &AppState,
+    user_id: Uuid,
+    body: &CancelOrderRequest,
+) -> Result<serde_json::Value, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Exactly one of order_id and client_order_id names the order
+    let command = match (&body.order_id, &body.client_order_id) {
+        (Some(order_id), None) => {
+            let order_id = Uuid::parse_str(order_id)
+                .map_err(|_| ApiError::BadRequest("Invalid order_id format".to_string()))?;
+            OrderBookCommand::CancelOrder {
+                user_id,
+                order_id,
+                response_tx,
+            }
+        }
+        (None, Some(client_order_id)) => OrderBookCommand::CancelOrderByClientId {
+            user_id,
+            client_order_id: client_order_id.clone(),
+            response_tx,
+        },
+        _ => {
+            return Err(ApiError::BadRequest(
+                "Give either order_id or client_order_id".to_string(),
+            ))
+        }
+    };
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::OrderCancelled { order_id, success } => {
+            Ok(serde_json::json!({
+                "order_id": order_id.to_string(),
+                "cancelled": success,
+            }))
+        }
+        OrderBookResponse::Error { message } => {
+            Err(ApiError::BadRequest(message))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+/// Cancel every resting order of the caller's that matches all given filters
+#[delete("/cancel-all", wrap = "from_fn(trade_scope)")]
+pub async fn mass_cancel(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<MassCancelRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Parse filters
+    let side = match body.side.as_deref().map(str::to_lowercase).as_deref() {
+        None => None,
+        Some("buy") => Some(OrderSide::Buy),
+        Some("sell") => Some(OrderSide::Sell),
+        Some(_) => {
+            return Err(ApiError::BadRequest("Invalid side, use 'buy' or 'sell'".to_string()))
+        }
+    };
+    if body.min_price.zip(body.max_price).is_some_and(|(min, max)| min > max) {
+        return Err(ApiError::BadRequest("min_price must not exceed max_price".to_string()));
+    }
+    let price_bound = |field: &str, value: Option<f64>| {
+        value
+            .map(Price::try_from_f64)
+            .transpose()
+            .map_err(|e| ApiError::BadRequest(format!("{} is {}", field, e)))
+    };
+    let filter = OrderFilter {
+        side,
+        min_price: price_bound("min_price", body.min_price)?,
+        max_price: price_bound("max_price", body.max_price)?,
+        tag: body.tag.clone(),
+    };
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::MassCancel {
+        user_id,
+        filter,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::OrdersCancelled { order_ids } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "cancelled": order_ids.len(),
+                "order_ids": order_ids,
+            })))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+/// Dead man's switch: unless called again within `timeout_ms`, every resting
+/// order of the caller's is cancelled. Each call replaces the previous deadline.
+#[post("/cancel-all-after", wrap = "from_fn(trade_scope)")]
+pub async fn cancel_all_after(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<CancelAllAfterRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Validate timeout
+    let timeout_range = MIN_CANCEL_AFTER_MS..=MAX_CANCEL_AFTER_MS;
+    if body.timeout_ms != 0 && !timeout_range.contains(&body.timeout_ms) {
+        return Err(ApiError::BadRequest(format!(
+            "timeout_ms must be 0 to disarm, or between {} and {}",
+            MIN_CANCEL_AFTER_MS, MAX_CANCEL_AFTER_MS
+        )));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::CancelAllAfter {
+        user_id,
+        timeout: Duration::from_millis(body.timeout_ms),
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::CancelAllAfterSet { cancel_at } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "armed": cancel_at.is_some(),
+                "cancel_at": cancel_at,
+            })))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[patch("/{order_id}", wrap = "from_fn(trade_scope)")]
+pub async fn amend_order(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<AmendOrderRequest>,
+) -> Result<impl Responder, ApiError> {
+    // A re-priced order queues by the time the amendment arrived
+    let received_at = ReceivedAt::of(&req);
+
     // Extract user_id from JWT
     let user_id = req.extensions().get::<Uuid>().copied()
         .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
 
     // Parse order_id
-    let order_id = Uuid::parse_str(&body.order_id)
+    let order_id = Uuid::parse_str(&path)
         .map_err(|_| ApiError::BadRequest("Invalid order_id format".to_string()))?;
 
+    let amended = submit_amendment(
+        &state,
+        user_id,
+        order_id,
+        &body,
+        received_at,
+        debug_timings(&req),
+    )
+    .await?;
+    Ok(ApiResponse::ok(amended))
+}
+
+/// Validate an amendment and apply it, answering with the placement response.
+/// Shared by the REST endpoint and the trading WebSocket.
+pub(crate) async fn submit_amendment(
+    state: &AppState,
+    user_id: Uuid,
+    order_id: Uuid,
+    body: &AmendOrderRequest,
+    received_at: DateTime<Utc>,
+    debug_timings: bool,
+) -> Result<serde_json::Value, ApiError> {
+    if body.price.is_none() && body.quantity.is_none() {
+        return Err(ApiError::BadRequest("Give a new price, quantity or both".to_string()));
+    }
+    let price = body
+        .price
+        .map(|price| positive_price("price", price))
+        .transpose()
+        .map_err(ApiError::BadRequest)?;
+    let quantity = body
+        .quantity
+        .map(|quantity| positive_quantity("quantity", quantity))
+        .transpose()
+        .map_err(ApiError::BadRequest)?;
+
     // Create oneshot channel
     let (response_tx, response_rx) = oneshot::channel();
 
-    // Send command
-    state.orderbook_tx.send(OrderBookCommand::CancelOrder {
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::AmendOrder {
         user_id,
         order_id,
+        price,
+        quantity,
+        received_at,
         response_tx,
-    })
-    .await
-    .map_err(|_| ApiError::InternalError("Failed to send command to orderbook".to_string()))?;
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::OrderPlaced { order_id, trades, status, timings } => {
+            Ok(order_placed_json(
+                debug_timings,
+                order_id,
+                trades,
+                status,
+                timings,
+                received_at,
+                &None,
+            ))
+        }
+        OrderBookResponse::Error { message } => {
+            Err(ApiError::BadRequest(message))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[get("/history", wrap = "from_fn(read_scope)")]
+pub async fn get_order_history(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<OrderHistoryQuery>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_HISTORY_LIMIT);
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
 
-    // Wait for response
-    let response = response_rx.await
-        .map_err(|_| ApiError::InternalError("Failed to receive response from orderbook".to_string()))?;
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetOrderHistory {
+        user_id,
+        limit,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
 
     // Handle response
     match response {
-        OrderBookResponse::OrderCancelled { order_id, success } => {
-            Ok(HttpResponse::Ok().json(serde_json::json!({
+        OrderBookResponse::OrderHistory { orders } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "orders": orders,
+            })))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+/// Look up one of the caller's orders by the client order ID it was placed with
+#[get("/by-client-id/{client_order_id}", wrap = "from_fn(read_scope)")]
+pub async fn get_order_by_client_id(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetOrderByClientId {
+        user_id,
+        client_order_id: path.into_inner(),
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::Order { order } => Ok(ApiResponse::ok(order)),
+        OrderBookResponse::Error { message } => Err(ApiError::NotFound(message)),
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[get("/{order_id}/fills", wrap = "from_fn(read_scope)")]
+pub async fn get_order_fills(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<OrderFillsQuery>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Parse order_id
+    let order_id = Uuid::parse_str(&path.into_inner())
+        .map_err(|_| ApiError::BadRequest("Invalid order_id format".to_string()))?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetOrderFills {
+        user_id,
+        order_id,
+        after: query.after.unwrap_or(0),
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::OrderFills { order_id, last_fill_seq, fills } => {
+            Ok(ApiResponse::ok(serde_json::json!({
                 "order_id": order_id.to_string(),
-                "cancelled": success,
+                "last_fill_seq": last_fill_seq,
+                "fills": fills,
             })))
         }
         OrderBookResponse::Error { message } => {
-            Err(ApiError::BadRequest(message))
+            Err(ApiError::NotFound(message))
         }
         _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::XorShift;
+
+    fn limit_order(price: f64, quantity: f64) -> LimitOrderRequest {
+        LimitOrderRequest {
+            side: "buy".to_string(),
+            price,
+            quantity,
+            time_in_force: None,
+            display_quantity: Some(quantity / 2.0),
+            hidden: None,
+            min_fill_qty: None,
+            expires_at: None,
+            trade_through_protection: None,
+            priority_fee: None,
+            client_order_id: None,
+        }
+    }
+
+    #[test]
+    fn test_out_of_range_numbers_are_refused_with_the_field() {
+        let refused =
+            |price, quantity| parse_limit_order(&limit_order(price, quantity)).unwrap_err();
+        assert_eq!(refused(-100.0, 1.0), "price must be positive");
+        assert_eq!(refused(1e-9, 1.0), "price must be positive");
+        assert_eq!(refused(1e15, 1.0), "price is too large");
+        assert_eq!(refused(100.0, 0.0), "quantity must be positive");
+        assert_eq!(refused(100.0, 1e12), "quantity is too large");
+        assert_eq!(refused(100.0, f64::NAN), "quantity is not a number");
+
+        // Smaller than the smallest quantity, so it rounds to nothing
+        let mut order = limit_order(100.0, 1.0);
+        order.display_quantity = Some(1e-9);
+        assert_eq!(
+            parse_limit_order(&order).unwrap_err(),
+            "display_quantity must be positive"
+        );
+    }
+
+    #[test]
+    fn test_fuzzed_orders_reach_the_engine_only_in_range() {
+        let mut rng = XorShift(0x1234_5678_9abc_def1);
+        let mut next = || rng.next_u64();
+        // Mix raw bit patterns with plausible magnitudes, so both sides get exercised
+        let mut number = || match next() % 3 {
+            0 => f64::from_bits(next()),
+            1 => (next() as i64) as f64 / 1e6,
+            _ => (next() % 1_000_000_000) as f64 / 1e4,
+        };
+        let mut accepted = 0;
+        for _ in 0..50_000 {
+            let (price, quantity) = (number(), number());
+            if let Ok(params) = parse_limit_order(&limit_order(price, quantity)) {
+                assert!(params.price.raw() > 0 && !params.quantity.is_zero());
+                assert!((params.price.to_f64() - price).abs() <= 0.5e-6 + price * 1e-15);
+                assert!((params.quantity.to_f64() - quantity).abs() <= 0.5e-8 + quantity * 1e-15);
+                assert!(params
+                    .display_quantity
+                    .is_some_and(|display| display <= params.quantity));
+                accepted += 1;
+            }
+        }
+        assert!(accepted > 1_000);
+    }
+}
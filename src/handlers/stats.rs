@@ -0,0 +1,300 @@
+use actix_web::{get, web, Responder};
+use chrono::{Duration, NaiveDate, Utc};
+use serde::Deserialize;
+use tokio::sync::oneshot;
+
+use crate::engine::{FEED_INTERVALS_KEPT, MAX_AVERAGE_WINDOW};
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::orderbook::{DepthTotals, DepthWindow};
+use crate::state::AppState;
+use crate::types::DEFAULT_MARKET;
+use crate::utils::error::ApiError;
+use crate::utils::response::ApiResponse;
+
+/// Longest date range a single daily stats request may cover
+const MAX_STATS_RANGE_DAYS: i64 = 366;
+
+#[derive(Debug, Deserialize)]
+pub struct DailyStatsQuery {
+    pub market: Option<String>,
+    pub from: Option<NaiveDate>, // YYYY-MM-DD, defaults to 30 days ago
+    pub to: Option<NaiveDate>,   // YYYY-MM-DD, defaults to today
+}
+
+#[get("/stats/daily")]
+pub async fn get_daily_stats(
+    state: web::Data<AppState>,
+    query: web::Query<DailyStatsQuery>,
+) -> Result<impl Responder, ApiError> {
+    let market = query.market.clone().unwrap_or_else(|| DEFAULT_MARKET.to_string());
+    if market != DEFAULT_MARKET {
+        return Err(ApiError::NotFound(format!("Unknown market '{}'", market)));
+    }
+
+    // Validate date range
+    let to = query.to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = query.from.unwrap_or(to - Duration::days(30));
+    if from > to {
+        return Err(ApiError::BadRequest("'from' must not be after 'to'".to_string()));
+    }
+    if (to - from).num_days() > MAX_STATS_RANGE_DAYS {
+        return Err(ApiError::BadRequest(format!(
+            "Date range may span at most {} days",
+            MAX_STATS_RANGE_DAYS
+        )));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetDailyStats {
+        market: market.clone(),
+        from,
+        to,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::DailyStats { stats } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "market": market,
+                "from": from,
+                "to": to,
+                "days": stats,
+            })))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PriceAveragesQuery {
+    pub window: Option<String>, // 30s, 15m, 1h or 1d; one hour if unset
+}
+
+/// Parse a window such as `90s`, `15m`, `4h` or `1d`
+fn parse_window(value: &str) -> Result<std::time::Duration, ApiError> {
+    let invalid = || {
+        ApiError::BadRequest(format!(
+            "Invalid window '{}', use a number followed by s, m, h or d",
+            value
+        ))
+    };
+    let split = value.char_indices().last().map_or(0, |(i, _)| i);
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| invalid())?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return Err(invalid()),
+    };
+    amount
+        .checked_mul(unit_secs)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+/// Volume- and time-weighted average trade prices over a trailing window, as
+/// benchmarks for judging execution quality
+#[get("/stats/vwap")]
+pub async fn get_price_averages(
+    state: web::Data<AppState>,
+    query: web::Query<PriceAveragesQuery>,
+) -> Result<impl Responder, ApiError> {
+    let window = parse_window(query.window.as_deref().unwrap_or("1h"))?;
+    if window.is_zero() || window > MAX_AVERAGE_WINDOW {
+        return Err(ApiError::BadRequest(format!(
+            "window must be between 1s and {}h",
+            MAX_AVERAGE_WINDOW.as_secs() / 3600
+        )));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetPriceAverages {
+        window,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::PriceAverages { averages } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "market": DEFAULT_MARKET,
+                "window_secs": window.as_secs(),
+                "from": averages.from,
+                "to": averages.to,
+                "trades": averages.trades,
+                "volume": averages.volume,
+                "vwap": averages.vwap,
+                "twap": averages.twap,
+            })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Most levels per side an imbalance may be measured over
+const MAX_IMBALANCE_LEVELS: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct ImbalanceQuery {
+    pub levels: Option<usize>, // Best displayed levels per side; 10 if neither is given
+    pub range: Option<f64>,    // Or levels within this percent of the mid
+}
+
+/// Bid versus ask displayed volume near the top of the book, for signals and
+/// monitoring: 1 when only bids are shown, -1 when only asks are
+#[get("/stats/imbalance")]
+pub async fn get_imbalance(
+    state: web::Data<AppState>,
+    query: web::Query<ImbalanceQuery>,
+) -> Result<impl Responder, ApiError> {
+    let window = match (query.levels, query.range) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::BadRequest(
+                "Give levels or range, not both".to_string(),
+            ))
+        }
+        (None, Some(pct)) if pct > 0.0 && pct < 100.0 => DepthWindow::Percent(pct),
+        (None, Some(_)) => {
+            return Err(ApiError::BadRequest(
+                "range must be a percentage above 0 and below 100".to_string(),
+            ))
+        }
+        (levels, None) => {
+            let levels = levels.unwrap_or(10);
+            if levels == 0 || levels > MAX_IMBALANCE_LEVELS {
+                return Err(ApiError::BadRequest(format!(
+                    "levels must be between 1 and {}",
+                    MAX_IMBALANCE_LEVELS
+                )));
+            }
+            DepthWindow::Levels(levels)
+        }
+    };
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetImbalance {
+        window,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::Imbalance {
+            mid_price,
+            bids,
+            asks,
+        } => {
+            let (levels, range) = match window {
+                DepthWindow::Levels(levels) => (Some(levels), None),
+                DepthWindow::Percent(pct) => (None, Some(pct)),
+            };
+            let side = |totals: &DepthTotals| {
+                serde_json::json!({
+                    "orders": totals.orders,
+                    "volume": totals.quantity.to_f64(),
+                    "notional": totals.notional,
+                })
+            };
+            Ok(ApiResponse::ok(serde_json::json!({
+                "market": DEFAULT_MARKET,
+                "levels": levels,
+                "range": range,
+                "mid_price": mid_price,
+                "bids": side(&bids),
+                "asks": side(&asks),
+                "imbalance": DepthTotals::volume_imbalance(&bids, &asks),
+                "notional_imbalance": DepthTotals::imbalance(&bids, &asks),
+            })))
+        }
+        _ => Err(ApiError::InternalError(
+            "Unexpected response from orderbook".to_string(),
+        )),
+    }
+}
+
+/// Traded volume per order channel (web, api-key, fix, algo, liquidation)
+#[get("/analytics/sources")]
+pub async fn get_source_volume(state: web::Data<AppState>) -> Result<impl Responder, ApiError> {
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetSourceVolume {
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::SourceVolume { stats } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "sources": stats,
+            })))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeedActivityQuery {
+    pub intervals: Option<usize>, // Most recent first; 15 by default, at most 60
+}
+
+/// Top-of-book changes, trades and order events per one-minute interval, for
+/// alerting on a feed that has gone quiet or is spiking
+#[get("/stats/feed-activity")]
+pub async fn get_feed_activity(
+    state: web::Data<AppState>,
+    query: web::Query<FeedActivityQuery>,
+) -> Result<impl Responder, ApiError> {
+    let intervals = query.intervals.unwrap_or(15);
+    if intervals == 0 || intervals > FEED_INTERVALS_KEPT {
+        return Err(ApiError::BadRequest(format!(
+            "intervals must be between 1 and {}",
+            FEED_INTERVALS_KEPT
+        )));
+    }
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetFeedActivity {
+        intervals,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::FeedActivity { activity } => Ok(ApiResponse::ok(activity)),
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
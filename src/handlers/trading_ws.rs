@@ -0,0 +1,278 @@
+use actix_web::middleware::from_fn;
+use actix_web::{get, web, HttpMessage, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::handlers::orders::{
+    submit_amendment, submit_cancel, submit_limit_order, submit_market_order, AmendOrderRequest,
+    CancelOrderRequest, LimitOrderRequest, MarketOrderRequest,
+};
+use crate::handlers::ws_session::{open_ws, WsAuth};
+use crate::state::AppState;
+use crate::types::OrderSource;
+use crate::utils::error::ApiError;
+use crate::utils::middleware::trade_scope;
+
+/// A request frame on the trading WebSocket, beside the client's `id`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TradingAction {
+    Place(LimitOrderRequest),        // Same body as POST /orders/limit
+    PlaceMarket(MarketOrderRequest), // Same body as POST /orders/market
+    Amend {
+        order_id: String,
+        price: Option<f64>,
+        quantity: Option<f64>,
+    },
+    Cancel(CancelOrderRequest), // Same body as DELETE /orders/cancel
+}
+
+/// Split a frame into its correlation ID and action. The ID is recovered even
+/// when the action is malformed, so the error can still be matched up.
+fn parse_frame(text: &str) -> Result<(Value, TradingAction), (Value, ApiError)> {
+    let frame: Value = serde_json::from_str(text).map_err(|e| {
+        (
+            Value::Null,
+            ApiError::BadRequest(format!("Invalid JSON: {}", e)),
+        )
+    })?;
+    let id = match frame.get("id") {
+        Some(id @ (Value::String(_) | Value::Number(_))) => id.clone(),
+        _ => {
+            return Err((
+                Value::Null,
+                ApiError::BadRequest("id must be a string or number".to_string()),
+            ))
+        }
+    };
+    match serde_json::from_value(frame) {
+        Ok(action) => Ok((id, action)),
+        Err(e) => Err((id, ApiError::BadRequest(e.to_string()))),
+    }
+}
+
+async fn run_action(
+    state: &AppState,
+    user_id: Uuid,
+    source: OrderSource,
+    action: TradingAction,
+    received_at: DateTime<Utc>,
+) -> Result<Value, ApiError> {
+    match action {
+        TradingAction::Place(order) => {
+            submit_limit_order(state, user_id, source, &order, received_at, false).await
+        }
+        TradingAction::PlaceMarket(order) => {
+            submit_market_order(state, user_id, source, &order, received_at, false).await
+        }
+        TradingAction::Amend {
+            order_id,
+            price,
+            quantity,
+        } => {
+            let order_id = Uuid::parse_str(&order_id)
+                .map_err(|_| ApiError::BadRequest("Invalid order_id format".to_string()))?;
+            let amendment = AmendOrderRequest { price, quantity };
+            submit_amendment(state, user_id, order_id, &amendment, received_at, false).await
+        }
+        TradingAction::Cancel(cancel) => submit_cancel(state, user_id, &cancel).await,
+    }
+}
+
+/// Answer one frame: the REST response body on success, otherwise the status
+/// and message the REST endpoint would have failed with
+async fn handle_frame(
+    state: &AppState,
+    user_id: Uuid,
+    source: OrderSource,
+    text: &str,
+    received_at: DateTime<Utc>,
+) -> Value {
+    let (id, result) = match parse_frame(text) {
+        Ok((id, action)) => {
+            let result = run_action(state, user_id, source, action, received_at).await;
+            (id, result)
+        }
+        Err((id, e)) => (id, Err(e)),
+    };
+    match result {
+        Ok(result) => serde_json::json!({ "id": id, "ok": true, "result": result }),
+        Err(e) => {
+            let (status, message) = e.status_and_message();
+            serde_json::json!({
+                "id": id,
+                "ok": false,
+                "status": status.as_u16(),
+                "error": message,
+            })
+        }
+    }
+}
+
+/// Place, amend and cancel orders over one authenticated connection instead of
+/// an HTTP request each. Frames are handled one at a time in arrival order, so
+/// a cancel always reaches the engine after the placement sent before it. The
+/// connection closes with code 1008 when the session token it opened with
+/// expires or its API key is revoked; no frame is acted on after that.
+#[get("/ws", wrap = "from_fn(trade_scope)")]
+pub async fn trading_ws(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    // Extract user_id from JWT; the connection trades as this user until it closes
+    let user_id = req
+        .extensions()
+        .get::<Uuid>()
+        .copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+    let source = req
+        .extensions()
+        .get::<OrderSource>()
+        .copied()
+        .unwrap_or_default();
+    let auth = WsAuth::from_request(&req)
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let (response, mut connection, mut messages) = open_ws(&req, body, &state, Some(user_id))?;
+
+    actix_web::rt::spawn(async move {
+        let mut heartbeat = connection.heartbeat();
+        loop {
+            let message = tokio::select! {
+                _ = heartbeat.tick() => {
+                    // Catches a key revoked while the connection is quiet
+                    if let Err(reason) = auth.check(Utc::now()).await {
+                        connection.close_unauthorized(reason).await;
+                        return;
+                    }
+                    if !connection.beat().await {
+                        return;
+                    }
+                    continue;
+                }
+                _ = auth.expired() => {
+                    connection.close_unauthorized("Session expired".to_string()).await;
+                    return;
+                }
+                message = messages.next() => match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(_)) | None => break,
+                },
+            };
+            // Stamped as the frame comes off the socket, before the key check
+            let received_at = Utc::now();
+            connection.heard();
+            let reply = match message {
+                Message::Text(text) => {
+                    if let Err(reason) = auth.check(Utc::now()).await {
+                        connection.close_unauthorized(reason).await;
+                        return;
+                    }
+                    handle_frame(&state, user_id, source, &text, received_at).await
+                }
+                Message::Binary(_) => serde_json::json!({
+                    "id": null,
+                    "ok": false,
+                    "status": 400,
+                    "error": "Send requests as text frames",
+                }),
+                Message::Ping(bytes) => {
+                    if !connection.pong(&bytes).await {
+                        return;
+                    }
+                    continue;
+                }
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            if !connection.send(&reply).await {
+                return;
+            }
+        }
+        connection.close().await;
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{event_channel, run_orderbook_engine, EngineConfig, EngineMetrics};
+    use crate::messages::OrderBookCommand;
+    use tokio::sync::{mpsc, oneshot};
+
+    #[tokio::test]
+    async fn test_frames_reuse_rest_validation_and_echo_ids() {
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            event_channel(),
+            EngineConfig::default(),
+        ));
+        let state = AppState::new(tx, metrics);
+        let user_id = Uuid::new_v4();
+        let (response_tx, response_rx) = oneshot::channel();
+        let command = OrderBookCommand::AddFunds {
+            user_id,
+            currency: "USD".to_string(),
+            amount: 10_000.0,
+            response_tx,
+        };
+        state
+            .dispatch(command, response_rx, state.deadline())
+            .await
+            .unwrap();
+
+        let reply = handle_frame(&state, user_id, OrderSource::Web, "not json", Utc::now()).await;
+        assert_eq!(reply["id"], Value::Null);
+        assert_eq!(reply["status"], 400);
+
+        let frame = r#"{"id": 1, "action": "place", "side": "up", "price": 10, "quantity": 1}"#;
+        let reply = handle_frame(&state, user_id, OrderSource::Web, frame, Utc::now()).await;
+        assert_eq!(reply["id"], 1);
+        assert_eq!(reply["ok"], false);
+        assert_eq!(reply["error"], "Invalid side, use 'buy' or 'sell'");
+
+        let frame = r#"{"id": "a", "action": "place", "side": "buy", "price": 10, "quantity": 1}"#;
+        let reply = handle_frame(&state, user_id, OrderSource::Web, frame, Utc::now()).await;
+        assert_eq!(reply["id"], "a");
+        assert_eq!(reply["ok"], true, "{}", reply);
+        let order_id = reply["result"]["order_id"].as_str().unwrap().to_string();
+
+        let frame = format!(
+            r#"{{"id": "b", "action": "amend", "order_id": "{}"}}"#,
+            order_id
+        );
+        let reply = handle_frame(&state, user_id, OrderSource::Web, &frame, Utc::now()).await;
+        assert_eq!(reply["error"], "Give a new price, quantity or both");
+
+        let frame = format!(
+            r#"{{"id": "c", "action": "cancel", "order_id": "{}"}}"#,
+            order_id
+        );
+        let reply = handle_frame(&state, user_id, OrderSource::Web, &frame, Utc::now()).await;
+        assert_eq!(reply["id"], "c");
+        assert_eq!(reply["result"]["cancelled"], true, "{}", reply);
+
+        let frame = r#"{"id": 2, "action": "place_market", "side": "buy", "quantity": -1}"#;
+        let reply = handle_frame(&state, user_id, OrderSource::Web, frame, Utc::now()).await;
+        assert_eq!(reply["error"], "quantity must be positive");
+
+        // Reaches the engine, which has nothing to sweep against
+        let frame = r#"{"id": 3, "action": "place_market", "side": "buy", "quantity": 1}"#;
+        let reply = handle_frame(&state, user_id, OrderSource::Web, frame, Utc::now()).await;
+        assert_eq!(reply["id"], 3);
+        assert_eq!(
+            reply["error"],
+            "Failed to place market order: Insufficient liquidity for market order"
+        );
+    }
+}
@@ -1,19 +1,115 @@
-use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header;
+use actix_web::middleware::from_fn;
+use actix_web::{get, post, put, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use futures_util::stream::{self, Stream, StreamExt};
 use serde::Deserialize;
+use std::io;
 use tokio::sync::oneshot;
 use uuid::Uuid;
 
+use crate::engine::MAX_SUMMARY_FILLS;
+use crate::handlers::auth::UserStore;
+use crate::handlers::funding::deposit_from_source;
 use crate::messages::{OrderBookCommand, OrderBookResponse};
 use crate::state::AppState;
+use crate::storage::HistoryQuery;
+use crate::types::{newest_first, LiquidityRole, OrderSide, UserTrade};
 use crate::utils::error::ApiError;
+use crate::utils::middleware::{read_scope, trade_scope};
+use crate::utils::response::{ApiResponse, Pagination};
+use crate::utils::DecimalSeparator;
+
+/// Longest date range a single statement may cover
+const MAX_STATEMENT_RANGE_DAYS: i64 = 366;
+
+/// Trades fetched from the store for each chunk of a statement
+const STATEMENT_PAGE_SIZE: usize = 500;
+
+/// Largest page the trade history endpoint will return
+const MAX_TRADES_PAGE: usize = 1000;
+
+/// Largest page the activity feed will return
+const MAX_ACTIVITY_PAGE: usize = 500;
+
+const STATEMENT_CSV_HEADER: [&str; 8] = [
+    "timestamp",
+    "order_id",
+    "trade_id",
+    "side",
+    "role",
+    "price",
+    "quantity",
+    "fee",
+];
 
 #[derive(Debug, Deserialize)]
 pub struct OnrampRequest {
     pub currency: String, // "USD" or "BTC"
     pub amount: f64,
+    pub funding_source_id: Option<String>, // Deposit from a linked source instead of the faucet
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PreferencesRequest {
+    pub display_currency: Option<String>, // null resets to USD
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InterestOptInRequest {
+    pub opted_in: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatementQuery {
+    pub from: Option<NaiveDate>, // YYYY-MM-DD, defaults to 30 days ago
+    pub to: Option<NaiveDate>,   // YYYY-MM-DD, defaults to today
+    pub decimal_separator: Option<String>, // CSV only: "." (default) or ","
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SummaryQuery {
+    pub fills: Option<usize>, // Recent fills to include, default 20
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserTradesQuery {
+    pub from: Option<DateTime<Utc>>, // RFC 3339, inclusive
+    pub to: Option<DateTime<Utc>>,   // RFC 3339, exclusive
+    pub limit: Option<usize>,
+    pub offset: Option<usize>, // Trades to skip, newest first
 }
 
-#[get("/balance")]
+#[derive(Debug, Deserialize)]
+pub struct ActivityQuery {
+    pub from: Option<DateTime<Utc>>, // RFC 3339, inclusive
+    pub to: Option<DateTime<Utc>>,   // RFC 3339, exclusive
+    pub limit: Option<usize>,
+    pub offset: Option<usize>, // Entries to skip, newest first
+}
+
+fn statement_csv_fields(fill: &UserTrade, separator: DecimalSeparator) -> [String; 8] {
+    let side = match fill.side {
+        OrderSide::Buy => "buy",
+        OrderSide::Sell => "sell",
+    };
+    let role = match fill.role {
+        LiquidityRole::Maker => "maker",
+        LiquidityRole::Taker => "taker",
+    };
+    [
+        fill.timestamp.to_rfc3339(),
+        fill.order_id.to_string(),
+        fill.trade_id.to_string(),
+        side.to_string(),
+        role.to_string(),
+        separator.localize(&fill.price.to_string()),
+        separator.localize(&fill.quantity.to_string()),
+        separator.localize(&fill.fee.to_string()),
+    ]
+}
+
+#[get("/balance", wrap = "from_fn(read_scope)")]
 pub async fn get_balance(
     req: HttpRequest,
     state: web::Data<AppState>,
@@ -25,22 +121,19 @@ pub async fn get_balance(
     // Create oneshot channel
     let (response_tx, response_rx) = oneshot::channel();
 
-    // Send command
-    state.orderbook_tx.send(OrderBookCommand::GetUserBalance {
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetUserBalance {
         user_id,
+        deadline,
         response_tx,
-    })
-    .await
-    .map_err(|_| ApiError::InternalError("Failed to send command to orderbook".to_string()))?;
-
-    // Wait for response
-    let response = response_rx.await
-        .map_err(|_| ApiError::InternalError("Failed to receive response from orderbook".to_string()))?;
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
 
     // Handle response
     match response {
         OrderBookResponse::UserBalance { balance } => {
-            Ok(HttpResponse::Ok().json(serde_json::json!({
+            Ok(ApiResponse::ok(serde_json::json!({
                 "user_id": balance.user_id.to_string(),
                 "balances": balance.balances,
             })))
@@ -52,10 +145,46 @@ pub async fn get_balance(
     }
 }
 
-#[post("/onramp")]
+/// Balances, open orders, recent fills, position, fees and alerts in one
+/// call, for the first load of a trading UI
+#[get("/summary", wrap = "from_fn(read_scope)")]
+pub async fn get_account_summary(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<SummaryQuery>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let fills = query.fills.unwrap_or(20).min(MAX_SUMMARY_FILLS);
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetAccountSummary {
+        user_id,
+        fills,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::AccountSummary { summary } => Ok(ApiResponse::ok(summary)),
+        OrderBookResponse::Error { message } => Err(ApiError::NotFound(message)),
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[post("/onramp", wrap = "from_fn(trade_scope)")]
 pub async fn onramp(
     req: HttpRequest,
     state: web::Data<AppState>,
+    user_store: web::Data<UserStore>,
     body: web::Json<OnrampRequest>,
 ) -> Result<impl Responder, ApiError> {
     // Extract user_id from JWT
@@ -68,31 +197,46 @@ pub async fn onramp(
     }
 
     // Validate amount
-    if body.amount <= 0.0 {
+    if !(body.amount.is_finite() && body.amount > 0.0) {
         return Err(ApiError::BadRequest("Amount must be positive".to_string()));
     }
 
+    if let Some(source_id) = &body.funding_source_id {
+        return deposit_from_source(
+            &state,
+            &user_store,
+            user_id,
+            source_id,
+            &body.currency,
+            body.amount,
+        )
+        .await;
+    }
+
+    // Free funds are for test environments only
+    if !state.profile.faucet {
+        return Err(ApiError::NotFound(
+            "Onramp is not available in this environment".to_string(),
+        ));
+    }
+
     // Create oneshot channel
     let (response_tx, response_rx) = oneshot::channel();
 
-    // Send command
-    state.orderbook_tx.send(OrderBookCommand::AddFunds {
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::AddFunds {
         user_id,
         currency: body.currency.clone(),
         amount: body.amount,
         response_tx,
-    })
-    .await
-    .map_err(|_| ApiError::InternalError("Failed to send command to orderbook".to_string()))?;
-
-    // Wait for response
-    let response = response_rx.await
-        .map_err(|_| ApiError::InternalError("Failed to receive response from orderbook".to_string()))?;
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
 
     // Handle response
     match response {
         OrderBookResponse::FundsAdded { user_id, currency, new_balance } => {
-            Ok(HttpResponse::Ok().json(serde_json::json!({
+            Ok(ApiResponse::ok(serde_json::json!({
                 "user_id": user_id.to_string(),
                 "currency": currency,
                 "new_balance": new_balance,
@@ -101,3 +245,396 @@ pub async fn onramp(
         _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
     }
 }
+
+#[get("/execution-quality", wrap = "from_fn(read_scope)")]
+pub async fn get_execution_quality(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetExecutionQuality {
+        user_id,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::ExecutionQuality { stats } => {
+            Ok(ApiResponse::ok(serde_json::json!({
+                "user_id": user_id.to_string(),
+                "execution_quality": stats,
+            })))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+/// The user's fills over a date range: JSON by default, or a CSV download
+/// when the request sends `Accept: text/csv`
+#[get("/statement", wrap = "from_fn(read_scope)")]
+pub async fn get_statement(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<StatementQuery>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Validate date range
+    let to = query.to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = query.from.unwrap_or(to - Duration::days(30));
+    if from > to {
+        return Err(ApiError::BadRequest("'from' must not be after 'to'".to_string()));
+    }
+    if (to - from).num_days() > MAX_STATEMENT_RANGE_DAYS {
+        return Err(ApiError::BadRequest(format!(
+            "Date range may span at most {} days",
+            MAX_STATEMENT_RANGE_DAYS
+        )));
+    }
+    let separator: DecimalSeparator = match &query.decimal_separator {
+        Some(separator) => separator.parse().map_err(ApiError::BadRequest)?,
+        None => DecimalSeparator::default(),
+    };
+    let csv = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/csv"));
+
+    // Fills are read from the store a page at a time as the body is sent
+    let start = from.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc();
+    let end = start + Duration::days((to - from).num_days() + 1);
+    let pages = statement_pages(state, user_id, start, end);
+
+    if csv {
+        let heading = separator.csv_record(&STATEMENT_CSV_HEADER);
+        let rows = pages.map(move |page| {
+            let fills = page?;
+            let rows: String = fills
+                .iter()
+                .map(|fill| separator.csv_record(&statement_csv_fields(fill, separator)))
+                .collect();
+            Ok::<_, io::Error>(web::Bytes::from(rows))
+        });
+        return Ok(HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"statement-{}-{}.csv\"", from, to),
+            ))
+            .streaming(stream::once(async move { Ok(web::Bytes::from(heading)) }).chain(rows)));
+    }
+
+    let entries = pages.map(|page| {
+        page?
+            .iter()
+            .map(|fill| serde_json::to_string(fill).map_err(io::Error::other))
+            .collect()
+    });
+    let body = serde_json::json!({
+        "user_id": user_id.to_string(),
+        "from": from,
+        "to": to,
+        "entries": [],
+    });
+    Ok(ApiResponse::ok(body).streaming(&req, "entries", entries))
+}
+
+/// The user's fills from `start` up to `end`, newest first, one page of
+/// trades at a time until a page comes back short
+fn statement_pages(
+    state: web::Data<AppState>,
+    user_id: Uuid,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> impl Stream<Item = Result<Vec<UserTrade>, io::Error>> {
+    stream::unfold(Some(0), move |offset| {
+        let state = state.clone();
+        async move {
+            let query = HistoryQuery {
+                from: Some(start),
+                to: Some(end),
+                offset: offset?,
+                limit: STATEMENT_PAGE_SIZE,
+            };
+            let (response_tx, response_rx) = oneshot::channel();
+            let deadline = state.deadline();
+            let command = OrderBookCommand::GetUserTrades {
+                user_id,
+                query,
+                deadline,
+                response_tx,
+            };
+            let page = match state.dispatch(command, response_rx, deadline).await {
+                Ok(OrderBookResponse::UserTrades { fills, trades }) => Ok((fills, trades)),
+                Ok(OrderBookResponse::Error { message }) => Err(message),
+                Ok(_) => Err("Unexpected response from orderbook".to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            match page {
+                Ok((fills, trades)) => {
+                    let next = (trades == STATEMENT_PAGE_SIZE).then_some(query.offset + trades);
+                    Some((Ok(fills), next))
+                }
+                // Abort the transfer so the client sees an incomplete download
+                Err(message) => Some((Err(io::Error::other(message)), None)),
+            }
+        }
+    })
+}
+
+/// The user's fills, newest first, with the role they played and the fee
+/// they paid. Pages count trades, so a trade against oneself lists two fills.
+#[get("/trades", wrap = "from_fn(read_scope)")]
+pub async fn get_user_trades(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<UserTradesQuery>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Validate time range
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from >= to {
+            return Err(ApiError::BadRequest("'from' must be before 'to'".to_string()));
+        }
+    }
+    let history_query = HistoryQuery {
+        from: query.from,
+        to: query.to,
+        offset: query.offset.unwrap_or(0),
+        limit: query.limit.unwrap_or(100).clamp(1, MAX_TRADES_PAGE),
+    };
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetUserTrades {
+        user_id,
+        query: history_query,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::UserTrades { fills, trades } => {
+            // A full page may have more behind it
+            let next_offset = (trades == history_query.limit).then_some(history_query.offset + trades);
+            let pagination = Pagination::by_offset(
+                history_query.offset,
+                history_query.limit,
+                trades,
+                next_offset,
+            );
+            Ok(ApiResponse::ok(serde_json::json!({
+                "user_id": user_id.to_string(),
+                "trades": fills,
+                "next_offset": next_offset,
+            }))
+            .paginated(pagination))
+        }
+        OrderBookResponse::Error { message } => Err(ApiError::InternalError(message)),
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+/// Sign-ins, API key changes, order events, deposits and withdrawals in one
+/// feed, newest first
+#[get("/activity", wrap = "from_fn(read_scope)")]
+pub async fn get_activity(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<ActivityQuery>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Validate time range
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from >= to {
+            return Err(ApiError::BadRequest("'from' must be before 'to'".to_string()));
+        }
+    }
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(100).clamp(1, MAX_ACTIVITY_PAGE);
+    // Each source is asked for everything up to the end of the page, plus one
+    // entry to tell whether another page follows
+    let sources_query = HistoryQuery {
+        from: query.from,
+        to: query.to,
+        offset: 0,
+        limit: offset.saturating_add(limit + 1),
+    };
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetActivity {
+        user_id,
+        query: sources_query,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::Activity { mut entries } => {
+            entries.extend(
+                state
+                    .audit
+                    .for_user(user_id, &sources_query)
+                    .await
+                    .map_err(ApiError::InternalError)?,
+            );
+            newest_first(&mut entries);
+            let end = offset.saturating_add(limit);
+            let next_offset = (entries.len() > end).then_some(end);
+            let page: Vec<_> = entries.into_iter().skip(offset).take(limit).collect();
+            let pagination = Pagination::by_offset(offset, limit, page.len(), next_offset);
+            Ok(ApiResponse::ok(serde_json::json!({
+                "user_id": user_id.to_string(),
+                "activity": page,
+                "next_offset": next_offset,
+            }))
+            .paginated(pagination))
+        }
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+#[get("/preferences", wrap = "from_fn(read_scope)")]
+pub async fn get_preferences(
+    req: HttpRequest,
+    user_store: web::Data<UserStore>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let user = user_store
+        .find_by_id(user_id)
+        .await
+        .map_err(ApiError::InternalError)?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    Ok(ApiResponse::ok(serde_json::json!({
+        "display_currency": user.display_currency,
+    })))
+}
+
+#[put("/preferences", wrap = "from_fn(trade_scope)")]
+pub async fn update_preferences(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    user_store: web::Data<UserStore>,
+    body: web::Json<PreferencesRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Validate currency against the configured FX table
+    let display_currency = body.display_currency.as_ref().map(|c| c.to_uppercase());
+    if let Some(currency) = &display_currency {
+        if !state.fx_rates.supports(currency) {
+            return Err(ApiError::BadRequest(format!(
+                "Unsupported display currency '{}'",
+                currency
+            )));
+        }
+    }
+
+    let user = user_store
+        .update_user(user_id, |user| {
+            user.display_currency = display_currency.clone()
+        })
+        .await
+        .map_err(ApiError::InternalError)?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    Ok(ApiResponse::ok(serde_json::json!({
+        "display_currency": user.display_currency,
+    })))
+}
+
+/// Current rates (APR and APY at the user's balance) and interest earned so far
+#[get("/interest", wrap = "from_fn(read_scope)")]
+pub async fn get_interest_summary(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::GetInterestSummary {
+        user_id,
+        deadline,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::InterestSummary { summary } => Ok(ApiResponse::ok(summary)),
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
+
+/// Opt in to (or out of) daily interest on idle balances
+#[put("/interest", wrap = "from_fn(trade_scope)")]
+pub async fn set_interest_opt_in(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<InterestOptInRequest>,
+) -> Result<impl Responder, ApiError> {
+    // Extract user_id from JWT
+    let user_id = req.extensions().get::<Uuid>().copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    // Create oneshot channel
+    let (response_tx, response_rx) = oneshot::channel();
+
+    // Send command and wait for response
+    let deadline = state.deadline();
+    let command = OrderBookCommand::SetInterestOptIn {
+        user_id,
+        opted_in: body.opted_in,
+        response_tx,
+    };
+    let response = state.dispatch(command, response_rx, deadline).await?;
+
+    // Handle response
+    match response {
+        OrderBookResponse::InterestSummary { summary } => Ok(ApiResponse::ok(summary)),
+        OrderBookResponse::Error { message } => Err(ApiError::BadRequest(message)),
+        _ => Err(ApiError::InternalError("Unexpected response from orderbook".to_string())),
+    }
+}
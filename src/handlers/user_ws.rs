@@ -0,0 +1,214 @@
+use actix_web::middleware::from_fn;
+use actix_web::{get, web, HttpMessage, HttpRequest, HttpResponse};
+use actix_ws::Message;
+use chrono::Utc;
+use futures_util::StreamExt;
+use serde_json::Value;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::engine::{MarketEvent, MarketMessage};
+use crate::handlers::ws_session::{open_ws, WsAuth};
+use crate::state::AppState;
+use crate::utils::error::ApiError;
+use crate::utils::middleware::read_scope;
+
+/// The frame a feed message becomes for `user_id`, if it is one of theirs.
+/// Orders and fills are shaped as the REST order endpoints return them.
+fn user_frame(user_id: Uuid, message: &MarketMessage) -> Option<Value> {
+    if message.event.owner() != Some(user_id) {
+        return None;
+    }
+    let mut frame = match &message.event {
+        MarketEvent::Fill(fill) => serde_json::json!({ "channel": "fills", "fill": fill }),
+        MarketEvent::Order(order) => serde_json::json!({ "channel": "orders", "order": order }),
+        MarketEvent::Balance { balances, .. } => {
+            serde_json::json!({ "channel": "balances", "balances": balances })
+        }
+        MarketEvent::Activity { entry, .. } => {
+            serde_json::json!({ "channel": "activity", "activity": entry })
+        }
+        _ => return None,
+    };
+    frame["seq"] = serde_json::json!(message.seq);
+    frame["sent_at"] = serde_json::json!(message.sent_at);
+    Some(frame)
+}
+
+/// Order updates, fills and balance changes of the signed-in user, pushed as
+/// the engine makes them. Each command's fills come first, then the orders
+/// it changed in their latest state, then the balances it moved. Withdrawal
+/// address notices arrive on the `activity` channel as they are stored. Like the
+/// trading socket, it closes when its session expires or API key is revoked.
+#[get("", wrap = "from_fn(read_scope)")]
+pub async fn user_ws(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    // Extract user_id from JWT; the stream carries only this user's events
+    let user_id = req
+        .extensions()
+        .get::<Uuid>()
+        .copied()
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+    let auth = WsAuth::from_request(&req)
+        .ok_or_else(|| ApiError::Unauthorized("Not authenticated".to_string()))?;
+
+    let (response, mut connection, mut messages) = open_ws(&req, body, &state, Some(user_id))?;
+    let mut events = state.events.subscribe_user(user_id);
+
+    actix_web::rt::spawn(async move {
+        let mut heartbeat = connection.heartbeat();
+        loop {
+            let frame = tokio::select! {
+                _ = heartbeat.tick() => {
+                    if let Err(reason) = auth.check(Utc::now()).await {
+                        connection.close_unauthorized(reason).await;
+                        return;
+                    }
+                    if !connection.beat().await {
+                        return;
+                    }
+                    continue;
+                }
+                _ = auth.expired() => {
+                    connection.close_unauthorized("Session expired".to_string()).await;
+                    return;
+                }
+                message = messages.next() => {
+                    connection.heard();
+                    match message {
+                        Some(Ok(Message::Ping(bytes))) => {
+                            if !connection.pong(&bytes).await {
+                                return;
+                            }
+                            continue;
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        // The stream takes no requests
+                        Some(Ok(_)) => continue,
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(message) => match user_frame(user_id, &message) {
+                        Some(frame) => frame,
+                        None => continue,
+                    },
+                    // Fetch what was missed from the REST endpoints
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        serde_json::json!({ "event": "lagged", "missed": missed })
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            };
+            if !connection.send(&frame).await {
+                return;
+            }
+        }
+        connection.close().await;
+    });
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{event_channel, run_orderbook_engine, EngineConfig, EngineMetrics};
+    use crate::handlers::orders::{submit_limit_order, LimitOrderRequest};
+    use crate::messages::OrderBookCommand;
+    use crate::types::{Activity, ActivityEntry, OrderSource};
+    use chrono::Utc;
+    use tokio::sync::{mpsc, oneshot};
+
+    #[tokio::test]
+    async fn test_each_user_sees_their_own_orders_fills_and_balances() {
+        let (tx, rx) = mpsc::channel(16);
+        let metrics = std::sync::Arc::new(EngineMetrics::new());
+        let events = event_channel();
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut market = events.subscribe();
+        let mut feed = events.subscribe_user(maker);
+        let mut taker_feed = events.subscribe_user(taker);
+        tokio::spawn(run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            events,
+            EngineConfig::default(),
+        ));
+        let state = AppState::new(tx, metrics);
+
+        for (user_id, currency, amount) in [(maker, "BTC", 2.0), (taker, "USD", 1_000.0)] {
+            let (response_tx, response_rx) = oneshot::channel();
+            let command = OrderBookCommand::AddFunds {
+                user_id,
+                currency: currency.to_string(),
+                amount,
+                response_tx,
+            };
+            state
+                .dispatch(command, response_rx, state.deadline())
+                .await
+                .unwrap();
+        }
+        for (user_id, order) in [
+            (maker, r#"{"side": "sell", "price": 100, "quantity": 1}"#),
+            (taker, r#"{"side": "buy", "price": 100, "quantity": 0.4}"#),
+        ] {
+            let order: LimitOrderRequest = serde_json::from_str(order).unwrap();
+            submit_limit_order(&state, user_id, OrderSource::Web, &order, Utc::now(), false)
+                .await
+                .unwrap();
+        }
+
+        // Nobody reads another user's events off the public feed
+        while let Ok(message) = market.try_recv() {
+            assert!(message.event.is_public());
+        }
+        while let Ok(message) = taker_feed.try_recv() {
+            assert_eq!(message.event.owner(), Some(taker));
+        }
+        let mut maker_frames = Vec::new();
+        while let Ok(message) = feed.try_recv() {
+            assert!(user_frame(Uuid::new_v4(), &message).is_none());
+            maker_frames.extend(user_frame(maker, &message));
+        }
+        let channels: Vec<&str> = maker_frames
+            .iter()
+            .map(|frame| frame["channel"].as_str().unwrap())
+            .collect();
+        // Funding, resting the sell, then the partial fill against it
+        assert_eq!(
+            channels,
+            ["balances", "orders", "balances", "fills", "orders", "balances"]
+        );
+        assert_eq!(maker_frames[4]["order"]["status"], "PartiallyFilled");
+        assert_eq!(maker_frames[5]["balances"]["USD"], 40.0);
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_address_notices_reach_only_their_owner() {
+        let events = event_channel();
+        let (owner, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut feed = events.subscribe_user(owner);
+        let mut other_feed = events.subscribe_user(other);
+        let added = Activity::WithdrawalAddressAdded {
+            funding_source_id: Uuid::new_v4(),
+            address: "bc1qexample".to_string(),
+            usable_from: Utc::now(),
+        };
+        let entry = ActivityEntry::new(Utc::now(), added);
+        events.notify(MarketEvent::Activity {
+            user_id: owner,
+            entry,
+        });
+
+        let message = feed.try_recv().unwrap();
+        assert!(user_frame(other, &message).is_none());
+        let frame = user_frame(owner, &message).unwrap();
+        assert_eq!(frame["channel"], "activity");
+        assert_eq!(frame["activity"]["address"], "bc1qexample");
+        assert!(other_feed.try_recv().is_err());
+    }
+}
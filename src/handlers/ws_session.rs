@@ -0,0 +1,201 @@
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, MessageStream, Session};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use uuid::Uuid;
+
+use crate::state::{AppState, WsLimits, WsSlot};
+use crate::storage::ApiKeyStore;
+use crate::utils::auth::Credential;
+use crate::utils::error::ApiError;
+
+/// One open WebSocket with the server's half of the heartbeat, the idle
+/// timeout and send backpressure. It holds its connection slot until dropped.
+pub struct WsConnection {
+    session: Session,
+    limits: WsLimits,
+    last_heard: Instant,
+    _slot: WsSlot,
+}
+
+/// Upgrade the request, if the connection limits leave room for it. The
+/// public market feed passes no `user_id`.
+pub fn open_ws(
+    req: &HttpRequest,
+    body: web::Payload,
+    state: &AppState,
+    user_id: Option<Uuid>,
+) -> Result<(HttpResponse, WsConnection, MessageStream), ApiError> {
+    let slot = state
+        .websockets
+        .open(user_id, req.peer_addr().map(|addr| addr.ip()))
+        .map_err(ApiError::TooManyRequests)?;
+    let (response, session, messages) =
+        actix_ws::handle(req, body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let connection = WsConnection {
+        session,
+        limits: state.websockets.limits,
+        last_heard: Instant::now(),
+        _slot: slot,
+    };
+    Ok((response, connection, messages))
+}
+
+/// The credential an authenticated socket was opened with, checked again
+/// while it stays open. Authenticating once at the upgrade would let a
+/// connection trade on after its session expired or its API key was revoked.
+pub struct WsAuth {
+    credential: Credential,
+    api_keys: Option<web::Data<ApiKeyStore>>,
+}
+
+impl WsAuth {
+    /// The credential the request authenticated with, if it did
+    pub fn from_request(req: &HttpRequest) -> Option<Self> {
+        let credential = req.extensions().get::<Credential>().cloned()?;
+        let api_keys = req.app_data::<web::Data<ApiKeyStore>>().cloned();
+        Some(WsAuth {
+            credential,
+            api_keys,
+        })
+    }
+
+    /// Err with the reason to close the connection once the session has
+    /// expired or the API key is revoked. An API key that cannot be looked up
+    /// counts as revoked.
+    pub async fn check(&self, now: DateTime<Utc>) -> Result<(), String> {
+        match &self.credential {
+            Credential::Session { expires_at } if *expires_at <= now => {
+                Err("Session expired".to_string())
+            }
+            Credential::Session { .. } => Ok(()),
+            Credential::ApiKey { key_id } => {
+                let active = match &self.api_keys {
+                    Some(api_keys) => api_keys.is_active(key_id).await?,
+                    None => false,
+                };
+                if active {
+                    Ok(())
+                } else {
+                    Err("API key revoked".to_string())
+                }
+            }
+        }
+    }
+
+    /// Resolves when the session expires; never for an API key, which is
+    /// checked instead
+    pub async fn expired(&self) {
+        match self.credential {
+            Credential::Session { expires_at } => {
+                let left = (expires_at - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(left).await
+            }
+            Credential::ApiKey { .. } => std::future::pending().await,
+        }
+    }
+}
+
+impl WsConnection {
+    /// Ticks when the next heartbeat is due. Kept apart from the connection so
+    /// it can be awaited beside the message stream.
+    pub fn heartbeat(&self) -> Interval {
+        let period = self.limits.heartbeat_interval;
+        let mut heartbeat = tokio::time::interval_at(Instant::now() + period, period);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        heartbeat
+    }
+
+    /// Note that the client was heard from. Any frame counts, pongs included.
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+    }
+
+    /// Ping the client, or close the connection if it has been silent past
+    /// the idle timeout. False once the connection is finished.
+    pub async fn beat(&mut self) -> bool {
+        if self.last_heard.elapsed() >= self.limits.idle_timeout {
+            let reason = CloseReason {
+                code: CloseCode::Away,
+                description: Some("Idle timeout".to_string()),
+            };
+            let session = self.session.clone();
+            let _ =
+                tokio::time::timeout(self.limits.send_timeout, session.close(Some(reason))).await;
+            return false;
+        }
+        let sent = tokio::time::timeout(self.limits.send_timeout, self.session.ping(b"")).await;
+        matches!(sent, Ok(Ok(())))
+    }
+
+    pub async fn pong(&mut self, bytes: &[u8]) -> bool {
+        let sent = tokio::time::timeout(self.limits.send_timeout, self.session.pong(bytes)).await;
+        matches!(sent, Ok(Ok(())))
+    }
+
+    /// Send a frame. A client that leaves it untaken past the send timeout is
+    /// too slow to keep: false, and the connection should be dropped.
+    pub async fn send(&mut self, frame: &Value) -> bool {
+        let sent = tokio::time::timeout(
+            self.limits.send_timeout,
+            self.session.text(frame.to_string()),
+        )
+        .await;
+        matches!(sent, Ok(Ok(())))
+    }
+
+    /// Close the connection normally
+    pub async fn close(self) {
+        let _ = tokio::time::timeout(self.limits.send_timeout, self.session.close(None)).await;
+    }
+
+    /// Close the connection because its credential no longer holds, with code
+    /// 1008 and the reason
+    pub async fn close_unauthorized(self, reason: String) {
+        let reason = CloseReason {
+            code: CloseCode::Policy,
+            description: Some(reason),
+        };
+        let _ =
+            tokio::time::timeout(self.limits.send_timeout, self.session.close(Some(reason))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ApiKeyScope;
+    use crate::utils::SecretBox;
+
+    #[tokio::test]
+    async fn test_sockets_lose_their_credential_on_expiry_or_revocation() {
+        let now = Utc::now();
+        let session = WsAuth {
+            credential: Credential::Session {
+                expires_at: now + chrono::Duration::minutes(5),
+            },
+            api_keys: None,
+        };
+        assert_eq!(session.check(now).await, Ok(()));
+        let expired = session.check(now + chrono::Duration::minutes(5)).await;
+        assert_eq!(expired, Err("Session expired".to_string()));
+
+        let api_keys = web::Data::new(ApiKeyStore::new());
+        let sealer = SecretBox::new("k1", b"test passphrase");
+        let user_id = Uuid::new_v4();
+        let (key, _) = api_keys
+            .create(&sealer, user_id, None, vec![ApiKeyScope::Trade])
+            .await
+            .unwrap();
+        let signed = WsAuth {
+            credential: Credential::ApiKey {
+                key_id: key.key_id.clone(),
+            },
+            api_keys: Some(api_keys.clone()),
+        };
+        assert_eq!(signed.check(now).await, Ok(()));
+        assert!(api_keys.revoke(user_id, &key.key_id).await.unwrap());
+        assert_eq!(signed.check(now).await, Err("API key revoked".to_string()));
+    }
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+/// Ledger amounts are signed integers in units of 1e-8 of the currency, so
+/// postings add up exactly and the zero-sum invariant can be checked with `==`
+pub type LedgerAmount = i128;
+
+/// Implied decimals of a `LedgerAmount`
+pub const LEDGER_DECIMALS: u32 = 8;
+
+const LEDGER_SCALE: f64 = 100_000_000.0;
+const LEDGER_UNIT: LedgerAmount = 100_000_000;
+
+/// Convert a floating-point balance into ledger units, rounding to the nearest unit
+pub fn to_ledger_units(amount: f64) -> LedgerAmount {
+    (amount * LEDGER_SCALE).round() as LedgerAmount
+}
+
+pub fn from_ledger_units(amount: LedgerAmount) -> f64 {
+    amount as f64 / LEDGER_SCALE
+}
+
+/// Exact decimal form of a ledger amount, e.g. `-12.50000000`
+pub fn format_ledger_units(amount: LedgerAmount) -> String {
+    let sign = if amount < 0 { "-" } else { "" };
+    let units = amount.unsigned_abs();
+    let scale = LEDGER_UNIT as u128;
+    format!(
+        "{}{}.{:0width$}",
+        sign,
+        units / scale,
+        units % scale,
+        width = LEDGER_DECIMALS as usize
+    )
+}
+
+/// Read back a decimal written by `format_ledger_units`, refusing anything
+/// more precise than a ledger unit rather than rounding it
+pub fn parse_ledger_units(s: &str) -> Result<LedgerAmount, String> {
+    let invalid = || format!("Invalid ledger amount: {:?}", s);
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let all_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if whole.is_empty()
+        || !all_digits(whole)
+        || !all_digits(fraction)
+        || fraction.len() > LEDGER_DECIMALS as usize
+    {
+        return Err(invalid());
+    }
+    let whole: LedgerAmount = whole.parse().map_err(|_| invalid())?;
+    let fraction: LedgerAmount = format!("{:0<width$}", fraction, width = LEDGER_DECIMALS as usize)
+        .parse()
+        .map_err(|_| invalid())?;
+    let amount = whole
+        .checked_mul(LEDGER_UNIT)
+        .and_then(|units| units.checked_add(fraction))
+        .ok_or_else(invalid)?;
+    Ok(if negative { -amount } else { amount })
+}
+
+/// Serde format for ledger amounts as exact decimal strings, for
+/// `#[serde(with = "crate::ledger::decimal")]`
+pub mod decimal {
+    use super::{format_ledger_units, parse_ledger_units, LedgerAmount};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        amount: &LedgerAmount,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format_ledger_units(*amount))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<LedgerAmount, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        parse_ledger_units(&s).map_err(de::Error::custom)
+    }
+}
+
+/// Who an account belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountOwner {
+    /// A customer's spendable funds
+    User(Uuid),
+    /// Exchange revenue from trading fees
+    Fees,
+    /// Exchange-funded buffer that absorbs losses a user cannot cover
+    Insurance,
+    /// The exchange's own funds, which pay interest on customer balances
+    Treasury,
+    /// Counterparty for money entering or leaving the exchange (banks, chains).
+    /// Its balance is the negative of everything held inside.
+    External,
+}
+
+/// One balance in the ledger: an owner's holding of one currency
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Account {
+    pub owner: AccountOwner,
+    pub currency: String,
+}
+
+impl Account {
+    pub fn new(owner: AccountOwner, currency: &str) -> Self {
+        Account {
+            owner,
+            currency: currency.to_string(),
+        }
+    }
+
+    pub fn user(user_id: Uuid, currency: &str) -> Self {
+        Account::new(AccountOwner::User(user_id), currency)
+    }
+
+    pub fn external(currency: &str) -> Self {
+        Account::new(AccountOwner::External, currency)
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.owner {
+            AccountOwner::User(id) => write!(f, "user:{}:{}", id, self.currency),
+            AccountOwner::Fees => write!(f, "exchange:fees:{}", self.currency),
+            AccountOwner::Insurance => write!(f, "exchange:insurance:{}", self.currency),
+            AccountOwner::Treasury => write!(f, "exchange:treasury:{}", self.currency),
+            AccountOwner::External => write!(f, "external:{}", self.currency),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ledger_amounts_round_trip_through_exact_decimals() {
+        for (amount, text) in [
+            (0, "0.00000000"),
+            (1, "0.00000001"),
+            (-1_250_000_000, "-12.50000000"),
+            (i128::MAX, "1701411834604692317316873037158.84105727"),
+        ] {
+            assert_eq!(format_ledger_units(amount), text);
+            assert_eq!(parse_ledger_units(text), Ok(amount));
+        }
+        assert_eq!(parse_ledger_units("3"), Ok(300_000_000));
+        assert_eq!(parse_ledger_units("-0.5"), Ok(-50_000_000));
+        for invalid in ["", "-", ".5", "1.000000001", "1e3", "+1", "1.2.3"] {
+            assert!(parse_ledger_units(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+}
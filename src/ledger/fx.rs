@@ -0,0 +1,128 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Operator-set conversion rate: one unit of `base` is worth `rate` units of
+/// `quote`. Journals that relied on a rate keep a copy of it, so reports
+/// reproduce the rate used at the time rather than today's.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FxRate {
+    pub base: String,
+    pub quote: String,
+    pub rate: f64,
+    pub version: u64, // Increases with every change to any rate
+    pub set_at: DateTime<Utc>,
+}
+
+impl FxRate {
+    /// `amount` of `currency` expressed in the other currency of the pair
+    pub fn convert(&self, amount: f64, currency: &str) -> Option<f64> {
+        if currency == self.base {
+            Some(amount * self.rate)
+        } else if currency == self.quote {
+            Some(amount / self.rate)
+        } else {
+            None
+        }
+    }
+}
+
+/// Current rate of each currency pair, as the engine values fees at
+/// settlement. Separate from the static display table in `utils::fx`, which
+/// only renders prices for users.
+#[derive(Debug, Default)]
+pub struct SettlementRates {
+    rates: BTreeMap<(String, String), FxRate>,
+    last_version: u64,
+}
+
+impl SettlementRates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set or replace the rate of `base` in `quote`
+    pub fn set(
+        &mut self,
+        base: &str,
+        quote: &str,
+        rate: f64,
+        now: DateTime<Utc>,
+    ) -> Result<FxRate, String> {
+        if base == quote {
+            return Err("base and quote must be different currencies".to_string());
+        }
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err("rate must be a positive number".to_string());
+        }
+        self.last_version += 1;
+        let fx_rate = FxRate {
+            base: base.to_string(),
+            quote: quote.to_string(),
+            rate,
+            version: self.last_version,
+            set_at: now,
+        };
+        self.rates
+            .insert((base.to_string(), quote.to_string()), fx_rate.clone());
+        Ok(fx_rate)
+    }
+
+    pub fn get(&self, base: &str, quote: &str) -> Option<&FxRate> {
+        self.rates.get(&(base.to_string(), quote.to_string()))
+    }
+
+    pub fn remove(&mut self, base: &str, quote: &str) -> Option<FxRate> {
+        self.rates.remove(&(base.to_string(), quote.to_string()))
+    }
+
+    /// Every rate, ordered by pair
+    pub fn list(&self) -> Vec<FxRate> {
+        self.rates.values().cloned().collect()
+    }
+
+    /// Version of the last change to any rate, removed ones included
+    pub fn last_version(&self) -> u64 {
+        self.last_version
+    }
+
+    /// Rates as `list` and `last_version` reported them
+    pub fn restore(rates: Vec<FxRate>, last_version: u64) -> Self {
+        let rates = rates
+            .into_iter()
+            .map(|rate| ((rate.base.clone(), rate.quote.clone()), rate))
+            .collect();
+        SettlementRates {
+            rates,
+            last_version,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rates_are_versioned_and_convert_both_ways() {
+        let mut rates = SettlementRates::new();
+        let now = Utc::now();
+        assert!(rates.set("BTC", "BTC", 1.0, now).is_err());
+        assert!(rates.set("BTC", "USD", 0.0, now).is_err());
+        assert!(rates.set("BTC", "USD", f64::NAN, now).is_err());
+
+        let first = rates.set("BTC", "USD", 50_000.0, now).unwrap();
+        let second = rates.set("BTC", "USD", 40_000.0, now).unwrap();
+        assert!(second.version > first.version);
+        assert_eq!(rates.get("BTC", "USD"), Some(&second));
+        assert_eq!(rates.get("USD", "BTC"), None);
+        assert_eq!(rates.list().len(), 1);
+
+        assert_eq!(second.convert(2.0, "BTC"), Some(80_000.0));
+        assert_eq!(second.convert(20.0, "USD"), Some(0.0005));
+        assert_eq!(second.convert(1.0, "EUR"), None);
+
+        assert_eq!(rates.remove("BTC", "USD"), Some(second));
+        assert!(rates.list().is_empty());
+    }
+}
@@ -0,0 +1,623 @@
+use crate::ledger::{Account, AccountOwner, FxRate, LedgerAmount};
+use crate::types::{OrderSide, Trade};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use uuid::Uuid;
+
+/// Journals kept for inspection; balances always cover the full history
+pub const DEFAULT_JOURNAL_RETENTION: usize = 10_000;
+
+/// Why money moved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalKind {
+    Deposit,
+    Withdrawal,
+    Trade,
+    /// Net result of a netting window's trades, one posting per user and currency
+    NetSettlement,
+    Fee,
+    Insurance,
+    Interest,
+    Adjustment,
+    /// Holdings of an expired dated market paid out in its quote currency
+    ExpirySettlement,
+}
+
+/// One side of a journal: `amount` is added to the account's balance.
+/// Positive amounts are debits, negative amounts credits.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Posting {
+    pub account: Account,
+    pub amount: LedgerAmount,
+}
+
+impl Posting {
+    pub fn new(account: Account, amount: LedgerAmount) -> Self {
+        Posting { account, amount }
+    }
+}
+
+/// A balanced set of postings applied atomically
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Journal {
+    pub id: u64,
+    pub kind: JournalKind,
+    pub postings: Vec<Posting>,
+    pub timestamp: DateTime<Utc>,
+    /// Rate the amounts were valued at, when they were charged in a currency
+    /// other than the one the user received
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fx_rate: Option<FxRate>,
+    /// Id of the journal this one undoes, posting the same amounts the other way
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverses: Option<u64>,
+}
+
+/// One account's line in a trial balance, in ledger units written out as
+/// exact decimals
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrialBalanceRow {
+    pub account: String,
+    pub currency: String,
+    #[serde(with = "crate::ledger::decimal")]
+    pub debit: LedgerAmount,
+    #[serde(with = "crate::ledger::decimal")]
+    pub credit: LedgerAmount,
+}
+
+/// Debit and credit totals of one currency. They are equal whenever the
+/// ledger is sound.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrialBalanceTotal {
+    pub currency: String,
+    #[serde(with = "crate::ledger::decimal")]
+    pub debits: LedgerAmount,
+    #[serde(with = "crate::ledger::decimal")]
+    pub credits: LedgerAmount,
+    pub balanced: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrialBalance {
+    pub rows: Vec<TrialBalanceRow>,
+    pub totals: Vec<TrialBalanceTotal>,
+    pub balanced: bool,
+    pub journal_count: u64,
+}
+
+/// Double-entry record of every movement of money. Each journal's postings
+/// sum to zero per currency, so all balances together always do too.
+pub struct Ledger {
+    balances: HashMap<Account, LedgerAmount>,
+    recent: VecDeque<Journal>,
+    funding_by_user: HashMap<Uuid, VecDeque<u64>>, // Recent deposits and withdrawals, oldest first
+    retention: usize,
+    next_id: u64,
+    unsaved: Vec<Journal>, // Posted since the last `take_unsaved`
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Ledger::new(DEFAULT_JOURNAL_RETENTION)
+    }
+}
+
+impl Ledger {
+    pub fn new(retention: usize) -> Self {
+        Ledger {
+            balances: HashMap::new(),
+            recent: VecDeque::new(),
+            funding_by_user: HashMap::new(),
+            retention,
+            next_id: 1,
+            unsaved: Vec::new(),
+        }
+    }
+
+    /// Number new journals after `last_id`, so ids stay unique across
+    /// restarts against the same store
+    pub fn continue_after(&mut self, last_id: u64) {
+        self.next_id = self.next_id.max(last_id + 1);
+    }
+
+    /// Apply `postings` as one journal. Rejected without effect unless every
+    /// currency in it nets to zero.
+    pub fn post(&mut self, kind: JournalKind, postings: Vec<Posting>) -> Result<u64, String> {
+        self.post_with_fx_rate(kind, postings, None)
+    }
+
+    /// `post`, keeping a snapshot of the FX rate the journal was valued at
+    pub fn post_with_fx_rate(
+        &mut self,
+        kind: JournalKind,
+        postings: Vec<Posting>,
+        fx_rate: Option<FxRate>,
+    ) -> Result<u64, String> {
+        self.post_journal(kind, postings, fx_rate, None)
+    }
+
+    /// Post a journal of the same kind as `journal_id` with every amount
+    /// negated, recording which one it reverses. The original must still be
+    /// among the recent journals, and may be reversed only once.
+    pub fn reverse(&mut self, journal_id: u64) -> Result<u64, String> {
+        let original = self
+            .journal(journal_id)
+            .ok_or_else(|| format!("Journal {} is not among the recent journals", journal_id))?;
+        if original.reverses.is_some() {
+            return Err(format!("Journal {} is itself a reversal", journal_id));
+        }
+        if self.recent.iter().any(|j| j.reverses == Some(journal_id)) {
+            return Err(format!("Journal {} is already reversed", journal_id));
+        }
+        let kind = original.kind;
+        let postings = original
+            .postings
+            .iter()
+            .map(|posting| Posting::new(posting.account.clone(), -posting.amount))
+            .collect();
+        self.post_journal(kind, postings, None, Some(journal_id))
+    }
+
+    fn post_journal(
+        &mut self,
+        kind: JournalKind,
+        postings: Vec<Posting>,
+        fx_rate: Option<FxRate>,
+        reverses: Option<u64>,
+    ) -> Result<u64, String> {
+        if postings.is_empty() {
+            return Err("A journal needs at least one posting".to_string());
+        }
+        let mut net: BTreeMap<&str, LedgerAmount> = BTreeMap::new();
+        for posting in &postings {
+            *net.entry(&posting.account.currency).or_default() += posting.amount;
+        }
+        if let Some((currency, amount)) = net.iter().find(|(_, amount)| **amount != 0) {
+            return Err(format!(
+                "Unbalanced {:?} journal: {} postings net to {}",
+                kind, currency, amount
+            ));
+        }
+
+        for posting in &postings {
+            *self.balances.entry(posting.account.clone()).or_default() += posting.amount;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let journal = Journal {
+            id,
+            kind,
+            postings,
+            timestamp: Utc::now(),
+            fx_rate,
+            reverses,
+        };
+        if self.retention > 0 {
+            if self.recent.len() == self.retention {
+                self.forget_oldest();
+            }
+            self.remember(journal.clone());
+        }
+        self.unsaved.push(journal);
+        Ok(id)
+    }
+
+    /// Keep `journal` as the newest recent one, indexing it by user if it
+    /// moved a user's money in or out
+    fn remember(&mut self, journal: Journal) {
+        if matches!(journal.kind, JournalKind::Deposit | JournalKind::Withdrawal) {
+            for user_id in users_of(&journal) {
+                self.funding_by_user
+                    .entry(user_id)
+                    .or_default()
+                    .push_back(journal.id);
+            }
+        }
+        self.recent.push_back(journal);
+    }
+
+    fn forget_oldest(&mut self) {
+        let Some(journal) = self.recent.pop_front() else {
+            return;
+        };
+        for user_id in users_of(&journal) {
+            if let Some(ids) = self.funding_by_user.get_mut(&user_id) {
+                if ids.front() == Some(&journal.id) {
+                    ids.pop_front();
+                }
+                if ids.is_empty() {
+                    self.funding_by_user.remove(&user_id);
+                }
+            }
+        }
+    }
+
+    /// Move `amount` from one account to another in the same currency
+    pub fn transfer(
+        &mut self,
+        kind: JournalKind,
+        from: Account,
+        to: Account,
+        amount: LedgerAmount,
+    ) -> Result<u64, String> {
+        if from.currency != to.currency {
+            return Err(format!(
+                "Cannot transfer between {} and {}",
+                from.currency, to.currency
+            ));
+        }
+        self.post(
+            kind,
+            vec![Posting::new(from, -amount), Posting::new(to, amount)],
+        )
+    }
+
+    /// Record the exchange of quote for base between the two sides of a trade
+    pub fn post_trade(&mut self, trade: &Trade, taker_side: OrderSide) -> Result<u64, String> {
+        self.post(JournalKind::Trade, trade_postings(trade, taker_side))
+    }
+
+    pub fn balance(&self, account: &Account) -> LedgerAmount {
+        self.balances.get(account).copied().unwrap_or(0)
+    }
+
+    /// Every account's balance, in account order
+    pub fn balances(&self) -> Vec<(Account, LedgerAmount)> {
+        let mut balances: Vec<_> = self
+            .balances
+            .iter()
+            .map(|(account, amount)| (account.clone(), *amount))
+            .collect();
+        balances.sort();
+        balances
+    }
+
+    /// Id of the last journal posted, 0 before the first
+    pub fn last_journal_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    /// Replace every account's balance and number new journals after
+    /// `last_journal_id`, as when restoring a snapshot. The journals
+    /// themselves are the store's to keep.
+    pub fn restore(&mut self, balances: Vec<(Account, LedgerAmount)>, last_journal_id: u64) {
+        self.balances = balances.into_iter().collect();
+        self.continue_after(last_journal_id);
+    }
+
+    /// Keep `journals`, oldest first, as the recent ones, as when reading
+    /// them back from the store on startup
+    pub fn recall(&mut self, journals: Vec<Journal>) {
+        let skip = journals.len().saturating_sub(self.retention);
+        self.recent.clear();
+        self.funding_by_user.clear();
+        for journal in journals.into_iter().skip(skip) {
+            self.remember(journal);
+        }
+        if let Some(last) = self.recent.back() {
+            self.continue_after(last.id);
+        }
+    }
+
+    /// Every journal posted since the last call, oldest first, for the store
+    pub fn take_unsaved(&mut self) -> Vec<Journal> {
+        std::mem::take(&mut self.unsaved)
+    }
+
+    /// Journal `id`, if it is among the recent ones
+    pub fn journal(&self, id: u64) -> Option<&Journal> {
+        let index = self
+            .recent
+            .binary_search_by_key(&id, |journal| journal.id)
+            .ok()?;
+        self.recent.get(index)
+    }
+
+    /// The recent deposits and withdrawals of `user_id`, newest first, found
+    /// through an index rather than by scanning every journal
+    pub fn funding_journals_of(&self, user_id: Uuid) -> impl Iterator<Item = &Journal> {
+        self.funding_by_user
+            .get(&user_id)
+            .into_iter()
+            .flat_map(|ids| ids.iter().rev())
+            .filter_map(|id| self.journal(*id))
+    }
+
+    /// Up to `limit` of the most recent journals, newest first
+    pub fn recent_journals(&self, limit: usize) -> Vec<Journal> {
+        self.recent.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// Up to `limit` of the most recent journals of one kind, newest first
+    pub fn recent_journals_of(&self, kind: JournalKind, limit: usize) -> Vec<Journal> {
+        self.recent
+            .iter()
+            .rev()
+            .filter(|journal| journal.kind == kind)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Every non-zero account with its balance on the debit or credit side,
+    /// plus per-currency totals
+    pub fn trial_balance(&self) -> TrialBalance {
+        self.trial_balance_including(std::iter::empty())
+    }
+
+    /// The trial balance as it would stand once `pending` movements were
+    /// posted, leaving the ledger itself untouched
+    pub fn trial_balance_including<'a>(
+        &'a self,
+        pending: impl IntoIterator<Item = (&'a Account, LedgerAmount)>,
+    ) -> TrialBalance {
+        let mut combined: HashMap<&Account, LedgerAmount> = self
+            .balances
+            .iter()
+            .map(|(account, amount)| (account, *amount))
+            .collect();
+        for (account, amount) in pending {
+            *combined.entry(account).or_default() += amount;
+        }
+        let mut accounts: Vec<(&Account, LedgerAmount)> = combined
+            .into_iter()
+            .filter(|(_, amount)| *amount != 0)
+            .collect();
+        accounts.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut sums: BTreeMap<&str, (LedgerAmount, LedgerAmount)> = BTreeMap::new();
+        let rows = accounts
+            .iter()
+            .map(|(account, amount)| {
+                let (debits, credits) = sums.entry(&account.currency).or_default();
+                if *amount > 0 {
+                    *debits += amount;
+                } else {
+                    *credits -= amount;
+                }
+                TrialBalanceRow {
+                    account: account.to_string(),
+                    currency: account.currency.clone(),
+                    debit: (*amount).max(0),
+                    credit: -(*amount).min(0),
+                }
+            })
+            .collect();
+
+        let totals: Vec<TrialBalanceTotal> = sums
+            .into_iter()
+            .map(|(currency, (debits, credits))| TrialBalanceTotal {
+                currency: currency.to_string(),
+                debits,
+                credits,
+                balanced: debits == credits,
+            })
+            .collect();
+
+        TrialBalance {
+            balanced: totals.iter().all(|total| total.balanced),
+            rows,
+            totals,
+            journal_count: self.next_id - 1,
+        }
+    }
+}
+
+/// Users whose accounts `journal` posts to, each once
+fn users_of(journal: &Journal) -> Vec<Uuid> {
+    let mut users = Vec::new();
+    for posting in &journal.postings {
+        if let AccountOwner::User(user_id) = &posting.account.owner {
+            if !users.contains(user_id) {
+                users.push(*user_id);
+            }
+        }
+    }
+    users
+}
+
+/// The four postings of a trade: buyer pays USD to the seller, seller delivers
+/// BTC to the buyer
+pub fn trade_postings(trade: &Trade, taker_side: OrderSide) -> Vec<Posting> {
+    let (buyer, seller) = match taker_side {
+        OrderSide::Buy => (trade.taker_user_id, trade.maker_user_id),
+        OrderSide::Sell => (trade.maker_user_id, trade.taker_user_id),
+    };
+    let base = trade.quantity.raw() as LedgerAmount;
+    // price (1e-6) * quantity (1e-8) rescaled to ledger units (1e-8), rounded
+    // to the nearest unit as `to_ledger_units` rounds the balances it mirrors
+    let quote = (trade.price.raw() as LedgerAmount * base + 500_000) / 1_000_000;
+
+    vec![
+        Posting::new(Account::user(buyer, "USD"), -quote),
+        Posting::new(Account::user(seller, "USD"), quote),
+        Posting::new(Account::user(seller, "BTC"), -base),
+        Posting::new(Account::user(buyer, "BTC"), base),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::{to_ledger_units, AccountOwner};
+    use crate::types::{Price, Quantity};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_unbalanced_journal_is_rejected_without_effect() {
+        let mut ledger = Ledger::default();
+        let user = Account::user(Uuid::new_v4(), "USD");
+
+        let result = ledger.post(
+            JournalKind::Adjustment,
+            vec![Posting::new(user.clone(), 100)],
+        );
+        assert!(result.is_err());
+        assert_eq!(ledger.balance(&user), 0);
+        assert_eq!(ledger.trial_balance().journal_count, 0);
+
+        assert!(ledger
+            .transfer(
+                JournalKind::Deposit,
+                Account::external("USD"),
+                Account::user(Uuid::new_v4(), "BTC"),
+                1
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_trial_balance_after_deposits_trade_and_fee() {
+        let mut ledger = Ledger::default();
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+
+        ledger
+            .transfer(
+                JournalKind::Deposit,
+                Account::external("USD"),
+                Account::user(buyer, "USD"),
+                to_ledger_units(1_000.0),
+            )
+            .unwrap();
+        ledger
+            .transfer(
+                JournalKind::Deposit,
+                Account::external("BTC"),
+                Account::user(seller, "BTC"),
+                to_ledger_units(2.0),
+            )
+            .unwrap();
+
+        let trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            seller,
+            buyer,
+            Price::from_f64(300.5),
+            Quantity::from_f64(1.5),
+        );
+        ledger.post_trade(&trade, OrderSide::Buy).unwrap();
+        ledger
+            .transfer(
+                JournalKind::Fee,
+                Account::user(seller, "USD"),
+                Account::new(AccountOwner::Fees, "USD"),
+                to_ledger_units(0.45),
+            )
+            .unwrap();
+
+        assert_eq!(
+            ledger.balance(&Account::user(buyer, "USD")),
+            to_ledger_units(549.25)
+        );
+        assert_eq!(
+            ledger.balance(&Account::user(buyer, "BTC")),
+            to_ledger_units(1.5)
+        );
+        assert_eq!(
+            ledger.balance(&Account::user(seller, "USD")),
+            to_ledger_units(450.3)
+        );
+
+        let report = ledger.trial_balance();
+        assert!(report.balanced);
+        assert_eq!(report.journal_count, 4);
+        let usd = report.totals.iter().find(|t| t.currency == "USD").unwrap();
+        assert_eq!(usd.debits, to_ledger_units(1_000.0));
+        assert_eq!(usd.credits, to_ledger_units(1_000.0));
+        let json = serde_json::to_value(usd).unwrap();
+        assert_eq!(json["debits"], "1000.00000000");
+        assert_eq!(ledger.recent_journals(1)[0].kind, JournalKind::Fee);
+    }
+
+    #[test]
+    fn test_a_reversal_undoes_its_journal_once() {
+        let mut ledger = Ledger::default();
+        let user = Account::user(Uuid::new_v4(), "USD");
+        let external = Account::external("USD");
+        ledger
+            .transfer(JournalKind::Deposit, external.clone(), user.clone(), 500)
+            .unwrap();
+        let withdrawal = ledger
+            .transfer(
+                JournalKind::Withdrawal,
+                external.clone(),
+                user.clone(),
+                -200,
+            )
+            .unwrap();
+
+        let reversal = ledger.reverse(withdrawal).unwrap();
+        assert_eq!(ledger.balance(&user), 500);
+        assert_eq!(ledger.balance(&external), -500);
+        let journal = ledger.journal(reversal).unwrap();
+        assert_eq!(journal.kind, JournalKind::Withdrawal);
+        assert_eq!(journal.reverses, Some(withdrawal));
+
+        assert!(ledger.reverse(withdrawal).is_err());
+        assert!(ledger.reverse(reversal).is_err());
+        assert!(ledger.reverse(999).is_err());
+        assert_eq!(ledger.balance(&user), 500);
+    }
+
+    #[test]
+    fn test_funding_journals_are_indexed_by_user_while_retained() {
+        let mut ledger = Ledger::new(3);
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let external = Account::external("USD");
+        let deposit = |ledger: &mut Ledger, user_id: Uuid| {
+            let user = Account::user(user_id, "USD");
+            ledger
+                .transfer(JournalKind::Deposit, external.clone(), user, 100)
+                .unwrap()
+        };
+        let ids = |ledger: &Ledger, user_id: Uuid| -> Vec<u64> {
+            ledger
+                .funding_journals_of(user_id)
+                .map(|journal| journal.id)
+                .collect()
+        };
+
+        let first = deposit(&mut ledger, alice);
+        let second = deposit(&mut ledger, bob);
+        ledger
+            .transfer(
+                JournalKind::Fee,
+                Account::user(bob, "USD"),
+                external.clone(),
+                1,
+            )
+            .unwrap();
+        assert_eq!(ids(&ledger, alice), vec![first]);
+        assert_eq!(ids(&ledger, bob), vec![second]);
+
+        // Journals leaving the retained window leave the index too
+        let third = deposit(&mut ledger, alice);
+        assert_eq!(ids(&ledger, alice), vec![third]);
+
+        let mut recalled = Ledger::new(3);
+        recalled.recall(ledger.recent_journals(3).into_iter().rev().collect());
+        assert_eq!(ids(&recalled, alice), vec![third]);
+        assert_eq!(ids(&recalled, bob), vec![second]);
+    }
+
+    #[test]
+    fn test_trade_quote_rounds_to_the_nearest_unit() {
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+        // 0.01 * 0.00000051 is 0.51 of a ledger unit
+        let trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            seller,
+            buyer,
+            Price::from_f64(0.01),
+            Quantity::from_f64(0.00000051),
+        );
+        let postings = trade_postings(&trade, OrderSide::Buy);
+        assert_eq!(postings[0], Posting::new(Account::user(buyer, "USD"), -1));
+        assert_eq!(postings[1], Posting::new(Account::user(seller, "USD"), 1));
+    }
+}
@@ -0,0 +1,10 @@
+pub mod accounts;
+pub mod fx;
+#[allow(clippy::module_inception)]
+pub mod ledger;
+pub mod netting;
+
+pub use accounts::*;
+pub use fx::*;
+pub use ledger::*;
+pub use netting::*;
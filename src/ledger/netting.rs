@@ -0,0 +1,147 @@
+use crate::ledger::{trade_postings, Account, JournalKind, Ledger, LedgerAmount, Posting};
+use crate::types::{OrderSide, Trade};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Trades awaiting settlement in a netted-clearing market. Their postings are
+/// summed per user and currency, and the whole window settles as one journal.
+#[derive(Debug, Default)]
+pub struct NettingWindow {
+    deltas: BTreeMap<Account, LedgerAmount>,
+    trades: usize,
+    opened_at: Option<Instant>,
+}
+
+impl NettingWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trades == 0
+    }
+
+    /// Trades accumulated since the window opened
+    pub fn trade_count(&self) -> usize {
+        self.trades
+    }
+
+    /// Net movements per account that settling the window would post
+    pub fn pending(&self) -> impl Iterator<Item = (&Account, LedgerAmount)> {
+        self.deltas
+            .iter()
+            .map(|(account, amount)| (account, *amount))
+    }
+
+    pub fn add_trade(&mut self, trade: &Trade, taker_side: OrderSide, now: Instant) {
+        for posting in trade_postings(trade, taker_side) {
+            *self.deltas.entry(posting.account).or_default() += posting.amount;
+        }
+        self.trades += 1;
+        self.opened_at.get_or_insert(now);
+    }
+
+    /// Whether the first trade in the window is at least `length` old
+    pub fn is_due(&self, now: Instant, length: Duration) -> bool {
+        self.opened_at
+            .is_some_and(|opened| now.duration_since(opened) >= length)
+    }
+
+    /// Post the window's net movements as a single journal and start a new
+    /// window. Users whose trades cancelled out get no posting at all.
+    pub fn settle(&mut self, ledger: &mut Ledger) -> Result<Option<u64>, String> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let postings: Vec<Posting> = std::mem::take(&mut self.deltas)
+            .into_iter()
+            .filter(|(_, amount)| *amount != 0)
+            .map(|(account, amount)| Posting::new(account, amount))
+            .collect();
+        self.trades = 0;
+        self.opened_at = None;
+
+        if postings.is_empty() {
+            return Ok(None);
+        }
+        ledger.post(JournalKind::NetSettlement, postings).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::to_ledger_units;
+    use crate::types::{Price, Quantity};
+    use uuid::Uuid;
+
+    fn trade(maker: Uuid, taker: Uuid, price: f64, quantity: f64) -> Trade {
+        Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            maker,
+            taker,
+            Price::from_f64(price),
+            Quantity::from_f64(quantity),
+        )
+    }
+
+    #[test]
+    fn test_window_nets_per_user_and_settles_once() {
+        let mut ledger = Ledger::default();
+        let mut window = NettingWindow::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        // Alice buys 1 BTC from Bob at 100, then sells 0.4 back at 110
+        window.add_trade(&trade(bob, alice, 100.0, 1.0), OrderSide::Buy, start);
+        window.add_trade(&trade(bob, alice, 110.0, 0.4), OrderSide::Sell, start);
+        assert_eq!(window.trade_count(), 2);
+        assert!(!window.is_due(start, Duration::from_millis(50)));
+        assert!(window.is_due(start + Duration::from_millis(50), Duration::from_millis(50)));
+
+        let journal = window.settle(&mut ledger).unwrap();
+        assert!(journal.is_some());
+        assert!(window.is_empty());
+        assert_eq!(
+            ledger.balance(&Account::user(alice, "USD")),
+            to_ledger_units(-56.0)
+        );
+        assert_eq!(
+            ledger.balance(&Account::user(alice, "BTC")),
+            to_ledger_units(0.6)
+        );
+        assert_eq!(
+            ledger.balance(&Account::user(bob, "USD")),
+            to_ledger_units(56.0)
+        );
+
+        let report = ledger.trial_balance();
+        assert!(report.balanced);
+        assert_eq!(report.journal_count, 1);
+        assert_eq!(window.settle(&mut ledger), Ok(None));
+    }
+
+    #[test]
+    fn test_round_trip_at_same_price_posts_nothing() {
+        let mut ledger = Ledger::default();
+        let mut window = NettingWindow::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+
+        window.add_trade(
+            &trade(bob, alice, 100.0, 1.0),
+            OrderSide::Buy,
+            Instant::now(),
+        );
+        window.add_trade(
+            &trade(bob, alice, 100.0, 1.0),
+            OrderSide::Sell,
+            Instant::now(),
+        );
+
+        assert_eq!(window.settle(&mut ledger), Ok(None));
+        assert!(window.is_empty());
+        assert_eq!(ledger.trial_balance().journal_count, 0);
+    }
+}
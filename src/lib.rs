@@ -1,7 +1,15 @@
+#![allow(non_snake_case)]
+
 pub mod engine;
+pub mod graphql;
+pub mod ledger;
 pub mod messages;
 pub mod orderbook;
+pub mod routes;
 pub mod state;
 pub mod types;
 pub mod utils;
 pub mod handlers;
+pub mod storage;
+#[cfg(test)]
+pub(crate) mod testing;
@@ -1,79 +1,173 @@
+#![allow(non_snake_case)]
+
 use actix_web::{middleware::Logger, web, App, HttpServer};
-use actix_web_httpauth::middleware::HttpAuthentication;
+use std::io::Write;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 
-mod engine;
-mod handlers;
-mod messages;
-mod orderbook;
-mod state;
-mod types;
-mod utils;
+use Orderbook::engine::{
+    control_channel, event_channel, run_orderbook_engine_with_control, run_simulator_bot,
+    ControlCommand, EngineConfig, EngineMetrics, SIMULATOR_INTERVAL,
+};
+use Orderbook::graphql;
+use Orderbook::handlers::auth::UserStore;
+use Orderbook::routes;
+use Orderbook::state::{
+    public_depth_limit_from_env, trusted_proxies_from_env, withdrawal_address_delay_from_env,
+    AppState, AuditLog, LogFormat, Profile, WsLimits,
+};
+use Orderbook::storage::{open_users, ApiKeyStore, StorageBackend};
+use Orderbook::utils::{admin_token, init_jwt_keys, secrets, FxRates, OidcConfig, PageSigner};
 
-use engine::run_orderbook_engine;
-use handlers::auth::UserStore;
-use state::AppState;
-use utils::jwt_validator;
+/// Log lines as text, or as one JSON object each for log shippers
+fn init_logger(format: LogFormat) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"));
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "ts": chrono::Utc::now(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // Resolve the deployment profile before anything reads configuration
+    let profile = Profile::from_env()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
     // Initialize logger
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    init_logger(profile.log_format);
 
-    println!("🚀 Starting Orderbook System...");
+    // Placeholder secrets are only acceptable in a relaxed dev profile
+    let missing = profile.missing_secrets(|name| secrets().contains(name));
+    if !missing.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{:?} profile requires {} to be set",
+                profile.environment,
+                missing.join(", ")
+            ),
+        ));
+    }
+    let placeholders = profile.placeholder_secrets(|name| secrets().text(name));
+    if !placeholders.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{:?} profile refuses example values for {}",
+                profile.environment,
+                placeholders.join(", ")
+            ),
+        ));
+    }
+    let jwt_keys = init_jwt_keys()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let tape_signer = PageSigner::from_env(&profile)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    println!("🚀 Starting Orderbook System ({:?} profile)...", profile.environment);
 
     // Create mpsc channel for orderbook commands
     let (orderbook_tx, orderbook_rx) = mpsc::channel(100);
 
     // Start orderbook engine in background
-    tokio::spawn(run_orderbook_engine(orderbook_rx));
+    let metrics = Arc::new(EngineMetrics::new());
+    let events = event_channel();
+    let engine_config = EngineConfig::from_env(&profile)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let (control_tx, control_rx) = control_channel();
+    let mut engine = tokio::spawn(run_orderbook_engine_with_control(
+        orderbook_rx,
+        control_rx,
+        metrics.clone(),
+        events.clone(),
+        engine_config,
+    ));
 
-    // Create shared state
-    let app_state = web::Data::new(AppState::new(orderbook_tx));
-    let user_store = web::Data::new(UserStore::new());
+    // Simulated market makers, so a dev book is never empty
+    for seed in 1..=profile.simulator_bots as u64 {
+        tokio::spawn(run_simulator_bot(orderbook_tx.clone(), seed, SIMULATOR_INTERVAL));
+    }
 
-    // Create JWT auth middleware
-    let auth = HttpAuthentication::bearer(jwt_validator);
+    // USER_STORAGE_URL=memory or postgres://...; instances on one database share accounts
+    let users_backend: StorageBackend = std::env::var("USER_STORAGE_URL")
+        .unwrap_or_else(|_| "memory".to_string())
+        .parse()
+        .map_err(|e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let users = open_users(&users_backend)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    let trusted_proxies = trusted_proxies_from_env()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let fx_rates = FxRates::from_env()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    // Create shared state
+    let app_state = web::Data::new(
+        AppState::new(orderbook_tx, metrics)
+            .with_control(control_tx.clone())
+            .with_fx_rates(fx_rates)
+            .with_tape_signer(tape_signer)
+            .with_events(events)
+            .with_ws_limits(WsLimits::from_env())
+            .with_public_depth_limit(public_depth_limit_from_env())
+            .with_withdrawal_address_delay(withdrawal_address_delay_from_env())
+            .with_trusted_proxies(trusted_proxies)
+            .with_audit_log(AuditLog::with_repository(users.clone()))
+            .with_profile(profile),
+    );
+    let user_store = web::Data::new(UserStore::with_repository(users.clone()));
+    let api_key_store = web::Data::new(ApiKeyStore::with_repository(users));
+    let oidc = web::Data::new(OidcConfig::from_secrets(secrets()));
+    let graphql_schema = web::Data::new(graphql::build_schema(app_state.get_ref().clone()));
 
     println!("📊 Orderbook engine started");
+    println!("🔑 Signing user tokens with key {}", jwt_keys.current_key_id());
+    if admin_token().is_none() {
+        println!("🔒 ADMIN_TOKEN is not set; admin routes are disabled");
+    }
     println!("🌐 Starting HTTP server on http://127.0.0.1:8080");
 
     // Start HTTP server
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
-            .wrap(Logger::default())
+            // The default format, plus the request ID errors are answered with
+            .wrap(Logger::new(
+                r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{X-Request-Id}o"#,
+            ))
             .app_data(app_state.clone())
             .app_data(user_store.clone())
-            // Public routes
-            .service(
-                web::scope("/api")
-                    // Health check
-                    .service(handlers::health)
-                    // Auth routes (no auth required)
-                    .service(
-                        web::scope("/auth")
-                            .service(handlers::signup)
-                            .service(handlers::signin)
-                    )
-                    // Market data (no auth required)
-                    .service(handlers::get_orderbook)
-                    // Protected routes (auth required)
-                    .service(
-                        web::scope("/orders")
-                            .wrap(auth.clone())
-                            .service(handlers::create_limit_order)
-                            .service(handlers::create_market_order)
-                            .service(handlers::cancel_order)
-                    )
-                    .service(
-                        web::scope("/user")
-                            .wrap(auth.clone())
-                            .service(handlers::get_balance)
-                            .service(handlers::onramp)
-                    )
-            )
+            .app_data(api_key_store.clone())
+            .app_data(oidc.clone())
+            .app_data(graphql_schema.clone())
+            // API routes, by schema version
+            .configure(routes::configure)
     })
     .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .run();
+
+    // An engine that stops on its own, e.g. because its stores or command
+    // log could not be opened, takes the server down with it
+    tokio::select! {
+        result = server => result?,
+        _ = &mut engine => {
+            return Err(std::io::Error::other("The orderbook engine stopped"));
+        }
+    }
+
+    // Let the engine finish its batch and flush history before exiting
+    if control_tx.send(ControlCommand::Shutdown).await.is_ok() {
+        let _ = engine.await;
+    }
+    Ok(())
 }
@@ -1,8 +1,41 @@
-use crate::types::{OrderSide, Price, Quantity, Trade, UserBalance};
+use crate::engine::{
+    AccountSummary, DailyMarketStats, DashboardSnapshot, DeadLetter, FeedActivity, Incident,
+    InterestSummary, LeverageSettings, MarginAssessment, OrderFill, OrderTimings, PriceAverages,
+    RecoveryReport, SourceVolume, SyntheticQuote, TapePage, UserExecutionQuality,
+};
+use crate::ledger::{FxRate, Journal, JournalKind, TrialBalance};
+use crate::orderbook::{
+    BookOrder, DepthLevel, DepthTotals, DepthWindow, Heatmap, MarketState, OrderEntry, OrderFilter,
+};
+use crate::storage::HistoryQuery;
+use crate::types::{
+    ActivityEntry, LeverageTiers, MarketConfig, Order, OrderSide, OrderSource, Peg, Price,
+    Quantity, SlippageGuard, SyntheticIndex, TimeInForce, Trade, TradeThroughBand, TradingStatus,
+    UserBalance, UserTrade,
+};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use tokio::sync::oneshot;
+use tokio::time::Instant;
 use uuid::Uuid;
 
+/// A limit order as validated by the gateway, before the engine places it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LimitOrderParams {
+    pub side: OrderSide,
+    pub price: Price,
+    pub quantity: Quantity,
+    pub time_in_force: TimeInForce,
+    pub display_quantity: Option<Quantity>,
+    pub hidden: bool,
+    pub min_fill_qty: Option<Quantity>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub trade_through_protected: bool,
+    pub priority_fee: f64,
+    pub client_order_id: Option<String>,
+}
+
 // Commands sent from HTTP handlers to the OrderBook engine thread
 pub enum OrderBookCommand {
     // Order commands
@@ -11,12 +44,49 @@ pub enum OrderBookCommand {
         side: OrderSide,
         price: Price,
         quantity: Quantity,
+        time_in_force: TimeInForce,
+        display_quantity: Option<Quantity>, // Iceberg peak size; None shows the whole order
+        hidden: bool,                       // Rest without being displayed at all
+        min_fill_qty: Option<Quantity>,     // Smallest fill it accepts while resting
+        expires_at: Option<DateTime<Utc>>,  // Good-till-date; None rests until cancelled
+        peg: Option<Peg>,                   // Set by the engine when it places a pegged order
+        trade_through_protected: bool,      // Reject rather than trade beyond the market's band
+        priority_fee: f64,                  // USD bid for queue position, where the market takes it
+        received_at: DateTime<Utc>,         // Stamped by the gateway before queueing
+        source: OrderSource,                // Stamped by the gateway from the authenticated channel
+        client_order_id: Option<String>,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    PlacePeggedOrder {
+        user_id: Uuid,
+        side: OrderSide,
+        quantity: Quantity,
+        peg: Peg,
+        expires_at: Option<DateTime<Utc>>,
+        received_at: DateTime<Utc>,
+        source: OrderSource,
+        client_order_id: Option<String>,
         response_tx: oneshot::Sender<OrderBookResponse>,
     },
     PlaceMarketOrder {
         user_id: Uuid,
         side: OrderSide,
         quantity: Quantity,
+        slippage: Option<SlippageGuard>,
+        received_at: DateTime<Utc>,
+        source: OrderSource,
+        client_order_id: Option<String>,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    PlaceStopOrder {
+        user_id: Uuid,
+        side: OrderSide,
+        quantity: Quantity,
+        stop_price: Price,
+        trigger_index: Option<String>, // Trigger on this synthetic index, not the last trade price
+        received_at: DateTime<Utc>,
+        source: OrderSource,
+        client_order_id: Option<String>,
         response_tx: oneshot::Sender<OrderBookResponse>,
     },
     CancelOrder {
@@ -24,14 +94,233 @@ pub enum OrderBookCommand {
         order_id: Uuid,
         response_tx: oneshot::Sender<OrderBookResponse>,
     },
+    CancelOrderByClientId {
+        user_id: Uuid,
+        client_order_id: String, // Resolves to the user's most recent order with this ID
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    MassCancel {
+        user_id: Uuid,
+        filter: OrderFilter,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    CancelAllAfter {
+        user_id: Uuid,
+        timeout: Duration, // Zero disarms the switch
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    PlaceOrderBatch {
+        user_id: Uuid,
+        orders: Vec<LimitOrderParams>, // Placed back-to-back, nothing interleaved
+        received_at: DateTime<Utc>,
+        source: OrderSource,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    AmendOrder {
+        user_id: Uuid,
+        order_id: Uuid,
+        price: Option<Price>, // Re-price: the order goes behind what already rests there
+        quantity: Option<Quantity>, // New total size; may only shrink, keeps queue priority
+        received_at: DateTime<Utc>,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
 
     // Query commands
+    // Queries carry a deadline: once it passes (or the caller hangs up) the
+    // engine skips them instead of computing an answer nobody will read
     GetOrderBook {
         depth: usize,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetFullBook {
+        depth: usize,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetImbalance {
+        window: DepthWindow,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetHeatmap {
+        buckets: usize,
+        range_pct: f64, // Half-width around the mid, in percent of it
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetMarketConfig {
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetMarketState {
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetSyntheticIndices {
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    /// Published depth and market state as of feed message `seq`
+    GetMarketSnapshot {
+        levels: usize, // Per side; never more than the public depth
+        deadline: Instant,
         response_tx: oneshot::Sender<OrderBookResponse>,
     },
     GetUserBalance {
         user_id: Uuid,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetDailyStats {
+        market: String,
+        from: NaiveDate,
+        to: NaiveDate,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetTradeTape {
+        after: Option<u64>, // Exclusive sequence cursor
+        limit: usize,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetRecentTrades {
+        limit: usize,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetExecutionQuality {
+        user_id: Uuid,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetOrderHistory {
+        user_id: Uuid,
+        limit: usize,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetOrderByClientId {
+        user_id: Uuid,
+        client_order_id: String,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetOrderFills {
+        user_id: Uuid,
+        order_id: Uuid,
+        after: u64, // Only fills with a higher fill_seq; 0 for all
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetUserTrades {
+        user_id: Uuid,
+        query: HistoryQuery,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    /// The user's order events, deposits and withdrawals, newest first
+    GetActivity {
+        user_id: Uuid,
+        query: HistoryQuery,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetStoredOrders {
+        user_id: Uuid,
+        query: HistoryQuery,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetSourceVolume {
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetFeedActivity {
+        intervals: usize,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetPriceAverages {
+        window: Duration,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetAccountSummary {
+        user_id: Uuid,
+        fills: usize, // Recent fills to include
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetDashboard {
+        depth: usize,
+        trades: usize,
+        users: usize,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetTrialBalance {
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetJournals {
+        kind: Option<JournalKind>, // None for every kind
+        limit: usize,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetFxRates {
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetIncidents {
+        open_only: bool,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetDeadLetters {
+        pending_only: bool,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetRecoveryReport {
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetInterestSummary {
+        user_id: Uuid,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+
+    GetLeverageSettings {
+        user_id: Uuid,
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetLeverageTiers {
+        user_id: Option<Uuid>, // None = the default table
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    GetLiquidationPreview {
+        user_id: Uuid,
+        delta_qty: f64,           // Signed change in base position, negative = sell
+        delta_price: Option<f64>, // Execution price of the change, defaults to mark
+        deadline: Instant,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+
+    // Margin commands
+    SetUserLeverage {
+        user_id: Uuid,
+        max_leverage: Option<f64>, // None clears the user's cap
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    SetLeverageTiers {
+        user_id: Option<Uuid>,        // None = the default table
+        tiers: Option<LeverageTiers>, // None removes a user override
         response_tx: oneshot::Sender<OrderBookResponse>,
     },
 
@@ -42,6 +331,360 @@ pub enum OrderBookCommand {
         amount: f64,
         response_tx: oneshot::Sender<OrderBookResponse>,
     },
+    WithdrawFunds {
+        user_id: Uuid,
+        currency: String,
+        amount: f64,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    /// Put back a withdrawal whose payout failed, reversing its journal
+    ReverseWithdrawal {
+        user_id: Uuid,
+        journal_id: u64,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    SetInterestOptIn {
+        user_id: Uuid,
+        opted_in: bool,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+
+    // Operator commands
+    SetTradingStatus {
+        market: String,
+        status: TradingStatus,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    /// Close a dated market now, at `price` or its own settlement price
+    SettleMarket {
+        market: String,
+        price: Option<Price>,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    SetTradeThroughBand {
+        market: String,
+        band: TradeThroughBand,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    DefineSyntheticIndex {
+        index: SyntheticIndex, // Replaces any index with the same symbol
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    RemoveSyntheticIndex {
+        symbol: String,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    ForceCancelOrder {
+        order_id: Uuid, // Cancelled on behalf of whoever owns it
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    ResolveIncident {
+        id: u64,
+        note: String, // What the operator found and did
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    RetryDeadLetter {
+        id: u64,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    CompensateDeadLetter {
+        id: u64,
+        note: String, // Why the operator gave up on settling it
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    SetFxRate {
+        base: String,
+        quote: String,
+        rate: f64, // Units of quote per unit of base
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    RemoveFxRate {
+        base: String,
+        quote: String,
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    AdjustBalance {
+        user_id: Uuid,
+        currency: String,
+        amount: f64, // Signed; a debit may not take the balance below zero
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+    AdvanceClock {
+        by: Duration, // Zero just reads the clock
+        response_tx: oneshot::Sender<OrderBookResponse>,
+    },
+}
+
+impl OrderBookCommand {
+    pub fn is_cancel(&self) -> bool {
+        matches!(
+            self,
+            OrderBookCommand::CancelOrder { .. }
+                | OrderBookCommand::CancelOrderByClientId { .. }
+                | OrderBookCommand::ForceCancelOrder { .. }
+        )
+    }
+
+    /// Answer the command without running it, e.g. when it could not be logged
+    pub fn reject(self, message: String) {
+        let response_tx = match self {
+            OrderBookCommand::PlaceLimitOrder { response_tx, .. }
+            | OrderBookCommand::PlacePeggedOrder { response_tx, .. }
+            | OrderBookCommand::PlaceMarketOrder { response_tx, .. }
+            | OrderBookCommand::PlaceStopOrder { response_tx, .. }
+            | OrderBookCommand::CancelOrder { response_tx, .. }
+            | OrderBookCommand::CancelOrderByClientId { response_tx, .. }
+            | OrderBookCommand::MassCancel { response_tx, .. }
+            | OrderBookCommand::CancelAllAfter { response_tx, .. }
+            | OrderBookCommand::PlaceOrderBatch { response_tx, .. }
+            | OrderBookCommand::AmendOrder { response_tx, .. }
+            | OrderBookCommand::GetOrderBook { response_tx, .. }
+            | OrderBookCommand::GetFullBook { response_tx, .. }
+            | OrderBookCommand::GetImbalance { response_tx, .. }
+            | OrderBookCommand::GetHeatmap { response_tx, .. }
+            | OrderBookCommand::GetMarketConfig { response_tx, .. }
+            | OrderBookCommand::GetMarketState { response_tx, .. }
+            | OrderBookCommand::GetSyntheticIndices { response_tx, .. }
+            | OrderBookCommand::GetMarketSnapshot { response_tx, .. }
+            | OrderBookCommand::GetUserBalance { response_tx, .. }
+            | OrderBookCommand::GetDailyStats { response_tx, .. }
+            | OrderBookCommand::GetTradeTape { response_tx, .. }
+            | OrderBookCommand::GetRecentTrades { response_tx, .. }
+            | OrderBookCommand::GetExecutionQuality { response_tx, .. }
+            | OrderBookCommand::GetOrderHistory { response_tx, .. }
+            | OrderBookCommand::GetOrderByClientId { response_tx, .. }
+            | OrderBookCommand::GetOrderFills { response_tx, .. }
+            | OrderBookCommand::GetUserTrades { response_tx, .. }
+            | OrderBookCommand::GetActivity { response_tx, .. }
+            | OrderBookCommand::GetStoredOrders { response_tx, .. }
+            | OrderBookCommand::GetSourceVolume { response_tx, .. }
+            | OrderBookCommand::GetFeedActivity { response_tx, .. }
+            | OrderBookCommand::GetPriceAverages { response_tx, .. }
+            | OrderBookCommand::GetAccountSummary { response_tx, .. }
+            | OrderBookCommand::GetDashboard { response_tx, .. }
+            | OrderBookCommand::GetTrialBalance { response_tx, .. }
+            | OrderBookCommand::GetJournals { response_tx, .. }
+            | OrderBookCommand::GetFxRates { response_tx, .. }
+            | OrderBookCommand::GetIncidents { response_tx, .. }
+            | OrderBookCommand::GetDeadLetters { response_tx, .. }
+            | OrderBookCommand::GetRecoveryReport { response_tx, .. }
+            | OrderBookCommand::GetInterestSummary { response_tx, .. }
+            | OrderBookCommand::GetLeverageSettings { response_tx, .. }
+            | OrderBookCommand::GetLeverageTiers { response_tx, .. }
+            | OrderBookCommand::GetLiquidationPreview { response_tx, .. }
+            | OrderBookCommand::SetUserLeverage { response_tx, .. }
+            | OrderBookCommand::SetLeverageTiers { response_tx, .. }
+            | OrderBookCommand::AddFunds { response_tx, .. }
+            | OrderBookCommand::WithdrawFunds { response_tx, .. }
+            | OrderBookCommand::ReverseWithdrawal { response_tx, .. }
+            | OrderBookCommand::SetInterestOptIn { response_tx, .. }
+            | OrderBookCommand::SetTradingStatus { response_tx, .. }
+            | OrderBookCommand::SettleMarket { response_tx, .. }
+            | OrderBookCommand::SetTradeThroughBand { response_tx, .. }
+            | OrderBookCommand::DefineSyntheticIndex { response_tx, .. }
+            | OrderBookCommand::RemoveSyntheticIndex { response_tx, .. }
+            | OrderBookCommand::ForceCancelOrder { response_tx, .. }
+            | OrderBookCommand::ResolveIncident { response_tx, .. }
+            | OrderBookCommand::RetryDeadLetter { response_tx, .. }
+            | OrderBookCommand::CompensateDeadLetter { response_tx, .. }
+            | OrderBookCommand::SetFxRate { response_tx, .. }
+            | OrderBookCommand::RemoveFxRate { response_tx, .. }
+            | OrderBookCommand::AdjustBalance { response_tx, .. }
+            | OrderBookCommand::AdvanceClock { response_tx, .. } => response_tx,
+        };
+        let _ = response_tx.send(OrderBookResponse::Error { message });
+    }
+
+    /// Whether the engine may skip this command without processing it.
+    /// Only read-only queries are ever skipped; mutations always run to completion
+    /// so a disconnected client never leaves the book half-updated.
+    pub fn is_abandoned(&self, now: Instant) -> Option<AbandonReason> {
+        let (deadline, response_tx) = match self {
+            OrderBookCommand::GetOrderBook {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetFullBook {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetImbalance {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetHeatmap {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetMarketConfig {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetMarketState {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetSyntheticIndices {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetMarketSnapshot {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetUserBalance {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetDailyStats {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetTradeTape {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetRecentTrades {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetExecutionQuality {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetOrderHistory {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetOrderByClientId {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetOrderFills {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetUserTrades {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetActivity {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetStoredOrders {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetSourceVolume {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetFeedActivity {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetPriceAverages {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetAccountSummary {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetDashboard {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetTrialBalance {
+                deadline,
+                response_tx,
+            }
+            | OrderBookCommand::GetJournals {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetFxRates {
+                deadline,
+                response_tx,
+            }
+            | OrderBookCommand::GetIncidents {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetDeadLetters {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetRecoveryReport {
+                deadline,
+                response_tx,
+            }
+            | OrderBookCommand::GetInterestSummary {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetLeverageSettings {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetLeverageTiers {
+                deadline,
+                response_tx,
+                ..
+            }
+            | OrderBookCommand::GetLiquidationPreview {
+                deadline,
+                response_tx,
+                ..
+            } => (deadline, response_tx),
+            _ => return None,
+        };
+
+        if response_tx.is_closed() {
+            Some(AbandonReason::Cancelled)
+        } else if now >= *deadline {
+            Some(AbandonReason::Expired)
+        } else {
+            None
+        }
+    }
+}
+
+/// Why a query was dropped before reaching the orderbook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbandonReason {
+    Expired,
+    Cancelled,
 }
 
 /// Responses sent from OrderBook engine thread back to HTTP handlers
@@ -52,20 +695,152 @@ pub enum OrderBookResponse {
         order_id: Uuid,
         trades: Vec<Trade>,
         status: String,
+        timings: OrderTimings,
     },
     OrderCancelled {
         order_id: Uuid,
         success: bool,
     },
+    OrdersCancelled {
+        order_ids: Vec<Uuid>,
+    },
+    CancelAllAfterSet {
+        cancel_at: Option<DateTime<Utc>>, // None when disarmed
+    },
+    BatchPlaced {
+        results: Vec<OrderBookResponse>, // One OrderPlaced or Error per order, in request order
+    },
 
     // Query responses
     OrderBookDepth {
-        bids: Vec<(Price, Quantity)>,
-        asks: Vec<(Price, Quantity)>,
+        bids: Vec<DepthLevel>,
+        asks: Vec<DepthLevel>,
+    },
+    OrderBookByOrder {
+        bids: Vec<OrderEntry>,
+        asks: Vec<OrderEntry>,
+    },
+    FullBook {
+        bids: Vec<BookOrder>,
+        asks: Vec<BookOrder>,
+    },
+    Heatmap {
+        heatmap: Option<Heatmap>, // None for an empty book
+    },
+    Imbalance {
+        mid_price: Option<f64>,
+        bids: DepthTotals,
+        asks: DepthTotals,
+    },
+    MarketConfig {
+        config: MarketConfig,
+    },
+    MarketState {
+        state: MarketState,
+    },
+    MarketSnapshot {
+        seq: u64, // Feed message the snapshot is current as of
+        bids: Vec<DepthLevel>,
+        asks: Vec<DepthLevel>,
+        state: MarketState,
     },
     UserBalance {
         balance: UserBalance,
     },
+    DailyStats {
+        stats: Vec<DailyMarketStats>,
+    },
+    TradeTape {
+        page: TapePage,
+    },
+    ExecutionQuality {
+        stats: UserExecutionQuality,
+    },
+    OrderHistory {
+        orders: Vec<Order>,
+    },
+    Order {
+        order: Order,
+    },
+    OrderFills {
+        order_id: Uuid,
+        last_fill_seq: u64, // Fills the order has had in total
+        fills: Vec<OrderFill>,
+    },
+    UserTrades {
+        fills: Vec<UserTrade>,
+        trades: usize, // Trades the fills came from, for paging by offset
+    },
+    Activity {
+        entries: Vec<ActivityEntry>,
+    },
+    SourceVolume {
+        stats: Vec<SourceVolume>,
+    },
+    FeedActivity {
+        activity: FeedActivity,
+    },
+    PriceAverages {
+        averages: PriceAverages,
+    },
+    AccountSummary {
+        summary: AccountSummary,
+    },
+    Dashboard {
+        snapshot: DashboardSnapshot,
+    },
+    TrialBalance {
+        report: TrialBalance,
+    },
+    Journals {
+        journals: Vec<Journal>, // Newest first
+    },
+    FxRates {
+        rates: Vec<FxRate>,
+    },
+    FxRate {
+        rate: FxRate,
+    },
+    Incidents {
+        incidents: Vec<Incident>, // Newest first
+    },
+    Incident {
+        incident: Incident,
+    },
+    DeadLetters {
+        letters: Vec<DeadLetter>, // Newest first
+    },
+    DeadLetter {
+        letter: DeadLetter,
+    },
+    RecoveryReport {
+        report: Option<RecoveryReport>, // None without a command log
+    },
+    InterestSummary {
+        summary: InterestSummary,
+    },
+    LeverageSettings {
+        settings: LeverageSettings,
+    },
+    LeverageTiers {
+        user_id: Option<Uuid>,
+        tiers: LeverageTiers,
+        custom: bool,
+    },
+    LiquidationPreview {
+        current: MarginAssessment,
+        preview: MarginAssessment,
+    },
+    SyntheticIndices {
+        quotes: Vec<SyntheticQuote>, // By symbol
+    },
+    SyntheticIndex {
+        quote: SyntheticQuote,
+    },
+    Clock {
+        now: DateTime<Utc>,
+        offset_ms: u64, // How far ahead of the wall clock the engine runs
+    },
 
     // Balance responses
     FundsAdded {
@@ -73,6 +848,12 @@ pub enum OrderBookResponse {
         currency: String,
         new_balance: f64,
     },
+    FundsWithdrawn {
+        user_id: Uuid,
+        currency: String,
+        new_balance: f64,
+        journal_id: u64, // Reversed if the payout fails
+    },
 
     // Error response
     Error {
@@ -1,21 +1,108 @@
 use crate::orderbook::OrderBook;
-use crate::types::{Order, OrderSide, Trade};
+use crate::types::{Order, OrderSide, Price, Quantity, SweepLimit, Trade};
 use std::cmp::Reverse;
 
+/// Tracks how much of the book a single market order has consumed
+struct SweepBudget {
+    limit: SweepLimit,
+    side: OrderSide,
+    worst_price: Option<Price>, // From the order's slippage guard
+    levels: usize,
+    last_price: Option<Price>,
+    notional: f64,
+}
+
+impl SweepBudget {
+    fn new(limit: SweepLimit, taker_order: &Order) -> Self {
+        SweepBudget {
+            limit,
+            side: taker_order.side,
+            worst_price: taker_order.worst_price,
+            levels: 0,
+            last_price: None,
+            notional: 0.0,
+        }
+    }
+
+    /// Largest quantity that may still be taken at `price`; zero means stop
+    fn allowance(&self, price: Price, wanted: Quantity) -> Quantity {
+        let beyond_worst = match (self.side, self.worst_price) {
+            (OrderSide::Buy, Some(worst)) => price > worst,
+            (OrderSide::Sell, Some(worst)) => price < worst,
+            (_, None) => false,
+        };
+        if beyond_worst {
+            return Quantity::new(0);
+        }
+
+        if self.last_price != Some(price) {
+            if let Some(max_levels) = self.limit.max_levels {
+                if self.levels >= max_levels {
+                    return Quantity::new(0);
+                }
+            }
+        }
+
+        match self.limit.max_notional {
+            Some(max_notional) => {
+                let left = (max_notional - self.notional).max(0.0);
+                let scale = 10f64.powi(Quantity::DECIMALS as i32);
+                let cap = Quantity::new((left / price.to_f64() * scale).floor() as u64);
+                std::cmp::min(wanted, cap)
+            }
+            None => wanted,
+        }
+    }
+
+    fn record(&mut self, price: Price, quantity: Quantity) {
+        if self.last_price != Some(price) {
+            self.levels += 1;
+            self.last_price = Some(price);
+        }
+        self.notional += price.to_f64() * quantity.to_f64();
+    }
+}
+
 impl OrderBook {
+    /// What a market buy would pay sweeping the asks as far as the sweep
+    /// limit and its slippage guard allow, counting hidden and iceberg
+    /// quantity. An upper bound: a maker's minimum fill can only leave less.
+    pub(crate) fn market_buy_cost(&self, taker_order: &Order) -> f64 {
+        let mut budget = SweepBudget::new(self.sweep_limit, taker_order);
+        let mut wanted = taker_order.remaining_quantity;
+        let mut cost = 0.0;
+        for (price, level) in &self.asks {
+            if wanted.is_zero() {
+                break;
+            }
+            let available = level.orders.iter().fold(Quantity::new(0), |total, order| {
+                total + order.remaining_quantity
+            });
+            let take = budget.allowance(*price, std::cmp::min(wanted, available));
+            if take.is_zero() {
+                break;
+            }
+            budget.record(*price, take);
+            cost += price.to_f64() * take.to_f64();
+            wanted -= take;
+        }
+        cost
+    }
+
+    /// Sweep the opposite side for a market order. Whatever is left once the
+    /// market's sweep limit or the order's worst price is reached is cancelled
+    /// rather than filled deeper.
     pub(crate) fn match_market_order(
         &mut self,
         taker_order: &mut Order,
     ) -> Result<Vec<Trade>, String> {
-        let mut trades = Vec::new();
+        let trades = match taker_order.side {
+            OrderSide::Buy => self.match_market_buy(taker_order)?,
+            OrderSide::Sell => self.match_market_sell(taker_order)?,
+        };
 
-        match taker_order.side {
-            OrderSide::Buy => {
-                trades = self.match_market_buy(taker_order)?;
-            }
-            OrderSide::Sell => {
-                trades = self.match_market_sell(taker_order)?;
-            }
+        if !taker_order.is_fully_filled() {
+            taker_order.cancel();
         }
 
         Ok(trades)
@@ -24,30 +111,44 @@ impl OrderBook {
     // Match a market buy order (taker buys at best ask prices)
     fn match_market_buy(&mut self, taker_order: &mut Order) -> Result<Vec<Trade>, String> {
         let mut trades = Vec::new();
+        let mut budget = SweepBudget::new(self.sweep_limit, taker_order);
 
         while !taker_order.is_fully_filled() {
-            let best_ask_price = match self.best_ask() {
-                Some(price) => price,
-                None => return Err("Insufficient liquidity for market order".to_string()),
-            };
+            let (best_ask_price, index) =
+                match self.best_eligible_ask(taker_order.remaining_quantity) {
+                    Some(found) => found,
+                    None if trades.is_empty() => {
+                        return Err("Insufficient liquidity for market order".to_string())
+                    }
+                    // What has filled has settled; the rest is cancelled
+                    None => break,
+                };
+
+            // The sweep limit or slippage guard may stop here, or leave less
+            // than the maker's minimum fill
+            let allowance = budget.allowance(best_ask_price, taker_order.remaining_quantity);
+            if allowance.is_zero()
+                || !self.asks[&best_ask_price].orders[index].accepts_fill(allowance)
+            {
+                break;
+            }
 
             let (trade, maker_id, maker_filled) = {
                 let price_level = self.asks.get_mut(&best_ask_price).unwrap();
 
-                if let Some(maker_order) = price_level.front_mut() {
-                    let fill_quantity = std::cmp::min(
-                        taker_order.remaining_quantity,
-                        maker_order.remaining_quantity,
-                    );
+                if let Some(maker_order) = price_level.order_mut(index) {
+                    let fill_quantity = std::cmp::min(allowance, maker_order.visible_quantity());
 
                     let maker_id = maker_order.id;
                     let maker_user_id = maker_order.user_id;
+                    let maker_source = maker_order.source;
 
                     maker_order.fill(fill_quantity);
                     taker_order.fill(fill_quantity);
 
+                    let maker_fill_seq = maker_order.fill_count;
                     let maker_filled = maker_order.is_fully_filled();
-                    price_level.update_volume(fill_quantity);
+                    price_level.record_fill(index, fill_quantity);
 
                     let trade = Trade::new(
                         maker_id,
@@ -56,7 +157,11 @@ impl OrderBook {
                         taker_order.user_id,
                         best_ask_price,
                         fill_quantity,
-                    );
+                    )
+                    .with_sources(maker_source, taker_order.source)
+                    .with_fill_seqs(maker_fill_seq, taker_order.fill_count)
+                    .with_taker_side(taker_order.side)
+                    .stamped(self.now);
 
                     (Some(trade), maker_id, maker_filled)
                 } else {
@@ -64,21 +169,22 @@ impl OrderBook {
                 }
             };
 
-            if let Some(trade) = trade {
-                self.execute_trade_settlement(&trade, OrderSide::Buy)?;
+            if let Some(mut trade) = trade {
+                budget.record(trade.price, trade.quantity);
+                self.settle_matched_trade(&mut trade, OrderSide::Buy);
                 trades.push(trade);
 
                 if maker_filled {
-                    self.orders.remove(&maker_id);
+                    self.remove_resting(maker_id);
                 } else if let Some(price_level) = self.asks.get(&best_ask_price) {
-                    if let Some(maker_order) = price_level.front() {
+                    if let Some(maker_order) = price_level.get(maker_id) {
                         self.orders.insert(maker_id, maker_order.clone());
                     }
                 }
             }
 
             if let Some(price_level) = self.asks.get_mut(&best_ask_price) {
-                price_level.pop_if_filled();
+                price_level.remove_if_filled(index);
 
                 if price_level.is_empty() {
                     self.asks.remove(&best_ask_price);
@@ -91,30 +197,44 @@ impl OrderBook {
 
     fn match_market_sell(&mut self, taker_order: &mut Order) -> Result<Vec<Trade>, String> {
         let mut trades = Vec::new();
+        let mut budget = SweepBudget::new(self.sweep_limit, taker_order);
 
         while !taker_order.is_fully_filled() {
-            let best_bid_price = match self.best_bid() {
-                Some(price) => price,
-                None => return Err("Insufficient liquidity for market order".to_string()),
-            };
+            let (best_bid_price, index) =
+                match self.best_eligible_bid(taker_order.remaining_quantity) {
+                    Some(found) => found,
+                    None if trades.is_empty() => {
+                        return Err("Insufficient liquidity for market order".to_string())
+                    }
+                    // What has filled has settled; the rest is cancelled
+                    None => break,
+                };
+
+            // The sweep limit or slippage guard may stop here, or leave less
+            // than the maker's minimum fill
+            let allowance = budget.allowance(best_bid_price, taker_order.remaining_quantity);
+            if allowance.is_zero()
+                || !self.bids[&Reverse(best_bid_price)].orders[index].accepts_fill(allowance)
+            {
+                break;
+            }
 
             let (trade, maker_id, maker_filled) = {
                 let price_level = self.bids.get_mut(&Reverse(best_bid_price)).unwrap();
 
-                if let Some(maker_order) = price_level.front_mut() {
-                    let fill_quantity = std::cmp::min(
-                        taker_order.remaining_quantity,
-                        maker_order.remaining_quantity,
-                    );
+                if let Some(maker_order) = price_level.order_mut(index) {
+                    let fill_quantity = std::cmp::min(allowance, maker_order.visible_quantity());
 
                     let maker_id = maker_order.id;
                     let maker_user_id = maker_order.user_id;
+                    let maker_source = maker_order.source;
 
                     maker_order.fill(fill_quantity);
                     taker_order.fill(fill_quantity);
 
+                    let maker_fill_seq = maker_order.fill_count;
                     let maker_filled = maker_order.is_fully_filled();
-                    price_level.update_volume(fill_quantity);
+                    price_level.record_fill(index, fill_quantity);
 
                     let trade = Trade::new(
                         maker_id,
@@ -123,7 +243,11 @@ impl OrderBook {
                         taker_order.user_id,
                         best_bid_price,
                         fill_quantity,
-                    );
+                    )
+                    .with_sources(maker_source, taker_order.source)
+                    .with_fill_seqs(maker_fill_seq, taker_order.fill_count)
+                    .with_taker_side(taker_order.side)
+                    .stamped(self.now);
 
                     (Some(trade), maker_id, maker_filled)
                 } else {
@@ -131,21 +255,22 @@ impl OrderBook {
                 }
             };
 
-            if let Some(trade) = trade {
-                self.execute_trade_settlement(&trade, OrderSide::Sell)?;
+            if let Some(mut trade) = trade {
+                budget.record(trade.price, trade.quantity);
+                self.settle_matched_trade(&mut trade, OrderSide::Sell);
                 trades.push(trade);
 
                 if maker_filled {
-                    self.orders.remove(&maker_id);
+                    self.remove_resting(maker_id);
                 } else if let Some(price_level) = self.bids.get(&Reverse(best_bid_price)) {
-                    if let Some(maker_order) = price_level.front() {
+                    if let Some(maker_order) = price_level.get(maker_id) {
                         self.orders.insert(maker_id, maker_order.clone());
                     }
                 }
             }
 
             if let Some(price_level) = self.bids.get_mut(&Reverse(best_bid_price)) {
-                price_level.pop_if_filled();
+                price_level.remove_if_filled(index);
 
                 if price_level.is_empty() {
                     self.bids.remove(&Reverse(best_bid_price));
@@ -156,3 +281,356 @@ impl OrderBook {
         Ok(trades)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orderbook::{DepthLevel, DepthTotals, DepthWindow};
+    use crate::types::OrderStatus;
+    use uuid::Uuid;
+
+    fn book_with_asks(prices: &[f64]) -> OrderBook {
+        let mut book = OrderBook::new();
+        let maker = Uuid::new_v4();
+        book.add_funds(maker, "BTC", 100.0);
+        for &price in prices {
+            book.add_order(Order::new_limit(
+                maker,
+                OrderSide::Sell,
+                Price::from_f64(price),
+                Quantity::from_f64(1.0),
+            ));
+        }
+        book
+    }
+
+    fn market_buy(book: &mut OrderBook, quantity: f64) -> (Order, Vec<Trade>) {
+        let taker = Uuid::new_v4();
+        book.add_funds(taker, "USD", 1_000_000.0);
+        let mut order = Order::new_market(taker, OrderSide::Buy, Quantity::from_f64(quantity));
+        let trades = book.match_order(&mut order).unwrap();
+        (order, trades)
+    }
+
+    #[test]
+    fn test_sweep_stops_at_level_limit() {
+        let mut book = book_with_asks(&[100.0, 101.0, 102.0]);
+        book.sweep_limit.max_levels = Some(2);
+
+        let (order, trades) = market_buy(&mut book, 3.0);
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(order.remaining_quantity, Quantity::from_f64(1.0));
+        assert_eq!(order.status, OrderStatus::Cancelled);
+        assert_eq!(book.best_ask(), Some(Price::from_f64(102.0)));
+    }
+
+    #[test]
+    fn test_user_order_index_follows_fills_and_cancels() {
+        let mut book = book_with_asks(&[100.0, 101.0, 102.0]);
+        let maker = book.orders.values().next().unwrap().user_id;
+        assert_eq!(book.open_order_count(maker), 3);
+
+        market_buy(&mut book, 1.5);
+        let open: Vec<Price> = book.user_orders(maker).filter_map(|o| o.price).collect();
+        assert_eq!(open.len(), 2);
+        assert!(!open.contains(&Price::from_f64(100.0)));
+
+        let ids: Vec<Uuid> = book.user_orders(maker).map(|o| o.id).collect();
+        for id in ids {
+            book.cancel_order(id).unwrap();
+        }
+        assert_eq!(book.open_order_count(maker), 0);
+        assert!(book.orders_by_user.is_empty());
+    }
+
+    #[test]
+    fn test_sweep_stops_at_notional_limit() {
+        let mut book = book_with_asks(&[100.0, 200.0]);
+        book.sweep_limit.max_notional = Some(200.0);
+
+        let (order, trades) = market_buy(&mut book, 2.0);
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(trades[1].quantity, Quantity::from_f64(0.5));
+        assert_eq!(order.remaining_quantity, Quantity::from_f64(0.5));
+        assert_eq!(order.status, OrderStatus::Cancelled);
+    }
+
+    #[test]
+    fn test_sweep_stops_beyond_worst_price() {
+        let mut book = book_with_asks(&[100.0, 101.0, 102.0]);
+        let taker = Uuid::new_v4();
+        book.add_funds(taker, "USD", 1_000_000.0);
+        let mut order = Order::new_market(taker, OrderSide::Buy, Quantity::from_f64(3.0))
+            .with_worst_price(Some(Price::from_f64(101.0)));
+
+        let trades = book.match_order(&mut order).unwrap();
+
+        assert_eq!(trades.len(), 2);
+        assert_eq!(order.remaining_quantity, Quantity::from_f64(1.0));
+        assert_eq!(order.status, OrderStatus::Cancelled);
+        assert_eq!(book.best_ask(), Some(Price::from_f64(102.0)));
+    }
+
+    #[test]
+    fn test_iceberg_refills_behind_orders_at_its_price() {
+        let mut book = OrderBook::new();
+        let maker = Uuid::new_v4();
+        book.add_funds(maker, "BTC", 100.0);
+        let iceberg = Order::new_limit(
+            maker,
+            OrderSide::Sell,
+            Price::from_f64(100.0),
+            Quantity::from_f64(5.0),
+        )
+        .with_display_quantity(Some(Quantity::from_f64(1.0)));
+        let plain = Order::new_limit(
+            maker,
+            OrderSide::Sell,
+            Price::from_f64(100.0),
+            Quantity::from_f64(1.0),
+        );
+        book.add_order(iceberg.clone());
+        book.add_order(plain.clone());
+        assert_eq!(book.get_depth(1).1[0].quantity, Quantity::from_f64(2.0));
+
+        let (_, trades) = market_buy(&mut book, 2.5);
+
+        let makers: Vec<_> = trades.iter().map(|t| t.maker_order_id).collect();
+        assert_eq!(makers, vec![iceberg.id, plain.id, iceberg.id]);
+        assert_eq!(book.get_depth(1).1[0].quantity, Quantity::from_f64(0.5));
+        let resting = book.get_order(iceberg.id).unwrap();
+        assert_eq!(resting.remaining_quantity, Quantity::from_f64(3.5));
+        assert_eq!(resting.visible_quantity(), Quantity::from_f64(0.5));
+    }
+
+    #[test]
+    fn test_hidden_order_fills_after_displayed_and_stays_off_depth() {
+        let mut book = OrderBook::new();
+        let maker = Uuid::new_v4();
+        book.add_funds(maker, "BTC", 100.0);
+        let order = |price: f64| {
+            Order::new_limit(
+                maker,
+                OrderSide::Sell,
+                Price::from_f64(price),
+                Quantity::from_f64(1.0),
+            )
+        };
+        let hidden = order(100.0).with_hidden(true);
+        let displayed = order(100.0);
+        let hidden_only = order(101.0).with_hidden(true);
+        let above = order(102.0);
+        book.add_order(hidden.clone());
+        book.add_order(displayed.clone());
+        book.add_order(hidden_only.clone());
+        book.add_order(above.clone());
+
+        let (_, asks) = book.get_depth(2);
+        assert_eq!(
+            asks,
+            vec![
+                DepthLevel::new(Price::from_f64(100.0), Quantity::from_f64(1.0), 1),
+                DepthLevel::new(Price::from_f64(102.0), Quantity::from_f64(1.0), 1),
+            ]
+        );
+        let (_, entries) = book.get_order_depth(5);
+        let ids: Vec<_> = entries.iter().map(|e| e.order_id).collect();
+        assert_eq!(ids, vec![displayed.id, above.id]);
+
+        // Hidden liquidity still trades, behind the displayed order that came later
+        let (_, trades) = market_buy(&mut book, 2.5);
+        let makers: Vec<_> = trades.iter().map(|t| t.maker_order_id).collect();
+        assert_eq!(makers, vec![displayed.id, hidden.id, hidden_only.id]);
+        assert_eq!(
+            book.get_order(hidden_only.id).unwrap().remaining_quantity,
+            Quantity::from_f64(0.5)
+        );
+        assert_eq!(book.get_depth(1).1[0].price, Price::from_f64(102.0));
+        // The hidden remainder trades next, but the public quote skips it
+        assert_eq!(book.best_ask(), Some(Price::from_f64(101.0)));
+        assert_eq!(book.bbo_snapshot().best_ask, Some(Price::from_f64(102.0)));
+        assert_eq!(book.mark_price(), Some(102.0));
+        assert_eq!(book.reference_bbo(), (None, Some(Price::from_f64(102.0))));
+    }
+
+    #[test]
+    fn test_full_book_shows_queue_positions_reserves_and_hidden_orders() {
+        let mut book = OrderBook::new();
+        let maker = Uuid::new_v4();
+        book.add_funds(maker, "BTC", 100.0);
+        let order = |price: f64, quantity: f64| {
+            Order::new_limit(
+                maker,
+                OrderSide::Sell,
+                Price::from_f64(price),
+                Quantity::from_f64(quantity),
+            )
+        };
+        let hidden = order(100.0, 1.0).with_hidden(true);
+        let iceberg = order(100.0, 5.0).with_display_quantity(Some(Quantity::from_f64(1.0)));
+        let hidden_only = order(101.0, 1.0).with_hidden(true);
+        book.add_order(hidden.clone());
+        book.add_order(iceberg.clone());
+        book.add_order(hidden_only.clone());
+
+        let (bids, asks) = book.get_full_book(10);
+        assert!(bids.is_empty());
+        let queue: Vec<_> = asks
+            .iter()
+            .map(|o| (o.order_id, o.queue_position))
+            .collect();
+        assert_eq!(
+            queue,
+            vec![(iceberg.id, 0), (hidden.id, 1), (hidden_only.id, 0)]
+        );
+        assert_eq!(asks[0].displayed_quantity, Quantity::from_f64(1.0));
+        assert_eq!(asks[0].reserve_quantity, Quantity::from_f64(4.0));
+        assert_eq!(asks[1].displayed_quantity, Quantity::from_f64(0.0));
+        assert!(asks[1].hidden);
+
+        // Levels count whether or not anything on them is displayed
+        assert_eq!(book.get_full_book(1).1.len(), 2);
+    }
+
+    #[test]
+    fn test_heatmap_bins_displayed_volume_around_the_mid() {
+        let mut book = book_with_asks(&[101.0, 101.5, 104.0, 120.0]);
+        let buyer = Uuid::new_v4();
+        book.add_funds(buyer, "USD", 10_000.0);
+        for (price, hidden) in [(99.0, false), (99.0, true), (97.0, false)] {
+            let order = Order::new_limit(
+                buyer,
+                OrderSide::Buy,
+                Price::from_f64(price),
+                Quantity::from_f64(2.0),
+            );
+            book.add_order(order.with_hidden(hidden));
+        }
+
+        // Mid is 100, so four buckets of 2.5 cover 95 to 105
+        let heatmap = book.get_heatmap(4, 5.0).unwrap();
+        assert_eq!(heatmap.mid_price, 100.0);
+        assert_eq!(heatmap.bucket_width, 2.5);
+        let volumes: Vec<(f64, f64)> = heatmap
+            .buckets
+            .iter()
+            .map(|b| (b.bid_quantity.to_f64(), b.ask_quantity.to_f64()))
+            .collect();
+        assert_eq!(
+            volumes,
+            vec![(2.0, 0.0), (2.0, 0.0), (0.0, 2.0), (0.0, 1.0)]
+        );
+
+        assert!(OrderBook::new().get_heatmap(4, 5.0).is_none());
+    }
+
+    #[test]
+    fn test_depth_totals_by_levels_or_distance_from_mid() {
+        let mut book = book_with_asks(&[101.0, 101.0, 102.0, 110.0]);
+        let buyer = Uuid::new_v4();
+        book.add_funds(buyer, "USD", 10_000.0);
+        for (price, hidden) in [(99.0, false), (98.0, true), (90.0, false)] {
+            let order = Order::new_limit(
+                buyer,
+                OrderSide::Buy,
+                Price::from_f64(price),
+                Quantity::from_f64(2.0),
+            );
+            book.add_order(order.with_hidden(hidden));
+        }
+
+        let (bids, asks) = book.depth_totals(DepthWindow::Levels(2));
+        assert_eq!((bids.orders, bids.quantity), (2, Quantity::from_f64(4.0)));
+        assert_eq!((asks.orders, asks.quantity), (3, Quantity::from_f64(3.0)));
+        assert_eq!(DepthTotals::volume_imbalance(&bids, &asks), Some(1.0 / 7.0));
+
+        // Mid is 100, so 5% reaches 95 to 105
+        let (bids, asks) = book.depth_totals(DepthWindow::Percent(5.0));
+        assert_eq!(bids.quantity, Quantity::from_f64(2.0));
+        assert_eq!(asks.quantity, Quantity::from_f64(3.0));
+
+        let (bids, asks) = OrderBook::new().depth_totals(DepthWindow::Percent(5.0));
+        assert_eq!(DepthTotals::volume_imbalance(&bids, &asks), None);
+    }
+
+    #[test]
+    fn test_depth_counts_orders_and_totals_each_side() {
+        let mut book = book_with_asks(&[100.0, 100.0, 101.0]);
+        let buyer = Uuid::new_v4();
+        book.add_funds(buyer, "USD", 1_000.0);
+        book.add_order(Order::new_limit(
+            buyer,
+            OrderSide::Buy,
+            Price::from_f64(99.0),
+            Quantity::from_f64(1.0),
+        ));
+
+        let (bids, asks) = book.get_depth(5);
+        assert_eq!(asks[0].orders, 2);
+        assert_eq!(asks[0].quantity, Quantity::from_f64(2.0));
+        assert_eq!(asks[1].orders, 1);
+
+        let (bid_totals, ask_totals) = (DepthTotals::of(&bids), DepthTotals::of(&asks));
+        assert_eq!(ask_totals.orders, 3);
+        assert_eq!(ask_totals.quantity, Quantity::from_f64(3.0));
+        assert_eq!(ask_totals.notional, 301.0);
+        assert_eq!(bid_totals.notional, 99.0);
+        assert_eq!(
+            DepthTotals::imbalance(&bid_totals, &ask_totals),
+            Some(-0.505)
+        );
+        assert_eq!(
+            DepthTotals::imbalance(&DepthTotals::default(), &DepthTotals::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_small_takers_skip_makers_with_a_larger_minimum_fill() {
+        let mut book = OrderBook::new();
+        let maker = Uuid::new_v4();
+        book.add_funds(maker, "BTC", 100.0);
+        let order = |quantity: f64| {
+            Order::new_limit(
+                maker,
+                OrderSide::Sell,
+                Price::from_f64(100.0),
+                Quantity::from_f64(quantity),
+            )
+        };
+        let block = order(5.0).with_min_fill_qty(Some(Quantity::from_f64(2.0)));
+        let plain = order(1.0);
+        let later = order(1.0);
+        book.add_order(block.clone());
+        book.add_order(plain.clone());
+        book.add_order(later.clone());
+
+        // Too small for the block: the next order in line fills instead
+        let (_, trades) = market_buy(&mut book, 1.0);
+        assert_eq!(trades[0].maker_order_id, plain.id);
+
+        // A limit taker large enough trades with the block first
+        let taker = Uuid::new_v4();
+        book.add_funds(taker, "USD", 1_000.0);
+        let mut limit = Order::new_limit(
+            taker,
+            OrderSide::Buy,
+            Price::from_f64(100.0),
+            Quantity::from_f64(4.5),
+        );
+        let trades = book.match_order(&mut limit).unwrap();
+        assert_eq!(trades[0].maker_order_id, block.id);
+        assert_eq!(trades[0].quantity, Quantity::from_f64(4.5));
+
+        // Less than the minimum is left, so taking all of it is enough
+        let (_, trades) = market_buy(&mut book, 0.5);
+        assert_eq!(trades[0].maker_order_id, block.id);
+        assert!(book.get_order(block.id).is_none());
+        assert_eq!(
+            book.get_order(later.id).unwrap().remaining_quantity,
+            Quantity::from_f64(1.0)
+        );
+    }
+}
@@ -1,27 +1,45 @@
 use crate::orderbook::OrderBook;
-use crate::types::{Order, OrderSide, OrderType, Quantity, Trade};
+use crate::types::{Order, OrderSide, OrderType, Quantity, TimeInForce, Trade};
 use std::cmp::Reverse;
 
 impl OrderBook {
-    /// Main entry point for matching an order against the orderbook
-    pub fn match_order(&mut self, mut order: Order) -> Result<Vec<Trade>, String> {
-        let mut trades = Vec::new();
-
-        match order.order_type {
+    /// Main entry point for matching an order against the orderbook.
+    /// On return `order` reflects its final state (fills applied, resting or not).
+    pub fn match_order(&mut self, order: &mut Order) -> Result<Vec<Trade>, String> {
+        let trades = match order.order_type {
             OrderType::Limit => {
-                trades = self.match_limit_order(&mut order)?;
+                let trades = self.match_limit_order(order)?;
                 if !order.is_fully_filled() {
-                    self.add_order(order);
+                    match order.time_in_force {
+                        // Orders it passed over for their minimum fill are still
+                        // there; resting across them would cross the book
+                        TimeInForce::GTC if self.would_cross(order) => order.cancel(),
+                        TimeInForce::GTC => {
+                            // An iceberg taker may have traded through its visible slice
+                            order.replenish();
+                            self.add_order(order.clone())
+                        }
+                        // The caller refunds whatever it reserved for the remainder
+                        TimeInForce::IOC => order.cancel(),
+                    }
                 }
+                trades
             }
-            OrderType::Market => {
-                trades = self.match_market_order(&mut order)?;
-            }
-        }
+            OrderType::Market => self.match_market_order(order)?,
+        };
 
         Ok(trades)
     }
 
+    /// Whether `order` would rest at or through the best price on the other side
+    fn would_cross(&self, order: &Order) -> bool {
+        match (order.side, order.price) {
+            (OrderSide::Buy, Some(price)) => self.best_ask().is_some_and(|ask| ask <= price),
+            (OrderSide::Sell, Some(price)) => self.best_bid().is_some_and(|bid| bid >= price),
+            (_, None) => false,
+        }
+    }
+
     fn match_limit_order(&mut self, taker_order: &mut Order) -> Result<Vec<Trade>, String> {
         let mut trades = Vec::new();
         let taker_price = taker_order.price.ok_or("Limit order must have price")?;
@@ -29,10 +47,11 @@ impl OrderBook {
         match taker_order.side {
             OrderSide::Buy => {
                 while !taker_order.is_fully_filled() {
-                    let best_ask_price = match self.best_ask() {
-                        Some(price) => price,
-                        None => break,
-                    };
+                    let (best_ask_price, index) =
+                        match self.best_eligible_ask(taker_order.remaining_quantity) {
+                            Some(found) => found,
+                            None => break,
+                        };
 
                     if best_ask_price > taker_price {
                         break;
@@ -41,20 +60,22 @@ impl OrderBook {
                     let (trade, _fill_quantity, maker_id, maker_filled) = {
                         let price_level = self.asks.get_mut(&best_ask_price).unwrap();
 
-                        if let Some(maker_order) = price_level.front_mut() {
+                        if let Some(maker_order) = price_level.order_mut(index) {
                             let fill_qty = std::cmp::min(
                                 taker_order.remaining_quantity,
-                                maker_order.remaining_quantity,
+                                maker_order.visible_quantity(),
                             );
 
                             let maker_id = maker_order.id;
                             let maker_user_id = maker_order.user_id;
+                            let maker_source = maker_order.source;
 
                             maker_order.fill(fill_qty);
                             taker_order.fill(fill_qty);
 
+                            let maker_fill_seq = maker_order.fill_count;
                             let maker_filled = maker_order.is_fully_filled();
-                            price_level.update_volume(fill_qty);
+                            price_level.record_fill(index, fill_qty);
 
                             let trade = Trade::new(
                                 maker_id,
@@ -63,7 +84,11 @@ impl OrderBook {
                                 taker_order.user_id,
                                 best_ask_price,
                                 fill_qty,
-                            );
+                            )
+                            .with_sources(maker_source, taker_order.source)
+                            .with_fill_seqs(maker_fill_seq, taker_order.fill_count)
+                            .with_taker_side(taker_order.side)
+                            .stamped(self.now);
 
                             (Some(trade), fill_qty, maker_id, maker_filled)
                         } else {
@@ -71,21 +96,21 @@ impl OrderBook {
                         }
                     };
 
-                    if let Some(trade) = trade {
-                        self.execute_trade_settlement(&trade, OrderSide::Buy)?;
+                    if let Some(mut trade) = trade {
+                        self.settle_matched_trade(&mut trade, OrderSide::Buy);
                         trades.push(trade);
 
                         if maker_filled {
-                            self.orders.remove(&maker_id);
+                            self.remove_resting(maker_id);
                         } else if let Some(price_level) = self.asks.get(&best_ask_price) {
-                            if let Some(maker_order) = price_level.front() {
+                            if let Some(maker_order) = price_level.get(maker_id) {
                                 self.orders.insert(maker_id, maker_order.clone());
                             }
                         }
                     }
 
                     if let Some(price_level) = self.asks.get_mut(&best_ask_price) {
-                        price_level.pop_if_filled();
+                        price_level.remove_if_filled(index);
 
                         if price_level.is_empty() {
                             self.asks.remove(&best_ask_price);
@@ -95,10 +120,11 @@ impl OrderBook {
             }
             OrderSide::Sell => {
                 while !taker_order.is_fully_filled() {
-                    let best_bid_price = match self.best_bid() {
-                        Some(price) => price,
-                        None => break,
-                    };
+                    let (best_bid_price, index) =
+                        match self.best_eligible_bid(taker_order.remaining_quantity) {
+                            Some(found) => found,
+                            None => break,
+                        };
 
                     if best_bid_price < taker_price {
                         break;
@@ -107,20 +133,22 @@ impl OrderBook {
                     let (trade, _fill_quantity, maker_id, maker_filled) = {
                         let price_level = self.bids.get_mut(&Reverse(best_bid_price)).unwrap();
 
-                        if let Some(maker_order) = price_level.front_mut() {
+                        if let Some(maker_order) = price_level.order_mut(index) {
                             let fill_qty = std::cmp::min(
                                 taker_order.remaining_quantity,
-                                maker_order.remaining_quantity,
+                                maker_order.visible_quantity(),
                             );
 
                             let maker_id = maker_order.id;
                             let maker_user_id = maker_order.user_id;
+                            let maker_source = maker_order.source;
 
                             maker_order.fill(fill_qty);
                             taker_order.fill(fill_qty);
 
+                            let maker_fill_seq = maker_order.fill_count;
                             let maker_filled = maker_order.is_fully_filled();
-                            price_level.update_volume(fill_qty);
+                            price_level.record_fill(index, fill_qty);
 
                             let trade = Trade::new(
                                 maker_id,
@@ -129,7 +157,11 @@ impl OrderBook {
                                 taker_order.user_id,
                                 best_bid_price,
                                 fill_qty,
-                            );
+                            )
+                            .with_sources(maker_source, taker_order.source)
+                            .with_fill_seqs(maker_fill_seq, taker_order.fill_count)
+                            .with_taker_side(taker_order.side)
+                            .stamped(self.now);
 
                             (Some(trade), fill_qty, maker_id, maker_filled)
                         } else {
@@ -137,21 +169,21 @@ impl OrderBook {
                         }
                     };
 
-                    if let Some(trade) = trade {
-                        self.execute_trade_settlement(&trade, OrderSide::Sell)?;
+                    if let Some(mut trade) = trade {
+                        self.settle_matched_trade(&mut trade, OrderSide::Sell);
                         trades.push(trade);
 
                         if maker_filled {
-                            self.orders.remove(&maker_id);
+                            self.remove_resting(maker_id);
                         } else if let Some(price_level) = self.bids.get(&Reverse(best_bid_price)) {
-                            if let Some(maker_order) = price_level.front() {
+                            if let Some(maker_order) = price_level.get(maker_id) {
                                 self.orders.insert(maker_id, maker_order.clone());
                             }
                         }
                     }
 
                     if let Some(price_level) = self.bids.get_mut(&Reverse(best_bid_price)) {
-                        price_level.pop_if_filled();
+                        price_level.remove_if_filled(index);
 
                         if price_level.is_empty() {
                             self.bids.remove(&Reverse(best_bid_price));
@@ -164,3 +196,94 @@ impl OrderBook {
         Ok(trades)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{OrderStatus, Price};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_a_remainder_that_would_cross_a_skipped_order_is_cancelled() {
+        let mut book = OrderBook::new();
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        book.add_funds(maker, "BTC", 10.0);
+        book.add_funds(taker, "USD", 1_000.0);
+        let block = Order::new_limit(
+            maker,
+            OrderSide::Sell,
+            Price::from_f64(100.0),
+            Quantity::from_f64(5.0),
+        )
+        .with_min_fill_qty(Some(Quantity::from_f64(5.0)));
+        book.add_order(block);
+        book.add_order(Order::new_limit(
+            maker,
+            OrderSide::Sell,
+            Price::from_f64(100.5),
+            Quantity::from_f64(0.2),
+        ));
+
+        // Too small for the block at 100, so it fills at 100.5 instead
+        let mut order = Order::new_limit(
+            taker,
+            OrderSide::Buy,
+            Price::from_f64(101.0),
+            Quantity::from_f64(1.0),
+        );
+        let trades = book.match_order(&mut order).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, Price::from_f64(100.5));
+        // Resting at 101 would sit above the ask at 100
+        assert_eq!(order.status, OrderStatus::Cancelled);
+        assert_eq!(order.remaining_quantity, Quantity::from_f64(0.8));
+        assert_eq!(book.best_bid(), None);
+        assert_eq!(book.best_ask(), Some(Price::from_f64(100.0)));
+
+        // Below the block it rests as usual
+        let mut order = Order::new_limit(
+            taker,
+            OrderSide::Buy,
+            Price::from_f64(99.0),
+            Quantity::from_f64(1.0),
+        );
+        assert!(book.match_order(&mut order).unwrap().is_empty());
+        assert_eq!(order.status, OrderStatus::Open);
+        assert_eq!(book.best_bid(), Some(Price::from_f64(99.0)));
+    }
+
+    #[test]
+    fn test_trade_through_check_counts_what_matching_would_take() {
+        let mut book = OrderBook::new();
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        book.add_funds(maker, "BTC", 10.0);
+        book.add_funds(taker, "USD", 1_000.0);
+        let ask = |price: f64, quantity: f64| {
+            Order::new_limit(
+                maker,
+                OrderSide::Sell,
+                Price::from_f64(price),
+                Quantity::from_f64(quantity),
+            )
+        };
+        // At the touch: a block too big for the taker, then a hidden order
+        book.add_order(ask(100.0, 5.0).with_min_fill_qty(Some(Quantity::from_f64(5.0))));
+        book.add_order(ask(100.0, 1.0).with_hidden(true));
+        book.add_order(ask(102.0, 1.0));
+
+        let (price, bound) = (Price::from_f64(102.0), Price::from_f64(101.0));
+        let beyond = |book: &OrderBook, quantity: f64| {
+            book.would_trade_beyond(OrderSide::Buy, price, Quantity::from_f64(quantity), bound)
+        };
+        // The hidden order fills 1, so only a larger order reaches 102. The
+        // block would fill 5 more but a taker of 2 can't meet its minimum.
+        assert!(!beyond(&book, 1.0));
+        assert!(beyond(&book, 2.0));
+        assert!(!beyond(&book, 6.0));
+
+        let mut order = Order::new_limit(taker, OrderSide::Buy, price, Quantity::from_f64(2.0));
+        let trades = book.match_order(&mut order).unwrap();
+        let prices: Vec<Price> = trades.iter().map(|trade| trade.price).collect();
+        assert_eq!(prices, vec![Price::from_f64(100.0), Price::from_f64(102.0)]);
+    }
+}
@@ -1,8 +1,10 @@
 pub mod market_matching;
 pub mod matching;
+#[allow(clippy::module_inception)]
 pub mod orderbook;
 pub mod price_level;
 pub mod settlement;
 
 pub use orderbook::*;
 pub use price_level::*;
+pub use settlement::*;
@@ -1,14 +1,205 @@
-use crate::orderbook::PriceLevel;
-use crate::types::{Order, OrderSide, Price, Quantity, UserBalance};
+use crate::orderbook::{BalanceViolation, PriceLevel};
+use crate::types::{
+    AllocationPolicy, BboSnapshot, FeeSchedule, Order, OrderSide, Price, Quantity, SweepLimit,
+    Trade, UserBalance,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
 use std::cmp::Reverse;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
 use uuid::Uuid;
 
+/// A single aggregated depth entry: price, total displayed quantity and the
+/// number of displayed orders making it up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DepthLevel {
+    pub price: Price,
+    pub quantity: Quantity,
+    pub orders: usize,
+}
+
+impl DepthLevel {
+    pub fn new(price: Price, quantity: Quantity, orders: usize) -> Self {
+        DepthLevel {
+            price,
+            quantity,
+            orders,
+        }
+    }
+
+    /// Quote value of the level
+    pub fn notional(&self) -> f64 {
+        self.price.to_f64() * self.quantity.to_f64()
+    }
+}
+
+/// What the given levels of one side add up to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DepthTotals {
+    pub orders: usize,
+    pub quantity: Quantity,
+    pub notional: f64, // Quote currency
+}
+
+impl DepthTotals {
+    pub fn of(levels: &[DepthLevel]) -> Self {
+        levels
+            .iter()
+            .fold(DepthTotals::default(), |mut totals, level| {
+                totals.orders += level.orders;
+                totals.quantity += level.quantity;
+                totals.notional += level.notional();
+                totals
+            })
+    }
+
+    /// The same totals for a market-by-order view, one order per entry
+    pub fn of_orders(entries: &[OrderEntry]) -> Self {
+        entries
+            .iter()
+            .fold(DepthTotals::default(), |mut totals, entry| {
+                totals.orders += 1;
+                totals.quantity += entry.quantity;
+                totals.notional += entry.price.to_f64() * entry.quantity.to_f64();
+                totals
+            })
+    }
+
+    /// Bid notional less ask notional, over their sum: 1 when only bids are
+    /// shown, -1 when only asks are. None for an empty book.
+    pub fn imbalance(bids: &DepthTotals, asks: &DepthTotals) -> Option<f64> {
+        let total = bids.notional + asks.notional;
+        (total > 0.0).then(|| (bids.notional - asks.notional) / total)
+    }
+
+    /// The same ratio over base currency quantity rather than notional
+    pub fn volume_imbalance(bids: &DepthTotals, asks: &DepthTotals) -> Option<f64> {
+        let (bid, ask) = (bids.quantity.to_f64(), asks.quantity.to_f64());
+        (bid + ask > 0.0).then(|| (bid - ask) / (bid + ask))
+    }
+}
+
+/// The part of each side of the book a measure looks at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DepthWindow {
+    Levels(usize), // The best displayed levels
+    Percent(f64),  // Displayed levels within this percent of the mid price
+}
+
+/// A single resting order as published in a market-by-order feed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderEntry {
+    pub order_id: Uuid,
+    pub price: Price,
+    pub quantity: Quantity,
+}
+
+/// A resting order with everything that decides when it fills, including the
+/// parts the public book leaves out. For operators debugging queue priority.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookOrder {
+    pub order_id: Uuid,
+    pub user_id: Uuid,
+    pub price: Price,
+    pub queue_position: usize, // 0 fills first at its price
+    pub remaining_quantity: Quantity,
+    pub displayed_quantity: Quantity, // What the public book shows
+    pub reserve_quantity: Quantity,   // Iceberg quantity not yet shown
+    pub hidden: bool,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Displayed volume binned into equal price buckets around the mid price,
+/// lowest bucket first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Heatmap {
+    pub mid_price: f64,
+    pub bucket_width: f64,
+    pub buckets: Vec<HeatmapBucket>,
+}
+
+/// Bids and asks priced from `price_low` up to `price_high`. The top bucket
+/// also takes orders priced exactly at its upper bound.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HeatmapBucket {
+    pub price_low: f64,
+    pub price_high: f64,
+    pub bid_quantity: Quantity,
+    pub ask_quantity: Quantity,
+}
+
+/// How far each trade pulls the rolling mark price toward its own price
+pub const MARK_PRICE_SMOOTHING: f64 = 0.2;
+
+/// Reference prices of the market as a whole, kept up to date by settlement.
+/// The imbalance is the engine's, which sees each top of book it publishes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MarketState {
+    pub last_trade_price: Option<Price>,
+    pub mark_price: Option<Price>,         // Rolling, trade-smoothed
+    pub session_open_price: Option<Price>, // First trade of the session
+    pub session_date: Option<NaiveDate>,   // UTC day of the session
+    pub imbalance: Option<f64>,            // Top of book, averaged over its changes
+}
+
+/// Which of a user's resting orders a mass cancel applies to. Unset fields
+/// match every order.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OrderFilter {
+    pub side: Option<OrderSide>,
+    pub min_price: Option<Price>,
+    pub max_price: Option<Price>,
+    pub tag: Option<String>, // Prefix of the client order ID
+}
+
+impl OrderFilter {
+    pub fn matches(&self, order: &Order) -> bool {
+        self.side.is_none_or(|side| side == order.side)
+            && order.price.is_some_and(|price| {
+                self.min_price.is_none_or(|min| price >= min)
+                    && self.max_price.is_none_or(|max| price <= max)
+            })
+            && self.tag.as_deref().is_none_or(|tag| {
+                order
+                    .client_order_id
+                    .as_deref()
+                    .is_some_and(|id| id.starts_with(tag))
+            })
+    }
+}
+
 pub struct OrderBook {
     pub bids: BTreeMap<Reverse<Price>, PriceLevel>,
     pub asks: BTreeMap<Price, PriceLevel>,
     pub orders: HashMap<Uuid, Order>,
+    /// Resting order IDs per user, so per-user work scales with that user's orders
+    pub orders_by_user: HashMap<Uuid, HashSet<Uuid>>,
     pub user_balances: HashMap<Uuid, UserBalance>,
+    /// Time spent settling trades since last taken, for latency reporting
+    pub settlement_time: Duration,
+    /// Bound on how far one market order may sweep the book
+    pub sweep_limit: SweepLimit,
+    /// How orders resting at one price are ranked for fills
+    pub allocation: AllocationPolicy,
+    /// Maker and taker fees each trade pays as part of its settlement
+    pub fees: FeeSchedule,
+    /// Price of the most recent settled trade; drives stop triggers
+    pub last_trade_price: Option<Price>,
+    /// Exponentially smoothed trade price, see `MARK_PRICE_SMOOTHING`
+    pub rolling_mark_price: Option<Price>,
+    /// First settled trade price of the current UTC day, and that day
+    pub session_open_price: Option<Price>,
+    pub session_date: Option<NaiveDate>,
+    /// Refused balance changes not yet picked up by the engine
+    pub balance_violations: Vec<BalanceViolation>,
+    /// Matched trades whose settlement was refused, with the reason, not yet
+    /// picked up by the engine
+    pub unsettled_trades: Vec<(Trade, String)>,
+    /// Users whose balances may have changed since last taken
+    pub changed_balances: HashSet<Uuid>,
+    /// Time the engine is matching at; trades are dated with it
+    pub now: DateTime<Utc>,
 }
 
 impl OrderBook {
@@ -17,7 +208,20 @@ impl OrderBook {
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
             orders: HashMap::new(),
+            orders_by_user: HashMap::new(),
             user_balances: HashMap::new(),
+            settlement_time: Duration::ZERO,
+            sweep_limit: SweepLimit::default(),
+            allocation: AllocationPolicy::default(),
+            fees: FeeSchedule::default(),
+            last_trade_price: None,
+            rolling_mark_price: None,
+            session_open_price: None,
+            session_date: None,
+            balance_violations: Vec::new(),
+            unsettled_trades: Vec::new(),
+            changed_balances: HashSet::new(),
+            now: Utc::now(),
         }
     }
 
@@ -29,6 +233,81 @@ impl OrderBook {
         self.asks.keys().next().copied()
     }
 
+    /// Best bid with a displayed order: the public quote, which hidden orders
+    /// resting ahead of it do not move
+    pub fn best_displayed_bid(&self) -> Option<Price> {
+        let displayed = |level: &&PriceLevel| level.displayed_orders() > 0;
+        self.bids.values().find(displayed).map(|level| level.price)
+    }
+
+    /// Best ask with a displayed order
+    pub fn best_displayed_ask(&self) -> Option<Price> {
+        let displayed = |level: &&PriceLevel| level.displayed_orders() > 0;
+        self.asks.values().find(displayed).map(|level| level.price)
+    }
+
+    /// Best ask with an order that a buyer able to take `available` may trade
+    /// with, and where that order sits in its queue. Orders with a minimum fill
+    /// larger than the buyer can take are passed over.
+    pub(crate) fn best_eligible_ask(&self, available: Quantity) -> Option<(Price, usize)> {
+        self.asks
+            .values()
+            .find_map(|level| Some((level.price, level.first_eligible(available)?)))
+    }
+
+    /// Best bid with an order that a seller able to give `available` may trade with
+    pub(crate) fn best_eligible_bid(&self, available: Quantity) -> Option<(Price, usize)> {
+        self.bids
+            .values()
+            .find_map(|level| Some((level.price, level.first_eligible(available)?)))
+    }
+
+    /// Best bid and ask among displayed orders that are not pegged: the
+    /// prices pegged orders follow, so they never chase their own quotes nor
+    /// give away where hidden orders rest
+    pub fn reference_bbo(&self) -> (Option<Price>, Option<Price>) {
+        let unpegged =
+            |level: &&PriceLevel| level.orders.iter().any(|o| o.peg.is_none() && !o.hidden);
+        let best_bid = self.bids.values().find(unpegged).map(|level| level.price);
+        let best_ask = self.asks.values().find(unpegged).map(|level| level.price);
+        (best_bid, best_ask)
+    }
+
+    /// Reference price for valuing positions: mid of the displayed quotes when
+    /// both sides are quoted, otherwise whichever side is. Hidden orders are
+    /// left out so the mark gives nothing away about them.
+    pub fn mark_price(&self) -> Option<f64> {
+        match (self.best_displayed_bid(), self.best_displayed_ask()) {
+            (Some(bid), Some(ask)) => Some((bid.to_f64() + ask.to_f64()) / 2.0),
+            (Some(price), None) | (None, Some(price)) => Some(price.to_f64()),
+            (None, None) => None,
+        }
+    }
+
+    pub fn market_state(&self) -> MarketState {
+        MarketState {
+            last_trade_price: self.last_trade_price,
+            mark_price: self.rolling_mark_price,
+            session_open_price: self.session_open_price,
+            session_date: self.session_date,
+            imbalance: None,
+        }
+    }
+
+    /// Settlement time accumulated since the last call, resetting the counter
+    pub fn take_settlement_time(&mut self) -> Duration {
+        std::mem::take(&mut self.settlement_time)
+    }
+
+    /// The displayed best bid and ask, as the market sees them
+    pub fn bbo_snapshot(&self) -> BboSnapshot {
+        BboSnapshot {
+            best_bid: self.best_displayed_bid(),
+            best_ask: self.best_displayed_ask(),
+            captured_at: self.now,
+        }
+    }
+
     /// Add a limit order to the orderbook
     /// This is a high-level operation that places the order in the appropriate price level queue
     pub fn add_order(&mut self, order: Order) {
@@ -40,23 +319,142 @@ impl OrderBook {
                 self.bids
                     .entry(Reverse(price))
                     .or_insert_with(|| PriceLevel::new(price))
-                    .enqueue_order(order.clone());
+                    .enqueue_order(order.clone(), self.allocation);
             }
             OrderSide::Sell => {
                 self.asks
                     .entry(price)
                     .or_insert_with(|| PriceLevel::new(price))
-                    .enqueue_order(order.clone());
+                    .enqueue_order(order.clone(), self.allocation);
             }
         }
 
+        self.orders_by_user
+            .entry(order.user_id)
+            .or_default()
+            .insert(order_id);
         self.orders.insert(order_id, order);
     }
 
+    /// Every resting order, best bid first and then best ask first, each
+    /// level in queue order
+    pub fn resting_orders(&self) -> Vec<Order> {
+        let bids = self.bids.values().flat_map(|level| level.orders.iter());
+        let asks = self.asks.values().flat_map(|level| level.orders.iter());
+        bids.chain(asks).cloned().collect()
+    }
+
+    /// Put back an order taken from `resting_orders`, behind those already at
+    /// its price whatever its priority, so the queues come back as they were.
+    /// Balances are left alone: the order's reservation is already out of them.
+    pub fn restore_order(&mut self, order: Order) {
+        let price = order.price.expect("Limit order must have price");
+        let level = match order.side {
+            OrderSide::Buy => self
+                .bids
+                .entry(Reverse(price))
+                .or_insert_with(|| PriceLevel::new(price)),
+            OrderSide::Sell => self
+                .asks
+                .entry(price)
+                .or_insert_with(|| PriceLevel::new(price)),
+        };
+        level.total_volume += order.displayed_quantity();
+        level.orders.push_back(order.clone());
+
+        self.orders_by_user
+            .entry(order.user_id)
+            .or_default()
+            .insert(order.id);
+        self.orders.insert(order.id, order);
+    }
+
+    /// Drop a filled or cancelled order from the order map and its owner's index
+    pub(crate) fn remove_resting(&mut self, order_id: Uuid) -> Option<Order> {
+        let order = self.orders.remove(&order_id)?;
+        if let Some(ids) = self.orders_by_user.get_mut(&order.user_id) {
+            ids.remove(&order_id);
+            if ids.is_empty() {
+                self.orders_by_user.remove(&order.user_id);
+            }
+        }
+        Some(order)
+    }
+
+    /// Whether an incoming limit order at `price` for `quantity` would reach a
+    /// level priced beyond `bound` before it is filled. Levels are walked the
+    /// way `match_order` takes them: hidden orders trade like any other, and
+    /// orders whose minimum fill the remainder can't meet are passed over.
+    pub fn would_trade_beyond(
+        &self,
+        side: OrderSide,
+        price: Price,
+        quantity: Quantity,
+        bound: Price,
+    ) -> bool {
+        let crossing: Vec<&PriceLevel> = match side {
+            OrderSide::Buy => self
+                .asks
+                .values()
+                .take_while(|l| l.price <= price)
+                .collect(),
+            OrderSide::Sell => self
+                .bids
+                .values()
+                .take_while(|l| l.price >= price)
+                .collect(),
+        };
+
+        let mut left = quantity;
+        for level in crossing {
+            if left.is_zero() {
+                return false;
+            }
+            // A level with nothing the remainder may trade with is skipped
+            if level.first_eligible(left).is_none() {
+                continue;
+            }
+            let beyond = match side {
+                OrderSide::Buy => level.price > bound,
+                OrderSide::Sell => level.price < bound,
+            };
+            if beyond {
+                return true;
+            }
+            for order in &level.orders {
+                if order.accepts_fill(left) {
+                    left -= std::cmp::min(left, order.remaining_quantity);
+                }
+            }
+        }
+        false
+    }
+
+    /// A user's resting orders, in no particular order
+    pub fn user_orders(&self, user_id: Uuid) -> impl Iterator<Item = &Order> {
+        self.orders_by_user
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|order_id| self.orders.get(order_id))
+    }
+
+    /// IDs of a user's resting orders that `filter` selects
+    pub fn matching_user_orders(&self, user_id: Uuid, filter: &OrderFilter) -> Vec<Uuid> {
+        self.user_orders(user_id)
+            .filter(|order| filter.matches(order))
+            .map(|order| order.id)
+            .collect()
+    }
+
+    pub fn open_order_count(&self, user_id: Uuid) -> usize {
+        self.orders_by_user.get(&user_id).map_or(0, HashSet::len)
+    }
+
     /// Cancel an order from the orderbook
     /// This is a high-level operation that removes the order from both the price level queue and global order map
     pub fn cancel_order(&mut self, order_id: Uuid) -> Result<Order, String> {
-        let order = self.orders.remove(&order_id).ok_or("Order not found")?;
+        let order = self.remove_resting(order_id).ok_or("Order not found")?;
         let price = order.price.ok_or("Order has no price")?;
 
         match order.side {
@@ -81,11 +479,33 @@ impl OrderBook {
         Ok(order)
     }
 
+    /// Shrink a resting order by `quantity` in place
+    /// Unlike a cancel and re-add, the order keeps its place in the queue
+    pub fn reduce_order(&mut self, order_id: Uuid, quantity: Quantity) -> Result<Order, String> {
+        let order = self.orders.get_mut(&order_id).ok_or("Order not found")?;
+        if quantity >= order.remaining_quantity {
+            return Err("Cannot reduce an order by its whole remaining quantity".to_string());
+        }
+        let price = order.price.ok_or("Order has no price")?;
+
+        let level = match order.side {
+            OrderSide::Buy => self.bids.get_mut(&Reverse(price)),
+            OrderSide::Sell => self.asks.get_mut(&price),
+        };
+        let reduced = level
+            .and_then(|level| level.reduce_order(order_id, quantity))
+            .ok_or("Order not found at its price level")?;
+        *order = reduced.clone();
+        Ok(order.clone())
+    }
+
     pub fn get_order(&self, order_id: Uuid) -> Option<&Order> {
         self.orders.get(&order_id)
     }
 
+    /// The user's balance, to change. Counts as a change for `take_changed_balances`.
     pub fn get_or_create_balance(&mut self, user_id: Uuid) -> &mut UserBalance {
+        self.changed_balances.insert(user_id);
         self.user_balances
             .entry(user_id)
             .or_insert_with(|| UserBalance::new(user_id))
@@ -123,7 +543,30 @@ impl OrderBook {
             .user_balances
             .get_mut(&user_id)
             .ok_or("User not found")?;
-        balance.subtract_balance(currency, amount)
+        balance.subtract_balance(currency, amount)?;
+        self.changed_balances.insert(user_id);
+        Ok(())
+    }
+
+    /// Set `amount` of what the user has available aside for an order
+    pub fn reserve_balance(
+        &mut self,
+        user_id: Uuid,
+        currency: &str,
+        amount: f64,
+    ) -> Result<(), String> {
+        let balance = self
+            .user_balances
+            .get_mut(&user_id)
+            .ok_or("User not found")?;
+        balance.reserve(currency, amount)?;
+        self.changed_balances.insert(user_id);
+        Ok(())
+    }
+
+    /// Users whose balances changed since the last call
+    pub fn take_changed_balances(&mut self) -> HashSet<Uuid> {
+        std::mem::take(&mut self.changed_balances)
     }
 
     pub fn credit_balance(&mut self, user_id: Uuid, currency: &str, amount: f64) {
@@ -131,23 +574,167 @@ impl OrderBook {
         balance.add_balance(currency, amount);
     }
 
-    pub fn get_depth(&self, levels: usize) -> (Vec<(Price, Quantity)>, Vec<(Price, Quantity)>) {
-        let bids: Vec<(Price, Quantity)> = self
+    /// Displayed quantity and order count per price, best first. Levels
+    /// holding only hidden orders are left out.
+    pub fn get_depth(&self, levels: usize) -> (Vec<DepthLevel>, Vec<DepthLevel>) {
+        let bids: Vec<DepthLevel> = self
             .bids
             .iter()
+            .filter(|(_, level)| !level.total_volume.is_zero())
             .take(levels)
-            .map(|(Reverse(price), level)| (*price, level.total_volume))
+            .map(|(Reverse(price), level)| {
+                DepthLevel::new(*price, level.total_volume, level.displayed_orders())
+            })
             .collect();
 
-        let asks: Vec<(Price, Quantity)> = self
+        let asks: Vec<DepthLevel> = self
             .asks
             .iter()
+            .filter(|(_, level)| !level.total_volume.is_zero())
             .take(levels)
-            .map(|(price, level)| (*price, level.total_volume))
+            .map(|(price, level)| {
+                DepthLevel::new(*price, level.total_volume, level.displayed_orders())
+            })
             .collect();
 
         (bids, asks)
     }
+
+    /// Individual displayed orders on the top `levels` displayed price levels
+    /// of each side, in queue order
+    pub fn get_order_depth(&self, levels: usize) -> (Vec<OrderEntry>, Vec<OrderEntry>) {
+        fn entries<'a>(levels: impl Iterator<Item = &'a PriceLevel>) -> Vec<OrderEntry> {
+            levels
+                .flat_map(|level| level.orders.iter())
+                .filter(|order| !order.hidden)
+                .map(|order| OrderEntry {
+                    order_id: order.id,
+                    price: order.price.expect("Resting order must have price"),
+                    quantity: order.visible_quantity(),
+                })
+                .collect()
+        }
+
+        let displayed = |level: &&PriceLevel| !level.total_volume.is_zero();
+        let bids = entries(self.bids.values().filter(displayed).take(levels));
+        let asks = entries(self.asks.values().filter(displayed).take(levels));
+        (bids, asks)
+    }
+
+    /// Mid of the best displayed bid and ask, or the one side displayed.
+    /// Unlike `mark_price`, it never reveals where hidden orders rest.
+    pub fn displayed_mid(&self) -> Option<f64> {
+        let displayed = |level: &&PriceLevel| !level.total_volume.is_zero();
+        let best_bid = self.bids.values().find(displayed).map(|l| l.price.to_f64());
+        let best_ask = self.asks.values().find(displayed).map(|l| l.price.to_f64());
+        match (best_bid, best_ask) {
+            (Some(bid), Some(ask)) => Some((bid + ask) / 2.0),
+            (Some(price), None) | (None, Some(price)) => Some(price),
+            (None, None) => None,
+        }
+    }
+
+    /// Displayed totals of each side within `window`. The levels keep their
+    /// volume up to date as orders come and go, so this walks levels, never
+    /// orders.
+    pub fn depth_totals(&self, window: DepthWindow) -> (DepthTotals, DepthTotals) {
+        fn totals<'a>(levels: impl Iterator<Item = &'a PriceLevel>) -> DepthTotals {
+            levels.fold(DepthTotals::default(), |mut totals, level| {
+                totals.orders += level.displayed_orders();
+                totals.quantity += level.total_volume;
+                totals.notional += level.price.to_f64() * level.total_volume.to_f64();
+                totals
+            })
+        }
+
+        let displayed = |level: &&PriceLevel| !level.total_volume.is_zero();
+        let bids = self.bids.values().filter(displayed);
+        let asks = self.asks.values().filter(displayed);
+        match window {
+            DepthWindow::Levels(levels) => (totals(bids.take(levels)), totals(asks.take(levels))),
+            DepthWindow::Percent(pct) => {
+                let Some(mid) = self.displayed_mid() else {
+                    return Default::default();
+                };
+                let (low, high) = (mid * (1.0 - pct / 100.0), mid * (1.0 + pct / 100.0));
+                (
+                    totals(bids.take_while(|level| level.price.to_f64() >= low)),
+                    totals(asks.take_while(|level| level.price.to_f64() <= high)),
+                )
+            }
+        }
+    }
+
+    /// Displayed volume within `range_pct` percent either side of the displayed
+    /// mid, split into `buckets` equal price buckets. None while neither side
+    /// displays anything.
+    pub fn get_heatmap(&self, buckets: usize, range_pct: f64) -> Option<Heatmap> {
+        let displayed = |level: &&PriceLevel| !level.total_volume.is_zero();
+        let mid_price = self.displayed_mid()?;
+
+        let low = mid_price * (1.0 - range_pct / 100.0);
+        let high = mid_price * (1.0 + range_pct / 100.0);
+        let bucket_width = (high - low) / buckets as f64;
+        let mut heatmap = Heatmap {
+            mid_price,
+            bucket_width,
+            buckets: (0..buckets)
+                .map(|i| HeatmapBucket {
+                    price_low: low + bucket_width * i as f64,
+                    price_high: low + bucket_width * (i + 1) as f64,
+                    bid_quantity: Quantity::new(0),
+                    ask_quantity: Quantity::new(0),
+                })
+                .collect(),
+        };
+        let bucket_of = |price: f64| (((price - low) / bucket_width) as usize).min(buckets - 1);
+
+        // Both sides are walked outward from the best price, so each stops at the range edge
+        for level in self.bids.values().filter(displayed) {
+            let price = level.price.to_f64();
+            if price < low {
+                break;
+            }
+            if price <= high {
+                heatmap.buckets[bucket_of(price)].bid_quantity += level.total_volume;
+            }
+        }
+        for level in self.asks.values().filter(displayed) {
+            let price = level.price.to_f64();
+            if price > high {
+                break;
+            }
+            if price >= low {
+                heatmap.buckets[bucket_of(price)].ask_quantity += level.total_volume;
+            }
+        }
+        Some(heatmap)
+    }
+
+    /// Every order on the top `levels` price levels of each side in queue
+    /// order, hidden orders and iceberg reserves included
+    pub fn get_full_book(&self, levels: usize) -> (Vec<BookOrder>, Vec<BookOrder>) {
+        fn entries<'a>(levels: impl Iterator<Item = &'a PriceLevel>) -> Vec<BookOrder> {
+            levels
+                .flat_map(|level| level.orders.iter().enumerate())
+                .map(|(queue_position, order)| BookOrder {
+                    order_id: order.id,
+                    user_id: order.user_id,
+                    price: order.price.expect("Resting order must have price"),
+                    queue_position,
+                    remaining_quantity: order.remaining_quantity,
+                    displayed_quantity: order.displayed_quantity(),
+                    reserve_quantity: order.hidden_quantity,
+                    hidden: order.hidden,
+                    received_at: order.received_at,
+                })
+                .collect()
+        }
+
+        let bids = entries(self.bids.values().take(levels));
+        let asks = entries(self.asks.values().take(levels));
+        (bids, asks)
+    }
 }
 
 impl Default for OrderBook {
@@ -1,4 +1,4 @@
-use crate::types::{Order, Price, Quantity};
+use crate::types::{AllocationPolicy, Order, Price, Quantity};
 use std::collections::VecDeque;
 use uuid::Uuid;
 
@@ -6,7 +6,7 @@ use uuid::Uuid;
 pub struct PriceLevel {
     pub price: Price,
     pub orders: VecDeque<Order>,
-    pub total_volume: Quantity,
+    pub total_volume: Quantity, // Displayed only: no iceberg reserves or hidden orders
 }
 
 impl PriceLevel {
@@ -18,27 +18,91 @@ impl PriceLevel {
         }
     }
 
-    // Enqueue an order to the back of the FIFO queue at this price level
-    pub fn enqueue_order(&mut self, order: Order) {
-        self.total_volume += order.remaining_quantity;
-        self.orders.push_back(order);
+    // How many orders at this price are shown on the book
+    pub fn displayed_orders(&self) -> usize {
+        self.orders.iter().filter(|o| !o.hidden).count()
+    }
+
+    // Enqueue an order at its place under `policy`, time priority by default.
+    // Orders are ranked by gateway receipt time, so one that was received earlier but
+    // queued behind a later one inside the process still gets its fair place.
+    // Equal timestamps keep arrival order. Hidden orders rank behind every
+    // displayed order, however early they arrived.
+    pub fn enqueue_order(&mut self, order: Order, policy: AllocationPolicy) {
+        self.total_volume += order.displayed_quantity();
+
+        let pos = self
+            .orders
+            .iter()
+            .rposition(|o| policy.priority(o, &order).is_le())
+            .map_or(0, |i| i + 1);
+        self.orders.insert(pos, order);
     }
 
     // Remove a specific order from the queue by its ID
     pub fn dequeue_order_by_id(&mut self, order_id: Uuid) -> Option<Order> {
         if let Some(pos) = self.orders.iter().position(|o| o.id == order_id) {
             let order = self.orders.remove(pos)?;
-            self.total_volume -= order.remaining_quantity;
+            self.total_volume -= order.displayed_quantity();
             Some(order)
         } else {
             None
         }
     }
 
+    // Shrink a queued order by `quantity` where it stands, keeping its priority
+    pub fn reduce_order(&mut self, order_id: Uuid, quantity: Quantity) -> Option<&Order> {
+        let order = self.orders.iter_mut().find(|o| o.id == order_id)?;
+        self.total_volume -= order.displayed_quantity();
+        order.reduce(quantity);
+        self.total_volume += order.displayed_quantity();
+        Some(order)
+    }
+
     pub fn update_volume(&mut self, quantity_filled: Quantity) {
         self.total_volume -= quantity_filled;
     }
 
+    // Account for a fill against the front order
+    pub fn record_front_fill(&mut self, quantity_filled: Quantity) {
+        self.record_fill(0, quantity_filled);
+    }
+
+    // Account for a fill against the order at `index`. An iceberg whose visible
+    // slice is used up shows the next slice from its reserve and goes to the back
+    // of the displayed orders, as a fresh order at this price would.
+    pub fn record_fill(&mut self, index: usize, quantity_filled: Quantity) {
+        let Some(order) = self.orders.get_mut(index) else {
+            return;
+        };
+        if order.hidden {
+            return;
+        }
+        self.total_volume -= quantity_filled;
+
+        if let Some(shown) = order.replenish() {
+            self.total_volume += shown;
+            if let Some(order) = self.orders.remove(index) {
+                let pos = self
+                    .orders
+                    .iter()
+                    .position(|o| o.hidden)
+                    .unwrap_or(self.orders.len());
+                self.orders.insert(pos, order);
+            }
+        }
+    }
+
+    // Position of the first order in the queue willing to trade with a taker
+    // that can take up to `available`. Orders it skips keep their place.
+    pub fn first_eligible(&self, available: Quantity) -> Option<usize> {
+        self.orders.iter().position(|o| o.accepts_fill(available))
+    }
+
+    pub fn get(&self, order_id: Uuid) -> Option<&Order> {
+        self.orders.iter().find(|o| o.id == order_id)
+    }
+
     pub fn is_empty(&self) -> bool {
         self.orders.is_empty()
     }
@@ -51,11 +115,17 @@ impl PriceLevel {
         self.orders.front_mut()
     }
 
+    pub fn order_mut(&mut self, index: usize) -> Option<&mut Order> {
+        self.orders.get_mut(index)
+    }
+
     pub fn pop_if_filled(&mut self) -> Option<Order> {
-        if let Some(order) = self.orders.front() {
-            if order.is_fully_filled() {
-                return self.orders.pop_front();
-            }
+        self.remove_if_filled(0)
+    }
+
+    pub fn remove_if_filled(&mut self, index: usize) -> Option<Order> {
+        if self.orders.get(index)?.is_fully_filled() {
+            return self.orders.remove(index);
         }
         None
     }
@@ -81,8 +151,8 @@ mod tests {
         let o1 = mk_order(3);
         let o2 = mk_order(7);
 
-        level.enqueue_order(o1.clone());
-        level.enqueue_order(o2.clone());
+        level.enqueue_order(o1.clone(), AllocationPolicy::Fifo);
+        level.enqueue_order(o2.clone(), AllocationPolicy::Fifo);
 
         assert_eq!(level.price, price);
         assert_eq!(level.total_volume, Quantity::new(3 + 7));
@@ -90,6 +160,95 @@ mod tests {
         assert!(!level.is_empty());
     }
 
+    #[test]
+    fn enqueue_order_ranks_by_receipt_time() {
+        let mut level = PriceLevel::new(Price::new(10_000));
+
+        let early = mk_order(1);
+        let late = mk_order(1).with_received_at(early.received_at + chrono::Duration::milliseconds(5));
+        let tied = mk_order(1).with_received_at(early.received_at);
+
+        // The later order reaches the engine first
+        level.enqueue_order(late.clone(), AllocationPolicy::Fifo);
+        level.enqueue_order(early.clone(), AllocationPolicy::Fifo);
+        level.enqueue_order(tied.clone(), AllocationPolicy::Fifo);
+
+        let ids: Vec<_> = level.orders.iter().map(|o| o.id).collect();
+        assert_eq!(ids, vec![early.id, tied.id, late.id]);
+    }
+
+    #[test]
+    fn enqueue_order_ranks_by_priority_fee_when_the_market_takes_them() {
+        let mut level = PriceLevel::new(Price::new(10_000));
+
+        let early = mk_order(1);
+        let late = mk_order(1)
+            .with_received_at(early.received_at + chrono::Duration::milliseconds(5))
+            .with_priority_fee(2.0);
+        let later = mk_order(1)
+            .with_received_at(early.received_at + chrono::Duration::milliseconds(9))
+            .with_priority_fee(2.0);
+        let hidden = mk_order(1).with_hidden(true).with_priority_fee(5.0);
+
+        for order in [&early, &late, &later, &hidden] {
+            level.enqueue_order(order.clone(), AllocationPolicy::PriorityFee);
+        }
+        let ids: Vec<_> = level.orders.iter().map(|o| o.id).collect();
+        assert_eq!(ids, vec![late.id, later.id, early.id, hidden.id]);
+    }
+
+    #[test]
+    fn iceberg_counts_display_only_and_requeues_after_refill() {
+        let mut level = PriceLevel::new(Price::new(10_000));
+
+        let iceberg = mk_order(10).with_display_quantity(Some(Quantity::new(4)));
+        let plain = mk_order(3);
+        level.enqueue_order(iceberg.clone(), AllocationPolicy::Fifo);
+        level.enqueue_order(plain.clone(), AllocationPolicy::Fifo);
+        assert_eq!(level.total_volume, Quantity::new(4 + 3));
+
+        // Partial fill of the visible slice keeps its place
+        level.front_mut().unwrap().fill(Quantity::new(1));
+        level.record_front_fill(Quantity::new(1));
+        assert_eq!(level.front().unwrap().id, iceberg.id);
+        assert_eq!(level.total_volume, Quantity::new(3 + 3));
+
+        // Exhausting it shows the next slice at the back of the queue
+        level.front_mut().unwrap().fill(Quantity::new(3));
+        level.record_front_fill(Quantity::new(3));
+        let ids: Vec<_> = level.orders.iter().map(|o| o.id).collect();
+        assert_eq!(ids, vec![plain.id, iceberg.id]);
+        assert_eq!(level.total_volume, Quantity::new(3 + 4));
+        assert_eq!(level.get(iceberg.id).unwrap().hidden_quantity, Quantity::new(2));
+
+        level.dequeue_order_by_id(iceberg.id);
+        assert_eq!(level.total_volume, Quantity::new(3));
+    }
+
+    #[test]
+    fn hidden_orders_rank_behind_displayed_and_add_no_volume() {
+        let mut level = PriceLevel::new(Price::new(10_000));
+
+        let hidden = mk_order(5).with_hidden(true);
+        let iceberg = mk_order(6)
+            .with_display_quantity(Some(Quantity::new(2)))
+            .with_received_at(hidden.received_at + chrono::Duration::milliseconds(1));
+        level.enqueue_order(hidden.clone(), AllocationPolicy::Fifo);
+        level.enqueue_order(iceberg.clone(), AllocationPolicy::Fifo);
+        assert_eq!(level.front().unwrap().id, iceberg.id);
+        assert_eq!(level.total_volume, Quantity::new(2));
+
+        // A refilled iceberg stays ahead of hidden orders
+        level.front_mut().unwrap().fill(Quantity::new(2));
+        level.record_front_fill(Quantity::new(2));
+        let ids: Vec<_> = level.orders.iter().map(|o| o.id).collect();
+        assert_eq!(ids, vec![iceberg.id, hidden.id]);
+        assert_eq!(level.total_volume, Quantity::new(2));
+
+        level.dequeue_order_by_id(hidden.id);
+        assert_eq!(level.total_volume, Quantity::new(2));
+    }
+
     #[test]
     fn dequeue_order_by_id_updates_volume() {
         let price = Price::new(10_000);
@@ -103,9 +262,9 @@ mod tests {
         let o2_id = o2.id;
         let o3_id = o3.id;
 
-        level.enqueue_order(o1.clone());
-        level.enqueue_order(o2.clone());
-        level.enqueue_order(o3.clone());
+        level.enqueue_order(o1.clone(), AllocationPolicy::Fifo);
+        level.enqueue_order(o2.clone(), AllocationPolicy::Fifo);
+        level.enqueue_order(o3.clone(), AllocationPolicy::Fifo);
 
         assert_eq!(level.total_volume, Quantity::new(5 + 2 + 4));
 
@@ -1,30 +1,291 @@
-use crate::orderbook::OrderBook;
-use crate::types::{OrderSide, Trade};
+use crate::orderbook::{OrderBook, MARK_PRICE_SMOOTHING};
+use crate::types::{OrderSide, Price, Trade};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Float drift below this is treated as zero rather than as a negative balance
+pub const BALANCE_TOLERANCE: f64 = 1e-9;
+
+/// What was moving money when a balance check failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceOperation {
+    Settlement,
+    Refund,
+}
+
+/// A balance change refused because it would have left a balance below zero.
+/// The engine turns each one into an incident.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BalanceViolation {
+    pub user_id: Uuid,
+    pub currency: String,
+    pub balance: f64, // Before the change
+    pub change: f64,  // Net change the operation tried to make
+    pub operation: BalanceOperation,
+    #[serde(default)]
+    pub reserved: bool, // The part set aside for open orders, not the available part
+}
 
 impl OrderBook {
     pub(crate) fn execute_trade_settlement(
         &mut self,
-        trade: &Trade,
+        trade: &mut Trade,
         taker_side: OrderSide,
     ) -> Result<(), String> {
+        let started = Instant::now();
+        let result = self.settle_balances(trade, taker_side);
+        self.settlement_time += started.elapsed();
+        if result.is_ok() {
+            self.record_trade_price(trade);
+        }
+        result
+    }
+
+    /// Settle a trade the book has just matched. A refused settlement does
+    /// not undo the match: the fills stand and the trade waits in
+    /// `unsettled_trades` for the engine to retry or compensate, and to keep
+    /// out of the tape and history until then.
+    pub(crate) fn settle_matched_trade(&mut self, trade: &mut Trade, taker_side: OrderSide) {
+        if let Err(e) = self.execute_trade_settlement(trade, taker_side) {
+            self.unsettled_trades.push((trade.clone(), e));
+        }
+    }
+
+    /// Try again to move the balances of a trade whose settlement was
+    /// refused. Prices are left alone; later trades have moved them on.
+    pub fn retry_settlement(&mut self, trade: &mut Trade) -> Result<(), String> {
+        self.settle_balances(trade, trade.taker_side)
+    }
+
+    /// Hand over the trades whose settlement was refused since the last call
+    pub fn take_unsettled_trades(&mut self) -> Vec<(Trade, String)> {
+        std::mem::take(&mut self.unsettled_trades)
+    }
+
+    /// Move the last, mark and session open prices to a settled trade. A
+    /// session starts with the first trade of each UTC day.
+    fn record_trade_price(&mut self, trade: &Trade) {
+        self.last_trade_price = Some(trade.price);
+
+        let price = trade.price.to_f64();
+        self.rolling_mark_price = Some(match self.rolling_mark_price {
+            Some(mark) => {
+                let mark = mark.to_f64();
+                Price::from_f64(mark + MARK_PRICE_SMOOTHING * (price - mark))
+            }
+            None => trade.price,
+        });
+
+        let date = trade.timestamp.date_naive();
+        if self.session_date != Some(date) {
+            self.session_date = Some(date);
+            self.session_open_price = Some(trade.price);
+        }
+    }
+
+    /// Each side pays out of what its order reserved and is credited what it
+    /// receives. A buying taker's reservation at its limit, or for its whole
+    /// market order, may be more than the trade costs; the engine releases
+    /// the difference once matching is done. Both sides' fees are paid in
+    /// USD with the rest, out of what is available: if either side cannot
+    /// pay its fee, the trade does not settle. The fees are recorded on it.
+    fn settle_balances(&mut self, trade: &mut Trade, taker_side: OrderSide) -> Result<(), String> {
         let btc_amount = trade.quantity.to_f64();
         let usd_amount = trade.price.to_f64() * btc_amount;
+        let (maker_fee, taker_fee) = self.fees.fees(usd_amount);
 
-        match taker_side {
-            OrderSide::Buy => {
-                self.deduct_balance(trade.taker_user_id, "USD", usd_amount)?;
-                self.credit_balance(trade.taker_user_id, "BTC", btc_amount);
-                self.deduct_balance(trade.maker_user_id, "BTC", btc_amount)?;
-                self.credit_balance(trade.maker_user_id, "USD", usd_amount);
-            }
-            OrderSide::Sell => {
-                self.deduct_balance(trade.taker_user_id, "BTC", btc_amount)?;
-                self.credit_balance(trade.taker_user_id, "USD", usd_amount);
-                self.deduct_balance(trade.maker_user_id, "USD", usd_amount)?;
-                self.credit_balance(trade.maker_user_id, "BTC", btc_amount);
+        let (buyer, seller) = match taker_side {
+            OrderSide::Buy => (trade.taker_user_id, trade.maker_user_id),
+            OrderSide::Sell => (trade.maker_user_id, trade.taker_user_id),
+        };
+        let mut available = vec![(buyer, "BTC", btc_amount), (seller, "USD", usd_amount)];
+        let fees = [
+            (trade.maker_user_id, maker_fee),
+            (trade.taker_user_id, taker_fee),
+        ];
+        for (user_id, fee) in fees {
+            if fee > 0.0 {
+                available.push((user_id, "USD", -fee));
             }
         }
+        self.apply_reserved_changes(
+            BalanceOperation::Settlement,
+            &available,
+            &[(buyer, "USD", -usd_amount), (seller, "BTC", -btc_amount)],
+        )?;
+        trade.maker_fee = maker_fee.max(0.0);
+        trade.taker_fee = taker_fee.max(0.0);
+        Ok(())
+    }
+
+    /// Apply `changes` all together or not at all. If any balance would end
+    /// up below zero nothing moves, and each offending balance is queued in
+    /// `balance_violations` for the engine to raise.
+    pub fn apply_balance_changes(
+        &mut self,
+        operation: BalanceOperation,
+        changes: &[(Uuid, &str, f64)],
+    ) -> Result<(), String> {
+        self.apply_reserved_changes(operation, changes, &[])
+    }
+
+    /// Like `apply_balance_changes`, also moving the `reserved` part of
+    /// balances that is set aside for open orders. Releasing a reservation is
+    /// a change to both.
+    pub fn apply_reserved_changes(
+        &mut self,
+        operation: BalanceOperation,
+        available: &[(Uuid, &str, f64)],
+        reserved: &[(Uuid, &str, f64)],
+    ) -> Result<(), String> {
+        let mut net: HashMap<(Uuid, &str, bool), f64> = HashMap::new();
+        let changes = available
+            .iter()
+            .map(|change| (change, false))
+            .chain(reserved.iter().map(|change| (change, true)));
+        for ((user_id, currency, change), reserved) in changes {
+            *net.entry((*user_id, *currency, reserved)).or_default() += change;
+        }
+
+        let mut violations: Vec<BalanceViolation> = net
+            .iter()
+            .filter_map(|((user_id, currency, reserved), change)| {
+                let balance = self.user_balances.get(user_id).map_or(0.0, |b| {
+                    if *reserved {
+                        b.get_reserved(currency)
+                    } else {
+                        b.get_balance(currency)
+                    }
+                });
+                let after = balance + change;
+                (after.is_nan() || after < -BALANCE_TOLERANCE).then(|| BalanceViolation {
+                    user_id: *user_id,
+                    currency: currency.to_string(),
+                    balance,
+                    change: *change,
+                    operation,
+                    reserved: *reserved,
+                })
+            })
+            .collect();
+        if !violations.is_empty() {
+            violations.sort_by_key(|v| (v.user_id, v.currency.clone(), v.reserved));
+            let part = match violations[0].reserved {
+                true => "reserved ",
+                false => "",
+            };
+            let message = format!(
+                "{:?} refused: it would leave the {}{} balance of {} negative",
+                operation, part, violations[0].currency, violations[0].user_id
+            );
+            self.balance_violations.extend(violations);
+            return Err(message);
+        }
 
+        for ((user_id, currency, reserved), change) in net {
+            let balance = self.get_or_create_balance(user_id);
+            let amounts = match reserved {
+                true => &mut balance.reserved,
+                false => &mut balance.balances,
+            };
+            let amount = amounts.entry(currency.to_string()).or_insert(0.0);
+            // Only drift within the tolerance can be below zero here
+            *amount = (*amount + change).max(0.0);
+        }
         Ok(())
     }
+
+    /// Hand over the violations queued since the last call
+    pub fn take_balance_violations(&mut self) -> Vec<BalanceViolation> {
+        std::mem::take(&mut self.balance_violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changes_apply_all_or_nothing() {
+        let mut book = OrderBook::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        book.add_funds(alice, "USD", 100.0);
+        book.add_funds(bob, "BTC", 1.0);
+
+        book.apply_balance_changes(
+            BalanceOperation::Settlement,
+            &[(alice, "USD", -100.0), (bob, "USD", 100.0)],
+        )
+        .unwrap();
+        assert_eq!(
+            book.get_user_balance(alice).unwrap().get_balance("USD"),
+            0.0
+        );
+        assert!(book.take_balance_violations().is_empty());
+
+        // Bob's leg is fine but Alice's is not, so neither moves
+        let refused = book.apply_balance_changes(
+            BalanceOperation::Settlement,
+            &[
+                (bob, "BTC", -1.0),
+                (alice, "BTC", 1.0),
+                (alice, "USD", -50.0),
+            ],
+        );
+        assert!(refused.is_err());
+        assert_eq!(book.get_user_balance(bob).unwrap().get_balance("BTC"), 1.0);
+        assert_eq!(
+            book.get_user_balance(alice).unwrap().get_balance("BTC"),
+            0.0
+        );
+
+        let violations = book.take_balance_violations();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].user_id, alice);
+        assert_eq!(violations[0].currency, "USD");
+        assert_eq!(violations[0].change, -50.0);
+        assert!(book.take_balance_violations().is_empty());
+    }
+
+    #[test]
+    fn test_settled_trades_move_last_mark_and_session_open_prices() {
+        let mut book = OrderBook::new();
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+        book.add_funds(buyer, "USD", 1_000.0);
+        book.add_funds(seller, "BTC", 10.0);
+        // Settlement pays out of what the orders reserved
+        book.reserve_balance(buyer, "USD", 1_000.0).unwrap();
+        book.reserve_balance(seller, "BTC", 10.0).unwrap();
+        let day = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut settle = |price: f64, at| {
+            let mut trade = Trade::new(
+                Uuid::new_v4(),
+                Uuid::new_v4(),
+                seller,
+                buyer,
+                Price::from_f64(price),
+                crate::types::Quantity::from_f64(1.0),
+            );
+            trade.timestamp = at;
+            book.execute_trade_settlement(&mut trade, OrderSide::Buy)
+                .unwrap();
+            book.market_state()
+        };
+
+        let first = settle(100.0, day);
+        assert_eq!(first.mark_price, Some(Price::from_f64(100.0)));
+        let second = settle(110.0, day);
+        assert_eq!(second.last_trade_price, Some(Price::from_f64(110.0)));
+        assert_eq!(second.mark_price, Some(Price::from_f64(102.0)));
+        assert_eq!(second.session_open_price, Some(Price::from_f64(100.0)));
+
+        // The next UTC day opens a new session
+        let next_day = day + chrono::Duration::days(1);
+        let next = settle(120.0, next_day);
+        assert_eq!(next.session_open_price, Some(Price::from_f64(120.0)));
+        assert_eq!(next.session_date, Some(next_day.date_naive()));
+    }
 }
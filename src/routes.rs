@@ -0,0 +1,595 @@
+use actix_web::middleware::{from_fn, DefaultHeaders};
+use actix_web::{web, HttpRequest};
+use actix_web_httpauth::middleware::HttpAuthentication;
+
+use crate::handlers;
+use crate::utils::{
+    admin_validator, depth_limit, envelope_errors, jwt_validator, maintenance_headers, request_id,
+    stamp_receipt,
+};
+
+/// Response header naming the API schema version that produced the response
+pub const API_VERSION_HEADER: &str = "API-Version";
+
+/// Versions of the HTTP schema. Each version gets its own route table; when a
+/// breaking change lands (e.g. decimal-string prices) it goes into a new version
+/// whose handlers speak the new shape, while the older table keeps adapting the
+/// old payloads to the same engine commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+    /// Every response in an `Envelope`, with pagination metadata on lists
+    V2,
+}
+
+impl ApiVersion {
+    /// Version served on unversioned `/api/...` paths, for clients predating versioning
+    pub const LEGACY: ApiVersion = ApiVersion::V1;
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "1",
+            ApiVersion::V2 => "2",
+        }
+    }
+
+    pub fn path_prefix(&self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/api/v1",
+            ApiVersion::V2 => "/api/v2",
+        }
+    }
+}
+
+/// Version of the route table that matched this request
+pub fn api_version(req: &HttpRequest) -> ApiVersion {
+    req.app_data::<ApiVersion>()
+        .copied()
+        .unwrap_or(ApiVersion::LEGACY)
+}
+
+/// Register every API version, plus the legacy unversioned paths
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    // Token verification keys live at the well-known path, outside any version
+    cfg.service(handlers::jwks);
+    // Probed by load balancers at a fixed path too
+    cfg.service(handlers::readyz);
+
+    // Versioned scopes must come first: `/api` would otherwise swallow `/api/v1/...`
+    cfg.service(
+        web::scope(ApiVersion::V2.path_prefix())
+            .app_data(ApiVersion::V2)
+            .wrap(DefaultHeaders::new().add((API_VERSION_HEADER, ApiVersion::V2.as_str())))
+            .wrap(from_fn(maintenance_headers))
+            .wrap(from_fn(envelope_errors))
+            .wrap(from_fn(request_id))
+            .wrap(from_fn(stamp_receipt))
+            .configure(v2)
+            .default_service(web::to(handlers::not_found)),
+    )
+    .service(
+        web::scope(ApiVersion::V1.path_prefix())
+            .app_data(ApiVersion::V1)
+            .wrap(DefaultHeaders::new().add((API_VERSION_HEADER, ApiVersion::V1.as_str())))
+            .wrap(from_fn(maintenance_headers))
+            .wrap(from_fn(request_id))
+            .wrap(from_fn(stamp_receipt))
+            .configure(v1),
+    )
+    .service(
+        web::scope("/api")
+            .app_data(ApiVersion::LEGACY)
+            .wrap(DefaultHeaders::new().add((API_VERSION_HEADER, ApiVersion::LEGACY.as_str())))
+            .wrap(from_fn(maintenance_headers))
+            .wrap(from_fn(request_id))
+            .wrap(from_fn(stamp_receipt))
+            .configure(v1),
+    );
+}
+
+/// Route table of schema version 2: the routes of version 1, each answering
+/// in the response envelope. The handlers pick the shape from the version.
+pub fn v2(cfg: &mut web::ServiceConfig) {
+    v1(cfg);
+}
+
+/// Route table of schema version 1
+pub fn v1(cfg: &mut web::ServiceConfig) {
+    // Create JWT auth middleware
+    let auth = HttpAuthentication::bearer(jwt_validator);
+    let admin_auth = HttpAuthentication::bearer(admin_validator);
+
+    cfg
+        // Health check
+        .service(handlers::health)
+        .service(handlers::metrics)
+        .service(handlers::server_time)
+        .service(handlers::get_event_schemas)
+        // Auth routes (no auth required)
+        .service(
+            web::scope("/auth")
+                .service(handlers::signup)
+                .service(handlers::signin)
+                .service(handlers::oidc_providers)
+                .service(handlers::oidc_login)
+                .service(handlers::oidc_callback),
+        )
+        // Market data (no auth required)
+        .service(handlers::get_markets)
+        .service(handlers::get_market_state)
+        .service(handlers::get_orderbook_heatmap)
+        // Diagnostic order-by-order book (admin token required)
+        .service(
+            web::scope("/orderbook/l3")
+                .wrap(admin_auth.clone())
+                .service(handlers::get_l3_orderbook),
+        )
+        // Public book, capped in depth unless a bearer token is given. After
+        // the other `/orderbook/...` routes, which this scope would swallow
+        .service(
+            web::scope("/orderbook")
+                .wrap(from_fn(depth_limit))
+                .service(handlers::get_orderbook),
+        )
+        .service(handlers::get_daily_stats)
+        .service(handlers::get_price_averages)
+        .service(handlers::get_imbalance)
+        .service(handlers::get_synthetic_indices)
+        .service(handlers::get_trade_tape)
+        .service(handlers::get_recent_trades)
+        .service(handlers::market_ws)
+        .service(handlers::stream_trades)
+        .service(handlers::stream_depth)
+        // GraphQL (bearer token optional, checked per field)
+        .service(handlers::graphql_query)
+        .service(handlers::graphql_playground)
+        .service(handlers::graphql_ws)
+        // Private account stream of the signed-in user
+        .service(
+            web::scope("/ws/user")
+                .wrap(auth.clone())
+                .service(handlers::user_ws),
+        )
+        // Protected routes (auth required)
+        .service(
+            web::scope("/orders")
+                .wrap(auth.clone())
+                .service(handlers::create_limit_order)
+                .service(handlers::create_order_batch)
+                .service(handlers::create_pegged_order)
+                .service(handlers::create_market_order)
+                .service(handlers::create_stop_order)
+                .service(handlers::cancel_order)
+                .service(handlers::mass_cancel)
+                .service(handlers::cancel_all_after)
+                .service(handlers::trading_ws)
+                .service(handlers::get_order_history)
+                // Before `/{order_id}/fills`, which would swallow a client ID of "fills"
+                .service(handlers::get_order_by_client_id)
+                .service(handlers::get_order_fills)
+                .service(handlers::amend_order),
+        )
+        .service(
+            web::scope("/user")
+                .wrap(auth)
+                .service(handlers::get_balance)
+                .service(handlers::get_account_summary)
+                .service(handlers::onramp)
+                .service(handlers::withdraw)
+                .service(handlers::list_funding_sources)
+                .service(handlers::link_funding_source)
+                .service(handlers::verify_funding_source)
+                .service(handlers::get_execution_quality)
+                .service(handlers::get_statement)
+                .service(handlers::get_user_trades)
+                .service(handlers::get_activity)
+                .service(handlers::export_history)
+                .service(handlers::get_preferences)
+                .service(handlers::update_preferences)
+                .service(handlers::get_interest_summary)
+                .service(handlers::set_interest_opt_in)
+                .service(handlers::get_leverage)
+                .service(handlers::update_leverage)
+                .service(handlers::get_liquidation_preview)
+                .service(handlers::create_api_key)
+                .service(handlers::list_api_keys)
+                .service(handlers::revoke_api_key)
+                .service(handlers::link_identity),
+        )
+        // Operator dashboard page; must precede the `/admin` scope, which would
+        // otherwise demand a token for the static page itself
+        .service(handlers::admin_dashboard)
+        // Operator routes (admin token required)
+        .service(
+            web::scope("/admin")
+                .wrap(admin_auth)
+                .service(handlers::get_leverage_tiers)
+                .service(handlers::update_leverage_tiers)
+                .service(handlers::get_source_volume)
+                .service(handlers::get_feed_activity)
+                .service(handlers::get_dashboard_summary)
+                .service(handlers::halt_market)
+                .service(handlers::resume_market)
+                .service(handlers::settle_market)
+                .service(handlers::set_trade_through_band)
+                .service(handlers::define_synthetic_index)
+                .service(handlers::remove_synthetic_index)
+                .service(handlers::force_cancel_order)
+                .service(handlers::adjust_balance)
+                .service(handlers::get_trial_balance)
+                .service(handlers::get_journals)
+                .service(handlers::get_fx_rates)
+                .service(handlers::set_fx_rate)
+                .service(handlers::remove_fx_rate)
+                .service(handlers::get_incidents)
+                .service(handlers::resolve_incident)
+                .service(handlers::get_dead_letters)
+                .service(handlers::retry_dead_letter)
+                .service(handlers::compensate_dead_letter)
+                .service(handlers::get_recovery_report)
+                .service(handlers::get_clock)
+                .service(handlers::advance_clock_by)
+                .service(handlers::announce_maintenance)
+                .service(handlers::clear_maintenance),
+        );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{MaintenanceNotice, MAINTENANCE_AT_HEADER, MAINTENANCE_MESSAGE_HEADER};
+    use crate::utils::REQUEST_ID_HEADER;
+    use actix_web::{test, App};
+
+    #[actix_web::test]
+    async fn test_versioned_and_legacy_paths_share_routes() {
+        let app = test::init_service(App::new().configure(configure)).await;
+
+        for path in ["/api/v1/health", "/api/health"] {
+            let req = test::TestRequest::get().uri(path).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success(), "{}", path);
+            assert_eq!(resp.headers().get(API_VERSION_HEADER).unwrap(), "1");
+        }
+
+        let req = test::TestRequest::get().uri("/api/v9/health").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 404);
+
+        let req = test::TestRequest::get()
+            .uri("/.well-known/jwks.json")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_receipt_is_stamped_before_slow_middleware_runs() {
+        use crate::utils::ReceivedAt;
+        use actix_web::{dev::Service, HttpResponse};
+        use chrono::Utc;
+
+        let app = test::init_service(
+            App::new()
+                .route(
+                    "/stamp",
+                    web::get().to(|req: HttpRequest| async move {
+                        HttpResponse::Ok().json((ReceivedAt::of(&req), Utc::now()))
+                    }),
+                )
+                // Stands in for authentication that takes a while
+                .wrap_fn(|req, srv| {
+                    let res = srv.call(req);
+                    async move {
+                        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                        res.await
+                    }
+                })
+                .wrap(from_fn(stamp_receipt)),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/stamp").to_request();
+        let (stamped, handled): (chrono::DateTime<Utc>, chrono::DateTime<Utc>) =
+            test::call_and_read_body_json(&app, req).await;
+        assert!(handled - stamped >= chrono::Duration::milliseconds(50));
+    }
+
+    #[actix_web::test]
+    async fn test_version_two_answers_in_the_envelope_with_the_request_id() {
+        let app = test::init_service(App::new().configure(configure)).await;
+        let get = |uri: &str| test::TestRequest::get().uri(uri);
+
+        let resp = test::call_service(&app, get("/api/v1/health").to_request()).await;
+        assert!(resp.headers().contains_key(REQUEST_ID_HEADER));
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body.get("data").is_none());
+
+        let req = get("/api/v2/health")
+            .insert_header((REQUEST_ID_HEADER, "trace-7"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get(API_VERSION_HEADER).unwrap(), "2");
+        assert_eq!(resp.headers().get(REQUEST_ID_HEADER).unwrap(), "trace-7");
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body["data"].is_object());
+        assert!(body["server_time"].is_string());
+        assert_eq!(body["request_id"], "trace-7");
+
+        // Errors carry the same ID, in the body as well as the header
+        let req = get("/api/v2/user/balance")
+            .insert_header((REQUEST_ID_HEADER, "not a usable id"))
+            .insert_header(("Authorization", "Bearer wrong"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+        let request_id = resp.headers().get(REQUEST_ID_HEADER).unwrap().clone();
+        assert_ne!(request_id, "not a usable id");
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"], serde_json::Value::Null);
+        assert_eq!(body["error"], "Invalid or expired token");
+        assert_eq!(body["request_id"], request_id.to_str().unwrap());
+
+        // So do paths no route matches
+        let resp = test::call_service(&app, get("/api/v2/no-such-route").to_request()).await;
+        assert_eq!(resp.status(), 404);
+        let request_id = resp.headers().get(REQUEST_ID_HEADER).unwrap().clone();
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["data"], serde_json::Value::Null);
+        assert_eq!(body["error"], "No route for /api/v2/no-such-route");
+        assert_eq!(body["request_id"], request_id.to_str().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_routes_need_the_api_key_scope_matching_what_they_do() {
+        use crate::storage::{signed_payload, ApiKeyStore};
+        use crate::types::ApiKeyScope::{self, Admin, Read, Trade, Withdraw};
+        use crate::utils::{secret_box, PageSigner};
+        use actix_web::http::Method;
+        use chrono::Utc;
+
+        let api_keys = web::Data::new(ApiKeyStore::new());
+        let app =
+            test::init_service(App::new().app_data(api_keys.clone()).configure(configure)).await;
+        let user_id = uuid::Uuid::new_v4();
+        let mut timestamp = Utc::now().timestamp_millis();
+        let mut call = async |scope: ApiKeyScope, method: Method, path: &str| {
+            let (key, secret) = api_keys
+                .create(secret_box(), user_id, None, vec![scope])
+                .await
+                .unwrap();
+            timestamp += 1;
+            let payload = signed_payload(&timestamp.to_string(), method.as_str(), path, b"");
+            let signature = PageSigner::new(secret, "").sign(payload.as_bytes());
+            let credential = format!("{}.{}.{}", key.key_id, timestamp, signature);
+            let req = test::TestRequest::default()
+                .method(method)
+                .uri(path)
+                .insert_header(("Authorization", format!("Bearer {}", credential)))
+                .to_request();
+            test::call_service(&app, req).await.status()
+        };
+
+        for (scope, method, path) in [
+            (Read, Method::GET, "/api/user/balance"),
+            (Read, Method::GET, "/api/v1/orders/history"),
+            (Read, Method::GET, "/api/orderbook?depth=500"),
+            (Trade, Method::POST, "/api/orders/limit"),
+            (Trade, Method::DELETE, "/api/orders/cancel"),
+            // Opened with a GET, but trades over the connection
+            (Trade, Method::GET, "/api/orders/ws"),
+            (Withdraw, Method::POST, "/api/user/withdraw"),
+            (Withdraw, Method::GET, "/api/user/funding-sources"),
+            (Admin, Method::GET, "/api/user/api-keys"),
+            (Admin, Method::POST, "/api/user/identities/google/link"),
+        ] {
+            for other in [Read, Trade, Withdraw, Admin] {
+                let status = call(other, method.clone(), path).await;
+                if other == scope {
+                    assert!(status != 401 && status != 403, "{} {}", path, status);
+                } else {
+                    assert_eq!(status, 403, "{} with {:?}", path, other);
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_routes_declaring_no_scope_refuse_api_keys() {
+        use crate::storage::{signed_payload, ApiKeyStore};
+        use crate::types::ApiKeyScope;
+        use crate::utils::{secret_box, PageSigner};
+        use actix_web::{HttpMessage, HttpResponse};
+        use actix_web_httpauth::middleware::HttpAuthentication;
+        use chrono::Utc;
+
+        let api_keys = web::Data::new(ApiKeyStore::new());
+        let app = test::init_service(
+            App::new().app_data(api_keys.clone()).service(
+                web::scope("/undeclared")
+                    .wrap(HttpAuthentication::bearer(jwt_validator))
+                    .route(
+                        "",
+                        web::get().to(|req: HttpRequest| async move {
+                            match req.extensions().get::<uuid::Uuid>() {
+                                Some(_) => HttpResponse::Ok().finish(),
+                                None => HttpResponse::Unauthorized().finish(),
+                            }
+                        }),
+                    ),
+            ),
+        )
+        .await;
+        let all = vec![
+            ApiKeyScope::Read,
+            ApiKeyScope::Trade,
+            ApiKeyScope::Withdraw,
+            ApiKeyScope::Admin,
+        ];
+        let (key, secret) = api_keys
+            .create(secret_box(), uuid::Uuid::new_v4(), None, all)
+            .await
+            .unwrap();
+        let timestamp = Utc::now().timestamp_millis().to_string();
+        let payload = signed_payload(&timestamp, "GET", "/undeclared", b"");
+        let signature = PageSigner::new(secret, "").sign(payload.as_bytes());
+        let req = test::TestRequest::get()
+            .uri("/undeclared")
+            .insert_header((
+                "Authorization",
+                format!("Bearer {}.{}.{}", key.key_id, timestamp, signature),
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn test_dashboard_page_is_public_but_its_data_is_not() {
+        let app = test::init_service(App::new().configure(configure)).await;
+
+        let req = test::TestRequest::get().uri("/api/v1/admin/dashboard").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri("/api/v1/admin/dashboard/summary")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+
+        // Without ADMIN_TOKEN configured, no token opens it
+        if crate::utils::admin_token().is_none() {
+            let req = test::TestRequest::get()
+                .uri("/api/v1/admin/dashboard/summary")
+                .insert_header((
+                    "Authorization",
+                    "Bearer your-admin-token-change-in-production",
+                ))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 403);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_readyz_fails_until_the_engine_is_ready() {
+        let metrics = std::sync::Arc::new(crate::engine::EngineMetrics::new());
+        let state = crate::state::AppState::new(tokio::sync::mpsc::channel(1).0, metrics.clone());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "starting");
+
+        metrics.mark_ready();
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "ready");
+    }
+
+    #[actix_web::test]
+    async fn test_readyz_fails_when_the_engine_does_not_answer_pings() {
+        let metrics = std::sync::Arc::new(crate::engine::EngineMetrics::new());
+        metrics.mark_ready();
+        let (control_tx, control_rx) = crate::engine::control_channel();
+        let state = crate::state::AppState::new(tokio::sync::mpsc::channel(1).0, metrics)
+            .with_control(control_tx);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+
+        drop(control_rx);
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+    }
+
+    #[actix_web::test]
+    async fn test_deep_books_need_a_bearer_token() {
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let metrics = std::sync::Arc::new(crate::engine::EngineMetrics::new());
+        tokio::spawn(crate::engine::run_orderbook_engine(
+            rx,
+            metrics.clone(),
+            crate::engine::event_channel(),
+            crate::engine::EngineConfig::default(),
+        ));
+        let state = crate::state::AppState::new(tx, metrics).with_public_depth_limit(5);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(crate::handlers::auth::UserStore::new()))
+                .configure(configure),
+        )
+        .await;
+        let get = |uri: &str| test::TestRequest::get().uri(uri);
+
+        for uri in ["/api/orderbook", "/api/v1/orderbook?depth=5"] {
+            let resp = test::call_service(&app, get(uri).to_request()).await;
+            assert!(resp.status().is_success(), "{}", uri);
+        }
+        let resp = test::call_service(&app, get("/api/orderbook?depth=6").to_request()).await;
+        assert_eq!(resp.status(), 401);
+        // The routes under `/orderbook/...` are still reached
+        let resp = test::call_service(&app, get("/api/orderbook/l3").to_request()).await;
+        assert_eq!(resp.status(), 401);
+
+        let token = crate::utils::generate_token(uuid::Uuid::new_v4(), "deep".to_string()).unwrap();
+        let deep = get("/api/orderbook?depth=500")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request();
+        assert!(test::call_service(&app, deep).await.status().is_success());
+        let forged = get("/api/orderbook?depth=500")
+            .insert_header(("Authorization", "Bearer forged"))
+            .to_request();
+        assert_eq!(test::call_service(&app, forged).await.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn test_responses_name_announced_maintenance() {
+        let metrics = std::sync::Arc::new(crate::engine::EngineMetrics::new());
+        let state = crate::state::AppState::new(tokio::sync::mpsc::channel(1).0, metrics);
+        let maintenance = state.maintenance.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .configure(configure),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/api/health").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.headers().get(MAINTENANCE_MESSAGE_HEADER).is_none());
+
+        let now = chrono::Utc::now();
+        let scheduled_at = now + chrono::Duration::minutes(30);
+        maintenance.announce(MaintenanceNotice::new("Engine upgrade", scheduled_at, now).unwrap());
+        for path in ["/api/health", "/api/v1/health"] {
+            let req = test::TestRequest::get().uri(path).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(
+                resp.headers().get(MAINTENANCE_MESSAGE_HEADER).unwrap(),
+                "Engine upgrade"
+            );
+            assert_eq!(
+                resp.headers().get(MAINTENANCE_AT_HEADER).unwrap(),
+                scheduled_at
+                    .to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+                    .as_str()
+            );
+        }
+    }
+}
@@ -1,18 +1,343 @@
-use crate::messages::OrderBookCommand;
+use crate::engine::{env_parse, event_channel, ControlCommand, EngineMetrics, EventChannels};
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::state::{AuditLog, MaintenanceBoard, Profile, WsLimits, WsRegistry};
+use crate::utils::error::ApiError;
+use crate::utils::fx::FxRates;
+use crate::utils::signing::PageSigner;
+use actix_web::HttpRequest;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// How long a handler waits for the engine before giving up
+pub const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Levels per side `/orderbook` serves without a bearer token
+pub const DEFAULT_PUBLIC_DEPTH_LIMIT: usize = 50;
+
+/// The public depth limit from `PUBLIC_DEPTH_LIMIT`, or the default
+pub fn public_depth_limit_from_env() -> usize {
+    env_parse("PUBLIC_DEPTH_LIMIT")
+        .filter(|levels| *levels > 0)
+        .unwrap_or(DEFAULT_PUBLIC_DEPTH_LIMIT)
+}
+
+/// How long a newly linked withdrawal address waits before funds may go to it
+pub const DEFAULT_WITHDRAWAL_ADDRESS_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The withdrawal address delay from `WITHDRAWAL_ADDRESS_DELAY_SECS`, or the
+/// default; 0 turns it off
+pub fn withdrawal_address_delay_from_env() -> Duration {
+    env_parse("WITHDRAWAL_ADDRESS_DELAY_SECS")
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_WITHDRAWAL_ADDRESS_DELAY)
+}
+
+/// Addresses from `TRUSTED_PROXIES`, comma-separated; none unless set. A
+/// malformed entry is an error, since skipping it would trust the wrong hops.
+pub fn trusted_proxies_from_env() -> Result<Vec<IpAddr>, String> {
+    let Ok(spec) = std::env::var("TRUSTED_PROXIES") else {
+        return Ok(Vec::new());
+    };
+    spec.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse()
+                .map_err(|_| format!("TRUSTED_PROXIES entry '{}' is not an IP address", entry))
+        })
+        .collect()
+}
 
 /// Application state shared across Actix-web workers
 /// Contains the sender end of the mpsc channel to communicate with OrderBook engine
 #[derive(Clone)]
 pub struct AppState {
     pub orderbook_tx: Arc<mpsc::Sender<OrderBookCommand>>,
+    pub metrics: Arc<EngineMetrics>,
+    pub command_timeout: Duration,
+    pub fx_rates: Arc<FxRates>,
+    pub tape_signer: Arc<PageSigner>,
+    pub events: EventChannels,
+    pub profile: Arc<Profile>,
+    pub maintenance: Arc<MaintenanceBoard>,
+    pub control_tx: Option<mpsc::Sender<ControlCommand>>, // Express lane for health pings
+    pub websockets: Arc<WsRegistry>,
+    pub audit: Arc<AuditLog>, // Sign-ins, API key changes and address notices, for the activity feed
+    pub public_depth_limit: usize, // Deeper books take a bearer token
+    pub withdrawal_address_delay: Duration, // Before a newly linked address can receive funds
+    pub trusted_proxies: Arc<Vec<IpAddr>>, // Whose forwarding headers name the client
 }
 
 impl AppState {
-    pub fn new(orderbook_tx: mpsc::Sender<OrderBookCommand>) -> Self {
+    pub fn new(orderbook_tx: mpsc::Sender<OrderBookCommand>, metrics: Arc<EngineMetrics>) -> Self {
         AppState {
             orderbook_tx: Arc::new(orderbook_tx),
+            metrics,
+            command_timeout: DEFAULT_COMMAND_TIMEOUT,
+            fx_rates: Arc::new(FxRates::default()),
+            tape_signer: Arc::new(PageSigner::default()),
+            events: event_channel(),
+            profile: Arc::new(Profile::default()),
+            maintenance: Arc::new(MaintenanceBoard::default()),
+            control_tx: None,
+            websockets: Arc::new(WsRegistry::default()),
+            audit: Arc::new(AuditLog::new()),
+            public_depth_limit: DEFAULT_PUBLIC_DEPTH_LIMIT,
+            withdrawal_address_delay: DEFAULT_WITHDRAWAL_ADDRESS_DELAY,
+            trusted_proxies: Arc::new(Vec::new()),
         }
     }
+
+    /// Share the engine's event channels so handlers can subscribe to them
+    pub fn with_events(mut self, events: EventChannels) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Let health checks ping the engine on its control channel
+    pub fn with_control(mut self, control_tx: mpsc::Sender<ControlCommand>) -> Self {
+        self.control_tx = Some(control_tx);
+        self
+    }
+
+    /// Heartbeat, idle and connection limits for the WebSocket endpoints
+    pub fn with_ws_limits(mut self, limits: WsLimits) -> Self {
+        self.websockets = Arc::new(WsRegistry::new(limits));
+        self
+    }
+
+    /// Cap order book requests without a bearer token at `levels` per side
+    pub fn with_public_depth_limit(mut self, levels: usize) -> Self {
+        self.public_depth_limit = levels;
+        self
+    }
+
+    /// Keep the audit trail in `audit` rather than process memory
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Arc::new(audit);
+        self
+    }
+
+    /// Hold withdrawals to newly linked addresses for `delay`
+    pub fn with_withdrawal_address_delay(mut self, delay: Duration) -> Self {
+        self.withdrawal_address_delay = delay;
+        self
+    }
+
+    /// Believe the `Forwarded` and `X-Forwarded-For` headers of requests
+    /// arriving from these addresses
+    pub fn with_trusted_proxies(mut self, proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = Arc::new(proxies);
+        self
+    }
+
+    /// Address of the client behind `req`: the connection's peer, unless the
+    /// peer is a trusted proxy that says who it forwarded for. Headers from
+    /// anyone else are ignored, since a client can write whatever it likes.
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+        let info = req.connection_info();
+        let forwarded = info.realip_remote_addr().and_then(|addr| {
+            addr.parse::<IpAddr>()
+                .ok()
+                .or_else(|| addr.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        });
+        Some(forwarded.unwrap_or(peer))
+    }
+
+    pub fn with_profile(mut self, profile: Profile) -> Self {
+        self.profile = Arc::new(profile);
+        self
+    }
+
+    pub fn with_fx_rates(mut self, fx_rates: FxRates) -> Self {
+        self.fx_rates = Arc::new(fx_rates);
+        self
+    }
+
+    pub fn with_tape_signer(mut self, tape_signer: PageSigner) -> Self {
+        self.tape_signer = Arc::new(tape_signer);
+        self
+    }
+
+    /// Deadline for a command issued now
+    pub fn deadline(&self) -> Instant {
+        Instant::now() + self.command_timeout
+    }
+
+    /// Send a command to the engine and wait for its response until `deadline`.
+    /// If the wait is abandoned (timeout or client disconnect) the receiver is dropped,
+    /// which the engine observes as a cancelled query.
+    pub async fn dispatch(
+        &self,
+        command: OrderBookCommand,
+        response_rx: oneshot::Receiver<OrderBookResponse>,
+        deadline: Instant,
+    ) -> Result<OrderBookResponse, ApiError> {
+        tokio::time::timeout_at(deadline, self.orderbook_tx.send(command))
+            .await
+            .map_err(|_| ApiError::Timeout("Orderbook engine is busy".to_string()))?
+            .map_err(|_| {
+                ApiError::InternalError("Failed to send command to orderbook".to_string())
+            })?;
+
+        tokio::time::timeout_at(deadline, response_rx)
+            .await
+            .map_err(|_| ApiError::Timeout("Orderbook engine did not respond in time".to_string()))?
+            .map_err(|_| {
+                ApiError::InternalError("Failed to receive response from orderbook".to_string())
+            })
+    }
+
+    /// `dispatch` for a mutation whose outcome must not be lost when the
+    /// caller stops waiting. A queued command runs even after `deadline`, so
+    /// instead of dropping the late response this hands it to `settle` once
+    /// it arrives; `settle` gets None if the command never ran. Whenever this
+    /// returns an error, `settle` has run or will.
+    pub async fn dispatch_or_settle<F, Fut>(
+        &self,
+        command: OrderBookCommand,
+        mut response_rx: oneshot::Receiver<OrderBookResponse>,
+        deadline: Instant,
+        settle: F,
+    ) -> Result<OrderBookResponse, ApiError>
+    where
+        F: FnOnce(Option<OrderBookResponse>) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let sent = match tokio::time::timeout_at(deadline, self.orderbook_tx.send(command)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(ApiError::InternalError(
+                "Failed to send command to orderbook".to_string(),
+            )),
+            Err(_) => Err(ApiError::Timeout("Orderbook engine is busy".to_string())),
+        };
+        if let Err(e) = sent {
+            settle(None).await;
+            return Err(e);
+        }
+
+        match tokio::time::timeout_at(deadline, &mut response_rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => {
+                settle(None).await;
+                Err(ApiError::InternalError(
+                    "Failed to receive response from orderbook".to_string(),
+                ))
+            }
+            Err(_) => {
+                tokio::spawn(async move { settle(response_rx.await.ok()).await });
+                Err(ApiError::Timeout(
+                    "Orderbook engine did not respond in time".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn withdrawal(response_tx: oneshot::Sender<OrderBookResponse>) -> OrderBookCommand {
+        OrderBookCommand::WithdrawFunds {
+            user_id: Uuid::new_v4(),
+            currency: "USD".to_string(),
+            amount: 1.0,
+            response_tx,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_a_late_response_is_settled_rather_than_dropped() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let state = AppState::new(tx, Arc::new(EngineMetrics::new()));
+        // An engine that answers only after the caller has given up
+        tokio::spawn(async move {
+            while let Some(OrderBookCommand::WithdrawFunds { response_tx, .. }) = rx.recv().await {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let _ = response_tx.send(OrderBookResponse::Error {
+                    message: "late".to_string(),
+                });
+            }
+        });
+
+        let (settled_tx, settled_rx) = oneshot::channel();
+        let (response_tx, response_rx) = oneshot::channel();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let result = state
+            .dispatch_or_settle(
+                withdrawal(response_tx),
+                response_rx,
+                deadline,
+                |late| async move {
+                    let _ = settled_tx.send(late);
+                },
+            )
+            .await;
+        assert!(matches!(result, Err(ApiError::Timeout(_))));
+        assert!(matches!(
+            settled_rx.await.unwrap(),
+            Some(OrderBookResponse::Error { message }) if message == "late"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_a_command_never_queued_is_settled_with_nothing() {
+        let (tx, _rx) = mpsc::channel(1);
+        let state = AppState::new(tx, Arc::new(EngineMetrics::new()));
+        // The queue is full, so the next command cannot be sent in time
+        state
+            .orderbook_tx
+            .send(withdrawal(oneshot::channel().0))
+            .await
+            .unwrap();
+
+        let (settled_tx, settled_rx) = oneshot::channel();
+        let (response_tx, response_rx) = oneshot::channel();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let result = state
+            .dispatch_or_settle(
+                withdrawal(response_tx),
+                response_rx,
+                deadline,
+                |late| async move {
+                    let _ = settled_tx.send(late);
+                },
+            )
+            .await;
+        assert!(matches!(result, Err(ApiError::Timeout(_))));
+        assert!(settled_rx.await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_forwarding_headers_count_only_from_trusted_proxies() {
+        use actix_web::test::TestRequest;
+
+        let proxy: SocketAddr = "10.0.0.2:443".parse().unwrap();
+        let request = |peer: SocketAddr| {
+            TestRequest::default()
+                .peer_addr(peer)
+                .insert_header(("X-Forwarded-For", "203.0.113.9"))
+                .to_http_request()
+        };
+        let (orderbook_tx, _) = mpsc::channel(1);
+        let state = AppState::new(orderbook_tx, Arc::new(EngineMetrics::new()));
+        let direct: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(state.client_ip(&request(proxy)), Some(direct));
+
+        let state = state.with_trusted_proxies(vec![proxy.ip()]);
+        let client: IpAddr = "203.0.113.9".parse().unwrap();
+        assert_eq!(state.client_ip(&request(proxy)), Some(client));
+        let stranger: SocketAddr = "198.51.100.7:5000".parse().unwrap();
+        assert_eq!(state.client_ip(&request(stranger)), Some(stranger.ip()));
+    }
 }
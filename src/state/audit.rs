@@ -0,0 +1,105 @@
+use crate::storage::{HistoryQuery, MemoryUserRepository, UserRepository};
+use crate::types::{Activity, ActivityEntry};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Sign-ins, API key changes and withdrawal address notices, which happen
+/// outside the engine and so have no record in its order history or ledger.
+/// Kept in the user repository, so they survive restarts and instances that
+/// share accounts share them too.
+pub struct AuditLog {
+    repository: Arc<dyn UserRepository>,
+}
+
+impl AuditLog {
+    /// Entries kept in process memory, lost on restart
+    pub fn new() -> Self {
+        Self::with_repository(Arc::new(MemoryUserRepository::new()))
+    }
+
+    pub fn with_repository(repository: Arc<dyn UserRepository>) -> Self {
+        AuditLog { repository }
+    }
+
+    /// Store `activity` at `at`, returning the entry as stored
+    pub async fn record(
+        &self,
+        user_id: Uuid,
+        activity: Activity,
+        at: DateTime<Utc>,
+    ) -> Result<ActivityEntry, String> {
+        let entry = ActivityEntry::new(at, activity);
+        self.repository.insert_activity(user_id, &entry).await?;
+        Ok(entry)
+    }
+
+    /// The page of a user's entries that `query` selects, newest first
+    pub async fn for_user(
+        &self,
+        user_id: Uuid,
+        query: &HistoryQuery,
+    ) -> Result<Vec<ActivityEntry>, String> {
+        self.repository.activities(user_id, query).await
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MEMORY_ACTIVITY_RETENTION;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn test_entries_are_kept_per_user_and_paged_newest_first() {
+        let log = AuditLog::new();
+        let (user, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+        let login = |ip: &str| Activity::Login {
+            method: "password".to_string(),
+            ip: Some(ip.to_string()),
+        };
+
+        log.record(user, login("10.0.0.1"), now - Duration::minutes(2))
+            .await
+            .unwrap();
+        log.record(other, login("10.0.0.9"), now - Duration::minutes(1))
+            .await
+            .unwrap();
+        let revoked = Activity::ApiKeyRevoked {
+            key_id: "ak_1".to_string(),
+        };
+        log.record(user, revoked.clone(), now).await.unwrap();
+
+        let page = log.for_user(user, &HistoryQuery::latest(10)).await.unwrap();
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].activity, revoked);
+        assert_eq!(page[1].activity, login("10.0.0.1"));
+        let second_page = HistoryQuery {
+            offset: 1,
+            ..HistoryQuery::latest(1)
+        };
+        let second = log.for_user(user, &second_page).await.unwrap();
+        assert_eq!(second, page[1..].to_vec());
+        let before_now = HistoryQuery {
+            to: Some(now),
+            ..HistoryQuery::latest(10)
+        };
+        let earlier = log.for_user(user, &before_now).await.unwrap();
+        assert_eq!(earlier, page[1..].to_vec());
+
+        for _ in 0..MEMORY_ACTIVITY_RETENTION {
+            log.record(other, revoked.clone(), now).await.unwrap();
+        }
+        let everything = HistoryQuery::latest(usize::MAX);
+        let kept = log.for_user(other, &everything).await.unwrap();
+        assert_eq!(kept.len(), MEMORY_ACTIVITY_RETENTION);
+        assert!(kept.iter().all(|entry| entry.activity == revoked));
+    }
+}
@@ -0,0 +1,113 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Response header carrying the start of announced maintenance (RFC 3339)
+pub const MAINTENANCE_AT_HEADER: &str = "Maintenance-Scheduled-At";
+
+/// Response header carrying the operator's maintenance message
+pub const MAINTENANCE_MESSAGE_HEADER: &str = "Maintenance-Message";
+
+/// Longest message accepted, so it fits comfortably in a header
+pub const MAX_MAINTENANCE_MESSAGE: usize = 200;
+
+/// Operator warning of an upcoming halt or restart
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceNotice {
+    pub message: String,
+    pub scheduled_at: DateTime<Utc>, // When the maintenance starts
+    pub announced_at: DateTime<Utc>,
+}
+
+impl MaintenanceNotice {
+    /// The message goes out as a header value, so it must be printable ASCII
+    pub fn new(
+        message: &str,
+        scheduled_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<Self, String> {
+        let message = message.trim();
+        if message.is_empty() {
+            return Err("Message must not be empty".to_string());
+        }
+        if message.len() > MAX_MAINTENANCE_MESSAGE {
+            return Err(format!(
+                "Message may be at most {} characters",
+                MAX_MAINTENANCE_MESSAGE
+            ));
+        }
+        if !message.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+            return Err("Message must be printable ASCII".to_string());
+        }
+        if scheduled_at <= now {
+            return Err("scheduled_at must be in the future".to_string());
+        }
+        Ok(MaintenanceNotice {
+            message: message.to_string(),
+            scheduled_at,
+            announced_at: now,
+        })
+    }
+}
+
+/// The maintenance notice currently announced, if any. Every change reaches
+/// all receivers from `subscribe`; the notice stays up until it is cleared.
+#[derive(Debug)]
+pub struct MaintenanceBoard {
+    notice: watch::Sender<Option<MaintenanceNotice>>,
+}
+
+impl Default for MaintenanceBoard {
+    fn default() -> Self {
+        MaintenanceBoard {
+            notice: watch::channel(None).0,
+        }
+    }
+}
+
+impl MaintenanceBoard {
+    pub fn current(&self) -> Option<MaintenanceNotice> {
+        self.notice.borrow().clone()
+    }
+
+    /// Replace the current notice, returning the one it replaced
+    pub fn announce(&self, notice: MaintenanceNotice) -> Option<MaintenanceNotice> {
+        self.notice.send_replace(Some(notice))
+    }
+
+    /// Withdraw the current notice, returning it
+    pub fn clear(&self) -> Option<MaintenanceNotice> {
+        self.notice.send_replace(None)
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<MaintenanceNotice>> {
+        self.notice.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_notice_validation_and_board() {
+        let now = Utc::now();
+        let later = now + Duration::hours(1);
+        assert!(MaintenanceNotice::new("  ", later, now).is_err());
+        assert!(MaintenanceNotice::new("Upgrade", now, now).is_err());
+        assert!(MaintenanceNotice::new("Mise à jour", later, now).is_err());
+        assert!(MaintenanceNotice::new(&"x".repeat(201), later, now).is_err());
+
+        let board = MaintenanceBoard::default();
+        let mut rx = board.subscribe();
+        let notice = MaintenanceNotice::new(" Engine upgrade ", later, now).unwrap();
+        assert_eq!(notice.message, "Engine upgrade");
+        assert_eq!(board.announce(notice.clone()), None);
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(*rx.borrow_and_update(), Some(notice.clone()));
+        assert_eq!(board.clear(), Some(notice));
+        assert_eq!(board.current(), None);
+        assert!(rx.has_changed().unwrap());
+    }
+}
@@ -1,2 +1,11 @@
 pub mod app_state;
+pub mod audit;
+pub mod maintenance;
+pub mod profile;
+pub mod ws_limits;
+
 pub use app_state::*;
+pub use audit::*;
+pub use maintenance::*;
+pub use profile::*;
+pub use ws_limits::*;
@@ -0,0 +1,263 @@
+use crate::engine::config::env_parse;
+use serde::Serialize;
+use std::str::FromStr;
+
+/// Secrets that otherwise fall back to a placeholder or a temporary key;
+/// strict auth refuses to start while any of them is left unset
+pub const REQUIRED_SECRETS: [&str; 4] = [
+    "JWT_PRIVATE_KEY",
+    "ADMIN_TOKEN",
+    "TAPE_SIGNING_KEY",
+    "API_KEY_ENCRYPTION_KEY",
+];
+
+/// Fragments of the example values shipped in docs and defaults. A secret
+/// containing one was copied rather than generated.
+const PLACEHOLDER_MARKERS: [&str; 5] = [
+    "change-in-production",
+    "changeme",
+    "change-me",
+    "placeholder",
+    "your-",
+];
+
+/// Whether `value` is empty or one of the example values
+pub fn is_placeholder_secret(value: &str) -> bool {
+    let value = value.trim().to_lowercase();
+    value.is_empty()
+        || PLACEHOLDER_MARKERS
+            .iter()
+            .any(|marker| value.contains(marker))
+}
+
+/// Deployment the server runs as, selected with `APP_ENV`. There is no
+/// fallback: a server started without it refuses to run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Environment {
+    #[default]
+    Dev,
+    Staging,
+    Prod,
+}
+
+impl FromStr for Environment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dev" | "development" | "local" => Ok(Environment::Dev),
+            "staging" | "stage" => Ok(Environment::Staging),
+            "prod" | "production" => Ok(Environment::Prod),
+            _ => Err(format!(
+                "Invalid environment '{}', use 'dev', 'staging' or 'prod'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text, // Human-readable lines
+    Json, // One JSON object per line, for log shippers
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" | "plain" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Invalid log format '{}', use 'text' or 'json'", s)),
+        }
+    }
+}
+
+/// Behavior that differs between deployments. Each environment has a preset;
+/// individual settings can still be overridden through their own variables.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Profile {
+    pub environment: Environment,
+    /// Append finalized daily stats to a file; otherwise keep them in memory
+    pub persist_stats: bool,
+    /// Refuse to start while any of `REQUIRED_SECRETS` is left at its default
+    pub strict_auth: bool,
+    /// Market-making bots run inside the server to keep the book populated
+    pub simulator_bots: usize,
+    /// Whether `/user/onramp` hands out funds
+    pub faucet: bool,
+    /// Whether admins may advance the engine clock to fire time-based work early
+    pub test_clock: bool,
+    pub log_format: LogFormat,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::preset(Environment::default())
+    }
+}
+
+impl Profile {
+    /// Settings an environment starts from before any overrides
+    pub fn preset(environment: Environment) -> Self {
+        match environment {
+            Environment::Dev => Profile {
+                environment,
+                persist_stats: false,
+                strict_auth: false,
+                simulator_bots: 2,
+                faucet: true,
+                test_clock: true,
+                log_format: LogFormat::Text,
+            },
+            Environment::Staging => Profile {
+                environment,
+                persist_stats: true,
+                strict_auth: true,
+                simulator_bots: 0,
+                faucet: true,
+                test_clock: false,
+                log_format: LogFormat::Json,
+            },
+            Environment::Prod => Profile {
+                environment,
+                persist_stats: true,
+                strict_auth: true,
+                simulator_bots: 0,
+                faucet: false,
+                test_clock: false,
+                log_format: LogFormat::Json,
+            },
+        }
+    }
+
+    /// Preset named by `APP_ENV`, then `PERSIST_STATS`, `STRICT_AUTH`,
+    /// `SIMULATOR_BOTS`, `FAUCET`, `TEST_CLOCK` and `LOG_FORMAT` on top.
+    /// A missing or unrecognized `APP_ENV` is an error rather than a silent
+    /// dev server.
+    pub fn from_env() -> Result<Self, String> {
+        let environment = Self::environment(std::env::var("APP_ENV").ok().as_deref())?;
+        let preset = Profile::preset(environment);
+
+        Ok(Profile {
+            environment,
+            persist_stats: env_parse("PERSIST_STATS").unwrap_or(preset.persist_stats),
+            strict_auth: env_parse("STRICT_AUTH").unwrap_or(preset.strict_auth),
+            simulator_bots: env_parse("SIMULATOR_BOTS").unwrap_or(preset.simulator_bots),
+            faucet: env_parse("FAUCET").unwrap_or(preset.faucet),
+            test_clock: env_parse("TEST_CLOCK").unwrap_or(preset.test_clock),
+            log_format: env_parse("LOG_FORMAT").unwrap_or(preset.log_format),
+        })
+    }
+
+    /// Environment `app_env` names, which must be given
+    fn environment(app_env: Option<&str>) -> Result<Environment, String> {
+        match app_env.map(str::trim).filter(|name| !name.is_empty()) {
+            Some(name) => name.parse(),
+            None => Err("APP_ENV must be set to 'dev', 'staging' or 'prod'".to_string()),
+        }
+    }
+
+    /// Names of required secrets `is_set` reports missing, if auth is strict.
+    /// Outside dev it always is: `STRICT_AUTH` can't switch it off there.
+    pub fn missing_secrets(&self, is_set: impl Fn(&str) -> bool) -> Vec<&'static str> {
+        if !self.strict_auth && self.environment == Environment::Dev {
+            return Vec::new();
+        }
+        REQUIRED_SECRETS
+            .into_iter()
+            .filter(|name| !is_set(name))
+            .collect()
+    }
+
+    /// Names of required secrets set to an example value, outside dev
+    pub fn placeholder_secrets(
+        &self,
+        value_of: impl Fn(&str) -> Option<String>,
+    ) -> Vec<&'static str> {
+        if self.environment == Environment::Dev {
+            return Vec::new();
+        }
+        REQUIRED_SECRETS
+            .into_iter()
+            .filter(|name| value_of(name).is_some_and(|value| is_placeholder_secret(&value)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets_and_parsing() {
+        assert_eq!(
+            "production".parse::<Environment>().unwrap(),
+            Environment::Prod
+        );
+        assert_eq!(
+            "Staging".parse::<Environment>().unwrap(),
+            Environment::Staging
+        );
+        assert!("qa".parse::<Environment>().is_err());
+        assert_eq!("JSON".parse::<LogFormat>().unwrap(), LogFormat::Json);
+
+        let dev = Profile::default();
+        assert_eq!(dev.environment, Environment::Dev);
+        assert!(dev.faucet && dev.test_clock && !dev.strict_auth && !dev.persist_stats);
+
+        let prod = Profile::preset(Environment::Prod);
+        assert!(!prod.faucet && !prod.test_clock && prod.strict_auth && prod.persist_stats);
+        assert_eq!(prod.simulator_bots, 0);
+        assert_eq!(prod.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn test_strict_auth_lists_unset_secrets() {
+        let prod = Profile::preset(Environment::Prod);
+        let missing = prod.missing_secrets(|name| name == "JWT_PRIVATE_KEY");
+        assert_eq!(
+            missing,
+            vec!["ADMIN_TOKEN", "TAPE_SIGNING_KEY", "API_KEY_ENCRYPTION_KEY"]
+        );
+        assert!(Profile::default().missing_secrets(|_| false).is_empty());
+
+        // Turning strict auth off does not excuse them outside dev
+        let relaxed = Profile {
+            strict_auth: false,
+            ..Profile::preset(Environment::Staging)
+        };
+        assert_eq!(
+            relaxed.missing_secrets(|_| false).len(),
+            REQUIRED_SECRETS.len()
+        );
+    }
+
+    #[test]
+    fn test_app_env_must_be_given() {
+        assert!(Profile::environment(None).is_err());
+        assert!(Profile::environment(Some(" ")).is_err());
+        assert!(Profile::environment(Some("qa")).is_err());
+        assert_eq!(Profile::environment(Some("dev")), Ok(Environment::Dev));
+    }
+
+    #[test]
+    fn test_example_secrets_are_refused_outside_dev() {
+        let value_of = |name: &str| match name {
+            "ADMIN_TOKEN" => Some("your-admin-token-change-in-production".to_string()),
+            "TAPE_SIGNING_KEY" => Some("CHANGEME".to_string()),
+            "JWT_PRIVATE_KEY" => Some("".to_string()),
+            _ => Some("b7f3c1d9e2a04f6c8e1d3b5a7c9e0f2a".to_string()),
+        };
+        let prod = Profile::preset(Environment::Prod);
+        assert_eq!(
+            prod.placeholder_secrets(value_of),
+            vec!["JWT_PRIVATE_KEY", "ADMIN_TOKEN", "TAPE_SIGNING_KEY"]
+        );
+        assert!(Profile::default().placeholder_secrets(value_of).is_empty());
+    }
+}
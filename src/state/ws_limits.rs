@@ -0,0 +1,200 @@
+use crate::engine::env_parse;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Heartbeat, idle and backpressure settings shared by the WebSocket endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WsLimits {
+    pub heartbeat_interval: Duration, // The server pings this often
+    pub idle_timeout: Duration,       // Closed after hearing nothing from the client this long
+    pub send_timeout: Duration,       // A client that takes no frame for this long is dropped
+    pub max_connections: usize,       // Open sockets across every WebSocket endpoint
+    pub max_per_user: usize,          // Open sockets per signed-in user
+    pub max_per_ip: usize,            // Open sockets per client address, signed in or not
+    pub max_subscriptions: usize,     // Subscriptions one socket may hold at once
+}
+
+impl Default for WsLimits {
+    fn default() -> Self {
+        WsLimits {
+            heartbeat_interval: Duration::from_secs(15),
+            idle_timeout: Duration::from_secs(45),
+            send_timeout: Duration::from_secs(5),
+            max_connections: 1_000,
+            max_per_user: 10,
+            max_per_ip: 20,
+            max_subscriptions: 20,
+        }
+    }
+}
+
+impl WsLimits {
+    /// Read limits from environment variables, defaulting any unset
+    pub fn from_env() -> Self {
+        let defaults = WsLimits::default();
+        let secs = |key: &str, default: Duration| {
+            env_parse::<u64>(key)
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+        WsLimits {
+            heartbeat_interval: secs("WS_HEARTBEAT_SECS", defaults.heartbeat_interval),
+            idle_timeout: secs("WS_IDLE_TIMEOUT_SECS", defaults.idle_timeout),
+            send_timeout: secs("WS_SEND_TIMEOUT_SECS", defaults.send_timeout),
+            max_connections: env_parse("WS_MAX_CONNECTIONS").unwrap_or(defaults.max_connections),
+            max_per_user: env_parse("WS_MAX_CONNECTIONS_PER_USER").unwrap_or(defaults.max_per_user),
+            max_per_ip: env_parse("WS_MAX_CONNECTIONS_PER_IP").unwrap_or(defaults.max_per_ip),
+            max_subscriptions: env_parse("WS_MAX_SUBSCRIPTIONS")
+                .unwrap_or(defaults.max_subscriptions),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct OpenSockets {
+    total: usize,
+    per_user: HashMap<Uuid, usize>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// How many sockets `key` has open
+fn count<K: Eq + Hash>(counts: &HashMap<K, usize>, key: K) -> usize {
+    counts.get(&key).copied().unwrap_or(0)
+}
+
+/// Take one off `key`'s count, forgetting it at zero
+fn release<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+/// The WebSocket connections open now, counted against `WsLimits`
+#[derive(Debug, Default)]
+pub struct WsRegistry {
+    pub limits: WsLimits,
+    open: Arc<Mutex<OpenSockets>>,
+}
+
+impl WsRegistry {
+    pub fn new(limits: WsLimits) -> Self {
+        WsRegistry {
+            limits,
+            open: Arc::default(),
+        }
+    }
+
+    /// Claim a slot for a new connection from `ip`, if the limits leave one.
+    /// The public market feed has no `user_id` and counts only towards the
+    /// total and its address.
+    pub fn open(&self, user_id: Option<Uuid>, ip: Option<IpAddr>) -> Result<WsSlot, String> {
+        let mut open = self.open.lock().unwrap();
+        if open.total >= self.limits.max_connections {
+            return Err("Too many WebSocket connections, try again later".to_string());
+        }
+        if ip.is_some_and(|ip| count(&open.per_ip, ip) >= self.limits.max_per_ip) {
+            return Err(format!(
+                "At most {} WebSocket connections per address",
+                self.limits.max_per_ip
+            ));
+        }
+        if user_id.is_some_and(|user_id| count(&open.per_user, user_id) >= self.limits.max_per_user)
+        {
+            return Err(format!(
+                "At most {} WebSocket connections per user",
+                self.limits.max_per_user
+            ));
+        }
+        open.total += 1;
+        if let Some(ip) = ip {
+            *open.per_ip.entry(ip).or_default() += 1;
+        }
+        if let Some(user_id) = user_id {
+            *open.per_user.entry(user_id).or_default() += 1;
+        }
+        Ok(WsSlot {
+            open: self.open.clone(),
+            user_id,
+            ip,
+        })
+    }
+
+    /// Connections open across every endpoint
+    pub fn open_count(&self) -> usize {
+        self.open.lock().unwrap().total
+    }
+}
+
+/// A claimed connection slot, given back when dropped
+#[derive(Debug)]
+pub struct WsSlot {
+    open: Arc<Mutex<OpenSockets>>,
+    user_id: Option<Uuid>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for WsSlot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+        open.total -= 1;
+        if let Some(user_id) = self.user_id {
+            release(&mut open.per_user, user_id);
+        }
+        if let Some(ip) = self.ip {
+            release(&mut open.per_ip, ip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_limited_in_total_and_per_user_and_given_back() {
+        let registry = WsRegistry::new(WsLimits {
+            max_connections: 3,
+            max_per_user: 2,
+            ..WsLimits::default()
+        });
+        let user = Uuid::new_v4();
+
+        let first = registry.open(Some(user), None).unwrap();
+        let _second = registry.open(Some(user), None).unwrap();
+        assert!(registry.open(Some(user), None).is_err());
+        let _public = registry.open(None, None).unwrap();
+        assert!(registry.open(Some(Uuid::new_v4()), None).is_err());
+        assert_eq!(registry.open_count(), 3);
+
+        drop(first);
+        assert_eq!(registry.open_count(), 2);
+        assert!(registry.open(Some(user), None).is_ok());
+    }
+
+    #[test]
+    fn test_slots_are_limited_per_address_signed_in_or_not() {
+        let registry = WsRegistry::new(WsLimits {
+            max_per_ip: 2,
+            ..WsLimits::default()
+        });
+        let (ip, other): (IpAddr, IpAddr) =
+            ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let public = registry.open(None, Some(ip)).unwrap();
+        let _signed_in = registry.open(Some(Uuid::new_v4()), Some(ip)).unwrap();
+        let refused = registry.open(None, Some(ip)).unwrap_err();
+        assert_eq!(refused, "At most 2 WebSocket connections per address");
+        assert!(registry.open(None, Some(other)).is_ok());
+
+        drop(public);
+        assert!(registry.open(None, Some(ip)).is_ok());
+    }
+}
@@ -0,0 +1,292 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::OsRng;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+use crate::storage::{MemoryUserRepository, UserRepository};
+use crate::types::{ApiKey, ApiKeyScope, API_KEY_PREFIX};
+use crate::utils::lru::LruCache;
+use crate::utils::{PageSigner, SecretBox};
+
+/// How far a signed request's timestamp may be from the server clock
+const API_KEY_MAX_SKEW_MS: i64 = 30_000;
+
+/// How many keys `authenticate` keeps at hand
+const API_KEY_CACHE_CAPACITY: usize = 10_000;
+
+/// The last request a key authenticated
+#[derive(Debug, Clone, Copy)]
+struct KeyUse {
+    at: DateTime<Utc>,
+    timestamp_ms: i64, // As signed; the next request must sign a later one
+}
+
+/// API keys, kept in a `UserRepository`
+pub struct ApiKeyStore {
+    repository: Arc<dyn UserRepository>,
+    /// Keys that signed recent requests, so authenticating skips the backing
+    /// store. Revoking a key invalidates it. Nothing is cached when other
+    /// instances share the repository, since a key they revoke must stop
+    /// working here too.
+    cache: Mutex<LruCache<String, ApiKey>>,
+    /// When each key last authenticated a request, and the timestamp it
+    /// signed. Kept beside the keys rather than in them, so recording a use
+    /// never touches the backing store.
+    last_used: Mutex<HashMap<String, KeyUse>>,
+}
+
+impl ApiKeyStore {
+    /// Keys kept in process memory, lost on restart
+    pub fn new() -> Self {
+        Self::with_repository(Arc::new(MemoryUserRepository::new()))
+    }
+
+    pub fn with_repository(repository: Arc<dyn UserRepository>) -> Self {
+        let capacity = if repository.is_shared() {
+            0
+        } else {
+            API_KEY_CACHE_CAPACITY
+        };
+        ApiKeyStore {
+            repository,
+            cache: Mutex::new(LruCache::new(capacity)),
+            last_used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Issue a key for `user_id`. The plaintext secret is returned once and
+    /// only its sealed form is stored.
+    pub async fn create(
+        &self,
+        sealer: &SecretBox,
+        user_id: Uuid,
+        label: Option<String>,
+        mut scopes: Vec<ApiKeyScope>,
+    ) -> Result<(ApiKey, String), String> {
+        scopes.sort();
+        scopes.dedup();
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let secret: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let key = ApiKey {
+            key_id: format!("{}{}", API_KEY_PREFIX, Uuid::new_v4().simple()),
+            user_id,
+            label,
+            secret: sealer.seal(secret.as_bytes())?,
+            created_at: Utc::now(),
+            scopes,
+        };
+        self.repository.insert_api_key(&key).await?;
+        Ok((key, secret))
+    }
+
+    /// When the key last authenticated a request, if it ever has
+    pub fn last_used(&self, key_id: &str) -> Option<DateTime<Utc>> {
+        self.last_used
+            .lock()
+            .unwrap()
+            .get(key_id)
+            .map(|used| used.at)
+    }
+
+    /// The user's keys, oldest first
+    pub async fn list(&self, user_id: Uuid) -> Result<Vec<ApiKey>, String> {
+        self.repository.api_keys_for_user(user_id).await
+    }
+
+    /// Remove one of the user's keys; false if they have no such key
+    pub async fn revoke(&self, user_id: Uuid, key_id: &str) -> Result<bool, String> {
+        if !self.repository.delete_api_key(user_id, key_id).await? {
+            return Ok(false);
+        }
+        self.cache.lock().unwrap().invalidate(&key_id.to_string());
+        self.last_used.lock().unwrap().remove(key_id);
+        Ok(true)
+    }
+
+    /// Whether the key still exists. A revoked key is gone from the backing
+    /// store, including when another instance revoked it.
+    pub async fn is_active(&self, key_id: &str) -> Result<bool, String> {
+        let cached = self.cache.lock().unwrap().get(&key_id.to_string());
+        if cached.is_some() {
+            return Ok(true);
+        }
+        Ok(self.repository.api_key(key_id).await?.is_some())
+    }
+
+    /// Check a `key_id.timestamp.signature` credential, where the timestamp is
+    /// in milliseconds and the signature is the hex HMAC-SHA256 of
+    /// `timestamp + METHOD + path_and_query + hex(SHA-256(body))` under the
+    /// key's secret. Each request must sign a later timestamp than the last
+    /// one the key authenticated here, so a captured request cannot be sent
+    /// again. Returns the key, whose owner the request acts as.
+    pub async fn authenticate(
+        &self,
+        sealer: &SecretBox,
+        credential: &str,
+        method: &str,
+        path_and_query: &str,
+        body: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<ApiKey, String> {
+        let mut parts = credential.splitn(3, '.');
+        let (key_id, timestamp, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(key_id), Some(timestamp), Some(signature)) => (key_id, timestamp, signature),
+            _ => return Err("Malformed API key credential".to_string()),
+        };
+
+        let timestamp_ms: i64 = timestamp
+            .parse()
+            .map_err(|_| "Invalid API key timestamp".to_string())?;
+        if (now.timestamp_millis() - timestamp_ms).abs() > API_KEY_MAX_SKEW_MS {
+            return Err("API key signature expired".to_string());
+        }
+
+        let cached = self.cache.lock().unwrap().get(&key_id.to_string());
+        let key = match cached {
+            Some(key) => key,
+            None => {
+                let key = self
+                    .repository
+                    .api_key(key_id)
+                    .await?
+                    .ok_or_else(|| "Unknown API key".to_string())?;
+                let mut cache = self.cache.lock().unwrap();
+                cache.insert(key.key_id.clone(), key.clone());
+                key
+            }
+        };
+        let secret = sealer.open(&key.secret)?;
+
+        let payload = signed_payload(timestamp, method, path_and_query, body);
+        if !PageSigner::new(secret, key.key_id.clone()).verify(payload.as_bytes(), signature) {
+            return Err("Invalid API key signature".to_string());
+        }
+
+        let mut last_used = self.last_used.lock().unwrap();
+        if last_used
+            .get(&key.key_id)
+            .is_some_and(|used| used.timestamp_ms >= timestamp_ms)
+        {
+            return Err("API key timestamp already used".to_string());
+        }
+        last_used.insert(
+            key.key_id.clone(),
+            KeyUse {
+                at: now,
+                timestamp_ms,
+            },
+        );
+        Ok(key)
+    }
+}
+
+/// What a request signs: the timestamp, method, path and query, then the hex
+/// SHA-256 of its body
+pub fn signed_payload(timestamp: &str, method: &str, path_and_query: &str, body: &[u8]) -> String {
+    let body_hash: String = Sha256::digest(body)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}{}{}{}", timestamp, method, path_and_query, body_hash)
+}
+
+impl Default for ApiKeyStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_signed_requests_authenticate_with_sealed_secret() {
+        let sealer = SecretBox::new("k1", b"test passphrase");
+        let store = ApiKeyStore::new();
+        let user_id = Uuid::new_v4();
+        let (key, secret) = store
+            .create(
+                &sealer,
+                user_id,
+                Some("bot".to_string()),
+                crate::types::default_scopes(),
+            )
+            .await
+            .unwrap();
+        assert!(!String::from_utf8_lossy(&key.secret.ciphertext).contains(&secret));
+
+        let now = Utc::now();
+        let sign = |timestamp: i64, path: &str, body: &[u8]| {
+            let payload = signed_payload(&timestamp.to_string(), "POST", path, body);
+            let signature = PageSigner::new(secret.clone(), "").sign(payload.as_bytes());
+            format!("{}.{}.{}", key.key_id, timestamp, signature)
+        };
+        let authenticate = async |credential: &str, path: &str, body: &[u8]| {
+            store
+                .authenticate(&sealer, credential, "POST", path, body, now)
+                .await
+        };
+
+        let body = br#"{"price":"100"}"#;
+        let credential = sign(now.timestamp_millis() - 1, "/api/orders/limit", body);
+        assert_eq!(store.last_used(&key.key_id), None);
+        // Signed for another path or body, or too long ago
+        assert!(authenticate(&credential, "/api/orders/market", body)
+            .await
+            .is_err());
+        assert!(
+            authenticate(&credential, "/api/orders/limit", br#"{"price":"1"}"#)
+                .await
+                .is_err()
+        );
+        let stale = sign(now.timestamp_millis() - 120_000, "/api/orders/limit", body);
+        assert!(authenticate(&stale, "/api/orders/limit", body)
+            .await
+            .is_err());
+        assert_eq!(store.last_used(&key.key_id), None);
+
+        assert_eq!(
+            authenticate(&credential, "/api/orders/limit", body)
+                .await
+                .map(|key| key.user_id),
+            Ok(user_id)
+        );
+        assert_eq!(store.last_used(&key.key_id), Some(now));
+        // The same request again, or one signed no later, is a replay
+        assert!(authenticate(&credential, "/api/orders/limit", body)
+            .await
+            .is_err());
+        let earlier = sign(now.timestamp_millis() - 2, "/api/orders/limit", body);
+        assert!(authenticate(&earlier, "/api/orders/limit", body)
+            .await
+            .is_err());
+        let next = sign(now.timestamp_millis(), "/api/orders/limit", body);
+        assert!(authenticate(&next, "/api/orders/limit", body).await.is_ok());
+
+        assert_eq!(store.revoke(Uuid::new_v4(), &key.key_id).await, Ok(false));
+        assert_eq!(store.revoke(user_id, &key.key_id).await, Ok(true));
+        let after = sign(now.timestamp_millis() + 1, "/api/orders/limit", body);
+        assert!(authenticate(&after, "/api/orders/limit", body)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_created_scopes_are_sorted_and_unique() {
+        use ApiKeyScope::{Read, Trade};
+        let sealer = SecretBox::new("k1", b"test passphrase");
+        let store = ApiKeyStore::new();
+        let (key, _) = store
+            .create(&sealer, Uuid::new_v4(), None, vec![Trade, Read, Trade])
+            .await
+            .unwrap();
+        assert_eq!(key.scopes, vec![Read, Trade]);
+    }
+}
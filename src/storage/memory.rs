@@ -0,0 +1,273 @@
+use crate::ledger::Journal;
+use crate::storage::{
+    HistoryQuery, LedgerStore, OrderStore, OutboxEvent, OutboxStore, TradeStore, UserRepository,
+};
+use crate::types::{newest_first, ActivityEntry, ApiKey, Order, Trade, User};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Audit entries the memory repository keeps per user; the oldest go first
+pub const MEMORY_ACTIVITY_RETENTION: usize = 1_000;
+
+/// Keeps everything in process memory; what tests and development run on
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    orders: HashMap<Uuid, Order>,
+    orders_by_user: HashMap<Uuid, Vec<Uuid>>, // Acceptance order
+    trades: Vec<Trade>,                       // Save order
+    journals: Vec<Journal>,                   // Posting order
+    outbox: Vec<(OutboxEvent, Option<DateTime<Utc>>)>, // Save order, with delivery time
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl OrderStore for MemoryStore {
+    fn save_orders(&mut self, orders: &[Order]) -> Result<(), String> {
+        for order in orders {
+            if self.orders.insert(order.id, order.clone()).is_none() {
+                self.orders_by_user
+                    .entry(order.user_id)
+                    .or_default()
+                    .push(order.id);
+            }
+        }
+        Ok(())
+    }
+
+    fn load_order(&self, order_id: Uuid) -> Result<Option<Order>, String> {
+        Ok(self.orders.get(&order_id).cloned())
+    }
+
+    fn orders_for_user(&self, user_id: Uuid, query: &HistoryQuery) -> Result<Vec<Order>, String> {
+        Ok(self
+            .orders_by_user
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .rev()
+            .filter_map(|id| self.orders.get(id))
+            .filter(|order| query.contains(order.timestamp))
+            .skip(query.offset)
+            .take(query.limit)
+            .cloned()
+            .collect())
+    }
+}
+
+impl TradeStore for MemoryStore {
+    fn save_trades(&mut self, trades: &[Trade]) -> Result<(), String> {
+        self.trades.extend_from_slice(trades);
+        let events = trades
+            .iter()
+            .map(|t| (OutboxEvent::trade_executed(t), None));
+        self.outbox.extend(events);
+        Ok(())
+    }
+
+    fn trades_for_user(&self, user_id: Uuid, query: &HistoryQuery) -> Result<Vec<Trade>, String> {
+        let mut trades: Vec<&Trade> = self
+            .trades
+            .iter()
+            .rev()
+            .filter(|t| t.maker_user_id == user_id || t.taker_user_id == user_id)
+            .filter(|t| query.contains(t.timestamp))
+            .collect();
+        // Stable, so trades with the same timestamp stay newest-saved first
+        trades.sort_by_key(|t| std::cmp::Reverse(t.timestamp));
+        Ok(trades
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit)
+            .cloned()
+            .collect())
+    }
+}
+
+impl LedgerStore for MemoryStore {
+    fn save_journals(&mut self, journals: &[Journal]) -> Result<(), String> {
+        self.journals.extend_from_slice(journals);
+        Ok(())
+    }
+
+    fn journals_after(&self, after_id: u64, limit: usize) -> Result<Vec<Journal>, String> {
+        let start = self.journals.partition_point(|j| j.id <= after_id);
+        Ok(self.journals[start..].iter().take(limit).cloned().collect())
+    }
+
+    fn last_journal_id(&self) -> Result<u64, String> {
+        Ok(self.journals.last().map_or(0, |j| j.id))
+    }
+}
+
+impl OutboxStore for MemoryStore {
+    fn queue_events(&mut self, events: &[OutboxEvent]) -> Result<(), String> {
+        for event in events {
+            if !self.outbox.iter().any(|(queued, _)| queued.id == event.id) {
+                self.outbox.push((event.clone(), None));
+            }
+        }
+        Ok(())
+    }
+
+    fn pending_events(&self, limit: usize) -> Result<Vec<OutboxEvent>, String> {
+        Ok(self
+            .outbox
+            .iter()
+            .filter(|(_, delivered_at)| delivered_at.is_none())
+            .take(limit)
+            .map(|(event, _)| event.clone())
+            .collect())
+    }
+
+    fn mark_delivered(&mut self, event_ids: &[Uuid], at: DateTime<Utc>) -> Result<(), String> {
+        for (event, delivered_at) in &mut self.outbox {
+            if delivered_at.is_none() && event_ids.contains(&event.id) {
+                *delivered_at = Some(at);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keeps accounts, API keys and audit trails in process memory, lost on restart
+#[derive(Debug, Default)]
+pub struct MemoryUserRepository {
+    users: Mutex<Vec<User>>,                  // Creation order
+    api_keys: Mutex<HashMap<String, ApiKey>>, // key_id -> ApiKey
+    // Each user's activity entries, oldest first
+    activities: Mutex<HashMap<Uuid, VecDeque<ActivityEntry>>>,
+}
+
+impl MemoryUserRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn find_user(&self, matches: impl Fn(&User) -> bool) -> Result<Option<User>, String> {
+        let users = self.users.lock().unwrap();
+        Ok(users.iter().find(|u| matches(u)).cloned())
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryUserRepository {
+    async fn insert_user(&self, user: &User) -> Result<bool, String> {
+        let mut users = self.users.lock().unwrap();
+        if users.iter().any(|u| u.username == user.username) {
+            return Ok(false);
+        }
+        users.push(user.clone());
+        Ok(true)
+    }
+
+    async fn update_user(&self, user: &User) -> Result<bool, String> {
+        let mut users = self.users.lock().unwrap();
+        let stored = users
+            .iter_mut()
+            .find(|u| u.id == user.id)
+            .ok_or_else(|| format!("Unknown user {}", user.id))?;
+        if stored.revision != user.revision {
+            return Ok(false);
+        }
+        *stored = User {
+            revision: user.revision + 1,
+            ..user.clone()
+        };
+        Ok(true)
+    }
+
+    async fn user_by_id(&self, user_id: Uuid) -> Result<Option<User>, String> {
+        self.find_user(|u| u.id == user_id)
+    }
+
+    async fn user_by_username(&self, username: &str) -> Result<Option<User>, String> {
+        self.find_user(|u| u.username == username)
+    }
+
+    async fn user_by_identity(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<User>, String> {
+        self.find_user(|u| {
+            u.external_identities
+                .iter()
+                .any(|i| i.provider == provider && i.subject == subject)
+        })
+    }
+
+    async fn user_by_email(&self, email: &str) -> Result<Option<User>, String> {
+        self.find_user(|u| u.email.eq_ignore_ascii_case(email))
+    }
+
+    async fn insert_api_key(&self, key: &ApiKey) -> Result<(), String> {
+        self.api_keys
+            .lock()
+            .unwrap()
+            .insert(key.key_id.clone(), key.clone());
+        Ok(())
+    }
+
+    async fn api_key(&self, key_id: &str) -> Result<Option<ApiKey>, String> {
+        Ok(self.api_keys.lock().unwrap().get(key_id).cloned())
+    }
+
+    async fn api_keys_for_user(&self, user_id: Uuid) -> Result<Vec<ApiKey>, String> {
+        let keys = self.api_keys.lock().unwrap();
+        let mut owned: Vec<ApiKey> = keys
+            .values()
+            .filter(|k| k.user_id == user_id)
+            .cloned()
+            .collect();
+        owned.sort_by_key(|k| k.created_at);
+        Ok(owned)
+    }
+
+    async fn delete_api_key(&self, user_id: Uuid, key_id: &str) -> Result<bool, String> {
+        let mut keys = self.api_keys.lock().unwrap();
+        match keys.get(key_id) {
+            Some(key) if key.user_id == user_id => Ok(keys.remove(key_id).is_some()),
+            _ => Ok(false),
+        }
+    }
+
+    async fn insert_activity(&self, user_id: Uuid, entry: &ActivityEntry) -> Result<(), String> {
+        let mut activities = self.activities.lock().unwrap();
+        let entries = activities.entry(user_id).or_default();
+        if entries.len() == MEMORY_ACTIVITY_RETENTION {
+            entries.pop_front();
+        }
+        entries.push_back(entry.clone());
+        Ok(())
+    }
+
+    async fn activities(
+        &self,
+        user_id: Uuid,
+        query: &HistoryQuery,
+    ) -> Result<Vec<ActivityEntry>, String> {
+        let activities = self.activities.lock().unwrap();
+        let mut selected: Vec<ActivityEntry> = activities
+            .get(&user_id)
+            .into_iter()
+            .flatten()
+            .rev()
+            .filter(|entry| query.contains(entry.at))
+            .cloned()
+            .collect();
+        // Recorded as they happen, but clocks can step back
+        newest_first(&mut selected);
+        Ok(selected
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit)
+            .collect())
+    }
+}
@@ -0,0 +1,107 @@
+/// One step of a database's schema, applied once and recorded in
+/// `schema_migrations`. The scripts live in `migrations/` at the crate root.
+/// The first one uses `IF NOT EXISTS` and creates the tables as they were
+/// before versioned migrations, so older databases are adopted as they are;
+/// every later change, such as the order timestamps, is its own migration
+/// that alters the tables and fills in what it adds before indexing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Created before anything else, to record which migrations have run
+pub const MIGRATIONS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS schema_migrations (
+        version BIGINT PRIMARY KEY,
+        name TEXT NOT NULL,
+        applied_us BIGINT NOT NULL
+    );
+";
+
+pub const POSTGRES_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "history",
+        sql: include_str!("../../migrations/postgres/0001_history.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "users",
+        sql: include_str!("../../migrations/postgres/0002_users.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "order_timestamps",
+        sql: include_str!("../../migrations/postgres/0003_order_timestamps.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "user_revisions",
+        sql: include_str!("../../migrations/postgres/0004_user_revisions.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "user_activity",
+        sql: include_str!("../../migrations/postgres/0005_user_activity.sql"),
+    },
+];
+
+pub const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "history",
+        sql: include_str!("../../migrations/sqlite/0001_history.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "order_timestamps",
+        sql: include_str!("../../migrations/sqlite/0002_order_timestamps.sql"),
+    },
+];
+
+/// The migrations a database at version `applied` still needs, in order.
+/// A database newer than this build is refused rather than written to.
+pub fn pending(
+    migrations: &'static [Migration],
+    applied: u32,
+) -> Result<&'static [Migration], String> {
+    let latest = migrations.last().map_or(0, |m| m.version);
+    if applied > latest {
+        return Err(format!(
+            "Database schema is at version {}, newer than the {} this build knows",
+            applied, latest
+        ));
+    }
+    let first = migrations.partition_point(|m| m.version <= applied);
+    Ok(&migrations[first..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_numbered_in_order_and_run_once() {
+        for migrations in [POSTGRES_MIGRATIONS, SQLITE_MIGRATIONS] {
+            let versions: Vec<u32> = migrations.iter().map(|m| m.version).collect();
+            let expected: Vec<u32> = (1..=migrations.len() as u32).collect();
+            assert_eq!(versions, expected);
+            assert!(migrations.iter().all(|m| !m.sql.trim().is_empty()));
+        }
+
+        assert_eq!(pending(POSTGRES_MIGRATIONS, 0).unwrap().len(), 5);
+        assert_eq!(pending(POSTGRES_MIGRATIONS, 1).unwrap()[0].name, "users");
+        assert_eq!(
+            pending(POSTGRES_MIGRATIONS, 4).unwrap()[0].name,
+            "user_activity"
+        );
+        assert!(pending(POSTGRES_MIGRATIONS, 5).unwrap().is_empty());
+        assert_eq!(
+            pending(SQLITE_MIGRATIONS, 1).unwrap()[0].name,
+            "order_timestamps"
+        );
+        assert!(pending(SQLITE_MIGRATIONS, 3).is_err());
+    }
+}
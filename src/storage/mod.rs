@@ -0,0 +1,689 @@
+pub mod api_keys;
+pub mod memory;
+pub mod migrations;
+pub mod postgres;
+pub mod sqlite;
+
+pub use api_keys::*;
+pub use memory::*;
+pub use postgres::*;
+pub use sqlite::*;
+
+use crate::ledger::Journal;
+use crate::types::{
+    ActivityEntry, ApiKey, BalanceEventV1, Order, OrderEventV1, Trade, TradeEventV1, User,
+    BALANCE_EVENT_VERSION, ORDER_EVENT_VERSION, TRADE_EVENT_VERSION,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Durable copy of every order the engine accepted, in its latest state
+pub trait OrderStore {
+    /// Insert or replace each order by id
+    fn save_orders(&mut self, orders: &[Order]) -> Result<(), String>;
+    fn load_order(&self, order_id: Uuid) -> Result<Option<Order>, String>;
+    /// The user's orders, most recently accepted first
+    fn orders_for_user(&self, user_id: Uuid, query: &HistoryQuery) -> Result<Vec<Order>, String>;
+}
+
+/// Which page of a user's orders or trades to return, counted newest first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryQuery {
+    pub from: Option<DateTime<Utc>>, // Inclusive
+    pub to: Option<DateTime<Utc>>,   // Exclusive
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+    /// The user's `limit` most recent records
+    pub fn latest(limit: usize) -> Self {
+        HistoryQuery {
+            from: None,
+            to: None,
+            offset: 0,
+            limit,
+        }
+    }
+
+    pub fn contains(&self, timestamp: DateTime<Utc>) -> bool {
+        self.from.is_none_or(|from| timestamp >= from) && self.to.is_none_or(|to| timestamp < to)
+    }
+
+    /// Bounds of the time range in microseconds, open ends widened to the extremes
+    pub(crate) fn range_micros(&self) -> (i64, i64) {
+        (
+            self.from.map_or(i64::MIN, |from| from.timestamp_micros()),
+            self.to.map_or(i64::MAX, |to| to.timestamp_micros()),
+        )
+    }
+}
+
+/// The name `HistoryQuery` had when it paged only trades
+#[deprecated(note = "renamed to HistoryQuery")]
+pub type TradeQuery = HistoryQuery;
+
+/// Durable copy of every trade, findable by either participant
+pub trait TradeStore {
+    /// Save the trades and queue one outbox event for each, all or nothing
+    fn save_trades(&mut self, trades: &[Trade]) -> Result<(), String>;
+    /// Trades the user was maker or taker of, newest first
+    fn trades_for_user(&self, user_id: Uuid, query: &HistoryQuery) -> Result<Vec<Trade>, String>;
+}
+
+/// Durable copy of every ledger journal, in posting order
+pub trait LedgerStore {
+    fn save_journals(&mut self, journals: &[Journal]) -> Result<(), String>;
+    /// Up to `limit` journals with an id above `after_id`, oldest first
+    fn journals_after(&self, after_id: u64, limit: usize) -> Result<Vec<Journal>, String>;
+    /// Highest journal id saved so far, 0 if none; the ledger numbers on from it
+    fn last_journal_id(&self) -> Result<u64, String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutboxEventKind {
+    #[serde(rename = "trade.executed")]
+    TradeExecuted,
+    #[serde(rename = "order.updated")]
+    OrderUpdated,
+    #[serde(rename = "balance.changed")]
+    BalanceChanged,
+}
+
+impl OutboxEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutboxEventKind::TradeExecuted => "trade.executed",
+            OutboxEventKind::OrderUpdated => "order.updated",
+            OutboxEventKind::BalanceChanged => "balance.changed",
+        }
+    }
+}
+
+/// An effect visible outside the exchange, waiting to be delivered.
+///
+/// Exactly-once works like this: a trade's event is saved in the same
+/// transaction as the trade, so no trade is stored without its event and none
+/// gets two. Order updates and balance changes are queued by the engine after
+/// the command's records, under an ID fixed before the first try. Delivery is
+/// at least once, since a crash between sending and recording the
+/// acknowledgement sends the event again. Every attempt carries the same `id`,
+/// which receivers use to drop the repeats.
+///
+/// The payload is a versioned schema rather than the engine's own `Trade` or
+/// `Order`, so what receivers get only changes when `version` does. Exactly
+/// one of `trade`, `order` and `balance` is set, as `kind` says.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEvent {
+    pub id: Uuid, // For a trade, the trade's ID, so it cannot produce a second event
+    pub kind: OutboxEventKind,
+    #[serde(default = "first_event_version")]
+    pub version: u32, // Of the payload; events queued before versioning are 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trade: Option<TradeEventV1>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<OrderEventV1>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<BalanceEventV1>,
+}
+
+fn first_event_version() -> u32 {
+    1
+}
+
+impl OutboxEvent {
+    pub fn trade_executed(trade: &Trade) -> Self {
+        OutboxEvent {
+            id: trade.id,
+            kind: OutboxEventKind::TradeExecuted,
+            version: TRADE_EVENT_VERSION,
+            trade: Some(TradeEventV1::from(trade)),
+            order: None,
+            balance: None,
+        }
+    }
+
+    /// An order in its state after a change
+    pub fn order_updated(order: &Order) -> Self {
+        OutboxEvent {
+            id: Uuid::new_v4(),
+            kind: OutboxEventKind::OrderUpdated,
+            version: ORDER_EVENT_VERSION,
+            trade: None,
+            order: Some(OrderEventV1::from(order)),
+            balance: None,
+        }
+    }
+
+    /// A user's balances after a command changed them
+    pub fn balance_changed(user_id: Uuid, balances: &HashMap<String, f64>) -> Self {
+        OutboxEvent {
+            id: Uuid::new_v4(),
+            kind: OutboxEventKind::BalanceChanged,
+            version: BALANCE_EVENT_VERSION,
+            trade: None,
+            order: None,
+            balance: Some(BalanceEventV1::new(user_id, balances)),
+        }
+    }
+
+    /// What the event is about: the trade, order or user it reports on
+    pub fn subject_id(&self) -> Uuid {
+        let trade = self.trade.as_ref().map(|trade| trade.id);
+        let order = self.order.as_ref().map(|order| order.id);
+        let balance = self.balance.as_ref().map(|balance| balance.user_id);
+        trade.or(order).or(balance).unwrap_or(self.id)
+    }
+}
+
+/// Events queued by the other stores until the receiver acknowledges them
+pub trait OutboxStore {
+    /// Queue events that go with no stored record; an ID already queued is skipped
+    fn queue_events(&mut self, events: &[OutboxEvent]) -> Result<(), String>;
+    /// Up to `limit` undelivered events, oldest first
+    fn pending_events(&self, limit: usize) -> Result<Vec<OutboxEvent>, String>;
+    /// Take the events off the pending list; unknown or delivered IDs are ignored
+    fn mark_delivered(&mut self, event_ids: &[Uuid], at: DateTime<Utc>) -> Result<(), String>;
+}
+
+/// Everything the engine persists, behind one backend
+pub trait Store: OrderStore + TradeStore + LedgerStore + OutboxStore + Send {}
+
+impl<T: OrderStore + TradeStore + LedgerStore + OutboxStore + Send> Store for T {}
+
+/// User accounts with their credentials and linked logins, and their API
+/// keys. Shared by every request handler rather than owned by the engine, so
+/// it takes `&self` throughout, and asynchronous so a lookup never holds up
+/// the handler's thread.
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Add a new account; false, with nothing saved, if the username is taken
+    async fn insert_user(&self, user: &User) -> Result<bool, String>;
+    /// Replace an existing account by id if it is still at `user.revision`,
+    /// moving it to the next one; false, with nothing saved, if it was saved
+    /// by someone else since it was read
+    async fn update_user(&self, user: &User) -> Result<bool, String>;
+    async fn user_by_id(&self, user_id: Uuid) -> Result<Option<User>, String>;
+    async fn user_by_username(&self, username: &str) -> Result<Option<User>, String>;
+    /// The account an outside identity is linked to
+    async fn user_by_identity(&self, provider: &str, subject: &str) -> Result<Option<User>, String>;
+    /// The oldest account with this email, ignoring case
+    async fn user_by_email(&self, email: &str) -> Result<Option<User>, String>;
+
+    async fn insert_api_key(&self, key: &ApiKey) -> Result<(), String>;
+    async fn api_key(&self, key_id: &str) -> Result<Option<ApiKey>, String>;
+    /// The user's keys, oldest first
+    async fn api_keys_for_user(&self, user_id: Uuid) -> Result<Vec<ApiKey>, String>;
+    /// Delete one of the user's keys; false if they have no such key
+    async fn delete_api_key(&self, user_id: Uuid, key_id: &str) -> Result<bool, String>;
+
+    /// Add an entry to the user's audit trail: sign-ins, API key changes and
+    /// withdrawal address notices
+    async fn insert_activity(&self, user_id: Uuid, entry: &ActivityEntry) -> Result<(), String>;
+    /// The page of the user's audit trail that `query` selects, newest first
+    async fn activities(
+        &self,
+        user_id: Uuid,
+        query: &HistoryQuery,
+    ) -> Result<Vec<ActivityEntry>, String>;
+
+    /// Whether other processes change the same records, so a copy cached in
+    /// this one can go stale
+    fn is_shared(&self) -> bool {
+        false
+    }
+}
+
+/// Where the engine persists orders, trades and journals
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum StorageBackend {
+    /// Kept for the life of the process only
+    #[default]
+    Memory,
+    /// A SQLite database file, created if missing; `:memory:` for a private in-memory one
+    Sqlite(PathBuf),
+    /// A PostgreSQL connection string
+    Postgres(String),
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    /// `memory`, `sqlite:<path>` or a `postgres://` / `postgresql://` URL
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "memory" {
+            Ok(StorageBackend::Memory)
+        } else if let Some(path) = s.strip_prefix("sqlite:").filter(|p| !p.is_empty()) {
+            Ok(StorageBackend::Sqlite(PathBuf::from(path)))
+        } else if s.starts_with("postgres://") || s.starts_with("postgresql://") {
+            Ok(StorageBackend::Postgres(s.to_string()))
+        } else {
+            Err(format!(
+                "Unknown storage backend {:?}: expected memory, sqlite:<path> or postgres://...",
+                s
+            ))
+        }
+    }
+}
+
+/// Stored records are kept whole as JSON
+pub(crate) fn encode<T: Serialize>(record: &T) -> String {
+    // Every stored type serializes infallibly: no maps with non-string keys
+    serde_json::to_string(record).expect("record serializes to JSON")
+}
+
+pub(crate) fn decode<T: DeserializeOwned>(body: &str) -> Result<T, String> {
+    serde_json::from_str(body).map_err(|e| format!("Corrupt stored record: {}", e))
+}
+
+/// Connect to `backend`, creating its tables if they do not exist yet
+pub fn open(backend: &StorageBackend) -> Result<Box<dyn Store>, String> {
+    Ok(match backend {
+        StorageBackend::Memory => Box::new(MemoryStore::new()),
+        StorageBackend::Sqlite(path) => Box::new(SqliteStore::open(path)?),
+        StorageBackend::Postgres(url) => Box::new(PostgresStore::connect(url)?),
+    })
+}
+
+/// Open the user repository for `backend`. Accounts live in memory or in
+/// PostgreSQL, where every instance pointed at the same database shares them.
+pub async fn open_users(backend: &StorageBackend) -> Result<Arc<dyn UserRepository>, String> {
+    Ok(match backend {
+        StorageBackend::Memory => Arc::new(MemoryUserRepository::new()),
+        StorageBackend::Sqlite(_) => {
+            return Err("User accounts are kept in memory or PostgreSQL, not SQLite".to_string())
+        }
+        StorageBackend::Postgres(url) => Arc::new(PostgresUserRepository::connect(url).await?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ApiKeyScope;
+    use crate::ledger::{Account, JournalKind, Posting};
+    use crate::types::{Activity, ExternalIdentity, OrderSide, Price, Quantity};
+    use crate::utils::SecretBox;
+    use chrono::Duration;
+
+    fn trade(maker_user_id: Uuid, taker_user_id: Uuid, seconds_ago: i64) -> Trade {
+        let mut trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            maker_user_id,
+            taker_user_id,
+            Price::from_f64(100.0),
+            Quantity::from_f64(1.0),
+        );
+        trade.timestamp = Utc::now() - Duration::seconds(seconds_ago);
+        trade
+    }
+
+    fn journal(id: u64) -> Journal {
+        let amount = 100 * id as i128;
+        let posted_at = "2024-03-01T12:00:00Z".parse().unwrap();
+        Journal {
+            id,
+            kind: JournalKind::Deposit,
+            postings: vec![
+                Posting::new(Account::user(Uuid::nil(), "USD"), amount),
+                Posting::new(Account::external("USD"), -amount),
+            ],
+            timestamp: posted_at,
+            fx_rate: None,
+            reverses: None,
+        }
+    }
+
+    /// The behaviour every backend must share
+    fn exercise(store: &mut dyn Store) {
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        assert_eq!(store.last_journal_id().unwrap(), 0);
+
+        let mut first = Order::new_limit(
+            alice,
+            OrderSide::Buy,
+            Price::from_f64(100.0),
+            Quantity::from_f64(2.0),
+        );
+        first.timestamp -= Duration::seconds(1);
+        let second = Order::new_limit(
+            alice,
+            OrderSide::Sell,
+            Price::from_f64(110.0),
+            Quantity::from_f64(1.0),
+        );
+        store.save_orders(&[first.clone(), second.clone()]).unwrap();
+        first.cancel();
+        store.save_orders(&[first.clone()]).unwrap();
+
+        let loaded = store.load_order(first.id).unwrap().unwrap();
+        assert_eq!(loaded.status, first.status);
+        assert_eq!(
+            store.load_order(Uuid::new_v4()).unwrap().map(|o| o.id),
+            None
+        );
+        let order_ids = |user_id, query| {
+            let orders = store.orders_for_user(user_id, &query).unwrap();
+            orders.iter().map(|o| o.id).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            order_ids(alice, HistoryQuery::latest(10)),
+            vec![second.id, first.id]
+        );
+        let second_page = HistoryQuery {
+            offset: 1,
+            ..HistoryQuery::latest(10)
+        };
+        assert_eq!(order_ids(alice, second_page), vec![first.id]);
+        let before_second = HistoryQuery {
+            to: Some(second.timestamp),
+            ..HistoryQuery::latest(10)
+        };
+        assert_eq!(order_ids(alice, before_second), vec![first.id]);
+        assert!(order_ids(bob, HistoryQuery::latest(10)).is_empty());
+
+        let older = trade(alice, bob, 60);
+        let newer = trade(bob, Uuid::new_v4(), 0);
+        store.save_trades(&[older.clone(), newer.clone()]).unwrap();
+        let ids = |user_id, query| {
+            let trades = store.trades_for_user(user_id, &query).unwrap();
+            trades.iter().map(|t| t.id).collect::<Vec<_>>()
+        };
+        assert_eq!(ids(bob, HistoryQuery::latest(10)), vec![newer.id, older.id]);
+        assert_eq!(ids(bob, HistoryQuery::latest(1)), vec![newer.id]);
+        assert_eq!(ids(alice, HistoryQuery::latest(10)), vec![older.id]);
+        let second_page = HistoryQuery {
+            offset: 1,
+            ..HistoryQuery::latest(1)
+        };
+        assert_eq!(ids(bob, second_page), vec![older.id]);
+        let before_newer = HistoryQuery {
+            to: Some(newer.timestamp),
+            ..HistoryQuery::latest(10)
+        };
+        assert_eq!(ids(bob, before_newer), vec![older.id]);
+        let from_newer = HistoryQuery {
+            from: Some(newer.timestamp),
+            ..HistoryQuery::latest(10)
+        };
+        assert_eq!(ids(bob, from_newer), vec![newer.id]);
+
+        let pending = |store: &dyn Store| {
+            let events = store.pending_events(10).unwrap();
+            events.iter().map(|e| e.id).collect::<Vec<_>>()
+        };
+        assert_eq!(pending(store), vec![older.id, newer.id]);
+        store.mark_delivered(&[older.id], Utc::now()).unwrap();
+        store.mark_delivered(&[older.id], Utc::now()).unwrap();
+        assert_eq!(pending(store), vec![newer.id]);
+        let first_pending = store.pending_events(1).unwrap().remove(0);
+        assert_eq!(first_pending.subject_id(), newer.id);
+
+        // Queueing again after a failed try adds nothing
+        let update = [OutboxEvent::order_updated(&first)];
+        store.queue_events(&update).unwrap();
+        store.queue_events(&update).unwrap();
+        assert_eq!(pending(store), vec![newer.id, update[0].id]);
+        store.mark_delivered(&[update[0].id], Utc::now()).unwrap();
+
+        store
+            .save_journals(&[journal(1), journal(2), journal(3)])
+            .unwrap();
+        let after_one = store.journals_after(1, 10).unwrap();
+        assert_eq!(after_one, vec![journal(2), journal(3)]);
+        assert_eq!(store.journals_after(0, 1).unwrap(), vec![journal(1)]);
+        assert_eq!(store.last_journal_id().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_memory_store() {
+        exercise(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        exercise(&mut SqliteStore::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_history_survives_reopening() {
+        let path = std::env::temp_dir().join(format!("orderbook-{}.db", Uuid::new_v4()));
+        exercise(&mut SqliteStore::open(&path).unwrap());
+        // The schema is already current, so nothing is migrated twice
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.last_journal_id().unwrap(), 3);
+        assert_eq!(store.pending_events(10).unwrap().len(), 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_sqlite_store_migrates_a_database_from_before_order_timestamps() {
+        let path = std::env::temp_dir().join(format!("orderbook-{}.db", Uuid::new_v4()));
+        let alice = Uuid::new_v4();
+        let mut older = Order::new_limit(
+            alice,
+            OrderSide::Buy,
+            Price::from_f64(100.0),
+            Quantity::from_f64(1.0),
+        );
+        older.timestamp -= Duration::seconds(1);
+        let newer = Order::new_limit(
+            alice,
+            OrderSide::Sell,
+            Price::from_f64(110.0),
+            Quantity::from_f64(1.0),
+        );
+        {
+            // The schema as it was before versioned migrations, when orders
+            // kept their time only in the body
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE orders (id TEXT PRIMARY KEY, user_id TEXT NOT NULL, body TEXT NOT NULL);
+                 CREATE INDEX orders_by_user ON orders (user_id);
+                 CREATE TABLE trades (
+                     id TEXT PRIMARY KEY,
+                     maker_user_id TEXT NOT NULL,
+                     taker_user_id TEXT NOT NULL,
+                     timestamp_us INTEGER NOT NULL,
+                     body TEXT NOT NULL
+                 );
+                 CREATE TABLE journals (id INTEGER PRIMARY KEY, body TEXT NOT NULL);",
+            )
+            .unwrap();
+            // Saved newest first, so the rowid alone would put them in the wrong order
+            for order in [&newer, &older] {
+                conn.execute(
+                    "INSERT INTO orders (id, user_id, body) VALUES (?1, ?2, ?3)",
+                    rusqlite::params![order.id.to_string(), alice.to_string(), encode(order)],
+                )
+                .unwrap();
+            }
+        }
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        let order_ids = |store: &SqliteStore, query| {
+            let orders = store.orders_for_user(alice, &query).unwrap();
+            orders.iter().map(|o| o.id).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            order_ids(&store, HistoryQuery::latest(10)),
+            vec![newer.id, older.id]
+        );
+        let before_newer = HistoryQuery {
+            to: Some(newer.timestamp),
+            ..HistoryQuery::latest(10)
+        };
+        assert_eq!(order_ids(&store, before_newer), vec![older.id]);
+        store.save_trades(&[trade(alice, Uuid::new_v4(), 0)]).unwrap();
+        assert_eq!(store.pending_events(10).unwrap().len(), 1);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    async fn exercise_users(repository: &dyn UserRepository) {
+        let user = |username: &str, email: &str| {
+            User::new(username.to_string(), email.to_string(), "hash".to_string())
+        };
+        let alice = user("alice", "Alice@example.com");
+        let other_alice = user("alice2", "alice@example.com");
+        assert!(repository.insert_user(&alice).await.unwrap());
+        assert!(repository.insert_user(&other_alice).await.unwrap());
+        assert!(!repository.insert_user(&user("alice", "a@b.c")).await.unwrap());
+
+        let found = repository.user_by_username("alice").await.unwrap().unwrap();
+        assert_eq!(found.id, alice.id);
+        assert_eq!(found.email, "Alice@example.com");
+        let oldest = repository.user_by_email("ALICE@example.com").await.unwrap();
+        assert_eq!(oldest.map(|u| u.id), Some(alice.id));
+
+        let mut linked = alice.clone();
+        linked.external_identities.push(ExternalIdentity {
+            provider: "google".to_string(),
+            subject: "g-1".to_string(),
+            email: None,
+            email_verified: false,
+        });
+        assert!(repository.update_user(&linked).await.unwrap());
+        // A save made from a copy read before that one is refused
+        assert!(!repository.update_user(&alice).await.unwrap());
+        let saved = repository.user_by_id(alice.id).await.unwrap().unwrap();
+        assert_eq!(saved.revision, 1);
+        assert_eq!(saved.external_identities.len(), 1);
+        let by_identity = repository.user_by_identity("google", "g-1").await.unwrap();
+        assert_eq!(by_identity.map(|u| u.id), Some(alice.id));
+        assert!(repository.user_by_identity("github", "g-1").await.unwrap().is_none());
+        assert!(repository.user_by_id(Uuid::new_v4()).await.unwrap().is_none());
+
+        let sealer = SecretBox::new("k1", b"test passphrase");
+        let key = ApiKey {
+            key_id: "ak_1".to_string(),
+            user_id: alice.id,
+            label: None,
+            secret: sealer.seal(b"secret").unwrap(),
+            created_at: Utc::now(),
+            scopes: vec![ApiKeyScope::Read],
+        };
+        repository.insert_api_key(&key).await.unwrap();
+        assert_eq!(repository.api_key("ak_1").await.unwrap().unwrap().user_id, alice.id);
+        assert_eq!(repository.api_keys_for_user(alice.id).await.unwrap().len(), 1);
+        assert!(!repository.delete_api_key(other_alice.id, "ak_1").await.unwrap());
+        assert!(repository.delete_api_key(alice.id, "ak_1").await.unwrap());
+        assert!(repository.api_key("ak_1").await.unwrap().is_none());
+
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        for (minutes, method) in [(0, "password"), (1, "google"), (2, "github")] {
+            let login = Activity::Login {
+                method: method.to_string(),
+                ip: None,
+            };
+            let entry = ActivityEntry::new(start + Duration::minutes(minutes), login);
+            repository.insert_activity(alice.id, &entry).await.unwrap();
+        }
+        let methods = |entries: Vec<ActivityEntry>| -> Vec<String> {
+            entries
+                .into_iter()
+                .map(|entry| match entry.activity {
+                    Activity::Login { method, .. } => method,
+                    other => panic!("unexpected {:?}", other),
+                })
+                .collect()
+        };
+        let latest = repository.activities(alice.id, &HistoryQuery::latest(2)).await.unwrap();
+        assert_eq!(methods(latest), ["github", "google"]);
+        let window = HistoryQuery {
+            from: Some(start),
+            to: Some(start + Duration::minutes(2)),
+            offset: 1,
+            limit: 10,
+        };
+        let paged = repository.activities(alice.id, &window).await.unwrap();
+        assert_eq!(methods(paged), ["password"]);
+        let latest = HistoryQuery::latest(10);
+        let others = repository.activities(other_alice.id, &latest).await.unwrap();
+        assert!(others.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_user_repository() {
+        exercise_users(&MemoryUserRepository::new()).await;
+    }
+
+    /// A database of its own on the server at `TEST_DATABASE_URL`, so the
+    /// PostgreSQL tests start empty; None when no server is configured
+    async fn scratch_postgres_database() -> Option<(String, String)> {
+        use sqlx::Connection;
+
+        let server = std::env::var("TEST_DATABASE_URL").ok()?;
+        let name = format!("orderbook_test_{}", Uuid::new_v4().simple());
+        let mut connection = sqlx::PgConnection::connect(&server).await.unwrap();
+        sqlx::raw_sql(&format!("CREATE DATABASE {}", name))
+            .execute(&mut connection)
+            .await
+            .unwrap();
+        let (base, _) = server.rsplit_once('/').unwrap();
+        let url = format!("{}/{}", base, name);
+        Some((server, url))
+    }
+
+    async fn drop_postgres_database(server: &str, url: &str) {
+        use sqlx::Connection;
+
+        let (_, name) = url.rsplit_once('/').unwrap();
+        let mut connection = sqlx::PgConnection::connect(server).await.unwrap();
+        sqlx::raw_sql(&format!("DROP DATABASE {} WITH (FORCE)", name))
+            .execute(&mut connection)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_postgres_store() {
+        let Some((server, url)) = scratch_postgres_database().await else {
+            return;
+        };
+        let mut store = PostgresStore::connect(&url).unwrap();
+        exercise(&mut store);
+        drop(store);
+        // The schema is already current, so nothing is migrated twice
+        let store = PostgresStore::connect(&url).unwrap();
+        assert_eq!(store.last_journal_id().unwrap(), 3);
+        drop(store);
+        drop_postgres_database(&server, &url).await;
+    }
+
+    #[tokio::test]
+    async fn test_postgres_user_repository() {
+        let Some((server, url)) = scratch_postgres_database().await else {
+            return;
+        };
+        let repository = PostgresUserRepository::connect(&url).await.unwrap();
+        exercise_users(&repository).await;
+        drop(repository);
+        drop_postgres_database(&server, &url).await;
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!("memory".parse(), Ok(StorageBackend::Memory));
+        assert_eq!(
+            "sqlite:data/orderbook.db".parse(),
+            Ok(StorageBackend::Sqlite(PathBuf::from("data/orderbook.db")))
+        );
+        assert_eq!(
+            "postgres://ob@localhost/ob".parse(),
+            Ok(StorageBackend::Postgres(
+                "postgres://ob@localhost/ob".to_string()
+            ))
+        );
+        assert!("sqlite:".parse::<StorageBackend>().is_err());
+        assert!("redis://localhost".parse::<StorageBackend>().is_err());
+    }
+}
@@ -0,0 +1,613 @@
+use crate::ledger::Journal;
+use crate::storage::migrations::{pending, MIGRATIONS_TABLE, POSTGRES_MIGRATIONS};
+use crate::storage::{
+    decode, encode, HistoryQuery, LedgerStore, OrderStore, OutboxEvent, OutboxStore, TradeStore,
+    UserRepository,
+};
+use crate::types::{ActivityEntry, ApiKey, Order, Trade, User};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use sqlx::postgres::{PgArguments, PgPool, PgPoolOptions, PgRow};
+use sqlx::query::Query;
+use sqlx::{Postgres, Row, Transaction};
+use std::future::Future;
+use std::sync::mpsc;
+use std::thread;
+use uuid::Uuid;
+
+/// Keeps instances that start together from migrating the same database at
+/// once; any number unlikely to clash with other users of advisory locks
+const MIGRATION_LOCK_ID: i64 = 0x4f52_4445_5242_4f4f;
+
+/// Connections the user repository keeps; requests beyond that wait their turn
+const USER_POOL_SIZE: u32 = 16;
+
+type Job = Box<dyn FnOnce(PgPool) -> BoxFuture<'static, ()> + Send>;
+
+/// Persists the engine's history to a PostgreSQL database. The store traits
+/// are blocking and only ever called from the history worker's thread, so the
+/// pool runs on a runtime of its own, on a dedicated thread, where it cannot
+/// stall the async runtime the engine and handlers share.
+pub struct PostgresStore {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl PostgresStore {
+    /// Connect to `url` and apply any migrations it has not had yet
+    pub fn connect(url: &str) -> Result<Self, String> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let url = url.to_string();
+
+        thread::Builder::new()
+            .name("postgres-store".to_string())
+            .spawn(move || {
+                let runtime = match tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                {
+                    Ok(runtime) => runtime,
+                    Err(e) => {
+                        let _ =
+                            ready_tx.send(Err(format!("Failed to start database runtime: {}", e)));
+                        return;
+                    }
+                };
+                // The worker hands over one job at a time, so one connection does
+                let pool = match runtime.block_on(connect(&url, 1)) {
+                    Ok(pool) => pool,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(()));
+                // Ends once the store, and with it the sender, is dropped
+                for job in queue {
+                    runtime.block_on(job(pool.clone()));
+                }
+            })
+            .map_err(|e| format!("Failed to start database thread: {}", e))?;
+
+        ready_rx
+            .recv()
+            .map_err(|_| "Database thread stopped".to_string())??;
+        Ok(PostgresStore { jobs })
+    }
+
+    /// Run `job` against the pool and wait for its result
+    fn run<R, F, Fut>(&self, job: F) -> Result<R, String>
+    where
+        R: Send + 'static,
+        F: FnOnce(PgPool) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<R, sqlx::Error>> + Send + 'static,
+    {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.jobs
+            .send(Box::new(move |pool| {
+                Box::pin(async move {
+                    let _ = reply_tx.send(job(pool).await);
+                })
+            }))
+            .map_err(|_| "Database thread stopped".to_string())?;
+        reply_rx
+            .recv()
+            .map_err(|_| "Database thread stopped".to_string())?
+            .map_err(db_error)
+    }
+
+    /// Run `query` and decode the JSON body in the first column of each row
+    fn bodies<T: DeserializeOwned>(
+        &self,
+        query: &'static str,
+        params: Vec<Param>,
+    ) -> Result<Vec<T>, String> {
+        let rows =
+            self.run(move |pool| async move { bind(query, params).fetch_all(&pool).await })?;
+        decode_bodies(&rows)
+    }
+
+    /// Run `insert` once per row of parameters, all in one transaction
+    fn save_all(&self, insert: &'static str, rows: Vec<Vec<Param>>) -> Result<(), String> {
+        self.save_batches(vec![(insert, rows)])
+    }
+
+    /// Like `save_all`, for several statements sharing one transaction
+    fn save_batches(&self, batches: Vec<(&'static str, Vec<Vec<Param>>)>) -> Result<(), String> {
+        self.run(move |pool| async move {
+            let mut tx = pool.begin().await?;
+            for (insert, rows) in batches {
+                for row in rows {
+                    bind(insert, row).execute(&mut *tx).await?;
+                }
+            }
+            tx.commit().await
+        })
+    }
+}
+
+/// An owned query parameter, so rows can be sent to the database thread
+enum Param {
+    Text(String),
+    BigInt(i64),
+}
+
+/// `query` with `params` bound to `$1`, `$2`, ... in order
+fn bind(query: &'static str, params: Vec<Param>) -> Query<'static, Postgres, PgArguments> {
+    params
+        .into_iter()
+        .fold(sqlx::query(query), |query, param| match param {
+            Param::Text(text) => query.bind(text),
+            Param::BigInt(number) => query.bind(number),
+        })
+}
+
+/// Decode the JSON body in the first column of each row
+fn decode_bodies<T: DeserializeOwned>(rows: &[PgRow]) -> Result<Vec<T>, String> {
+    rows.iter()
+        .map(|row| decode(&row.try_get::<String, _>(0).map_err(db_error)?))
+        .collect()
+}
+
+/// Open a pool of up to `max_connections` to `url`, with the schema brought
+/// up to date
+async fn connect(url: &str, max_connections: u32) -> Result<PgPool, String> {
+    let pool = PgPoolOptions::new()
+        .max_connections(max_connections)
+        .connect(url)
+        .await
+        .map_err(db_error)?;
+    migrate(&pool).await?;
+    Ok(pool)
+}
+
+/// Bring the schema up to date, all in one transaction
+async fn migrate(pool: &PgPool) -> Result<(), String> {
+    let mut tx = pool.begin().await.map_err(db_error)?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(MIGRATION_LOCK_ID)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    sqlx::raw_sql(MIGRATIONS_TABLE)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    let applied: i64 =
+        sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM schema_migrations")
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(db_error)?;
+    for migration in pending(POSTGRES_MIGRATIONS, applied as u32)? {
+        sqlx::raw_sql(migration.sql)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| {
+                format!(
+                    "Migration {} ({}) failed: {}",
+                    migration.version, migration.name, e
+                )
+            })?;
+        sqlx::query(
+            "INSERT INTO schema_migrations (version, name, applied_us) VALUES ($1, $2, $3)",
+        )
+        .bind(migration.version as i64)
+        .bind(migration.name)
+        .bind(Utc::now().timestamp_micros())
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    }
+    tx.commit().await.map_err(db_error)
+}
+
+/// Point each of the user's outside identities at them, and only those
+async fn link_identities(
+    tx: &mut Transaction<'_, Postgres>,
+    user: &User,
+) -> Result<(), sqlx::Error> {
+    let user_id = user.id.to_string();
+    sqlx::query("DELETE FROM user_identities WHERE user_id = $1")
+        .bind(&user_id)
+        .execute(&mut **tx)
+        .await?;
+    for identity in &user.external_identities {
+        sqlx::query(
+            "INSERT INTO user_identities (provider, subject, user_id) VALUES ($1, $2, $3)
+             ON CONFLICT (provider, subject) DO UPDATE SET user_id = EXCLUDED.user_id",
+        )
+        .bind(&identity.provider)
+        .bind(&identity.subject)
+        .bind(&user_id)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+fn db_error(e: sqlx::Error) -> String {
+    format!("Database error: {}", e)
+}
+
+impl OrderStore for PostgresStore {
+    fn save_orders(&mut self, orders: &[Order]) -> Result<(), String> {
+        let rows = orders
+            .iter()
+            .map(|order| {
+                vec![
+                    Param::Text(order.id.to_string()),
+                    Param::Text(order.user_id.to_string()),
+                    Param::BigInt(order.timestamp.timestamp_micros()),
+                    Param::Text(encode(order)),
+                ]
+            })
+            .collect();
+        self.save_all(
+            "INSERT INTO orders (id, user_id, timestamp_us, body) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (id) DO UPDATE SET body = EXCLUDED.body",
+            rows,
+        )
+    }
+
+    fn load_order(&self, order_id: Uuid) -> Result<Option<Order>, String> {
+        let orders = self.bodies(
+            "SELECT body FROM orders WHERE id = $1",
+            vec![Param::Text(order_id.to_string())],
+        )?;
+        Ok(orders.into_iter().next())
+    }
+
+    fn orders_for_user(&self, user_id: Uuid, query: &HistoryQuery) -> Result<Vec<Order>, String> {
+        let (from, to) = query.range_micros();
+        self.bodies(
+            "SELECT body FROM orders
+             WHERE user_id = $1 AND timestamp_us >= $2 AND timestamp_us < $3
+             ORDER BY timestamp_us DESC, seq DESC LIMIT $4 OFFSET $5",
+            vec![
+                Param::Text(user_id.to_string()),
+                Param::BigInt(from),
+                Param::BigInt(to),
+                Param::BigInt(query.limit as i64),
+                Param::BigInt(query.offset as i64),
+            ],
+        )
+    }
+}
+
+impl TradeStore for PostgresStore {
+    fn save_trades(&mut self, trades: &[Trade]) -> Result<(), String> {
+        let rows = trades
+            .iter()
+            .map(|trade| {
+                vec![
+                    Param::Text(trade.id.to_string()),
+                    Param::Text(trade.maker_user_id.to_string()),
+                    Param::Text(trade.taker_user_id.to_string()),
+                    Param::BigInt(trade.timestamp.timestamp_micros()),
+                    Param::Text(encode(trade)),
+                ]
+            })
+            .collect();
+        let events = trades
+            .iter()
+            .map(|trade| {
+                let event = OutboxEvent::trade_executed(trade);
+                vec![
+                    Param::Text(event.id.to_string()),
+                    Param::Text(encode(&event)),
+                ]
+            })
+            .collect();
+        self.save_batches(vec![
+            (
+                "INSERT INTO trades (id, maker_user_id, taker_user_id, timestamp_us, body)
+                 VALUES ($1, $2, $3, $4, $5)",
+                rows,
+            ),
+            (
+                "INSERT INTO outbox (id, body) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING",
+                events,
+            ),
+        ])
+    }
+
+    fn trades_for_user(&self, user_id: Uuid, query: &HistoryQuery) -> Result<Vec<Trade>, String> {
+        let (from, to) = query.range_micros();
+        self.bodies(
+            "SELECT body FROM trades
+             WHERE (maker_user_id = $1 OR taker_user_id = $1)
+               AND timestamp_us >= $2 AND timestamp_us < $3
+             ORDER BY timestamp_us DESC, seq DESC LIMIT $4 OFFSET $5",
+            vec![
+                Param::Text(user_id.to_string()),
+                Param::BigInt(from),
+                Param::BigInt(to),
+                Param::BigInt(query.limit as i64),
+                Param::BigInt(query.offset as i64),
+            ],
+        )
+    }
+}
+
+impl LedgerStore for PostgresStore {
+    fn save_journals(&mut self, journals: &[Journal]) -> Result<(), String> {
+        let rows = journals
+            .iter()
+            .map(|journal| {
+                vec![
+                    Param::BigInt(journal.id as i64),
+                    Param::Text(encode(journal)),
+                ]
+            })
+            .collect();
+        self.save_all("INSERT INTO journals (id, body) VALUES ($1, $2)", rows)
+    }
+
+    fn journals_after(&self, after_id: u64, limit: usize) -> Result<Vec<Journal>, String> {
+        self.bodies(
+            "SELECT body FROM journals WHERE id > $1 ORDER BY id LIMIT $2",
+            vec![Param::BigInt(after_id as i64), Param::BigInt(limit as i64)],
+        )
+    }
+
+    fn last_journal_id(&self) -> Result<u64, String> {
+        let id: i64 = self.run(|pool| async move {
+            sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM journals")
+                .fetch_one(&pool)
+                .await
+        })?;
+        Ok(id as u64)
+    }
+}
+
+impl OutboxStore for PostgresStore {
+    fn queue_events(&mut self, events: &[OutboxEvent]) -> Result<(), String> {
+        let rows = events
+            .iter()
+            .map(|event| {
+                vec![
+                    Param::Text(event.id.to_string()),
+                    Param::Text(encode(event)),
+                ]
+            })
+            .collect();
+        self.save_all(
+            "INSERT INTO outbox (id, body) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING",
+            rows,
+        )
+    }
+
+    fn pending_events(&self, limit: usize) -> Result<Vec<OutboxEvent>, String> {
+        self.bodies(
+            "SELECT body FROM outbox WHERE delivered_us IS NULL ORDER BY seq LIMIT $1",
+            vec![Param::BigInt(limit as i64)],
+        )
+    }
+
+    fn mark_delivered(&mut self, event_ids: &[Uuid], at: DateTime<Utc>) -> Result<(), String> {
+        let rows = event_ids
+            .iter()
+            .map(|id| {
+                vec![
+                    Param::Text(id.to_string()),
+                    Param::BigInt(at.timestamp_micros()),
+                ]
+            })
+            .collect();
+        self.save_all(
+            "UPDATE outbox SET delivered_us = $2 WHERE id = $1 AND delivered_us IS NULL",
+            rows,
+        )
+    }
+}
+
+/// Users and API keys in a PostgreSQL database, over a pool of connections
+/// the request handlers share. Accounts are read and written whole; each save
+/// moves the row's `revision` on, and one made from an older read is refused.
+pub struct PostgresUserRepository {
+    pool: PgPool,
+}
+
+impl PostgresUserRepository {
+    /// Connect to `url` and apply any migrations it has not had yet
+    pub async fn connect(url: &str) -> Result<Self, String> {
+        Ok(PostgresUserRepository {
+            pool: connect(url, USER_POOL_SIZE).await?,
+        })
+    }
+
+    /// Run `query` and decode the JSON body in the first column of each row
+    async fn bodies<T: DeserializeOwned>(
+        &self,
+        query: &'static str,
+        params: Vec<Param>,
+    ) -> Result<Vec<T>, String> {
+        let rows = bind(query, params)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(db_error)?;
+        decode_bodies(&rows)
+    }
+
+    /// Run `query`, which selects a user's `body` then `revision`, and decode
+    /// the first row
+    async fn user(&self, query: &'static str, params: Vec<Param>) -> Result<Option<User>, String> {
+        let row = bind(query, params)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(db_error)?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let mut user: User = decode(&row.try_get::<String, _>(0).map_err(db_error)?)?;
+        user.revision = row.try_get::<i64, _>(1).map_err(db_error)? as u64;
+        Ok(Some(user))
+    }
+}
+
+#[async_trait]
+impl UserRepository for PostgresUserRepository {
+    async fn insert_user(&self, user: &User) -> Result<bool, String> {
+        let mut tx = self.pool.begin().await.map_err(db_error)?;
+        let inserted = sqlx::query(
+            "INSERT INTO users (id, username, email, body) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (username) DO NOTHING",
+        )
+        .bind(user.id.to_string())
+        .bind(&user.username)
+        .bind(&user.email)
+        .bind(encode(user))
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+        if inserted.rows_affected() == 0 {
+            return Ok(false);
+        }
+        link_identities(&mut tx, user).await.map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+        Ok(true)
+    }
+
+    async fn update_user(&self, user: &User) -> Result<bool, String> {
+        let mut tx = self.pool.begin().await.map_err(db_error)?;
+        let updated = sqlx::query(
+            "UPDATE users SET email = $2, body = $3, revision = revision + 1
+             WHERE id = $1 AND revision = $4",
+        )
+        .bind(user.id.to_string())
+        .bind(&user.email)
+        .bind(encode(user))
+        .bind(user.revision as i64)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+        if updated.rows_affected() == 0 {
+            return Ok(false);
+        }
+        link_identities(&mut tx, user).await.map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+        Ok(true)
+    }
+
+    async fn user_by_id(&self, user_id: Uuid) -> Result<Option<User>, String> {
+        self.user(
+            "SELECT body, revision FROM users WHERE id = $1",
+            vec![Param::Text(user_id.to_string())],
+        )
+        .await
+    }
+
+    async fn user_by_username(&self, username: &str) -> Result<Option<User>, String> {
+        self.user(
+            "SELECT body, revision FROM users WHERE username = $1",
+            vec![Param::Text(username.to_string())],
+        )
+        .await
+    }
+
+    async fn user_by_identity(
+        &self,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<User>, String> {
+        self.user(
+            "SELECT users.body, users.revision FROM users
+             JOIN user_identities ON user_identities.user_id = users.id
+             WHERE user_identities.provider = $1 AND user_identities.subject = $2",
+            vec![
+                Param::Text(provider.to_string()),
+                Param::Text(subject.to_string()),
+            ],
+        )
+        .await
+    }
+
+    async fn user_by_email(&self, email: &str) -> Result<Option<User>, String> {
+        self.user(
+            "SELECT body, revision FROM users WHERE lower(email) = lower($1)
+             ORDER BY seq LIMIT 1",
+            vec![Param::Text(email.to_string())],
+        )
+        .await
+    }
+
+    async fn insert_api_key(&self, key: &ApiKey) -> Result<(), String> {
+        sqlx::query(
+            "INSERT INTO api_keys (key_id, user_id, created_us, body) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(&key.key_id)
+        .bind(key.user_id.to_string())
+        .bind(key.created_at.timestamp_micros())
+        .bind(encode(key))
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(db_error)
+    }
+
+    async fn api_key(&self, key_id: &str) -> Result<Option<ApiKey>, String> {
+        let keys = self
+            .bodies(
+                "SELECT body FROM api_keys WHERE key_id = $1",
+                vec![Param::Text(key_id.to_string())],
+            )
+            .await?;
+        Ok(keys.into_iter().next())
+    }
+
+    async fn api_keys_for_user(&self, user_id: Uuid) -> Result<Vec<ApiKey>, String> {
+        self.bodies(
+            "SELECT body FROM api_keys WHERE user_id = $1 ORDER BY created_us",
+            vec![Param::Text(user_id.to_string())],
+        )
+        .await
+    }
+
+    async fn delete_api_key(&self, user_id: Uuid, key_id: &str) -> Result<bool, String> {
+        let deleted = sqlx::query("DELETE FROM api_keys WHERE key_id = $1 AND user_id = $2")
+            .bind(key_id)
+            .bind(user_id.to_string())
+            .execute(&self.pool)
+            .await
+            .map_err(db_error)?;
+        Ok(deleted.rows_affected() > 0)
+    }
+
+    async fn insert_activity(&self, user_id: Uuid, entry: &ActivityEntry) -> Result<(), String> {
+        sqlx::query("INSERT INTO user_activity (user_id, at_us, body) VALUES ($1, $2, $3)")
+            .bind(user_id.to_string())
+            .bind(entry.at.timestamp_micros())
+            .bind(encode(entry))
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(db_error)
+    }
+
+    async fn activities(
+        &self,
+        user_id: Uuid,
+        query: &HistoryQuery,
+    ) -> Result<Vec<ActivityEntry>, String> {
+        let (from, to) = query.range_micros();
+        self.bodies(
+            "SELECT body FROM user_activity
+             WHERE user_id = $1 AND at_us >= $2 AND at_us < $3
+             ORDER BY at_us DESC, seq DESC LIMIT $4 OFFSET $5",
+            vec![
+                Param::Text(user_id.to_string()),
+                Param::BigInt(from),
+                Param::BigInt(to),
+                Param::BigInt(query.limit.min(i64::MAX as usize) as i64),
+                Param::BigInt(query.offset.min(i64::MAX as usize) as i64),
+            ],
+        )
+        .await
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,247 @@
+use crate::ledger::Journal;
+use crate::storage::migrations::{pending, MIGRATIONS_TABLE, SQLITE_MIGRATIONS};
+use crate::storage::{
+    decode, encode, HistoryQuery, LedgerStore, OrderStore, OutboxEvent, OutboxStore, TradeStore,
+};
+use crate::types::{Order, Trade};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, TransactionBehavior};
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Persists to a SQLite database file
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+impl SqliteStore {
+    /// Open the database at `path`, creating the file if missing, and apply any
+    /// migrations it has not had yet
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create database directory: {}", e))?;
+        }
+        let mut conn =
+            Connection::open(path).map_err(|e| format!("Failed to open database: {}", e))?;
+        migrate(&mut conn)?;
+        Ok(SqliteStore { conn })
+    }
+
+    /// Run `query` and decode the JSON body in the first column of each row
+    fn bodies<T: DeserializeOwned>(
+        &self,
+        query: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<T>, String> {
+        let mut statement = self.conn.prepare_cached(query).map_err(db_error)?;
+        let rows = statement
+            .query_map(params, |row| row.get::<_, String>(0))
+            .map_err(db_error)?;
+        rows.map(|body| decode(&body.map_err(db_error)?)).collect()
+    }
+
+    /// Run `insert` once per item, all in one transaction
+    fn save_all<T>(
+        &mut self,
+        items: &[T],
+        insert: &str,
+        bind: impl Fn(&mut rusqlite::CachedStatement, &T) -> rusqlite::Result<usize>,
+    ) -> Result<(), String> {
+        let tx = self.conn.transaction().map_err(db_error)?;
+        {
+            let mut statement = tx.prepare_cached(insert).map_err(db_error)?;
+            for item in items {
+                bind(&mut statement, item).map_err(db_error)?;
+            }
+        }
+        tx.commit().map_err(db_error)
+    }
+}
+
+/// Bring the schema up to date, all in one transaction. Taking the write lock
+/// up front keeps two processes from migrating the same file at once.
+fn migrate(conn: &mut Connection) -> Result<(), String> {
+    let tx = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .map_err(db_error)?;
+    tx.execute_batch(MIGRATIONS_TABLE).map_err(db_error)?;
+    let applied: i64 = tx
+        .query_row(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(db_error)?;
+    for migration in pending(SQLITE_MIGRATIONS, applied as u32)? {
+        tx.execute_batch(migration.sql).map_err(|e| {
+            format!(
+                "Migration {} ({}) failed: {}",
+                migration.version, migration.name, e
+            )
+        })?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name, applied_us) VALUES (?1, ?2, ?3)",
+            params![
+                migration.version,
+                migration.name,
+                Utc::now().timestamp_micros()
+            ],
+        )
+        .map_err(db_error)?;
+    }
+    tx.commit().map_err(db_error)
+}
+
+fn db_error(e: rusqlite::Error) -> String {
+    format!("Database error: {}", e)
+}
+
+impl OrderStore for SqliteStore {
+    fn save_orders(&mut self, orders: &[Order]) -> Result<(), String> {
+        self.save_all(
+            orders,
+            "INSERT INTO orders (id, user_id, timestamp_us, body) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (id) DO UPDATE SET body = excluded.body",
+            |statement, order| {
+                statement.execute(params![
+                    order.id.to_string(),
+                    order.user_id.to_string(),
+                    order.timestamp.timestamp_micros(),
+                    encode(order)
+                ])
+            },
+        )
+    }
+
+    fn load_order(&self, order_id: Uuid) -> Result<Option<Order>, String> {
+        let orders = self.bodies(
+            "SELECT body FROM orders WHERE id = ?1",
+            params![order_id.to_string()],
+        )?;
+        Ok(orders.into_iter().next())
+    }
+
+    fn orders_for_user(&self, user_id: Uuid, query: &HistoryQuery) -> Result<Vec<Order>, String> {
+        let (from, to) = query.range_micros();
+        // Upserts keep the rowid, so it breaks ties in the order of first save
+        self.bodies(
+            "SELECT body FROM orders
+             WHERE user_id = ?1 AND timestamp_us >= ?2 AND timestamp_us < ?3
+             ORDER BY timestamp_us DESC, rowid DESC LIMIT ?4 OFFSET ?5",
+            params![
+                user_id.to_string(),
+                from,
+                to,
+                query.limit as i64,
+                query.offset as i64
+            ],
+        )
+    }
+}
+
+impl TradeStore for SqliteStore {
+    fn save_trades(&mut self, trades: &[Trade]) -> Result<(), String> {
+        let tx = self.conn.transaction().map_err(db_error)?;
+        {
+            let mut insert = tx
+                .prepare_cached(
+                    "INSERT INTO trades (id, maker_user_id, taker_user_id, timestamp_us, body)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                )
+                .map_err(db_error)?;
+            let mut queue = tx
+                .prepare_cached(
+                    "INSERT INTO outbox (id, body) VALUES (?1, ?2) ON CONFLICT (id) DO NOTHING",
+                )
+                .map_err(db_error)?;
+            for trade in trades {
+                insert
+                    .execute(params![
+                        trade.id.to_string(),
+                        trade.maker_user_id.to_string(),
+                        trade.taker_user_id.to_string(),
+                        trade.timestamp.timestamp_micros(),
+                        encode(trade)
+                    ])
+                    .map_err(db_error)?;
+                let event = OutboxEvent::trade_executed(trade);
+                queue
+                    .execute(params![event.id.to_string(), encode(&event)])
+                    .map_err(db_error)?;
+            }
+        }
+        tx.commit().map_err(db_error)
+    }
+
+    fn trades_for_user(&self, user_id: Uuid, query: &HistoryQuery) -> Result<Vec<Trade>, String> {
+        let (from, to) = query.range_micros();
+        self.bodies(
+            "SELECT body FROM trades
+             WHERE (maker_user_id = ?1 OR taker_user_id = ?1)
+               AND timestamp_us >= ?2 AND timestamp_us < ?3
+             ORDER BY timestamp_us DESC, rowid DESC LIMIT ?4 OFFSET ?5",
+            params![
+                user_id.to_string(),
+                from,
+                to,
+                query.limit as i64,
+                query.offset as i64
+            ],
+        )
+    }
+}
+
+impl LedgerStore for SqliteStore {
+    fn save_journals(&mut self, journals: &[Journal]) -> Result<(), String> {
+        self.save_all(
+            journals,
+            "INSERT INTO journals (id, body) VALUES (?1, ?2)",
+            |statement, journal| statement.execute(params![journal.id as i64, encode(journal)]),
+        )
+    }
+
+    fn journals_after(&self, after_id: u64, limit: usize) -> Result<Vec<Journal>, String> {
+        self.bodies(
+            "SELECT body FROM journals WHERE id > ?1 ORDER BY id LIMIT ?2",
+            params![after_id as i64, limit as i64],
+        )
+    }
+
+    fn last_journal_id(&self) -> Result<u64, String> {
+        self.conn
+            .query_row("SELECT COALESCE(MAX(id), 0) FROM journals", [], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|id| id as u64)
+            .map_err(db_error)
+    }
+}
+
+impl OutboxStore for SqliteStore {
+    fn queue_events(&mut self, events: &[OutboxEvent]) -> Result<(), String> {
+        self.save_all(
+            events,
+            "INSERT INTO outbox (id, body) VALUES (?1, ?2) ON CONFLICT (id) DO NOTHING",
+            |statement, event| statement.execute(params![event.id.to_string(), encode(event)]),
+        )
+    }
+
+    fn pending_events(&self, limit: usize) -> Result<Vec<OutboxEvent>, String> {
+        self.bodies(
+            "SELECT body FROM outbox WHERE delivered_us IS NULL ORDER BY rowid LIMIT ?1",
+            params![limit as i64],
+        )
+    }
+
+    fn mark_delivered(&mut self, event_ids: &[Uuid], at: DateTime<Utc>) -> Result<(), String> {
+        self.save_all(
+            event_ids,
+            "UPDATE outbox SET delivered_us = ?2 WHERE id = ?1 AND delivered_us IS NULL",
+            |statement, id| statement.execute(params![id.to_string(), at.timestamp_micros()]),
+        )
+    }
+}
@@ -0,0 +1,81 @@
+//! Fixtures shared by the unit tests
+
+use chrono::{DateTime, Utc};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::messages::{OrderBookCommand, OrderBookResponse};
+use crate::types::{OrderSide, OrderSource, Price, Quantity, TimeInForce};
+
+/// xorshift64: reproducible from its seed, which fuzz tests fix so a failure
+/// can be replayed
+pub struct XorShift(pub u64);
+
+impl XorShift {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// A limit order as the gateway queues one: GTC, from the web, with every
+/// optional feature off. Tests set the rest with struct update syntax.
+pub struct LimitOrder {
+    pub user_id: Uuid,
+    pub side: OrderSide,
+    pub price: Price,
+    pub quantity: Quantity,
+    pub time_in_force: TimeInForce,
+    pub display_quantity: Option<Quantity>,
+    pub hidden: bool,
+    pub min_fill_qty: Option<Quantity>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub trade_through_protected: bool,
+    pub priority_fee: f64,
+    pub source: OrderSource,
+    pub client_order_id: Option<String>,
+}
+
+impl LimitOrder {
+    pub fn new(user_id: Uuid, side: OrderSide, price: f64, quantity: f64) -> Self {
+        LimitOrder {
+            user_id,
+            side,
+            price: Price::from_f64(price),
+            quantity: Quantity::from_f64(quantity),
+            time_in_force: TimeInForce::GTC,
+            display_quantity: None,
+            hidden: false,
+            min_fill_qty: None,
+            expires_at: None,
+            trade_through_protected: false,
+            priority_fee: 0.0,
+            source: OrderSource::Web,
+            client_order_id: None,
+        }
+    }
+
+    /// The command placing this order, received now
+    pub fn command(self, response_tx: oneshot::Sender<OrderBookResponse>) -> OrderBookCommand {
+        OrderBookCommand::PlaceLimitOrder {
+            user_id: self.user_id,
+            side: self.side,
+            price: self.price,
+            quantity: self.quantity,
+            time_in_force: self.time_in_force,
+            display_quantity: self.display_quantity,
+            hidden: self.hidden,
+            min_fill_qty: self.min_fill_qty,
+            expires_at: self.expires_at,
+            peg: None,
+            trade_through_protected: self.trade_through_protected,
+            priority_fee: self.priority_fee,
+            received_at: Utc::now(),
+            source: self.source,
+            client_order_id: self.client_order_id,
+            response_tx,
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use uuid::Uuid;
+
+use crate::types::{LiquidityRole, OrderSide, OrderType, Price, Quantity};
+
+/// Something that happened to a user's account, as shown on its activity feed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Activity {
+    Login {
+        method: String,     // "password", or the identity provider signed in with
+        ip: Option<String>, // Of the connection, or as a trusted proxy in front of it reports
+    },
+    ApiKeyCreated {
+        key_id: String,
+        label: Option<String>,
+        scopes: Vec<String>,
+    },
+    ApiKeyRevoked {
+        key_id: String,
+    },
+    OrderPlaced {
+        order_id: Uuid,
+        side: OrderSide,
+        order_type: OrderType,
+        price: Option<Price>, // None for market orders
+        quantity: Quantity,
+    },
+    OrderFilled {
+        order_id: Uuid,
+        trade_id: Uuid,
+        role: LiquidityRole,
+        price: Price,
+        quantity: Quantity,
+        fee: f64,
+    },
+    OrderCancelled {
+        order_id: Uuid,
+        remaining_quantity: Quantity,
+    },
+    OrderExpired {
+        order_id: Uuid,
+        remaining_quantity: Quantity,
+    },
+    /// A triggered stop the engine could not execute
+    OrderRejected {
+        order_id: Uuid,
+        reason: String,
+    },
+    Deposit {
+        currency: String,
+        amount: f64,
+    },
+    Withdrawal {
+        currency: String,
+        amount: f64,
+    },
+    /// A withdrawal whose payout failed, returned to the balance
+    WithdrawalReversed {
+        currency: String,
+        amount: f64,
+    },
+    /// A withdrawal address was linked; funds can go to it from `usable_from`
+    WithdrawalAddressAdded {
+        funding_source_id: Uuid,
+        address: String,
+        usable_from: DateTime<Utc>,
+    },
+    /// A withdrawal was refused because its address is still cooling off
+    WithdrawalBlocked {
+        funding_source_id: Uuid,
+        address: String,
+        amount: f64,
+        usable_from: DateTime<Utc>,
+    },
+}
+
+/// One line of the activity feed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActivityEntry {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+impl ActivityEntry {
+    pub fn new(at: DateTime<Utc>, activity: Activity) -> Self {
+        ActivityEntry { at, activity }
+    }
+}
+
+/// Sort newest first. Entries at the same instant keep their order, so lists
+/// that are each newest first already can be merged with it.
+pub fn newest_first(entries: &mut [ActivityEntry]) {
+    entries.sort_by_key(|entry| Reverse(entry.at));
+}
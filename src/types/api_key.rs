@@ -0,0 +1,55 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::utils::secrets::SealedSecret;
+
+/// Prefix that tells API key credentials apart from user JWTs
+pub const API_KEY_PREFIX: &str = "ak_";
+
+/// What an API key may be used for. Each authenticated route declares the one
+/// it needs; sessions signed in with a password are not limited by them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    Read,     // Balances, orders, history
+    Trade,    // Placing, amending and cancelling orders; account settings
+    Withdraw, // Withdrawals and the funding sources they go to
+    Admin,    // Creating, listing and revoking API keys
+}
+
+impl ApiKeyScope {
+    /// Granted when a key is created without naming its scopes
+    pub const DEFAULT: [ApiKeyScope; 2] = [ApiKeyScope::Read, ApiKeyScope::Trade];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyScope::Read => "read",
+            ApiKeyScope::Trade => "trade",
+            ApiKeyScope::Withdraw => "withdraw",
+            ApiKeyScope::Admin => "admin",
+        }
+    }
+}
+
+/// Scopes of the API key a request authenticated with, kept in the request
+/// extensions. Absent for session tokens.
+#[derive(Debug, Clone)]
+pub struct GrantedScopes(pub Vec<ApiKeyScope>);
+
+/// An API key. The secret is kept encrypted, since request signatures can only
+/// be checked by recomputing them with it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub key_id: String,
+    pub user_id: Uuid,
+    pub label: Option<String>,
+    pub secret: SealedSecret,
+    pub created_at: DateTime<Utc>,
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+pub fn default_scopes() -> Vec<ApiKeyScope> {
+    ApiKeyScope::DEFAULT.to_vec()
+}
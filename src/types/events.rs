@@ -0,0 +1,254 @@
+use super::{
+    LiquidityRole, Order, OrderSide, OrderSource, OrderStatus, OrderType, Price, Quantity,
+    TimeInForce, Trade,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// Version of the trade payload on `trade.executed` events
+pub const TRADE_EVENT_VERSION: u32 = 1;
+
+/// Version of the payload on `order.updated` events
+pub const ORDER_EVENT_VERSION: u32 = 1;
+
+/// Version of the payload on `balance.changed` events
+pub const BALANCE_EVENT_VERSION: u32 = 1;
+
+/// An event schema published to receivers outside the exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct EventSchema {
+    pub kind: &'static str,
+    pub version: u32,
+}
+
+/// Every externally published event and the version now being sent. Adding
+/// a field to `Trade` or `Order` changes none of them: each version is a
+/// struct of its own that copies what it publishes. Removing, renaming or
+/// retyping a published field needs a new version struct alongside the old.
+pub const EVENT_SCHEMAS: &[EventSchema] = &[
+    EventSchema {
+        kind: "trade.executed",
+        version: TRADE_EVENT_VERSION,
+    },
+    EventSchema {
+        kind: "order.updated",
+        version: ORDER_EVENT_VERSION,
+    },
+    EventSchema {
+        kind: "balance.changed",
+        version: BALANCE_EVENT_VERSION,
+    },
+];
+
+/// Version 1 of a trade as published: the fields `Trade` had when events
+/// were first versioned, frozen in that shape
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TradeEventV1 {
+    pub id: Uuid,
+    pub maker_order_id: Uuid,
+    pub taker_order_id: Uuid,
+    pub maker_user_id: Uuid,
+    pub taker_user_id: Uuid,
+    pub price: Price,       // Fixed point, six decimals
+    pub quantity: Quantity, // Fixed point, eight decimals
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub price_improvement: PriceImprovementV1,
+    #[serde(default)]
+    pub maker_source: OrderSource,
+    #[serde(default)]
+    pub taker_source: OrderSource,
+    #[serde(default)]
+    pub maker_fill_seq: u64,
+    #[serde(default)]
+    pub taker_fill_seq: u64,
+    pub taker_side: OrderSide,
+    pub buyer_role: LiquidityRole,
+    pub seller_role: LiquidityRole,
+    #[serde(default)]
+    pub maker_fee: f64,
+    #[serde(default)]
+    pub taker_fee: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PriceImprovementV1 {
+    pub vs_limit: Option<f64>,
+    pub vs_arrival_bbo: Option<f64>,
+}
+
+impl From<&Trade> for TradeEventV1 {
+    fn from(trade: &Trade) -> Self {
+        TradeEventV1 {
+            id: trade.id,
+            maker_order_id: trade.maker_order_id,
+            taker_order_id: trade.taker_order_id,
+            maker_user_id: trade.maker_user_id,
+            taker_user_id: trade.taker_user_id,
+            price: trade.price,
+            quantity: trade.quantity,
+            timestamp: trade.timestamp,
+            price_improvement: PriceImprovementV1 {
+                vs_limit: trade.price_improvement.vs_limit,
+                vs_arrival_bbo: trade.price_improvement.vs_arrival_bbo,
+            },
+            maker_source: trade.maker_source,
+            taker_source: trade.taker_source,
+            maker_fill_seq: trade.maker_fill_seq,
+            taker_fill_seq: trade.taker_fill_seq,
+            taker_side: trade.taker_side,
+            buyer_role: trade.buyer_role,
+            seller_role: trade.seller_role,
+            maker_fee: trade.maker_fee,
+            taker_fee: trade.taker_fee,
+        }
+    }
+}
+
+/// Version 1 of an order as published, in its state after a change
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderEventV1 {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub client_order_id: Option<String>,
+    pub side: OrderSide,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub price: Option<Price>, // None for market orders
+    pub original_quantity: Quantity,
+    pub remaining_quantity: Quantity,
+    pub status: OrderStatus,
+    pub source: OrderSource,
+    pub timestamp: DateTime<Utc>, // When the engine accepted the order
+}
+
+impl From<&Order> for OrderEventV1 {
+    fn from(order: &Order) -> Self {
+        OrderEventV1 {
+            id: order.id,
+            user_id: order.user_id,
+            client_order_id: order.client_order_id.clone(),
+            side: order.side,
+            order_type: order.order_type,
+            time_in_force: order.time_in_force,
+            price: order.price,
+            original_quantity: order.original_quantity,
+            remaining_quantity: order.remaining_quantity,
+            status: order.status,
+            source: order.source,
+            timestamp: order.timestamp,
+        }
+    }
+}
+
+/// Version 1 of a user's balances as published after a change
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BalanceEventV1 {
+    pub user_id: Uuid,
+    pub balances: BTreeMap<String, f64>, // Currency -> balance, sorted by currency
+}
+
+impl BalanceEventV1 {
+    pub fn new(user_id: Uuid, balances: &HashMap<String, f64>) -> Self {
+        BalanceEventV1 {
+            user_id,
+            balances: balances.clone().into_iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::OutboxEvent;
+
+    fn trade() -> Trade {
+        let mut trade = Trade::new(
+            Uuid::from_u128(1),
+            Uuid::from_u128(2),
+            Uuid::from_u128(3),
+            Uuid::from_u128(4),
+            Price::from_f64(50_000.0),
+            Quantity::from_f64(0.5),
+        );
+        trade.id = Uuid::from_u128(5);
+        trade.timestamp = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        trade.price_improvement.vs_limit = Some(1.5);
+        trade.maker_fee = 5.0;
+        trade.taker_fee = 10.0;
+        trade
+    }
+
+    /// The version 1 payload, byte for byte as receivers have been parsing it
+    fn trade_event_v1_json() -> serde_json::Value {
+        serde_json::json!({
+            "id": "00000000-0000-0000-0000-000000000005",
+            "kind": "trade.executed",
+            "version": 1,
+            "trade": {
+                "id": "00000000-0000-0000-0000-000000000005",
+                "maker_order_id": "00000000-0000-0000-0000-000000000001",
+                "taker_order_id": "00000000-0000-0000-0000-000000000002",
+                "maker_user_id": "00000000-0000-0000-0000-000000000003",
+                "taker_user_id": "00000000-0000-0000-0000-000000000004",
+                "price": 50000000000u64,
+                "quantity": 50000000,
+                "timestamp": "2023-11-14T22:13:20Z",
+                "price_improvement": { "vs_limit": 1.5, "vs_arrival_bbo": null },
+                "maker_source": "web",
+                "taker_source": "web",
+                "maker_fill_seq": 0,
+                "taker_fill_seq": 0,
+                "taker_side": "Buy",
+                "buyer_role": "taker",
+                "seller_role": "maker",
+                "maker_fee": 5.0,
+                "taker_fee": 10.0
+            }
+        })
+    }
+
+    #[test]
+    fn test_trade_event_v1_shape_is_frozen() {
+        // Fails when a published field changes; that calls for a version 2
+        let event = OutboxEvent::trade_executed(&trade());
+        assert_eq!(serde_json::to_value(&event).unwrap(), trade_event_v1_json());
+    }
+
+    #[test]
+    fn test_older_and_newer_payloads_still_decode() {
+        // Queued before events carried a version
+        let mut json = trade_event_v1_json();
+        json.as_object_mut().unwrap().remove("version");
+        let event: OutboxEvent = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(event.version, 1);
+        assert_eq!(event.trade, Some(TradeEventV1::from(&trade())));
+
+        // Fields a later `Trade` gains are ignored by version 1 readers
+        json["trade"]["venue"] = serde_json::json!("dark-pool");
+        let event: OutboxEvent = serde_json::from_value(json).unwrap();
+        assert_eq!(event.trade, Some(TradeEventV1::from(&trade())));
+    }
+
+    #[test]
+    fn test_registry_names_the_kinds_sent() {
+        let order = Order::new_limit(
+            Uuid::from_u128(1),
+            OrderSide::Buy,
+            Price::from_f64(50_000.0),
+            Quantity::from_f64(0.5),
+        );
+        let balances = HashMap::from([("USD".to_string(), 100.0)]);
+        for event in [
+            OutboxEvent::trade_executed(&trade()),
+            OutboxEvent::order_updated(&order),
+            OutboxEvent::balance_changed(order.user_id, &balances),
+        ] {
+            let kind = serde_json::to_value(event.kind).unwrap();
+            let schema = EVENT_SCHEMAS.iter().find(|schema| kind == schema.kind);
+            assert_eq!(schema.map(|schema| schema.version), Some(event.version));
+        }
+    }
+}
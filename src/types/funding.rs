@@ -0,0 +1,271 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Wrong answers a source may get to its verification challenge before it is
+/// rejected for good
+pub const MAX_VERIFICATION_ATTEMPTS: u32 = 3;
+
+/// Where money enters and leaves the exchange from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FundingSourceKind {
+    MockBank,
+    MockCryptoWallet,
+}
+
+impl FundingSourceKind {
+    /// Whether sources of this kind are addresses, which may have to wait out
+    /// a cooling-off period before withdrawals go to them
+    pub fn is_address(&self) -> bool {
+        matches!(self, FundingSourceKind::MockCryptoWallet)
+    }
+
+    /// The one currency a source of this kind moves
+    pub fn currency(&self) -> &'static str {
+        match self {
+            FundingSourceKind::MockBank => "USD",
+            FundingSourceKind::MockCryptoWallet => "BTC",
+        }
+    }
+
+    /// Limits a newly linked source starts with
+    pub fn default_limits(&self) -> FundingLimits {
+        match self {
+            FundingSourceKind::MockBank => FundingLimits {
+                per_transfer: 25_000.0,
+                daily: 50_000.0,
+            },
+            FundingSourceKind::MockCryptoWallet => FundingLimits {
+                per_transfer: 2.0,
+                daily: 5.0,
+            },
+        }
+    }
+}
+
+impl FromStr for FundingSourceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mock_bank" => Ok(FundingSourceKind::MockBank),
+            "mock_crypto_wallet" => Ok(FundingSourceKind::MockCryptoWallet),
+            _ => Err(format!(
+                "Unknown funding source kind '{}', use 'mock_bank' or 'mock_crypto_wallet'",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerificationStatus {
+    /// Linked, but ownership not yet proven; cannot move money
+    Pending,
+    Verified,
+    /// Failed verification; has to be linked again
+    Rejected,
+}
+
+/// Bounds on the money one source may move, in its currency
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FundingLimits {
+    pub per_transfer: f64,
+    pub daily: f64, // Deposits and withdrawals together, per UTC day
+}
+
+/// A bank account or wallet a user has linked for deposits and withdrawals
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FundingSource {
+    pub id: Uuid,
+    pub kind: FundingSourceKind,
+    pub label: String,
+    pub reference: String, // Masked account number or wallet address
+    pub status: VerificationStatus,
+    pub limits: FundingLimits,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub failed_verifications: u32,
+    #[serde(default)]
+    pub used_today: f64,
+    #[serde(default)]
+    pub usage_day: Option<NaiveDate>, // UTC day `used_today` counts
+    #[serde(default)]
+    pub withdrawable_from: Option<DateTime<Utc>>, // None = withdrawals allowed since linking
+}
+
+impl FundingSource {
+    pub fn new(
+        kind: FundingSourceKind,
+        label: String,
+        reference: String,
+        status: VerificationStatus,
+    ) -> Self {
+        FundingSource {
+            id: Uuid::new_v4(),
+            kind,
+            label,
+            reference,
+            status,
+            limits: kind.default_limits(),
+            created_at: Utc::now(),
+            failed_verifications: 0,
+            used_today: 0.0,
+            usage_day: None,
+            withdrawable_from: None,
+        }
+    }
+
+    pub fn currency(&self) -> &'static str {
+        self.kind.currency()
+    }
+
+    /// What is left of today's limit
+    pub fn remaining_today(&self, today: NaiveDate) -> f64 {
+        let used = if self.usage_day == Some(today) {
+            self.used_today
+        } else {
+            0.0
+        };
+        (self.limits.daily - used).max(0.0)
+    }
+
+    /// Count `amount` against today's limit, or say why it may not move
+    pub fn reserve(&mut self, amount: f64, today: NaiveDate) -> Result<(), String> {
+        match self.status {
+            VerificationStatus::Verified => {}
+            VerificationStatus::Pending => {
+                return Err("Funding source is not verified yet".to_string())
+            }
+            VerificationStatus::Rejected => {
+                return Err("Funding source failed verification".to_string())
+            }
+        }
+        if amount > self.limits.per_transfer {
+            return Err(format!(
+                "Amount exceeds the per-transfer limit of {} {}",
+                self.limits.per_transfer,
+                self.currency()
+            ));
+        }
+        if amount > self.remaining_today(today) {
+            return Err(format!(
+                "Amount exceeds the {} {} left of today's limit",
+                self.remaining_today(today),
+                self.currency()
+            ));
+        }
+
+        if self.usage_day != Some(today) {
+            self.usage_day = Some(today);
+            self.used_today = 0.0;
+        }
+        self.used_today += amount;
+        Ok(())
+    }
+
+    /// When withdrawals to the source become allowed, if that is still after `now`
+    pub fn withdrawal_hold(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.withdrawable_from.filter(|from| *from > now)
+    }
+
+    /// Give back a reservation whose transfer did not go through
+    pub fn release(&mut self, amount: f64, today: NaiveDate) {
+        if self.usage_day == Some(today) {
+            self.used_today = (self.used_today - amount).max(0.0);
+        }
+    }
+
+    /// Record the outcome of a verification attempt. Only pending sources
+    /// change; a rejected one stays rejected.
+    pub fn record_verification(&mut self, passed: bool) {
+        if self.status != VerificationStatus::Pending {
+            return;
+        }
+        if passed {
+            self.status = VerificationStatus::Verified;
+            return;
+        }
+        self.failed_verifications += 1;
+        if self.failed_verifications >= MAX_VERIFICATION_ATTEMPTS {
+            self.status = VerificationStatus::Rejected;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank(status: VerificationStatus) -> FundingSource {
+        FundingSource::new(
+            FundingSourceKind::MockBank,
+            "Checking".to_string(),
+            "****6789".to_string(),
+            status,
+        )
+    }
+
+    #[test]
+    fn test_limits_apply_per_transfer_and_per_day() {
+        let today = Utc::now().date_naive();
+        let tomorrow = today.succ_opt().unwrap();
+        let mut source = bank(VerificationStatus::Verified);
+
+        assert!(source.reserve(30_000.0, today).is_err());
+        source.reserve(25_000.0, today).unwrap();
+        source.reserve(20_000.0, today).unwrap();
+        assert!(source.reserve(10_000.0, today).is_err());
+
+        // A failed transfer hands its share back
+        source.release(20_000.0, today);
+        source.reserve(10_000.0, today).unwrap();
+        assert_eq!(source.remaining_today(today), 15_000.0);
+
+        // A new day starts from zero
+        assert_eq!(source.remaining_today(tomorrow), 50_000.0);
+        source.reserve(25_000.0, tomorrow).unwrap();
+        assert_eq!(source.used_today, 25_000.0);
+    }
+
+    #[test]
+    fn test_only_verified_sources_move_money() {
+        let today = Utc::now().date_naive();
+        let mut source = bank(VerificationStatus::Pending);
+        assert!(source.reserve(1.0, today).is_err());
+
+        source.record_verification(false);
+        assert_eq!(source.status, VerificationStatus::Pending);
+        source.record_verification(true);
+        assert!(source.reserve(1.0, today).is_ok());
+
+        let mut source = bank(VerificationStatus::Pending);
+        for _ in 0..MAX_VERIFICATION_ATTEMPTS {
+            source.record_verification(false);
+        }
+        assert_eq!(source.status, VerificationStatus::Rejected);
+        assert!(source.reserve(1.0, today).is_err());
+    }
+
+    #[test]
+    fn test_new_addresses_wait_before_withdrawals() {
+        let now = Utc::now();
+        let mut wallet = FundingSource::new(
+            FundingSourceKind::MockCryptoWallet,
+            "Cold storage".to_string(),
+            "bc1q...wlh".to_string(),
+            VerificationStatus::Verified,
+        );
+        assert!(wallet.kind.is_address());
+        assert_eq!(wallet.withdrawal_hold(now), None);
+
+        let from = now + chrono::Duration::hours(24);
+        wallet.withdrawable_from = Some(from);
+        assert_eq!(wallet.withdrawal_hold(now), Some(from));
+        assert_eq!(wallet.withdrawal_hold(from), None);
+        assert!(!bank(VerificationStatus::Verified).kind.is_address());
+    }
+}
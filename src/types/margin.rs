@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+
+/// One position-size bracket: positions with notional up to `max_notional`
+/// may use at most `max_leverage`, and must keep `maintenance_margin_rate`
+/// of their notional as margin.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LeverageTier {
+    pub max_notional: f64,
+    pub max_leverage: f64,
+    pub maintenance_margin_rate: f64,
+}
+
+/// Ordered brackets, smallest position first
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LeverageTiers {
+    pub tiers: Vec<LeverageTier>,
+}
+
+impl LeverageTiers {
+    /// Validate and build a tier table
+    pub fn new(tiers: Vec<LeverageTier>) -> Result<Self, String> {
+        if tiers.is_empty() {
+            return Err("At least one leverage tier is required".to_string());
+        }
+
+        for (i, tier) in tiers.iter().enumerate() {
+            if !(tier.max_notional.is_finite() && tier.max_notional > 0.0) {
+                return Err(format!("Tier {}: max_notional must be positive", i));
+            }
+            if !(tier.max_leverage.is_finite() && tier.max_leverage >= 1.0) {
+                return Err(format!("Tier {}: max_leverage must be at least 1", i));
+            }
+            // Maintenance must stay below initial margin or positions would be
+            // liquidatable the moment they are opened
+            if !(tier.maintenance_margin_rate > 0.0
+                && tier.maintenance_margin_rate < 1.0 / tier.max_leverage)
+            {
+                return Err(format!(
+                    "Tier {}: maintenance_margin_rate must be in (0, 1/max_leverage)",
+                    i
+                ));
+            }
+            if let Some(prev) = i.checked_sub(1).map(|p| &tiers[p]) {
+                if tier.max_notional <= prev.max_notional {
+                    return Err(format!("Tier {}: max_notional must increase", i));
+                }
+                if tier.max_leverage > prev.max_leverage {
+                    return Err(format!("Tier {}: max_leverage must not increase", i));
+                }
+            }
+        }
+
+        Ok(LeverageTiers { tiers })
+    }
+
+    /// The bracket a position of `notional` falls into, if any
+    pub fn tier_for(&self, notional: f64) -> Option<&LeverageTier> {
+        self.tiers.iter().find(|t| notional.abs() <= t.max_notional)
+    }
+
+    /// Largest position notional the table allows
+    pub fn max_notional(&self) -> f64 {
+        self.tiers.last().map_or(0.0, |t| t.max_notional)
+    }
+
+    /// Margin required to open a position of `notional` at `leverage`
+    pub fn initial_margin(&self, notional: f64, leverage: f64) -> Result<f64, String> {
+        let tier = self
+            .tier_for(notional)
+            .ok_or_else(|| "Position exceeds the largest leverage tier".to_string())?;
+        if leverage < 1.0 || leverage > tier.max_leverage {
+            return Err(format!(
+                "Leverage {} outside allowed range 1..={} for this position size",
+                leverage, tier.max_leverage
+            ));
+        }
+        Ok(notional.abs() / leverage)
+    }
+
+    /// Margin a position of `notional` must keep to avoid liquidation
+    pub fn maintenance_margin(&self, notional: f64) -> Result<f64, String> {
+        self.tier_for(notional)
+            .map(|t| notional.abs() * t.maintenance_margin_rate)
+            .ok_or_else(|| "Position exceeds the largest leverage tier".to_string())
+    }
+}
+
+impl Default for LeverageTiers {
+    fn default() -> Self {
+        let tier = |max_notional, max_leverage, maintenance_margin_rate| LeverageTier {
+            max_notional,
+            max_leverage,
+            maintenance_margin_rate,
+        };
+        LeverageTiers {
+            tiers: vec![
+                tier(50_000.0, 20.0, 0.025),
+                tier(250_000.0, 10.0, 0.05),
+                tier(1_000_000.0, 5.0, 0.1),
+                tier(5_000_000.0, 2.0, 0.25),
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_tiers_are_valid() {
+        let tiers = LeverageTiers::default();
+        assert_eq!(LeverageTiers::new(tiers.tiers.clone()).unwrap(), tiers);
+    }
+
+    #[test]
+    fn test_margin_by_bracket() {
+        let tiers = LeverageTiers::default();
+        assert_eq!(tiers.tier_for(10_000.0).unwrap().max_leverage, 20.0);
+        assert_eq!(tiers.tier_for(100_000.0).unwrap().max_leverage, 10.0);
+        assert_eq!(tiers.initial_margin(100_000.0, 10.0).unwrap(), 10_000.0);
+        assert!(tiers.initial_margin(100_000.0, 20.0).is_err());
+        assert_eq!(tiers.maintenance_margin(100_000.0).unwrap(), 5_000.0);
+        assert!(tiers.maintenance_margin(10_000_000.0).is_err());
+    }
+
+    #[test]
+    fn test_rejects_inconsistent_tiers() {
+        let ok = LeverageTier {
+            max_notional: 1000.0,
+            max_leverage: 10.0,
+            maintenance_margin_rate: 0.05,
+        };
+        let more_leverage = LeverageTier {
+            max_notional: 2000.0,
+            max_leverage: 20.0,
+            maintenance_margin_rate: 0.01,
+        };
+        let bad_maintenance = LeverageTier {
+            maintenance_margin_rate: 0.2,
+            ..ok
+        };
+
+        assert!(LeverageTiers::new(vec![]).is_err());
+        assert!(LeverageTiers::new(vec![ok, more_leverage]).is_err());
+        assert!(LeverageTiers::new(vec![bad_maintenance]).is_err());
+        assert!(LeverageTiers::new(vec![ok, ok]).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use crate::types::{Order, OrderSide, Price, SlippageGuard};
+
+/// Symbol of the single market this engine currently runs
+pub const DEFAULT_MARKET: &str = "BTC-USD";
+
+/// How the public book feed for a market is shaped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedMode {
+    /// Aggregated quantity per price level (L2)
+    #[default]
+    ByPrice,
+    /// Every resting order individually, in queue order (L3)
+    ByOrder,
+}
+
+impl std::str::FromStr for FeedMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "by_price" | "mbp" | "l2" => Ok(FeedMode::ByPrice),
+            "by_order" | "mbo" | "l3" => Ok(FeedMode::ByOrder),
+            _ => Err(format!("Unknown feed mode '{}'", s)),
+        }
+    }
+}
+
+/// When a market's trades are settled in the ledger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClearingMode {
+    /// One ledger journal per trade, as it happens
+    #[default]
+    PerTrade,
+    /// Trades accumulate in a netting window and settle together as one
+    /// journal holding each user's net movement per asset. Order book balances
+    /// still move with each trade; only the ledger waits for the window, so it
+    /// lags the book by the trades not yet settled.
+    Netted,
+}
+
+impl std::str::FromStr for ClearingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "per_trade" | "gross" => Ok(ClearingMode::PerTrade),
+            "netted" | "net" => Ok(ClearingMode::Netted),
+            _ => Err(format!("Unknown clearing mode '{}'", s)),
+        }
+    }
+}
+
+/// How resting orders at one price are ranked for fills. Hidden orders rank
+/// behind displayed ones under every policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AllocationPolicy {
+    /// Time priority: first received, first filled
+    #[default]
+    Fifo,
+    /// Experimental: orders that paid a higher priority fee rank first, and
+    /// equal fees keep time priority
+    PriorityFee,
+}
+
+impl AllocationPolicy {
+    /// Less means `a` is filled before `b` at the same price. Equal keeps
+    /// arrival order.
+    pub fn priority(&self, a: &Order, b: &Order) -> Ordering {
+        let by_time = || (a.hidden, a.received_at).cmp(&(b.hidden, b.received_at));
+        match self {
+            AllocationPolicy::Fifo => by_time(),
+            AllocationPolicy::PriorityFee => a
+                .hidden
+                .cmp(&b.hidden)
+                .then(b.priority_fee.total_cmp(&a.priority_fee))
+                .then_with(by_time),
+        }
+    }
+}
+
+impl std::str::FromStr for AllocationPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fifo" | "time" => Ok(AllocationPolicy::Fifo),
+            "priority_fee" => Ok(AllocationPolicy::PriorityFee),
+            _ => Err(format!("Unknown allocation policy '{}'", s)),
+        }
+    }
+}
+
+/// Whether a market is accepting new orders. Cancels are always accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingStatus {
+    #[default]
+    Trading,
+    /// Operator stop: new orders are rejected, resting orders stay on the book
+    Halted,
+    /// A dated market past its expiry, with every order cancelled and every
+    /// position closed. It is kept for reference and never trades again.
+    Settled,
+}
+
+/// How a dated market was closed out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketSettlement {
+    pub price: Price,
+    pub settled_at: DateTime<Utc>,
+    pub positions: usize, // Accounts whose position in the market was closed
+}
+
+/// How far a single market order may walk the book before the rest is cancelled.
+/// None on either bound means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SweepLimit {
+    pub max_levels: Option<usize>,
+    pub max_notional: Option<f64>, // In quote currency
+}
+
+/// Trading fees as a fraction of each fill's notional, charged in quote
+/// currency to the maker and taker respectively
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    pub maker_rate: f64,
+    pub taker_rate: f64,
+}
+
+impl FeeSchedule {
+    /// Fees owed by the maker and the taker of a fill worth `notional`
+    pub fn fees(&self, notional: f64) -> (f64, f64) {
+        (notional * self.maker_rate, notional * self.taker_rate)
+    }
+}
+
+/// Band around a reference price (such as an external index) that
+/// trade-through protected limit orders may not trade beyond. Without a
+/// reference price nothing is enforced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TradeThroughBand {
+    pub reference_price: Option<Price>,
+    pub band_bps: u32,
+}
+
+impl TradeThroughBand {
+    /// Worst price a protected order on `side` may trade at
+    pub fn limit(&self, side: OrderSide) -> Option<Price> {
+        SlippageGuard::MaxBps(self.band_bps).worst_price(side, self.reference_price)
+    }
+}
+
+/// Per-market settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketConfig {
+    pub symbol: String,
+    pub feed_mode: FeedMode,
+    pub sweep_limit: SweepLimit,
+    #[serde(default)]
+    pub trading_status: TradingStatus,
+    #[serde(default)]
+    pub clearing_mode: ClearingMode,
+    #[serde(default)]
+    pub fees: FeeSchedule,
+    #[serde(default)]
+    pub trade_through: TradeThroughBand,
+    #[serde(default)]
+    pub allocation: AllocationPolicy,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>, // Dated markets stop trading and settle then
+    #[serde(default)]
+    pub settlement: Option<MarketSettlement>,
+}
+
+impl MarketConfig {
+    /// Whether the market is dated and its expiry has passed
+    pub fn has_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        MarketConfig {
+            symbol: DEFAULT_MARKET.to_string(),
+            feed_mode: FeedMode::default(),
+            sweep_limit: SweepLimit::default(),
+            trading_status: TradingStatus::default(),
+            clearing_mode: ClearingMode::default(),
+            fees: FeeSchedule::default(),
+            trade_through: TradeThroughBand::default(),
+            allocation: AllocationPolicy::default(),
+            expires_at: None,
+            settlement: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_feed_mode_parsing() {
+        assert_eq!("by_order".parse::<FeedMode>().unwrap(), FeedMode::ByOrder);
+        assert_eq!("L2".parse::<FeedMode>().unwrap(), FeedMode::ByPrice);
+        assert!("full".parse::<FeedMode>().is_err());
+        assert_eq!(serde_json::to_string(&FeedMode::ByOrder).unwrap(), "\"by_order\"");
+    }
+
+    #[test]
+    fn test_clearing_mode_parsing_and_default() {
+        assert_eq!("NET".parse::<ClearingMode>().unwrap(), ClearingMode::Netted);
+        assert_eq!(
+            "per_trade".parse::<ClearingMode>().unwrap(),
+            ClearingMode::PerTrade
+        );
+        assert!("weekly".parse::<ClearingMode>().is_err());
+
+        let config: MarketConfig = serde_json::from_str(
+            r#"{"symbol":"BTC-USD","feed_mode":"by_price","sweep_limit":{"max_levels":null,"max_notional":null}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.clearing_mode, ClearingMode::PerTrade);
+        assert_eq!(config.fees, FeeSchedule::default());
+        assert_eq!(config.trade_through, TradeThroughBand::default());
+        assert_eq!(config.allocation, AllocationPolicy::Fifo);
+        assert_eq!(config.expires_at, None);
+        assert!(!config.has_expired(Utc::now()));
+    }
+
+    #[test]
+    fn test_trade_through_band_limits() {
+        let band = TradeThroughBand {
+            reference_price: Some(Price::from_f64(100.0)),
+            band_bps: 50,
+        };
+        assert_eq!(band.limit(OrderSide::Buy), Some(Price::from_f64(100.5)));
+        assert_eq!(band.limit(OrderSide::Sell), Some(Price::from_f64(99.5)));
+        assert_eq!(TradeThroughBand::default().limit(OrderSide::Buy), None);
+    }
+}
@@ -1,11 +1,25 @@
+pub mod activity;
+pub mod api_key;
+pub mod events;
+pub mod funding;
+pub mod margin;
+pub mod market;
 pub mod order;
 pub mod price;
 pub mod quantity;
+pub mod synthetic;
 pub mod trade;
 pub mod user;
 
+pub use activity::*;
+pub use api_key::*;
+pub use events::*;
+pub use funding::*;
+pub use margin::*;
+pub use market::*;
 pub use order::*;
 pub use price::*;
 pub use quantity::*;
+pub use synthetic::*;
 pub use trade::*;
 pub use user::*;
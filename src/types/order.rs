@@ -3,66 +3,384 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum OrderSide {
     Buy,
     Sell,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum OrderType {
     Limit,
     Market,
 }
 
+/// How long an order may stay on the book
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum TimeInForce {
+    /// Good-til-cancelled: any unfilled remainder rests on the book
+    #[default]
+    GTC,
+    /// Immediate-or-cancel: fill what is possible now, cancel the rest
+    IOC,
+}
+
+impl std::str::FromStr for TimeInForce {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gtc" => Ok(TimeInForce::GTC),
+            "ioc" => Ok(TimeInForce::IOC),
+            _ => Err(format!("Invalid time_in_force '{}', use 'gtc' or 'ioc'", s)),
+        }
+    }
+}
+
+/// Top-of-book price a pegged order follows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PegReference {
+    BestBid,
+    BestAsk,
+    Mid,
+}
+
+impl std::str::FromStr for PegReference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bid" | "best_bid" => Ok(PegReference::BestBid),
+            "ask" | "best_ask" => Ok(PegReference::BestAsk),
+            "mid" | "midpoint" => Ok(PegReference::Mid),
+            _ => Err(format!("Invalid peg '{}', use 'bid', 'ask' or 'mid'", s)),
+        }
+    }
+}
+
+/// How a pegged order is priced: the reference price moved by `offset` raw
+/// price units (negative moves it down), but never through `limit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peg {
+    pub reference: PegReference,
+    pub offset: i64,
+    pub limit: Option<Price>, // Buys never price above it, sells never below
+}
+
+impl Peg {
+    /// Price for an order on `side` given the best bid and ask, or None while
+    /// the reference is missing from the book. A midpoint between two ticks
+    /// rounds away from the other side.
+    pub fn price(
+        &self,
+        side: OrderSide,
+        best_bid: Option<Price>,
+        best_ask: Option<Price>,
+    ) -> Option<Price> {
+        let reference = match self.reference {
+            PegReference::BestBid => best_bid?.raw(),
+            PegReference::BestAsk => best_ask?.raw(),
+            PegReference::Mid => {
+                let sum = best_bid?.raw() + best_ask?.raw();
+                match side {
+                    OrderSide::Buy => sum / 2,
+                    OrderSide::Sell => sum.div_ceil(2),
+                }
+            }
+        };
+        let pegged = Price::new(reference.saturating_add_signed(self.offset).max(1));
+        Some(match (side, self.limit) {
+            (OrderSide::Buy, Some(limit)) => pegged.min(limit),
+            (OrderSide::Sell, Some(limit)) => pegged.max(limit),
+            (_, None) => pegged,
+        })
+    }
+}
+
+/// How far from the best opposite price a market order may trade before the
+/// rest is cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlippageGuard {
+    /// Basis points away from the best price before the order arrived
+    MaxBps(u32),
+    /// Buys never pay above it, sells never receive below
+    LimitPrice(Price),
+}
+
+impl SlippageGuard {
+    /// Worst price an order on `side` may trade at, given the best opposite
+    /// price before it traded. None when that price is needed but missing.
+    pub fn worst_price(&self, side: OrderSide, best: Option<Price>) -> Option<Price> {
+        match *self {
+            SlippageGuard::LimitPrice(limit) => Some(limit),
+            SlippageGuard::MaxBps(bps) => {
+                let best = best?.raw() as u128;
+                let bps = bps as u128;
+                let worst = match side {
+                    OrderSide::Buy => best * (10_000 + bps) / 10_000,
+                    OrderSide::Sell => (best * 10_000u128.saturating_sub(bps)).div_ceil(10_000),
+                };
+                Some(Price::new(worst.min(u64::MAX as u128) as u64))
+            }
+        }
+    }
+}
+
+/// Channel an order entered through, stamped by the gateway (or the engine for
+/// its own orders) and never taken from the request body
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OrderSource {
+    #[default]
+    Web,
+    ApiKey,
+    Fix,
+    Algo,
+    Liquidation,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OrderStatus {
     Open,
     PartiallyFilled,
     Filled,
     Cancelled,
+    /// Reached its `expires_at` while resting; the remainder was cancelled
+    Expired,
+    /// A triggered stop that could not be executed; nothing traded
+    Rejected,
+}
+
+/// Top of book as seen when an order was accepted, kept for best-execution analysis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BboSnapshot {
+    pub best_bid: Option<Price>,
+    pub best_ask: Option<Price>,
+    pub captured_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl BboSnapshot {
+    /// The price a taker on `side` would have crossed against
+    pub fn opposite(&self, side: OrderSide) -> Option<Price> {
+        match side {
+            OrderSide::Buy => self.best_ask,
+            OrderSide::Sell => self.best_bid,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Order {
     pub id: Uuid,
     pub user_id: Uuid,
     pub side: OrderSide,
     pub order_type: OrderType,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
     pub price: Option<Price>, // None for market orders
     pub original_quantity: Quantity,
     pub remaining_quantity: Quantity,
     pub status: OrderStatus,
-    pub timestamp: DateTime<Utc>,
+    pub timestamp: DateTime<Utc>,   // When the engine accepted the order
+    pub received_at: DateTime<Utc>, // When the gateway received the request; drives time priority
+    #[serde(default)]
+    pub arrival_bbo: Option<BboSnapshot>,
+    #[serde(default)]
+    pub client_order_id: Option<String>, // Caller-supplied reference, echoed back as-is
+    #[serde(default)]
+    pub source: OrderSource,
+    #[serde(default)]
+    pub display_quantity: Option<Quantity>, // Iceberg peak size; None shows the whole order
+    #[serde(default)]
+    pub hidden_quantity: Quantity, // Iceberg reserve not yet shown on the book
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>, // Good-till-date: cancelled if still resting then
+    #[serde(default)]
+    pub fill_count: u64, // Executions so far; also the fill sequence number of the latest one
+    #[serde(default)]
+    pub peg: Option<Peg>, // Pegged orders are re-priced by the engine as the BBO moves
+    #[serde(default)]
+    pub hidden: bool, // Never displayed; fills after the displayed orders at its price
+    #[serde(default)]
+    pub min_fill_qty: Option<Quantity>, // Smallest fill it accepts while resting
+    #[serde(default)]
+    pub worst_price: Option<Price>, // Market orders stop sweeping at levels beyond it
+    #[serde(default)]
+    pub priority_fee: f64, // Quote currency paid for queue position under AllocationPolicy::PriorityFee
+    #[serde(default)]
+    pub reject_reason: Option<String>, // Why the engine refused it, when it is Rejected
 }
 
 impl Order {
     pub fn new_limit(user_id: Uuid, side: OrderSide, price: Price, quantity: Quantity) -> Self {
+        let now = Utc::now();
         Order {
             id: Uuid::new_v4(),
             user_id,
             side,
             order_type: OrderType::Limit,
+            time_in_force: TimeInForce::GTC,
             price: Some(price),
             original_quantity: quantity,
             remaining_quantity: quantity,
             status: OrderStatus::Open,
-            timestamp: Utc::now(),
+            timestamp: now,
+            received_at: now,
+            arrival_bbo: None,
+            client_order_id: None,
+            source: OrderSource::default(),
+            display_quantity: None,
+            hidden_quantity: Quantity::new(0),
+            expires_at: None,
+            fill_count: 0,
+            peg: None,
+            hidden: false,
+            min_fill_qty: None,
+            worst_price: None,
+            priority_fee: 0.0,
+            reject_reason: None,
         }
     }
 
     pub fn new_market(user_id: Uuid, side: OrderSide, quantity: Quantity) -> Self {
+        let now = Utc::now();
         Order {
             id: Uuid::new_v4(),
             user_id,
             side,
             order_type: OrderType::Market,
+            time_in_force: TimeInForce::IOC,
             price: None,
             original_quantity: quantity,
             remaining_quantity: quantity,
             status: OrderStatus::Open,
-            timestamp: Utc::now(),
+            timestamp: now,
+            received_at: now,
+            arrival_bbo: None,
+            client_order_id: None,
+            source: OrderSource::default(),
+            display_quantity: None,
+            hidden_quantity: Quantity::new(0),
+            expires_at: None,
+            fill_count: 0,
+            peg: None,
+            hidden: false,
+            min_fill_qty: None,
+            worst_price: None,
+            priority_fee: 0.0,
+            reject_reason: None,
+        }
+    }
+
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Date the order by the engine's clock rather than the wall clock
+    pub fn with_timestamp(mut self, timestamp: DateTime<Utc>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Stamp the order with the time it entered the system at the edge
+    pub fn with_received_at(mut self, received_at: DateTime<Utc>) -> Self {
+        self.received_at = received_at;
+        self
+    }
+
+    pub fn with_time_in_force(mut self, time_in_force: TimeInForce) -> Self {
+        self.time_in_force = time_in_force;
+        self
+    }
+
+    pub fn with_source(mut self, source: OrderSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn with_client_order_id(mut self, client_order_id: Option<String>) -> Self {
+        self.client_order_id = client_order_id;
+        self
+    }
+
+    pub fn with_priority_fee(mut self, priority_fee: f64) -> Self {
+        self.priority_fee = priority_fee;
+        self
+    }
+
+    pub fn with_peg(mut self, peg: Option<Peg>) -> Self {
+        self.peg = peg;
+        self
+    }
+
+    pub fn with_expires_at(mut self, expires_at: Option<DateTime<Utc>>) -> Self {
+        self.expires_at = expires_at;
+        self
+    }
+
+    /// Make this an iceberg that shows at most `display` at a time and keeps the
+    /// rest in reserve. A display size covering the whole order has no effect.
+    pub fn with_display_quantity(mut self, display: Option<Quantity>) -> Self {
+        if let Some(display) = display.filter(|d| *d < self.remaining_quantity) {
+            self.display_quantity = Some(display);
+            self.hidden_quantity = self.remaining_quantity - display;
         }
+        self
+    }
+
+    pub fn with_hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Only trade as a maker against takers able to fill at least `min_fill_qty`,
+    /// or all that remains once less than that is left
+    pub fn with_min_fill_qty(mut self, min_fill_qty: Option<Quantity>) -> Self {
+        self.min_fill_qty = min_fill_qty;
+        self
+    }
+
+    /// Cap how far a market order may sweep: levels priced beyond `worst_price`
+    /// are left alone and the remainder is cancelled
+    pub fn with_worst_price(mut self, worst_price: Option<Price>) -> Self {
+        self.worst_price = worst_price;
+        self
+    }
+
+    /// Whether a taker that can take up to `available` may trade with this order
+    pub fn accepts_fill(&self, available: Quantity) -> bool {
+        self.min_fill_qty
+            .is_none_or(|min| available >= std::cmp::min(min, self.remaining_quantity))
+    }
+
+    /// Quantity available to match against right now. It is also what the book
+    /// shows, unless the order is hidden.
+    pub fn visible_quantity(&self) -> Quantity {
+        self.remaining_quantity - self.hidden_quantity
+    }
+
+    /// Quantity shown in the public book
+    pub fn displayed_quantity(&self) -> Quantity {
+        if self.hidden {
+            Quantity::new(0)
+        } else {
+            self.visible_quantity()
+        }
+    }
+
+    /// Show the next slice of an iceberg whose visible part is used up.
+    /// Returns the newly shown quantity, or None if nothing needed replenishing.
+    pub fn replenish(&mut self) -> Option<Quantity> {
+        let display = self.display_quantity?;
+        if !self.visible_quantity().is_zero() || self.hidden_quantity.is_zero() {
+            return None;
+        }
+        let shown = std::cmp::min(display, self.hidden_quantity);
+        self.hidden_quantity -= shown;
+        Some(shown)
     }
 
     pub fn is_fully_filled(&self) -> bool {
@@ -71,6 +389,9 @@ impl Order {
 
     pub fn fill(&mut self, quantity: Quantity) {
         self.remaining_quantity -= quantity;
+        self.fill_count += 1;
+        // Fills beyond the visible part (only possible as a taker) eat the reserve
+        self.hidden_quantity = std::cmp::min(self.hidden_quantity, self.remaining_quantity);
 
         if self.is_fully_filled() {
             self.status = OrderStatus::Filled;
@@ -79,9 +400,27 @@ impl Order {
         }
     }
 
+    /// Shrink the order by `quantity` without filling it. The iceberg reserve
+    /// goes first, so the displayed slice stays as long as possible.
+    pub fn reduce(&mut self, quantity: Quantity) {
+        let from_reserve = std::cmp::min(quantity, self.hidden_quantity);
+        self.hidden_quantity -= from_reserve;
+        self.remaining_quantity -= quantity;
+        self.original_quantity -= quantity;
+    }
+
     pub fn cancel(&mut self) {
         self.status = OrderStatus::Cancelled;
     }
+
+    pub fn expire(&mut self) {
+        self.status = OrderStatus::Expired;
+    }
+
+    pub fn reject(&mut self, reason: String) {
+        self.status = OrderStatus::Rejected;
+        self.reject_reason = Some(reason);
+    }
 }
 
 #[cfg(test)]
@@ -203,6 +542,39 @@ mod tests {
         assert_eq!(order.remaining_quantity, Quantity::new(6));
     }
 
+    #[test]
+    fn test_iceberg_fill_and_replenish() {
+        let mut order = Order::new_limit(
+            Uuid::new_v4(),
+            OrderSide::Sell,
+            Price::new(10000),
+            Quantity::new(10),
+        )
+        .with_display_quantity(Some(Quantity::new(4)));
+        assert_eq!(order.visible_quantity(), Quantity::new(4));
+        assert_eq!(order.replenish(), None);
+
+        order.fill(Quantity::new(4));
+        assert_eq!(order.visible_quantity(), Quantity::new(0));
+        assert_eq!(order.replenish(), Some(Quantity::new(4)));
+        assert_eq!(order.visible_quantity(), Quantity::new(4));
+
+        // Taking more than is shown draws down the reserve
+        order.fill(Quantity::new(5));
+        assert_eq!(order.remaining_quantity, Quantity::new(1));
+        assert_eq!(order.replenish(), Some(Quantity::new(1)));
+        assert_eq!(order.hidden_quantity, Quantity::new(0));
+
+        let plain = Order::new_limit(
+            Uuid::new_v4(),
+            OrderSide::Buy,
+            Price::new(10000),
+            Quantity::new(3),
+        )
+        .with_display_quantity(Some(Quantity::new(3)));
+        assert_eq!(plain.display_quantity, None);
+    }
+
     #[test]
     fn test_order_side_variants() {
         let user_id = Uuid::new_v4();
@@ -255,4 +627,69 @@ mod tests {
         assert!(order.timestamp >= before);
         assert!(order.timestamp <= after);
     }
+
+    #[test]
+    fn test_peg_price_follows_reference_within_limit() {
+        let (bid, ask) = (Some(Price::new(99_000)), Some(Price::new(100_001)));
+        let peg = |reference, offset, limit| Peg {
+            reference,
+            offset,
+            limit,
+        };
+
+        let bid_plus = peg(PegReference::BestBid, 500, None);
+        assert_eq!(
+            bid_plus.price(OrderSide::Buy, bid, ask),
+            Some(Price::new(99_500))
+        );
+        assert_eq!(bid_plus.price(OrderSide::Buy, None, ask), None);
+
+        // Odd midpoints round away from the opposite side
+        let mid = peg(PegReference::Mid, 0, None);
+        assert_eq!(
+            mid.price(OrderSide::Buy, bid, ask),
+            Some(Price::new(99_500))
+        );
+        assert_eq!(
+            mid.price(OrderSide::Sell, bid, ask),
+            Some(Price::new(99_501))
+        );
+
+        let capped = peg(PegReference::BestAsk, -1, Some(Price::new(99_800)));
+        assert_eq!(
+            capped.price(OrderSide::Buy, bid, ask),
+            Some(Price::new(99_800))
+        );
+        let floored = peg(PegReference::BestBid, -2_000, Some(Price::new(98_000)));
+        assert_eq!(
+            floored.price(OrderSide::Sell, bid, ask),
+            Some(Price::new(98_000))
+        );
+
+        assert_eq!("MID".parse::<PegReference>().unwrap(), PegReference::Mid);
+        assert!("last".parse::<PegReference>().is_err());
+    }
+
+    #[test]
+    fn test_slippage_guard_worst_price() {
+        let best = Some(Price::new(100_000));
+
+        let bps = SlippageGuard::MaxBps(50);
+        assert_eq!(
+            bps.worst_price(OrderSide::Buy, best),
+            Some(Price::new(100_500))
+        );
+        assert_eq!(
+            bps.worst_price(OrderSide::Sell, best),
+            Some(Price::new(99_500))
+        );
+        assert_eq!(bps.worst_price(OrderSide::Buy, None), None);
+
+        // An explicit limit needs no reference price
+        let limit = SlippageGuard::LimitPrice(Price::new(101_000));
+        assert_eq!(
+            limit.worst_price(OrderSide::Buy, None),
+            Some(Price::new(101_000))
+        );
+    }
 }
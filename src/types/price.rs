@@ -1,3 +1,4 @@
+use crate::utils::format::{fixed_from_f64, format_price, parse_fixed, FixedPointError};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 
@@ -5,18 +6,31 @@ use std::cmp::Ordering;
 pub struct Price(u64);
 
 impl Price {
-
+    pub const DECIMALS: u32 = 6;
     const MULTIPLIER: u64 = 1_000_000;
 
     pub fn new(value: u64) -> Self {
         Price(value)
     }
 
+    /// For values already known to be in range. Saturates rather than fails:
+    /// NaN and negatives become zero and huge values the maximum, so anything
+    /// from a request goes through `try_from_f64` instead.
     pub fn from_f64(value: f64) -> Self {
         let fixed_point = (value * Self::MULTIPLIER as f64).round() as u64;
         Price(fixed_point)
     }
 
+    /// Rounds to the nearest unit; NaN, infinite, negative or out of range is an error
+    pub fn try_from_f64(value: f64) -> Result<Self, FixedPointError> {
+        fixed_from_f64(value, Self::DECIMALS).map(Price)
+    }
+
+    /// Exact parse of a decimal string such as "50000.25"
+    pub fn from_decimal_str(s: &str) -> Result<Self, FixedPointError> {
+        parse_fixed(s, Self::DECIMALS).map(Price)
+    }
+
     pub fn to_f64(&self) -> f64 {
         self.0 as f64 / Self::MULTIPLIER as f64
     }
@@ -40,7 +54,15 @@ impl Ord for Price {
 
 impl std::fmt::Display for Price {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:.6}", self.to_f64())
+        f.write_str(&format_price(*self, false))
+    }
+}
+
+impl std::str::FromStr for Price {
+    type Err = FixedPointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_decimal_str(s)
     }
 }
 
@@ -60,4 +82,20 @@ mod tests {
         let p2 = Price::from_f64(200.0);
         assert!(p1 < p2);
     }
+
+    #[test]
+    fn test_checked_conversions() {
+        assert_eq!(Price::try_from_f64(123.456789), Ok(Price::new(123_456_789)));
+        assert_eq!(Price::try_from_f64(-1.0), Err(FixedPointError::Negative));
+        assert_eq!(
+            Price::from_decimal_str("1,000.5"),
+            Ok(Price::new(1_000_500_000))
+        );
+        assert_eq!(
+            Price::from_decimal_str("1.0000001"),
+            Err(FixedPointError::TooPrecise { decimals: 6 })
+        );
+        // The unchecked conversion clamps instead
+        assert_eq!(Price::from_f64(-1.0), Price::new(0));
+    }
 }
@@ -1,22 +1,36 @@
+use crate::utils::format::{fixed_from_f64, format_quantity, parse_fixed, FixedPointError};
 use serde::{Deserialize, Serialize};
 use std::ops::{Add, AddAssign, Sub, SubAssign};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Quantity(u64);
 
 impl Quantity {
-    // const DECIMAL_PLACES: u32 = 8;
+    pub const DECIMALS: u32 = 8;
     const MULTIPLIER: u64 = 100_000_000; // 10^8
 
     pub fn new(value: u64) -> Self {
         Quantity(value)
     }
 
+    /// For values already known to be in range. Saturates rather than fails:
+    /// NaN and negatives become zero and huge values the maximum, so anything
+    /// from a request goes through `try_from_f64` instead.
     pub fn from_f64(value: f64) -> Self {
         let fixed_point = (value * Self::MULTIPLIER as f64).round() as u64;
         Quantity(fixed_point)
     }
 
+    /// Rounds to the nearest unit; NaN, infinite, negative or out of range is an error
+    pub fn try_from_f64(value: f64) -> Result<Self, FixedPointError> {
+        fixed_from_f64(value, Self::DECIMALS).map(Quantity)
+    }
+
+    /// Exact parse of a decimal string such as "50000.25"
+    pub fn from_decimal_str(s: &str) -> Result<Self, FixedPointError> {
+        parse_fixed(s, Self::DECIMALS).map(Quantity)
+    }
+
     pub fn to_f64(&self) -> f64 {
         self.0 as f64 / Self::MULTIPLIER as f64
     }
@@ -64,7 +78,15 @@ impl SubAssign for Quantity {
 
 impl std::fmt::Display for Quantity {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:.8}", self.to_f64())
+        f.write_str(&format_quantity(*self, false))
+    }
+}
+
+impl std::str::FromStr for Quantity {
+    type Err = FixedPointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_decimal_str(s)
     }
 }
 
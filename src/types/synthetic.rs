@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::Price;
+
+/// One underlying market of a synthetic index, and how many units of it the
+/// basket holds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexComponent {
+    pub market: String,
+    pub weight: f64,
+}
+
+/// A weighted basket of underlying markets whose value is published like a
+/// price. It cannot be traded, but stop orders can trigger on it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyntheticIndex {
+    pub symbol: String,
+    pub components: Vec<IndexComponent>,
+    pub divisor: f64, // The weighted sum is divided by this, to set the index's scale
+}
+
+impl SyntheticIndex {
+    /// Validate and build a definition
+    pub fn new(
+        symbol: String,
+        components: Vec<IndexComponent>,
+        divisor: f64,
+    ) -> Result<Self, String> {
+        if symbol.trim().is_empty() {
+            return Err("Index symbol must not be empty".to_string());
+        }
+        if components.is_empty() {
+            return Err("At least one component is required".to_string());
+        }
+        for (i, component) in components.iter().enumerate() {
+            if !(component.weight.is_finite() && component.weight > 0.0) {
+                return Err(format!("Component {}: weight must be positive", i));
+            }
+            if components[..i]
+                .iter()
+                .any(|earlier| earlier.market == component.market)
+            {
+                return Err(format!(
+                    "Component {}: {} is listed twice",
+                    i, component.market
+                ));
+            }
+        }
+        if !(divisor.is_finite() && divisor > 0.0) {
+            return Err("divisor must be positive".to_string());
+        }
+        Ok(SyntheticIndex {
+            symbol,
+            components,
+            divisor,
+        })
+    }
+
+    /// The weighted sum of the component prices over the divisor. None until
+    /// every component has a price; an error if the result is not a price
+    /// that can be represented, rather than one saturated to fit.
+    pub fn value(&self, price_of: impl Fn(&str) -> Option<Price>) -> Result<Option<Price>, String> {
+        let mut sum = 0.0;
+        for component in &self.components {
+            let Some(price) = price_of(&component.market) else {
+                return Ok(None);
+            };
+            sum += component.weight * price.to_f64();
+        }
+        Price::try_from_f64(sum / self.divisor)
+            .map(Some)
+            .map_err(|e| format!("Index {} value is {}", self.symbol, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component(market: &str, weight: f64) -> IndexComponent {
+        IndexComponent {
+            market: market.to_string(),
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_value_is_the_weighted_sum_over_the_divisor() {
+        let components = vec![component("BTC-USD", 2.0), component("ETH-USD", 10.0)];
+        let index = SyntheticIndex::new("CRYPTO2".to_string(), components, 4.0).unwrap();
+        let price_of = |market: &str| match market {
+            "BTC-USD" => Some(Price::from_f64(100.0)),
+            "ETH-USD" => Some(Price::from_f64(8.0)),
+            _ => None,
+        };
+        assert_eq!(index.value(price_of), Ok(Some(Price::from_f64(70.0))));
+        // A component that has not traded leaves the index without a value
+        assert_eq!(index.value(|_| None), Ok(None));
+
+        // Past the price range is an error, not the largest price
+        let huge = SyntheticIndex::new("HUGE".to_string(), vec![component("BTC-USD", 1e300)], 1.0);
+        let result = huge.unwrap().value(|_| Some(Price::from_f64(100.0)));
+        assert_eq!(result, Err("Index HUGE value is too large".to_string()));
+
+        let invalid = [
+            (vec![], 1.0),
+            (vec![component("BTC-USD", 0.0)], 1.0),
+            (
+                vec![component("BTC-USD", 1.0), component("BTC-USD", 2.0)],
+                1.0,
+            ),
+            (vec![component("BTC-USD", 1.0)], 0.0),
+            (vec![component("BTC-USD", f64::INFINITY)], 1.0),
+            (vec![component("BTC-USD", 1.0)], f64::NAN),
+        ];
+        for (components, divisor) in invalid {
+            assert!(SyntheticIndex::new("X".to_string(), components, divisor).is_err());
+        }
+    }
+}
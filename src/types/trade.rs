@@ -1,8 +1,25 @@
-use super::{Price, Quantity};
+use super::{OrderSide, OrderSource, Price, Quantity};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
+/// How much better (positive) or worse (negative) than its reference price the
+/// taker was filled, in quote currency for the whole fill
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PriceImprovement {
+    pub vs_limit: Option<f64>,       // None for market orders
+    pub vs_arrival_bbo: Option<f64>, // None if the opposite side was empty on arrival
+}
+
+/// Which side of a trade an order was on: resting (maker) or crossing the spread (taker)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LiquidityRole {
+    Maker,
+    Taker,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Trade {
     pub id: Uuid,
@@ -13,6 +30,53 @@ pub struct Trade {
     pub price: Price,
     pub quantity: Quantity,
     pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub price_improvement: PriceImprovement,
+    #[serde(default)]
+    pub maker_source: OrderSource,
+    #[serde(default)]
+    pub taker_source: OrderSource,
+    #[serde(default)]
+    pub maker_fill_seq: u64, // This trade's place among the maker order's fills, from 1
+    #[serde(default)]
+    pub taker_fill_seq: u64, // This trade's place among the taker order's fills, from 1
+    #[serde(default = "default_taker_side")]
+    pub taker_side: OrderSide, // Liquidity flag: the side that crossed the spread
+    #[serde(default = "default_buyer_role")]
+    pub buyer_role: LiquidityRole,
+    #[serde(default = "default_seller_role")]
+    pub seller_role: LiquidityRole,
+    #[serde(default)]
+    pub maker_fee: f64, // Quote currency, charged to the maker
+    #[serde(default)]
+    pub taker_fee: f64, // Quote currency, charged to the taker
+}
+
+/// One user's part in a trade, as listed in their trade history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserTrade {
+    pub trade_id: Uuid,
+    pub order_id: Uuid,
+    pub side: OrderSide,
+    pub role: LiquidityRole,
+    #[serde(with = "crate::utils::decimal")]
+    pub price: Price,
+    #[serde(with = "crate::utils::decimal")]
+    pub quantity: Quantity,
+    pub fee: f64, // Quote currency
+    pub timestamp: DateTime<Utc>,
+}
+
+fn default_taker_side() -> OrderSide {
+    OrderSide::Buy
+}
+
+fn default_buyer_role() -> LiquidityRole {
+    LiquidityRole::Taker
+}
+
+fn default_seller_role() -> LiquidityRole {
+    LiquidityRole::Maker
 }
 
 impl Trade {
@@ -33,8 +97,97 @@ impl Trade {
             price,
             quantity,
             timestamp: Utc::now(),
+            price_improvement: PriceImprovement::default(),
+            maker_source: OrderSource::default(),
+            taker_source: OrderSource::default(),
+            maker_fill_seq: 0,
+            taker_fill_seq: 0,
+            taker_side: default_taker_side(),
+            buyer_role: default_buyer_role(),
+            seller_role: default_seller_role(),
+            maker_fee: 0.0,
+            taker_fee: 0.0,
         }
     }
+
+    /// Record which channels the two orders came in through
+    pub fn with_sources(mut self, maker_source: OrderSource, taker_source: OrderSource) -> Self {
+        self.maker_source = maker_source;
+        self.taker_source = taker_source;
+        self
+    }
+
+    /// Record which side crossed the spread, and so each counterparty's role
+    pub fn with_taker_side(mut self, taker_side: OrderSide) -> Self {
+        let (buyer_role, seller_role) = match taker_side {
+            OrderSide::Buy => (LiquidityRole::Taker, LiquidityRole::Maker),
+            OrderSide::Sell => (LiquidityRole::Maker, LiquidityRole::Taker),
+        };
+        self.taker_side = taker_side;
+        self.buyer_role = buyer_role;
+        self.seller_role = seller_role;
+        self
+    }
+
+    /// The fills `user_id` had in this trade: none if they were not part of
+    /// it, both if they traded against themselves
+    pub fn fills_of(&self, user_id: Uuid) -> Vec<UserTrade> {
+        let maker_side = match self.taker_side {
+            OrderSide::Buy => OrderSide::Sell,
+            OrderSide::Sell => OrderSide::Buy,
+        };
+        let fill = |order_id, side, role, fee| UserTrade {
+            trade_id: self.id,
+            order_id,
+            side,
+            role,
+            price: self.price,
+            quantity: self.quantity,
+            fee,
+            timestamp: self.timestamp,
+        };
+
+        let mut fills = Vec::new();
+        if self.maker_user_id == user_id {
+            fills.push(fill(
+                self.maker_order_id,
+                maker_side,
+                LiquidityRole::Maker,
+                self.maker_fee,
+            ));
+        }
+        if self.taker_user_id == user_id {
+            fills.push(fill(
+                self.taker_order_id,
+                self.taker_side,
+                LiquidityRole::Taker,
+                self.taker_fee,
+            ));
+        }
+        fills
+    }
+
+    /// Record the per-order fill sequence numbers this execution was given
+    pub fn with_fill_seqs(mut self, maker_fill_seq: u64, taker_fill_seq: u64) -> Self {
+        self.maker_fill_seq = maker_fill_seq;
+        self.taker_fill_seq = taker_fill_seq;
+        self
+    }
+
+    /// Date the trade `at` and name it after its taker order and that order's
+    /// fill number. Order IDs come from the command log, so a replayed command
+    /// makes the same trades under the same IDs and times.
+    pub fn stamped(mut self, at: DateTime<Utc>) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(self.taker_order_id.as_bytes());
+        hasher.update(self.taker_fill_seq.to_be_bytes());
+        let digest = hasher.finalize();
+        let mut bytes = [0; 16];
+        bytes.copy_from_slice(&digest[..16]);
+        self.id = uuid::Builder::from_random_bytes(bytes).into_uuid();
+        self.timestamp = at;
+        self
+    }
 }
 
 #[cfg(test)]
@@ -94,4 +247,53 @@ mod tests {
 
         assert_ne!(trade1.id, trade2.id);
     }
+
+    #[test]
+    fn test_fills_of_each_participant() {
+        let (maker, taker) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            maker,
+            taker,
+            Price::new(5000),
+            Quantity::new(20),
+        )
+        .with_taker_side(OrderSide::Sell);
+        trade.maker_fee = 0.1;
+        trade.taker_fee = 0.2;
+
+        let maker_fills = trade.fills_of(maker);
+        assert_eq!(maker_fills.len(), 1);
+        assert_eq!(maker_fills[0].order_id, trade.maker_order_id);
+        assert_eq!(maker_fills[0].side, OrderSide::Buy);
+        assert_eq!(maker_fills[0].role, LiquidityRole::Maker);
+        assert_eq!(maker_fills[0].fee, 0.1);
+
+        let taker_fills = trade.fills_of(taker);
+        assert_eq!(taker_fills[0].side, OrderSide::Sell);
+        assert_eq!(taker_fills[0].role, LiquidityRole::Taker);
+        assert_eq!(taker_fills[0].fee, 0.2);
+
+        assert!(trade.fills_of(Uuid::new_v4()).is_empty());
+        trade.taker_user_id = maker;
+        assert_eq!(trade.fills_of(maker).len(), 2);
+    }
+
+    #[test]
+    fn test_fills_carry_price_and_quantity_as_decimal_strings() {
+        let trade = Trade::new(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Price::new(50_000_250_000),
+            Quantity::new(12_345_678),
+        );
+        let fill = trade.fills_of(trade.maker_user_id).remove(0);
+        let json = serde_json::to_value(&fill).unwrap();
+        assert_eq!(json["price"], "50000.25");
+        assert_eq!(json["quantity"], "0.12345678");
+        assert_eq!(serde_json::from_value::<UserTrade>(json).unwrap(), fill);
+    }
 }
@@ -2,12 +2,31 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use uuid::Uuid;
 
+use crate::types::FundingSource;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: Uuid,
     pub username: String,
     pub email: String,
     pub password_hash: String,
+    #[serde(default)]
+    pub display_currency: Option<String>, // Preferred fiat for market data, None = USD
+    #[serde(default)]
+    pub external_identities: Vec<ExternalIdentity>, // Linked OIDC logins
+    #[serde(default)]
+    pub funding_sources: Vec<FundingSource>, // Linked banks and wallets
+    #[serde(skip)]
+    pub revision: u64, // Saves so far, as read from the repository
+}
+
+/// An account at an outside identity provider that can sign in as a user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExternalIdentity {
+    pub provider: String, // e.g. "google", "github"
+    pub subject: String,  // The provider's stable user id
+    pub email: Option<String>,
+    pub email_verified: bool,
 }
 
 impl User {
@@ -17,14 +36,26 @@ impl User {
             username,
             email,
             password_hash,
+            display_currency: None,
+            external_identities: Vec::new(),
+            funding_sources: Vec::new(),
+            revision: 0,
         }
     }
+
+    /// Whether password sign-in is possible; accounts created through an
+    /// identity provider have no password
+    pub fn has_password(&self) -> bool {
+        !self.password_hash.is_empty()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserBalance {
     pub user_id: Uuid,
-    pub balances: HashMap<String, f64>,
+    pub balances: HashMap<String, f64>, // Available to trade or withdraw
+    #[serde(default)]
+    pub reserved: HashMap<String, f64>, // Set aside for open orders
 }
 
 impl UserBalance {
@@ -33,7 +64,11 @@ impl UserBalance {
         balances.insert("USD".to_string(), 0.0);
         balances.insert("BTC".to_string(), 0.0);
 
-        UserBalance { user_id, balances }
+        UserBalance {
+            user_id,
+            balances,
+            reserved: HashMap::new(),
+        }
     }
 
     pub fn add_balance(&mut self, currency: &str, amount: f64) {
@@ -57,6 +92,18 @@ impl UserBalance {
     pub fn get_balance(&self, currency: &str) -> f64 {
         *self.balances.get(currency).unwrap_or(&0.0)
     }
+
+    /// Move `amount` out of what is available into what is set aside for an
+    /// order. Settlement pays out of it; cancelling releases it.
+    pub fn reserve(&mut self, currency: &str, amount: f64) -> Result<(), String> {
+        self.subtract_balance(currency, amount)?;
+        *self.reserved.entry(currency.to_string()).or_insert(0.0) += amount;
+        Ok(())
+    }
+
+    pub fn get_reserved(&self, currency: &str) -> f64 {
+        *self.reserved.get(currency).unwrap_or(&0.0)
+    }
 }
 
 #[cfg(test)]
@@ -1,8 +1,24 @@
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, EncodePrivateKey};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+    OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
 use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
 use uuid::Uuid;
 
-const JWT_SECRET: &[u8] = b"your-secret-key-change-in-production";
+use crate::utils::secrets::{parse_keyring, secrets, SecretChain};
+use aes_gcm::aead::OsRng;
+
 const TOKEN_EXPIRATION_HOURS: i64 = 24;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -12,6 +28,154 @@ pub struct Claims {
     pub exp: usize,       // Expiration time
 }
 
+/// What a request authenticated with, kept in the request extensions so a
+/// connection that outlives the request can tell when it no longer holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credential {
+    Session { expires_at: DateTime<Utc> }, // A session token, good until it expires
+    ApiKey { key_id: String },             // A signed API key request, good until revoked
+}
+
+impl Claims {
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.exp as i64, 0).unwrap_or_default()
+    }
+}
+
+/// Ed25519 keys user tokens are signed and verified with. Only this server
+/// holds the private key; other services verify tokens against the public
+/// keys served as a JWKS. New tokens carry the current key's id, and tokens
+/// signed with a retired key keep verifying until they expire, so rotating
+/// the key logs nobody out.
+pub struct JwtKeys {
+    current_id: String,
+    signing: EncodingKey,
+    verifying: Vec<(String, VerifyingKey)>, // Current key first
+}
+
+impl JwtKeys {
+    pub fn new(key_id: impl Into<String>, signing_key: &SigningKey) -> Result<Self, String> {
+        let der = signing_key
+            .to_pkcs8_der()
+            .map_err(|e| format!("Failed to encode JWT signing key: {}", e))?;
+        let current_id = key_id.into();
+        Ok(JwtKeys {
+            signing: EncodingKey::from_ed_der(der.as_bytes()),
+            verifying: vec![(current_id.clone(), signing_key.verifying_key())],
+            current_id,
+        })
+    }
+
+    /// A fresh random key, for servers with no configured key. Its tokens stop
+    /// verifying when the process exits.
+    pub fn generate(key_id: impl Into<String>) -> Self {
+        Self::new(key_id, &SigningKey::generate(&mut OsRng))
+            .expect("a generated key always encodes")
+    }
+
+    /// Accept tokens signed with a retired key
+    pub fn with_previous(mut self, key_id: impl Into<String>, public_key: VerifyingKey) -> Self {
+        let key_id = key_id.into();
+        if !self.verifying.iter().any(|(id, _)| *id == key_id) {
+            self.verifying.push((key_id, public_key));
+        }
+        self
+    }
+
+    /// PKCS#8 PEM private key in `JWT_PRIVATE_KEY` with id `JWT_KEY_ID`, plus
+    /// retired public keys as `id=base64url` lines in `JWT_PREVIOUS_KEYS`.
+    /// Without a private key a temporary one is generated.
+    pub fn from_secrets(secrets: &SecretChain) -> Result<Self, String> {
+        let key_id = secrets
+            .text("JWT_KEY_ID")
+            .unwrap_or_else(|| "default".to_string());
+        let keys = match secrets.text("JWT_PRIVATE_KEY") {
+            Some(pem) => {
+                let signing_key = SigningKey::from_pkcs8_pem(&pem)
+                    .map_err(|e| format!("Invalid JWT_PRIVATE_KEY: {}", e))?;
+                Self::new(key_id, &signing_key)?
+            }
+            None => Self::generate(format!("temporary-{}", Uuid::new_v4().simple())),
+        };
+
+        let previous = secrets.text("JWT_PREVIOUS_KEYS").unwrap_or_default();
+        parse_keyring(&previous)
+            .into_iter()
+            .try_fold(keys, |keys, (id, public_key)| {
+                let public_key = decode_public_key(&public_key)
+                    .map_err(|e| format!("Invalid JWT_PREVIOUS_KEYS entry '{}': {}", id, e))?;
+                Ok(keys.with_previous(id, public_key))
+            })
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.current_id
+    }
+
+    /// Sign `claims` with the current key
+    pub fn sign(&self, claims: &Claims) -> Result<String, String> {
+        let header = Header {
+            kid: Some(self.current_id.clone()),
+            ..Header::new(Algorithm::EdDSA)
+        };
+        encode(&header, claims, &self.signing)
+            .map_err(|e| format!("Failed to generate token: {}", e))
+    }
+
+    /// Verify with the public key the token names
+    pub fn verify(&self, token: &str) -> Result<Claims, String> {
+        let header = decode_header(token).map_err(|e| format!("Invalid token: {}", e))?;
+        let kid = header
+            .kid
+            .ok_or_else(|| "Invalid token: no key id".to_string())?;
+        let public_key = self
+            .verifying
+            .iter()
+            .find(|(id, _)| *id == kid)
+            .map(|(_, key)| key)
+            .ok_or_else(|| format!("Invalid token: unknown key '{}'", kid))?;
+        decode::<Claims>(
+            token,
+            &DecodingKey::from_ed_der(public_key.as_bytes()),
+            &Validation::new(Algorithm::EdDSA),
+        )
+        .map(|data| data.claims)
+        .map_err(|e| format!("Invalid token: {}", e))
+    }
+
+    /// Public keys of the current and retired keys, as a JSON Web Key Set
+    pub fn jwks(&self) -> JwkSet {
+        let keys = self
+            .verifying
+            .iter()
+            .map(|(id, public_key)| Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    key_algorithm: Some(KeyAlgorithm::EdDSA),
+                    key_id: Some(id.clone()),
+                    ..CommonParameters::default()
+                },
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(public_key.as_bytes()),
+                }),
+            })
+            .collect();
+        JwkSet { keys }
+    }
+}
+
+/// Ed25519 public key from its unpadded base64url form, as in a JWK's `x`
+fn decode_public_key(encoded: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| "expected 32 bytes".to_string())?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string())
+}
+
 /// Generate JWT token for a user
 pub fn generate_token(user_id: Uuid, username: String) -> Result<String, String> {
     let expiration = chrono::Utc::now()
@@ -25,23 +189,67 @@ pub fn generate_token(user_id: Uuid, username: String) -> Result<String, String>
         exp: expiration,
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(JWT_SECRET),
-    )
-    .map_err(|e| format!("Failed to generate token: {}", e))
+    jwt_keys().sign(&claims)
 }
 
 /// Validate JWT token and extract claims
 pub fn validate_token(token: &str) -> Result<Claims, String> {
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(JWT_SECRET),
-        &Validation::default(),
-    )
-    .map(|data| data.claims)
-    .map_err(|e| format!("Invalid token: {}", e))
+    jwt_keys().verify(token)
+}
+
+static JWT_KEYS: OnceLock<JwtKeys> = OnceLock::new();
+
+/// Load the signing keys through the secret providers, so a malformed key
+/// stops the server at startup instead of at the first sign-in
+pub fn init_jwt_keys() -> Result<&'static JwtKeys, String> {
+    let keys = JwtKeys::from_secrets(secrets())?;
+    Ok(JWT_KEYS.get_or_init(|| keys))
+}
+
+/// Process-wide signing keys
+pub fn jwt_keys() -> &'static JwtKeys {
+    JWT_KEYS.get_or_init(|| {
+        JwtKeys::from_secrets(secrets()).expect("JWT keys are checked by init_jwt_keys")
+    })
+}
+
+/// Static bearer token for operator/admin routes, from `ADMIN_TOKEN`. None
+/// when it is not set, and the admin routes are then closed.
+pub fn admin_token() -> Option<&'static str> {
+    static TOKEN: OnceLock<Option<String>> = OnceLock::new();
+    TOKEN
+        .get_or_init(|| {
+            secrets()
+                .text("ADMIN_TOKEN")
+                .filter(|token| !token.is_empty())
+        })
+        .as_deref()
+}
+
+/// Check an offered admin token; nothing passes while none is configured
+pub fn verify_admin_token(offered: &str) -> bool {
+    admin_token().is_some_and(|expected| tokens_match(expected, offered))
+}
+
+/// Compare tokens without short-circuiting on the first mismatch
+fn tokens_match(expected: &str, offered: &str) -> bool {
+    let expected = expected.as_bytes();
+    let offered = offered.as_bytes();
+    offered.len() == expected.len()
+        && offered
+            .iter()
+            .zip(expected)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Best-effort user lookup for public routes: returns the user id if the request
+/// carries a valid bearer token, without rejecting anonymous requests
+pub fn user_id_from_request(req: &HttpRequest) -> Option<Uuid> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let token = header.strip_prefix("Bearer ")?;
+    let claims = validate_token(token).ok()?;
+    Uuid::parse_str(&claims.sub).ok()
 }
 
 /// Hash password using bcrypt
@@ -67,6 +275,19 @@ mod tests {
         assert!(!verify_password("wrong_password", &hash).unwrap());
     }
 
+    #[test]
+    fn test_admin_token_verification() {
+        assert!(tokens_match("admin-token", "admin-token"));
+        assert!(!tokens_match("admin-token", "admin-tokem"));
+        assert!(!tokens_match("admin-token", "wrong"));
+        assert!(!tokens_match("admin-token", ""));
+        // Without a configured token, not even a former default gets in
+        if admin_token().is_none() {
+            assert!(!verify_admin_token("your-admin-token-change-in-production"));
+            assert!(!verify_admin_token(""));
+        }
+    }
+
     #[test]
     fn test_token_generation() {
         let user_id = Uuid::new_v4();
@@ -75,4 +296,62 @@ mod tests {
         assert_eq!(claims.sub, user_id.to_string());
         assert_eq!(claims.username, "testuser");
     }
+
+    #[test]
+    fn test_rotated_keys_still_verify_old_tokens() {
+        let claims = Claims {
+            sub: Uuid::new_v4().to_string(),
+            username: "rotating".to_string(),
+            exp: (chrono::Utc::now().timestamp() + 60) as usize,
+        };
+        let old_key = SigningKey::generate(&mut OsRng);
+        let old = JwtKeys::new("k1", &old_key).unwrap();
+        let old_token = old.sign(&claims).unwrap();
+
+        let new_key = SigningKey::generate(&mut OsRng);
+        let rotated = JwtKeys::new("k2", &new_key)
+            .unwrap()
+            .with_previous("k1", old_key.verifying_key());
+        assert_eq!(rotated.verify(&old_token).unwrap().sub, claims.sub);
+        let new_token = rotated.sign(&claims).unwrap();
+        assert!(rotated.verify(&new_token).is_ok());
+
+        // Once the old key is dropped its tokens stop working, and an old
+        // server never accepts a token signed with a key it has not seen
+        let dropped = JwtKeys::new("k2", &new_key).unwrap();
+        assert!(dropped.verify(&old_token).is_err());
+        assert!(old.verify(&new_token).is_err());
+    }
+
+    #[test]
+    fn test_jwks_verifies_tokens_without_the_private_key() {
+        let keys = JwtKeys::generate("k1");
+        let claims = Claims {
+            sub: Uuid::new_v4().to_string(),
+            username: "jwks".to_string(),
+            exp: (chrono::Utc::now().timestamp() + 60) as usize,
+        };
+        let token = keys.sign(&claims).unwrap();
+
+        // What another service does with the published key set
+        let jwks: JwkSet =
+            serde_json::from_value(serde_json::to_value(keys.jwks()).unwrap()).unwrap();
+        let kid = decode_header(&token).unwrap().kid.unwrap();
+        let jwk = jwks.find(&kid).unwrap();
+        let decoded = decode::<Claims>(
+            &token,
+            &DecodingKey::from_jwk(jwk).unwrap(),
+            &Validation::new(Algorithm::EdDSA),
+        )
+        .unwrap();
+        assert_eq!(decoded.claims.sub, claims.sub);
+
+        let encoded = match &jwk.algorithm {
+            AlgorithmParameters::OctetKeyPair(params) => params.x.clone(),
+            _ => panic!("expected an Ed25519 key"),
+        };
+        let public_key = decode_public_key(&encoded).unwrap();
+        let verifier = JwtKeys::generate("k2").with_previous("k1", public_key);
+        assert!(verifier.verify(&token).is_ok());
+    }
 }
@@ -11,8 +11,11 @@ pub struct ErrorResponse {
 pub enum ApiError {
     BadRequest(String),
     Unauthorized(String),
+    Forbidden(String),
     NotFound(String),
     InternalError(String),
+    Timeout(String),
+    TooManyRequests(String),
 }
 
 impl fmt::Display for ApiError {
@@ -20,21 +23,36 @@ impl fmt::Display for ApiError {
         match self {
             ApiError::BadRequest(msg) => write!(f, "Bad Request: {}", msg),
             ApiError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            ApiError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
             ApiError::NotFound(msg) => write!(f, "Not Found: {}", msg),
             ApiError::InternalError(msg) => write!(f, "Internal Error: {}", msg),
+            ApiError::Timeout(msg) => write!(f, "Timeout: {}", msg),
+            ApiError::TooManyRequests(msg) => write!(f, "Too Many Requests: {}", msg),
+        }
+    }
+}
+
+impl ApiError {
+    /// Status and message as an HTTP error response carries them
+    pub fn status_and_message(&self) -> (StatusCode, &str) {
+        match self {
+            ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            ApiError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+            ApiError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
+            ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            ApiError::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+            ApiError::Timeout(msg) => (StatusCode::GATEWAY_TIMEOUT, msg),
+            ApiError::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
         }
     }
 }
 
 impl ResponseError for ApiError {
     fn error_response(&self) -> HttpResponse {
-        let (status, message) = match self {
-            ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.clone()),
-            ApiError::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg.clone()),
-            ApiError::NotFound(msg) => (StatusCode::NOT_FOUND, msg.clone()),
-            ApiError::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.clone()),
-        };
+        let (status, message) = self.status_and_message();
 
-        HttpResponse::build(status).json(ErrorResponse { error: message })
+        HttpResponse::build(status).json(ErrorResponse {
+            error: message.to_string(),
+        })
     }
 }
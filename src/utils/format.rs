@@ -0,0 +1,400 @@
+use crate::types::{Price, Quantity};
+
+/// How many decimals an asset is conventionally shown with
+pub fn asset_decimals(asset: &str) -> u32 {
+    match asset {
+        "JPY" => 0,
+        "USD" | "EUR" | "GBP" | "INR" => 2,
+        "BTC" => Quantity::DECIMALS,
+        _ => 8,
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Always show at least this many decimals; further trailing zeros are trimmed
+    pub min_decimals: u32,
+    /// Group the integer part in thousands with ','
+    pub thousands_separator: bool,
+}
+
+/// Render a fixed-point integer with `scale` implied decimals.
+/// Never goes through f64, so every representable value prints exactly.
+pub fn format_fixed(raw: u64, scale: u32, options: FormatOptions) -> String {
+    let divisor = 10u64.pow(scale);
+    let integer = (raw / divisor).to_string();
+    let fraction = format!("{:0width$}", raw % divisor, width = scale as usize);
+
+    let mut out = String::with_capacity(integer.len() * 4 / 3 + fraction.len() + 1);
+    if options.thousands_separator {
+        for (i, digit) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i).is_multiple_of(3) {
+                out.push(',');
+            }
+            out.push(digit);
+        }
+    } else {
+        out.push_str(&integer);
+    }
+
+    let keep = options.min_decimals.min(scale) as usize;
+    let fraction = fraction.trim_end_matches('0');
+    let fraction = if fraction.len() < keep {
+        &format!("{:0<keep$}", fraction)
+    } else {
+        fraction
+    };
+    if !fraction.is_empty() {
+        out.push('.');
+        out.push_str(fraction);
+    }
+    out
+}
+
+/// Why a number cannot become a fixed-point price or quantity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixedPointError {
+    NotANumber, // Empty, NaN, infinite, or not plain decimal digits
+    Negative,
+    TooLarge, // Past the u64 range once scaled
+    TooPrecise { decimals: u32 },
+}
+
+impl std::fmt::Display for FixedPointError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FixedPointError::NotANumber => f.write_str("not a number"),
+            FixedPointError::Negative => f.write_str("negative"),
+            FixedPointError::TooLarge => f.write_str("too large"),
+            FixedPointError::TooPrecise { decimals } => {
+                write!(f, "more precise than {} decimal places", decimals)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FixedPointError {}
+
+/// Parse a decimal string back into a fixed-point integer with `scale` decimals.
+/// Accepts ',' thousands separators; rejects anything that would lose precision.
+pub fn parse_fixed(input: &str, scale: u32) -> Result<u64, FixedPointError> {
+    let cleaned: String = input.trim().chars().filter(|c| *c != ',').collect();
+    if let Some(magnitude) = cleaned.strip_prefix('-') {
+        return Err(match parse_fixed(magnitude, scale) {
+            Err(FixedPointError::NotANumber) => FixedPointError::NotANumber,
+            _ => FixedPointError::Negative,
+        });
+    }
+    let (integer, fraction) = cleaned.split_once('.').unwrap_or((&cleaned, ""));
+
+    if integer.is_empty() && fraction.is_empty() {
+        return Err(FixedPointError::NotANumber);
+    }
+    if !integer
+        .chars()
+        .chain(fraction.chars())
+        .all(|c| c.is_ascii_digit())
+    {
+        return Err(FixedPointError::NotANumber);
+    }
+    if fraction.len() > scale as usize {
+        return Err(FixedPointError::TooPrecise { decimals: scale });
+    }
+
+    let integer: u64 = if integer.is_empty() {
+        0
+    } else {
+        integer.parse().map_err(|_| FixedPointError::TooLarge)?
+    };
+    let fraction: u64 = format!("{:0<width$}", fraction, width = scale as usize)
+        .parse()
+        .unwrap_or(0);
+
+    integer
+        .checked_mul(10u64.pow(scale))
+        .and_then(|v| v.checked_add(fraction))
+        .ok_or(FixedPointError::TooLarge)
+}
+
+/// Scale a float into a fixed-point integer with `scale` decimals, rounding
+/// to the nearest unit. Unlike an `as` cast, never clamps: NaN, infinities,
+/// negatives and values past the u64 range are errors.
+pub fn fixed_from_f64(value: f64, scale: u32) -> Result<u64, FixedPointError> {
+    if !value.is_finite() {
+        return Err(FixedPointError::NotANumber);
+    }
+    if value < 0.0 {
+        return Err(FixedPointError::Negative);
+    }
+    let scaled = (value * 10f64.powi(scale as i32)).round();
+    // u64::MAX as f64 rounds up to 2^64, which itself does not fit
+    if scaled >= u64::MAX as f64 {
+        return Err(FixedPointError::TooLarge);
+    }
+    Ok(scaled as u64)
+}
+
+/// Quote-currency rendering of a price
+pub fn format_price(price: Price, thousands_separator: bool) -> String {
+    format_fixed(
+        price.raw(),
+        Price::DECIMALS,
+        FormatOptions {
+            min_decimals: asset_decimals("USD"),
+            thousands_separator,
+        },
+    )
+}
+
+/// Base-asset rendering of a quantity
+pub fn format_quantity(quantity: Quantity, thousands_separator: bool) -> String {
+    format_fixed(
+        quantity.raw(),
+        Quantity::DECIMALS,
+        FormatOptions {
+            min_decimals: asset_decimals("BTC"),
+            thousands_separator,
+        },
+    )
+}
+
+/// Render a floating-point balance in `asset`, rounded to the asset's precision
+pub fn format_amount(amount: f64, asset: &str, thousands_separator: bool) -> String {
+    let decimals = asset_decimals(asset);
+    let raw = (amount.abs() * 10f64.powi(decimals as i32)).round() as u64;
+    let formatted = format_fixed(
+        raw,
+        decimals,
+        FormatOptions {
+            min_decimals: decimals,
+            thousands_separator,
+        },
+    );
+    if amount < 0.0 && raw != 0 {
+        format!("-{}", formatted)
+    } else {
+        formatted
+    }
+}
+
+/// Decimal convention for CSV exports. Locales that write decimals with ','
+/// separate fields with ';', which is what their spreadsheets expect.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecimalSeparator {
+    #[default]
+    Point,
+    Comma,
+}
+
+impl DecimalSeparator {
+    pub fn field_delimiter(self) -> char {
+        match self {
+            DecimalSeparator::Point => ',',
+            DecimalSeparator::Comma => ';',
+        }
+    }
+
+    /// Rewrite a number rendered without thousands separators for this convention
+    pub fn localize(self, number: &str) -> String {
+        match self {
+            DecimalSeparator::Point => number.to_string(),
+            DecimalSeparator::Comma => number.replace('.', ","),
+        }
+    }
+
+    /// One newline-terminated CSV record. Fields holding the delimiter, quotes or
+    /// line breaks are quoted.
+    pub fn csv_record<S: AsRef<str>>(self, fields: &[S]) -> String {
+        let delimiter = self.field_delimiter();
+        let mut out = String::new();
+        for (i, field) in fields.iter().enumerate() {
+            let field = field.as_ref();
+            if i > 0 {
+                out.push(delimiter);
+            }
+            if field.contains([delimiter, '"', '\n', '\r']) {
+                out.push('"');
+                out.push_str(&field.replace('"', "\"\""));
+                out.push('"');
+            } else {
+                out.push_str(field);
+            }
+        }
+        out.push_str("\r\n");
+        out
+    }
+}
+
+impl std::str::FromStr for DecimalSeparator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "." | "point" => Ok(DecimalSeparator::Point),
+            "," | "comma" => Ok(DecimalSeparator::Comma),
+            _ => Err(format!("Unknown decimal separator '{}': use '.' or ','", s)),
+        }
+    }
+}
+
+/// Serde format for prices and quantities as exact decimal strings rather
+/// than raw fixed-point integers, for `#[serde(with = "crate::utils::decimal")]`
+pub mod decimal {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::XorShift;
+
+    #[test]
+    fn test_price_and_quantity_round_trip() {
+        for raw in [0, 1, 999_999, 1_000_000, 30_000_500_000, u64::MAX] {
+            let text = format_fixed(raw, 6, FormatOptions::default());
+            assert_eq!(parse_fixed(&text, 6).unwrap(), raw);
+            let grouped = format_fixed(
+                raw,
+                6,
+                FormatOptions {
+                    min_decimals: 2,
+                    thousands_separator: true,
+                },
+            );
+            assert_eq!(parse_fixed(&grouped, 6).unwrap(), raw);
+        }
+
+        let price = Price::from_f64(30000.5);
+        assert_eq!(price.to_string(), "30000.50");
+        assert_eq!(format_price(price, true), "30,000.50");
+        assert_eq!(price.to_string().parse::<Price>().unwrap(), price);
+
+        let quantity = Quantity::new(1);
+        assert_eq!(quantity.to_string(), "0.00000001");
+        assert_eq!(quantity.to_string().parse::<Quantity>().unwrap(), quantity);
+    }
+
+    #[test]
+    fn test_parse_rejects_lossy_or_malformed_input() {
+        assert!(parse_fixed("1.0000001", 6).is_err());
+        assert!(parse_fixed("-1", 6).is_err());
+        assert!(parse_fixed("1e3", 6).is_err());
+        assert!(parse_fixed("", 6).is_err());
+        assert!(parse_fixed("99999999999999999999", 6).is_err());
+        assert_eq!(parse_fixed(".5", 6).unwrap(), 500_000);
+    }
+
+    #[test]
+    fn test_conversion_errors_say_why() {
+        assert_eq!(parse_fixed("-1.5", 6), Err(FixedPointError::Negative));
+        assert_eq!(parse_fixed("-x", 6), Err(FixedPointError::NotANumber));
+        assert_eq!(parse_fixed("NaN", 6), Err(FixedPointError::NotANumber));
+        assert_eq!(
+            parse_fixed("0.0000001", 6),
+            Err(FixedPointError::TooPrecise { decimals: 6 })
+        );
+        assert_eq!(
+            parse_fixed("18446744073709.551616", 6),
+            Err(FixedPointError::TooLarge)
+        );
+        assert_eq!(parse_fixed("18446744073709.551615", 6), Ok(u64::MAX));
+
+        assert_eq!(
+            fixed_from_f64(f64::NAN, 6),
+            Err(FixedPointError::NotANumber)
+        );
+        assert_eq!(
+            fixed_from_f64(f64::INFINITY, 6),
+            Err(FixedPointError::NotANumber)
+        );
+        assert_eq!(fixed_from_f64(-0.01, 6), Err(FixedPointError::Negative));
+        assert_eq!(fixed_from_f64(1e14, 6), Err(FixedPointError::TooLarge));
+        assert_eq!(fixed_from_f64(-0.0, 6), Ok(0));
+        assert_eq!(fixed_from_f64(0.1, 8), Ok(10_000_000));
+    }
+
+    #[test]
+    fn test_fuzzed_strings_parse_exactly_or_not_at_all() {
+        let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+        let alphabet = b"0123456789.,-+e _";
+        for _ in 0..20_000 {
+            let len = (rng.next_u64() % 24) as usize;
+            let input: String = (0..len)
+                .map(|_| alphabet[(rng.next_u64() % alphabet.len() as u64) as usize] as char)
+                .collect();
+            if let Ok(raw) = parse_fixed(&input, 6) {
+                // Whatever is accepted is a plain decimal that reads back the same
+                let text = format_fixed(raw, 6, FormatOptions::default());
+                assert_eq!(parse_fixed(&text, 6), Ok(raw), "{:?}", input);
+                assert!(input
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ".,".contains(c) || c == ' '));
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuzzed_floats_convert_or_are_refused() {
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        for _ in 0..100_000 {
+            // Every bit pattern: NaNs, infinities, subnormals, negatives and huge values
+            let value = f64::from_bits(rng.next_u64());
+            match fixed_from_f64(value, 8) {
+                Ok(raw) => {
+                    assert!(value >= 0.0 && raw < u64::MAX);
+                    let back = raw as f64 / 1e8;
+                    assert!((back - value).abs() <= 0.5e-8 + value * 1e-15, "{}", value);
+                }
+                Err(FixedPointError::NotANumber) => assert!(!value.is_finite()),
+                Err(FixedPointError::Negative) => assert!(value < 0.0),
+                Err(FixedPointError::TooLarge) => assert!(value >= 1.8e11),
+                Err(e) => panic!("{} gave {:?}", value, e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_amounts_use_asset_precision() {
+        assert_eq!(format_amount(1234567.891, "USD", true), "1,234,567.89");
+        assert_eq!(format_amount(1500.4, "JPY", false), "1500");
+        assert_eq!(format_amount(0.5, "BTC", false), "0.50000000");
+        assert_eq!(format_amount(-2.5, "EUR", false), "-2.50");
+    }
+
+    #[test]
+    fn test_csv_records_follow_the_decimal_convention() {
+        let price = Price::from_f64(30000.5).to_string();
+
+        let point = DecimalSeparator::Point;
+        assert_eq!(
+            point.csv_record(&["BTC-USD", &point.localize(&price)]),
+            "BTC-USD,30000.50\r\n"
+        );
+
+        let comma: DecimalSeparator = ",".parse().unwrap();
+        assert_eq!(
+            comma.csv_record(&["a;b", "say \"hi\"", &comma.localize(&price)]),
+            "\"a;b\";\"say \"\"hi\"\"\";30000,50\r\n"
+        );
+        assert!("_".parse::<DecimalSeparator>().is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use uuid::Uuid;
+
+use crate::types::{FundingSource, FundingSourceKind, VerificationStatus};
+
+/// The two amounts the mock bank "sends" to a newly linked account; the
+/// user proves ownership by reading them back
+pub const MOCK_MICRO_DEPOSITS: [f64; 2] = [0.32, 0.45];
+
+/// The rail behind one kind of funding source. Each adapter knows how to
+/// check the details a user links, verify ownership and move money.
+pub trait FundingAdapter: Send + Sync {
+    /// Whether money moved through this adapter is simulated. Deposits
+    /// through a simulated adapter are only allowed where the faucet is.
+    fn simulated(&self) -> bool;
+
+    /// Validate the account details a user gives when linking a source.
+    /// Returns what to show in place of the details, and the status the
+    /// source starts in.
+    fn link(&self, details: &str) -> Result<(String, VerificationStatus), String>;
+
+    /// Check the user's answer to the source's verification challenge
+    fn confirm(&self, source: &FundingSource, answer: &[f64]) -> bool;
+
+    /// Pull `amount` from the source into the exchange. Returns the rail's
+    /// reference for the transfer.
+    fn deposit(&self, source: &FundingSource, amount: f64) -> Result<String, String>;
+
+    /// Push `amount` out of the exchange to the source
+    fn withdraw(&self, source: &FundingSource, amount: f64) -> Result<String, String>;
+}
+
+/// A bank that accepts any well-formed account number and verifies it with
+/// fixed micro-deposits
+pub struct MockBank;
+
+/// A wallet rail that accepts any bech32-looking address as verified
+pub struct MockCryptoWallet;
+
+impl FundingAdapter for MockBank {
+    fn simulated(&self) -> bool {
+        true
+    }
+
+    fn link(&self, details: &str) -> Result<(String, VerificationStatus), String> {
+        let account_number = details.trim();
+        if !(8..=17).contains(&account_number.len())
+            || !account_number.chars().all(|c| c.is_ascii_digit())
+        {
+            return Err("Account number must be 8 to 17 digits".to_string());
+        }
+        let last_four = &account_number[account_number.len() - 4..];
+        Ok((format!("****{}", last_four), VerificationStatus::Pending))
+    }
+
+    fn confirm(&self, _source: &FundingSource, answer: &[f64]) -> bool {
+        let mut answer = answer.to_vec();
+        answer.sort_by(f64::total_cmp);
+        answer.len() == MOCK_MICRO_DEPOSITS.len()
+            && answer
+                .iter()
+                .zip(MOCK_MICRO_DEPOSITS)
+                .all(|(given, sent)| (given - sent).abs() < 1e-9)
+    }
+
+    fn deposit(&self, _source: &FundingSource, _amount: f64) -> Result<String, String> {
+        Ok(format!("mock-ach-{}", Uuid::new_v4().simple()))
+    }
+
+    fn withdraw(&self, _source: &FundingSource, _amount: f64) -> Result<String, String> {
+        Ok(format!("mock-ach-{}", Uuid::new_v4().simple()))
+    }
+}
+
+impl FundingAdapter for MockCryptoWallet {
+    fn simulated(&self) -> bool {
+        true
+    }
+
+    fn link(&self, details: &str) -> Result<(String, VerificationStatus), String> {
+        let address = details.trim();
+        let well_formed = (address.starts_with("bc1") || address.starts_with("tb1"))
+            && (14..=74).contains(&address.len())
+            && address
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit());
+        if !well_formed {
+            return Err("Wallet address must be a bech32 address (bc1... or tb1...)".to_string());
+        }
+        Ok((address.to_string(), VerificationStatus::Verified))
+    }
+
+    fn confirm(&self, _source: &FundingSource, _answer: &[f64]) -> bool {
+        true
+    }
+
+    fn deposit(&self, _source: &FundingSource, _amount: f64) -> Result<String, String> {
+        Ok(format!("mock-tx-{}", Uuid::new_v4().simple()))
+    }
+
+    fn withdraw(&self, _source: &FundingSource, _amount: f64) -> Result<String, String> {
+        Ok(format!("mock-tx-{}", Uuid::new_v4().simple()))
+    }
+}
+
+/// The adapter that serves sources of `kind`
+pub fn funding_adapter(kind: FundingSourceKind) -> &'static dyn FundingAdapter {
+    match kind {
+        FundingSourceKind::MockBank => &MockBank,
+        FundingSourceKind::MockCryptoWallet => &MockCryptoWallet,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_bank_masks_and_verifies_with_micro_deposits() {
+        let bank = funding_adapter(FundingSourceKind::MockBank);
+        assert!(bank.link("1234").is_err());
+        assert!(bank.link("12345678x").is_err());
+
+        let (reference, status) = bank.link("000123456789").unwrap();
+        assert_eq!(reference, "****6789");
+        assert_eq!(status, VerificationStatus::Pending);
+
+        let source = FundingSource::new(
+            FundingSourceKind::MockBank,
+            "Checking".to_string(),
+            reference,
+            status,
+        );
+        assert!(bank.confirm(&source, &[0.45, 0.32]));
+        assert!(!bank.confirm(&source, &[0.32]));
+        assert!(!bank.confirm(&source, &[0.32, 0.54]));
+    }
+
+    #[test]
+    fn test_mock_wallet_checks_address_shape() {
+        let wallet = funding_adapter(FundingSourceKind::MockCryptoWallet);
+        assert!(wallet.link("1BoatSLRHtKNngkdXEeobR76b53LETtpyT").is_err());
+        let (reference, status) = wallet
+            .link("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq")
+            .unwrap();
+        assert_eq!(reference, "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq");
+        assert_eq!(status, VerificationStatus::Verified);
+    }
+}
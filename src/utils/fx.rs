@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+/// The currency prices are quoted in on the book
+pub const BASE_QUOTE_CURRENCY: &str = "USD";
+
+/// Static FX table used to render USD-quoted prices in other fiat currencies.
+/// Rates are "units of currency per 1 USD" and are display-only: nothing in the
+/// engine ever settles in a converted currency. The rates fee journals are
+/// valued at live in `ledger::SettlementRates`.
+#[derive(Debug, Clone)]
+pub struct FxRates {
+    rates: HashMap<String, f64>,
+}
+
+impl FxRates {
+    pub fn new(rates: HashMap<String, f64>) -> Self {
+        let mut rates: HashMap<String, f64> = rates
+            .into_iter()
+            .map(|(currency, rate)| (currency.to_uppercase(), rate))
+            .filter(|(_, rate)| rate.is_finite() && *rate > 0.0)
+            .collect();
+        rates.insert(BASE_QUOTE_CURRENCY.to_string(), 1.0);
+        FxRates { rates }
+    }
+
+    /// Parse rates from a `CUR=rate,CUR=rate` string, e.g. `EUR=0.92,INR=83.1`
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut rates = HashMap::new();
+        for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (currency, rate) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid FX rate entry '{}'", pair))?;
+            let currency = currency.trim();
+            if currency.is_empty() {
+                return Err(format!("Invalid FX rate entry '{}'", pair));
+            }
+            let rate: f64 = rate
+                .trim()
+                .parse()
+                .ok()
+                .filter(|rate: &f64| rate.is_finite() && *rate > 0.0)
+                .ok_or_else(|| format!("Invalid FX rate for {}", currency))?;
+            rates.insert(currency.to_string(), rate);
+        }
+        Ok(Self::new(rates))
+    }
+
+    /// Load rates from the `FX_RATES` environment variable, or the defaults
+    /// when it is unset. A malformed entry is an error rather than skipped.
+    pub fn from_env() -> Result<Self, String> {
+        match std::env::var("FX_RATES") {
+            Ok(spec) => Self::parse(&spec).map_err(|e| format!("FX_RATES: {}", e)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn supports(&self, currency: &str) -> bool {
+        self.rates.contains_key(&currency.to_uppercase())
+    }
+
+    /// Convert a USD amount into `currency`, if a rate is configured
+    pub fn convert(&self, usd_amount: f64, currency: &str) -> Option<f64> {
+        self.rates
+            .get(&currency.to_uppercase())
+            .map(|rate| usd_amount * rate)
+    }
+}
+
+impl Default for FxRates {
+    fn default() -> Self {
+        let rates = [("EUR", 0.92), ("GBP", 0.79), ("INR", 83.0), ("JPY", 150.0)]
+            .into_iter()
+            .map(|(currency, rate)| (currency.to_string(), rate))
+            .collect();
+        Self::new(rates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_convert() {
+        let fx = FxRates::parse("eur=0.5, INR=80").unwrap();
+        assert_eq!(fx.convert(10.0, "EUR"), Some(5.0));
+        assert_eq!(fx.convert(10.0, "inr"), Some(800.0));
+        assert_eq!(fx.convert(10.0, "USD"), Some(10.0));
+        assert_eq!(fx.convert(10.0, "GBP"), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_garbage() {
+        assert!(FxRates::parse("EUR").is_err());
+        assert!(FxRates::parse("EUR=abc").is_err());
+        assert!(FxRates::parse("EUR=0.9,INR=-1").is_err());
+        assert!(FxRates::parse("EUR=inf").is_err());
+        assert!(FxRates::parse("=0.9").is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A fixed-size map that evicts the least recently used entry to make room.
+/// Callers own consistency: whatever changes the backing record must
+/// `invalidate` its key, or the cache keeps serving the old copy.
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>, // Value and the tick it was last used at
+    recency: BTreeMap<u64, K>,     // Oldest use first
+    tick: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// A copy of the cached value, marking it most recently used
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let Some((value, used_at)) = self.entries.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.recency.remove(used_at);
+        *used_at = self.tick;
+        self.recency.insert(self.tick, key.clone());
+        self.hits += 1;
+        Some(value.clone())
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.invalidate(&key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    /// Drop `key` so the next lookup goes to the backing store
+    pub fn invalidate(&mut self, key: &K) {
+        if let Some((_, used_at)) = self.entries.remove(key) {
+            self.recency.remove(&used_at);
+        }
+    }
+
+    /// Look `key` up, loading and caching it on a miss. Nothing is cached when
+    /// `load` finds nothing.
+    pub fn get_or_load(&mut self, key: &K, load: impl FnOnce() -> Option<V>) -> Option<V> {
+        if let Some(value) = self.get(key) {
+            return Some(value);
+        }
+        let value = load()?;
+        self.insert(key.clone(), value.clone());
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used_and_honors_invalidation() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(1)); // "b" is now the oldest
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.len(), 2);
+
+        cache.invalidate(&"a");
+        let mut loads = 0;
+        let mut load = |value| {
+            loads += 1;
+            value
+        };
+        assert_eq!(cache.get_or_load(&"a", || load(Some(10))), Some(10));
+        assert_eq!(cache.get_or_load(&"a", || load(Some(20))), Some(10));
+        assert_eq!(cache.get_or_load(&"z", || load(None)), None);
+        assert_eq!(loads, 2);
+        assert_eq!((cache.hits, cache.misses), (3, 3));
+    }
+}
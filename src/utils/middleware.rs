@@ -1,23 +1,84 @@
-use actix_web::{dev::ServiceRequest, Error, HttpMessage};
+use actix_web::body::{BoxBody, EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpRequest};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
 use uuid::Uuid;
 
-use crate::utils::auth::validate_token;
+use crate::state::{
+    AppState, DEFAULT_PUBLIC_DEPTH_LIMIT, MAINTENANCE_AT_HEADER, MAINTENANCE_MESSAGE_HEADER,
+};
+use crate::storage::ApiKeyStore;
+use crate::types::{ApiKeyScope, GrantedScopes, OrderSource, API_KEY_PREFIX};
+use crate::utils::auth::{admin_token, validate_token, verify_admin_token, Credential};
 use crate::utils::error::ApiError;
+use crate::utils::response::{error_envelope, RequestId, REQUEST_ID_HEADER};
+use crate::utils::secrets::secret_box;
 
 pub async fn jwt_validator(
-    req: ServiceRequest,
+    mut req: ServiceRequest,
     credentials: BearerAuth,
 ) -> Result<ServiceRequest, (Error, ServiceRequest)> {
     let token = credentials.token();
 
+    // API keys sign each request instead of presenting a session token
+    if token.starts_with(API_KEY_PREFIX) {
+        let path_and_query = req
+            .uri()
+            .path_and_query()
+            .map_or(req.path(), |p| p.as_str())
+            .to_string();
+        // The body is signed too; read it, then put it back for the handler
+        let body = match req.extract::<web::Bytes>().await {
+            Ok(body) => body,
+            Err(e) => return Err((e, req)),
+        };
+        req.set_payload(body.clone().into());
+        let authenticated = match req.app_data::<web::Data<ApiKeyStore>>().cloned() {
+            Some(api_keys) => {
+                let method = req.method().as_str().to_string();
+                api_keys
+                    .authenticate(
+                        secret_box(),
+                        token,
+                        &method,
+                        &path_and_query,
+                        &body,
+                        Utc::now(),
+                    )
+                    .await
+            }
+            None => Err("API keys are not enabled".to_string()),
+        };
+        let key = match authenticated {
+            Ok(key) => key,
+            Err(message) => return Err((ApiError::Unauthorized(message).into(), req)),
+        };
+        // The user ID is held back until the route checks the key's scopes,
+        // so routes that declare no scope refuse API keys
+        req.extensions_mut().insert(UnscopedApiKeyUser(key.user_id));
+        req.extensions_mut().insert(OrderSource::ApiKey);
+        let credential = Credential::ApiKey { key_id: key.key_id };
+        req.extensions_mut().insert(credential);
+        req.extensions_mut().insert(GrantedScopes(key.scopes));
+        return Ok(req);
+    }
+
     match validate_token(token) {
         Ok(claims) => {
             // Parse user_id from claims
             match Uuid::parse_str(&claims.sub) {
                 Ok(user_id) => {
-                    // Store user_id in request extensions for later use
+                    // Store user_id in request extensions for later use,
+                    // along with the channel this session authenticated through
                     req.extensions_mut().insert(user_id);
+                    req.extensions_mut().insert(OrderSource::Web);
+                    req.extensions_mut().insert(Credential::Session {
+                        expires_at: claims.expires_at(),
+                    });
                     Ok(req)
                 }
                 Err(_) => Err((
@@ -32,3 +93,230 @@ pub async fn jwt_validator(
         )),
     }
 }
+
+/// User an API key authenticated as, before the route it reached has checked
+/// the key's scopes
+#[derive(Debug, Clone, Copy)]
+struct UnscopedApiKeyUser(Uuid);
+
+/// Let an API key request through to a route needing `scope`, handing the
+/// key's user to the handler. Session tokens are not limited by scopes.
+fn grant_scope(req: &ServiceRequest, scope: ApiKeyScope) -> Result<(), ApiError> {
+    let Some(UnscopedApiKeyUser(user_id)) = req.extensions().get::<UnscopedApiKeyUser>().copied()
+    else {
+        return Ok(());
+    };
+    let granted = req
+        .extensions()
+        .get::<GrantedScopes>()
+        .is_some_and(|GrantedScopes(scopes)| scopes.contains(&scope));
+    if !granted {
+        let message = format!("API key lacks the {} scope", scope.as_str());
+        return Err(ApiError::Forbidden(message));
+    }
+    req.extensions_mut().insert(user_id);
+    Ok(())
+}
+
+async fn require_scope<B: MessageBody>(
+    scope: ApiKeyScope,
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    match grant_scope(&req, scope) {
+        Ok(()) => Ok(next.call(req).await?.map_into_left_body()),
+        Err(e) => Ok(req.error_response(e).map_into_right_body()),
+    }
+}
+
+/// Route middleware for reads: balances, orders, history
+pub async fn read_scope<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    require_scope(ApiKeyScope::Read, req, next).await
+}
+
+/// Route middleware for placing, amending and cancelling orders, and for
+/// account settings
+pub async fn trade_scope<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    require_scope(ApiKeyScope::Trade, req, next).await
+}
+
+/// Route middleware for withdrawals and the funding sources they go to
+pub async fn withdraw_scope<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    require_scope(ApiKeyScope::Withdraw, req, next).await
+}
+
+/// Route middleware for managing API keys and linked identities
+pub async fn admin_scope<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    require_scope(ApiKeyScope::Admin, req, next).await
+}
+
+/// Guards operator routes with the static admin token
+pub async fn admin_validator(
+    req: ServiceRequest,
+    credentials: BearerAuth,
+) -> Result<ServiceRequest, (Error, ServiceRequest)> {
+    if admin_token().is_none() {
+        let message = "Admin routes are disabled until ADMIN_TOKEN is set".to_string();
+        Err((ApiError::Forbidden(message).into(), req))
+    } else if verify_admin_token(credentials.token()) {
+        Ok(req)
+    } else {
+        Err((
+            ApiError::Unauthorized("Invalid admin token".to_string()).into(),
+            req,
+        ))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DepthParam {
+    depth: Option<usize>,
+}
+
+/// Serve the public depth limit to anyone, and deeper books only to requests
+/// that authenticate the way the protected routes do. A malformed `depth` is
+/// left for the handler to refuse.
+pub async fn depth_limit<B: MessageBody>(
+    mut req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let limit = req
+        .app_data::<web::Data<AppState>>()
+        .map(|state| state.public_depth_limit)
+        .unwrap_or(DEFAULT_PUBLIC_DEPTH_LIMIT);
+    let depth = web::Query::<DepthParam>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.depth);
+    if depth.is_none_or(|depth| depth <= limit) {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let authenticated = match req.extract::<BearerAuth>().await {
+        Ok(credentials) => match jwt_validator(req, credentials).await {
+            Ok(req) => match grant_scope(&req, ApiKeyScope::Read) {
+                Ok(()) => Ok(req),
+                Err(e) => Err((e.into(), req)),
+            },
+            Err(rejected) => Err(rejected),
+        },
+        Err(_) => {
+            let message = format!("A depth beyond {} levels needs a bearer token", limit);
+            Err((ApiError::Unauthorized(message).into(), req))
+        }
+    };
+    match authenticated {
+        Ok(req) => Ok(next.call(req).await?.map_into_left_body()),
+        Err((e, req)) => Ok(req.error_response(e).map_into_right_body()),
+    }
+}
+
+/// Name any announced maintenance on the response, so REST clients see it
+/// coming without subscribing to anything
+pub async fn maintenance_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let notice = req
+        .app_data::<web::Data<AppState>>()
+        .and_then(|state| state.maintenance.current());
+    let mut res = next.call(req).await?;
+
+    if let Some(notice) = notice {
+        // The message was checked to be printable ASCII when announced
+        let scheduled_at = notice
+            .scheduled_at
+            .to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        for (name, value) in [
+            (MAINTENANCE_AT_HEADER, scheduled_at.as_str()),
+            (MAINTENANCE_MESSAGE_HEADER, notice.message.as_str()),
+        ] {
+            if let (Ok(name), Ok(value)) =
+                (HeaderName::try_from(name), HeaderValue::from_str(value))
+            {
+                res.headers_mut().insert(name, value);
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// When the gateway received the request being served, set by the
+/// `stamp_receipt` middleware before authentication and body parsing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceivedAt(pub DateTime<Utc>);
+
+impl ReceivedAt {
+    /// Receipt time of `req`, or now if no middleware stamped it
+    pub fn of(req: &HttpRequest) -> DateTime<Utc> {
+        req.extensions()
+            .get::<ReceivedAt>()
+            .map_or_else(Utc::now, |stamp| stamp.0)
+    }
+}
+
+/// Stamp each request with the time it reached the gateway. Wrapped outside
+/// every other middleware, so time spent authenticating, reading the body or
+/// waiting on the engine never counts against an order's time priority.
+pub async fn stamp_receipt(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    req.extensions_mut().insert(ReceivedAt(Utc::now()));
+    next.call(req).await
+}
+
+/// Give each request an ID: the caller's own `X-Request-Id` if it is usable,
+/// otherwise a new one. Handlers and the response envelope read it from the
+/// request, and it is echoed on every response so errors can be traced.
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| RequestId::is_usable(id))
+        .map_or_else(|| Uuid::new_v4().to_string(), str::to_string);
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    if let (Ok(name), Ok(value)) = (
+        HeaderName::try_from(REQUEST_ID_HEADER),
+        HeaderValue::from_str(&id),
+    ) {
+        res.headers_mut().insert(name, value);
+    }
+    Ok(res)
+}
+
+/// Answer errors in the response envelope too, on versions that use it
+pub async fn envelope_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let res = next.call(req).await?.map_into_boxed_body();
+    let Some(error) = res.response().error() else {
+        return Ok(res);
+    };
+    let message = match error.as_error::<ApiError>() {
+        Some(error) => error.status_and_message().1.to_string(),
+        None => error.to_string(),
+    };
+    let status = res.status();
+    let (req, _) = res.into_parts();
+    let response = error_envelope(&req, status, message);
+    Ok(ServiceResponse::new(req, response))
+}
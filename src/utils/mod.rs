@@ -1,7 +1,23 @@
 pub mod auth;
 pub mod error;
+pub mod format;
+pub mod funding;
+pub mod fx;
+pub mod lru;
 pub mod middleware;
+pub mod oidc;
+pub mod response;
+pub mod secrets;
+pub mod signing;
 
 pub use auth::*;
 pub use error::*;
+pub use format::*;
+pub use funding::*;
+pub use fx::*;
+pub use lru::*;
 pub use middleware::*;
+pub use oidc::*;
+pub use response::*;
+pub use secrets::*;
+pub use signing::*;
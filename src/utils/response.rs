@@ -0,0 +1,311 @@
+use actix_web::body::BoxBody;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder, ResponseError};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream, StreamExt};
+use serde::Serialize;
+use std::io;
+
+use crate::routes::{api_version, ApiVersion};
+use crate::utils::error::ApiError;
+
+/// Header carrying the ID of a request, on the way in and on its response
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Longest request ID a caller may choose for itself
+pub const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// ID of the request being served, set by the `request_id` middleware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Whether `id`, sent by a caller, can be used as it is
+    pub fn is_usable(id: &str) -> bool {
+        !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+    }
+}
+
+fn request_id_of(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestId>().map(|id| id.0.clone())
+}
+
+/// Where a page of a list sits, in whichever of the two ways the list pages:
+/// by offset, or after a sequence number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Pagination {
+    pub limit: usize,
+    pub returned: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_offset: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_after: Option<u64>,
+    pub has_more: bool,
+}
+
+impl Pagination {
+    pub fn by_offset(
+        offset: usize,
+        limit: usize,
+        returned: usize,
+        next_offset: Option<usize>,
+    ) -> Self {
+        Pagination {
+            limit,
+            returned,
+            offset: Some(offset),
+            next_offset,
+            after: None,
+            next_after: None,
+            has_more: next_offset.is_some(),
+        }
+    }
+
+    pub fn by_seq(
+        after: Option<u64>,
+        limit: usize,
+        returned: usize,
+        next_after: Option<u64>,
+    ) -> Self {
+        Pagination {
+            limit,
+            returned,
+            offset: None,
+            next_offset: None,
+            after,
+            next_after,
+            has_more: next_after.is_some(),
+        }
+    }
+}
+
+/// What version 2 of the API answers with
+#[derive(Debug, Serialize)]
+pub struct Envelope<T: Serialize> {
+    pub data: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pagination: Option<Pagination>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub server_time: DateTime<Utc>,
+    pub request_id: Option<String>,
+}
+
+/// A successful handler response. Versions before 2 send `data` as the whole
+/// body, as they always have; version 2 wraps it in an `Envelope`.
+#[derive(Debug)]
+pub struct ApiResponse {
+    status: StatusCode,
+    data: Result<serde_json::Value, String>,
+    pagination: Option<Pagination>,
+}
+
+impl ApiResponse {
+    pub fn ok(data: impl Serialize) -> Self {
+        Self::with_status(StatusCode::OK, data)
+    }
+
+    pub fn created(data: impl Serialize) -> Self {
+        Self::with_status(StatusCode::CREATED, data)
+    }
+
+    pub fn unavailable(data: impl Serialize) -> Self {
+        Self::with_status(StatusCode::SERVICE_UNAVAILABLE, data)
+    }
+
+    fn with_status(status: StatusCode, data: impl Serialize) -> Self {
+        ApiResponse {
+            status,
+            data: serde_json::to_value(data).map_err(|e| e.to_string()),
+            pagination: None,
+        }
+    }
+
+    pub fn paginated(mut self, pagination: Pagination) -> Self {
+        self.pagination = Some(pagination);
+        self
+    }
+
+    /// Answers with the empty array `field` of `data` filled in from `pages`
+    /// as they arrive, each a page of items already encoded as JSON, so a
+    /// long list is never held in memory. An error in `pages` aborts the
+    /// transfer, leaving the client with a body that does not parse.
+    pub fn streaming<S>(self, req: &HttpRequest, field: &str, pages: S) -> HttpResponse
+    where
+        S: Stream<Item = Result<Vec<String>, io::Error>> + 'static,
+    {
+        let document = self.data.and_then(|data| {
+            let document = match api_version(req) {
+                ApiVersion::V1 => serde_json::to_string(&data),
+                ApiVersion::V2 => serde_json::to_string(&Envelope {
+                    data: Some(data),
+                    pagination: self.pagination,
+                    error: None,
+                    server_time: Utc::now(),
+                    request_id: request_id_of(req),
+                }),
+            };
+            document.map_err(|e| e.to_string())
+        });
+        // Quotes inside strings are escaped, so only the field itself matches
+        let placeholder = format!("\"{}\":[]", field);
+        let document = match document {
+            Ok(document) => document,
+            Err(e) => {
+                let error = ApiError::InternalError(format!("Failed to encode response: {}", e));
+                return error.error_response();
+            }
+        };
+        let Some(at) = document.find(&placeholder) else {
+            let message = format!("Response has no '{}' list to stream", field);
+            return ApiError::InternalError(message).error_response();
+        };
+        // Split just inside the brackets
+        let (head, tail) = document.split_at(at + placeholder.len() - 1);
+        let (head, tail) = (head.to_string(), tail.to_string());
+
+        let items = pages.scan(true, |first, page| {
+            let chunk = page.map(|items| {
+                let mut chunk = String::new();
+                for item in items {
+                    if !std::mem::take(first) {
+                        chunk.push(',');
+                    }
+                    chunk.push_str(&item);
+                }
+                web::Bytes::from(chunk)
+            });
+            async move { Some(chunk) }
+        });
+        HttpResponse::build(self.status)
+            .content_type("application/json")
+            .streaming(
+                stream::once(async move { Ok(web::Bytes::from(head)) })
+                    .chain(items)
+                    .chain(stream::once(async move { Ok(web::Bytes::from(tail)) })),
+            )
+    }
+}
+
+impl Responder for ApiResponse {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        let data = match self.data {
+            Ok(data) => data,
+            Err(e) => {
+                let error = ApiError::InternalError(format!("Failed to encode response: {}", e));
+                return error.error_response();
+            }
+        };
+        match api_version(req) {
+            ApiVersion::V1 => HttpResponse::build(self.status).json(data),
+            ApiVersion::V2 => HttpResponse::build(self.status).json(Envelope {
+                data: Some(data),
+                pagination: self.pagination,
+                error: None,
+                server_time: Utc::now(),
+                request_id: request_id_of(req),
+            }),
+        }
+    }
+}
+
+/// An error answered in the envelope, with no data
+pub fn error_envelope(req: &HttpRequest, status: StatusCode, message: String) -> HttpResponse {
+    HttpResponse::build(status).json(Envelope::<()> {
+        data: None,
+        pagination: None,
+        error: Some(message),
+        server_time: Utc::now(),
+        request_id: request_id_of(req),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{body, test};
+
+    #[actix_web::test]
+    async fn test_version_two_wraps_data_and_earlier_versions_do_not() {
+        let page = || {
+            ApiResponse::ok(serde_json::json!({ "trades": [1, 2] }))
+                .paginated(Pagination::by_offset(0, 2, 2, Some(2)))
+        };
+        let body_of = |resp: HttpResponse| async move {
+            let bytes = body::to_bytes(resp.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+
+        let req = test::TestRequest::default().to_http_request();
+        let body = body_of(page().respond_to(&req)).await;
+        assert_eq!(body, serde_json::json!({ "trades": [1, 2] }));
+
+        let req = test::TestRequest::default()
+            .app_data(ApiVersion::V2)
+            .to_http_request();
+        req.extensions_mut().insert(RequestId("req-1".to_string()));
+        let resp = page().respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_of(resp).await;
+        assert_eq!(body["data"]["trades"], serde_json::json!([1, 2]));
+        assert_eq!(body["pagination"]["next_offset"], 2);
+        assert_eq!(body["pagination"]["has_more"], true);
+        assert!(body["pagination"].get("after").is_none());
+        assert_eq!(body["request_id"], "req-1");
+        assert!(body["server_time"].is_string());
+        assert!(body.get("error").is_none());
+
+        assert!(RequestId::is_usable("4f1c-ab_9.x:1"));
+        assert!(!RequestId::is_usable("has space"));
+        assert!(!RequestId::is_usable(&"x".repeat(MAX_REQUEST_ID_LEN + 1)));
+    }
+
+    #[actix_web::test]
+    async fn test_streamed_lists_are_filled_in_page_by_page() {
+        let pages = || {
+            stream::iter(vec![
+                Ok(vec!["1".to_string(), "2".to_string()]),
+                Ok(vec![]),
+                Ok(vec!["3".to_string()]),
+            ])
+        };
+        let body_of = |resp: HttpResponse| async move {
+            let bytes = body::to_bytes(resp.into_body()).await.ok().unwrap();
+            serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()
+        };
+        let data = || serde_json::json!({ "user_id": "\"entries\":[]", "entries": [] });
+
+        let req = test::TestRequest::default().to_http_request();
+        let body = body_of(ApiResponse::ok(data()).streaming(&req, "entries", pages())).await;
+        assert_eq!(body["entries"], serde_json::json!([1, 2, 3]));
+        assert_eq!(body["user_id"], "\"entries\":[]");
+
+        let req = test::TestRequest::default()
+            .app_data(ApiVersion::V2)
+            .to_http_request();
+        let body = body_of(ApiResponse::ok(data()).streaming(&req, "entries", pages())).await;
+        assert_eq!(body["data"]["entries"], serde_json::json!([1, 2, 3]));
+        assert!(body["server_time"].is_string());
+
+        // A failed page cuts the body short
+        let failing = stream::iter(vec![
+            Ok(vec!["1".to_string()]),
+            Err(io::Error::other("gone")),
+        ]);
+        let resp = ApiResponse::ok(data()).streaming(&req, "entries", failing);
+        assert!(body::to_bytes(resp.into_body()).await.is_err());
+
+        let resp = ApiResponse::ok(serde_json::json!({})).streaming(&req, "entries", pages());
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -0,0 +1,283 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+const DEFAULT_API_KEY_ENCRYPTION_KEY: &str = "your-api-key-encryption-key-change-in-production";
+
+/// Somewhere secret material can be looked up by name. The server only asks
+/// for names; whether the value comes from the environment, a mounted file or
+/// a KMS client is up to the provider.
+pub trait SecretProvider: Send + Sync {
+    /// The secret called `name`, or None if this provider does not hold it
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String>;
+}
+
+/// Secrets held in environment variables of the same name
+pub struct EnvSecrets;
+
+impl SecretProvider for EnvSecrets {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(std::env::var_os(name).map(|value| value.into_encoded_bytes()))
+    }
+}
+
+/// One file per secret in a directory, the way Docker and Kubernetes mount
+/// them. A single trailing newline is not part of the secret.
+pub struct FileSecrets {
+    dir: PathBuf,
+}
+
+impl FileSecrets {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileSecrets { dir: dir.into() }
+    }
+}
+
+impl SecretProvider for FileSecrets {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        match std::fs::read(self.dir.join(name)) {
+            Ok(mut value) => {
+                if value.last() == Some(&b'\n') {
+                    value.pop();
+                    if value.last() == Some(&b'\r') {
+                        value.pop();
+                    }
+                }
+                Ok(Some(value))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Failed to read secret {}: {}", name, e)),
+        }
+    }
+}
+
+/// Fixed secrets, for tests and tooling
+impl SecretProvider for HashMap<String, Vec<u8>> {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.get(name).cloned())
+    }
+}
+
+/// Providers asked in order; the first one holding a secret wins
+pub struct SecretChain {
+    providers: Vec<Box<dyn SecretProvider>>,
+}
+
+impl SecretChain {
+    pub fn new(providers: Vec<Box<dyn SecretProvider>>) -> Self {
+        SecretChain { providers }
+    }
+
+    /// Files under `SECRETS_DIR` when set, then the environment
+    pub fn from_env() -> Self {
+        let mut providers: Vec<Box<dyn SecretProvider>> = Vec::new();
+        if let Some(dir) = std::env::var_os("SECRETS_DIR") {
+            providers.push(Box::new(FileSecrets::new(dir)));
+        }
+        providers.push(Box::new(EnvSecrets));
+        Self::new(providers)
+    }
+
+    /// Secret as text, or None when no provider holds it. A failing lookup is
+    /// reported and treated as missing, so the usual default or strict-auth
+    /// check applies.
+    pub fn text(&self, name: &str) -> Option<String> {
+        self.get(name)
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                None
+            })
+            .map(|value| String::from_utf8_lossy(&value).into_owned())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.text(name).is_some()
+    }
+}
+
+impl SecretProvider for SecretChain {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        for provider in &self.providers {
+            if let Some(value) = provider.get(name)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Process-wide secret source, built from the environment on first use
+pub fn secrets() -> &'static SecretChain {
+    static SECRETS: OnceLock<SecretChain> = OnceLock::new();
+    SECRETS.get_or_init(SecretChain::from_env)
+}
+
+/// A secret encrypted at rest, with the id of the key that sealed it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SealedSecret {
+    pub key_id: String,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// AES-256-GCM encryption for secrets the server has to keep but must be able
+/// to read back, such as API key secrets used to check request signatures.
+/// Retired keys stay available for opening what they sealed.
+pub struct SecretBox {
+    current_id: String,
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+impl SecretBox {
+    /// Any passphrase works: the AES key is its SHA-256 digest
+    pub fn new(key_id: impl Into<String>, passphrase: &[u8]) -> Self {
+        let key_id = key_id.into();
+        let mut keys = HashMap::new();
+        keys.insert(key_id.clone(), Self::cipher(passphrase));
+        SecretBox {
+            current_id: key_id,
+            keys,
+        }
+    }
+
+    /// Keep opening secrets sealed under a retired key
+    pub fn with_previous(mut self, key_id: impl Into<String>, passphrase: &[u8]) -> Self {
+        self.keys
+            .entry(key_id.into())
+            .or_insert_with(|| Self::cipher(passphrase));
+        self
+    }
+
+    /// `API_KEY_ENCRYPTION_KEY` / `API_KEY_ENCRYPTION_KEY_ID`, plus retired
+    /// keys as `id=passphrase` lines in `API_KEY_ENCRYPTION_PREVIOUS_KEYS`
+    pub fn from_secrets(secrets: &SecretChain) -> Self {
+        let passphrase = secrets
+            .text("API_KEY_ENCRYPTION_KEY")
+            .unwrap_or_else(|| DEFAULT_API_KEY_ENCRYPTION_KEY.to_string());
+        let key_id = secrets
+            .text("API_KEY_ENCRYPTION_KEY_ID")
+            .unwrap_or_else(|| "default".to_string());
+
+        let previous = secrets
+            .text("API_KEY_ENCRYPTION_PREVIOUS_KEYS")
+            .unwrap_or_default();
+        parse_keyring(&previous).into_iter().fold(
+            Self::new(key_id, passphrase.as_bytes()),
+            |sealer, (id, passphrase)| sealer.with_previous(id, passphrase.as_bytes()),
+        )
+    }
+
+    fn cipher(passphrase: &[u8]) -> Aes256Gcm {
+        let digest = Sha256::digest(passphrase);
+        Aes256Gcm::new(&digest)
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.current_id
+    }
+
+    /// Encrypt under the current key with a fresh random nonce
+    pub fn seal(&self, plaintext: &[u8]) -> Result<SealedSecret, String> {
+        let cipher = &self.keys[&self.current_id];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| "Failed to encrypt secret".to_string())?;
+        Ok(SealedSecret {
+            key_id: self.current_id.clone(),
+            nonce: nonce.to_vec(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypt with whichever key sealed it; fails if that key is unknown or
+    /// the ciphertext was tampered with
+    pub fn open(&self, sealed: &SealedSecret) -> Result<Vec<u8>, String> {
+        let cipher = self
+            .keys
+            .get(&sealed.key_id)
+            .ok_or_else(|| format!("Unknown encryption key '{}'", sealed.key_id))?;
+        let nonce: [u8; 12] = sealed
+            .nonce
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Malformed sealed secret".to_string())?;
+        cipher
+            .decrypt(&Nonce::from(nonce), sealed.ciphertext.as_slice())
+            .map_err(|_| "Failed to decrypt secret".to_string())
+    }
+}
+
+/// Sealer for stored API key secrets, keyed from the process-wide secrets
+pub fn secret_box() -> &'static SecretBox {
+    static SECRET_BOX: OnceLock<SecretBox> = OnceLock::new();
+    SECRET_BOX.get_or_init(|| SecretBox::from_secrets(secrets()))
+}
+
+/// Parse `id=secret` entries, one per line or comma-separated
+pub fn parse_keyring(spec: &str) -> Vec<(String, String)> {
+    spec.split(['\n', ','])
+        .map(str::trim)
+        .filter_map(|entry| entry.split_once('='))
+        .map(|(id, secret)| (id.trim().to_string(), secret.trim().to_string()))
+        .filter(|(id, secret)| !id.is_empty() && !secret.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain_prefers_earlier_providers_and_trims_files() {
+        let dir = std::env::temp_dir().join(format!("secrets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("JWT_SECRET"), "from-file\n").unwrap();
+
+        let fixed: HashMap<String, Vec<u8>> = [
+            ("JWT_SECRET".to_string(), b"from-map".to_vec()),
+            ("ADMIN_TOKEN".to_string(), b"admin".to_vec()),
+        ]
+        .into_iter()
+        .collect();
+        let chain = SecretChain::new(vec![Box::new(FileSecrets::new(&dir)), Box::new(fixed)]);
+
+        assert_eq!(chain.text("JWT_SECRET").as_deref(), Some("from-file"));
+        assert_eq!(chain.text("ADMIN_TOKEN").as_deref(), Some("admin"));
+        assert!(!chain.contains("TAPE_SIGNING_KEY"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_secret_box_round_trip_and_rotation() {
+        let old = SecretBox::new("k1", b"old passphrase");
+        let sealed_old = old.seal(b"api secret").unwrap();
+        assert_ne!(sealed_old.ciphertext, b"api secret".to_vec());
+
+        let rotated =
+            SecretBox::new("k2", b"new passphrase").with_previous("k1", b"old passphrase");
+        assert_eq!(rotated.open(&sealed_old).unwrap(), b"api secret".to_vec());
+        let sealed_new = rotated.seal(b"api secret").unwrap();
+        assert_eq!(sealed_new.key_id, "k2");
+        assert!(old.open(&sealed_new).is_err());
+
+        let mut tampered = sealed_new.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(rotated.open(&tampered).is_err());
+    }
+
+    #[test]
+    fn test_parse_keyring() {
+        assert_eq!(
+            parse_keyring("k1=alpha, k2 = beta\nbroken\n=nokey"),
+            vec![
+                ("k1".to_string(), "alpha".to_string()),
+                ("k2".to_string(), "beta".to_string()),
+            ]
+        );
+    }
+}
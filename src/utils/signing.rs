@@ -0,0 +1,111 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::state::{Environment, Profile};
+use crate::utils::secrets::secrets;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// HMAC-SHA256 signer for published data pages (e.g. the public trade tape).
+/// Consumers holding the key can verify a page was produced by this exchange unaltered.
+#[derive(Clone)]
+pub struct PageSigner {
+    key: Vec<u8>,
+    pub key_id: String,
+}
+
+impl PageSigner {
+    pub fn new(key: impl Into<Vec<u8>>, key_id: impl Into<String>) -> Self {
+        PageSigner {
+            key: key.into(),
+            key_id: key_id.into(),
+        }
+    }
+
+    /// A key made up for this process alone; nothing it signs verifies after
+    /// a restart or on another instance
+    pub fn ephemeral() -> Self {
+        let mut key = Uuid::new_v4().as_bytes().to_vec();
+        key.extend_from_slice(Uuid::new_v4().as_bytes());
+        Self::new(key, "ephemeral")
+    }
+
+    /// Load the key from the `TAPE_SIGNING_KEY` / `TAPE_SIGNING_KEY_ID` secrets.
+    /// Only the dev profile may run without one, on an ephemeral key.
+    pub fn from_env(profile: &Profile) -> Result<Self, String> {
+        let Some(key) = secrets().text("TAPE_SIGNING_KEY") else {
+            return match profile.environment {
+                Environment::Dev => Ok(Self::ephemeral()),
+                environment => Err(format!(
+                    "{:?} profile requires TAPE_SIGNING_KEY to be set",
+                    environment
+                )),
+            };
+        };
+        let key_id = secrets()
+            .text("TAPE_SIGNING_KEY_ID")
+            .unwrap_or_else(|| "default".to_string());
+        Ok(Self::new(key.into_bytes(), key_id))
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(payload);
+        mac
+    }
+
+    /// Hex-encoded HMAC of `payload`
+    pub fn sign(&self, payload: &[u8]) -> String {
+        self.mac(payload)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Whether `signature` is the hex HMAC of `payload`, compared in constant time
+    pub fn verify(&self, payload: &[u8], signature: &str) -> bool {
+        decode_hex(signature).is_some_and(|bytes| self.mac(payload).verify_slice(&bytes).is_ok())
+    }
+}
+
+impl Default for PageSigner {
+    fn default() -> Self {
+        Self::ephemeral()
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = PageSigner::new(b"secret".to_vec(), "k1");
+        let signature = signer.sign(b"page");
+        assert_eq!(signature.len(), 64);
+        assert!(signer.verify(b"page", &signature));
+        assert!(!signer.verify(b"tampered", &signature));
+        assert!(!PageSigner::new(b"other".to_vec(), "k2").verify(b"page", &signature));
+        assert!(!signer.verify(b"page", &signature[..62]));
+        assert!(!signer.verify(b"page", "not hex"));
+    }
+
+    #[test]
+    fn test_ephemeral_keys_differ() {
+        let signature = PageSigner::ephemeral().sign(b"page");
+        assert!(!PageSigner::ephemeral().verify(b"page", &signature));
+    }
+}